@@ -0,0 +1,81 @@
+//! Finds what the kernel's being built from, for `heorot::version()`: the
+//! commit, the time, the compiler and the features. Runs on the host, so it
+//! has std, and git if there is one.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rustc-env=HEOROT_GIT_HASH={}", git_hash().unwrap_or_else(|| "unknown".into()));
+    println!("cargo:rustc-env=HEOROT_BUILD_TIME={}", build_time());
+    println!("cargo:rustc-env=HEOROT_RUSTC={}", rustc().unwrap_or_else(|| "unknown".into()));
+    println!("cargo:rustc-env=HEOROT_FEATURES={}", features().join(","));
+
+    watch_git();
+    // Edits, staged or not, for the -dirty mark
+    for path in &["src", "tests", "etc", "Cargo.toml", "build.rs"] {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+// A checkout moves HEAD, a commit moves the branch HEAD is on, in its own
+// file or packed-refs, and staging touches the index. Without a HEAD that
+// resolves, a path that isn't there has Cargo run this every build.
+fn watch_git() {
+    let git_dir = output(Command::new("git").args(["rev-parse", "--git-dir"]));
+    let head = output(Command::new("git").args(["rev-parse", "--symbolic-full-name", "HEAD"]));
+    let (git_dir, head) = match (git_dir, head) {
+        (Some(git_dir), Some(head)) if !head.is_empty() => (PathBuf::from(git_dir), head),
+        _ => {
+            println!("cargo:rerun-if-changed=.git/HEAD-does-not-resolve");
+            return;
+        }
+    };
+    println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+    println!("cargo:rerun-if-changed={}", git_dir.join("index").display());
+    // "HEAD" itself when it's detached
+    let branch = git_dir.join(&head);
+    if branch.exists() {
+        println!("cargo:rerun-if-changed={}", branch.display());
+    }
+    let packed = git_dir.join("packed-refs");
+    if packed.exists() {
+        println!("cargo:rerun-if-changed={}", packed.display());
+    }
+}
+
+fn output(command: &mut Command) -> Option<String> {
+    let output = command.output().ok().filter(|output| output.status.success())?;
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+// The commit checked out, marked if there's more than it in the tree
+fn git_hash() -> Option<String> {
+    let hash = output(Command::new("git").args(["rev-parse", "--short", "HEAD"]))?;
+    let status = output(Command::new("git").args(["status", "--porcelain", "--untracked-files=no"]))?;
+    Some(if status.is_empty() { hash } else { hash + "-dirty" })
+}
+
+// Seconds since the Unix epoch; SOURCE_DATE_EPOCH, if it's set, so builds
+// can be reproduced
+fn build_time() -> u64 {
+    env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.parse().ok()).unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+    })
+}
+
+fn rustc() -> Option<String> {
+    output(Command::new(env::var("RUSTC").unwrap_or_else(|_| "rustc".into())).arg("--version"))
+}
+
+// Cargo says which are on as CARGO_FEATURE_<NAME>, uppercased with - as _
+fn features() -> Vec<String> {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|name| name.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    features
+}
@@ -0,0 +1,132 @@
+//! Finding ACPI tables. The RSDP is wherever the BIOS left it, and points
+//! at the XSDT (or, from ACPI 1.0 firmware, the RSDT) listing every other
+//! table. Everything is read through the kernel's mapping of physical
+//! memory, so nothing here works before memory::init.
+
+use core::slice;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::paging;
+
+/// The header every table but the RSDP starts with
+pub const HEADER_SIZE: usize = 36;
+// Bigger than any table firmware really has
+const MAX_TABLE_SIZE: u32 = 1024 * 1024;
+
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+// Segment of the extended BIOS data area, kept in the BIOS data area
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA: (u64, u64) = (0xe_0000, 0x10_0000);
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(u32_at(bytes, offset)) | (u64::from(u32_at(bytes, offset + 4)) << 32)
+}
+
+fn sums_to_zero(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+// `len` bytes of physical memory from `addr`, if they're mapped
+fn physical(addr: u64, len: usize) -> Option<&'static [u8]> {
+    let end = addr.checked_add(len as u64)?;
+    let (start, last) = (paging::phys_to_virt(PhysAddr::new(addr)), paging::phys_to_virt(PhysAddr::new(end - 1)));
+    paging::translate_addr(start)?;
+    paging::translate_addr(last)?;
+    // The bootloader maps all of physical memory, and firmware tables
+    // never move
+    Some(unsafe { slice::from_raw_parts(start.as_ptr(), len) })
+}
+
+// The RSDP sits on a 16-byte boundary in the first KiB of the EBDA or in
+// the BIOS area below 1 MiB
+fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda = physical(EBDA_POINTER, 2).map(|segment| u64::from(u16::from_le_bytes([segment[0], segment[1]])) << 4);
+    let areas = [ebda.map(|start| (start, start + 1024)), Some(BIOS_AREA)];
+    for &(start, end) in areas.iter().flatten() {
+        for addr in (start..end).step_by(16) {
+            let rsdp = match physical(addr, RSDP_V1_SIZE) {
+                Some(rsdp) => rsdp,
+                None => break,
+            };
+            if &rsdp[..8] == RSDP_SIGNATURE && sums_to_zero(rsdp) {
+                // Revision 2 on adds the XSDT, with a checksum of its own
+                if rsdp[15] < 2 {
+                    return Some(rsdp);
+                }
+                match physical(addr, RSDP_V2_SIZE) {
+                    Some(rsdp) if sums_to_zero(rsdp) => return Some(rsdp),
+                    _ => {}
+                }
+            }
+        }
+    }
+    None
+}
+
+/// An ACPI table whose length and checksum have been checked
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    bytes: &'static [u8],
+}
+
+impl Table {
+    fn at(addr: u64) -> Option<Table> {
+        let header = physical(addr, HEADER_SIZE)?;
+        let len = u32_at(header, 4);
+        if (len as usize) < HEADER_SIZE || len > MAX_TABLE_SIZE {
+            return None;
+        }
+        let bytes = physical(addr, len as usize)?;
+        if !sums_to_zero(bytes) {
+            return None;
+        }
+        Some(Table { bytes })
+    }
+
+    pub fn signature(&self) -> &'static [u8] {
+        &self.bytes[..4]
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    /// The whole table, header and all
+    pub fn bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+
+    /// Where the table is, in the kernel's view of physical memory
+    pub fn address(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.bytes.as_ptr())
+    }
+}
+
+/// The table with `signature`, if the firmware has one
+pub fn find(signature: &[u8; 4]) -> Option<Table> {
+    let rsdp = find_rsdp()?;
+    let xsdt = if rsdp[15] >= 2 { u64_at(rsdp, 24) } else { 0 };
+    // The XSDT lists 64-bit addresses, the RSDT 32-bit ones
+    let (root, entry_size) = if xsdt != 0 { (Table::at(xsdt)?, 8) } else { (Table::at(u64::from(u32_at(rsdp, 16)))?, 4) };
+    root.bytes[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| if entry_size == 8 { u64_at(entry, 0) } else { u64::from(u32_at(entry, 0)) })
+        .filter_map(Table::at)
+        .find(|table| table.signature() == signature)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_finds_the_fadt() {
+    // Every PC firmware QEMU boots with has one
+    let fadt = find(b"FACP").expect("no FADT");
+    assert_eq!(fadt.signature(), b"FACP");
+    assert!(fadt.bytes().len() > HEADER_SIZE);
+    assert!(find(b"NONE").is_none());
+}
@@ -0,0 +1,116 @@
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+use super::linked_list::LinkedListAllocator;
+
+// Each size is also the block's alignment, so they need to be powers of two
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct BlockNode {
+    next: *mut BlockNode,
+}
+
+/// Small allocations come from per-size lists of freed blocks, which is
+/// quick and doesn't fragment. Lists are filled from the linked-list
+/// allocator, which also takes anything bigger than the largest block.
+pub struct FixedSizeBlockAllocator {
+    heads: [*mut BlockNode; BLOCK_SIZES.len()],
+    fallback: LinkedListAllocator,
+}
+
+// The lists only ever point into the heap the allocator was given
+unsafe impl Send for FixedSizeBlockAllocator {}
+
+// The smallest block size that fits the layout, if any does
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required)
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> FixedSizeBlockAllocator {
+        FixedSizeBlockAllocator {
+            heads: [ptr::null_mut(); BLOCK_SIZES.len()],
+            fallback: LinkedListAllocator::new(),
+        }
+    }
+
+    /// # Safety
+    /// Same as `LinkedListAllocator::init`.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback.init(heap_start, heap_size);
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => {
+                let head = self.heads[index];
+                if head.is_null() {
+                    let size = BLOCK_SIZES[index];
+                    // Sizes are powers of two, so this can't fail
+                    let block = Layout::from_size_align(size, size).unwrap();
+                    return self.fallback.allocate(block);
+                }
+                unsafe { self.heads[index] = (*head).next };
+                head as *mut u8
+            }
+            None => self.fallback.allocate(layout),
+        }
+    }
+
+    /// # Safety
+    /// `ptr` must have come from `allocate` with the same `layout`.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                // Every block is big and aligned enough to hold a node
+                debug_assert!(BLOCK_SIZES[index] >= mem::size_of::<BlockNode>());
+                let node = ptr as *mut BlockNode;
+                node.write(BlockNode { next: self.heads[index] });
+                self.heads[index] = node;
+            }
+            None => self.fallback.deallocate(ptr, layout),
+        }
+    }
+
+    /// Bytes not handed out: cached blocks plus the fallback's free regions
+    pub fn free_bytes(&self) -> usize {
+        let mut bytes = self.fallback.free().0;
+        for (head, size) in self.heads.iter().zip(BLOCK_SIZES) {
+            let mut current = *head;
+            while !current.is_null() {
+                bytes += size;
+                current = unsafe { (*current).next };
+            }
+        }
+        bytes
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_freed_blocks_are_reused() {
+    #[repr(align(16))]
+    struct Arena([u8; 4096]);
+    let mut arena = Arena([0; 4096]);
+    let mut allocator = FixedSizeBlockAllocator::new();
+    unsafe { allocator.init(arena.0.as_mut_ptr() as usize, arena.0.len()) };
+
+    let small = Layout::from_size_align(24, 8).unwrap();
+    let first = allocator.allocate(small);
+    assert!(!first.is_null());
+    assert_eq!(first as usize % 32, 0);
+    unsafe { allocator.deallocate(first, small) };
+    assert_eq!(allocator.allocate(small), first);
+
+    // Too big for any block list
+    let large = Layout::from_size_align(3000, 8).unwrap();
+    let block = allocator.allocate(large);
+    assert!(!block.is_null());
+    unsafe {
+        allocator.deallocate(block, large);
+        allocator.deallocate(first, small);
+    }
+    assert_eq!(allocator.free_bytes(), 4096);
+}
@@ -0,0 +1,180 @@
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+use super::align_up;
+
+// Header written at the start of every free region
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+impl ListNode {
+    fn start(&self) -> usize {
+        self as *const ListNode as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.size
+    }
+}
+
+const NODE_SIZE: usize = mem::size_of::<ListNode>();
+
+/// First-fit allocator over a list of free regions kept in address order.
+/// Neighbouring regions are merged when memory is freed, so the heap doesn't
+/// splinter into pieces too small to use.
+pub struct LinkedListAllocator {
+    // Dummy node whose `next` is the first free region
+    head: ListNode,
+}
+
+// The list only ever points into the heap the allocator was given
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    pub const fn new() -> LinkedListAllocator {
+        LinkedListAllocator {
+            head: ListNode {
+                size: 0,
+                next: ptr::null_mut(),
+            },
+        }
+    }
+
+    /// # Safety
+    /// `heap_start..heap_start + heap_size` must be unused memory that stays
+    /// valid for as long as the allocator does, and this may only be called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        let start = align_up(heap_start, mem::align_of::<ListNode>());
+        let size = (heap_start + heap_size).saturating_sub(start);
+        if size >= NODE_SIZE {
+            self.add_free_region(start, size);
+        }
+    }
+
+    // Insert in address order, merging with the regions either side
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        debug_assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        debug_assert!(size >= NODE_SIZE);
+
+        let head: *mut ListNode = &mut self.head;
+        let mut prev = head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+            prev = (*prev).next;
+        }
+
+        let next = (*prev).next;
+        let node = addr as *mut ListNode;
+        node.write(ListNode { size, next });
+        if !next.is_null() && (*node).end() == next as usize {
+            (*node).size += (*next).size;
+            (*node).next = (*next).next;
+        }
+
+        if prev != head && (*prev).end() == addr {
+            (*prev).size += (*node).size;
+            (*prev).next = (*node).next;
+        } else {
+            (*prev).next = node;
+        }
+    }
+
+    // Where in `region` an allocation would start, if it fits. Leftovers on
+    // either side have to be big enough to hold a node of their own.
+    fn fit(region: &ListNode, size: usize, align: usize) -> Option<usize> {
+        let mut start = align_up(region.start(), align);
+        if start != region.start() && start - region.start() < NODE_SIZE {
+            start = align_up(region.start() + NODE_SIZE, align);
+        }
+        let end = start.checked_add(size)?;
+        if end > region.end() {
+            return None;
+        }
+        let excess = region.end() - end;
+        if excess > 0 && excess < NODE_SIZE {
+            return None;
+        }
+        Some(start)
+    }
+
+    // Round the layout up so whatever's freed can hold a node
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        (layout.size().max(NODE_SIZE), layout.align())
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = LinkedListAllocator::size_align(layout);
+        unsafe {
+            let mut prev: *mut ListNode = &mut self.head;
+            while !(*prev).next.is_null() {
+                let region = (*prev).next;
+                if let Some(start) = LinkedListAllocator::fit(&*region, size, align) {
+                    let (region_start, region_end) = ((*region).start(), (*region).end());
+                    (*prev).next = (*region).next;
+                    if start > region_start {
+                        self.add_free_region(region_start, start - region_start);
+                    }
+                    if start + size < region_end {
+                        self.add_free_region(start + size, region_end - (start + size));
+                    }
+                    return start as *mut u8;
+                }
+                prev = region;
+            }
+        }
+        ptr::null_mut()
+    }
+
+    /// # Safety
+    /// `ptr` must have come from `allocate` with the same `layout`.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+
+    /// Bytes in free regions, and how many regions there are
+    pub fn free(&self) -> (usize, usize) {
+        let (mut bytes, mut regions) = (0, 0);
+        let mut current = self.head.next;
+        while !current.is_null() {
+            unsafe {
+                bytes += (*current).size;
+                current = (*current).next;
+            }
+            regions += 1;
+        }
+        (bytes, regions)
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_freed_neighbours_merge() {
+    #[repr(align(16))]
+    struct Arena([u8; 1024]);
+    let mut arena = Arena([0; 1024]);
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(arena.0.as_mut_ptr() as usize, arena.0.len()) };
+    assert_eq!(allocator.free(), (1024, 1));
+
+    let layout = Layout::from_size_align(200, 8).unwrap();
+    let blocks = [allocator.allocate(layout), allocator.allocate(layout), allocator.allocate(layout)];
+    assert!(blocks.iter().all(|block| !block.is_null()));
+    // Too big for what's left
+    assert!(allocator.allocate(Layout::from_size_align(600, 8).unwrap()).is_null());
+
+    // Free out of order; the regions should still come back together
+    unsafe {
+        allocator.deallocate(blocks[0], layout);
+        allocator.deallocate(blocks[2], layout);
+        allocator.deallocate(blocks[1], layout);
+    }
+    assert_eq!(allocator.free(), (1024, 1));
+    assert!(!allocator.allocate(Layout::from_size_align(1024, 8).unwrap()).is_null());
+}
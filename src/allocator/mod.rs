@@ -0,0 +1,76 @@
+use core::alloc::{GlobalAlloc, Layout};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+use crate::memory::{self, paging};
+use fixed_size_block::FixedSizeBlockAllocator;
+
+pub mod fixed_size_block;
+pub mod linked_list;
+
+/// Where the kernel heap lives, well away from anything the bootloader maps
+pub const HEAP_START: usize = 0x_4444_0000_0000;
+pub const HEAP_SIZE: usize = 256 * 1024;
+
+/// An allocator behind a lock, so it can be used as the global one
+pub struct Locked<A> {
+    inner: Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Locked<A> {
+        Locked { inner: Mutex::new(inner) }
+    }
+}
+
+// Locked with interrupts off, so a handler that allocates can't find it held
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "fault-inject")]
+        if crate::fault::should_fail(crate::fault::Point::Alloc) {
+            return core::ptr::null_mut();
+        }
+        let ptr = interrupts::without_interrupts(|| self.inner.lock().allocate(layout));
+        #[cfg(feature = "alloc-trace")]
+        crate::alloctrace::record(crate::alloctrace::Event::Alloc, ptr, layout);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "kasan")]
+        crate::kasan::unpoison(ptr as u64, layout.size() as u64);
+        interrupts::without_interrupts(|| self.inner.lock().deallocate(ptr, layout));
+        #[cfg(feature = "alloc-trace")]
+        crate::alloctrace::record(crate::alloctrace::Event::Free, ptr, layout);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+
+/// Map the heap's pages and hand them to the allocator. Needs `memory::init`
+/// to have run; until this has, every allocation fails.
+pub fn init_heap() -> Result<(), &'static str> {
+    let start = Page::containing_address(VirtAddr::new(HEAP_START as u64));
+    let end = Page::containing_address(VirtAddr::new((HEAP_START + HEAP_SIZE - 1) as u64));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in Page::range_inclusive(start, end) {
+        let frame = memory::allocate_frame().ok_or("out of memory for the heap")?;
+        // The heap range is ours alone, and the frame is fresh
+        unsafe { paging::map_page(page, frame, flags)? };
+    }
+
+    interrupts::without_interrupts(|| unsafe { ALLOCATOR.inner.lock().init(HEAP_START, HEAP_SIZE) });
+    Ok(())
+}
+
+/// Heap bytes not currently allocated
+pub fn free_bytes() -> usize {
+    interrupts::without_interrupts(|| ALLOCATOR.inner.lock().free_bytes())
+}
+
+// Round `addr` up to a multiple of `align`, which must be a power of two
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,218 @@
+//! Allocation tracing: while it's on, every heap allocation and free goes
+//! into the log ring at trace level, with its size, alignment and call
+//! site, and is counted against that site, for `alloctrace` to say which
+//! sites allocate most and how fast. It's off until `alloctrace on`.
+//!
+//! The call site is the first frame up the stack that isn't the allocator
+//! or alloc's and core's own code, so a `Vec::push` counts against whoever
+//! pushed. Finding it needs the symbol table filled in; without it, every
+//! allocation's site is unknown.
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::backtrace::Backtrace;
+use crate::log::{self, Level};
+use crate::time::{Duration, Instant};
+use crate::{kptr, symbols};
+
+const MAX_SITES: usize = 64;
+// Frames that are the allocator's plumbing, not its callers
+const PLUMBING: [&str; 8] = [
+    "heorot::allocator", "<heorot::allocator", "heorot::alloctrace", "__rust", "alloc::", "<alloc::", "core::",
+    "<core::",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Alloc,
+    Free,
+}
+
+/// What's been counted against one call site, 0 for an unknown one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Site {
+    pub address: u64,
+    pub allocs: u64,
+    pub frees: u64,
+    /// Allocated, not counting what's been freed
+    pub bytes: u64,
+}
+
+struct Sites {
+    sites: [Option<Site>; MAX_SITES],
+    // Events at sites there wasn't room for
+    untracked: u64,
+}
+
+static ON: AtomicBool = AtomicBool::new(false);
+// Only ever locked with interrupts off, so a handler can't find it held.
+// Nothing allocates while it is.
+static SITES: Mutex<Sites> = Mutex::new(Sites { sites: [None; MAX_SITES], untracked: 0 });
+// How long it's been on before, and since when if it is now. Only ever
+// locked with interrupts off.
+static TRACED: Mutex<(Duration, Option<Instant>)> = Mutex::new((Duration::from_secs(0), None));
+
+/// Start or stop tracing; starting again keeps the counts
+pub fn set(on: bool) {
+    interrupts::without_interrupts(|| {
+        let mut traced = TRACED.lock();
+        match (on, traced.1) {
+            (true, None) => traced.1 = Some(Instant::now()),
+            (false, Some(since)) => *traced = (traced.0 + since.elapsed(), None),
+            _ => {}
+        }
+        ON.store(on, Ordering::Relaxed);
+    });
+}
+
+pub fn on() -> bool {
+    ON.load(Ordering::Relaxed)
+}
+
+/// Forget every site, and time rates from now
+pub fn clear() {
+    interrupts::without_interrupts(|| {
+        *SITES.lock() = Sites { sites: [None; MAX_SITES], untracked: 0 };
+        let mut traced = TRACED.lock();
+        *traced = (Duration::from_secs(0), traced.1.map(|_| Instant::now()));
+    });
+}
+
+// The innermost return address outside PLUMBING
+fn call_site(trace: &Backtrace) -> u64 {
+    let caller = |address: &u64| match symbols::resolve(address.wrapping_sub(1)) {
+        Some((name, _)) => !PLUMBING.iter().any(|prefix| name.starts_with(prefix)),
+        None => false,
+    };
+    trace.frames().iter().copied().find(caller).unwrap_or(0)
+}
+
+// From the global allocator, once `ptr` has been allocated or freed
+pub(crate) fn record(event: Event, ptr: *mut u8, layout: Layout) {
+    if !on() || ptr.is_null() {
+        return;
+    }
+    let site = call_site(&Backtrace::capture());
+    let (name, offset) = symbols::resolve(site.wrapping_sub(1)).map_or(("?", 0), |(name, offset)| (name, offset + 1));
+    let what = match event {
+        Event::Alloc => "alloc",
+        Event::Free => "free",
+    };
+    log::keep(Level::Trace, module_path!(), format_args!("{} {} {} bytes, align {}, from {}+{:#x}", what,
+        kptr::ptr(ptr as u64), layout.size(), layout.align(), name, offset));
+
+    interrupts::without_interrupts(|| {
+        let mut sites = SITES.lock();
+        let found = sites.sites.iter().position(|slot| slot.map_or(true, |slot| slot.address == site));
+        let slot = match found {
+            Some(index) => &mut sites.sites[index],
+            None => {
+                sites.untracked += 1;
+                return;
+            }
+        };
+        let counts = slot.get_or_insert(Site { address: site, allocs: 0, frees: 0, bytes: 0 });
+        match event {
+            Event::Alloc => {
+                counts.allocs += 1;
+                counts.bytes += layout.size() as u64;
+            }
+            Event::Free => {
+                counts.frees += 1;
+                counts.bytes = counts.bytes.saturating_sub(layout.size() as u64);
+            }
+        }
+    });
+}
+
+/// Every site counted, most allocations first, and the events at sites
+/// there wasn't room for
+pub fn sites() -> ([Option<Site>; MAX_SITES], u64) {
+    let (mut sites, untracked) = interrupts::without_interrupts(|| {
+        let sites = SITES.lock();
+        (sites.sites, sites.untracked)
+    });
+    sites.sort_by_key(|site| core::cmp::Reverse(site.map_or(0, |site| site.allocs)));
+    (sites, untracked)
+}
+
+/// How long it's been on for, since the last clear
+pub fn traced() -> Duration {
+    let (before, since) = interrupts::without_interrupts(|| *TRACED.lock());
+    before + since.map_or(Duration::from_secs(0), |since| since.elapsed())
+}
+
+#[cfg(feature = "shell")]
+fn cmd_alloctrace(args: &[&str]) -> crate::shell::Status {
+    use crate::println;
+    use crate::shell::{FAILURE, SUCCESS};
+
+    match args {
+        [] => {}
+        ["on"] => {
+            set(true);
+            return SUCCESS;
+        }
+        ["off"] => {
+            set(false);
+            return SUCCESS;
+        }
+        ["clear"] => {
+            clear();
+            return SUCCESS;
+        }
+        _ => {
+            println!("usage: alloctrace [on | off | clear]");
+            return FAILURE;
+        }
+    }
+    let traced = traced();
+    let (sites, untracked) = sites();
+    println!("alloctrace: {}, on for {:?}; {} at sites not counted", if on() { "on" } else { "off" }, traced,
+        untracked);
+    println!("{:>8} {:>8} {:>8} {:>10}  SITE", "ALLOCS", "PER-SEC", "FREES", "LIVE");
+    for site in sites.iter().flatten() {
+        let rate = (u128::from(site.allocs) * 1000).checked_div(traced.as_millis()).unwrap_or(0);
+        let name = symbols::resolve(site.address.wrapping_sub(1)).map_or("?", |(name, _)| name);
+        println!("{:>8} {:>8} {:>8} {:>10}  {}", site.allocs, rate, site.frees, site.bytes, name);
+    }
+    SUCCESS
+}
+
+pub fn init() {
+    #[cfg(feature = "shell")]
+    crate::shell::register(crate::shell::Command {
+        name: "alloctrace",
+        help: "trace heap allocations into dmesg, and sum them up by call site: alloctrace [on | off | clear]",
+        run: cmd_alloctrace,
+        complete: None,
+    })
+    .expect("couldn't register alloctrace");
+}
+
+/// TESTS
+
+#[test_case]
+fn test_counts_by_site() {
+    use alloc::boxed::Box;
+
+    clear();
+    set(true);
+    // Kept from being optimized away, allocation and all
+    drop(core::hint::black_box(Box::new([0u8; 48])));
+    set(false);
+    let (sites, _) = sites();
+    let allocs: u64 = sites.iter().flatten().map(|site| site.allocs).sum();
+    let frees: u64 = sites.iter().flatten().map(|site| site.frees).sum();
+    assert!(allocs >= 1 && frees >= 1);
+    let contents = log::contents();
+    let text = core::str::from_utf8(&contents).unwrap();
+    assert!(text.contains("bytes, align"));
+
+    // Nothing while it's off
+    clear();
+    drop(core::hint::black_box(Box::new(1u64)));
+    assert!(sites().0.iter().all(Option::is_none));
+}
@@ -0,0 +1,495 @@
+//! The local APIC and IO-APICs, taking over from the 8259s on machines whose
+//! MADT lists them. Legacy IRQs keep the vectors the PICs gave them
+//! (PIC_1_OFFSET + line), so their handlers don't care which is delivering,
+//! and the line masks the PICs had carry over. The local APIC's timer takes
+//! over the tick from PIT channel 0 on the timer's vector, calibrated
+//! against PIT channel 2.
+//!
+//! Registers are reached through the kernel's mapping of physical memory,
+//! mapped into it uncached where it doesn't reach that high. Serial isn't
+//! interrupt-driven, so only the lines the PICs let through get unmasked.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::cpu::{self, Feature};
+use crate::device::{self, State};
+use crate::interrupts::{InterruptIndex, PICS, PIC_1_OFFSET};
+use crate::memory::paging;
+use crate::msr;
+use crate::resource::{self, Resource};
+use crate::time::{self, Duration, TICK_HZ};
+use crate::acpi;
+
+/// Where the local APIC sends interrupts that went away before delivery
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const MAX_IO_APICS: usize = 4;
+const MAX_OVERRIDES: usize = 16;
+const MAX_CPUS: usize = 64;
+const IO_APIC_NAMES: [&str; MAX_IO_APICS] = ["ioapic0", "ioapic1", "ioapic2", "ioapic3"];
+
+// MADT entries, after its header, local APIC address, and flags
+const MADT_ENTRIES: usize = acpi::HEADER_SIZE + 8;
+const MADT_PCAT_COMPAT: u32 = 1;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_OVERRIDE: u8 = 2;
+const MADT_ADDRESS_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_ENABLED: u32 = 1;
+const MADT_ONLINE_CAPABLE: u32 = 2;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+mmio! {
+    /// The local APIC's registers, the ones used here
+    struct LocalApic {
+        id @ 0x20: ReadOnly<u32>,
+        task_priority @ 0x80: ReadWrite<u32> reserved 0xffff_ff00,
+        eoi @ 0xb0: WriteOnly<u32>,
+        spurious @ 0xf0: ReadWrite<u32> reserved 0xffff_ec00,
+        command_low @ 0x300: ReadWrite<u32> reserved 0xfff3_2000,
+        command_high @ 0x310: ReadWrite<u32> reserved 0x00ff_ffff,
+        timer @ 0x320: ReadWrite<u32> reserved 0xfff8_ef00,
+        timer_initial @ 0x380: ReadWrite<u32>,
+        timer_current @ 0x390: ReadOnly<u32>,
+        timer_divide @ 0x3e0: ReadWrite<u32> reserved 0xffff_fff4,
+    }
+}
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0x3;
+const DELIVERY_PENDING: u32 = 1 << 12;
+// The destination shorthand for every CPU but the sender
+const ALL_BUT_SELF: u32 = 0b11 << 18;
+
+mmio! {
+    /// An IO-APIC's registers are all reached through these two
+    struct IoApicRegisters {
+        select @ 0x00: ReadWrite<u32> reserved 0xffff_ff00,
+        window @ 0x10: ReadWrite<u32>,
+    }
+}
+
+const IO_VERSION: u32 = 0x01;
+const IO_REDIRECTION: u32 = 0x10;
+const MASKED: u32 = 1 << 16;
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+const ACTIVE_LOW: u32 = 1 << 13;
+
+// Where the local APIC's registers are; 0 while the PICs are in charge
+static LOCAL: AtomicU64 = AtomicU64::new(0);
+// The local APIC timer's count rate; 0 until calibrated
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(u32_at(bytes, offset)) | (u64::from(u32_at(bytes, offset + 4)) << 32)
+}
+
+/// An IO-APIC as the MADT describes it: its interrupt inputs are global
+/// system interrupts from `gsi_base` on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u64,
+    pub gsi_base: u32,
+}
+
+/// A legacy IRQ that doesn't arrive on the GSI of the same number, or not
+/// with ISA's edge-triggered, active-high signalling. `flags` are the MPS
+/// INTI flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Override {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+/// What the MADT says about the interrupt controllers
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic: u64,
+    /// Whether there are 8259s as well
+    pub pcat_compat: bool,
+    io_apics: [Option<IoApicEntry>; MAX_IO_APICS],
+    overrides: [Option<Override>; MAX_OVERRIDES],
+    // APIC IDs of the processors that are there or can be brought online
+    cpus: [Option<u32>; MAX_CPUS],
+}
+
+impl Madt {
+    /// The firmware's, if it has one
+    pub fn read() -> Result<Madt, &'static str> {
+        Madt::parse(acpi::find(b"APIC").ok_or("no MADT")?.bytes())
+    }
+
+    /// From the bytes of a MADT, header and all
+    pub fn parse(bytes: &[u8]) -> Result<Madt, &'static str> {
+        if bytes.len() < MADT_ENTRIES {
+            return Err("MADT is cut off");
+        }
+        let mut madt = Madt {
+            local_apic: u64::from(u32_at(bytes, acpi::HEADER_SIZE)),
+            pcat_compat: u32_at(bytes, acpi::HEADER_SIZE + 4) & MADT_PCAT_COMPAT != 0,
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_OVERRIDES],
+            cpus: [None; MAX_CPUS],
+        };
+        let mut at = MADT_ENTRIES;
+        while at + 2 <= bytes.len() {
+            let (kind, len) = (bytes[at], usize::from(bytes[at + 1]));
+            if len < 2 || at + len > bytes.len() {
+                return Err("MADT entry is cut off");
+            }
+            let entry = &bytes[at..at + len];
+            at += len;
+            let usable = |flags: u32| flags & (MADT_ENABLED | MADT_ONLINE_CAPABLE) != 0;
+            match kind {
+                MADT_LOCAL_APIC if len >= 8 && usable(u32_at(entry, 4)) => madt.add_cpu(u32::from(entry[3])),
+                MADT_LOCAL_X2APIC if len >= 16 && usable(u32_at(entry, 8)) => madt.add_cpu(u32_at(entry, 4)),
+                MADT_IO_APIC if len >= 12 => {
+                    let io_apic = IoApicEntry { id: entry[2], address: u64::from(u32_at(entry, 4)),
+                        gsi_base: u32_at(entry, 8) };
+                    let slot = madt.io_apics.iter_mut().find(|slot| slot.is_none()).ok_or("too many IO-APICs")?;
+                    *slot = Some(io_apic);
+                }
+                MADT_OVERRIDE if len >= 10 => {
+                    let irq_override = Override { irq: entry[3], gsi: u32_at(entry, 4), flags: u16_at(entry, 8) };
+                    // Bus 0 is ISA, the only one there is
+                    if entry[2] == 0 {
+                        let slot = madt.overrides.iter_mut().find(|slot| slot.is_none())
+                            .ok_or("too many interrupt source overrides")?;
+                        *slot = Some(irq_override);
+                    }
+                }
+                MADT_ADDRESS_OVERRIDE if len >= 12 => madt.local_apic = u64_at(entry, 4),
+                _ => {}
+            }
+        }
+        Ok(madt)
+    }
+
+    fn add_cpu(&mut self, apic_id: u32) {
+        // Any more than that just don't get used
+        if let Some(slot) = self.cpus.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(apic_id);
+        }
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApicEntry> + '_ {
+        self.io_apics.iter().flatten().copied()
+    }
+
+    /// APIC IDs of the processors
+    pub fn cpus(&self) -> impl Iterator<Item = u32> + '_ {
+        self.cpus.iter().flatten().copied()
+    }
+
+    /// The GSI legacy IRQ `irq` comes in on, and its INTI flags
+    pub fn route(&self, irq: u8) -> (u32, u16) {
+        self.overrides
+            .iter()
+            .flatten()
+            .find(|irq_override| irq_override.irq == irq)
+            .map_or((u32::from(irq), 0), |irq_override| (irq_override.gsi, irq_override.flags))
+    }
+}
+
+// The redirection entry's low half for a legacy IRQ, unmasked
+fn redirection(irq: u8, flags: u16) -> u32 {
+    let mut low = u32::from(PIC_1_OFFSET + irq);
+    // 0b11 is active low and level triggered; anything else is ISA's way
+    if flags & 0b11 == 0b11 {
+        low |= ACTIVE_LOW;
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        low |= LEVEL_TRIGGERED;
+    }
+    low
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    registers: IoApicRegisters,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.registers.select().write(register);
+        self.registers.window().read()
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.registers.select().write(register);
+        self.registers.window().write(value);
+    }
+
+    // Destination first, so the entry's never live with a stale one
+    fn redirect(&self, pin: u32, low: u32, destination: u32) {
+        self.write(IO_REDIRECTION + 2 * pin + 1, destination << 24);
+        self.write(IO_REDIRECTION + 2 * pin, low);
+    }
+}
+
+struct Routing {
+    madt: Madt,
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    // The local APIC everything's sent to
+    destination: u32,
+}
+
+impl Routing {
+    fn route(&self, irq: u8, masked: bool) -> Result<(), &'static str> {
+        let (gsi, flags) = self.madt.route(irq);
+        let io_apic = self
+            .io_apics
+            .iter()
+            .flatten()
+            .find(|io_apic| (io_apic.gsi_base..io_apic.gsi_base + io_apic.pins).contains(&gsi))
+            .ok_or("no IO-APIC has that line")?;
+        let low = redirection(irq, flags) | if masked { MASKED } else { 0 };
+        io_apic.redirect(gsi - io_apic.gsi_base, low, self.destination);
+        Ok(())
+    }
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static ROUTING: Mutex<Option<Routing>> = Mutex::new(None);
+
+fn local() -> LocalApic {
+    unsafe { LocalApic::new(LOCAL.load(Ordering::Relaxed)) }
+}
+
+/// Whether the APICs have taken over from the PICs
+pub fn enabled() -> bool {
+    LOCAL.load(Ordering::Relaxed) != 0
+}
+
+/// This CPU's local APIC ID
+pub fn local_id() -> u32 {
+    local().id().read() >> 24
+}
+
+/// Send an inter-processor interrupt: `command` is the low half of the
+/// interrupt command register, saying what kind and with which vector
+pub(crate) fn send_ipi(apic_id: u32, command: u32) {
+    local().command_high().write(apic_id << 24);
+    local().command_low().write(command);
+    while local().command_low().read() & DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Send an inter-processor interrupt to every CPU but this one
+pub(crate) fn broadcast_ipi(command: u32) {
+    local().command_high().write(0);
+    local().command_low().write(command | ALL_BUT_SELF);
+    while local().command_low().read() & DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+// Turn on this CPU's local APIC, taking spurious interrupts to their vector
+fn enable_local() -> Result<(), &'static str> {
+    // Only the enable bit changes; the registers stay where they are
+    unsafe { msr::APIC_BASE.update(|base| base | APIC_BASE_ENABLE)? };
+    local().task_priority().write(0);
+    local().spurious().write(SPURIOUS_ENABLE | u32::from(SPURIOUS_VECTOR));
+    Ok(())
+}
+
+/// Set up an application processor's local APIC; its registers are at the
+/// same address as the boot CPU's. Nothing is routed to it.
+pub(crate) fn init_ap() -> Result<(), &'static str> {
+    enable_local()
+}
+
+/// Acknowledge the interrupt being handled
+pub(crate) fn end_of_interrupt() {
+    local().eoi().write(0);
+}
+
+/// Let legacy IRQ `irq` through the IO-APIC
+pub(crate) fn unmask_irq(irq: u8) {
+    interrupts::without_interrupts(|| {
+        if let Some(routing) = ROUTING.lock().as_ref() {
+            // One the firmware didn't wire up can't fire anyway
+            let _ = routing.route(irq, false);
+        }
+    });
+}
+
+/// Whether the local APIC's timer is ticking in place of the PIT
+pub(crate) fn timer_enabled() -> bool {
+    TIMER_HZ.load(Ordering::Relaxed) != 0
+}
+
+/// (Re)start the tick
+pub(crate) fn timer_periodic() {
+    local().timer().write(u32::from(InterruptIndex::Timer.as_u8()) | TIMER_PERIODIC);
+    local().timer_initial().write((TIMER_HZ.load(Ordering::Relaxed) / TICK_HZ).max(1) as u32);
+}
+
+/// Replace the tick with a single interrupt `delay` from now
+pub(crate) fn timer_oneshot(delay: Duration) {
+    let count = delay.as_nanos() * u128::from(TIMER_HZ.load(Ordering::Relaxed)) / 1_000_000_000;
+    local().timer().write(u32::from(InterruptIndex::Timer.as_u8()));
+    local().timer_initial().write(count.max(1).min(u128::from(u32::MAX)) as u32);
+}
+
+/// How long since the timer last fired (or was started)
+#[cfg(feature = "irq-latency")]
+pub(crate) fn timer_elapsed() -> Duration {
+    let counted = local().timer_initial().read().saturating_sub(local().timer_current().read());
+    Duration::from_nanos(u64::from(counted) * 1_000_000_000 / TIMER_HZ.load(Ordering::Relaxed).max(1))
+}
+
+// Registers at `phys`, through the map of all physical memory, mapping
+// them there uncached if it doesn't reach that far
+fn registers(phys: u64) -> Result<VirtAddr, &'static str> {
+    let virt = paging::phys_to_virt(PhysAddr::new(phys));
+    if paging::translate_addr(virt).is_none() {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        unsafe { paging::map_page(Page::containing_address(virt), PhysFrame::containing_address(PhysAddr::new(phys)), flags)? };
+    }
+    Ok(virt)
+}
+
+// Count the timer down from the top through a calibration window
+fn calibrate_timer() -> u64 {
+    local().timer().write(MASKED);
+    local().timer_divide().write(DIVIDE_BY_16);
+    let window = time::calibration_window(|| local().timer_initial().write(u32::MAX));
+    let counted = u32::MAX - local().timer_current().read();
+    local().timer_initial().write(0);
+    (u128::from(counted) * 1_000_000_000 / window.as_nanos()) as u64
+}
+
+fn register_device(name: &'static str, description: &'static str, address: u64) {
+    if let Ok(id) = device::register(name, description, Some(device::platform())) {
+        let state = match resource::claim(id, Resource::Mmio { start: address, length: 0x1000 }) {
+            Ok(()) => State::Active,
+            Err(_) => State::Failed,
+        };
+        device::bind(id, "apic", state);
+    }
+}
+
+/// Switch from the PICs to the APICs, if the MADT says there are some.
+/// Needs memory::init, for the ACPI tables. On an error the PICs stay in
+/// charge, just as they were.
+pub fn init() -> Result<(), &'static str> {
+    if enabled() {
+        return Ok(());
+    }
+    if !cpu::has_feature(Feature::Apic) {
+        return Err("no local APIC");
+    }
+    let madt = Madt::read()?;
+    if madt.io_apics().next().is_none() {
+        return Err("no IO-APIC");
+    }
+    let local = registers(madt.local_apic)?;
+    let mut io_apics = [None; MAX_IO_APICS];
+    for (slot, entry) in io_apics.iter_mut().zip(madt.io_apics()) {
+        let registers = unsafe { IoApicRegisters::new(registers(entry.address)?.as_u64()) };
+        let mut io_apic = IoApic { registers, gsi_base: entry.gsi_base, pins: 0 };
+        io_apic.pins = ((io_apic.read(IO_VERSION) >> 16) & 0xff) + 1;
+        *slot = Some(io_apic);
+    }
+
+    interrupts::without_interrupts(|| {
+        LOCAL.store(local.as_u64(), Ordering::Relaxed);
+        if let Err(message) = enable_local() {
+            LOCAL.store(0, Ordering::Relaxed);
+            return Err(message);
+        }
+
+        let routing = Routing { madt: madt.clone(), io_apics, destination: local_id() };
+        // Nothing the firmware left behind gets through
+        for io_apic in routing.io_apics.iter().flatten() {
+            for pin in 0..io_apic.pins {
+                io_apic.redirect(pin, MASKED, 0);
+            }
+        }
+        // The lines that were let through on the PICs are on the IO-APIC
+        // instead, bar the timer's and the cascade
+        let mut pics = PICS.lock();
+        let [primary, secondary] = unsafe { pics.read_masks() };
+        let pic_masks = u16::from(primary) | (u16::from(secondary) << 8);
+        for irq in 1..16 {
+            if irq != 2 {
+                let _ = routing.route(irq, pic_masks & (1 << irq) != 0);
+            }
+        }
+        unsafe { pics.write_masks(0xff, 0xff) };
+        drop(pics);
+        *ROUTING.lock() = Some(routing);
+
+        time::stop_channel0();
+        TIMER_HZ.store(calibrate_timer(), Ordering::Relaxed);
+        time::program_periodic();
+        Ok(())
+    })?;
+
+    register_device("lapic", "local APIC", madt.local_apic);
+    for (entry, name) in madt.io_apics().zip(IO_APIC_NAMES.iter()) {
+        register_device(name, "IO-APIC", entry.address);
+    }
+    Ok(())
+}
+
+/// TESTS
+
+#[test_case]
+fn test_madt_parses_controllers_and_overrides() {
+    let mut madt = alloc::vec![0u8; MADT_ENTRIES];
+    madt[acpi::HEADER_SIZE..acpi::HEADER_SIZE + 4].copy_from_slice(&0xfee0_0000u32.to_le_bytes());
+    madt[acpi::HEADER_SIZE + 4] = MADT_PCAT_COMPAT as u8;
+    // Two processors, one disabled
+    madt.extend_from_slice(&[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+    madt.extend_from_slice(&[MADT_LOCAL_APIC, 8, 1, 1, 0, 0, 0, 0]);
+    madt.extend_from_slice(&[MADT_IO_APIC, 12, 2, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0]);
+    // The PIT's IRQ 0 on GSI 2, and IRQ 9 level triggered and active low
+    madt.extend_from_slice(&[MADT_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+    madt.extend_from_slice(&[MADT_OVERRIDE, 10, 0, 9, 9, 0, 0, 0, 0x0f, 0]);
+    let madt = Madt::parse(&madt).unwrap();
+
+    assert_eq!(madt.local_apic, 0xfee0_0000);
+    assert!(madt.pcat_compat);
+    assert_eq!(madt.cpus().collect::<alloc::vec::Vec<_>>(), [0]);
+    assert_eq!(madt.io_apics().next(), Some(IoApicEntry { id: 2, address: 0xfec0_0000, gsi_base: 0 }));
+    assert_eq!(madt.route(0), (2, 0));
+    assert_eq!(madt.route(1), (1, 0));
+    assert_eq!(redirection(9, madt.route(9).1), u32::from(PIC_1_OFFSET + 9) | ACTIVE_LOW | LEVEL_TRIGGERED);
+    assert_eq!(Madt::parse(&[0; MADT_ENTRIES - 1]).err(), Some("MADT is cut off"));
+}
+
+#[test_case]
+fn test_timer_ticks_either_way() {
+    // Whichever of the PIT and the local APIC is ticking
+    let before = time::ticks();
+    let start = time::Instant::now();
+    while start.elapsed() < Duration::from_millis(5) {
+        x86_64::instructions::hlt();
+    }
+    assert!(time::ticks() > before);
+    if enabled() {
+        assert!(timer_enabled());
+        assert!(Madt::read().unwrap().cpus().next().is_some());
+    }
+}
@@ -0,0 +1,110 @@
+//! Stack walking by frame pointer. The kernel is built with frame pointers
+//! forced on (see .cargo/config.toml), so each frame starts with the
+//! caller's rbp followed by the return address.
+
+use core::arch::asm;
+use core::fmt;
+use x86_64::VirtAddr;
+use crate::{kptr, symbols};
+use crate::memory::paging;
+
+pub const MAX_FRAMES: usize = 16;
+
+/// Return addresses, innermost first
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+// Whether the frame record at `rbp` can be read without faulting
+fn readable(rbp: u64) -> bool {
+    let mapped = |address| VirtAddr::try_new(address).map_or(false, |address| paging::translate_addr(address).is_some());
+    rbp != 0 && rbp % 8 == 0 && mapped(rbp) && mapped(rbp + 15)
+}
+
+impl Backtrace {
+    /// How the caller got to where it is
+    #[inline(never)]
+    pub fn capture() -> Backtrace {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        // Our own frame is live and well-formed
+        unsafe { Backtrace::from_frame(None, rbp) }
+    }
+
+    /// Walk a stack from frame pointer `rbp`, starting with `pc` if given
+    /// (for a stack that's switched out, where it stopped).
+    ///
+    /// # Safety
+    /// Nothing may be running on the stack meanwhile. Frames that aren't
+    /// mapped, or don't lead up the stack, end the walk, but a stack that
+    /// isn't one at all can still give nonsense.
+    pub unsafe fn from_frame(pc: Option<u64>, mut rbp: u64) -> Backtrace {
+        let mut trace = Backtrace { frames: [0; MAX_FRAMES], len: 0 };
+        if let Some(pc) = pc {
+            trace.push(pc);
+        }
+        while trace.len < MAX_FRAMES && readable(rbp) {
+            let record = rbp as *const u64;
+            let (caller_rbp, return_address) = (record.read(), record.add(1).read());
+            if return_address == 0 {
+                break;
+            }
+            trace.push(return_address);
+            // Callers' frames are always further up the stack
+            if caller_rbp <= rbp {
+                break;
+            }
+            rbp = caller_rbp;
+        }
+        trace
+    }
+
+    fn push(&mut self, address: u64) {
+        if self.len < MAX_FRAMES {
+            self.frames[self.len] = address;
+            self.len += 1;
+        }
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+// One frame per line, with the function's name when the symbol table has
+// been filled in; addresses are hashed unless kptr hashing is off
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (depth, &address) in self.frames().iter().enumerate() {
+            write!(f, "  #{:<2} {}", depth, kptr::ptr(address))?;
+            // Return addresses point past the call, which may be the last
+            // instruction of its function
+            match symbols::resolve(address.wrapping_sub(1)) {
+                Some((name, offset)) => writeln!(f, " {}+{:#x}", name, offset + 1)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_capture_walks_callers() {
+    #[inline(never)]
+    fn inner() -> Backtrace {
+        Backtrace::capture()
+    }
+    #[inline(never)]
+    fn outer() -> Backtrace {
+        inner()
+    }
+
+    let trace = outer();
+    // inner's return into outer, outer's into this test, and on up
+    assert!(trace.frames().len() >= 3);
+    assert!(trace.frames().iter().all(|&address| address != 0));
+}
@@ -0,0 +1,30 @@
+use spin::Mutex;
+use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+/// Enough for a whole screen of text, a newline after every row
+pub const CLIPBOARD_MAX: usize = BUFFER_HEIGHT * (BUFFER_WIDTH + 1);
+
+struct Clipboard {
+    bytes: [u8; CLIPBOARD_MAX],
+    len: usize,
+}
+
+// Never touched by interrupt handlers
+static CLIPBOARD: Mutex<Clipboard> = Mutex::new(Clipboard {
+    bytes: [0; CLIPBOARD_MAX],
+    len: 0,
+});
+
+/// Replace the clipboard's contents; anything past CLIPBOARD_MAX is cut off
+pub fn copy(bytes: &[u8]) {
+    let mut clipboard = CLIPBOARD.lock();
+    let len = bytes.len().min(CLIPBOARD_MAX);
+    clipboard.bytes[..len].copy_from_slice(&bytes[..len]);
+    clipboard.len = len;
+}
+
+/// Call `f` with what's on the clipboard
+pub fn with_contents<R>(f: impl FnOnce(&[u8]) -> R) -> R {
+    let clipboard = CLIPBOARD.lock();
+    f(&clipboard.bytes[..clipboard.len])
+}
@@ -0,0 +1,58 @@
+//! The kernel command line. Bootloader 0.9 has no way to pass one, so it's
+//! baked in at build time: `HEOROT_CMDLINE="console=ttyS0" cargo run`.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const CMDLINE: &str = match option_env!("HEOROT_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+pub fn cmdline() -> &'static str {
+    CMDLINE
+}
+
+fn parse<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|word| match word.split_once('=') {
+            Some((name, value)) if name == key => Some(value),
+            None if word == key => Some(""),
+            _ => None,
+        })
+        .last()
+}
+
+/// The value given as `key=value`, or "" for a bare `key`. If it's given
+/// more than once, the last one counts.
+pub fn get(key: &str) -> Option<&'static str> {
+    parse(CMDLINE, key)
+}
+
+// 0 until the first lookup, then 1 for VGA or 2 for serial only
+static HEADLESS: AtomicU8 = AtomicU8::new(0);
+
+/// Whether the console is serial only (`console=ttyS0`), in which case
+/// nothing touches the VGA hardware at all. Cheap enough for every print.
+pub fn headless() -> bool {
+    match HEADLESS.load(Ordering::Relaxed) {
+        0 => {
+            let headless = get("console") == Some("ttyS0");
+            HEADLESS.store(if headless { 2 } else { 1 }, Ordering::Relaxed);
+            headless
+        }
+        state => state == 2,
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_parse() {
+    let cmdline = "console=tty0 quiet  console=ttyS0 empty=";
+    assert_eq!(parse(cmdline, "console"), Some("ttyS0"));
+    assert_eq!(parse(cmdline, "quiet"), Some(""));
+    assert_eq!(parse(cmdline, "empty"), Some(""));
+    assert_eq!(parse(cmdline, "missing"), None);
+    assert_eq!(parse("", "console"), None);
+}
@@ -0,0 +1,265 @@
+//! Where the print macros write to: the VGA text buffer, or text drawn into
+//! a pixel framebuffer when there is one. Both take the same ANSI escape
+//! sequences, so output looks the same on either (and on a serial terminal).
+
+use alloc::string::String;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const MAX_PARAMS: usize = 4;
+
+// A copy of what's printed while `capture` runs; only ever locked with
+// interrupts off, so a handler can't find it held
+static CAPTURED: Mutex<Option<String>> = Mutex::new(None);
+// Whether CAPTURED is worth locking, as printing mostly isn't captured
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+/// A text console that print! can target
+pub trait Console {
+    /// Write text, interpreting ANSI escape sequences
+    fn write_string(&mut self, s: &str);
+    /// Move the write position within the current row
+    fn set_column(&mut self, column: usize);
+    /// Blank the screen and start writing again from the bottom left
+    fn clear_screen(&mut self);
+}
+
+// So write_fmt can format straight into any console
+struct Adapter<'a>(&'a mut dyn Console);
+
+impl fmt::Write for Adapter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_string(s);
+        Ok(())
+    }
+}
+
+pub fn write_fmt(console: &mut dyn Console, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // Adapter never fails; an error could only come from a Display impl
+    let _ = Adapter(console).write_fmt(args);
+}
+
+/// Called by print! with everything printed, to keep a copy if capturing
+pub(crate) fn tee(args: fmt::Arguments) {
+    if !CAPTURING.load(Ordering::Acquire) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        if let Some(captured) = CAPTURED.lock().as_mut() {
+            let _ = fmt::Write::write_fmt(captured, args);
+        }
+    });
+}
+
+/// Run `f`, and return what it printed along with its result, e.g. for a
+/// test to check. It's still printed too. Escape sequences are kept as
+/// they were; moving the column and clearing the screen aren't kept. An
+/// enclosing capture gets what the inner one did, too.
+pub fn capture<R>(f: impl FnOnce() -> R) -> (R, String) {
+    let outer = interrupts::without_interrupts(|| CAPTURED.lock().replace(String::new()));
+    CAPTURING.store(true, Ordering::Release);
+    let result = f();
+    let inner = interrupts::without_interrupts(|| {
+        let mut captured = CAPTURED.lock();
+        let inner = captured.take().unwrap_or_default();
+        *captured = outer.map(|outer| outer + &inner);
+        CAPTURING.store(captured.is_some(), Ordering::Release);
+        inner
+    });
+    (result, inner)
+}
+
+/// Run `f` on the console output goes to: the framebuffer if one was set
+/// up, the VGA text buffer otherwise. Interrupts are off meanwhile.
+pub fn with_console<R>(f: impl FnOnce(&mut dyn Console) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut framebuffer = crate::framebuffer::CONSOLE.lock();
+        match framebuffer.as_mut() {
+            Some(console) => f(console),
+            None => f(&mut *crate::vga_buffer::WRITER.lock()),
+        }
+    })
+}
+
+/// For a panic, once the other CPUs are halted: free the locks printing
+/// takes, that one of them may have been halted holding. What it was in
+/// the middle of printing is cut off.
+///
+/// # Safety
+/// Nothing else may be running that could be holding them.
+pub(crate) unsafe fn break_locks() {
+    if crate::framebuffer::CONSOLE.try_lock().is_none() {
+        crate::framebuffer::CONSOLE.force_unlock();
+    }
+    if crate::vga_buffer::WRITER.try_lock().is_none() {
+        crate::vga_buffer::WRITER.force_unlock();
+    }
+    if crate::serial::SERIAL1.try_lock().is_none() {
+        crate::serial::SERIAL1.force_unlock();
+    }
+    if CAPTURED.try_lock().is_none() {
+        CAPTURED.force_unlock();
+    }
+}
+
+/// Print what's valid UTF-8 in `bytes`, with a replacement for anything that
+/// isn't. Returns how many bytes at the end start a character that may
+/// finish in the next read.
+pub fn print_utf8(mut bytes: &[u8]) -> usize {
+    loop {
+        let error = match core::str::from_utf8(bytes) {
+            Ok(text) => {
+                crate::print!("{}", text);
+                return 0;
+            }
+            Err(error) => error,
+        };
+        let (valid, rest) = bytes.split_at(error.valid_up_to());
+        crate::print!("{}", core::str::from_utf8(valid).unwrap_or(""));
+        match error.error_len() {
+            Some(len) => {
+                crate::print!("{}", core::char::REPLACEMENT_CHARACTER);
+                bytes = &rest[len..];
+            }
+            None => return rest.len(),
+        }
+    }
+}
+
+/// What an EscapeParser made of one character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parsed {
+    /// Plain output, not part of a sequence
+    Char(char),
+    /// The end of ESC[ params command; `params[..count]` were given, with
+    /// 0 for any left empty
+    Control { command: u8, params: [u16; MAX_PARAMS], count: usize },
+    /// Inside a sequence; nothing to show yet
+    Pending,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    // Just seen ESC
+    Escape,
+    // Inside ESC[, collecting parameters
+    Csi,
+}
+
+/// Picks ANSI control sequences (ESC[...) out of output, a character at a
+/// time, so one can be split across writes. Other escapes are swallowed.
+pub struct EscapeParser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    // The parameter being read
+    index: usize,
+}
+
+impl EscapeParser {
+    pub const fn new() -> EscapeParser {
+        EscapeParser { state: State::Ground, params: [0; MAX_PARAMS], index: 0 }
+    }
+
+    pub fn feed(&mut self, c: char) -> Parsed {
+        match self.state {
+            State::Ground if c == '\x1b' => self.state = State::Escape,
+            State::Ground => return Parsed::Char(c),
+            State::Escape if c == '[' => {
+                self.state = State::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.index = 0;
+            }
+            State::Escape => self.state = State::Ground,
+            State::Csi => match c {
+                '0'..='9' => {
+                    let param = &mut self.params[self.index];
+                    *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                }
+                ';' => self.index = (self.index + 1).min(MAX_PARAMS - 1),
+                // The final byte
+                '\x40'..='\x7e' => {
+                    self.state = State::Ground;
+                    return Parsed::Control { command: c as u8, params: self.params, count: self.index + 1 };
+                }
+                // Private markers like the ? in ESC[?25l; nothing we act on
+                _ => {}
+            },
+        }
+        Parsed::Pending
+    }
+}
+
+/// Colors picked by SGR sequences (ESC[...m), as ANSI color numbers 0-15
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub foreground: usize,
+    pub background: usize,
+    pub bold: bool,
+}
+
+impl Attributes {
+    /// Green on black, as the console has always been
+    pub const DEFAULT: Attributes = Attributes { foreground: 2, background: 0, bold: false };
+
+    /// Apply one SGR parameter; unknown ones are ignored
+    pub fn select_graphic(&mut self, param: u16) {
+        match param {
+            0 => *self = Attributes::DEFAULT,
+            1 => self.bold = true,
+            22 => self.bold = false,
+            30..=37 => self.foreground = usize::from(param - 30),
+            39 => self.foreground = Attributes::DEFAULT.foreground,
+            40..=47 => self.background = usize::from(param - 40),
+            49 => self.background = Attributes::DEFAULT.background,
+            90..=97 => self.foreground = usize::from(param - 90) + 8,
+            100..=107 => self.background = usize::from(param - 100) + 8,
+            _ => {}
+        }
+    }
+
+    /// The foreground to draw with: bold shows as the bright version of the
+    /// color, as on most terminals
+    pub fn shown_foreground(&self) -> usize {
+        if self.bold {
+            self.foreground | 8
+        } else {
+            self.foreground
+        }
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_escape_parser() {
+    let mut parser = EscapeParser::new();
+    assert_eq!(parser.feed('a'), Parsed::Char('a'));
+    for c in "\x1b[1;3".chars() {
+        assert_eq!(parser.feed(c), Parsed::Pending);
+    }
+    let expected = Parsed::Control { command: b'm', params: [1, 3, 0, 0], count: 2 };
+    assert_eq!(parser.feed('m'), expected);
+    // Not a control sequence; both characters are swallowed
+    assert_eq!(parser.feed('\x1b'), Parsed::Pending);
+    assert_eq!(parser.feed('c'), Parsed::Pending);
+    assert_eq!(parser.feed('é'), Parsed::Char('é'));
+}
+
+#[test_case]
+fn test_capture() {
+    let (answer, printed) = capture(|| {
+        crate::print!("\x1b[1mbold\x1b[0m ");
+        let ((), inner) = capture(|| crate::println!("{}", 42));
+        assert_eq!(inner, "42\n");
+        7
+    });
+    assert_eq!((answer, printed.as_str()), (7, "\x1b[1mbold\x1b[0m 42\n"));
+    // Nothing's kept after
+    crate::println!("not captured");
+    assert!(!CAPTURING.load(Ordering::Acquire));
+}
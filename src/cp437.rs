@@ -0,0 +1,76 @@
+//! Code page 437, the VGA text buffer's character set. Besides ASCII it has
+//! accented letters, box drawing, and a handful of symbols.
+
+// What bytes 0x01-0x1f show as; the hardware draws them as symbols
+const LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘',
+    '○', '◙', '♂', '♀', '♪', '♫', '☼', '►',
+    '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑',
+    '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+// Bytes 0x80-0xff
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç',
+    'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù',
+    'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º',
+    '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖',
+    '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟',
+    '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫',
+    '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ',
+    'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈',
+    '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// The CP437 byte that shows `c`, if there is one. ASCII control characters
+/// have none, since those bytes show as symbols.
+pub fn encode(c: char) -> Option<u8> {
+    if (' '..='~').contains(&c) {
+        return Some(c as u8);
+    }
+    if c == '⌂' {
+        return Some(0x7f);
+    }
+    if let Some(index) = LOW.iter().position(|&low| low == c) {
+        return Some(index as u8 + 0x01);
+    }
+    HIGH.iter().position(|&high| high == c).map(|index| index as u8 + 0x80)
+}
+
+/// The character a CP437 byte shows as. 0 shows as a blank.
+pub fn decode(byte: u8) -> char {
+    match byte {
+        0x00 => ' ',
+        0x01..=0x1f => LOW[usize::from(byte - 0x01)],
+        0x7f => '⌂',
+        0x80..=0xff => HIGH[usize::from(byte - 0x80)],
+        _ => char::from(byte),
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_encode() {
+    assert_eq!(encode('A'), Some(b'A'));
+    assert_eq!(encode('é'), Some(0x82));
+    assert_eq!(encode('─'), Some(0xc4));
+    assert_eq!(encode('☺'), Some(0x01));
+    assert_eq!(encode('\u{a0}'), Some(0xff));
+    assert_eq!(encode('€'), None);
+    assert_eq!(encode('\n'), None);
+}
+
+#[test_case]
+fn test_decode_round_trips() {
+    for byte in 0x01..=0xff {
+        assert_eq!(encode(decode(byte)), Some(byte));
+    }
+}
@@ -0,0 +1,249 @@
+use core::arch::x86_64::{__cpuid_count, __get_cpuid_max, _rdtsc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use crate::msr;
+use crate::time::{self, Duration};
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// CPU features we know how to look up via CPUID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Smep,
+    Smap,
+    Umip,
+    Rdrand,
+    /// The no-execute bit in page table entries
+    NoExecute,
+    /// A local APIC
+    Apic,
+    Tsc,
+    InvariantTsc,
+    Hypervisor,
+    /// APERF/MPERF frequency counters
+    AperfMperf,
+    /// Per-core temperature in IA32_THERM_STATUS
+    DigitalThermalSensor,
+    /// Package temperature in IA32_PACKAGE_THERM_STATUS
+    PackageThermal,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Register {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl Feature {
+    // Where the feature bit lives: (leaf, output register, bit)
+    fn location(self) -> (u32, Register, u32) {
+        match self {
+            Feature::Smep => (7, Register::Ebx, 7),
+            Feature::Smap => (7, Register::Ebx, 20),
+            Feature::Umip => (7, Register::Ecx, 2),
+            Feature::Rdrand => (1, Register::Ecx, 30),
+            Feature::NoExecute => (0x8000_0001, Register::Edx, 20),
+            Feature::Apic => (1, Register::Edx, 9),
+            Feature::Tsc => (1, Register::Edx, 4),
+            Feature::InvariantTsc => (0x8000_0007, Register::Edx, 8),
+            Feature::Hypervisor => (1, Register::Ecx, 31),
+            Feature::AperfMperf => (6, Register::Ecx, 0),
+            Feature::DigitalThermalSensor => (6, Register::Eax, 0),
+            Feature::PackageThermal => (6, Register::Eax, 6),
+        }
+    }
+}
+
+/// Ask CPUID whether this processor supports `feature`
+pub fn has_feature(feature: Feature) -> bool {
+    let (leaf, register, bit) = feature.location();
+
+    // Leaves above the reported maximum return garbage, so check first
+    let (max_leaf, _) = unsafe { __get_cpuid_max(leaf & 0x8000_0000) };
+    if leaf > max_leaf {
+        return false;
+    }
+
+    let result = unsafe { __cpuid_count(leaf, 0) };
+    let value = match register {
+        Register::Eax => result.eax,
+        Register::Ebx => result.ebx,
+        Register::Ecx => result.ecx,
+        Register::Edx => result.edx,
+    };
+    value & (1 << bit) != 0
+}
+
+/// Turn on SMEP, SMAP, and UMIP in CR4, and no-execute pages in EFER, for
+/// whichever of them the CPU supports; and write protection in CR0, so
+/// read-only pages are read-only to the kernel too
+pub fn enable_protections() {
+    let smep = has_feature(Feature::Smep);
+    let smap = has_feature(Feature::Smap);
+    let umip = has_feature(Feature::Umip);
+    let nx = has_feature(Feature::NoExecute);
+
+    unsafe {
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        Cr4::update(|flags| {
+            flags.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, smep);
+            flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, smap);
+            flags.set(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION, umip);
+        });
+    }
+
+    if nx {
+        unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    }
+
+    // STAC/CLAC are #UD without SMAP, so uaccess needs to know
+    SMAP_ENABLED.store(smap, Ordering::SeqCst);
+    // And NO_EXECUTE is a reserved bit without NXE
+    NX_ENABLED.store(nx, Ordering::SeqCst);
+}
+
+/// Make the IDT, every CPU's GDT and the kernel's top-level page table
+/// read-only, for once boot is done changing them. The TSSs stay writable,
+/// and so do the lower levels of the page tables. x86_64's descriptors
+/// come with their accessed bits set, and loading the TSS has already set
+/// its busy bit, so the CPU has no reason to write to a GDT again.
+pub fn protect_tables() -> Result<(), &'static str> {
+    use crate::memory::paging;
+
+    crate::interrupts::idt().make_read_only()?;
+    for gdt in crate::gdt::gdts() {
+        gdt.make_read_only()?;
+    }
+    paging::protect_level_4()
+}
+
+/// Whether SMAP is active (and so STAC/CLAC are needed around user accesses)
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Whether pages can be mapped NO_EXECUTE
+pub fn nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::SeqCst)
+}
+
+/// The CPUID vendor string, e.g. "GenuineIntel"
+pub fn vendor() -> [u8; 12] {
+    let result = unsafe { __cpuid_count(0, 0) };
+    let mut vendor = [0; 12];
+    vendor[..4].copy_from_slice(&result.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&result.edx.to_le_bytes());
+    vendor[8..].copy_from_slice(&result.ecx.to_le_bytes());
+    vendor
+}
+
+/// The processor brand string, NUL-padded, if the CPU has one
+pub fn brand() -> Option<[u8; 48]> {
+    let (max_leaf, _) = unsafe { __get_cpuid_max(0x8000_0000) };
+    if max_leaf < 0x8000_0004 {
+        return None;
+    }
+    let mut brand = [0; 48];
+    for (leaf, chunk) in (0x8000_0002..=0x8000_0004).zip(brand.chunks_mut(16)) {
+        let result = unsafe { __cpuid_count(leaf, 0) };
+        for (word, bytes) in [result.eax, result.ebx, result.ecx, result.edx].iter().zip(chunk.chunks_mut(4)) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+    }
+    Some(brand)
+}
+
+fn is_intel() -> bool {
+    &vendor() == b"GenuineIntel"
+}
+
+// Frequency and thermal status
+
+// Intel's usual TjMax, for parts that don't report theirs
+const DEFAULT_TJ_MAX: u8 = 100;
+
+/// How fast the CPU actually ran over a sampling window
+#[derive(Debug, Clone, Copy)]
+pub struct Frequency {
+    /// Average clock while not halted, from APERF/MPERF against the TSC rate
+    pub effective_hz: u64,
+    /// Share of the window spent not halted, in percent
+    pub busy_percent: u64,
+}
+
+/// Sample APERF/MPERF across `window`. Below the TSC frequency means the
+/// CPU (or the host under it) was throttling.
+pub fn effective_frequency(window: Duration) -> Option<Frequency> {
+    if !has_feature(Feature::AperfMperf) {
+        return None;
+    }
+    let tsc_hz = time::tsc_frequency()?;
+    let sample = || -> Option<(u64, u64, u64)> {
+        let tsc = unsafe { _rdtsc() };
+        Some((msr::APERF.read().ok()?, msr::MPERF.read().ok()?, tsc))
+    };
+
+    let (aperf_start, mperf_start, tsc_start) = sample()?;
+    time::sleep_precise(window);
+    let (aperf_end, mperf_end, tsc_end) = sample()?;
+
+    let actual = aperf_end.wrapping_sub(aperf_start);
+    let reference = mperf_end.wrapping_sub(mperf_start);
+    let elapsed = tsc_end.wrapping_sub(tsc_start);
+    if reference == 0 || elapsed == 0 {
+        return None;
+    }
+    // MPERF ticks at the TSC rate, but only while the core isn't halted
+    Some(Frequency {
+        effective_hz: (u128::from(tsc_hz) * u128::from(actual) / u128::from(reference)) as u64,
+        busy_percent: (u128::from(reference) * 100 / u128::from(elapsed)).min(100) as u64,
+    })
+}
+
+/// Temperatures from the digital thermal sensors (Intel only)
+#[derive(Debug, Clone, Copy)]
+pub struct Thermal {
+    /// This core's temperature
+    pub core_celsius: u8,
+    pub package_celsius: Option<u8>,
+    /// The temperature the CPU starts throttling at
+    pub tj_max: u8,
+    /// Throttling right now
+    pub throttling: bool,
+    /// Has throttled since the log bit was last cleared
+    pub throttled: bool,
+}
+
+// Degrees below TjMax, if the reading is valid
+fn thermal_readout(status: u64) -> Option<u8> {
+    if status & (1 << 31) == 0 {
+        return None;
+    }
+    Some(((status >> 16) & 0x7f) as u8)
+}
+
+/// The current core (and package) temperature, where the CPU reports one
+pub fn thermal() -> Option<Thermal> {
+    if !is_intel() || !has_feature(Feature::DigitalThermalSensor) {
+        return None;
+    }
+    let tj_max = msr::TEMPERATURE_TARGET
+        .read()
+        .ok()
+        .map(|target| ((target >> 16) & 0xff) as u8)
+        .filter(|&tj_max| tj_max != 0)
+        .unwrap_or(DEFAULT_TJ_MAX);
+    let status = msr::THERM_STATUS.read().ok()?;
+    let package = msr::PACKAGE_THERM_STATUS.read().ok().and_then(thermal_readout);
+    Some(Thermal {
+        core_celsius: tj_max.saturating_sub(thermal_readout(status)?),
+        package_celsius: package.map(|below| tj_max.saturating_sub(below)),
+        tj_max,
+        throttling: status & 1 != 0,
+        throttled: status & 2 != 0,
+    })
+}
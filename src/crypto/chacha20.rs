@@ -0,0 +1,139 @@
+/// ChaCha20 as in RFC 8439: 256-bit key, 96-bit nonce, 32-bit block counter
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const BLOCK_LEN: usize = 64;
+
+// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn words<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    let mut words = [0; N];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    words
+}
+
+/// One 64-byte keystream block
+pub fn block(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; NONCE_LEN]) -> [u8; BLOCK_LEN] {
+    let key: [u32; 8] = words(key);
+    let nonce: [u32; 3] = words(nonce);
+    let mut input = [0; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(&key);
+    input[12] = counter;
+    input[13..].copy_from_slice(&nonce);
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut output = [0; BLOCK_LEN];
+    for ((chunk, word), original) in output.chunks_exact_mut(4).zip(state.iter()).zip(input.iter()) {
+        chunk.copy_from_slice(&word.wrapping_add(*original).to_le_bytes());
+    }
+    output
+}
+
+/// A ChaCha20 stream, for encrypting or decrypting by XOR with the keystream
+pub struct ChaCha20 {
+    key: [u8; KEY_LEN],
+    nonce: [u8; NONCE_LEN],
+    counter: u32,
+    keystream: [u8; BLOCK_LEN],
+    // How much of `keystream` has been used
+    offset: usize,
+}
+
+impl ChaCha20 {
+    pub fn new(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], counter: u32) -> ChaCha20 {
+        ChaCha20 {
+            key: *key,
+            nonce: *nonce,
+            counter,
+            keystream: [0; BLOCK_LEN],
+            offset: BLOCK_LEN,
+        }
+    }
+
+    pub fn apply_keystream(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.offset == BLOCK_LEN {
+                self.keystream = block(&self.key, self.counter, &self.nonce);
+                self.counter = self.counter.wrapping_add(1);
+                self.offset = 0;
+            }
+            *byte ^= self.keystream[self.offset];
+            self.offset += 1;
+        }
+    }
+}
+
+impl Drop for ChaCha20 {
+    fn drop(&mut self) {
+        // Don't leave key material lying around on the stack
+        for byte in self.key.iter_mut().chain(self.keystream.iter_mut()) {
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_chacha20_block() {
+    use super::from_hex;
+
+    // RFC 8439 section 2.3.2
+    let key = from_hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+    let nonce = from_hex("000000090000004a00000000");
+    assert_eq!(block(&key, 1, &nonce)[..], from_hex::<64>(
+        "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+         d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e")[..]);
+}
+
+#[test_case]
+fn test_chacha20_encryption() {
+    use super::from_hex;
+
+    // RFC 8439 section 2.4.2
+    let key = from_hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+    let nonce = from_hex("000000000000004a00000000");
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+        only one tip for the future, sunscreen would be it.";
+    let expected: [u8; 114] = from_hex(
+        "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b\
+         f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8\
+         07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736\
+         5af90bbf74a35be6b40b8eedf2785e42874d");
+
+    let mut data = *plaintext;
+    // Odd-sized pieces, so keystream blocks get split across calls
+    let mut cipher = ChaCha20::new(&key, &nonce, 1);
+    for chunk in data.chunks_mut(37) {
+        cipher.apply_keystream(chunk);
+    }
+    assert_eq!(data[..], expected[..]);
+
+    ChaCha20::new(&key, &nonce, 1).apply_keystream(&mut data);
+    assert_eq!(data[..], plaintext[..]);
+}
@@ -0,0 +1,82 @@
+use super::sha256::{Sha256, BLOCK_LEN, DIGEST_LEN};
+
+const INNER_PAD: u8 = 0x36;
+const OUTER_PAD: u8 = 0x5c;
+
+/// Incremental HMAC-SHA256 (RFC 2104)
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        // Keys longer than a block are hashed down first
+        let mut block_key = [0; BLOCK_LEN];
+        if key.len() > BLOCK_LEN {
+            block_key[..DIGEST_LEN].copy_from_slice(&super::sha256(key));
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        let mut outer = Sha256::new();
+        let mut pad = [0; BLOCK_LEN];
+        for (pad, key) in pad.iter_mut().zip(block_key.iter()) {
+            *pad = key ^ INNER_PAD;
+        }
+        inner.update(&pad);
+        for (pad, key) in pad.iter_mut().zip(block_key.iter()) {
+            *pad = key ^ OUTER_PAD;
+        }
+        outer.update(&pad);
+        HmacSha256 { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; DIGEST_LEN] {
+        let HmacSha256 { inner, mut outer } = self;
+        outer.update(&inner.finalize());
+        outer.finalize()
+    }
+
+    /// Check a received tag without leaking how much of it matched
+    pub fn verify(self, tag: &[u8]) -> bool {
+        super::constant_time_eq(&self.finalize(), tag)
+    }
+}
+
+/// HMAC-SHA256 of `data` under `key` in one go
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(data);
+    mac.finalize()
+}
+
+/// TESTS
+
+#[test_case]
+fn test_hmac_sha256_vectors() {
+    use super::from_hex;
+
+    // RFC 4231 test cases 1, 2, and 6
+    assert_eq!(hmac_sha256(&[0x0b; 20], b"Hi There"),
+        from_hex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"));
+    assert_eq!(hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+        from_hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"));
+    assert_eq!(hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First"),
+        from_hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"));
+}
+
+#[test_case]
+fn test_hmac_verify() {
+    let tag = hmac_sha256(b"key", b"message");
+    let mut mac = HmacSha256::new(b"key");
+    mac.update(b"message");
+    assert!(mac.clone().verify(&tag));
+    assert!(!mac.verify(&tag[..31]));
+}
@@ -0,0 +1,46 @@
+//! Software implementations of the cryptographic primitives the kernel
+//! needs. No hardware acceleration; none of this uses the FPU or SIMD.
+
+pub mod chacha20;
+pub mod hmac;
+pub mod sha256;
+
+pub use chacha20::ChaCha20;
+pub use hmac::{hmac_sha256, HmacSha256};
+pub use sha256::{sha256, Sha256};
+
+/// Compare two byte strings in time that depends only on their lengths,
+/// for checking MACs without leaking where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y));
+    // Keep the compiler from turning the fold into an early exit
+    core::hint::black_box(difference) == 0
+}
+
+/// Decode a hex test vector
+#[cfg(test)]
+pub(crate) fn from_hex<const N: usize>(hex: &str) -> [u8; N] {
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => c - b'0',
+        b'a'..=b'f' => c - b'a' + 10,
+        _ => panic!("bad hex digit in test vector"),
+    };
+    assert_eq!(hex.len(), 2 * N, "test vector has the wrong length");
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *byte = digit(pair[0]) << 4 | digit(pair[1]);
+    }
+    bytes
+}
+
+/// TESTS
+
+#[test_case]
+fn test_constant_time_eq() {
+    assert!(constant_time_eq(b"same tag", b"same tag"));
+    assert!(!constant_time_eq(b"same tag", b"same taG"));
+    assert!(!constant_time_eq(b"short", b"longer"));
+}
@@ -0,0 +1,384 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::events::{self, Event};
+use crate::resource::{self, Resource};
+
+const MAX_DEVICES: usize = 32;
+
+/// Handle to a registered device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId(usize);
+
+/// Where a device is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Known to exist, but no driver has set it up
+    Registered,
+    /// A driver has it up and running
+    Active,
+    /// A driver tried and gave up on it
+    Failed,
+    /// Quiesced for a power transition; resume brings it back
+    Suspended,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Registered => "registered",
+            State::Active => "active",
+            State::Failed => "failed",
+            State::Suspended => "suspended",
+        }
+    }
+}
+
+/// How a driver saves and restores its device across a power transition
+#[derive(Debug, Clone, Copy)]
+pub struct PowerOps {
+    /// Quiesce the device and save whatever resume needs
+    pub suspend: fn(DeviceId) -> Result<(), &'static str>,
+    /// Put the device back the way suspend found it, assuming the hardware
+    /// may have lost all of its state in between
+    pub resume: fn(DeviceId) -> Result<(), &'static str>,
+}
+
+/// What the device table knows about a device
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parent: Option<DeviceId>,
+    /// Module driving it, if any
+    pub driver: Option<&'static str>,
+    pub state: State,
+    /// The driver's suspend/resume hooks, if it has any
+    pub power: Option<PowerOps>,
+}
+
+// Only ever locked with interrupts off, so drivers can update their state anywhere
+static DEVICES: Mutex<[Option<DeviceInfo>; MAX_DEVICES]> = Mutex::new([None; MAX_DEVICES]);
+
+/// Add a device under `parent` (None only for the root). Names need to be
+/// unique among siblings. Subscribers hear about it as a DeviceAdded event.
+pub fn register(name: &'static str, description: &'static str, parent: Option<DeviceId>)
+    -> Result<DeviceId, &'static str>
+{
+    let id = interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        if let Some(parent) = parent {
+            if devices.get(parent.0).map_or(true, |slot| slot.is_none()) {
+                return Err("parent device isn't registered");
+            }
+        }
+        let duplicate = devices.iter().flatten()
+            .any(|device| device.parent == parent && device.name == name);
+        if duplicate {
+            return Err("a device with that name is already registered there");
+        }
+        let index = devices.iter().position(|slot| slot.is_none())
+            .ok_or("device table is full")?;
+        devices[index] = Some(DeviceInfo {
+            id: DeviceId(index),
+            name,
+            description,
+            parent,
+            driver: None,
+            state: State::Registered,
+            power: None,
+        });
+        Ok(DeviceId(index))
+    })?;
+    events::publish(Event::DeviceAdded { id, name });
+    Ok(id)
+}
+
+/// Remove a device that has gone away, giving back whatever it claimed.
+/// Its children have to go first.
+pub fn unregister(id: DeviceId) -> Result<(), &'static str> {
+    let name = interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        if devices.iter().flatten().any(|device| device.parent == Some(id)) {
+            return Err("device still has children");
+        }
+        match devices.get_mut(id.0) {
+            Some(slot @ Some(_)) => Ok(slot.take().unwrap().name),
+            _ => Err("no such device"),
+        }
+    })?;
+    // Copied out before `f` is called, so releasing as it goes is fine
+    resource::for_each_owned(id, |resource| resource::release(id, resource));
+    events::publish(Event::DeviceRemoved { id, name });
+    Ok(())
+}
+
+fn update(id: DeviceId, f: impl FnOnce(&mut DeviceInfo)) {
+    interrupts::without_interrupts(|| {
+        if let Some(Some(device)) = DEVICES.lock().get_mut(id.0) {
+            f(device);
+        }
+    });
+}
+
+/// Record that `driver` has taken the device on, and how that went
+pub fn bind(id: DeviceId, driver: &'static str, state: State) {
+    update(id, |device| {
+        device.driver = Some(driver);
+        device.state = state;
+    });
+}
+
+pub fn set_state(id: DeviceId, state: State) {
+    update(id, |device| device.state = state);
+}
+
+/// Give the device suspend/resume hooks
+pub fn set_power_ops(id: DeviceId, ops: PowerOps) {
+    update(id, |device| device.power = Some(ops));
+}
+
+pub fn info(id: DeviceId) -> Option<DeviceInfo> {
+    interrupts::without_interrupts(|| DEVICES.lock().get(id.0).copied().flatten())
+}
+
+/// Look up a device by name among `parent`'s children
+pub fn find(parent: Option<DeviceId>, name: &str) -> Option<DeviceId> {
+    interrupts::without_interrupts(|| {
+        DEVICES.lock().iter().flatten()
+            .find(|device| device.parent == parent && device.name == name)
+            .map(|device| device.id)
+    })
+}
+
+/// Visit every device depth-first from the root, with its depth in the tree
+pub fn walk(mut f: impl FnMut(usize, &DeviceInfo)) {
+    // Copy the table so callbacks can print, or even register, without the lock
+    let devices = interrupts::without_interrupts(|| *DEVICES.lock());
+    fn visit(devices: &[Option<DeviceInfo>], parent: Option<DeviceId>, depth: usize,
+        f: &mut dyn FnMut(usize, &DeviceInfo))
+    {
+        for device in devices.iter().flatten().filter(|device| device.parent == parent) {
+            f(depth, device);
+            visit(devices, Some(device.id), depth + 1, f);
+        }
+    }
+    visit(&devices, None, 0, &mut f);
+}
+
+// Device ids in walk order: every parent before its children
+fn power_order() -> ([Option<DeviceId>; MAX_DEVICES], usize) {
+    let mut order = [None; MAX_DEVICES];
+    let mut count = 0;
+    walk(|_, device| {
+        order[count] = Some(device.id);
+        count += 1;
+    });
+    (order, count)
+}
+
+/// Suspend every active device with power hooks, children before their
+/// parents. If one refuses, the ones already suspended are resumed and its
+/// error comes back. Run with interrupts off.
+pub fn suspend_all() -> Result<(), &'static str> {
+    let (order, count) = power_order();
+    for (index, id) in order[..count].iter().flatten().enumerate().rev() {
+        let device = match info(*id) {
+            Some(device) if device.state == State::Active => device,
+            _ => continue,
+        };
+        if let Some(power) = device.power {
+            if let Err(message) = (power.suspend)(device.id) {
+                crate::early_println!("{}: suspend failed: {}", device.name, message);
+                let _ = resume_from(&order[index + 1..count]);
+                return Err(message);
+            }
+            set_state(device.id, State::Suspended);
+        }
+    }
+    Ok(())
+}
+
+/// Resume every suspended device, parents before their children. A device
+/// that fails to come back is marked failed; the rest still get resumed.
+/// Run with interrupts off.
+pub fn resume_all() -> Result<(), &'static str> {
+    let (order, count) = power_order();
+    resume_from(&order[..count])
+}
+
+fn resume_from(order: &[Option<DeviceId>]) -> Result<(), &'static str> {
+    let mut result = Ok(());
+    for id in order.iter().flatten() {
+        let device = match info(*id) {
+            Some(device) if device.state == State::Suspended => device,
+            _ => continue,
+        };
+        let resumed = device.power.map_or(Ok(()), |power| (power.resume)(device.id));
+        match resumed {
+            Ok(()) => set_state(device.id, State::Active),
+            Err(message) => {
+                crate::early_println!("{}: resume failed: {}", device.name, message);
+                set_state(device.id, State::Failed);
+                result = result.and(Err(message));
+            }
+        }
+    }
+    result
+}
+
+/// The root of the tree; everything the kernel drives hangs off it
+pub fn platform() -> DeviceId {
+    find(None, "platform").expect("device::init hasn't run")
+}
+
+/// Register the root and the legacy PC hardware every machine we run on has
+pub fn init() {
+    let platform = register("platform", "PC platform", None).expect("couldn't register platform");
+    bind(platform, "device", State::Active);
+
+    let legacy: [(&str, &str, &str, &[Resource], Option<PowerOps>); 5] = [
+        ("pic", "8259 programmable interrupt controllers", "interrupts",
+            &[Resource::Ports { first: 0x20, last: 0x21 }, Resource::Ports { first: 0xa0, last: 0xa1 },
+                Resource::Irq(2)],
+            Some(crate::interrupts::PIC_POWER)),
+        // Port 0x61 gates channel 2, which we use for calibration and precise sleeps
+        ("pit", "8253/8254 programmable interval timer", "time",
+            &[Resource::Ports { first: 0x40, last: 0x43 }, Resource::port(0x61), Resource::Irq(0)],
+            Some(crate::time::PIT_POWER)),
+        ("rtc", "CMOS real-time clock", "rtc", &[Resource::Ports { first: 0x70, last: 0x71 }], None),
+        ("uart0", "16550 UART on COM1", "serial",
+            &[Resource::Ports { first: 0x3f8, last: 0x3ff }, Resource::Irq(crate::serial::IRQ)], None),
+        ("vga", "VGA text mode console", "vga_buffer", &[Resource::Mmio { start: 0xb8000, length: 0x8000 }],
+            None),
+    ];
+    for (name, description, driver, resources, power) in legacy.iter() {
+        // A serial-only console leaves the VGA hardware entirely alone
+        if *name == "vga" && crate::cmdline::headless() {
+            continue;
+        }
+        let id = register(name, description, Some(platform)).expect("couldn't register legacy device");
+        let state = match resource::claim_all(id, resources) {
+            Ok(()) => State::Active,
+            Err(_) => State::Failed,
+        };
+        bind(id, driver, state);
+        if let Some(power) = power {
+            set_power_ops(id, *power);
+        }
+    }
+    // The keyboard and mouse drivers register themselves underneath it
+    let i8042 = register("i8042", "PS/2 controller", Some(platform)).expect("couldn't register i8042");
+    let ports = [Resource::port(0x60), Resource::port(0x64)];
+    if resource::claim_all(i8042, &ports).is_ok() {
+        set_state(i8042, State::Active);
+    }
+}
+
+/// The PS/2 controller, parent of the keyboard and mouse
+pub fn i8042() -> DeviceId {
+    find(Some(platform()), "i8042").expect("device::init hasn't run")
+}
+
+/// TESTS
+
+#[test_case]
+fn test_register_and_walk() {
+    let platform = platform();
+    let bus = register("test-bus", "test bus", Some(platform)).unwrap();
+    let child = register("test-child", "test device", Some(bus)).unwrap();
+    assert!(register("test-child", "duplicate", Some(bus)).is_err());
+    assert_eq!(find(Some(bus), "test-child"), Some(child));
+    assert_eq!(info(child).unwrap().state, State::Registered);
+
+    bind(child, "test", State::Failed);
+    assert_eq!(info(child).unwrap().driver, Some("test"));
+
+    let mut bus_depth = None;
+    let mut seen_child_after_bus = false;
+    walk(|depth, device| {
+        if device.id == bus {
+            bus_depth = Some(depth);
+        } else if device.id == child {
+            seen_child_after_bus = bus_depth == Some(depth - 1);
+        }
+    });
+    assert_eq!(bus_depth, Some(1));
+    assert!(seen_child_after_bus);
+
+    assert!(unregister(bus).is_err());
+    unregister(child).unwrap();
+    unregister(bus).unwrap();
+    assert_eq!(find(Some(platform), "test-bus"), None);
+}
+
+#[test_case]
+fn test_suspend_order_and_rollback() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // Each hook call appends the device's (test-local) number as a digit
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn record(id: DeviceId) {
+        let number = match info(id).map(|device| device.name) {
+            Some("test-pm-bus") => 1,
+            Some("test-pm-child") => 2,
+            _ => 9,
+        };
+        CALLS.store(CALLS.load(Ordering::Relaxed) * 10 + number, Ordering::Relaxed);
+    }
+    fn ok(id: DeviceId) -> Result<(), &'static str> {
+        record(id);
+        Ok(())
+    }
+    fn refuse(id: DeviceId) -> Result<(), &'static str> {
+        record(id);
+        Err("busy")
+    }
+
+    let bus = register("test-pm-bus", "test bus", Some(platform())).unwrap();
+    let child = register("test-pm-child", "test device", Some(bus)).unwrap();
+    bind(bus, "test", State::Active);
+    bind(child, "test", State::Active);
+    set_power_ops(bus, PowerOps { suspend: ok, resume: ok });
+    set_power_ops(child, PowerOps { suspend: ok, resume: ok });
+
+    // The real devices' hooks run too, so keep their interrupts out of it
+    interrupts::without_interrupts(|| {
+        suspend_all().unwrap();
+        assert_eq!(info(child).unwrap().state, State::Suspended);
+        resume_all().unwrap();
+    });
+    // Child suspended before the bus, the bus resumed before the child
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2112);
+    assert_eq!(info(child).unwrap().state, State::Active);
+
+    // A refusal rolls back the devices already suspended
+    CALLS.store(0, Ordering::Relaxed);
+    set_power_ops(bus, PowerOps { suspend: refuse, resume: ok });
+    assert_eq!(interrupts::without_interrupts(suspend_all), Err("busy"));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 212);
+    assert_eq!(info(child).unwrap().state, State::Active);
+    assert_eq!(info(bus).unwrap().state, State::Active);
+
+    unregister(child).unwrap();
+    unregister(bus).unwrap();
+}
+
+#[test_case]
+fn test_unregister_releases_claims() {
+    // Made-up resources nothing real uses
+    let ports = Resource::Ports { first: 0xe100, last: 0xe10f };
+    let mmio = Resource::Mmio { start: 0xfd10_0000, length: 0x1000 };
+    let first = register("test-claims", "claims test", Some(platform())).unwrap();
+    resource::claim_all(first, &[ports, mmio]).unwrap();
+    unregister(first).unwrap();
+
+    let again = register("test-claims", "claims test", Some(platform())).unwrap();
+    let mut owned = 0;
+    resource::for_each_owned(again, |_| owned += 1);
+    assert_eq!(owned, 0);
+    assert_eq!(resource::claim_all(again, &[ports, mmio]), Ok(()));
+    unregister(again).unwrap();
+    assert_eq!(resource::claim(platform(), ports), Ok(()));
+    resource::release(platform(), ports);
+}
@@ -0,0 +1,675 @@
+//! ATA disks over PIO on the two legacy IDE channels. Each channel has up
+//! to two drives, master and slave; IDENTIFY tells which are there. Every
+//! transfer is polled, a sector at a time through the data port, so it
+//! works with interrupts masked and without DMA.
+
+use core::fmt;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use crate::device::{self, DeviceId, State};
+use crate::metrics::{self, Counter, Histogram, Metric, Value};
+use crate::pio::{Port, Ports, ReadOnly, ReadWrite, WriteOnly};
+use crate::resource::{self, Resource};
+use crate::time::{Duration, Instant};
+
+pub const SECTOR_SIZE: usize = 512;
+pub const MAX_DISKS: usize = 4;
+
+// Register offsets from a channel's command block
+const DATA: u16 = 0;
+// ERROR when read, FEATURES when written
+const ERROR: u16 = 1;
+const FEATURES: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_HEAD: u16 = 6;
+// STATUS when read, COMMAND when written
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+// In the control block's device control register: no interrupts, we poll
+const CONTROL_NIEN: u8 = 1 << 1;
+
+const IDENTIFY: u8 = 0xec;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const FLUSH_CACHE: u8 = 0xe7;
+const FLUSH_CACHE_EXT: u8 = 0xea;
+// SMART's commands are all this one, with what to do in the feature
+// register, and a signature in the LBA registers so it isn't sent by chance
+const SMART: u8 = 0xb0;
+const SMART_READ_DATA: u8 = 0xd0;
+const SMART_READ_THRESHOLDS: u8 = 0xd1;
+const SMART_RETURN_STATUS: u8 = 0xda;
+const SMART_SIGNATURE: (u8, u8) = (0x4f, 0xc2);
+// What RETURN STATUS leaves in the LBA registers when a threshold is crossed
+const SMART_FAILING: (u8, u8) = (0xf4, 0x2c);
+// Attributes are 12 bytes each from byte 2 of the data sector, 30 at most
+const SMART_ATTRIBUTES: usize = 30;
+
+// Sectors one command can move; a count of 0 means this many
+const MAX_TRANSFER: usize = 256;
+// LBA28 addresses stop here
+const LBA28_LIMIT: u64 = 1 << 28;
+const TIMEOUT: Duration = Duration::from_secs(2);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Primary,
+    Secondary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+impl Bus {
+    // Command block and control block ports
+    fn ports(self) -> (u16, u16) {
+        match self {
+            Bus::Primary => (0x1f0, 0x3f6),
+            Bus::Secondary => (0x170, 0x376),
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Bus::Primary => 0,
+            Bus::Secondary => 1,
+        }
+    }
+}
+
+impl fmt::Display for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Bus::Primary => "primary",
+            Bus::Secondary => "secondary",
+        })
+    }
+}
+
+impl fmt::Display for Drive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Drive::Master => "master",
+            Drive::Slave => "slave",
+        })
+    }
+}
+
+/// A drive IDENTIFY found
+#[derive(Debug, Clone, Copy)]
+pub struct Disk {
+    pub bus: Bus,
+    pub drive: Drive,
+    /// Addressable sectors
+    pub sectors: u64,
+    lba48: bool,
+    model: [u8; 40],
+    serial: [u8; 20],
+    firmware: [u8; 8],
+    smart: Smart,
+}
+
+/// Whether a drive has SMART, as IDENTIFY says
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Smart {
+    Unsupported,
+    Disabled,
+    Enabled,
+}
+
+/// One of the attributes SMART keeps: a normalized value (higher is
+/// better), the worst it's been, and a raw count whose meaning depends on
+/// the attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmartAttribute {
+    pub id: u8,
+    pub value: u8,
+    pub worst: u8,
+    /// The value at or below which the drive counts this as failing; 0 if
+    /// it never does
+    pub threshold: u8,
+    pub raw: u64,
+}
+
+impl SmartAttribute {
+    pub fn failing(&self) -> bool {
+        self.threshold != 0 && self.value <= self.threshold
+    }
+
+    /// What it's usually called, for the attributes most drives have
+    pub fn name(&self) -> &'static str {
+        match self.id {
+            1 => "Raw_Read_Error_Rate",
+            3 => "Spin_Up_Time",
+            4 => "Start_Stop_Count",
+            5 => "Reallocated_Sector_Ct",
+            7 => "Seek_Error_Rate",
+            9 => "Power_On_Hours",
+            10 => "Spin_Retry_Count",
+            12 => "Power_Cycle_Count",
+            190 => "Airflow_Temperature_Cel",
+            194 => "Temperature_Celsius",
+            195 => "Hardware_ECC_Recovered",
+            196 => "Reallocated_Event_Count",
+            197 => "Current_Pending_Sector",
+            198 => "Offline_Uncorrectable",
+            199 => "UDMA_CRC_Error_Count",
+            _ => "Unknown_Attribute",
+        }
+    }
+}
+
+// A channel's command block, and its control block's one register
+struct Registers {
+    data: Port<ReadWrite, u16>,
+    error: Port<ReadOnly, u8>,
+    features: Port<WriteOnly, u8>,
+    sector_count: Port<WriteOnly, u8>,
+    lba_low: Port<WriteOnly, u8>,
+    lba_mid: Port<ReadWrite, u8>,
+    lba_high: Port<ReadWrite, u8>,
+    drive_head: Port<WriteOnly, u8>,
+    status: Port<ReadOnly, u8>,
+    command: Port<WriteOnly, u8>,
+    // The status again, read without acknowledging anything
+    alternate_status: Port<ReadOnly, u8>,
+    device_control: Port<WriteOnly, u8>,
+}
+
+// One transfer at a time per channel; the two drives on it share registers.
+// Never touched by interrupt handlers.
+static CHANNELS: [Mutex<()>; 2] = [Mutex::new(()), Mutex::new(())];
+// Set by init for each channel a drive was found on
+static REGISTERS: [Once<Registers>; 2] = [Once::new(), Once::new()];
+static DISKS: Mutex<[Option<Disk>; MAX_DISKS]> = Mutex::new([None; MAX_DISKS]);
+static SECTORS_READ: Counter = Counter::new();
+static SECTORS_WRITTEN: Counter = Counter::new();
+static READ_MICROS: Histogram = Histogram::new();
+
+const CHANNEL_NAMES: [&str; 2] = ["ide0", "ide1"];
+const DISK_NAMES: [&str; MAX_DISKS] = ["ata0", "ata1", "ata2", "ata3"];
+
+impl Registers {
+    // The ports of `bus`, which `owner` has to have claimed
+    fn owned(owner: DeviceId, bus: Bus) -> Result<Registers, &'static str> {
+        let (base, control) = bus.ports();
+        let command = Ports::owned(owner, base, base + 7)?;
+        let control = Ports::owned(owner, control, control)?;
+        Ok(Registers {
+            data: command.port(DATA)?,
+            error: command.port(ERROR)?,
+            features: command.port(FEATURES)?,
+            sector_count: command.port(SECTOR_COUNT)?,
+            lba_low: command.port(LBA_LOW)?,
+            lba_mid: command.port(LBA_MID)?,
+            lba_high: command.port(LBA_HIGH)?,
+            drive_head: command.port(DRIVE_HEAD)?,
+            status: command.port(STATUS)?,
+            command: command.port(COMMAND)?,
+            alternate_status: control.port(0)?,
+            device_control: control.port(0)?,
+        })
+    }
+
+    // What the error register says went wrong
+    fn error_message(&self) -> &'static str {
+        let error = self.error.read();
+        if error & 0x40 != 0 {
+            "uncorrectable data error"
+        } else if error & 0x10 != 0 {
+            "sector not found"
+        } else if error & 0x80 != 0 {
+            "bad block"
+        } else if error & 0x04 != 0 {
+            "command aborted"
+        } else {
+            "drive reported an error"
+        }
+    }
+
+    // Drives want 400ns after a drive select before their status means
+    // anything; four reads of the alternate status take about that long
+    fn settle(&self) {
+        for _ in 0..4 {
+            self.alternate_status.read();
+        }
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, &'static str> {
+        let start = Instant::now();
+        loop {
+            let status = self.status.read();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if start.elapsed() > TIMEOUT {
+                return Err("drive timed out");
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    // Once the drive is ready to move the next sector
+    fn wait_data(&self) -> Result<(), &'static str> {
+        self.settle();
+        let status = self.wait_not_busy()?;
+        if status & STATUS_ERR != 0 {
+            return Err(self.error_message());
+        }
+        if status & STATUS_DF != 0 {
+            return Err("drive fault");
+        }
+        if status & STATUS_DRQ == 0 {
+            return Err("drive isn't asking for data");
+        }
+        Ok(())
+    }
+
+    // A flush can take far longer than a transfer, up to ATA's 30 seconds
+    fn wait_flushed(&self) -> Result<(), &'static str> {
+        let start = Instant::now();
+        loop {
+            match self.wait_idle() {
+                Err("drive timed out") if start.elapsed() < FLUSH_TIMEOUT => {}
+                result => return result,
+            }
+        }
+    }
+
+    // Once a command without data has finished
+    fn wait_idle(&self) -> Result<(), &'static str> {
+        self.settle();
+        let status = self.wait_not_busy()?;
+        if status & STATUS_ERR != 0 {
+            return Err(self.error_message());
+        }
+        if status & STATUS_DF != 0 {
+            return Err("drive fault");
+        }
+        Ok(())
+    }
+
+    // A sector through the data port
+    fn read_sector(&self, sector: &mut [u8]) {
+        for word in sector.chunks_exact_mut(2) {
+            word.copy_from_slice(&self.data.read().to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, sector: &[u8]) {
+        for word in sector.chunks_exact(2) {
+            self.data.write(u16::from_le_bytes([word[0], word[1]]));
+        }
+    }
+}
+
+impl Disk {
+    // Only found on a channel init got the registers of
+    fn registers(&self) -> &'static Registers {
+        REGISTERS[self.bus.index()].r#try().expect("disk on a channel without registers")
+    }
+
+    /// The model name the drive reports, without its padding
+    pub fn model(&self) -> &str {
+        core::str::from_utf8(&self.model).unwrap_or("").trim()
+    }
+
+    pub fn serial(&self) -> &str {
+        core::str::from_utf8(&self.serial).unwrap_or("").trim()
+    }
+
+    pub fn firmware(&self) -> &str {
+        core::str::from_utf8(&self.firmware).unwrap_or("").trim()
+    }
+
+    pub fn smart(&self) -> Smart {
+        self.smart
+    }
+
+    /// Whether the drive thinks it's healthy: false once any attribute has
+    /// crossed its threshold
+    pub fn smart_healthy(&self) -> Result<bool, &'static str> {
+        let _channel = CHANNELS[self.bus.index()].lock();
+        self.smart_command(SMART_RETURN_STATUS)?;
+        let registers = self.registers();
+        registers.wait_idle()?;
+        match (registers.lba_mid.read(), registers.lba_high.read()) {
+            SMART_SIGNATURE => Ok(true),
+            SMART_FAILING => Ok(false),
+            _ => Err("drive didn't say how it is"),
+        }
+    }
+
+    /// The attributes the drive keeps, with their thresholds
+    pub fn smart_attributes(&self) -> Result<Vec<SmartAttribute>, &'static str> {
+        let data = self.smart_read(SMART_READ_DATA)?;
+        let thresholds = self.smart_read(SMART_READ_THRESHOLDS)?;
+        let entries = data[2..2 + SMART_ATTRIBUTES * 12].chunks_exact(12).zip(thresholds[2..].chunks_exact(12));
+        Ok(entries
+            // Unused slots have ID 0
+            .filter(|(entry, _)| entry[0] != 0)
+            .map(|(entry, threshold)| SmartAttribute {
+                id: entry[0],
+                value: entry[3],
+                worst: entry[4],
+                // The threshold sector has the same layout, ID then threshold
+                threshold: if threshold[0] == entry[0] { threshold[1] } else { 0 },
+                raw: entry[5..11].iter().rev().fold(0, |raw, &byte| raw << 8 | u64::from(byte)),
+            })
+            .collect())
+    }
+
+    // A SMART sector
+    fn smart_read(&self, feature: u8) -> Result<[u8; SECTOR_SIZE], &'static str> {
+        let _channel = CHANNELS[self.bus.index()].lock();
+        self.smart_command(feature)?;
+        let registers = self.registers();
+        registers.wait_data()?;
+        let mut sector = [0; SECTOR_SIZE];
+        registers.read_sector(&mut sector);
+        Ok(sector)
+    }
+
+    // Start a SMART command; the channel has to be locked
+    fn smart_command(&self, feature: u8) -> Result<(), &'static str> {
+        match self.smart {
+            Smart::Unsupported => return Err("the drive doesn't have SMART"),
+            Smart::Disabled => return Err("SMART is turned off on the drive"),
+            Smart::Enabled => {}
+        }
+        let registers = self.registers();
+        let slave = if self.drive == Drive::Slave { 0x10 } else { 0 };
+        registers.wait_not_busy()?;
+        registers.drive_head.write(0xa0 | slave);
+        registers.settle();
+        registers.features.write(feature);
+        registers.sector_count.write(1);
+        registers.lba_mid.write(SMART_SIGNATURE.0);
+        registers.lba_high.write(SMART_SIGNATURE.1);
+        registers.command.write(SMART);
+        Ok(())
+    }
+
+    /// Read whole sectors from `lba` on into `buf`
+    pub fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.check_range(lba, buf.len())?;
+        #[cfg(feature = "fault-inject")]
+        crate::fault::check(crate::fault::Point::DiskRead)?;
+        let start = Instant::now();
+        let _channel = CHANNELS[self.bus.index()].lock();
+        for (index, chunk) in buf.chunks_mut(MAX_TRANSFER * SECTOR_SIZE).enumerate() {
+            let first = lba + (index * MAX_TRANSFER) as u64;
+            let sectors = chunk.len() / SECTOR_SIZE;
+            self.command(READ_SECTORS, READ_SECTORS_EXT, first, sectors)?;
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                self.registers().wait_data()?;
+                self.registers().read_sector(sector);
+            }
+        }
+        SECTORS_READ.add((buf.len() / SECTOR_SIZE) as u64);
+        READ_MICROS.observe(start.elapsed().as_micros() as u64);
+        Ok(())
+    }
+
+    /// Write whole sectors from `buf` to `lba` on. They may sit in the
+    /// drive's write cache until `flush`.
+    pub fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        self.check_range(lba, buf.len())?;
+        #[cfg(feature = "fault-inject")]
+        crate::fault::check(crate::fault::Point::DiskWrite)?;
+        let _channel = CHANNELS[self.bus.index()].lock();
+        for (index, chunk) in buf.chunks(MAX_TRANSFER * SECTOR_SIZE).enumerate() {
+            let first = lba + (index * MAX_TRANSFER) as u64;
+            let sectors = chunk.len() / SECTOR_SIZE;
+            self.command(WRITE_SECTORS, WRITE_SECTORS_EXT, first, sectors)?;
+            for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                self.registers().wait_data()?;
+                self.registers().write_sector(sector);
+            }
+            // Each command has to be done before the next is given
+            self.registers().wait_idle()?;
+        }
+        SECTORS_WRITTEN.add((buf.len() / SECTOR_SIZE) as u64);
+        Ok(())
+    }
+
+    /// Have the drive put everything in its write cache on the medium
+    pub fn flush(&self) -> Result<(), &'static str> {
+        let _channel = CHANNELS[self.bus.index()].lock();
+        let registers = self.registers();
+        let slave = if self.drive == Drive::Slave { 0x10 } else { 0 };
+        registers.wait_not_busy()?;
+        registers.drive_head.write(0xe0 | slave);
+        registers.settle();
+        registers.command.write(if self.lba48 { FLUSH_CACHE_EXT } else { FLUSH_CACHE });
+        registers.wait_flushed()
+    }
+
+    // Whether `len` bytes at `lba` is whole sectors, all on the disk
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), &'static str> {
+        if len % SECTOR_SIZE != 0 {
+            return Err("buffer isn't a whole number of sectors");
+        }
+        match lba.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err("beyond the end of the disk"),
+        }
+    }
+
+    // Select the drive and start a transfer of `count` sectors (at most
+    // MAX_TRANSFER), with the 48-bit command only where it's needed
+    fn command(&self, command: u8, command_ext: u8, lba: u64, count: usize) -> Result<(), &'static str> {
+        let registers = self.registers();
+        let slave = if self.drive == Drive::Slave { 0x10 } else { 0 };
+        registers.wait_not_busy()?;
+        if lba + count as u64 <= LBA28_LIMIT || !self.lba48 {
+            registers.drive_head.write(0xe0 | slave | ((lba >> 24) as u8 & 0x0f));
+            registers.settle();
+            // Wraps to 0 for MAX_TRANSFER, as the drive expects
+            registers.sector_count.write(count as u8);
+            registers.lba_low.write(lba as u8);
+            registers.lba_mid.write((lba >> 8) as u8);
+            registers.lba_high.write((lba >> 16) as u8);
+            registers.command.write(command);
+        } else {
+            registers.drive_head.write(0x40 | slave);
+            registers.settle();
+            // High bytes first; each register holds two
+            registers.sector_count.write((count >> 8) as u8);
+            registers.lba_low.write((lba >> 24) as u8);
+            registers.lba_mid.write((lba >> 32) as u8);
+            registers.lba_high.write((lba >> 40) as u8);
+            registers.sector_count.write(count as u8);
+            registers.lba_low.write(lba as u8);
+            registers.lba_mid.write((lba >> 8) as u8);
+            registers.lba_high.write((lba >> 16) as u8);
+            registers.command.write(command_ext);
+        }
+        Ok(())
+    }
+}
+
+// Ask a drive what it is. None if there's no ATA drive there (nothing at
+// all, or ATAPI/SATA, which answer with a signature instead).
+fn identify(registers: &Registers, bus: Bus, drive: Drive) -> Option<Disk> {
+    registers.device_control.write(CONTROL_NIEN);
+    // A channel with nothing on it floats high
+    if registers.status.read() == 0xff {
+        return None;
+    }
+    let mut disk = Disk {
+        bus,
+        drive,
+        sectors: 0,
+        lba48: false,
+        model: [b' '; 40],
+        serial: [b' '; 20],
+        firmware: [b' '; 8],
+        smart: Smart::Unsupported,
+    };
+    let slave = if drive == Drive::Slave { 0x10 } else { 0 };
+    registers.drive_head.write(0xa0 | slave);
+    registers.settle();
+    registers.sector_count.write(0);
+    registers.lba_low.write(0);
+    registers.lba_mid.write(0);
+    registers.lba_high.write(0);
+    registers.command.write(IDENTIFY);
+    if registers.status.read() == 0 {
+        return None;
+    }
+    registers.wait_not_busy().ok()?;
+    if registers.lba_mid.read() != 0 || registers.lba_high.read() != 0 {
+        return None;
+    }
+    registers.wait_data().ok()?;
+
+    let mut words = [0u16; 256];
+    for word in words.iter_mut() {
+        *word = registers.data.read();
+    }
+    disk.lba48 = words[83] & (1 << 10) != 0;
+    disk.sectors = if disk.lba48 {
+        words[100..104].iter().rev().fold(0, |sectors, &word| sectors << 16 | u64::from(word))
+    } else {
+        u64::from(words[61]) << 16 | u64::from(words[60])
+    };
+    // Two characters a word, the first in the high byte
+    let strings: [(&mut [u8], &[u16]); 3] =
+        [(&mut disk.model, &words[27..47]), (&mut disk.serial, &words[10..20]), (&mut disk.firmware, &words[23..27])];
+    for (string, source) in strings {
+        for (pair, &word) in string.chunks_exact_mut(2).zip(source) {
+            pair.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+    // Supported in word 82, turned on in word 85
+    disk.smart = match (words[82] & 1 != 0, words[85] & 1 != 0) {
+        (false, _) => Smart::Unsupported,
+        (true, false) => Smart::Disabled,
+        (true, true) => Smart::Enabled,
+    };
+    Some(disk)
+}
+
+/// Look for drives on both channels and register what's found
+pub fn init() {
+    for &bus in [Bus::Primary, Bus::Secondary].iter() {
+        let (base, control) = bus.ports();
+        let description = match bus {
+            Bus::Primary => "primary IDE channel",
+            Bus::Secondary => "secondary IDE channel",
+        };
+        let channel = match device::register(CHANNEL_NAMES[bus.index()], description, Some(device::platform())) {
+            Ok(channel) => channel,
+            Err(_) => continue,
+        };
+        let ports = [Resource::Ports { first: base, last: base + 7 }, Resource::port(control)];
+        let registers = match resource::claim_all(channel, &ports).and_then(|()| Registers::owned(channel, bus)) {
+            Ok(registers) => registers,
+            Err(_) => {
+                device::bind(channel, "ata", State::Failed);
+                continue;
+            }
+        };
+        let found = [identify(&registers, bus, Drive::Master), identify(&registers, bus, Drive::Slave)];
+        if found.iter().all(Option::is_none) {
+            // Nothing to drive on it, so the ports go back
+            if let Err(message) = device::unregister(channel) {
+                crate::log::warn!("ata: {}", message);
+            }
+            continue;
+        }
+        REGISTERS[bus.index()].call_once(|| registers);
+        device::bind(channel, "ata", State::Active);
+
+        for (drive, disk) in found.iter().enumerate() {
+            let index = bus.index() * 2 + drive;
+            if let Some(disk) = disk {
+                if let Ok(id) = device::register(DISK_NAMES[index], "ATA disk", Some(channel)) {
+                    device::bind(id, "ata", State::Active);
+                }
+                DISKS.lock()[index] = Some(*disk);
+            }
+        }
+    }
+
+    let ata_metrics = [
+        Metric { name: "heorot_ata_read_sectors_total", help: "Sectors read", value: Value::Counter(&SECTORS_READ) },
+        Metric {
+            name: "heorot_ata_written_sectors_total",
+            help: "Sectors written",
+            value: Value::Counter(&SECTORS_WRITTEN),
+        },
+        Metric {
+            name: "heorot_ata_read_microseconds",
+            help: "How long reads took",
+            value: Value::Histogram(&READ_MICROS),
+        },
+    ];
+    for metric in ata_metrics {
+        if let Err(message) = metrics::register(metric) {
+            crate::log::warn!("ata: {}", message);
+        }
+    }
+}
+
+/// The disk at `index`: 0 and 1 are primary master and slave, 2 and 3
+/// secondary
+pub fn disk(index: usize) -> Option<Disk> {
+    DISKS.lock().get(index).copied().flatten()
+}
+
+/// Call `f` with each disk found, by index
+pub fn for_each_disk(mut f: impl FnMut(usize, &Disk)) {
+    let disks = *DISKS.lock();
+    for (index, disk) in disks.iter().enumerate() {
+        if let Some(disk) = disk {
+            f(index, disk);
+        }
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_read_boot_sector() {
+    // QEMU boots the tests from a raw image on the primary master
+    let disk = disk(0).expect("no primary master");
+    assert!(disk.sectors > 0);
+    let mut sector = [0u8; SECTOR_SIZE];
+    disk.read_sectors(0, &mut sector).unwrap();
+    assert_eq!(&sector[510..], &[0x55, 0xaa]);
+
+    assert!(disk.read_sectors(0, &mut sector[..100]).is_err());
+    assert!(disk.read_sectors(disk.sectors, &mut sector).is_err());
+    // Nothing's been written, but the drive takes the command all the same
+    assert_eq!(disk.flush(), Ok(()));
+}
+
+#[test_case]
+fn test_smart() {
+    let disk = disk(0).expect("no primary master");
+    // QEMU's disks have SMART, and never fail it
+    if disk.smart() != Smart::Enabled {
+        return;
+    }
+    assert_eq!(disk.smart_healthy(), Ok(true));
+    let attributes = disk.smart_attributes().unwrap();
+    assert!(!attributes.is_empty());
+    assert!(attributes.iter().all(|attribute| !attribute.failing()));
+}
@@ -0,0 +1,9 @@
+//! Drivers for storage and other hardware that isn't part of every PC, the
+//! virtio transport and the devices on it, and the loopback network
+//! interface
+
+pub mod ata;
+pub mod loopback;
+pub mod pci;
+pub mod virtio;
+pub mod virtio_rng;
@@ -0,0 +1,472 @@
+//! The PCI bus, through configuration mechanism #1: an address written to
+//! 0xcf8 picks a bus, device, function and register, and 0xcfc reads or
+//! writes it. `init` walks every bus once and keeps what it finds, with the
+//! BARs sized, for `functions` to hand out; names for what the numbers mean
+//! come from the small database at the end.
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::device::{self, State};
+use crate::pio::{Port, Ports, ReadWrite};
+use crate::resource::{self, Resource};
+
+pub const MAX_FUNCTIONS: usize = 32;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const ENABLE: u32 = 1 << 31;
+
+// Registers, by offset into the header
+const VENDOR_ID: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0e;
+const BAR0: u8 = 0x10;
+const SECONDARY_BUS: u8 = 0x19;
+const INTERRUPT_LINE: u8 = 0x3c;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_BRIDGE: u8 = 0x01;
+const NO_VENDOR: u16 = 0xffff;
+
+struct Config {
+    address: Port<ReadWrite, u32>,
+    data: Port<ReadWrite, u32>,
+}
+
+// Set by init once the ports are claimed for the bus. Only ever locked with
+// interrupts off, so a handler can't find it held; the address and the data
+// have to go together.
+static CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+static FUNCTIONS: Mutex<[Option<Function>; MAX_FUNCTIONS]> = Mutex::new([None; MAX_FUNCTIONS]);
+
+/// Where a function is on the bus, printed as bus:device.function in hex
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Address {
+        Address { bus, device, function }
+    }
+
+    fn config(self, offset: u8) -> u32 {
+        ENABLE | u32::from(self.bus) << 16 | u32::from(self.device & 0x1f) << 11 | u32::from(self.function & 7) << 8
+            | u32::from(offset & 0xfc)
+    }
+
+    /// The 32-bit register at `offset`, rounded down to a multiple of 4;
+    /// all ones, as for nothing there, if there's no bus
+    pub fn read(self, offset: u8) -> u32 {
+        interrupts::without_interrupts(|| match &*CONFIG.lock() {
+            Some(config) => {
+                config.address.write(self.config(offset));
+                config.data.read()
+            }
+            None => !0,
+        })
+    }
+
+    pub fn write(self, offset: u8, value: u32) {
+        interrupts::without_interrupts(|| {
+            if let Some(config) = &*CONFIG.lock() {
+                config.address.write(self.config(offset));
+                config.data.write(value);
+            }
+        })
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        (self.read(offset) >> (u32::from(offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        (self.read(offset) >> (u32::from(offset & 3) * 8)) as u8
+    }
+
+    // The status register shares its dword, and writing its bits back
+    // would clear them, so it gets zeroes
+    fn write_command(self, command: u16) {
+        self.write(COMMAND, u32::from(command));
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// What a base address register asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// `size` I/O ports from `port`
+    Io { port: u16, size: u16 },
+    /// `size` bytes of memory space from `address`; a 64-bit one takes up
+    /// the register after it too
+    Memory { address: u64, size: u64, prefetchable: bool, wide: bool },
+}
+
+impl Bar {
+    /// As a resource a driver can claim
+    pub fn resource(&self) -> Resource {
+        match *self {
+            Bar::Io { port, size } => Resource::Ports { first: port, last: port + size.saturating_sub(1) },
+            Bar::Memory { address, size, .. } => Resource::Mmio { start: address, length: size },
+        }
+    }
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bar::Io { port, size } => write!(f, "I/O ports at {:#x} [size={}]", port, size),
+            Bar::Memory { address, size, prefetchable, wide } => {
+                write!(f, "Memory at {:#x} ({}-bit, {}) [size={}K]", address, if wide { 64 } else { 32 },
+                    if prefetchable { "prefetchable" } else { "non-prefetchable" }, size / 1024)
+            }
+        }
+    }
+}
+
+/// From a BAR's value and what it read back as once all ones were written
+/// to it, along with the next register's pair for a 64-bit one
+fn decode_bar(value: u32, sizing: u32, high: (u32, u32)) -> Option<Bar> {
+    if value & 1 != 0 {
+        let mask = sizing as u16 & 0xfffc;
+        if mask == 0 {
+            return None;
+        }
+        return Some(Bar::Io { port: value as u16 & 0xfffc, size: (!mask).wrapping_add(1) });
+    }
+    let wide = (value >> 1) & 3 == 2;
+    let address = u64::from(value & 0xffff_fff0) | if wide { u64::from(high.0) << 32 } else { 0 };
+    let mask = u64::from(sizing & 0xffff_fff0) | if wide { u64::from(high.1) << 32 } else { 0xffff_ffff_0000_0000 };
+    if mask == 0 || mask == 0xffff_ffff_0000_0000 {
+        return None;
+    }
+    Some(Bar::Memory { address, size: !mask + 1, prefetchable: value & 8 != 0, wide })
+}
+
+/// One function of a device on the bus, as `init` found it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Function {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// What the firmware routed its interrupt to, if it has one
+    pub irq: Option<u8>,
+    pub bars: [Option<Bar>; 6],
+}
+
+impl Function {
+    fn probe(address: Address) -> Option<Function> {
+        let id = address.read(VENDOR_ID);
+        if id as u16 == NO_VENDOR {
+            return None;
+        }
+        let class = address.read(CLASS);
+        let header_type = address.read_u8(HEADER_TYPE);
+        let interrupt = address.read(INTERRUPT_LINE);
+        let (line, pin) = (interrupt as u8, (interrupt >> 8) as u8);
+        let mut function = Function {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            irq: if pin != 0 && line != 0xff { Some(line) } else { None },
+            bars: [None; 6],
+        };
+        function.size_bars();
+        Some(function)
+    }
+
+    // With decoding off meanwhile, so the device doesn't answer at the
+    // all-ones address
+    fn size_bars(&mut self) {
+        let address = self.address;
+        let count = match self.header_type & 0x7f {
+            0 => 6,
+            HEADER_BRIDGE => 2,
+            _ => return,
+        };
+        let command = address.read_u16(COMMAND);
+        address.write_command(command & !(COMMAND_IO | COMMAND_MEMORY));
+        let sizing = |index: usize| {
+            let offset = BAR0 + 4 * index as u8;
+            let value = address.read(offset);
+            address.write(offset, 0xffff_ffff);
+            let size = address.read(offset);
+            address.write(offset, value);
+            (value, size)
+        };
+        let mut index = 0;
+        while index < count {
+            let (value, size) = sizing(index);
+            let wide = value & 1 == 0 && (value >> 1) & 3 == 2 && index + 1 < count;
+            let high = if wide { sizing(index + 1) } else { (0, 0) };
+            self.bars[index] = decode_bar(value, size, high);
+            index += if wide { 2 } else { 1 };
+        }
+        address.write_command(command);
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type & 0x7f == HEADER_BRIDGE
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+
+    /// The vendor's and the device's names, if the database has them
+    pub fn names(&self) -> (Option<&'static str>, Option<&'static str>) {
+        (vendor_name(self.vendor_id), device_name(self.vendor_id, self.device_id))
+    }
+
+    /// Turn on memory and I/O decoding and bus mastering, for a driver
+    /// taking the device over
+    pub fn enable(&self) {
+        let command = self.address.read_u16(COMMAND);
+        self.address.write_command(command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    }
+}
+
+// Whether anything answers at 0xcf8: mechanism #1 reads back what's written
+fn present(config: &Config) -> bool {
+    let old = config.address.read();
+    config.address.write(ENABLE);
+    let found = config.address.read() == ENABLE;
+    config.address.write(old);
+    found
+}
+
+// Each function on `bus`, and on every bus behind a bridge there; `found`
+// counts them even past the table's end
+fn scan_bus(bus: u8, table: &mut [Option<Function>; MAX_FUNCTIONS], found: &mut usize, depth: usize) {
+    for device in 0..32 {
+        let first = match Function::probe(Address::new(bus, device, 0)) {
+            Some(first) => first,
+            None => continue,
+        };
+        let functions = if first.header_type & HEADER_MULTIFUNCTION != 0 { 8 } else { 1 };
+        for number in 0..functions {
+            let function = match number {
+                0 => first,
+                _ => match Function::probe(Address::new(bus, device, number)) {
+                    Some(function) => function,
+                    None => continue,
+                },
+            };
+            if let Some(slot) = table.get_mut(*found) {
+                *slot = Some(function);
+            }
+            *found += 1;
+            let secondary = function.address.read_u8(SECONDARY_BUS);
+            // A bus can't be behind itself; and nothing real nests deeper
+            if function.is_bridge() && secondary > bus && depth < 8 {
+                scan_bus(secondary, table, found, depth + 1);
+            }
+        }
+    }
+}
+
+/// Find every function on the bus, and register the bus with the device
+/// table; the number found, or why there's no bus
+pub fn init() -> Result<usize, &'static str> {
+    let id = device::register("pci", "PCI bus", Some(device::platform()))?;
+    let ports = [Resource::Ports { first: CONFIG_ADDRESS, last: CONFIG_ADDRESS + 3 },
+        Resource::Ports { first: CONFIG_DATA, last: CONFIG_DATA + 3 }];
+    let config = resource::claim_all(id, &ports).and_then(|()| {
+        let address = Ports::owned(id, CONFIG_ADDRESS, CONFIG_ADDRESS + 3)?;
+        let data = Ports::owned(id, CONFIG_DATA, CONFIG_DATA + 3)?;
+        Ok(Config { address: address.port(0)?, data: data.port(0)? })
+    });
+    let config = match config {
+        Ok(config) => config,
+        Err(message) => {
+            device::bind(id, "pci", State::Failed);
+            return Err(message);
+        }
+    };
+    // Only tried once the ports are the bus's; they go back if there's none
+    if !interrupts::without_interrupts(|| present(&config)) {
+        device::unregister(id)?;
+        return Err("no PCI configuration space");
+    }
+    interrupts::without_interrupts(|| *CONFIG.lock() = Some(config));
+    let mut table = [None; MAX_FUNCTIONS];
+    let mut found = 0;
+    scan_bus(0, &mut table, &mut found, 0);
+    interrupts::without_interrupts(|| *FUNCTIONS.lock() = table);
+    device::bind(id, "pci", State::Active);
+    if found > MAX_FUNCTIONS {
+        crate::log::warn!("{} functions, only the first {} kept", found, MAX_FUNCTIONS);
+    }
+    Ok(found)
+}
+
+/// Every function `init` found, by address
+pub fn functions() -> impl Iterator<Item = Function> {
+    let table = interrupts::without_interrupts(|| *FUNCTIONS.lock());
+    (0..MAX_FUNCTIONS).filter_map(move |index| table[index])
+}
+
+/// The first function with this vendor and device ID
+pub fn find(vendor_id: u16, device_id: u16) -> Option<Function> {
+    functions().find(|function| function.vendor_id == vendor_id && function.device_id == device_id)
+}
+
+/// The first function of this class and subclass
+pub fn find_class(class: u8, subclass: u8) -> Option<Function> {
+    functions().find(|function| function.class == class && function.subclass == subclass)
+}
+
+// The database
+
+const CLASSES: &[(u8, u8, &str)] = &[
+    (0x00, 0x00, "Non-VGA unclassified device"),
+    (0x00, 0x01, "VGA compatible unclassified device"),
+    (0x01, 0x00, "SCSI storage controller"),
+    (0x01, 0x01, "IDE interface"),
+    (0x01, 0x05, "ATA controller"),
+    (0x01, 0x06, "SATA controller"),
+    (0x01, 0x08, "Non-Volatile memory controller"),
+    (0x01, 0x80, "Mass storage controller"),
+    (0x02, 0x00, "Ethernet controller"),
+    (0x02, 0x80, "Network controller"),
+    (0x03, 0x00, "VGA compatible controller"),
+    (0x03, 0x80, "Display controller"),
+    (0x04, 0x01, "Multimedia audio controller"),
+    (0x04, 0x03, "Audio device"),
+    (0x05, 0x00, "RAM memory"),
+    (0x06, 0x00, "Host bridge"),
+    (0x06, 0x01, "ISA bridge"),
+    (0x06, 0x04, "PCI bridge"),
+    (0x06, 0x80, "Bridge"),
+    (0x07, 0x00, "Serial controller"),
+    (0x08, 0x00, "PIC"),
+    (0x08, 0x80, "System peripheral"),
+    (0x0c, 0x03, "USB controller"),
+    (0x0c, 0x05, "SMBus"),
+    (0xff, 0x00, "Unassigned class"),
+];
+
+const CLASS_NAMES: [&str; 0x14] = [
+    "Unclassified device",
+    "Mass storage controller",
+    "Network controller",
+    "Display controller",
+    "Multimedia controller",
+    "Memory controller",
+    "Bridge",
+    "Communication controller",
+    "Generic system peripheral",
+    "Input device controller",
+    "Docking station",
+    "Processor",
+    "Serial bus controller",
+    "Wireless controller",
+    "Intelligent controller",
+    "Satellite communications controller",
+    "Encryption controller",
+    "Signal processing controller",
+    "Processing accelerators",
+    "Non-essential instrumentation",
+];
+
+const VENDORS: &[(u16, &str)] = &[
+    (0x1022, "AMD"),
+    (0x10de, "NVIDIA"),
+    (0x10ec, "Realtek"),
+    (0x1234, "QEMU"),
+    (0x15ad, "VMware"),
+    (0x1af4, "Red Hat (virtio)"),
+    (0x1b36, "Red Hat (QEMU)"),
+    (0x8086, "Intel"),
+];
+
+const DEVICES: &[(u16, u16, &str)] = &[
+    (0x1234, 0x1111, "Standard VGA"),
+    (0x1af4, 0x1000, "Virtio network device"),
+    (0x1af4, 0x1001, "Virtio block device"),
+    (0x1af4, 0x1005, "Virtio RNG"),
+    (0x1af4, 0x1041, "Virtio 1.0 network device"),
+    (0x1af4, 0x1042, "Virtio 1.0 block device"),
+    (0x1b36, 0x000d, "QEMU XHCI Host Controller"),
+    (0x8086, 0x100e, "82540EM Gigabit Ethernet Controller"),
+    (0x8086, 0x10d3, "82574L Gigabit Network Connection"),
+    (0x8086, 0x1237, "440FX - 82441FX PMC [Natoma]"),
+    (0x8086, 0x2415, "82801AA AC'97 Audio Controller"),
+    (0x8086, 0x2918, "82801IB (ICH9) LPC Interface Controller"),
+    (0x8086, 0x2922, "82801IR/IO/IH (ICH9R/DO/DH) 6 port SATA Controller [AHCI mode]"),
+    (0x8086, 0x2930, "82801I (ICH9 Family) SMBus Controller"),
+    (0x8086, 0x29c0, "82G33/G31/P35/P31 Express DRAM Controller"),
+    (0x8086, 0x7000, "82371SB PIIX3 ISA [Natoma/Triton II]"),
+    (0x8086, 0x7010, "82371SB PIIX3 IDE [Natoma/Triton II]"),
+    (0x8086, 0x7020, "82371SB PIIX3 USB [Natoma/Triton II]"),
+    (0x8086, 0x7113, "82371AB/EB/MB PIIX4 ACPI"),
+];
+
+/// What a class and subclass are called, or just the class if the
+/// subclass isn't in the database
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    CLASSES
+        .iter()
+        .find(|&&(known, known_sub, _)| known == class && known_sub == subclass)
+        .map(|&(_, _, name)| name)
+        .or_else(|| CLASS_NAMES.get(usize::from(class)).copied())
+        .unwrap_or("Unknown class")
+}
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    VENDORS.iter().find(|&&(id, _)| id == vendor_id).map(|&(_, name)| name)
+}
+
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    DEVICES.iter().find(|&&(vendor, id, _)| vendor == vendor_id && id == device_id).map(|&(_, _, name)| name)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_finds_the_host_bridge() {
+    // Every machine QEMU emulates has one at the very start of bus 0, and
+    // init has already run by the time tests do
+    let host = functions().next().expect("no PCI functions");
+    assert_eq!(host.address, Address::new(0, 0, 0));
+    assert_eq!((host.class, host.subclass), (0x06, 0x00));
+    assert_eq!(host.class_name(), "Host bridge");
+    assert_eq!(find(host.vendor_id, host.device_id), Some(host));
+    assert_eq!(find_class(0x06, 0x00), Some(host));
+    assert_eq!(alloc::format!("{}", Address::new(0, 0x1f, 2)), "00:1f.2");
+}
+
+#[test_case]
+fn test_bars_decode() {
+    assert_eq!(decode_bar(0xc001, 0xffff_ffe1, (0, 0)), Some(Bar::Io { port: 0xc000, size: 32 }));
+    assert_eq!(decode_bar(0xfebc_0000, 0xfffe_0000, (0, 0)),
+        Some(Bar::Memory { address: 0xfebc_0000, size: 128 * 1024, prefetchable: false, wide: false }));
+    assert_eq!(decode_bar(0xfe00_000c, 0xffff_c00c, (0x1, 0xffff_ffff)),
+        Some(Bar::Memory { address: 0x1_fe00_0000, size: 16 * 1024, prefetchable: true, wide: true }));
+    // Nothing there
+    assert_eq!(decode_bar(0, 0, (0, 0)), None);
+    assert_eq!(class_name(0x02, 0x42), "Network controller");
+    assert_eq!(device_name(0x8086, 0x100e), Some("82540EM Gigabit Ethernet Controller"));
+}
@@ -0,0 +1,180 @@
+//! Virtio devices through the legacy PCI transport, which QEMU's
+//! transitional devices have: the device's registers are I/O ports in BAR0,
+//! and each virtqueue sits in physically contiguous memory the device is
+//! told the page number of. Queues are polled one request at a time;
+//! nothing here takes interrupts.
+
+use core::sync::atomic::{fence, Ordering};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::device::DeviceId;
+use crate::memory::{self, paging, FRAME_SIZE};
+use crate::pio::{Port, Ports, ReadOnly, ReadWrite, WriteOnly};
+use crate::time::{Duration, Instant};
+use super::pci::{Bar, Function};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+// Registers, by offset into BAR0
+const GUEST_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0c;
+const QUEUE_SELECT: u16 = 0x0e;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const REGISTERS_SIZE: u16 = 0x14;
+
+const ACKNOWLEDGE: u8 = 1;
+const DRIVER: u8 = 2;
+const DRIVER_OK: u8 = 4;
+const FAILED: u8 = 0x80;
+
+// The device writes to the buffer, rather than reading it
+const DESCRIPTOR_WRITE: u16 = 2;
+const DESCRIPTOR_SIZE: u64 = 16;
+// The used ring starts on the page after the descriptors and available
+// ring, so a queue takes two pages for as big as fits
+const USED_RING: u64 = FRAME_SIZE;
+const MAX_QUEUE_SIZE: u16 = 128;
+
+/// A legacy device's registers, claimed for its driver
+pub struct Transport {
+    guest_features: Port<ReadWrite, u32>,
+    queue_address: Port<ReadWrite, u32>,
+    queue_size: Port<ReadOnly, u16>,
+    queue_select: Port<ReadWrite, u16>,
+    queue_notify: Port<WriteOnly, u16>,
+    status: Port<ReadWrite, u8>,
+}
+
+impl Transport {
+    /// Claim `function`'s registers for `owner`, turn it on, and reset it
+    /// so it can be set up from scratch
+    pub fn claim(owner: DeviceId, function: &Function) -> Result<Transport, &'static str> {
+        let (port, size) = match function.bars[0] {
+            Some(Bar::Io { port, size }) if size >= REGISTERS_SIZE => (port, size),
+            _ => return Err("no legacy virtio registers"),
+        };
+        let ports = Ports::claim(owner, port, port + (size - 1))?;
+        let transport = Transport {
+            guest_features: ports.port(GUEST_FEATURES)?,
+            queue_address: ports.port(QUEUE_ADDRESS)?,
+            queue_size: ports.port(QUEUE_SIZE)?,
+            queue_select: ports.port(QUEUE_SELECT)?,
+            queue_notify: ports.port(QUEUE_NOTIFY)?,
+            status: ports.port(DEVICE_STATUS)?,
+        };
+        function.enable();
+        transport.status.write(0);
+        transport.status.write(ACKNOWLEDGE | DRIVER);
+        Ok(transport)
+    }
+
+    /// Take none of the optional features, give the device queue `index`,
+    /// and tell it the driver's ready. On failure the device is told it's
+    /// not going to be driven.
+    pub fn start(&self, index: u16) -> Result<Queue, &'static str> {
+        let queue = self.setup(index);
+        match &queue {
+            Ok(queue) => {
+                self.queue_address.write((queue.frame.start_address().as_u64() / FRAME_SIZE) as u32);
+                self.status.write(ACKNOWLEDGE | DRIVER | DRIVER_OK);
+            }
+            Err(_) => self.status.write(FAILED),
+        }
+        queue
+    }
+
+    fn setup(&self, index: u16) -> Result<Queue, &'static str> {
+        self.guest_features.write(0);
+        self.queue_select.write(index);
+        let size = self.queue_size.read();
+        if size == 0 {
+            return Err("no such virtqueue");
+        }
+        if size > MAX_QUEUE_SIZE {
+            return Err("virtqueue is too big");
+        }
+        let frame = memory::allocate_contiguous(2).ok_or(memory::OUT_OF_MEMORY)?;
+        if frame.start_address().as_u64() / FRAME_SIZE > u64::from(u32::MAX) {
+            free_queue(frame);
+            return Err("virtqueue is out of the device's reach");
+        }
+        // Zeroed, the rings are empty
+        unsafe { virt(frame, 0).as_mut_ptr::<u8>().write_bytes(0, 2 * FRAME_SIZE as usize) };
+        Ok(Queue { index, frame, size, used_seen: 0 })
+    }
+
+    /// Reset the device, which stops it using its queues
+    pub fn reset(&self) {
+        self.status.write(0);
+    }
+}
+
+fn virt(frame: PhysFrame, offset: u64) -> VirtAddr {
+    paging::phys_to_virt(frame.start_address()) + offset
+}
+
+fn free_queue(frame: PhysFrame) {
+    // Neither has been given to the device
+    unsafe {
+        memory::deallocate_frame(frame);
+        memory::deallocate_frame(frame + 1);
+    }
+}
+
+/// One virtqueue, with one request in it at a time
+pub struct Queue {
+    index: u16,
+    frame: PhysFrame,
+    size: u16,
+    // The used ring's index as of the last request done
+    used_seen: u16,
+}
+
+impl Queue {
+    fn available_ring(&self) -> VirtAddr {
+        virt(self.frame, u64::from(self.size) * DESCRIPTOR_SIZE)
+    }
+
+    /// Give the device `len` bytes at `buffer` to fill, and wait up to
+    /// `timeout` for it to; how many it wrote. If it times out the request
+    /// is still the device's, and the queue is no good until a reset.
+    pub fn fill(&mut self, transport: &Transport, buffer: PhysAddr, len: u32, timeout: Duration)
+        -> Result<u32, &'static str>
+    {
+        unsafe {
+            let descriptor = virt(self.frame, 0).as_mut_ptr::<u8>();
+            (descriptor as *mut u64).write_volatile(buffer.as_u64());
+            (descriptor.add(8) as *mut u32).write_volatile(len);
+            (descriptor.add(12) as *mut u16).write_volatile(DESCRIPTOR_WRITE);
+            (descriptor.add(14) as *mut u16).write_volatile(0);
+
+            let available = self.available_ring().as_mut_ptr::<u16>();
+            let index = available.add(1).read_volatile();
+            available.add(2 + usize::from(index % self.size)).write_volatile(0);
+            // The descriptor has to be there before the device can see it
+            fence(Ordering::SeqCst);
+            available.add(1).write_volatile(index.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        transport.queue_notify.write(self.index);
+
+        let used = virt(self.frame, USED_RING).as_ptr::<u16>();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let index = unsafe { used.add(1).read_volatile() };
+            if index != self.used_seen {
+                fence(Ordering::SeqCst);
+                let slot = u64::from(self.used_seen % self.size);
+                self.used_seen = index;
+                let written = virt(self.frame, USED_RING + 4 + slot * 8 + 4).as_ptr::<u32>();
+                return Ok(unsafe { written.read_volatile() }.min(len));
+            }
+            if Instant::now() >= deadline {
+                return Err("virtio device didn't answer");
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! virtio-rng, as QEMU's `-device virtio-rng-pci` has it: randomness from
+//! the host, mixed into the entropy pool once at boot and every
+//! RESEED_PERIOD after. Reading waits on the device, so the reseeds after
+//! boot run on the system workqueue rather than in the idle loop.
+
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use crate::device::{self, State};
+use crate::entropy;
+use crate::memory::{self, paging, FRAME_SIZE};
+use crate::time::Duration;
+use crate::workqueue;
+use super::pci;
+use super::virtio::{self, Queue, Transport};
+
+// The transitional device, which has the legacy registers
+const DEVICE_ID: u16 = 0x1005;
+// Bytes mixed in at a time
+const READ_SIZE: usize = 64;
+const RESEED_PERIOD: Duration = Duration::from_secs(60);
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// What `read` fails with when there's no device, or it's stopped answering
+pub const NO_DEVICE: &str = "no virtio-rng device";
+
+struct Rng {
+    id: device::DeviceId,
+    transport: Transport,
+    queue: Queue,
+    // What the device fills, before it's copied out
+    buffer: PhysFrame,
+}
+
+// Set by init, and emptied if the device stops answering. Never touched by
+// interrupt handlers.
+static RNG: Mutex<Option<Rng>> = Mutex::new(None);
+
+/// Fill as much of `buffer` as the device will in one go, up to a page's
+/// worth; how much that was
+pub fn read(buffer: &mut [u8]) -> Result<usize, &'static str> {
+    let mut slot = RNG.lock();
+    let rng = slot.as_mut().ok_or(NO_DEVICE)?;
+    let len = buffer.len().min(FRAME_SIZE as usize);
+    match rng.queue.fill(&rng.transport, rng.buffer.start_address(), len as u32, TIMEOUT) {
+        Ok(written) => {
+            let written = written as usize;
+            let source = paging::phys_to_virt(rng.buffer.start_address()).as_ptr::<u8>();
+            // The device is done with it
+            unsafe { core::ptr::copy_nonoverlapping(source, buffer.as_mut_ptr(), written) };
+            Ok(written)
+        }
+        Err(message) => {
+            // The request may still land, so neither the queue nor the
+            // buffer can be reused
+            rng.transport.reset();
+            device::set_state(rng.id, State::Failed);
+            *slot = None;
+            Err(message)
+        }
+    }
+}
+
+fn reseed() {
+    let mut bytes = [0; READ_SIZE];
+    match read(&mut bytes) {
+        Ok(read) => entropy::add_entropy(&bytes[..read]),
+        // Said so when it stopped
+        Err(NO_DEVICE) => {}
+        Err(message) => crate::log::warn!("virtio-rng: {}", message),
+    }
+}
+
+fn queue_reseed() {
+    if let Err(message) = workqueue::queue(workqueue::system(), reseed) {
+        crate::log::warn!("virtio-rng: {}", message);
+    }
+}
+
+/// Set up the device, if there is one, and start feeding the pool from it
+pub fn init() {
+    let function = match pci::find(virtio::VENDOR_ID, DEVICE_ID) {
+        Some(function) => function,
+        None => return,
+    };
+    let bus = device::find(Some(device::platform()), "pci");
+    let id = match device::register("virtio-rng", "virtio entropy source", bus) {
+        Ok(id) => id,
+        Err(message) => {
+            crate::log::warn!("virtio-rng: {}", message);
+            return;
+        }
+    };
+    let rng = Transport::claim(id, &function).and_then(|transport| {
+        let buffer = memory::allocate_frame().ok_or(memory::OUT_OF_MEMORY)?;
+        match transport.start(0) {
+            Ok(queue) => Ok(Rng { id, transport, queue, buffer }),
+            Err(message) => {
+                // Never given to the device
+                unsafe { memory::deallocate_frame(buffer) };
+                Err(message)
+            }
+        }
+    });
+    match rng {
+        Ok(rng) => *RNG.lock() = Some(rng),
+        Err(message) => {
+            device::bind(id, "virtio-rng", State::Failed);
+            crate::log::warn!("virtio-rng: {}", message);
+            return;
+        }
+    }
+    device::bind(id, "virtio-rng", State::Active);
+    reseed();
+    if let Err(message) = crate::timer::every(RESEED_PERIOD, queue_reseed) {
+        crate::log::warn!("virtio-rng: {}", message);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_reads_from_the_host() {
+    // The test runner gives QEMU one
+    let mut first = [0; READ_SIZE];
+    let mut second = [0; READ_SIZE];
+    assert_eq!(read(&mut first), Ok(READ_SIZE));
+    assert_eq!(read(&mut second), Ok(READ_SIZE));
+    assert_ne!(first, second);
+}
@@ -0,0 +1,129 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::port::{PortReadOnly, PortWriteOnly};
+
+// Everything in here is deliberately primitive: no lazy_static, no locks,
+// no heap. It has to work before `init()` has set anything up, and from a
+// panic that happened while the real console was half-initialized.
+
+const VGA_BUFFER: *mut u16 = 0xb8000 as *mut u16;
+const BUFFER_HEIGHT: usize = 25;
+const BUFFER_WIDTH: usize = 80;
+const COLOR: u16 = 0x0f00; // White on black
+
+const COM1: u16 = 0x3F8;
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+
+static COLUMN: AtomicUsize = AtomicUsize::new(0);
+static CONSOLE_READY: AtomicBool = AtomicBool::new(false);
+
+/// Called once the regular WRITER/SERIAL1 consoles can be relied on
+pub fn mark_console_ready() {
+    // The VGA console keeps its own copy of the screen, behind ours
+    crate::vga_buffer::reload();
+    CONSOLE_READY.store(true, Ordering::SeqCst);
+}
+
+/// Whether output should still go through the early console
+pub fn console_ready() -> bool {
+    CONSOLE_READY.load(Ordering::SeqCst)
+}
+
+fn serial_write_byte(byte: u8) {
+    let mut data: PortWriteOnly<u8> = PortWriteOnly::new(COM1);
+    let mut line_status: PortReadOnly<u8> = PortReadOnly::new(COM1 + 5);
+
+    unsafe {
+        // Give up after a while rather than hang on a missing UART
+        for _ in 0..100_000 {
+            if line_status.read() & LINE_STATUS_THR_EMPTY != 0 {
+                break;
+            }
+        }
+        data.write(byte);
+    }
+}
+
+fn vga_new_line() {
+    unsafe {
+        for row in 1..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let cell = VGA_BUFFER.add(row * BUFFER_WIDTH + col).read_volatile();
+                VGA_BUFFER.add((row - 1) * BUFFER_WIDTH + col).write_volatile(cell);
+            }
+        }
+        for col in 0..BUFFER_WIDTH {
+            VGA_BUFFER
+                .add((BUFFER_HEIGHT - 1) * BUFFER_WIDTH + col)
+                .write_volatile(COLOR | u16::from(b' '));
+        }
+    }
+    COLUMN.store(0, Ordering::Relaxed);
+}
+
+fn vga_write_byte(byte: u8) {
+    if byte == b'\n' {
+        vga_new_line();
+        return;
+    }
+    if COLUMN.load(Ordering::Relaxed) >= BUFFER_WIDTH {
+        vga_new_line();
+    }
+
+    let col = COLUMN.fetch_add(1, Ordering::Relaxed);
+    let byte = match byte {
+        0x20..=0x7e => byte,
+        _ => 0xfe,
+    };
+    unsafe {
+        VGA_BUFFER
+            .add((BUFFER_HEIGHT - 1) * BUFFER_WIDTH + col)
+            .write_volatile(COLOR | u16::from(byte));
+    }
+}
+
+/// Lock-free writer that goes straight to COM1 and, unless the console is
+/// serial only, the VGA buffer
+pub struct EarlyWriter;
+
+impl fmt::Write for EarlyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            serial_write_byte(byte);
+            if !crate::cmdline::headless() {
+                vga_write_byte(byte);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `EarlyWriter` without the screen: COM1 alone, for a panic handler that
+/// can't trust anything else
+pub struct RawSerial;
+
+impl fmt::Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(serial_write_byte);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = EarlyWriter.write_fmt(args);
+}
+
+/// Prints through the early console, usable before `init()` finishes.
+#[macro_export]
+macro_rules! early_print {
+    ($($arg:tt)*) => ($crate::early_console::_print(format_args!($($arg)*)));
+}
+
+/// Prints through the early console, appending a newline.
+#[macro_export]
+macro_rules! early_println {
+    () => ($crate::early_print!("\n"));
+    ($($arg:tt)*) => ($crate::early_print!("{}\n", format_args!($($arg)*)));
+}
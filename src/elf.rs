@@ -0,0 +1,325 @@
+//! ELF64 executables: statically linked x86-64 ones, loaded at the addresses
+//! they were linked for. Those have to be inside a program's part of its
+//! address space (user::space::SPACE_START on), clear of its stack; linking
+//! with `ld -Ttext-segment=0x100000000000` does it.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::memory::paging;
+use crate::user::space::{AddressSpace, SPACE_END, SPACE_START};
+use crate::user::{self, Claim, Exit, STACK_START, STACK_TOP};
+use crate::{cpu, fs};
+
+/// The biggest executable `exec_file` will read
+pub const MAX_FILE_SIZE: usize = 64 * 1024;
+/// The most room a program's arguments and environment can take on its
+/// stack, strings and pointers together
+pub const MAX_ARGS_SIZE: usize = 4096;
+// The most memory a program's segments can take, in pages (4 MiB)
+const MAX_PAGES: u64 = 1024;
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+pub(crate) fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub(crate) fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+pub(crate) fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(u32_at(bytes, offset)) | (u64::from(u32_at(bytes, offset + 4)) << 32)
+}
+
+/// A PT_LOAD segment: `file_size` bytes from `offset` in the file, put at
+/// `address`, then zeroes up to `memory_size`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u64,
+    pub memory_size: u64,
+    pub offset: usize,
+    pub file_size: usize,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    fn pages(&self) -> (Page<Size4KiB>, Page<Size4KiB>) {
+        (Page::containing_address(VirtAddr::new(self.address)),
+            Page::containing_address(VirtAddr::new(self.address + self.memory_size - 1)))
+    }
+}
+
+/// An executable, checked over: all it asks for can be loaded
+pub struct Elf<'a> {
+    image: &'a [u8],
+    entry: u64,
+    program_headers: usize,
+    entry_size: usize,
+    count: usize,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Elf<'a>, &'static str> {
+        if image.len() < HEADER_SIZE || &image[..4] != MAGIC {
+            return Err("not an ELF file");
+        }
+        if image[4] != CLASS_64 || image[5] != DATA_LITTLE_ENDIAN || u16_at(image, 18) != MACHINE_X86_64 {
+            return Err("not an x86-64 ELF file");
+        }
+        if u16_at(image, 16) != TYPE_EXECUTABLE {
+            return Err("not an executable");
+        }
+        let program_headers = u64_at(image, 32);
+        let entry_size = usize::from(u16_at(image, 54));
+        let count = usize::from(u16_at(image, 56));
+        let table_size = (entry_size * count) as u64;
+        if entry_size < PROGRAM_HEADER_SIZE
+            || program_headers.checked_add(table_size).map_or(true, |end| end > image.len() as u64)
+        {
+            return Err("bad program header table");
+        }
+        let elf = Elf { image, entry: u64_at(image, 24), program_headers: program_headers as usize, entry_size, count };
+
+        let mut pages = 0;
+        let mut entry_in_code = false;
+        for segment in elf.segments() {
+            let segment = segment?;
+            let (first, last) = segment.pages();
+            pages += last - first + 1;
+            entry_in_code |= segment.executable
+                && (segment.address..segment.address + segment.memory_size).contains(&elf.entry);
+        }
+        if pages > MAX_PAGES {
+            return Err("program is too big");
+        }
+        if !entry_in_code {
+            return Err("entry point isn't in code");
+        }
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    /// The PT_LOAD segments, in the order the file lists them
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment, &'static str>> + '_ {
+        (0..self.count).filter_map(move |index| self.segment(index).transpose())
+    }
+
+    fn segment(&self, index: usize) -> Result<Option<Segment>, &'static str> {
+        let header = &self.image[self.program_headers + index * self.entry_size..];
+        let memory_size = u64_at(header, 40);
+        if u32_at(header, 0) != PT_LOAD || memory_size == 0 {
+            return Ok(None);
+        }
+        let flags = u32_at(header, 4);
+        let offset = u64_at(header, 8);
+        let address = u64_at(header, 16);
+        let file_size = u64_at(header, 32);
+        if file_size > memory_size {
+            return Err("segment is bigger in the file than in memory");
+        }
+        if offset.checked_add(file_size).map_or(true, |end| end > self.image.len() as u64) {
+            return Err("segment is cut off");
+        }
+        let end = address.checked_add(memory_size).ok_or("segment is outside the program's address space")?;
+        if address < SPACE_START || end > SPACE_END {
+            return Err("segment is outside the program's address space");
+        }
+        // Anywhere the stack could be put
+        if address < STACK_TOP && end > STACK_START {
+            return Err("segment overlaps the stack");
+        }
+        Ok(Some(Segment {
+            address,
+            memory_size,
+            offset: offset as usize,
+            file_size: file_size as usize,
+            writable: flags & PF_W != 0,
+            executable: flags & PF_X != 0,
+        }))
+    }
+}
+
+// Map a segment's pages the way it asks and copy its bytes in. A page it
+// shares with an earlier segment gets what both asked for.
+fn load(image: &[u8], segment: &Segment) -> Result<(), &'static str> {
+    let mut flags = PageTableFlags::empty();
+    if segment.writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !segment.executable && cpu::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let (first, last) = segment.pages();
+    for page in Page::range_inclusive(first, last) {
+        let shared = unsafe {
+            paging::with_entry(page, |entry| {
+                let old = entry.flags();
+                if !old.contains(PageTableFlags::PRESENT) {
+                    return false;
+                }
+                let no_execute = (old & flags).contains(PageTableFlags::NO_EXECUTE);
+                let mut merged = (old | flags) - PageTableFlags::NO_EXECUTE;
+                merged.set(PageTableFlags::NO_EXECUTE, no_execute);
+                entry.set_flags(merged);
+                true
+            })
+        };
+        if shared == Some(true) {
+            tlb::flush(page.start_address());
+        } else {
+            user::map(page.start_address(), 1, flags)?;
+        }
+    }
+    user::load(VirtAddr::new(segment.address), &image[segment.offset..segment.offset + segment.file_size])
+}
+
+// Put argc, argv, the environment and an empty auxiliary vector at the top
+// of the stack, with the strings they point at above them, as the System V
+// ABI has it; returns where the stack pointer starts, 16-byte aligned
+fn push_args(argv: &[&str], envp: &[&str]) -> Result<VirtAddr, &'static str> {
+    let strings: usize = argv.iter().chain(envp).map(|string| string.len() + 1).sum();
+    // argc, the two lists with a null after each, and AT_NULL's pair
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2;
+    let top = user::stack_top();
+    let strings_start = (top - strings as u64) & !15;
+    let start = (strings_start - words as u64 * 8) & !15;
+    if top - start > MAX_ARGS_SIZE as u64 {
+        return Err("arguments are too long");
+    }
+
+    let mut pointers = Vec::with_capacity(words * 8);
+    let mut text = Vec::with_capacity(strings);
+    pointers.extend_from_slice(&(argv.len() as u64).to_le_bytes());
+    for list in [argv, envp] {
+        for string in list {
+            pointers.extend_from_slice(&(strings_start + text.len() as u64).to_le_bytes());
+            text.extend_from_slice(string.as_bytes());
+            text.push(0);
+        }
+        pointers.extend_from_slice(&0u64.to_le_bytes());
+    }
+    pointers.extend_from_slice(&[0; 16]);
+    user::load(VirtAddr::new(start), &pointers)?;
+    user::load(VirtAddr::new(strings_start), &text)?;
+    Ok(VirtAddr::new(start))
+}
+
+/// Load `image` into an address space of its own, and run it in ring 3
+/// from its entry point until it exits or faults, with no arguments or
+/// environment. Everything it had is freed afterwards.
+pub fn exec(image: &[u8]) -> Result<Exit, &'static str> {
+    exec_with(image, &[], &[])
+}
+
+/// `exec`, giving the program `argv` and the environment `envp` (each
+/// "NAME=value") on its stack. They can take up to MAX_ARGS_SIZE bytes.
+pub fn exec_with(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Exit, &'static str> {
+    let elf = Elf::parse(image)?;
+    let _claim = Claim::new()?;
+    let space = AddressSpace::new()?;
+    space.enter(|| {
+        for segment in elf.segments() {
+            load(image, &segment?)?;
+        }
+        user::map_stack()?;
+        let stack = push_args(argv, envp)?;
+        Ok(unsafe { user::enter(elf.entry(), stack) })
+    })
+}
+
+/// Read the executable at `path` from the filesystem and `exec_with` it
+pub fn exec_file(path: &str, argv: &[&str], envp: &[&str]) -> Result<Exit, &'static str> {
+    let mut file = fs::File::open_for(path, fs::Access::Execute)?;
+    let size = file.size() as usize;
+    if size > MAX_FILE_SIZE {
+        return Err("file is too big to run");
+    }
+    let mut image = vec![0u8; size];
+    let mut done = 0;
+    while done < size {
+        match file.read(&mut image[done..])? {
+            0 => return Err("file ended early"),
+            n => done += n,
+        }
+    }
+    exec_with(&image, argv, envp)
+}
+
+/// TESTS
+
+// One segment holding the whole file, headers and all, at `address`, and
+// the entry point just past the headers
+#[cfg(test)]
+fn executable(address: u64, flags: u32, code: &[u8]) -> Vec<u8> {
+    let mut image = vec![0u8; HEADER_SIZE + PROGRAM_HEADER_SIZE];
+    image[..4].copy_from_slice(MAGIC);
+    image[4] = CLASS_64;
+    image[5] = DATA_LITTLE_ENDIAN;
+    image[6] = 1;
+    image[16..18].copy_from_slice(&TYPE_EXECUTABLE.to_le_bytes());
+    image[18..20].copy_from_slice(&MACHINE_X86_64.to_le_bytes());
+    image[24..32].copy_from_slice(&(address + image.len() as u64).to_le_bytes());
+    image[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    image[52..54].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image[56..58].copy_from_slice(&1u16.to_le_bytes());
+    image.extend_from_slice(code);
+    let len = image.len() as u64;
+    let header = &mut image[HEADER_SIZE..];
+    header[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+    header[4..8].copy_from_slice(&flags.to_le_bytes());
+    header[16..24].copy_from_slice(&address.to_le_bytes());
+    header[32..40].copy_from_slice(&len.to_le_bytes());
+    // And some zeroes after
+    header[40..48].copy_from_slice(&(len + 0x100).to_le_bytes());
+    image
+}
+
+#[test_case]
+fn test_parse_finds_segments() {
+    let image = executable(SPACE_START, PF_X | 4, &[0xcc; 8]);
+    let elf = Elf::parse(&image).unwrap();
+    assert_eq!(elf.entry(), VirtAddr::new(SPACE_START + (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64));
+    let segments: Vec<_> = elf.segments().collect();
+    assert_eq!(segments, [Ok(Segment {
+        address: SPACE_START,
+        memory_size: image.len() as u64 + 0x100,
+        offset: 0,
+        file_size: image.len(),
+        writable: false,
+        executable: true,
+    })]);
+}
+
+#[test_case]
+fn test_parse_rejects_what_cant_load() {
+    let mut image = executable(SPACE_START, PF_X, &[0xcc; 8]);
+    assert_eq!(Elf::parse(&image[..40]).err(), Some("not an ELF file"));
+    image[16] = 3;
+    assert_eq!(Elf::parse(&image).err(), Some("not an executable"));
+    let image = executable(0x40_0000, PF_X, &[0xcc; 8]);
+    assert_eq!(Elf::parse(&image).err(), Some("segment is outside the program's address space"));
+    let image = executable(STACK_START - 0x80, PF_X, &[0xcc; 8]);
+    assert_eq!(Elf::parse(&image).err(), Some("segment overlaps the stack"));
+    let image = executable(SPACE_START, PF_W, &[0xcc; 8]);
+    assert_eq!(Elf::parse(&image).err(), Some("entry point isn't in code"));
+    let image = executable(SPACE_START, PF_X, &[0xcc; 8]);
+    assert_eq!(Elf::parse(&image[..image.len() - 1]).err(), Some("segment is cut off"));
+}
@@ -0,0 +1,180 @@
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::cpu::{self, Feature};
+use crate::crypto::{chacha20, Sha256};
+
+// Bumped on every fallback draw so back-to-back calls don't collide
+static FALLBACK_STATE: AtomicU64 = AtomicU64::new(0x853c_49e6_748f_ea9b);
+
+/// Intel recommends retrying RDRAND a handful of times before giving up
+const RDRAND_RETRIES: usize = 10;
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!(
+                "rdrand {0}",
+                "setc {1}",
+                out(reg) value,
+                out(reg_byte) ok,
+                options(nomem, nostack),
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// splitmix64 finalizer, spreads the few bits of TSC jitter across the word
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A random 64-bit value: RDRAND when available, otherwise mixed TSC readings.
+/// The fallback is fine for canaries and hashing, not for key material.
+pub fn random_u64() -> u64 {
+    if cpu::has_feature(Feature::Rdrand) {
+        if let Some(value) = rdrand() {
+            return value;
+        }
+    }
+
+    let tsc = unsafe { _rdtsc() };
+    let state = FALLBACK_STATE.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+    mix(tsc ^ state)
+}
+
+// Rekey from the pool at least this often, even with no new input
+const RESEED_BYTES: u64 = 1 << 20;
+// Samples of each source hashed into the first key
+const SEED_SAMPLES: usize = 64;
+// We rekey after every request, so a fixed nonce never repeats a keystream
+const NONCE: [u8; chacha20::NONCE_LEN] = [0; chacha20::NONCE_LEN];
+
+/// The kernel CSPRNG: ChaCha20 with fast key erasure, keyed from a SHA-256
+/// pool that hardware randomness and interrupt timings get mixed into
+struct Pool {
+    key: [u8; chacha20::KEY_LEN],
+    seeded: bool,
+    // Input gathered since the last reseed
+    pending: Sha256,
+    pending_inputs: u64,
+    since_reseed: u64,
+}
+
+// Only ever locked with interrupts off, so nothing can add to it mid-update
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    key: [0; chacha20::KEY_LEN],
+    seeded: false,
+    pending: Sha256::new(),
+    pending_inputs: 0,
+    since_reseed: 0,
+});
+
+impl Pool {
+    fn reseed(&mut self) {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.key);
+        hasher.update(&core::mem::replace(&mut self.pending, Sha256::new()).finalize());
+        if !self.seeded {
+            // Whatever the CPU offers, plus timing jitter of the sampling itself
+            for _ in 0..SEED_SAMPLES {
+                hasher.update(&random_u64().to_le_bytes());
+                hasher.update(&unsafe { _rdtsc() }.to_le_bytes());
+            }
+        } else if let Some(value) = rdrand_if_present() {
+            hasher.update(&value.to_le_bytes());
+        }
+        self.key = hasher.finalize();
+        self.seeded = true;
+        self.pending_inputs = 0;
+        self.since_reseed = 0;
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        if !self.seeded || self.pending_inputs > 0 || self.since_reseed >= RESEED_BYTES {
+            self.reseed();
+        }
+
+        // The first half of block 0 becomes the next key, so this output
+        // can't be recomputed from state captured later
+        let first = chacha20::block(&self.key, 0, &NONCE);
+        let (next_key, rest) = first.split_at(chacha20::KEY_LEN);
+        let mut counter = 1;
+        let mut available = rest;
+        let mut keystream;
+        for byte in buffer.iter_mut() {
+            if available.is_empty() {
+                keystream = chacha20::block(&self.key, counter, &NONCE);
+                counter += 1;
+                available = &keystream[..];
+            }
+            *byte = available[0];
+            available = &available[1..];
+        }
+        self.key.copy_from_slice(next_key);
+        self.since_reseed += buffer.len() as u64;
+    }
+}
+
+fn rdrand_if_present() -> Option<u64> {
+    if cpu::has_feature(Feature::Rdrand) {
+        rdrand()
+    } else {
+        None
+    }
+}
+
+/// Fill `buffer` from the kernel CSPRNG; suitable for keys and nonces
+pub fn fill_bytes(buffer: &mut [u8]) {
+    interrupts::without_interrupts(|| POOL.lock().fill(buffer));
+}
+
+/// Mix some input into the pool; it's folded in on the next draw. Input
+/// doesn't need to be secret or uniform, only hard to predict.
+pub fn add_entropy(data: &[u8]) {
+    interrupts::without_interrupts(|| {
+        let mut pool = POOL.lock();
+        pool.pending.update(data);
+        pool.pending_inputs += 1;
+    });
+}
+
+/// Feed in when an interrupt arrived, along with what it delivered. The
+/// hashing happens later, outside the interrupt handler.
+pub(crate) fn add_interrupt_timing(value: u8) {
+    fn mix_in(sample: u64) {
+        add_entropy(&sample.to_le_bytes());
+    }
+
+    let tsc = unsafe { _rdtsc() };
+    // Losing a sample when the queue is busy is harmless
+    let _ = crate::softirq::defer(mix_in, tsc ^ u64::from(value));
+}
+
+/// TESTS
+
+#[test_case]
+fn test_random_values_differ() {
+    assert_ne!(random_u64(), random_u64());
+}
+
+#[test_case]
+fn test_csprng_output_differs() {
+    let mut first = [0u8; 100];
+    let mut second = [0u8; 100];
+    fill_bytes(&mut first);
+    add_entropy(b"test input");
+    fill_bytes(&mut second);
+    assert_ne!(first[..], second[..]);
+    assert_ne!(first[..], [0u8; 100][..]);
+}
@@ -0,0 +1,171 @@
+//! Errors as a type, for code that has to tell them apart. Most of the
+//! kernel says what went wrong with a `&'static str`, and still does;
+//! `KernelError::from` sorts those into kinds by the messages subsystems
+//! export for the purpose (`fs::NOT_FOUND` and the like), keeping the
+//! message as context, and `message()` gives it back, so code on either
+//! side can `?` the other. Syscalls return `errno()`, and nothing else maps
+//! errors to errno values.
+
+use core::fmt;
+use crate::fs::{self, lock};
+use crate::memory;
+use crate::user::{limit, signal, syscall};
+
+/// Filesystem errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    NotMounted,
+    ReadOnly,
+    /// Someone else holds the lock, and the caller wouldn't wait
+    Locked,
+}
+
+/// Memory errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// No frames left
+    OutOfMemory,
+    /// A program's gone past its memory limit
+    OverLimit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    Fs(FsError),
+    Memory(MemoryError),
+    /// A pointer from a program isn't mapped for it
+    BadAddress,
+    /// No file descriptor has that number
+    BadFd,
+    /// An argument makes no sense; says which
+    InvalidArgument(&'static str),
+    NoSuchThread,
+    NoSuchSignal,
+    NameTooLong,
+    /// A wait was cut short by a signal
+    Interrupted,
+    /// There's nothing to read yet, and the caller won't wait for it
+    WouldBlock,
+    /// The program has dropped a capability it needs for this
+    NotPermitted,
+    Unsupported,
+    /// Anything else, a device failing most often: what went wrong
+    Other(&'static str),
+}
+
+impl KernelError {
+    /// Say what went wrong, as the `&'static str` style has it
+    pub fn message(&self) -> &'static str {
+        match *self {
+            KernelError::Fs(FsError::NotFound) => fs::NOT_FOUND,
+            KernelError::Fs(FsError::NotADirectory) => fs::NOT_A_DIRECTORY,
+            KernelError::Fs(FsError::NotMounted) => fs::NOT_MOUNTED,
+            KernelError::Fs(FsError::ReadOnly) => fs::READ_ONLY,
+            KernelError::Fs(FsError::Locked) => lock::WOULD_BLOCK,
+            KernelError::Memory(MemoryError::OutOfMemory) => memory::OUT_OF_MEMORY,
+            KernelError::Memory(MemoryError::OverLimit) => limit::OVER_LIMIT,
+            KernelError::BadAddress => "bad address",
+            KernelError::BadFd => "bad file descriptor",
+            KernelError::InvalidArgument(message) | KernelError::Other(message) => message,
+            KernelError::NoSuchThread => "no such thread",
+            KernelError::NoSuchSignal => signal::NO_SUCH_SIGNAL,
+            KernelError::NameTooLong => "name too long",
+            KernelError::Interrupted => "interrupted",
+            KernelError::WouldBlock => "nothing to read",
+            KernelError::NotPermitted => "operation not permitted",
+            KernelError::Unsupported => "not supported",
+        }
+    }
+
+    /// The errno a program gets for this, negative as syscalls return it
+    pub fn errno(&self) -> i64 {
+        match self {
+            KernelError::Fs(FsError::NotFound) | KernelError::Fs(FsError::NotMounted) => syscall::ENOENT,
+            KernelError::Fs(FsError::NotADirectory) => syscall::ENOTDIR,
+            KernelError::Fs(FsError::ReadOnly) => syscall::EROFS,
+            KernelError::Fs(FsError::Locked) => syscall::EWOULDBLOCK,
+            KernelError::Memory(_) => syscall::ENOMEM,
+            KernelError::BadAddress => syscall::EFAULT,
+            KernelError::BadFd => syscall::EBADF,
+            KernelError::InvalidArgument(_) | KernelError::NoSuchSignal => syscall::EINVAL,
+            KernelError::NoSuchThread => syscall::ESRCH,
+            KernelError::NameTooLong => syscall::ENAMETOOLONG,
+            KernelError::Interrupted => syscall::EINTR,
+            KernelError::WouldBlock => syscall::EWOULDBLOCK,
+            KernelError::NotPermitted => syscall::EPERM,
+            KernelError::Unsupported => syscall::ENOSYS,
+            KernelError::Other(_) => syscall::EIO,
+        }
+    }
+}
+
+impl From<FsError> for KernelError {
+    fn from(error: FsError) -> KernelError {
+        KernelError::Fs(error)
+    }
+}
+
+impl From<MemoryError> for KernelError {
+    fn from(error: MemoryError) -> KernelError {
+        KernelError::Memory(error)
+    }
+}
+
+impl From<&'static str> for KernelError {
+    fn from(message: &'static str) -> KernelError {
+        const KINDS: [KernelError; 8] = [
+            KernelError::Fs(FsError::NotFound),
+            KernelError::Fs(FsError::NotADirectory),
+            KernelError::Fs(FsError::NotMounted),
+            KernelError::Fs(FsError::ReadOnly),
+            KernelError::Fs(FsError::Locked),
+            KernelError::Memory(MemoryError::OutOfMemory),
+            KernelError::Memory(MemoryError::OverLimit),
+            KernelError::NoSuchSignal,
+        ];
+        KINDS.iter().copied().find(|kind| kind.message() == message).unwrap_or(KernelError::Other(message))
+    }
+}
+
+impl From<KernelError> for &'static str {
+    fn from(error: KernelError) -> &'static str {
+        error.message()
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_conversions() {
+    assert_eq!(KernelError::from(fs::NOT_FOUND), KernelError::Fs(FsError::NotFound));
+    assert_eq!(KernelError::from(limit::OVER_LIMIT), KernelError::Memory(MemoryError::OverLimit));
+    assert_eq!(KernelError::from("disk timed out"), KernelError::Other("disk timed out"));
+    for message in [fs::NOT_MOUNTED, lock::WOULD_BLOCK, memory::OUT_OF_MEMORY, "disk timed out"] {
+        assert_eq!(KernelError::from(message).message(), message);
+    }
+
+    // What ? does on each side
+    fn lookup() -> Result<(), &'static str> {
+        Err(fs::NOT_A_DIRECTORY)
+    }
+    fn typed() -> Result<(), KernelError> {
+        lookup()?;
+        Ok(())
+    }
+    fn untyped() -> Result<(), &'static str> {
+        typed()?;
+        Ok(())
+    }
+    assert_eq!(typed().map_err(|error| error.errno()), Err(syscall::ENOTDIR));
+    assert_eq!(untyped(), Err(fs::NOT_A_DIRECTORY));
+    assert_eq!(KernelError::Other("disk timed out").errno(), syscall::EIO);
+    assert_eq!(KernelError::InvalidArgument("bad timer").errno(), syscall::EINVAL);
+}
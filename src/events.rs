@@ -0,0 +1,200 @@
+//! Publish/subscribe for things happening around the kernel: devices coming
+//! and going, network links, memory running low. Producers publish without
+//! knowing who's listening, and every subscriber gets its own copy of each
+//! event from an async stream.
+
+use core::fmt;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crate::device::DeviceId;
+use crate::sync::{MpscQueue, WakerSlot};
+
+const MAX_SUBSCRIBERS: usize = 8;
+// Events a subscriber can fall behind by before it misses some
+const QUEUE_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    DeviceAdded { id: DeviceId, name: &'static str },
+    DeviceRemoved { id: DeviceId, name: &'static str },
+    /// A network interface got a link, named as the driver calls it
+    LinkUp(&'static str),
+    LinkDown(&'static str),
+    /// Physical memory dropped below the low-water mark
+    LowMemory { free_frames: usize },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::DeviceAdded { name, .. } => write!(f, "device added: {}", name),
+            Event::DeviceRemoved { name, .. } => write!(f, "device removed: {}", name),
+            Event::LinkUp(interface) => write!(f, "link up: {}", interface),
+            Event::LinkDown(interface) => write!(f, "link down: {}", interface),
+            Event::LowMemory { free_frames } => write!(f, "low memory: {} frames free", free_frames),
+        }
+    }
+}
+
+struct Slot {
+    taken: AtomicBool,
+    queue: MpscQueue<Event, QUEUE_SIZE>,
+    waker: WakerSlot,
+    missed: AtomicU64,
+}
+
+impl Slot {
+    const fn new() -> Slot {
+        Slot {
+            taken: AtomicBool::new(false),
+            queue: MpscQueue::new(),
+            waker: WakerSlot::new(),
+            missed: AtomicU64::new(0),
+        }
+    }
+}
+
+// A const item, so the array can repeat it
+const FREE_SLOT: Slot = Slot::new();
+static SLOTS: [Slot; MAX_SUBSCRIBERS] = [FREE_SLOT; MAX_SUBSCRIBERS];
+
+/// Hand `event` to every subscriber. Safe from interrupt handlers; a
+/// subscriber whose queue is full misses it.
+pub fn publish(event: Event) {
+    for slot in SLOTS.iter().filter(|slot| slot.taken.load(Ordering::Acquire)) {
+        if slot.queue.push(event).is_err() {
+            slot.missed.fetch_add(1, Ordering::Relaxed);
+        }
+        slot.waker.wake();
+    }
+}
+
+/// Events published from when it was created on. Dropping it unsubscribes.
+pub struct Subscriber {
+    slot: &'static Slot,
+}
+
+impl Subscriber {
+    pub fn new() -> Result<Subscriber, &'static str> {
+        let slot = SLOTS
+            .iter()
+            .find(|slot| !slot.taken.swap(true, Ordering::AcqRel))
+            .ok_or("too many event subscribers")?;
+        // Left over from whoever had the slot before
+        while slot.queue.pop().is_some() {}
+        slot.missed.store(0, Ordering::Relaxed);
+        Ok(Subscriber { slot })
+    }
+
+    /// The next event if one is waiting
+    pub fn try_next(&mut self) -> Option<Event> {
+        self.slot.queue.pop()
+    }
+
+    pub fn poll_next(&mut self, context: &mut Context) -> Poll<Event> {
+        if let Some(event) = self.try_next() {
+            return Poll::Ready(event);
+        }
+        self.slot.waker.register(context.waker());
+        // One may have been published before we registered
+        match self.try_next() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+
+    /// The next event, whenever it comes
+    pub async fn next(&mut self) -> Event {
+        poll_fn(|context| self.poll_next(context)).await
+    }
+
+    /// Events dropped because this subscriber's queue was full
+    pub fn missed(&self) -> u64 {
+        self.slot.missed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.slot.taken.store(false, Ordering::Release);
+    }
+}
+
+/// Print events as they're published, until Escape is pressed
+#[cfg(feature = "keyboard")]
+pub async fn print_events() {
+    use pc_keyboard::DecodedKey;
+    use crate::println;
+    use crate::task::keyboard::ScancodeStream;
+
+    let mut subscriber = match Subscriber::new() {
+        Ok(subscriber) => subscriber,
+        Err(message) => {
+            println!("print_events: {}", message);
+            return;
+        }
+    };
+    let mut scancodes = match ScancodeStream::new() {
+        Some(scancodes) => scancodes,
+        None => {
+            println!("print_events: the keyboard already has a reader");
+            return;
+        }
+    };
+    loop {
+        // Whichever comes first: an event to print, or a key
+        let next = poll_fn(|context| {
+            if let Poll::Ready(event) = subscriber.poll_next(context) {
+                return Poll::Ready(Ok(event));
+            }
+            scancodes.poll_next(context).map(Err)
+        });
+        match next.await {
+            Ok(event) => println!("{}", event),
+            Err(scancode) => {
+                if let Some(DecodedKey::Unicode('\u{1b}')) = crate::keyboard::decode(scancode) {
+                    break;
+                }
+            }
+        }
+    }
+    if subscriber.missed() > 0 {
+        println!("({} events missed)", subscriber.missed());
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_every_subscriber_gets_each_event() {
+    let mut first = Subscriber::new().unwrap();
+    let mut second = Subscriber::new().unwrap();
+    publish(Event::LinkUp("test0"));
+    assert_eq!(first.try_next(), Some(Event::LinkUp("test0")));
+    assert_eq!(second.try_next(), Some(Event::LinkUp("test0")));
+    assert_eq!(first.try_next(), None);
+
+    // Once dropped, its slot starts over empty for the next subscriber
+    publish(Event::LinkDown("test0"));
+    drop(second);
+    let mut third = Subscriber::new().unwrap();
+    assert_eq!(third.try_next(), None);
+    assert_eq!(first.try_next(), Some(Event::LinkDown("test0")));
+
+    for _ in 0..QUEUE_SIZE + 2 {
+        publish(Event::LowMemory { free_frames: 0 });
+    }
+    assert_eq!(first.missed(), 2);
+}
+
+#[test_case]
+fn test_device_hotplug_is_published() {
+    use crate::device;
+
+    let mut subscriber = Subscriber::new().unwrap();
+    let id = device::register("test-hotplug", "test device", Some(device::platform())).unwrap();
+    device::unregister(id).unwrap();
+    assert_eq!(subscriber.try_next(), Some(Event::DeviceAdded { id, name: "test-hotplug" }));
+    assert_eq!(subscriber.try_next(), Some(Event::DeviceRemoved { id, name: "test-hotplug" }));
+}
@@ -0,0 +1,252 @@
+//! Failures on purpose, so error paths that real hardware hardly ever takes
+//! get run: each point can be set to fail one call in N. Without a seed
+//! it's exactly every Nth; with one, calls fail at random one time in N, in
+//! the same pattern every boot with that seed. Set from the command line,
+//! e.g. `fault=alloc:1000,disk-read:50,seed:7`, or the `fault` command.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Somewhere a failure can be injected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Point {
+    /// Heap allocations come back null
+    Alloc,
+    /// ATA reads and writes return an error
+    DiskRead,
+    DiskWrite,
+    /// net::send fails as if the device had, without sending
+    NetSend,
+    /// net::receive loses the frame it got, as a bad checksum would
+    NetReceive,
+}
+
+pub const POINTS: [Point; 5] = [Point::Alloc, Point::DiskRead, Point::DiskWrite, Point::NetSend, Point::NetReceive];
+
+impl Point {
+    pub fn name(self) -> &'static str {
+        match self {
+            Point::Alloc => "alloc",
+            Point::DiskRead => "disk-read",
+            Point::DiskWrite => "disk-write",
+            Point::NetSend => "net-send",
+            Point::NetReceive => "net-receive",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Point> {
+        POINTS.iter().copied().find(|point| point.name() == name)
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+// One in how many calls fails at each point; 0 for none
+static INTERVALS: [AtomicU64; POINTS.len()] = [ZERO; POINTS.len()];
+static CALLS: [AtomicU64; POINTS.len()] = [ZERO; POINTS.len()];
+static INJECTED: [AtomicU64; POINTS.len()] = [ZERO; POINTS.len()];
+// xorshift64 state; 0 when counting instead
+static RANDOM: AtomicU64 = AtomicU64::new(0);
+
+/// Make one call in `interval` at `point` fail, counting afresh; 0 stops it
+pub fn set(point: Point, interval: u64) {
+    CALLS[point.index()].store(0, Ordering::Relaxed);
+    INJECTED[point.index()].store(0, Ordering::Relaxed);
+    INTERVALS[point.index()].store(interval, Ordering::Relaxed);
+}
+
+/// Pick failures at random from `seed`, or, with 0, every Nth call
+pub fn set_seed(seed: u64) {
+    RANDOM.store(seed, Ordering::Relaxed);
+}
+
+/// Stop injecting anywhere
+pub fn clear() {
+    for &point in POINTS.iter() {
+        set(point, 0);
+    }
+}
+
+/// (one in how many, calls, failures injected) at `point`
+pub fn stats(point: Point) -> (u64, u64, u64) {
+    let index = point.index();
+    (INTERVALS[index].load(Ordering::Relaxed), CALLS[index].load(Ordering::Relaxed),
+        INJECTED[index].load(Ordering::Relaxed))
+}
+
+fn next_random() -> Option<u64> {
+    RANDOM.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+        if state == 0 {
+            return None;
+        }
+        let mut state = state;
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        Some(state)
+    }).ok()
+}
+
+/// Whether this call at `point` is to fail. Only atomics, so it's safe from
+/// interrupt handlers and the allocator.
+pub fn should_fail(point: Point) -> bool {
+    let index = point.index();
+    let interval = INTERVALS[index].load(Ordering::Relaxed);
+    if interval == 0 {
+        return false;
+    }
+    let call = CALLS[index].fetch_add(1, Ordering::Relaxed) + 1;
+    let fail = match next_random() {
+        // The state before the update is returned; it's as random as the next
+        Some(random) => random % interval == 0,
+        None => call % interval == 0,
+    };
+    if fail {
+        INJECTED[index].fetch_add(1, Ordering::Relaxed);
+    }
+    fail
+}
+
+/// `should_fail` as an error, for paths that return one
+pub fn check(point: Point) -> Result<(), &'static str> {
+    if should_fail(point) { Err("injected fault") } else { Ok(()) }
+}
+
+// `fault=` from the command line: point:N and seed:S, separated by commas
+fn configure(spec: &str) -> Result<(), &'static str> {
+    for item in spec.split(',') {
+        let (name, value) = item.split_once(':').ok_or("expected name:value")?;
+        let value: u64 = value.parse().map_err(|_| "bad number")?;
+        match name {
+            "seed" => set_seed(value),
+            _ => set(Point::from_name(name).ok_or("unknown fault point")?, value),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "shell")]
+fn cmd_fault(args: &[&str]) -> crate::shell::Status {
+    use crate::println;
+    use crate::shell::{FAILURE, SUCCESS};
+
+    match args {
+        [] => {
+            for &point in POINTS.iter() {
+                let (interval, calls, injected) = stats(point);
+                println!("{:<10} 1 in {:<6} {} calls, {} failed", point.name(), interval, calls, injected);
+            }
+            SUCCESS
+        }
+        ["off"] => {
+            clear();
+            SUCCESS
+        }
+        [spec] => match configure(spec) {
+            Ok(()) => SUCCESS,
+            Err(message) => {
+                println!("fault: {}", message);
+                FAILURE
+            }
+        },
+        _ => {
+            println!("usage: fault [off | point:N,...,seed:S]");
+            FAILURE
+        }
+    }
+}
+
+/// Take `fault=` from the command line, and add the `fault` command
+pub fn init() {
+    if let Some(spec) = crate::cmdline::get("fault") {
+        if let Err(message) = configure(spec) {
+            crate::log::warn!("fault: {}: {}", spec, message);
+        }
+    }
+    #[cfg(feature = "shell")]
+    crate::shell::register(crate::shell::Command {
+        name: "fault",
+        help: "fail allocations, disk I/O or network frames on purpose (fault off to stop)",
+        run: cmd_fault,
+        complete: None,
+    })
+    .expect("couldn't register fault");
+}
+
+/// TESTS
+
+// Nothing injected when a test starts, or once it's done
+#[cfg(test)]
+struct NoFaults;
+
+#[cfg(test)]
+impl crate::testing::Fixture for NoFaults {
+    fn setup() -> NoFaults {
+        clear();
+        set_seed(0);
+        NoFaults
+    }
+
+    fn teardown(self) {
+        clear();
+        set_seed(0);
+    }
+}
+
+#[test_case]
+fn test_every_nth() {
+    crate::testing::with(|_: &mut NoFaults| {
+        set(Point::DiskRead, 3);
+        let failed: alloc::vec::Vec<bool> = (0..6).map(|_| should_fail(Point::DiskRead)).collect();
+        assert_eq!(failed, [false, false, true, false, false, true]);
+        assert_eq!(stats(Point::DiskRead), (3, 6, 2));
+        set(Point::DiskRead, 0);
+        assert!(!should_fail(Point::DiskRead));
+    });
+}
+
+#[test_case]
+fn test_seeded() {
+    crate::testing::with(|_: &mut NoFaults| {
+        let run = |seed| {
+            set_seed(seed);
+            set(Point::DiskWrite, 4);
+            (0..64).map(|_| should_fail(Point::DiskWrite)).collect::<alloc::vec::Vec<bool>>()
+        };
+        let first = run(7);
+        assert_eq!(run(7), first);
+        assert!(first.contains(&true) && first.contains(&false));
+    });
+}
+
+#[test_case]
+fn test_paths_fail() {
+    crate::testing::with(|_: &mut NoFaults| {
+        configure("alloc:1,disk-read:1").unwrap();
+        let mut buf = alloc::vec::Vec::<u8>::new();
+        assert!(buf.try_reserve(64).is_err());
+        set(Point::Alloc, 0);
+        if let Some(disk) = crate::drivers::ata::disk(0) {
+            let mut sector = [0; crate::drivers::ata::SECTOR_SIZE];
+            assert_eq!(disk.read_sectors(0, &mut sector), Err("injected fault"));
+        }
+        set(Point::DiskRead, 0);
+
+        configure("net-send:1,net-receive:2").unwrap();
+        let mut executor = crate::task::simple_executor::SimpleExecutor::new();
+        executor.spawn(crate::task::Task::new(async {
+            assert_eq!(crate::net::send("lo", b"lost").await, Err("injected fault"));
+            set(Point::NetSend, 0);
+            crate::net::send("lo", b"comes").await.unwrap();
+            crate::net::send("lo", b"lost").await.unwrap();
+            let mut frame = [0; 8];
+            // Every second one is lost on the way in
+            assert_eq!(crate::net::receive("lo", &mut frame).await, Ok(5));
+            assert_eq!(crate::net::receive("lo", &mut frame).await, Err("injected fault"));
+        }));
+        executor.run();
+        assert_eq!(configure("disk:1"), Err("unknown fault point"));
+    });
+}
@@ -0,0 +1,298 @@
+//! Text drawn into a pixel framebuffer with an embedded 8x16 font, for
+//! machines without VGA text mode (booting through UEFI, say). Once `init`
+//! has run, print! goes here instead of the VGA buffer.
+
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::console::{Attributes, Console, EscapeParser, Parsed};
+
+pub mod font;
+
+/// Byte order of each pixel's color channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+}
+
+/// Framebuffer layout, as the bootloader or firmware reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBufferInfo {
+    pub width: usize,
+    pub height: usize,
+    /// Pixels from the start of one scanline to the next, padding included
+    pub stride: usize,
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+// The standard VGA palette, indexed by ANSI color number
+pub(crate) const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0xaa, 0x00, 0x00],
+    [0x00, 0xaa, 0x00],
+    [0xaa, 0x55, 0x00],
+    [0x00, 0x00, 0xaa],
+    [0xaa, 0x00, 0xaa],
+    [0x00, 0xaa, 0xaa],
+    [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55],
+    [0xff, 0x55, 0x55],
+    [0x55, 0xff, 0x55],
+    [0xff, 0xff, 0x55],
+    [0x55, 0x55, 0xff],
+    [0xff, 0x55, 0xff],
+    [0x55, 0xff, 0xff],
+    [0xff, 0xff, 0xff],
+];
+
+/// A text console drawn into a framebuffer. Behaves like the VGA writer:
+/// output starts on the bottom row and scrolls up.
+pub struct FramebufferConsole {
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+    rows: usize,
+    cols: usize,
+    row: usize,
+    col: usize,
+    attributes: Attributes,
+    escape: EscapeParser,
+}
+
+impl FramebufferConsole {
+    pub fn new(buffer: &'static mut [u8], info: FrameBufferInfo) -> Result<FramebufferConsole, &'static str> {
+        if info.bytes_per_pixel < 3 || info.stride < info.width {
+            return Err("unsupported framebuffer layout");
+        }
+        if buffer.len() < info.stride * info.height * info.bytes_per_pixel {
+            return Err("framebuffer smaller than its layout says");
+        }
+        let (rows, cols) = (info.height / font::HEIGHT, info.width / font::WIDTH);
+        if rows == 0 || cols == 0 {
+            return Err("framebuffer too small for a single character");
+        }
+        let mut console = FramebufferConsole {
+            buffer,
+            info,
+            rows,
+            cols,
+            row: rows - 1,
+            col: 0,
+            attributes: Attributes::DEFAULT,
+            escape: EscapeParser::new(),
+        };
+        console.clear_screen();
+        Ok(console)
+    }
+
+    /// Size of the text grid, as (rows, columns)
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    /// Size in pixels, as (width, height)
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.info.width, self.info.height)
+    }
+
+    /// Copy scanline `y` into `line` as RGB, 3 bytes a pixel
+    pub fn read_scanline(&self, y: usize, line: &mut [u8]) {
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        for (x, rgb) in line.chunks_exact_mut(3).take(self.info.width).enumerate() {
+            let start = (y * self.info.stride + x) * bytes_per_pixel;
+            let pixel = &self.buffer[start..start + 3];
+            match self.info.format {
+                PixelFormat::Rgb => rgb.copy_from_slice(pixel),
+                PixelFormat::Bgr => rgb.copy_from_slice(&[pixel[2], pixel[1], pixel[0]]),
+            }
+        }
+    }
+
+    fn pixel_bytes(&self, color: usize) -> [u8; 3] {
+        let [red, green, blue] = PALETTE[color];
+        match self.info.format {
+            PixelFormat::Rgb => [red, green, blue],
+            PixelFormat::Bgr => [blue, green, red],
+        }
+    }
+
+    // Draw `byte` into a cell, each pixel in the foreground or background
+    fn draw(&mut self, row: usize, col: usize, byte: u8) {
+        let foreground = self.pixel_bytes(self.attributes.shown_foreground());
+        let background = self.pixel_bytes(self.attributes.background);
+        let glyph = font::glyph(byte);
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        for (y, bits) in glyph.iter().enumerate() {
+            let line = (row * font::HEIGHT + y) * self.info.stride + col * font::WIDTH;
+            for x in 0..font::WIDTH {
+                let color = if bits & (0x80 >> x) != 0 { foreground } else { background };
+                let start = (line + x) * bytes_per_pixel;
+                self.buffer[start..start + 3].copy_from_slice(&color);
+            }
+        }
+    }
+
+    fn blank(&mut self, row: usize, cols: Range<usize>) {
+        for col in cols {
+            self.draw(row, col, b' ');
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.col = 0;
+        if self.row < self.rows - 1 {
+            self.row += 1;
+            return;
+        }
+        // One text row's worth of scanlines, moved up in a single copy
+        let row_bytes = font::HEIGHT * self.info.stride * self.info.bytes_per_pixel;
+        self.buffer.copy_within(row_bytes..self.rows * row_bytes, 0);
+        self.blank(self.rows - 1, 0..self.cols);
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if self.col >= self.cols {
+            self.new_line();
+        }
+        self.draw(self.row, self.col, byte);
+        self.col += 1;
+    }
+
+    // The same sequences as the VGA writer
+    fn control_sequence(&mut self, command: u8, params: &[u16]) {
+        let count = |index: usize| params.get(index).map_or(1, |&param| usize::from(param).max(1));
+        let (last_row, last_col) = (self.rows - 1, self.cols - 1);
+        match command {
+            b'm' => {
+                for &param in params {
+                    self.attributes.select_graphic(param);
+                }
+            }
+            b'H' | b'f' => {
+                self.row = (count(0) - 1).min(last_row);
+                self.col = (count(1) - 1).min(last_col);
+            }
+            b'A' => self.row = self.row.saturating_sub(count(0)),
+            b'B' => self.row = (self.row + count(0)).min(last_row),
+            b'C' => self.col = (self.col + count(0)).min(last_col),
+            b'D' => self.col = self.col.min(last_col).saturating_sub(count(0)),
+            b'J' => self.erase_display(params[0]),
+            b'K' => self.erase_line(params[0]),
+            _ => {}
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        let (row, col) = (self.row, self.col.min(self.cols - 1));
+        match mode {
+            0 => self.blank(row, col..self.cols),
+            1 => self.blank(row, 0..col + 1),
+            2 => self.blank(row, 0..self.cols),
+            _ => {}
+        }
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        let rows = match mode {
+            0 => self.row + 1..self.rows,
+            1 => 0..self.row,
+            2 => 0..self.rows,
+            _ => return,
+        };
+        for row in rows {
+            self.blank(row, 0..self.cols);
+        }
+        if mode != 2 {
+            self.erase_line(mode);
+        }
+    }
+}
+
+impl Console for FramebufferConsole {
+    fn write_string(&mut self, s: &str) {
+        for c in s.chars() {
+            match self.escape.feed(c) {
+                Parsed::Char('\r') => self.col = 0,
+                Parsed::Char('\u{8}') => {
+                    if self.col > 0 {
+                        self.col = self.col.min(self.cols) - 1;
+                        self.blank(self.row, self.col..self.col + 1);
+                    }
+                }
+                Parsed::Char('\n') => self.new_line(),
+                // One cell per character, whatever its encoded length
+                Parsed::Char(c) => self.write_byte(font::nearest(c)),
+                Parsed::Control { command, params, count } => self.control_sequence(command, &params[..count]),
+                Parsed::Pending => {}
+            }
+        }
+    }
+
+    fn set_column(&mut self, column: usize) {
+        self.col = column.min(self.cols);
+    }
+
+    fn clear_screen(&mut self) {
+        for row in 0..self.rows {
+            self.blank(row, 0..self.cols);
+        }
+        self.row = self.rows - 1;
+        self.col = 0;
+    }
+}
+
+// Only ever locked with interrupts off, like the VGA writer
+pub(crate) static CONSOLE: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+
+/// Send console output to a framebuffer from now on. Under bootloader 0.9
+/// there isn't one (it boots in VGA text mode), so nothing calls this yet;
+/// it's for boot paths that hand over a framebuffer instead.
+pub fn init(buffer: &'static mut [u8], info: FrameBufferInfo) -> Result<(), &'static str> {
+    let console = FramebufferConsole::new(buffer, info)?;
+    interrupts::without_interrupts(|| *CONSOLE.lock() = Some(console));
+    Ok(())
+}
+
+/// TESTS
+
+#[test_case]
+fn test_text_is_drawn_and_scrolled() {
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    // Two rows of three characters, with a little padding on each scanline
+    let info = FrameBufferInfo {
+        width: 3 * font::WIDTH,
+        height: 2 * font::HEIGHT,
+        stride: 3 * font::WIDTH + 4,
+        bytes_per_pixel: 4,
+        format: PixelFormat::Bgr,
+    };
+    let buffer = Box::leak(vec![0u8; info.stride * info.height * 4].into_boxed_slice());
+    let mut console = FramebufferConsole::new(buffer, info).unwrap();
+    assert_eq!(console.size(), (2, 3));
+    console.write_string("x\nA\x1b[31mBé");
+
+    // Reads back one cell's pixels as glyph rows, given its color as BGR
+    let cell = |row: usize, col: usize, color: [u8; 3]| {
+        let mut bits = [0u8; font::HEIGHT];
+        for (y, bits) in bits.iter_mut().enumerate() {
+            for x in 0..font::WIDTH {
+                let pixel = ((row * font::HEIGHT + y) * info.stride + col * font::WIDTH + x) * 4;
+                if console.buffer[pixel..pixel + 3] == color {
+                    *bits |= 0x80 >> x;
+                }
+            }
+        }
+        bits
+    };
+    let green = [0x00, 0xaa, 0x00];
+    let red = [0x00, 0x00, 0xaa];
+    // "x" has scrolled up a row
+    assert_eq!(&cell(0, 0, green), font::glyph(b'x'));
+    assert_eq!(&cell(1, 0, green), font::glyph(b'A'));
+    assert_eq!(&cell(1, 1, red), font::glyph(b'B'));
+    // Two bytes of UTF-8, one cell, drawn without its accent
+    assert_eq!(&cell(1, 2, red), font::glyph(b'e'));
+}
@@ -0,0 +1,166 @@
+//! A block device that encrypts another: each sector is XORed with a
+//! ChaCha20 keystream whose nonce is the sector's number, so sectors can be
+//! read and written alone and take no more room. Like dm-crypt's plain
+//! modes it has no integrity, and a sector rewritten in place reuses its
+//! keystream, so someone with two copies of the disk learns where they
+//! differ. The key is stretched from a passphrase with PBKDF2, salted with
+//! the volume's own random salt.
+//!
+//! The disk's first sector is a header in the clear: MAGIC, the PBKDF2
+//! rounds as a little-endian u32, then the salt. The encrypted sectors
+//! follow it, each numbered (for its nonce) from 0 after the header.
+
+use core::fmt;
+use crate::crypto::chacha20::{KEY_LEN, NONCE_LEN};
+use crate::crypto::{hmac_sha256, ChaCha20, HmacSha256};
+use super::{BlockDevice, SECTOR_SIZE};
+
+/// What the header starts with
+pub const MAGIC: &[u8; 8] = b"HEOCRYPT";
+pub const SALT_LEN: usize = 16;
+// The most PBKDF2-HMAC-SHA256 rounds a header can ask for; the tool's
+// 10,000 are a second or so on a fast machine
+const MAX_ITERATIONS: u32 = 100_000;
+// Sectors before the encrypted ones
+const HEADER_SECTORS: u64 = 1;
+
+// PBKDF2 (RFC 8018) with HMAC-SHA256, for as much key as one block gives
+fn pbkdf2(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; KEY_LEN] {
+    // The first round has the salt and the block's number, from 1
+    let mut mac = HmacSha256::new(passphrase);
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut round = mac.finalize();
+    let mut key = round;
+    for _ in 1..iterations {
+        round = hmac_sha256(passphrase, &round);
+        for (byte, next) in key.iter_mut().zip(round.iter()) {
+            *byte ^= next;
+        }
+    }
+    key
+}
+
+/// `device` as its plaintext
+#[derive(Clone, Copy)]
+pub struct Crypt<D> {
+    device: D,
+    key: [u8; KEY_LEN],
+}
+
+impl<D: BlockDevice> Crypt<D> {
+    /// `device`, read and written with the key `passphrase` and the salt in
+    /// its header give. A wrong passphrase isn't caught here: it reads noise.
+    pub fn open(device: D, passphrase: &[u8]) -> Result<Crypt<D>, &'static str> {
+        let mut header = [0; SECTOR_SIZE];
+        device.read_sector(0, &mut header)?;
+        if header[..MAGIC.len()] != MAGIC[..] {
+            return Err("no crypt header");
+        }
+        let iterations = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if iterations == 0 || iterations > MAX_ITERATIONS {
+            return Err("bad crypt header");
+        }
+        let salt = &header[12..12 + SALT_LEN];
+        Ok(Crypt { device, key: pbkdf2(passphrase, salt, iterations) })
+    }
+
+    // Encrypt or decrypt a sector; it's the same XOR both ways
+    fn apply(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..8].copy_from_slice(&lba.to_le_bytes());
+        ChaCha20::new(&self.key, &nonce, 0).apply_keystream(buf);
+    }
+}
+
+// Without the key
+impl<D: fmt::Debug> fmt::Debug for Crypt<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Crypt").field("device", &self.device).finish()
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Crypt<D> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        self.device.read_sector(lba + HEADER_SECTORS, buf)?;
+        self.apply(lba, buf);
+        Ok(())
+    }
+
+    fn write_sector(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        let mut sector = *buf;
+        self.apply(lba, &mut sector);
+        self.device.write_sector(lba + HEADER_SECTORS, &sector)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.device.flush()
+    }
+
+    fn write_sector_fua(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        let mut sector = *buf;
+        self.apply(lba, &mut sector);
+        self.device.write_sector_fua(lba + HEADER_SECTORS, &sector)
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_pbkdf2() {
+    use crate::crypto::from_hex;
+
+    // The usual PBKDF2-HMAC-SHA256 vectors
+    assert_eq!(pbkdf2(b"password", b"salt", 1),
+        from_hex::<32>("120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"));
+    assert_eq!(pbkdf2(b"password", b"salt", 4096),
+        from_hex::<32>("c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"));
+}
+
+#[test_case]
+fn test_sectors_round_trip() {
+    use crate::testing::{self, RamDisk};
+
+    testing::with(|disk: &mut RamDisk| {
+        let disk = &*disk;
+        let crypt = Crypt { device: disk, key: [7; KEY_LEN] };
+        let plain = [b'x'; SECTOR_SIZE];
+        crypt.write_sector(1, &plain).unwrap();
+        crypt.write_sector_fua(2, &plain).unwrap();
+        // After the header, not in the clear, and the same text differs
+        // between sectors
+        let stored = disk.sectors.borrow().clone();
+        assert_eq!(stored[SECTOR_SIZE..2 * SECTOR_SIZE], [0; SECTOR_SIZE][..]);
+        assert_ne!(stored[2 * SECTOR_SIZE..3 * SECTOR_SIZE], plain[..]);
+        assert_ne!(stored[2 * SECTOR_SIZE..3 * SECTOR_SIZE], stored[3 * SECTOR_SIZE..4 * SECTOR_SIZE]);
+
+        let mut buf = [0; SECTOR_SIZE];
+        crypt.read_sector(2, &mut buf).unwrap();
+        assert_eq!(buf, plain);
+        // The wrong key reads noise
+        let wrong = Crypt { device: disk, key: [8; KEY_LEN] };
+        wrong.read_sector(1, &mut buf).unwrap();
+        assert_ne!(buf, plain);
+    });
+}
+
+#[test_case]
+fn test_open_reads_the_salt_from_the_header() {
+    use crate::testing::{self, RamDisk};
+
+    testing::with(|disk: &mut RamDisk| {
+        let disk = &*disk;
+        assert_eq!(Crypt::open(disk, b"password").err(), Some("no crypt header"));
+        let mut header = [0; SECTOR_SIZE];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&1u32.to_le_bytes());
+        header[12..12 + SALT_LEN].copy_from_slice(b"salt of its own!");
+        disk.write_sector(0, &header).unwrap();
+        let crypt = Crypt::open(disk, b"password").unwrap();
+        assert_eq!(crypt.key, pbkdf2(b"password", b"salt of its own!", 1));
+
+        header[8..12].copy_from_slice(&(MAX_ITERATIONS + 1).to_le_bytes());
+        disk.write_sector(0, &header).unwrap();
+        assert_eq!(Crypt::open(disk, b"password").err(), Some("bad crypt header"));
+    });
+}
@@ -0,0 +1,704 @@
+//! Read-only FAT32, over anything that reads 512-byte sectors. Long file
+//! names are used where they're present; short names are shown the way
+//! Windows NT shows them, lowercased if their flags say so. Timestamps are
+//! in local time on disk, and come out as SystemTimes. `fsck` checks a
+//! volume, and repairs are all that's ever written.
+
+use alloc::string::String;
+use crate::cp437;
+use crate::rtc::DateTime;
+use crate::time::{system, Duration, SystemTime};
+use super::{BlockDevice, SECTOR_SIZE};
+
+pub mod fsck;
+
+const ENTRY_LEN: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_LEN;
+// FAT entries at or above this end a chain; only the low 28 bits count
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+// Read-only, hidden, system and volume ID all at once mark a long name piece
+const ATTR_LONG_NAME: u8 = 0x0f;
+// Set on the first long name piece stored, which holds the end of the name
+const LAST_LONG_ENTRY: u8 = 0x40;
+// In a short entry's flags: the base name, or the extension, is lowercase
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXTENSION: u8 = 0x10;
+// UTF-16 units in one long name piece, and the most pieces a name can have
+const LONG_PIECE_LEN: usize = 13;
+const MAX_LONG_PIECES: usize = 20;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+// A date (days from 1, months from 1, years from 1980) and a time (in two
+// second steps) as FAT packs them, plus hundredths of a second; None for a
+// date that isn't set, or isn't one
+fn timestamp(date: u16, time: u16, hundredths: u8) -> Option<SystemTime> {
+    let datetime = DateTime {
+        year: 1980 + (date >> 9),
+        month: (date >> 5 & 0x0f) as u8,
+        day: (date & 0x1f) as u8,
+        hour: (time >> 11) as u8,
+        minute: (time >> 5 & 0x3f) as u8,
+        second: (time & 0x1f) as u8 * 2,
+    };
+    if !(1..=12).contains(&datetime.month) || datetime.day == 0 || datetime.hour > 23 || datetime.minute > 59
+        || datetime.second > 59
+    {
+        return None;
+    }
+    let local = SystemTime::from_datetime(&datetime).since_unix_epoch() + Duration::from_millis(u64::from(hundredths) * 10);
+    let offset = i64::from(system::utc_offset_minutes()) * 60;
+    let utc = (local.as_secs() as i64 - offset).max(0) as u64;
+    Some(SystemTime::from_unix(Duration::new(utc, local.subsec_nanos())))
+}
+
+/// A FAT32 filesystem, as its boot sector describes it
+#[derive(Debug, Clone, Copy)]
+pub struct Volume<D> {
+    device: D,
+    sectors_per_cluster: u32,
+    fat_start: u64,
+    data_start: u64,
+    root_cluster: u32,
+    clusters: u32,
+    fats: u32,
+    fat_size: u32,
+    // The FSInfo sector, which keeps a count of free clusters, if there's one
+    fs_info: Option<u64>,
+}
+
+impl<D: BlockDevice> Volume<D> {
+    /// The filesystem whose boot sector is at `start` on `device`
+    pub fn open(device: D, start: u64) -> Result<Volume<D>, &'static str> {
+        let mut sector = [0; SECTOR_SIZE];
+        device.read_sector(start, &mut sector)?;
+        if sector[510..] != [0x55, 0xaa] {
+            return Err("no boot signature");
+        }
+        if usize::from(u16_at(&sector, 0x0b)) != SECTOR_SIZE {
+            return Err("unsupported sector size");
+        }
+        // FAT12 and FAT16 have a fixed-size root directory, and their FAT
+        // size in the 16-bit field
+        if u16_at(&sector, 0x11) != 0 || u16_at(&sector, 0x16) != 0 {
+            return Err("not FAT32");
+        }
+        let sectors_per_cluster = u32::from(sector[0x0d]);
+        let reserved = u64::from(u16_at(&sector, 0x0e));
+        let fats = u64::from(sector[0x10]);
+        let fat_size = u32_at(&sector, 0x24);
+        let total = match u16_at(&sector, 0x13) {
+            0 => u64::from(u32_at(&sector, 0x20)),
+            total => u64::from(total),
+        };
+        if !sectors_per_cluster.is_power_of_two() || reserved == 0 || fats == 0 || fat_size == 0 {
+            return Err("bad BIOS parameter block");
+        }
+
+        let data_start = reserved + fats * u64::from(fat_size);
+        let data_clusters = total.checked_sub(data_start).ok_or("bad BIOS parameter block")?
+            / u64::from(sectors_per_cluster);
+        // No more than the FAT has entries for, less the two reserved ones
+        let fat_entries = u64::from(fat_size) * (SECTOR_SIZE / 4) as u64 - 2;
+        let volume = Volume {
+            device,
+            sectors_per_cluster,
+            fat_start: start + reserved,
+            data_start: start + data_start,
+            root_cluster: u32_at(&sector, 0x2c) & CLUSTER_MASK,
+            clusters: data_clusters.min(fat_entries) as u32,
+            fats: fats as u32,
+            fat_size,
+            fs_info: match u16_at(&sector, 0x30) {
+                0 | 0xffff => None,
+                sector => Some(start + u64::from(sector)),
+            },
+        };
+        volume.cluster_lba(volume.root_cluster)?;
+        Ok(volume)
+    }
+
+    /// What the volume is read from
+    pub fn device(&self) -> D {
+        self.device
+    }
+
+    /// Put whatever's been written to the volume on the medium
+    pub fn flush(&self) -> Result<(), &'static str> {
+        self.device.flush()
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    // Where a data cluster starts; data clusters are numbered from 2
+    fn cluster_lba(&self, cluster: u32) -> Result<u64, &'static str> {
+        if cluster < 2 || cluster - 2 >= self.clusters {
+            return Err("corrupt cluster chain");
+        }
+        Ok(self.data_start + u64::from(cluster - 2) * u64::from(self.sectors_per_cluster))
+    }
+
+    // The cluster after `cluster` in its chain, or None at the end
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, &'static str> {
+        let offset = u64::from(cluster) * 4;
+        let mut sector = [0; SECTOR_SIZE];
+        self.device.read_sector(self.fat_start + offset / SECTOR_SIZE as u64, &mut sector)?;
+        let next = u32_at(&sector, (offset % SECTOR_SIZE as u64) as usize) & CLUSTER_MASK;
+        if next >= END_OF_CHAIN {
+            return Ok(None);
+        }
+        self.cluster_lba(next)?;
+        Ok(Some(next))
+    }
+
+    // Follow `path` from the root; "" and "/" are the root itself
+    fn lookup(&self, path: &str) -> Result<DirEntry, &'static str> {
+        let mut entry = DirEntry {
+            name: String::from("/"),
+            attributes: ATTR_DIRECTORY,
+            cluster: self.root_cluster,
+            size: 0,
+            created: None,
+            modified: None,
+            accessed: None,
+        };
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if !entry.is_dir() {
+                return Err(super::NOT_A_DIRECTORY);
+            }
+            let mut found = None;
+            for child in self.dir(entry.cluster) {
+                let child = child?;
+                // Names are matched the way FAT does, ignoring (ASCII) case
+                if child.name.eq_ignore_ascii_case(component) {
+                    found = Some(child);
+                    break;
+                }
+            }
+            entry = found.ok_or(super::NOT_FOUND)?;
+            // ".." in a directory just under the root says cluster 0
+            if entry.is_dir() && entry.cluster == 0 {
+                entry.cluster = self.root_cluster;
+            }
+        }
+        Ok(entry)
+    }
+
+    fn dir(&self, cluster: u32) -> Dir<D> {
+        Dir {
+            volume: *self,
+            cluster: Some(cluster),
+            sector: 0,
+            index: 0,
+            walked: 0,
+            buf: [0; SECTOR_SIZE],
+            long: [0; LONG_PIECE_LEN * MAX_LONG_PIECES],
+            long_next: None,
+            long_checksum: 0,
+        }
+    }
+
+    /// The entries of the directory at `path`
+    pub fn read_dir(&self, path: &str) -> Result<Dir<D>, &'static str> {
+        let entry = self.lookup(path)?;
+        if !entry.is_dir() {
+            return Err(super::NOT_A_DIRECTORY);
+        }
+        Ok(self.dir(entry.cluster))
+    }
+
+    /// The file or directory at `path`, as its directory lists it
+    pub fn stat(&self, path: &str) -> Result<DirEntry, &'static str> {
+        self.lookup(path)
+    }
+
+    /// The file at `path`, for reading from the start
+    pub fn open_file(&self, path: &str) -> Result<File<D>, &'static str> {
+        self.open_entry(&self.lookup(path)?)
+    }
+
+    /// The file `entry` is, as `stat` gave it, for reading from the start
+    pub fn open_entry(&self, entry: &DirEntry) -> Result<File<D>, &'static str> {
+        if entry.is_dir() {
+            return Err("is a directory");
+        }
+        Ok(File {
+            volume: *self,
+            size: entry.size,
+            position: 0,
+            cluster: entry.cluster,
+            cluster_index: 0,
+        })
+    }
+}
+
+/// A file or directory, as its directory lists it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    name: String,
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+    created: Option<SystemTime>,
+    modified: Option<SystemTime>,
+    // Only the date is kept
+    accessed: Option<SystemTime>,
+}
+
+impl DirEntry {
+    /// The long name if there is one, otherwise the short one
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// In bytes; always 0 for directories
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.attributes & ATTR_READ_ONLY != 0
+    }
+
+    /// None where the filesystem didn't say; always for the root
+    pub fn created(&self) -> Option<SystemTime> {
+        self.created
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// To the day, not the second
+    pub fn accessed(&self) -> Option<SystemTime> {
+        self.accessed
+    }
+}
+
+// "NAME    EXT" as "NAME.EXT", the base or extension lowercased if flagged
+fn short_name(raw: &[u8]) -> String {
+    let mut name = String::new();
+    let mut push = |bytes: &[u8], lower: bool| {
+        let end = bytes.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1);
+        for &byte in &bytes[..end] {
+            let c = cp437::decode(byte);
+            name.push(if lower { c.to_ascii_lowercase() } else { c });
+        }
+    };
+    let mut base = [0; 8];
+    base.copy_from_slice(&raw[..8]);
+    // 0xe5 marks deleted entries, so a name really starting with it says 0x05
+    if base[0] == 0x05 {
+        base[0] = 0xe5;
+    }
+    push(&base, raw[12] & LOWER_BASE != 0);
+    if raw[8..11] != *b"   " {
+        push(&[b'.'], false);
+        push(&raw[8..11], raw[12] & LOWER_EXTENSION != 0);
+    }
+    name
+}
+
+// What long name pieces carry to tie them to their short entry
+fn short_checksum(raw: &[u8]) -> u8 {
+    raw[..11].iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// The entries of a directory, "." and ".." included (except in the root).
+/// Stops at the first error.
+pub struct Dir<D> {
+    volume: Volume<D>,
+    // None once the end is reached
+    cluster: Option<u32>,
+    sector: u32,
+    index: usize,
+    // Clusters followed, so a chain that loops back can't go on forever
+    walked: u32,
+    buf: [u8; SECTOR_SIZE],
+    // A long name being put together from its pieces, which come last first
+    long: [u16; LONG_PIECE_LEN * MAX_LONG_PIECES],
+    // The number of the piece expected next; Some(0) once it's complete
+    long_next: Option<u8>,
+    long_checksum: u8,
+}
+
+impl<D: BlockDevice> Dir<D> {
+    // The next raw entry, or None past the last cluster
+    fn next_raw(&mut self) -> Result<Option<[u8; ENTRY_LEN]>, &'static str> {
+        let cluster = match self.cluster {
+            Some(cluster) => cluster,
+            None => return Ok(None),
+        };
+        if self.index == 0 {
+            let lba = self.volume.cluster_lba(cluster)? + u64::from(self.sector);
+            self.volume.device.read_sector(lba, &mut self.buf)?;
+        }
+        let mut raw = [0; ENTRY_LEN];
+        raw.copy_from_slice(&self.buf[self.index * ENTRY_LEN..][..ENTRY_LEN]);
+
+        self.index += 1;
+        if self.index == ENTRIES_PER_SECTOR {
+            self.index = 0;
+            self.sector += 1;
+            if self.sector == self.volume.sectors_per_cluster {
+                self.sector = 0;
+                self.walked += 1;
+                if self.walked > self.volume.clusters {
+                    return Err("corrupt cluster chain");
+                }
+                self.cluster = self.volume.next_cluster(cluster)?;
+            }
+        }
+        Ok(Some(raw))
+    }
+
+    // Keep a long name piece, or forget the name if it's out of sequence
+    fn long_piece(&mut self, raw: &[u8]) {
+        let number = raw[0] & !LAST_LONG_ENTRY;
+        if raw[0] & LAST_LONG_ENTRY != 0 {
+            if number == 0 || usize::from(number) > MAX_LONG_PIECES {
+                self.long_next = None;
+                return;
+            }
+            self.long = [0; LONG_PIECE_LEN * MAX_LONG_PIECES];
+            self.long_checksum = raw[13];
+        } else if self.long_next != Some(number) || number == 0 || raw[13] != self.long_checksum {
+            self.long_next = None;
+            return;
+        }
+        // Pieces are numbered from 1, in three runs of UTF-16 units
+        let piece = &mut self.long[usize::from(number - 1) * LONG_PIECE_LEN..][..LONG_PIECE_LEN];
+        let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+        for (unit, offset) in piece.iter_mut().zip(offsets) {
+            *unit = u16_at(raw, offset);
+        }
+        self.long_next = Some(number - 1);
+    }
+
+    fn entry(&mut self, raw: &[u8]) -> DirEntry {
+        let long = self.long_next.take() == Some(0) && self.long_checksum == short_checksum(raw);
+        let name = if long {
+            // Ends at a NUL unless it fills its last piece; 0xffff pads
+            let units = self.long.iter().copied().take_while(|&unit| unit != 0 && unit != 0xffff);
+            core::char::decode_utf16(units)
+                .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                .collect()
+        } else {
+            short_name(raw)
+        };
+        DirEntry {
+            name,
+            attributes: raw[11],
+            cluster: (u32::from(u16_at(raw, 0x14)) << 16 | u32::from(u16_at(raw, 0x1a))) & CLUSTER_MASK,
+            size: u32_at(raw, 0x1c),
+            created: timestamp(u16_at(raw, 0x10), u16_at(raw, 0x0e), raw[0x0d]),
+            modified: timestamp(u16_at(raw, 0x18), u16_at(raw, 0x16), 0),
+            accessed: timestamp(u16_at(raw, 0x12), 0, 0),
+        }
+    }
+}
+
+impl<D: BlockDevice> Iterator for Dir<D> {
+    type Item = Result<DirEntry, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let raw = match self.next_raw() {
+                Ok(Some(raw)) => raw,
+                Ok(None) => return None,
+                Err(message) => {
+                    self.cluster = None;
+                    return Some(Err(message));
+                }
+            };
+            match raw[0] {
+                // Nothing in use from here on
+                0 => {
+                    self.cluster = None;
+                    return None;
+                }
+                0xe5 => self.long_next = None,
+                _ if raw[11] & 0x3f == ATTR_LONG_NAME => self.long_piece(&raw),
+                _ if raw[11] & ATTR_VOLUME_ID != 0 => self.long_next = None,
+                _ => return Some(Ok(self.entry(&raw))),
+            }
+        }
+    }
+}
+
+/// A file open for reading
+pub struct File<D> {
+    volume: Volume<D>,
+    size: u32,
+    position: u32,
+    // The cluster holding `position`, and how far along the chain it is
+    cluster: u32,
+    cluster_index: u32,
+}
+
+impl<D: BlockDevice> File<D> {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Read from where the last read stopped; 0 means the end of the file
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let cluster_bytes = self.volume.cluster_bytes();
+        let mut done = 0;
+        while done < buf.len() && self.position < self.size {
+            while self.cluster_index < self.position / cluster_bytes {
+                self.cluster = self.volume.next_cluster(self.cluster)?.ok_or("file is shorter than its size")?;
+                self.cluster_index += 1;
+            }
+            let offset = self.position % cluster_bytes;
+            let lba = self.volume.cluster_lba(self.cluster)? + u64::from(offset) / SECTOR_SIZE as u64;
+            let mut sector = [0; SECTOR_SIZE];
+            self.volume.device.read_sector(lba, &mut sector)?;
+
+            let within = offset as usize % SECTOR_SIZE;
+            let len = (SECTOR_SIZE - within)
+                .min(buf.len() - done)
+                .min((self.size - self.position) as usize);
+            buf[done..done + len].copy_from_slice(&sector[within..within + len]);
+            done += len;
+            self.position += len as u32;
+        }
+        Ok(done)
+    }
+}
+
+/// TESTS
+
+#[cfg(test)]
+mod image {
+    //! A small FAT32 image built in memory: one sector per cluster, one FAT
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+    use super::*;
+
+    pub const RESERVED: usize = 32;
+    const TOTAL_SECTORS: usize = 128;
+
+    #[derive(Clone, Copy)]
+    pub struct Image<'a>(pub &'a [u8]);
+
+    /// An image that can be written to as well
+    #[derive(Clone, Copy)]
+    pub struct Writable<'a>(pub &'a RefCell<Vec<u8>>);
+
+    impl BlockDevice for Writable<'_> {
+        fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+            Image(&self.0.borrow()).read_sector(lba, buf)
+        }
+
+        fn write_sector(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+            let start = lba as usize * SECTOR_SIZE;
+            let mut image = self.0.borrow_mut();
+            let sector = image.get_mut(start..start + SECTOR_SIZE).ok_or("past the end of the image")?;
+            sector.copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    impl BlockDevice for Image<'_> {
+        fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+            let start = lba as usize * SECTOR_SIZE;
+            let sector = self.0.get(start..start + SECTOR_SIZE).ok_or("past the end of the image")?;
+            buf.copy_from_slice(sector);
+            Ok(())
+        }
+    }
+
+    pub fn short_entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; ENTRY_LEN] {
+        let mut entry = [0; ENTRY_LEN];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[0x14..0x16].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[0x1a..0x1c].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[0x1c..0x20].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    // The long name pieces for `name`, in the order they're stored
+    pub fn long_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; ENTRY_LEN]> {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        if units.len() % LONG_PIECE_LEN != 0 {
+            units.push(0);
+        }
+        while units.len() % LONG_PIECE_LEN != 0 {
+            units.push(0xffff);
+        }
+        let pieces = units.len() / LONG_PIECE_LEN;
+        let offsets: Vec<usize> = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2)).collect();
+        (0..pieces)
+            .rev()
+            .map(|piece| {
+                let mut entry = [0; ENTRY_LEN];
+                entry[0] = (piece as u8 + 1) | if piece == pieces - 1 { LAST_LONG_ENTRY } else { 0 };
+                entry[11] = ATTR_LONG_NAME;
+                entry[13] = short_checksum(short);
+                for (&unit, &offset) in units[piece * LONG_PIECE_LEN..][..LONG_PIECE_LEN].iter().zip(&offsets) {
+                    entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+                }
+                entry
+            })
+            .collect()
+    }
+
+    /// An empty filesystem with its root directory in cluster 2
+    pub fn new() -> Vec<u8> {
+        let mut image = vec![0; TOTAL_SECTORS * SECTOR_SIZE];
+        let boot = &mut image[..SECTOR_SIZE];
+        boot[0x0b..0x0d].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot[0x0d] = 1;
+        boot[0x0e..0x10].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+        boot[0x10] = 1;
+        boot[0x20..0x24].copy_from_slice(&(TOTAL_SECTORS as u32).to_le_bytes());
+        boot[0x24..0x28].copy_from_slice(&1u32.to_le_bytes());
+        boot[0x2c..0x30].copy_from_slice(&2u32.to_le_bytes());
+        boot[510] = 0x55;
+        boot[511] = 0xaa;
+        set_fat(&mut image, 2, CLUSTER_MASK);
+        image
+    }
+
+    pub fn set_fat(image: &mut [u8], cluster: u32, next: u32) {
+        let offset = RESERVED * SECTOR_SIZE + cluster as usize * 4;
+        image[offset..offset + 4].copy_from_slice(&next.to_le_bytes());
+    }
+
+    pub fn cluster(image: &mut [u8], cluster: u32) -> &mut [u8] {
+        let start = (RESERVED + 1 + cluster as usize - 2) * SECTOR_SIZE;
+        &mut image[start..start + SECTOR_SIZE]
+    }
+
+    pub fn put_entries(image: &mut [u8], cluster_number: u32, entries: &[[u8; ENTRY_LEN]]) {
+        let sector = cluster(image, cluster_number);
+        for (slot, entry) in sector.chunks_exact_mut(ENTRY_LEN).zip(entries) {
+            slot.copy_from_slice(entry);
+        }
+    }
+}
+
+#[test_case]
+fn test_directories_and_long_names() {
+    use alloc::vec::Vec;
+    use image::*;
+
+    let mut disk = new();
+    let mut root = long_entries("A long file name.txt", b"ALONGF~1TXT");
+    root.push(short_entry(b"ALONGF~1TXT", 0x20, 5, 600));
+    let mut readme = short_entry(b"README  TXT", 0x20, 0, 0);
+    readme[12] = LOWER_BASE;
+    root.push(readme);
+    // A deleted entry, and long name pieces whose checksum doesn't match
+    root.push(short_entry(b"\xe5OLD    TXT", 0x20, 0, 0));
+    root.extend(long_entries("Not mine", b"SOMEONE ELS"));
+    root.push(short_entry(b"SUB        ", ATTR_DIRECTORY, 3, 0));
+    put_entries(&mut disk, 2, &root);
+    set_fat(&mut disk, 3, CLUSTER_MASK);
+    put_entries(&mut disk, 3, &[
+        short_entry(b".          ", ATTR_DIRECTORY, 3, 0),
+        short_entry(b"..         ", ATTR_DIRECTORY, 0, 0),
+        short_entry(b"HELLO   TXT", 0x20, 4, 3),
+    ]);
+    set_fat(&mut disk, 4, CLUSTER_MASK);
+    cluster(&mut disk, 4)[..3].copy_from_slice(b"hi\n");
+    // Two clusters, and not in order
+    set_fat(&mut disk, 5, 7);
+    set_fat(&mut disk, 7, CLUSTER_MASK);
+    cluster(&mut disk, 5).fill(b'a');
+    cluster(&mut disk, 7).fill(b'b');
+
+    let volume = Volume::open(Image(&disk), 0).unwrap();
+    let names: Vec<String> = volume.read_dir("/").unwrap().map(|entry| entry.unwrap().name).collect();
+    assert_eq!(names, ["A long file name.txt", "readme.TXT", "SUB"]);
+    let names: Vec<String> = volume.read_dir("sub/../SUB").unwrap().map(|entry| entry.unwrap().name).collect();
+    assert_eq!(names, [".", "..", "HELLO.TXT"]);
+
+    let mut file = volume.open_file("/Sub/hello.txt").unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(file.read(&mut buf), Ok(3));
+    assert_eq!(&buf[..3], b"hi\n");
+    assert_eq!(file.read(&mut buf), Ok(0));
+
+    // Read in pieces that straddle the cluster boundary
+    let mut file = volume.open_file("a LONG file name.TXT").unwrap();
+    let mut contents = Vec::new();
+    loop {
+        let n = file.read(&mut buf[..7]).unwrap();
+        if n == 0 {
+            break;
+        }
+        contents.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(contents.len(), 600);
+    assert!(contents[..SECTOR_SIZE].iter().all(|&byte| byte == b'a'));
+    assert!(contents[SECTOR_SIZE..].iter().all(|&byte| byte == b'b'));
+
+    assert_eq!(volume.open_file("SUB").err(), Some("is a directory"));
+    assert_eq!(volume.read_dir("readme.txt").err(), Some("not a directory"));
+    assert_eq!(volume.open_file("nope").err(), Some("no such file or directory"));
+}
+
+#[test_case]
+fn test_timestamps_and_attributes() {
+    use image::*;
+
+    let mut disk = new();
+    let mut entry = short_entry(b"NOTES   TXT", ATTR_READ_ONLY, 0, 0);
+    // Modified 2024-02-29 13:45:58, created 2023-12-31 23:59:59.50 (the
+    // hundredths carry the odd second), last read on 2024-03-01
+    entry[0x16..0x18].copy_from_slice(&(13 << 11 | 45 << 5 | 29u16).to_le_bytes());
+    entry[0x18..0x1a].copy_from_slice(&(44 << 9 | 2 << 5 | 29u16).to_le_bytes());
+    entry[0x0d] = 150;
+    entry[0x0e..0x10].copy_from_slice(&(23 << 11 | 59 << 5 | 29u16).to_le_bytes());
+    entry[0x10..0x12].copy_from_slice(&(43 << 9 | 12 << 5 | 31u16).to_le_bytes());
+    entry[0x12..0x14].copy_from_slice(&(44 << 9 | 3 << 5 | 1u16).to_le_bytes());
+    put_entries(&mut disk, 2, &[entry, short_entry(b"PLAIN   TXT", 0x20, 0, 0)]);
+
+    let offset = system::utc_offset_minutes();
+    system::set_utc_offset_minutes(60);
+    let volume = Volume::open(Image(&disk), 0).unwrap();
+    let notes = volume.stat("notes.txt").unwrap();
+    system::set_utc_offset_minutes(offset);
+    let secs = |time: Option<SystemTime>| time.unwrap().since_unix_epoch().as_secs();
+    // An hour ahead of UTC
+    assert_eq!(secs(notes.modified()), 1_709_214_358 - 3600);
+    assert_eq!(notes.created().unwrap().since_unix_epoch(), Duration::from_millis(1_704_067_200_500 - 3_600_000));
+    assert_eq!(secs(notes.accessed()), 1_709_251_200 - 3600);
+    assert!(notes.is_read_only());
+
+    let plain = volume.stat("/plain.txt").unwrap();
+    assert_eq!((plain.modified(), plain.is_read_only()), (None, false));
+    assert_eq!(volume.stat("/").unwrap().created(), None);
+}
+
+#[test_case]
+fn test_rejects_other_filesystems() {
+    use image::*;
+
+    let mut disk = new();
+    // A FAT16 boot sector says how many root directory entries it has
+    disk[0x11] = 0x02;
+    assert_eq!(Volume::open(Image(&disk), 0).err(), Some("not FAT32"));
+    assert_eq!(Volume::open(Image(&[0; SECTOR_SIZE]), 0).err(), Some("no boot signature"));
+}
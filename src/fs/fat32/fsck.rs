@@ -0,0 +1,442 @@
+//! Checking a FAT32 volume the way fsck.fat does. It checks that:
+//! - every chain runs through clusters in use to an end
+//! - no cluster is in two chains
+//! - each file has as many clusters as its size needs
+//! - "." and ".." say where they are
+//! - every cluster in use belongs to something
+//! - all the FATs agree
+//! - FSInfo's free count is right
+//!
+//! With `repair`, a link to nowhere ends its chain there, and a chain
+//! longer than its file, or running into another, is cut short. Lost
+//! clusters are freed, the FATs are made the same again, and the free count
+//! is rewritten. What would need a directory entry changed is only
+//! reported.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use super::{u32_at, BlockDevice, Volume, CLUSTER_MASK, END_OF_CHAIN, SECTOR_SIZE};
+
+const FREE: u32 = 0;
+const BAD_CLUSTER: u32 = 0x0fff_fff7;
+// Where FSInfo has its signatures, and its count of free clusters
+const FS_INFO_SIGNATURES: [(usize, u32); 3] = [(0, 0x4161_5252), (484, 0x6141_7272), (508, 0xaa55_0000)];
+const FS_INFO_FREE: usize = 488;
+// A free count that says it isn't known
+const UNKNOWN: u32 = 0xffff_ffff;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// A FAT entry links to a cluster outside the volume, free or bad
+    BadLink { cluster: u32, next: u32 },
+    /// A file or directory starts outside the volume, or at a free cluster
+    BadStart { path: String, cluster: u32 },
+    /// A chain runs into a cluster already in use, by it or another
+    CrossLinked { path: String, cluster: u32 },
+    /// A file doesn't have the clusters its size needs
+    WrongLength { path: String, size: u32, clusters: u32 },
+    /// A directory's "." or ".." points somewhere else
+    BadDotEntry { path: String },
+    /// A directory couldn't be read through
+    Unreadable { path: String, error: &'static str },
+    /// Clusters in use that nothing refers to
+    LostClusters { clusters: u32 },
+    /// The copies of the FAT don't agree
+    FatsDiffer,
+    /// FSInfo's count of free clusters is off
+    FreeCount { recorded: u32, actual: u32 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::BadLink { cluster, next } => write!(f, "cluster {} links to {}, which isn't in use", cluster, next),
+            Problem::BadStart { path, cluster } => write!(f, "{}: starts at cluster {}, which isn't in use", path,
+                cluster),
+            Problem::CrossLinked { path, cluster } => write!(f, "{}: runs into cluster {}, already in use", path,
+                cluster),
+            Problem::WrongLength { path, size, clusters } => write!(f, "{}: {} bytes in {} clusters", path, size,
+                clusters),
+            Problem::BadDotEntry { path } => write!(f, "{}: \".\" or \"..\" points elsewhere", path),
+            Problem::Unreadable { path, error } => write!(f, "{}: {}", path, error),
+            Problem::LostClusters { clusters } => write!(f, "{} clusters in use by nothing", clusters),
+            Problem::FatsDiffer => write!(f, "the FATs differ"),
+            Problem::FreeCount { recorded, actual } => write!(f, "FSInfo says {} clusters are free, not {}", recorded,
+                actual),
+        }
+    }
+}
+
+/// What a check found
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Each problem, and whether it was repaired
+    pub problems: Vec<(Problem, bool)>,
+    pub files: usize,
+    /// The root included
+    pub directories: usize,
+    /// Clusters in use and free, after any repairs
+    pub used: u32,
+    pub free: u32,
+}
+
+impl Report {
+    /// Whether nothing's wrong, or was and has been repaired
+    pub fn is_clean(&self) -> bool {
+        self.problems.iter().all(|&(_, repaired)| repaired)
+    }
+}
+
+struct Checker<'a, D> {
+    volume: &'a Volume<D>,
+    repair: bool,
+    // The first FAT, by cluster, and which of its sectors repairs changed
+    fat: Vec<u32>,
+    dirty: Vec<bool>,
+    // Clusters some file or directory has
+    owned: Vec<bool>,
+    report: Report,
+}
+
+impl<D: BlockDevice> Checker<'_, D> {
+    fn found(&mut self, problem: Problem, repaired: bool) {
+        self.report.problems.push((problem, repaired));
+    }
+
+    fn set(&mut self, cluster: u32, next: u32) {
+        self.fat[cluster as usize] = next;
+        self.dirty[cluster as usize * 4 / SECTOR_SIZE] = true;
+    }
+
+    // A cluster in the data area that's in use
+    fn in_use(&self, cluster: u32) -> bool {
+        (2..self.fat.len() as u32).contains(&cluster) && !matches!(self.fat[cluster as usize], FREE | BAD_CLUSTER)
+    }
+
+    // The cluster after `cluster` in its chain, if it goes on to one in use
+    fn next(&self, cluster: u32) -> Option<u32> {
+        let next = self.fat[cluster as usize];
+        if next < END_OF_CHAIN && self.in_use(next) { Some(next) } else { None }
+    }
+
+    fn check_links(&mut self) {
+        for cluster in 2..self.fat.len() as u32 {
+            let next = self.fat[cluster as usize];
+            if matches!(next, FREE | BAD_CLUSTER) || next >= END_OF_CHAIN || self.in_use(next) {
+                continue;
+            }
+            if self.repair {
+                self.set(cluster, CLUSTER_MASK);
+            }
+            self.found(Problem::BadLink { cluster, next }, self.repair);
+        }
+    }
+
+    // Take `start`'s chain as `path`'s, up to any cluster that's already
+    // someone's; how many clusters it has
+    fn claim(&mut self, path: &str, start: u32) -> u32 {
+        let mut clusters = 0;
+        let mut previous = None;
+        let mut cluster = Some(start);
+        while let Some(this) = cluster {
+            if self.owned[this as usize] {
+                // Only the first cluster is in the directory entry
+                let repaired = self.repair && previous.is_some();
+                if let Some(previous) = previous.filter(|_| repaired) {
+                    self.set(previous, CLUSTER_MASK);
+                }
+                self.found(Problem::CrossLinked { path: String::from(path), cluster: this }, repaired);
+                break;
+            }
+            self.owned[this as usize] = true;
+            clusters += 1;
+            previous = Some(this);
+            cluster = self.next(this);
+        }
+        clusters
+    }
+
+    // Cut `start`'s chain after `keep` clusters, giving up the rest
+    fn cut(&mut self, start: u32, keep: u32, clusters: u32) {
+        let mut cluster = start;
+        for _ in 1..keep {
+            cluster = self.next(cluster).expect("chain is as long as claimed");
+        }
+        let mut rest = self.next(cluster);
+        self.set(cluster, CLUSTER_MASK);
+        for _ in keep..clusters {
+            let this = match rest {
+                Some(this) => this,
+                None => break,
+            };
+            self.owned[this as usize] = false;
+            rest = self.next(this);
+        }
+    }
+
+    fn check_file(&mut self, path: &str, start: u32, size: u32) {
+        let cluster_bytes = self.volume.cluster_bytes();
+        let wanted = (u64::from(size) + u64::from(cluster_bytes) - 1) / u64::from(cluster_bytes);
+        let clusters = if start == 0 { 0 } else { self.claim(path, start) };
+        if u64::from(clusters) == wanted {
+            return;
+        }
+        // Too many can be cut down; too few would need the size changed
+        let repaired = self.repair && u64::from(clusters) > wanted && wanted > 0;
+        if repaired {
+            self.cut(start, wanted as u32, clusters);
+        }
+        self.found(Problem::WrongLength { path: String::from(path), size, clusters }, repaired);
+    }
+
+    fn check_tree(&mut self) {
+        let root = self.volume.root_cluster;
+        self.claim("/", root);
+        // Directories to look through, with their parents' clusters
+        let mut pending = vec![(String::new(), root, root)];
+        while let Some((path, cluster, parent)) = pending.pop() {
+            self.report.directories += 1;
+            for entry in self.volume.dir(cluster) {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(error) => {
+                        let path = if path.is_empty() { String::from("/") } else { path.clone() };
+                        self.found(Problem::Unreadable { path, error }, false);
+                        break;
+                    }
+                };
+                let child = format!("{}/{}", path, entry.name);
+                // ".." in a directory just under the root says cluster 0
+                let dot = match entry.name() {
+                    "." => Some(entry.cluster == cluster),
+                    ".." => Some(entry.cluster == parent || (parent == root && entry.cluster == 0)),
+                    _ => None,
+                };
+                match dot {
+                    Some(true) => continue,
+                    Some(false) => {
+                        self.found(Problem::BadDotEntry { path: path.clone() }, false);
+                        continue;
+                    }
+                    None => {}
+                }
+                if !entry.is_dir() {
+                    self.report.files += 1;
+                    if entry.cluster == 0 || self.in_use(entry.cluster) {
+                        self.check_file(&child, entry.cluster, entry.size);
+                        continue;
+                    }
+                } else if self.in_use(entry.cluster) {
+                    // Not into one that's already someone's, which may
+                    // well be one of its own parents
+                    if self.claim(&child, entry.cluster) > 0 {
+                        pending.push((child, entry.cluster, cluster));
+                    }
+                    continue;
+                }
+                self.found(Problem::BadStart { path: child, cluster: entry.cluster }, false);
+            }
+        }
+    }
+
+    fn check_lost(&mut self) {
+        let mut lost = 0;
+        for cluster in 2..self.fat.len() as u32 {
+            if !self.owned[cluster as usize] && self.in_use(cluster) {
+                lost += 1;
+                if self.repair {
+                    self.set(cluster, FREE);
+                }
+            }
+        }
+        if lost > 0 {
+            self.found(Problem::LostClusters { clusters: lost }, self.repair);
+        }
+    }
+
+    fn check_free_count(&mut self) -> Result<(), &'static str> {
+        let free = self.fat[2..].iter().filter(|&&next| next == FREE).count() as u32;
+        self.report.free = free;
+        self.report.used = self.owned.iter().filter(|&&owned| owned).count() as u32;
+        let lba = match self.volume.fs_info {
+            Some(lba) => lba,
+            None => return Ok(()),
+        };
+        let mut sector = [0; SECTOR_SIZE];
+        self.volume.device.read_sector(lba, &mut sector)?;
+        if FS_INFO_SIGNATURES.iter().any(|&(offset, signature)| u32_at(&sector, offset) != signature) {
+            return Ok(());
+        }
+        let recorded = u32_at(&sector, FS_INFO_FREE);
+        if recorded == UNKNOWN || recorded == free {
+            return Ok(());
+        }
+        if self.repair {
+            sector[FS_INFO_FREE..FS_INFO_FREE + 4].copy_from_slice(&free.to_le_bytes());
+            self.volume.device.write_sector(lba, &sector)?;
+        }
+        self.found(Problem::FreeCount { recorded, actual: free }, self.repair);
+        Ok(())
+    }
+
+    // Write the FAT sectors repairs changed to every copy, keeping the top
+    // four bits of each entry, which aren't part of it. Each copy is on the
+    // disk before the next is touched, so losing power leaves one whole.
+    fn flush(&mut self) -> Result<(), &'static str> {
+        let volume = self.volume;
+        let mut sector = [0; SECTOR_SIZE];
+        for copy in 0..volume.fats {
+            for index in (0..self.dirty.len()).filter(|&index| self.dirty[index]) {
+                volume.device.read_sector(volume.fat_start + index as u64, &mut sector)?;
+                let first = index * SECTOR_SIZE / 4;
+                for (slot, &next) in sector.chunks_exact_mut(4).zip(&self.fat[first..]) {
+                    let kept = u32_at(slot, 0) & !CLUSTER_MASK;
+                    slot.copy_from_slice(&(kept | next).to_le_bytes());
+                }
+                let lba = volume.fat_start + u64::from(copy) * u64::from(volume.fat_size) + index as u64;
+                volume.device.write_sector(lba, &sector)?;
+            }
+            volume.device.flush()?;
+        }
+        self.dirty.iter_mut().for_each(|dirty| *dirty = false);
+        Ok(())
+    }
+}
+
+// The first FAT, as far as it has entries for clusters, and whether the
+// other copies are the same
+fn read_fat<D: BlockDevice>(volume: &Volume<D>) -> Result<(Vec<u32>, bool), &'static str> {
+    let entries = volume.clusters as usize + 2;
+    let sectors = (entries * 4 + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    let mut fat = Vec::with_capacity(sectors * SECTOR_SIZE / 4);
+    let mut same = true;
+    let (mut sector, mut copy) = ([0; SECTOR_SIZE], [0; SECTOR_SIZE]);
+    for index in 0..sectors as u64 {
+        volume.device.read_sector(volume.fat_start + index, &mut sector)?;
+        for other in 1..volume.fats {
+            volume.device.read_sector(volume.fat_start + u64::from(other) * u64::from(volume.fat_size) + index,
+                &mut copy)?;
+            same &= copy == sector;
+        }
+        fat.extend(sector.chunks_exact(4).map(|entry| u32_at(entry, 0) & CLUSTER_MASK));
+    }
+    fat.truncate(entries);
+    Ok((fat, same))
+}
+
+/// Check `volume`, and with `repair` fix what can be fixed. Errors are for
+/// failing to read (or write) the disk, not for what's found on it.
+pub fn check<D: BlockDevice>(volume: &Volume<D>, repair: bool) -> Result<Report, &'static str> {
+    let (fat, same) = read_fat(volume)?;
+    let sectors = (fat.len() * 4 + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    let mut checker = Checker {
+        volume,
+        repair,
+        owned: vec![false; fat.len()],
+        fat,
+        // All of it, to make the copies agree again
+        dirty: vec![repair && !same; sectors],
+        report: Report::default(),
+    };
+    if !same {
+        checker.found(Problem::FatsDiffer, repair);
+    }
+    checker.check_links();
+    // So directories read through what's been repaired
+    if repair {
+        checker.flush()?;
+    }
+    checker.check_tree();
+    checker.check_lost();
+    checker.check_free_count()?;
+    if repair {
+        checker.flush()?;
+    }
+    Ok(checker.report)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_a_clean_volume_is_clean() {
+    use super::image::*;
+    use super::ATTR_DIRECTORY;
+
+    let mut disk = new();
+    put_entries(&mut disk, 2, &[
+        short_entry(b"HELLO   TXT", 0x20, 3, 3),
+        short_entry(b"EMPTY   TXT", 0x20, 0, 0),
+        short_entry(b"SUB        ", ATTR_DIRECTORY, 4, 0),
+    ]);
+    set_fat(&mut disk, 3, CLUSTER_MASK);
+    set_fat(&mut disk, 4, CLUSTER_MASK);
+    put_entries(&mut disk, 4, &[
+        short_entry(b".          ", ATTR_DIRECTORY, 4, 0),
+        short_entry(b"..         ", ATTR_DIRECTORY, 0, 0),
+    ]);
+    let volume = Volume::open(Image(&disk), 0).unwrap();
+    let report = check(&volume, false).unwrap();
+    assert!(report.problems.is_empty());
+    assert_eq!((report.files, report.directories, report.used), (2, 2, 3));
+    assert_eq!(report.free, volume.clusters - 3);
+}
+
+#[test_case]
+fn test_finds_and_repairs_problems() {
+    use core::cell::RefCell;
+    use super::image::*;
+
+    let mut disk = new();
+    put_entries(&mut disk, 2, &[
+        // A cluster more than it needs, then two files sharing cluster 6
+        short_entry(b"LONG    TXT", 0x20, 3, 10),
+        short_entry(b"SHARED  TXT", 0x20, 5, 600),
+        short_entry(b"OTHER   TXT", 0x20, 7, 600),
+    ]);
+    set_fat(&mut disk, 3, 4);
+    set_fat(&mut disk, 4, CLUSTER_MASK);
+    set_fat(&mut disk, 5, 6);
+    set_fat(&mut disk, 6, CLUSTER_MASK);
+    set_fat(&mut disk, 7, 6);
+    // A lost chain, and a lost cluster linking to nowhere
+    set_fat(&mut disk, 9, 10);
+    set_fat(&mut disk, 10, CLUSTER_MASK);
+    set_fat(&mut disk, 11, 0x0fff_0000);
+    // FSInfo in sector 1, its count of free clusters wrong
+    disk[0x30] = 1;
+    for &(offset, signature) in FS_INFO_SIGNATURES.iter() {
+        disk[SECTOR_SIZE + offset..][..4].copy_from_slice(&signature.to_le_bytes());
+    }
+    disk[SECTOR_SIZE + FS_INFO_FREE..][..4].copy_from_slice(&5u32.to_le_bytes());
+
+    let disk = RefCell::new(disk);
+    let volume = Volume::open(Writable(&disk), 0).unwrap();
+    let before = disk.borrow().clone();
+    let report = check(&volume, false).unwrap();
+    assert_eq!(*disk.borrow(), before);
+    let free = volume.clusters - 9;
+    let problems: Vec<Problem> = report.problems.iter().map(|(problem, _)| problem.clone()).collect();
+    assert_eq!(problems, [
+        Problem::BadLink { cluster: 11, next: 0x0fff_0000 },
+        Problem::WrongLength { path: String::from("/LONG.TXT"), size: 10, clusters: 2 },
+        Problem::CrossLinked { path: String::from("/OTHER.TXT"), cluster: 6 },
+        Problem::WrongLength { path: String::from("/OTHER.TXT"), size: 600, clusters: 1 },
+        Problem::LostClusters { clusters: 3 },
+        Problem::FreeCount { recorded: 5, actual: free },
+    ]);
+    assert!(!report.is_clean());
+
+    // All but the short file, which would need its size changed
+    let report = check(&volume, true).unwrap();
+    let unrepaired: Vec<&Problem> = report.problems.iter().filter(|(_, repaired)| !repaired).map(|(problem, _)| problem)
+        .collect();
+    assert_eq!(unrepaired, [&Problem::WrongLength { path: String::from("/OTHER.TXT"), size: 600, clusters: 1 }]);
+    assert_eq!(report.used, 5);
+    let report = check(&volume, false).unwrap();
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(report.free, volume.clusters - 5);
+    assert_eq!(u32_at(&disk.borrow()[SECTOR_SIZE..], FS_INFO_FREE), report.free);
+}
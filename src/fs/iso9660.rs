@@ -0,0 +1,582 @@
+//! Read-only ISO 9660, the CD filesystem, over anything that reads 512-byte
+//! sectors. Rock Ridge names and modes are used where a disc has them,
+//! otherwise names are the plain ISO ones with their ";1" version cut off,
+//! and are matched ignoring case. There's no ATAPI driver, so an image is
+//! read from an ATA disk (QEMU's `-drive file=x.iso,format=raw`), not from
+//! the drive the kernel booted from.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::rtc::DateTime;
+use crate::time::{Duration, SystemTime};
+use super::{BlockDevice, SECTOR_SIZE};
+
+// Volume descriptors start 16 2048-byte blocks in, whatever the block size
+const DESCRIPTORS_START: u64 = 16 * 2048;
+const DESCRIPTOR_LEN: usize = 2048;
+const PRIMARY: u8 = 1;
+const TERMINATOR: u8 = 255;
+// There's a descriptor per thing on the disc, but never these many
+const MAX_DESCRIPTORS: u64 = 32;
+const MAX_BLOCK: usize = 2048;
+
+// A directory record: its length, extent, data length, date, flags and
+// name, then whatever system use entries (Rock Ridge's) follow
+const RECORD_LEN: usize = 33;
+const FLAG_DIRECTORY: u8 = 0x02;
+// Rock Ridge's NM flags for an entry naming "." or ".."; names in pieces
+// are just joined, so the flag saying more follows doesn't matter
+const NM_CURRENT: u8 = 0x02;
+const NM_PARENT: u8 = 0x04;
+// Continuation areas followed for one record, at most
+const MAX_CONTINUATIONS: usize = 8;
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+// A record's date: years from 1900, month, day, hour, minute and second,
+// then the offset from UTC in 15 minute steps. None if it isn't set.
+fn timestamp(raw: &[u8]) -> Option<SystemTime> {
+    let datetime = DateTime {
+        year: 1900 + u16::from(raw[0]),
+        month: raw[1],
+        day: raw[2],
+        hour: raw[3],
+        minute: raw[4],
+        second: raw[5],
+    };
+    if datetime.year < 1970 || !(1..=12).contains(&datetime.month) || datetime.day == 0 || datetime.hour > 23
+        || datetime.minute > 59 || datetime.second > 59
+    {
+        return None;
+    }
+    let local = SystemTime::from_datetime(&datetime).since_unix_epoch().as_secs() as i64;
+    let offset = i64::from(raw[6] as i8) * 15 * 60;
+    Some(SystemTime::from_unix(Duration::from_secs((local - offset).max(0) as u64)))
+}
+
+/// An ISO 9660 filesystem, as its primary volume descriptor describes it
+#[derive(Debug, Clone, Copy)]
+pub struct Volume<D> {
+    device: D,
+    // The sector the image starts at
+    start: u64,
+    block_size: u32,
+    root_extent: u32,
+    root_size: u32,
+    // Bytes to skip in each record's system use area if it has Rock Ridge
+    rock_ridge: Option<u8>,
+}
+
+impl<D: BlockDevice> Volume<D> {
+    /// The filesystem whose image starts at sector `start` of `device`
+    pub fn open(device: D, start: u64) -> Result<Volume<D>, &'static str> {
+        let mut descriptor = [0; DESCRIPTOR_LEN];
+        let mut volume = Volume { device, start, block_size: 0, root_extent: 0, root_size: 0, rock_ridge: None };
+        for index in 0..MAX_DESCRIPTORS {
+            volume.read_bytes(DESCRIPTORS_START + index * DESCRIPTOR_LEN as u64, &mut descriptor)?;
+            if descriptor[1..6] != *b"CD001" {
+                return Err("not ISO 9660");
+            }
+            match descriptor[0] {
+                PRIMARY => break,
+                TERMINATOR => return Err("no primary volume descriptor"),
+                _ if index == MAX_DESCRIPTORS - 1 => return Err("no primary volume descriptor"),
+                _ => {}
+            }
+        }
+        let block_size = u32::from(u16::from_le_bytes([descriptor[128], descriptor[129]]));
+        if !block_size.is_power_of_two() || block_size < SECTOR_SIZE as u32 || block_size > MAX_BLOCK as u32 {
+            return Err("unsupported block size");
+        }
+        // The root's own record is in the descriptor
+        let root = &descriptor[156..156 + 34];
+        volume.block_size = block_size;
+        volume.root_extent = u32_at(root, 2);
+        volume.root_size = u32_at(root, 10);
+
+        // Rock Ridge announces itself with an SP entry in the root's "."
+        let mut dir = volume.dir(volume.root_extent, volume.root_size);
+        let record = dir.next_record()?.ok_or("corrupt directory")?;
+        let su = &record[system_use_start(&record)..];
+        if su.len() >= 7 && su[..2] == *b"SP" && su[4..6] == [0xbe, 0xef] {
+            volume.rock_ridge = Some(su[6]);
+        }
+        Ok(volume)
+    }
+
+    /// Whether names and modes come from Rock Ridge
+    pub fn has_rock_ridge(&self) -> bool {
+        self.rock_ridge.is_some()
+    }
+
+    // Fill `buf` from `position` bytes into the image
+    fn read_bytes(&self, mut position: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let mut sector = [0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            self.device.read_sector(self.start + position / SECTOR_SIZE as u64, &mut sector)?;
+            let within = (position % SECTOR_SIZE as u64) as usize;
+            let len = (SECTOR_SIZE - within).min(buf.len() - done);
+            buf[done..done + len].copy_from_slice(&sector[within..within + len]);
+            done += len;
+            position += len as u64;
+        }
+        Ok(())
+    }
+
+    fn block_position(&self, block: u32) -> u64 {
+        u64::from(block) * u64::from(self.block_size)
+    }
+
+    fn dir(&self, extent: u32, size: u32) -> Dir<D> {
+        Dir { volume: *self, extent, size, offset: 0, loaded: None, buf: [0; MAX_BLOCK] }
+    }
+
+    // Follow `path` from the root; "" and "/" are the root itself
+    fn lookup(&self, path: &str) -> Result<DirEntry, &'static str> {
+        let mut entry = DirEntry {
+            name: String::from("/"),
+            flags: FLAG_DIRECTORY,
+            extent: self.root_extent,
+            size: self.root_size,
+            recorded: None,
+            mode: None,
+        };
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if !entry.is_dir() {
+                return Err(super::NOT_A_DIRECTORY);
+            }
+            let mut found = None;
+            for child in self.dir(entry.extent, entry.size) {
+                let child = child?;
+                // Rock Ridge names are Unix ones, so their case counts
+                let matches = if self.has_rock_ridge() {
+                    child.name == component
+                } else {
+                    child.name.eq_ignore_ascii_case(component)
+                };
+                if matches {
+                    found = Some(child);
+                    break;
+                }
+            }
+            entry = found.ok_or(super::NOT_FOUND)?;
+        }
+        Ok(entry)
+    }
+
+    /// The entries of the directory at `path`
+    pub fn read_dir(&self, path: &str) -> Result<Dir<D>, &'static str> {
+        let entry = self.lookup(path)?;
+        if !entry.is_dir() {
+            return Err(super::NOT_A_DIRECTORY);
+        }
+        Ok(self.dir(entry.extent, entry.size))
+    }
+
+    /// The file or directory at `path`, as its directory lists it
+    pub fn stat(&self, path: &str) -> Result<DirEntry, &'static str> {
+        self.lookup(path)
+    }
+
+    /// The file at `path`, for reading from the start
+    pub fn open_file(&self, path: &str) -> Result<File<D>, &'static str> {
+        self.open_entry(&self.lookup(path)?)
+    }
+
+    /// The file `entry` is, as `stat` gave it, for reading from the start
+    pub fn open_entry(&self, entry: &DirEntry) -> Result<File<D>, &'static str> {
+        if entry.is_dir() {
+            return Err("is a directory");
+        }
+        Ok(File { volume: *self, extent: entry.extent, size: entry.size, position: 0 })
+    }
+
+    // Fill in what a record's Rock Ridge entries say, following any
+    // continuation areas
+    fn rock_ridge(&self, record: &[u8], entry: &mut DirEntry) -> Result<(), &'static str> {
+        let skip = match self.rock_ridge {
+            Some(skip) => usize::from(skip),
+            None => return Ok(()),
+        };
+        let mut name = String::new();
+        let mut has_name = false;
+        let mut area: Vec<u8> = record.get(system_use_start(record) + skip..).unwrap_or(&[]).into();
+        for _ in 0..=MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut index = 0;
+            while index + 4 <= area.len() {
+                let len = usize::from(area[index + 2]);
+                if len < 4 || index + len > area.len() {
+                    break;
+                }
+                let item = &area[index..index + len];
+                match &item[..2] {
+                    b"NM" if len >= 5 => {
+                        if item[4] & (NM_CURRENT | NM_PARENT) == 0 {
+                            name.push_str(&String::from_utf8_lossy(&item[5..]));
+                            has_name = true;
+                        }
+                    }
+                    b"PX" if len >= 12 => entry.mode = Some(u32_at(item, 4)),
+                    b"CE" if len >= 28 => continuation = Some((u32_at(item, 4), u32_at(item, 12), u32_at(item, 20))),
+                    b"ST" => break,
+                    _ => {}
+                }
+                index += len;
+            }
+            let (block, offset, len) = match continuation {
+                Some(continuation) => continuation,
+                None => break,
+            };
+            if len as usize > MAX_BLOCK {
+                return Err("corrupt directory");
+            }
+            area = alloc::vec![0; len as usize];
+            self.read_bytes(self.block_position(block) + u64::from(offset), &mut area)?;
+        }
+        if has_name {
+            entry.name = name;
+        }
+        Ok(())
+    }
+}
+
+// Where a record's system use area starts: after the name, and a byte of
+// padding if that leaves it at an odd offset
+fn system_use_start(record: &[u8]) -> usize {
+    let name_len = usize::from(record[32]);
+    (RECORD_LEN + name_len + (name_len + 1) % 2).min(record.len())
+}
+
+// A record's ISO name: "." and ".." are coded as 0 and 1, and files end in
+// a version, and a dot when there's no extension
+fn iso_name(raw: &[u8]) -> String {
+    match raw {
+        [0] => return String::from("."),
+        [1] => return String::from(".."),
+        _ => {}
+    }
+    let mut name = String::from_utf8_lossy(raw).into_owned();
+    if let Some(semicolon) = name.rfind(';') {
+        name.truncate(semicolon);
+    }
+    if name.ends_with('.') {
+        name.pop();
+    }
+    name
+}
+
+/// A file or directory, as its directory lists it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    name: String,
+    flags: u8,
+    extent: u32,
+    size: u32,
+    recorded: Option<SystemTime>,
+    mode: Option<u32>,
+}
+
+impl DirEntry {
+    /// The Rock Ridge name if there is one, otherwise the ISO one
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    /// In bytes; for a directory, the size of its records
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// When it was put on the disc; None for the root
+    pub fn recorded(&self) -> Option<SystemTime> {
+        self.recorded
+    }
+
+    /// The Unix mode, type bits and all, if Rock Ridge gave one
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+}
+
+/// The entries of a directory, "." and ".." included. Stops at the first
+/// error.
+pub struct Dir<D> {
+    volume: Volume<D>,
+    extent: u32,
+    size: u32,
+    // How far into the directory's records the next one is
+    offset: u32,
+    // The block of the directory in `buf`
+    loaded: Option<u32>,
+    buf: [u8; MAX_BLOCK],
+}
+
+impl<D: BlockDevice> Dir<D> {
+    // The next record, or None past the last
+    fn next_record(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+        let block_size = self.volume.block_size;
+        loop {
+            if self.offset >= self.size {
+                return Ok(None);
+            }
+            let block = self.offset / block_size;
+            if self.loaded != Some(block) {
+                let position = self.volume.block_position(self.extent + block);
+                self.volume.read_bytes(position, &mut self.buf[..block_size as usize])?;
+                self.loaded = Some(block);
+            }
+            let within = (self.offset % block_size) as usize;
+            let len = usize::from(self.buf[within]);
+            // Records don't cross blocks; the rest of this one is padding
+            if len == 0 {
+                self.offset = (block + 1) * block_size;
+                continue;
+            }
+            let record = &self.buf[within..];
+            if len < RECORD_LEN || within + len > block_size as usize || RECORD_LEN + usize::from(record[32]) > len {
+                return Err("corrupt directory");
+            }
+            self.offset += len as u32;
+            return Ok(Some(record[..len].into()));
+        }
+    }
+
+    fn entry(&self, record: &[u8]) -> Result<DirEntry, &'static str> {
+        let mut entry = DirEntry {
+            name: iso_name(&record[RECORD_LEN..RECORD_LEN + usize::from(record[32])]),
+            flags: record[25],
+            extent: u32_at(record, 2),
+            size: u32_at(record, 10),
+            recorded: timestamp(&record[18..25]),
+            mode: None,
+        };
+        self.volume.rock_ridge(record, &mut entry)?;
+        Ok(entry)
+    }
+}
+
+impl<D: BlockDevice> Iterator for Dir<D> {
+    type Item = Result<DirEntry, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = match self.next_record() {
+            Ok(Some(record)) => self.entry(&record),
+            Ok(None) => return None,
+            Err(message) => Err(message),
+        };
+        if entry.is_err() {
+            self.offset = self.size;
+        }
+        Some(entry)
+    }
+}
+
+/// A file open for reading
+pub struct File<D> {
+    volume: Volume<D>,
+    extent: u32,
+    size: u32,
+    position: u32,
+}
+
+impl<D: BlockDevice> File<D> {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Read from where the last read stopped; 0 means the end of the file.
+    /// Files are in one piece on the disc, so this is one run of sectors.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let len = buf.len().min((self.size - self.position) as usize);
+        let position = self.volume.block_position(self.extent) + u64::from(self.position);
+        self.volume.read_bytes(position, &mut buf[..len])?;
+        self.position += len as u32;
+        Ok(len)
+    }
+}
+
+/// TESTS
+
+#[cfg(test)]
+mod image {
+    //! A small ISO 9660 image built in memory, in 2048-byte blocks: the
+    //! descriptors at 16 and 17, then whatever the test puts after them
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use super::*;
+
+    pub const BLOCK: usize = 2048;
+    const BLOCKS: usize = 32;
+    pub const ROOT: u32 = 18;
+
+    #[derive(Clone, Copy)]
+    pub struct Image<'a>(pub &'a [u8]);
+
+    impl BlockDevice for Image<'_> {
+        fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+            let start = lba as usize * SECTOR_SIZE;
+            let sector = self.0.get(start..start + SECTOR_SIZE).ok_or("past the end of the image")?;
+            buf.copy_from_slice(sector);
+            Ok(())
+        }
+    }
+
+    /// A record for `name` (as stored), with `system_use` after it
+    pub fn record(name: &[u8], flags: u8, extent: u32, size: u32, system_use: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; RECORD_LEN];
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[6..10].copy_from_slice(&extent.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        // 2024-02-29 13:45:58, two hours ahead of UTC
+        record[18..25].copy_from_slice(&[124, 2, 29, 13, 45, 58, 8]);
+        record[25] = flags;
+        record[32] = name.len() as u8;
+        record.extend_from_slice(name);
+        if name.len() % 2 == 0 {
+            record.push(0);
+        }
+        record.extend_from_slice(system_use);
+        record[0] = record.len() as u8;
+        record
+    }
+
+    /// A system use entry
+    pub fn su(signature: &[u8; 2], body: &[u8]) -> Vec<u8> {
+        let mut entry = vec![signature[0], signature[1], 4 + body.len() as u8, 1];
+        entry.extend_from_slice(body);
+        entry
+    }
+
+    pub fn both_u32(value: u32) -> [u8; 8] {
+        let mut both = [0; 8];
+        both[..4].copy_from_slice(&value.to_le_bytes());
+        both[4..].copy_from_slice(&value.to_be_bytes());
+        both
+    }
+
+    /// An image whose root, at block ROOT, is one block of `root` records
+    pub fn new(root: &[Vec<u8>]) -> Vec<u8> {
+        let mut image = vec![0; BLOCKS * BLOCK];
+        let primary = &mut image[16 * BLOCK..17 * BLOCK];
+        primary[0] = PRIMARY;
+        primary[1..6].copy_from_slice(b"CD001");
+        primary[6] = 1;
+        primary[128..130].copy_from_slice(&(BLOCK as u16).to_le_bytes());
+        primary[130..132].copy_from_slice(&(BLOCK as u16).to_be_bytes());
+        let own = record(&[0], FLAG_DIRECTORY, ROOT, BLOCK as u32, &[]);
+        primary[156..156 + own.len()].copy_from_slice(&own);
+        let terminator = &mut image[17 * BLOCK..18 * BLOCK];
+        terminator[0] = TERMINATOR;
+        terminator[1..6].copy_from_slice(b"CD001");
+        put_records(&mut image, ROOT, root);
+        image
+    }
+
+    pub fn block(image: &mut [u8], block: u32) -> &mut [u8] {
+        &mut image[block as usize * BLOCK..][..BLOCK]
+    }
+
+    pub fn put_records(image: &mut [u8], at: u32, records: &[Vec<u8>]) {
+        let block = block(image, at);
+        let mut offset = 0;
+        for record in records {
+            block[offset..offset + record.len()].copy_from_slice(record);
+            offset += record.len();
+        }
+    }
+}
+
+#[test_case]
+fn test_iso_names_and_files() {
+    use image::*;
+
+    let mut disc = new(&[
+        record(&[0], FLAG_DIRECTORY, ROOT, BLOCK as u32, &[]),
+        record(&[1], FLAG_DIRECTORY, ROOT, BLOCK as u32, &[]),
+        record(b"DOCS", FLAG_DIRECTORY, 19, BLOCK as u32, &[]),
+        record(b"README.TXT;1", 0, 20, 3000, &[]),
+    ]);
+    put_records(&mut disc, 19, &[
+        record(&[0], FLAG_DIRECTORY, 19, BLOCK as u32, &[]),
+        record(&[1], FLAG_DIRECTORY, ROOT, BLOCK as u32, &[]),
+        record(b"NOTES.;1", 0, 22, 5, &[]),
+    ]);
+    block(&mut disc, 20).fill(b'a');
+    block(&mut disc, 21).fill(b'b');
+    block(&mut disc, 22)[..5].copy_from_slice(b"notes");
+
+    let volume = Volume::open(Image(&disc), 0).unwrap();
+    assert!(!volume.has_rock_ridge());
+    let names: Vec<String> = volume.read_dir("/").unwrap().map(|entry| entry.unwrap().name).collect();
+    assert_eq!(names, [".", "..", "DOCS", "README.TXT"]);
+    let notes = volume.stat("docs/../DOCS/notes").unwrap();
+    assert_eq!((notes.size(), notes.mode()), (5, None));
+    // Two hours ahead of UTC
+    assert_eq!(notes.recorded().unwrap().since_unix_epoch().as_secs(), 1_709_214_358 - 7200);
+
+    // Read in pieces that straddle sectors and blocks
+    let mut file = volume.open_file("/readme.txt").unwrap();
+    let mut contents = Vec::new();
+    let mut buf = [0; 700];
+    loop {
+        let n = file.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        contents.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(contents.len(), 3000);
+    assert!(contents[..BLOCK].iter().all(|&byte| byte == b'a'));
+    assert!(contents[BLOCK..].iter().all(|&byte| byte == b'b'));
+
+    assert_eq!(volume.open_file("DOCS").err(), Some("is a directory"));
+    assert_eq!(volume.read_dir("README.TXT").err(), Some("not a directory"));
+    assert_eq!(volume.open_file("nope").err(), Some("no such file or directory"));
+    assert_eq!(Volume::open(Image(&[0; 40 * BLOCK]), 0).err(), Some("not ISO 9660"));
+}
+
+#[test_case]
+fn test_rock_ridge() {
+    use image::*;
+
+    let mut sp = su(b"SP", &[0xbe, 0xef, 0]);
+    sp.extend(su(b"NM", &[NM_CURRENT]));
+    // A name in two pieces, the second in a continuation area
+    let mut long = su(b"NM", &[0x01]);
+    long.extend_from_slice(b"A Long ");
+    long.extend(su(b"CE", &[both_u32(23), both_u32(0), both_u32(32)].concat()));
+    let mut px: Vec<u8> = both_u32(0o100_644).into();
+    px.extend_from_slice(&both_u32(1));
+    let mut continued = su(b"NM", b"\0name.txt");
+    continued.extend(su(b"PX", &px));
+
+    let mut disc = new(&[
+        record(&[0], FLAG_DIRECTORY, ROOT, BLOCK as u32, &sp),
+        record(&[1], FLAG_DIRECTORY, ROOT, BLOCK as u32, &su(b"NM", &[NM_PARENT])),
+        record(b"A_LONG_N.TXT;1", 0, 22, 2, &long),
+        record(b"PLAIN.TXT;1", 0, 22, 2, &[]),
+    ]);
+    block(&mut disc, 23)[..continued.len()].copy_from_slice(&continued);
+    block(&mut disc, 22)[..2].copy_from_slice(b"hi");
+
+    let volume = Volume::open(Image(&disc), 0).unwrap();
+    assert!(volume.has_rock_ridge());
+    let names: Vec<String> = volume.read_dir("").unwrap().map(|entry| entry.unwrap().name).collect();
+    assert_eq!(names, [".", "..", "A Long name.txt", "PLAIN.TXT"]);
+    let entry = volume.stat("/A Long name.txt").unwrap();
+    assert_eq!(entry.mode(), Some(0o100_644));
+    // Case counts with Rock Ridge
+    assert!(volume.stat("/a long name.txt").is_err());
+    let mut buf = [0; 4];
+    assert_eq!(volume.open_entry(&entry).unwrap().read(&mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"hi");
+}
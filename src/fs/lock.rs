@@ -0,0 +1,175 @@
+//! Advisory whole-file locks, as flock has them: any number of shared
+//! holders or one exclusive one. They're only advisory; nothing stops a
+//! reader that doesn't ask. Files are told apart by path, made absolute
+//! and lowercased, which is enough with no links on FAT. A lock lasts as
+//! long as its `FileLock`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::sched::{self, ThreadId};
+
+/// What `lock` fails with when it isn't to wait
+pub const WOULD_BLOCK: &str = "file is locked";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+struct Locked {
+    path: String,
+    shared: usize,
+    exclusive: bool,
+    // Threads waiting for it, woken whenever a holder lets go
+    waiters: Vec<ThreadId>,
+}
+
+// Never touched by interrupt handlers
+static LOCKS: Mutex<Vec<Locked>> = Mutex::new(Vec::new());
+
+/// A lock on a file, given up when dropped
+#[derive(Debug)]
+pub struct FileLock {
+    path: String,
+    kind: LockKind,
+}
+
+impl FileLock {
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+
+    /// The path locked, as `lock` normalized it
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let mut locks = LOCKS.lock();
+        let index = match locks.iter().position(|locked| locked.path == self.path) {
+            Some(index) => index,
+            None => return,
+        };
+        let locked = &mut locks[index];
+        match self.kind {
+            LockKind::Shared => locked.shared -= 1,
+            LockKind::Exclusive => locked.exclusive = false,
+        }
+        for &waiter in locked.waiters.iter() {
+            sched::unpark(waiter);
+        }
+        if locked.shared == 0 && !locked.exclusive {
+            locks.swap_remove(index);
+        }
+    }
+}
+
+/// `path` from the root, "." and ".." worked out, in lowercase as FAT
+/// doesn't mind case
+pub fn normalize(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    let mut normal = String::new();
+    for component in components {
+        normal.push('/');
+        normal.push_str(component);
+    }
+    if normal.is_empty() {
+        normal.push('/');
+    }
+    normal.make_ascii_lowercase();
+    normal
+}
+
+/// Lock the file at `path`, whether or not it exists. With `wait`, park
+/// until it can be had; otherwise fail with WOULD_BLOCK.
+pub fn lock(path: &str, kind: LockKind, wait: bool) -> Result<FileLock, &'static str> {
+    let path = normalize(path);
+    loop {
+        {
+            let mut locks = LOCKS.lock();
+            let me = sched::current();
+            let index = match locks.iter().position(|locked| locked.path == path) {
+                Some(index) => index,
+                None => {
+                    locks.push(Locked { path: path.clone(), shared: 0, exclusive: false, waiters: Vec::new() });
+                    locks.len() - 1
+                }
+            };
+            let locked = &mut locks[index];
+            let free = match kind {
+                LockKind::Shared => !locked.exclusive,
+                LockKind::Exclusive => !locked.exclusive && locked.shared == 0,
+            };
+            if free {
+                match kind {
+                    LockKind::Shared => locked.shared += 1,
+                    LockKind::Exclusive => locked.exclusive = true,
+                }
+                locked.waiters.retain(|&waiter| waiter != me);
+                return Ok(FileLock { path, kind });
+            }
+            if !wait {
+                return Err(WOULD_BLOCK);
+            }
+            if !locked.waiters.contains(&me) {
+                locked.waiters.push(me);
+            }
+        }
+        // Woken by any holder letting go, so look again
+        sched::park();
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_shared_and_exclusive() {
+    let first = lock("/locks/a.txt", LockKind::Shared, false).unwrap();
+    let second = lock("/LOCKS/./b/../A.TXT", LockKind::Shared, false).unwrap();
+    assert_eq!(lock("locks/a.txt", LockKind::Exclusive, false).err(), Some(WOULD_BLOCK));
+    drop(first);
+    assert!(lock("/locks/a.txt", LockKind::Exclusive, false).is_err());
+    drop(second);
+    let exclusive = lock("/locks/a.txt", LockKind::Exclusive, false).unwrap();
+    assert_eq!(exclusive.kind(), LockKind::Exclusive);
+    assert!(lock("/locks/a.txt", LockKind::Shared, false).is_err());
+    drop(exclusive);
+    assert!(LOCKS.lock().is_empty());
+}
+
+#[test_case]
+fn test_waiters_get_the_lock() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static HAD_IT: AtomicBool = AtomicBool::new(false);
+    fn waiter() {
+        let _lock = lock("/locks/wait", LockKind::Exclusive, true).unwrap();
+        HAD_IT.store(true, Ordering::SeqCst);
+    }
+
+    let held = lock("/locks/wait", LockKind::Shared, false).unwrap();
+    sched::spawn("test-flock", waiter).unwrap();
+    // It runs, finds the file locked, and parks
+    while sched::has_ready() {
+        sched::yield_now();
+    }
+    assert!(!HAD_IT.load(Ordering::SeqCst));
+    drop(held);
+    while !HAD_IT.load(Ordering::SeqCst) || sched::has_ready() {
+        sched::yield_now();
+    }
+    assert!(LOCKS.lock().is_empty());
+}
@@ -0,0 +1,458 @@
+//! Files on disk. The root filesystem is read-only FAT32 (see `fat32`),
+//! mounted from the first ATA disk that has one, either across the whole
+//! disk or in one of its MBR partitions. Paths start from its root. Other
+//! disks' FAT32 filesystems can be mounted on its directories with
+//! `mount_disk`, and paths under those go to them instead. `fsck`
+//! checks it, and is the one thing that writes to it, to make repairs.
+//! `iso9660` reads CD images, though nothing mounts one yet. A disk can be
+//! encrypted, and is then read through `crypt`.
+//!
+//! There are no users, so each file's owner bits of its mode are the ones
+//! that count, and `File::open_for` checks them. FAT has no execute bit,
+//! so, as with Linux's vfat by default, everything can be run, and files
+//! marked read-only lose their write bits.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::drivers::ata::{self, Disk, MAX_DISKS};
+use crate::sync::Rcu;
+use crate::time::SystemTime;
+
+pub mod crypt;
+pub mod fat32;
+pub mod iso9660;
+pub mod lock;
+pub mod raid;
+
+pub use crypt::Crypt;
+pub use fat32::DirEntry;
+pub use lock::{FileLock, LockKind};
+pub use raid::{Level, Mirror, Stripe};
+
+pub const SECTOR_SIZE: usize = ata::SECTOR_SIZE;
+
+/// What lookups fail with, for callers that tell failures apart
+pub const NOT_FOUND: &str = "no such file or directory";
+pub const NOT_A_DIRECTORY: &str = "not a directory";
+pub const NOT_MOUNTED: &str = "no filesystem mounted";
+pub const READ_ONLY: &str = "read-only device";
+
+// MBR partition types for FAT32, addressed by CHS and by LBA
+const FAT32_PARTITION_TYPES: [u8; 2] = [0x0b, 0x0c];
+
+/// Somewhere a filesystem can read sectors from, and maybe write them.
+/// Writes can be cached, and reach the medium in any order, until `flush`.
+pub trait BlockDevice: Copy {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str>;
+
+    fn write_sector(&self, _lba: u64, _buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        Err(READ_ONLY)
+    }
+
+    /// Put every write so far on the medium before returning, so none made
+    /// after can land ahead of them
+    fn flush(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Write a sector straight to the medium (forced unit access), leaving
+    /// other cached writes be; a flush after the write unless the device
+    /// can do better
+    fn write_sector_fua(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        self.write_sector(lba, buf)?;
+        self.flush()
+    }
+}
+
+impl BlockDevice for Disk {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        self.read_sectors(lba, buf)
+    }
+
+    fn write_sector(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        self.write_sectors(lba, buf)
+    }
+
+    // ATA only has FUA writes for DMA, so these get the flush
+    fn flush(&self) -> Result<(), &'static str> {
+        Disk::flush(self)
+    }
+}
+
+/// What the root filesystem is read through
+#[derive(Debug, Clone, Copy)]
+pub enum Device {
+    Disk(Disk),
+    Crypt(Crypt<Disk>),
+    Stripe(Stripe<Disk>),
+    Mirror(Mirror<Disk>),
+}
+
+impl BlockDevice for Device {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        match self {
+            Device::Disk(disk) => disk.read_sector(lba, buf),
+            Device::Crypt(crypt) => crypt.read_sector(lba, buf),
+            Device::Stripe(stripe) => stripe.read_sector(lba, buf),
+            Device::Mirror(mirror) => mirror.read_sector(lba, buf),
+        }
+    }
+
+    fn write_sector(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        match self {
+            Device::Disk(disk) => disk.write_sector(lba, buf),
+            Device::Crypt(crypt) => crypt.write_sector(lba, buf),
+            Device::Stripe(stripe) => stripe.write_sector(lba, buf),
+            Device::Mirror(mirror) => mirror.write_sector(lba, buf),
+        }
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        match self {
+            Device::Disk(disk) => BlockDevice::flush(disk),
+            Device::Crypt(crypt) => crypt.flush(),
+            Device::Stripe(stripe) => stripe.flush(),
+            Device::Mirror(mirror) => mirror.flush(),
+        }
+    }
+}
+
+/// Where the root filesystem was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mount {
+    /// As `ata::disk` numbers it
+    pub disk: usize,
+    /// The MBR partition, from 1, or None if it's the whole disk
+    pub partition: Option<usize>,
+    /// Whether the disk is read through `crypt`
+    pub encrypted: bool,
+    /// The kind of set, if `disk` is the first of a `raid` set's members
+    pub raid: Option<Level>,
+}
+
+// Both read without a lock, as every path is looked up in them. Never
+// written by interrupt handlers.
+static ROOT: Rcu<Option<(Mount, fat32::Volume<Device>)>> = Rcu::new(None);
+// Filesystems mounted on directories, each by its path as lock::normalize
+// has it
+static MOUNTS: Rcu<Vec<(String, Mount, fat32::Volume<Device>)>> = Rcu::new(Vec::new());
+
+// The FAT32 volume on `device`, and its partition if it's in one
+fn probe(device: Device) -> Option<(Option<usize>, fat32::Volume<Device>)> {
+    if let Ok(volume) = fat32::Volume::open(device, 0) {
+        return Some((None, volume));
+    }
+    let mut mbr = [0; SECTOR_SIZE];
+    device.read_sector(0, &mut mbr).ok()?;
+    if mbr[510..] != [0x55, 0xaa] {
+        return None;
+    }
+    // Four 16-byte entries: the type at 4, the first sector at 8
+    mbr[0x1be..0x1fe].chunks_exact(16).enumerate().find_map(|(index, entry)| {
+        if !FAT32_PARTITION_TYPES.contains(&entry[4]) {
+            return None;
+        }
+        let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+        fat32::Volume::open(device, u64::from(start)).ok().map(|volume| (Some(index + 1), volume))
+    })
+}
+
+/// Look through the disks for a filesystem to mount, if none is yet. With
+/// `raid=mirror:1,2` (or `stripe:`) on the command line, it's on those
+/// disks as a set instead. Encrypted disks are left for `mount_encrypted`,
+/// as their passphrase is only ever typed in.
+pub fn mount() -> Result<Mount, &'static str> {
+    if let Some(mount) = mounted() {
+        return Ok(mount);
+    }
+    if let Some(set) = crate::cmdline::get("raid") {
+        return mount_raid(set);
+    }
+    for index in 0..MAX_DISKS {
+        if let Some((partition, volume)) = ata::disk(index).map(Device::Disk).and_then(probe) {
+            let mount = Mount { disk: index, partition, encrypted: false, raid: None };
+            ROOT.update(|root| *root = Some((mount, volume)));
+            return Ok(mount);
+        }
+    }
+    Err("no FAT32 filesystem found")
+}
+
+/// Mount the filesystem on disk `index`, decrypting it with the key
+/// `passphrase` and the disk's salt give; see `crypt`
+pub fn mount_encrypted(index: usize, passphrase: &[u8]) -> Result<Mount, &'static str> {
+    if ROOT.read(Option::is_some) {
+        return Err("a filesystem is already mounted");
+    }
+    let disk = ata::disk(index).ok_or("no such disk")?;
+    mount_through(index, Crypt::open(disk, passphrase)?)
+}
+
+fn mount_through(index: usize, crypt: Crypt<Disk>) -> Result<Mount, &'static str> {
+    // A wrong passphrase reads noise, which won't look like FAT32
+    let (partition, volume) = probe(Device::Crypt(crypt)).ok_or("no FAT32 filesystem found; wrong passphrase?")?;
+    let mount = Mount { disk: index, partition, encrypted: true, raid: None };
+    ROOT.update(|root| *root = Some((mount, volume)));
+    Ok(mount)
+}
+
+// Mount the set that `set`, as `raid=` has it, describes
+fn mount_raid(set: &str) -> Result<Mount, &'static str> {
+    let (level, disks) = match set.split_once(':') {
+        Some(("stripe", disks)) => (Level::Stripe, disks),
+        Some(("mirror", disks)) => (Level::Mirror, disks),
+        _ => return Err("raid= wants stripe: or mirror: and a list of disks"),
+    };
+    // With the number of each, as the first is where it says it's mounted
+    let mut members = Vec::new();
+    for disk in disks.split(',') {
+        let index: usize = disk.parse().map_err(|_| "bad disk number in raid=")?;
+        members.push((index, ata::disk(index).ok_or("no such disk")?));
+    }
+    let disks: Vec<Disk> = members.iter().map(|&(_, disk)| disk).collect();
+    let device = match level {
+        Level::Stripe => Device::Stripe(Stripe::new(&disks, raid::DEFAULT_CHUNK)?),
+        Level::Mirror => Device::Mirror(Mirror::new(&disks)?),
+    };
+    let (partition, volume) = probe(device).ok_or("no FAT32 filesystem found")?;
+    let mount = Mount { disk: members[0].0, partition, encrypted: false, raid: Some(level) };
+    ROOT.update(|root| *root = Some((mount, volume)));
+    Ok(mount)
+}
+
+/// Where the root filesystem is, if one's mounted
+pub fn mounted() -> Option<Mount> {
+    ROOT.read(|root| root.map(|(mount, _)| mount))
+}
+
+fn root() -> Result<fat32::Volume<Device>, &'static str> {
+    ROOT.read(|root| root.map(|(_, volume)| volume)).ok_or(NOT_MOUNTED)
+}
+
+// Whether normalized `path` is `at` or under it
+fn within(path: &str, at: &str) -> bool {
+    path == at || path.starts_with(at) && path.as_bytes().get(at.len()) == Some(&b'/')
+}
+
+// The volume `path` is on, from the mount nearest it, and where it is there
+fn resolve(path: &str) -> Result<(fat32::Volume<Device>, String), &'static str> {
+    let normal = lock::normalize(path);
+    let nearest = MOUNTS.read(|mounts| {
+        let nearest = mounts.iter().filter(|(at, _, _)| within(&normal, at)).max_by_key(|(at, _, _)| at.len());
+        nearest.map(|(at, _, volume)| (*volume, at.len()))
+    });
+    match nearest {
+        Some((volume, at)) => Ok((volume, String::from(&normal[at..]))),
+        None => Ok((root()?, normal)),
+    }
+}
+
+/// Mount the FAT32 filesystem on disk `index` on the directory at `path`,
+/// or as the root if `path` is "/" and nothing's mounted yet. Each disk is
+/// mounted once at most.
+pub fn mount_disk(index: usize, path: &str) -> Result<Mount, &'static str> {
+    let at = lock::normalize(path);
+    let on_root = at == "/";
+    if mounts().iter().any(|(_, mount)| mount.disk == index) {
+        return Err("that disk is already mounted");
+    }
+    let taken = MOUNTS.read(|mounts| mounts.iter().any(|(mounted, _, _)| *mounted == at));
+    if on_root && ROOT.read(Option::is_some) || taken {
+        return Err("something's already mounted there");
+    }
+    if !on_root && !stat(&at)?.is_dir() {
+        return Err(NOT_A_DIRECTORY);
+    }
+    let disk = ata::disk(index).ok_or("no such disk")?;
+    let (partition, volume) = probe(Device::Disk(disk)).ok_or("no FAT32 filesystem found")?;
+    let mount = Mount { disk: index, partition, encrypted: false, raid: None };
+    if on_root {
+        ROOT.update(|root| *root = Some((mount, volume)));
+    } else {
+        MOUNTS.update(|mounts| mounts.push((at, mount, volume)));
+    }
+    Ok(mount)
+}
+
+/// Put what's been written to the filesystem mounted on `path` on its disk,
+/// and take it away. The root can't be, while others are mounted on it.
+pub fn unmount(path: &str) -> Result<(), &'static str> {
+    let at = lock::normalize(path);
+    if at == "/" {
+        if !MOUNTS.read(Vec::is_empty) {
+            return Err("other filesystems are mounted on it");
+        }
+        root()?.flush()?;
+        ROOT.update(|root| *root = None);
+        return Ok(());
+    }
+    let volume = MOUNTS.read(|mounts| {
+        let &(_, _, volume) = mounts.iter().find(|(mounted, _, _)| *mounted == at).ok_or(NOT_MOUNTED)?;
+        if mounts.iter().any(|(mounted, _, _)| *mounted != at && within(mounted, &at)) {
+            return Err("other filesystems are mounted on it");
+        }
+        Ok(volume)
+    })?;
+    volume.flush()?;
+    MOUNTS.update(|mounts| mounts.retain(|(mounted, _, _)| *mounted != at));
+    Ok(())
+}
+
+/// Everything mounted, by path, the root first
+pub fn mounts() -> Vec<(String, Mount)> {
+    let mut mounts: Vec<(String, Mount)> = mounted().map(|mount| (String::from("/"), mount)).into_iter().collect();
+    MOUNTS.read(|mounted| mounts.extend(mounted.iter().map(|(at, mount, _)| (at.clone(), *mount))));
+    mounts
+}
+
+/// File types and permission bits in a mode, as in Unix
+pub const S_IFDIR: u32 = 0o040_000;
+pub const S_IFREG: u32 = 0o100_000;
+const OWNER_READ: u32 = 0o400;
+const OWNER_WRITE: u32 = 0o200;
+const OWNER_EXECUTE: u32 = 0o100;
+
+/// What a file is opened to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// What `stat` says about a file or directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// In bytes; 0 for directories
+    pub size: u32,
+    /// The file type and permission bits
+    pub mode: u32,
+    /// None where the filesystem doesn't say
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+    /// When it was made. FAT keeps no change time, so this is what stat
+    /// gives as ctime, as Linux does.
+    pub created: Option<SystemTime>,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFDIR != 0
+    }
+
+    /// Whether the mode allows `access`
+    pub fn allows(&self, access: Access) -> bool {
+        let bit = match access {
+            Access::Read => OWNER_READ,
+            Access::Write => OWNER_WRITE,
+            Access::Execute => OWNER_EXECUTE,
+        };
+        self.mode & bit != 0
+    }
+}
+
+impl From<&DirEntry> for Metadata {
+    fn from(entry: &DirEntry) -> Metadata {
+        let kind = if entry.is_dir() { S_IFDIR } else { S_IFREG };
+        let permissions = if entry.is_read_only() { 0o555 } else { 0o755 };
+        Metadata {
+            size: entry.size(),
+            mode: kind | permissions,
+            modified: entry.modified(),
+            accessed: entry.accessed(),
+            created: entry.created(),
+        }
+    }
+}
+
+/// What the file or directory at `path` is
+pub fn stat(path: &str) -> Result<Metadata, &'static str> {
+    let (volume, path) = resolve(path)?;
+    Ok(Metadata::from(&volume.stat(&path)?))
+}
+
+/// Lock the file at `path`, which has to exist; see `lock::lock`
+pub fn flock(path: &str, kind: LockKind, wait: bool) -> Result<FileLock, &'static str> {
+    stat(path)?;
+    lock::lock(path, kind, wait)
+}
+
+/// A file open for reading
+pub struct File {
+    file: fat32::File<Device>,
+}
+
+impl File {
+    pub fn open(path: &str) -> Result<File, &'static str> {
+        File::open_for(path, Access::Read)
+    }
+
+    /// Open `path` if its mode allows `access`; there's nothing that
+    /// writes, so opening for it never gets further than the check
+    pub fn open_for(path: &str, access: Access) -> Result<File, &'static str> {
+        let (volume, path) = resolve(path)?;
+        let entry = volume.stat(&path)?;
+        if !Metadata::from(&entry).allows(access) {
+            return Err("permission denied");
+        }
+        if access == Access::Write {
+            return Err("read-only filesystem");
+        }
+        Ok(File { file: volume.open_entry(&entry)? })
+    }
+
+    /// In bytes
+    pub fn size(&self) -> u32 {
+        self.file.size()
+    }
+
+    /// Read from where the last read stopped; 0 means the end of the file
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        self.file.read(buf)
+    }
+}
+
+/// Check the mounted filesystem, and with `repair` fix what can be fixed
+pub fn fsck(repair: bool) -> Result<fat32::fsck::Report, &'static str> {
+    fat32::fsck::check(&root()?, repair)
+}
+
+/// Bring the mounted mirror's members back in line with member `from`;
+/// see `Mirror::resync`
+pub fn resync(from: usize) -> Result<Vec<u64>, &'static str> {
+    let mirror = match root()?.device() {
+        Device::Mirror(mirror) => mirror,
+        _ => return Err("the filesystem isn't on a mirror"),
+    };
+    // As far as the smallest member goes
+    let sectors = mirror.members().iter().map(|disk| disk.sectors).min().unwrap_or(0);
+    mirror.resync(from, sectors)
+}
+
+/// Put everything written to the mounted filesystems on their disks
+pub fn sync() -> Result<(), &'static str> {
+    root()?.flush()?;
+    let volumes: Vec<fat32::Volume<Device>> =
+        MOUNTS.read(|mounts| mounts.iter().map(|&(_, _, volume)| volume).collect());
+    volumes.iter().try_for_each(|volume| volume.flush())
+}
+
+/// `sync`, for the panic hooks, going on past a volume that fails. Reading
+/// the mounts can't block, even if the panic hit mid-mount.
+pub fn panic_sync() {
+    if let Ok(volume) = root() {
+        let _ = volume.flush();
+    }
+    // In place, as the heap may be what panicked
+    MOUNTS.read(|mounts| {
+        for (_, _, volume) in mounts.iter() {
+            let _ = volume.flush();
+        }
+    });
+}
+
+/// The entries of the directory at `path`
+pub fn read_dir(path: &str) -> Result<fat32::Dir<Device>, &'static str> {
+    let (volume, path) = resolve(path)?;
+    volume.read_dir(&path)
+}
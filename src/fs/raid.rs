@@ -0,0 +1,208 @@
+//! Block devices made of others, as software RAID makes them: `Stripe`
+//! (RAID-0) spreads chunks of sectors across its members in turn, `Mirror`
+//! (RAID-1) keeps the same sectors on all of them. Neither keeps anything
+//! on the members about the set they're in, so it's said each time, and a
+//! mirror doesn't remember which member fell behind: reads go to the first
+//! that answers, and `resync` brings the others back in line with one.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use super::{BlockDevice, SECTOR_SIZE};
+
+/// Which kind of set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Stripe,
+    Mirror,
+}
+
+/// Members a set can have
+pub const MAX_MEMBERS: usize = 4;
+/// Sectors per chunk in a stripe, unless asked otherwise: 64K, as mdadm has
+pub const DEFAULT_CHUNK: u64 = 128;
+
+// The members given, in a fixed array; the first fills the slots past them
+fn members<D: BlockDevice>(given: &[D]) -> Result<[D; MAX_MEMBERS], &'static str> {
+    let first = *given.first().ok_or("a set needs members")?;
+    if given.len() > MAX_MEMBERS {
+        return Err("too many members");
+    }
+    let mut members = [first; MAX_MEMBERS];
+    members[..given.len()].copy_from_slice(given);
+    Ok(members)
+}
+
+/// RAID-0: no redundancy, but the members' room and speed added up
+#[derive(Debug, Clone, Copy)]
+pub struct Stripe<D> {
+    members: [D; MAX_MEMBERS],
+    count: usize,
+    chunk: u64,
+}
+
+impl<D: BlockDevice> Stripe<D> {
+    /// Chunks of `chunk` sectors, a power of two, taking turns on `members`
+    pub fn new(members: &[D], chunk: u64) -> Result<Stripe<D>, &'static str> {
+        if !chunk.is_power_of_two() {
+            return Err("the chunk size isn't a power of two");
+        }
+        Ok(Stripe { members: self::members(members)?, count: members.len(), chunk })
+    }
+
+    // The member `lba` is on, and where on it
+    fn locate(&self, lba: u64) -> (&D, u64) {
+        let chunk = lba / self.chunk;
+        let member = (chunk % self.count as u64) as usize;
+        let offset = chunk / self.count as u64 * self.chunk + lba % self.chunk;
+        (&self.members[member], offset)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Stripe<D> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        let (member, lba) = self.locate(lba);
+        member.read_sector(lba, buf)
+    }
+
+    fn write_sector(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        let (member, lba) = self.locate(lba);
+        member.write_sector(lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.members[..self.count].iter().try_for_each(|member| member.flush())
+    }
+
+    fn write_sector_fua(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        let (member, lba) = self.locate(lba);
+        member.write_sector_fua(lba, buf)
+    }
+}
+
+/// RAID-1: every member has every sector, so any one of them will do
+#[derive(Debug, Clone, Copy)]
+pub struct Mirror<D> {
+    members: [D; MAX_MEMBERS],
+    count: usize,
+}
+
+impl<D: BlockDevice> Mirror<D> {
+    pub fn new(members: &[D]) -> Result<Mirror<D>, &'static str> {
+        Ok(Mirror { members: self::members(members)?, count: members.len() })
+    }
+
+    pub fn members(&self) -> &[D] {
+        &self.members[..self.count]
+    }
+
+    /// Make the first `sectors` of every member match member `from`,
+    /// writing only those that differ. Says how many were rewritten on
+    /// each member.
+    pub fn resync(&self, from: usize, sectors: u64) -> Result<Vec<u64>, &'static str> {
+        let source = self.members().get(from).ok_or("no such member")?;
+        let mut rewritten = vec![0; self.count];
+        let (mut good, mut other) = ([0; SECTOR_SIZE], [0; SECTOR_SIZE]);
+        for lba in 0..sectors {
+            source.read_sector(lba, &mut good)?;
+            for (index, member) in self.members().iter().enumerate().filter(|&(index, _)| index != from) {
+                // One that can't be read is rewritten all the same
+                if member.read_sector(lba, &mut other).is_err() || other != good {
+                    member.write_sector(lba, &good)?;
+                    rewritten[index] += 1;
+                }
+            }
+        }
+        self.flush()?;
+        Ok(rewritten)
+    }
+
+    // Do `write` on every member; it's done if any took it, as the set
+    // carries on without the ones that failed until they're resynced
+    fn write_all(&self, write: impl Fn(&D) -> Result<(), &'static str>) -> Result<(), &'static str> {
+        let mut result = Err("no member took the write");
+        for (index, member) in self.members().iter().enumerate() {
+            match write(member) {
+                Ok(()) => result = Ok(()),
+                Err(message) => crate::log::warn!("mirror member {}: {}", index, message),
+            }
+        }
+        result
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Mirror<D> {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        let mut result = Err("no member");
+        for member in self.members() {
+            result = member.read_sector(lba, buf);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    fn write_sector(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        self.write_all(|member| member.write_sector(lba, buf))
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.write_all(|member| member.flush())
+    }
+
+    fn write_sector_fua(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        self.write_all(|member| member.write_sector_fua(lba, buf))
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_stripe() {
+    use crate::testing::RamDisk;
+
+    let (a, b) = (RamDisk::new(4), RamDisk::new(4));
+    let stripe = Stripe::new(&[&a, &b], 2).unwrap();
+    for lba in 0..8 {
+        stripe.write_sector(lba, &[lba as u8; SECTOR_SIZE]).unwrap();
+    }
+    // Chunks of two sectors, in turn
+    assert_eq!(a.firsts(), [0, 1, 4, 5]);
+    assert_eq!(b.firsts(), [2, 3, 6, 7]);
+    let mut buf = [0; SECTOR_SIZE];
+    stripe.read_sector(6, &mut buf).unwrap();
+    assert_eq!(buf[0], 6);
+    assert!(stripe.read_sector(8, &mut buf).is_err());
+    assert!(Stripe::new(&[&a, &b], 3).is_err());
+    assert!(Stripe::<&RamDisk>::new(&[], 2).is_err());
+}
+
+#[test_case]
+fn test_mirror_and_resync() {
+    use crate::testing::RamDisk;
+
+    let (a, b) = (RamDisk::new(4), RamDisk::new(4));
+    let mirror = Mirror::new(&[&a, &b]).unwrap();
+    mirror.write_sector(0, &[1; SECTOR_SIZE]).unwrap();
+    assert_eq!(a.firsts(), [1, 0, 0, 0]);
+    assert_eq!(b.firsts(), [1, 0, 0, 0]);
+
+    // With one member gone, it carries on with the other
+    a.failed.set(true);
+    mirror.write_sector(1, &[2; SECTOR_SIZE]).unwrap();
+    let mut buf = [0; SECTOR_SIZE];
+    mirror.read_sector(1, &mut buf).unwrap();
+    assert_eq!(buf[0], 2);
+    assert_eq!(mirror.resync(0, 4).err(), Some("failed"));
+
+    // Back, but behind: resync it from the one that kept up
+    a.failed.set(false);
+    assert_eq!(a.firsts(), [1, 0, 0, 0]);
+    assert_eq!(mirror.resync(1, 4).unwrap(), [1, 0]);
+    assert_eq!(a.firsts(), [1, 2, 0, 0]);
+    assert_eq!(mirror.resync(1, 4).unwrap(), [0, 0]);
+
+    b.failed.set(true);
+    a.failed.set(true);
+    assert!(mirror.write_sector(0, &[3; SECTOR_SIZE]).is_err());
+}
@@ -1,50 +1,114 @@
+use alloc::vec::Vec;
+use core::ptr::{addr_of, addr_of_mut};
+use spin::Mutex;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 use x86_64::structures::gdt::SegmentSelector;
 use lazy_static::lazy_static;
+use crate::memory::paging::PageAligned;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-// TSS (Interrupt Stack Table, rudimentary stack management)
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+// TSS (Interrupt Stack Table, rudimentary stack management). Mutable because
+// the stack for interrupts out of ring 3 changes whenever userspace is
+// entered; the CPU reads it straight from memory.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+fn init_tss() {
+    const STACK_SIZE: usize = 4096 * 5;
+    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+    let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
+    let stack_end = stack_start + STACK_SIZE;
+    unsafe { (*addr_of_mut!(TSS)).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end };
 }
 
-// GDT (Loads the TSS, and manages switching between user space and kernel space)
+// GDT (Loads the TSS, and manages switching between user space and kernel space).
+// The order is what SYSCALL and SYSRET expect: kernel data right after
+// kernel code, and user code right after user data. On a page of its own,
+// so cpu::protect_tables can make it read-only.
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+    static ref GDT: (PageAligned<GlobalDescriptorTable>, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
+        let selectors =
+            Selectors { code_selector, data_selector, user_data_selector, user_code_selector, tss_selector };
+        (PageAligned(gdt), selectors)
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
+// The application processors' GDTs, as init_ap leaks them. Never touched
+// by interrupt handlers.
+static AP_GDTS: Mutex<Vec<&'static PageAligned<GlobalDescriptorTable>>> = Mutex::new(Vec::new());
+
+/// The segment selectors the GDT defines
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
+/// Where the TSS keeps the stack the CPU switches to on an interrupt out of
+/// ring 3. Written from assembly when entering userspace.
+pub fn kernel_stack_slot() -> *mut VirtAddr {
+    unsafe { addr_of_mut!((*addr_of_mut!(TSS)).privilege_stack_table) as *mut VirtAddr }
+}
+
 pub fn init() {
-    use x86_64::instructions::segmentation::Segment;
-    use x86_64::instructions::segmentation::CS;
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
+    init_tss();
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Give an application processor a GDT and TSS of its own, with the same
+/// selectors as the boot CPU's: a TSS can only be loaded on one CPU. Double
+/// faults there run on the stack ending at `double_fault_stack`.
+pub fn init_ap(double_fault_stack: VirtAddr) {
+    use alloc::boxed::Box;
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
+    use x86_64::instructions::tables::load_tss;
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    gdt.add_entry(Descriptor::user_data_segment());
+    gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    let gdt: &'static PageAligned<GlobalDescriptorTable> = Box::leak(Box::new(PageAligned(gdt)));
+    gdt.load();
+    unsafe {
+        CS::set_reg(code_selector);
+        SS::set_reg(data_selector);
+        load_tss(tss_selector);
+    }
+    AP_GDTS.lock().push(gdt);
+}
+
+/// Every CPU's GDT, the boot CPU's first
+pub fn gdts() -> Vec<&'static PageAligned<GlobalDescriptorTable>> {
+    let mut gdts = alloc::vec![&GDT.0];
+    gdts.extend(AP_GDTS.lock().iter().copied());
+    gdts
+}
@@ -0,0 +1,153 @@
+//! A command channel for the host, as QEMU's guest agent has, on COM2: the
+//! host sends a command on a line, and gets back one line, `ok` or `err`
+//! and what there is to say. With `hostchan` on the command line, a
+//! kernel thread polls the port (there's no serial IRQ); the host end is
+//! QEMU's second serial port, e.g.
+//! `-serial stdio -serial unix:/tmp/heorot.sock,server,nowait`, and
+//! tools/hostctl.py talks to it.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+use crate::time::{self, Duration};
+use crate::{interrupts, memory, qemu, sched, selftest};
+
+const COM2: u16 = 0x2f8;
+const LINE_STATUS_DATA_READY: u8 = 1;
+const LINE_STATUS_EMPTY: u8 = 1 << 6;
+// Where the UART has a byte of scratch space, to tell it's there
+const SCRATCH: u16 = 7;
+const MAX_LINE: usize = 128;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What's left to do once the reply's gone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    None,
+    Shutdown,
+    Reboot,
+}
+
+fn stats() -> String {
+    let free = memory::with_frame_allocator(|frames| frames.free_frames()).unwrap_or(0);
+    let mut threads = 0;
+    sched::for_each_thread(|_, _, _, _| threads += 1);
+    format!("uptime_ms={} timer_irqs={} free_kib={} threads={}", time::uptime().as_millis(),
+        interrupts::irq_count(0), free as u64 * memory::FRAME_SIZE / 1024, threads)
+}
+
+// The answer to `line`, and anything to do after it's sent
+fn handle(line: &str) -> (String, Action) {
+    let words: alloc::vec::Vec<&str> = line.split_whitespace().collect();
+    let reply = match words.as_slice() {
+        ["ping"] => String::from("ok pong"),
+        ["stats"] => format!("ok {}", stats()),
+        ["tests"] => {
+            let mut reply = String::from("ok");
+            for name in selftest::names() {
+                let _ = write!(reply, " {}", name.replace(' ', "-"));
+            }
+            reply
+        }
+        ["test", name] => match selftest::check(&name.replace('-', " ")) {
+            Some(selftest::Outcome::Pass) => String::from("ok pass"),
+            Some(selftest::Outcome::Skip(why)) => format!("ok skip {}", why),
+            Some(selftest::Outcome::Fail(why)) => format!("err fail {}", why),
+            None => format!("err no test {}", name),
+        },
+        ["shutdown"] => return (String::from("ok"), Action::Shutdown),
+        ["reboot"] => return (String::from("ok"), Action::Reboot),
+        ["help"] => String::from("ok ping stats tests test shutdown reboot"),
+        [] => String::from("err empty command"),
+        [command, ..] => format!("err unknown command {}", command),
+    };
+    (reply, Action::None)
+}
+
+// Whether there's a UART at `base`
+fn present(base: u16) -> bool {
+    let mut scratch: Port<u8> = Port::new(base + SCRATCH);
+    unsafe {
+        scratch.write(0x5a);
+        scratch.read() == 0x5a
+    }
+}
+
+// Wait a while for what's been sent to be out of the FIFO
+fn drain(line_status: &mut Port<u8>) {
+    for _ in 0..100_000 {
+        if unsafe { line_status.read() } & LINE_STATUS_EMPTY != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn run() {
+    let mut port = unsafe { SerialPort::new(COM2) };
+    port.init();
+    let mut line_status: Port<u8> = Port::new(COM2 + 5);
+    let mut line = [0u8; MAX_LINE];
+    let mut len = 0;
+    // Set when a line ran past MAX_LINE; the rest of it is dropped
+    let mut overlong = false;
+    loop {
+        while unsafe { line_status.read() } & LINE_STATUS_DATA_READY != 0 {
+            match port.receive() {
+                b'\r' => {}
+                b'\n' => {
+                    let (reply, action) = match core::str::from_utf8(&line[..len]) {
+                        _ if overlong => (String::from("err line too long"), Action::None),
+                        Ok(command) => handle(command),
+                        Err(_) => (String::from("err not UTF-8"), Action::None),
+                    };
+                    let _ = writeln!(port, "{}", reply);
+                    match action {
+                        Action::None => {}
+                        Action::Shutdown => {
+                            let _ = crate::fs::sync();
+                            drain(&mut line_status);
+                            qemu::exit(qemu::ExitCode::Success);
+                        }
+                        Action::Reboot => {
+                            drain(&mut line_status);
+                            crate::power::reboot();
+                        }
+                    }
+                    len = 0;
+                    overlong = false;
+                }
+                _ if len == MAX_LINE => overlong = true,
+                byte => {
+                    line[len] = byte;
+                    len += 1;
+                }
+            }
+        }
+        time::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Start answering on COM2, if it's there
+pub fn start() -> Result<(), &'static str> {
+    if !present(COM2) {
+        return Err("no COM2");
+    }
+    sched::spawn("hostchan", run).map(|_| ())
+}
+
+/// TESTS
+
+#[test_case]
+fn test_commands() {
+    assert_eq!(handle("ping"), (String::from("ok pong"), Action::None));
+    assert_eq!(handle("  shutdown "), (String::from("ok"), Action::Shutdown));
+    assert_eq!(handle("frobnicate now").0, "err unknown command frobnicate");
+    assert_eq!(handle("").0, "err empty command");
+    assert!(handle("stats").0.starts_with("ok uptime_ms="));
+    assert_eq!(handle("test heap").0, "ok pass");
+    assert_eq!(handle("test nothing").0, "err no test nothing");
+    assert!(handle("tests").0.contains(" disk-read"));
+}
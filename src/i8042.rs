@@ -0,0 +1,78 @@
+//! Polled access to the PS/2 controller, shared by the keyboard and mouse
+//! drivers
+
+use spin::Once;
+use crate::device;
+use crate::pio::{Port, Ports, ReadOnly, ReadWrite, WriteOnly};
+
+const DATA_PORT: u16 = 0x60;
+// Read for the status, written for commands
+const STATUS_PORT: u16 = 0x64;
+
+// Status register bits
+const OUTPUT_FULL: u8 = 0x01;
+const INPUT_FULL: u8 = 0x02;
+
+// Device replies to a command byte
+pub const ACK: u8 = 0xfa;
+pub const RESEND: u8 = 0xfe;
+
+// Don't hang if there's no controller or device answering
+const TIMEOUT: usize = 100_000;
+
+struct Controller {
+    data: Port<ReadWrite, u8>,
+    status: Port<ReadOnly, u8>,
+    command: Port<WriteOnly, u8>,
+}
+
+static CONTROLLER: Once<Controller> = Once::new();
+
+// The ports, once it's sure device::init claimed them for the controller
+fn controller() -> Result<&'static Controller, &'static str> {
+    if let Some(controller) = CONTROLLER.r#try() {
+        return Ok(controller);
+    }
+    let data = Ports::owned(device::i8042(), DATA_PORT, DATA_PORT)?;
+    let status = Ports::owned(device::i8042(), STATUS_PORT, STATUS_PORT)?;
+    let controller = Controller { data: data.port(0)?, status: status.port(0)?, command: status.port(0)? };
+    Ok(CONTROLLER.call_once(|| controller))
+}
+
+fn wait_for_write(controller: &Controller) -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT {
+        if controller.status.read() & INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err("PS/2 controller not accepting input")
+}
+
+/// Wait for a byte from the controller. Only with interrupts off, or the
+/// IRQ handlers would get to it first.
+pub fn read_data() -> Result<u8, &'static str> {
+    let controller = controller()?;
+    for _ in 0..TIMEOUT {
+        if controller.status.read() & OUTPUT_FULL != 0 {
+            return Ok(controller.data.read());
+        }
+    }
+    Err("no response from PS/2 controller")
+}
+
+/// A command for the controller itself
+pub fn controller_command(command: u8) -> Result<(), &'static str> {
+    let controller = controller()?;
+    wait_for_write(controller)?;
+    controller.command.write(command);
+    Ok(())
+}
+
+/// A byte for the first port's device (the keyboard), or for the controller
+/// after a command that takes an argument
+pub fn write_data(byte: u8) -> Result<(), &'static str> {
+    let controller = controller()?;
+    wait_for_write(controller)?;
+    controller.data.write(byte);
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! The kernel's own image, as the program headers it was loaded from
+//! describe it. lld maps the ELF header with the first segment, so they
+//! can be read from memory.
+
+use core::mem::size_of;
+use core::ptr;
+
+const PT_LOAD: u32 = 1;
+// Segment flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+// The parts of the ELF64 file header we need
+const E_PHOFF: usize = 0x20;
+const E_PHENTSIZE: usize = 0x36;
+const E_PHNUM: usize = 0x38;
+
+#[allow(dead_code)] // Mirrors the ELF layout; not every field is used
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+/// A loaded segment, where it is now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub flags: u32,
+    pub vaddr: u64,
+    /// What came from the file; the rest, up to memory_size, is zeroed
+    pub file_size: u64,
+    pub memory_size: u64,
+}
+
+impl Segment {
+    /// What it holds, as it sits in memory
+    pub fn contents(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.vaddr as *const u8, self.file_size as usize) }
+    }
+}
+
+unsafe fn read_header<T: Copy>(base: *const u8, offset: usize) -> T {
+    ptr::read_unaligned(base.add(offset) as *const T)
+}
+
+/// The loaded segments, in program header order
+pub fn segments() -> impl Iterator<Item = Segment> {
+    let ehdr = unsafe { ptr::addr_of!(__ehdr_start) };
+    let (phoff, phentsize, phnum): (u64, u16, u16) =
+        unsafe { (read_header(ehdr, E_PHOFF), read_header(ehdr, E_PHENTSIZE), read_header(ehdr, E_PHNUM)) };
+    assert!(usize::from(phentsize) >= size_of::<ProgramHeader>());
+    (0..usize::from(phnum)).filter_map(move |index| {
+        let header: ProgramHeader = unsafe { read_header(ehdr, phoff as usize + index * usize::from(phentsize)) };
+        (header.kind == PT_LOAD).then(|| Segment {
+            flags: header.flags,
+            vaddr: header.vaddr,
+            file_size: header.file_size,
+            memory_size: header.memory_size,
+        })
+    })
+}
+
+/// From the start of the first segment to the end of the last
+pub fn extent() -> (u64, u64) {
+    segments().fold((u64::MAX, 0), |(start, end), segment| {
+        (start.min(segment.vaddr), end.max(segment.vaddr + segment.memory_size))
+    })
+}
+
+/// Bytes of each kind in the image, going by the segments: code is what's
+/// executable, read-only data (which has the ELF headers, too) what's
+/// neither executable nor writable, and the writable segments' zeroed tails
+/// are the .bss
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Sizes {
+    pub text: u64,
+    pub rodata: u64,
+    pub data: u64,
+    pub bss: u64,
+}
+
+impl Sizes {
+    fn add(mut self, segment: Segment) -> Sizes {
+        if segment.flags & PF_X != 0 {
+            self.text += segment.memory_size;
+        } else if segment.flags & PF_W != 0 {
+            self.data += segment.file_size;
+            self.bss += segment.memory_size - segment.file_size;
+        } else {
+            self.rodata += segment.memory_size;
+        }
+        self
+    }
+
+    pub fn total(&self) -> u64 {
+        self.text + self.rodata + self.data + self.bss
+    }
+}
+
+/// The kernel's sizes, as loaded
+pub fn sizes() -> Sizes {
+    segments().fold(Sizes::default(), Sizes::add)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_sizes() {
+    let read_only = Segment { flags: 4, vaddr: 0x20_0000, file_size: 0x100, memory_size: 0x100 };
+    let code = Segment { flags: 4 | PF_X, vaddr: 0x20_1000, file_size: 0x800, memory_size: 0x800 };
+    let data = Segment { flags: 4 | PF_W, vaddr: 0x20_2000, file_size: 0x40, memory_size: 0x1040 };
+    let sizes = [read_only, code, data].iter().fold(Sizes::default(), |sizes, &segment| sizes.add(segment));
+    assert_eq!(sizes, Sizes { text: 0x800, rodata: 0x100, data: 0x40, bss: 0x1000 });
+
+    // The kernel itself has some of everything but perhaps .data
+    let sizes = self::sizes();
+    assert!(sizes.text > 0 && sizes.rodata > 0 && sizes.bss > 0);
+    let (start, end) = extent();
+    assert!(end - start >= sizes.total());
+}
@@ -3,20 +3,32 @@ use x86_64::structures::idt::PageFaultErrorCode;
 use pic8259::ChainedPics;
 use crate::hlt_loop;
 use crate::println;
-use crate::print;
+use crate::device::{DeviceId, PowerOps};
 use crate::gdt;
+use crate::kptr;
+use crate::memory::paging::PageAligned;
 use lazy_static::lazy_static;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use spin;
+use x86_64::VirtAddr;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// PIC line the PS/2 mouse interrupts on
+pub const MOUSE_IRQ: u8 = 12;
+// The secondary PIC is chained into this line of the primary one
+const CASCADE_IRQ: u8 = 2;
+
 // Because the timer users line 0 of the primary PIC, we store its interrupt in an enum
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial = PIC_1_OFFSET + crate::serial::IRQ,
+    Mouse = PIC_1_OFFSET + MOUSE_IRQ,
 }
 
 impl InterruptIndex {
@@ -29,12 +41,43 @@ impl InterruptIndex {
     }
 }
 
+// Per-line counts for the 16 legacy PIC IRQs
+const IRQ_COUNT_INIT: AtomicU64 = AtomicU64::new(0);
+static IRQ_COUNTS: [AtomicU64; 16] = [IRQ_COUNT_INIT; 16];
+
+fn count_irq(index: InterruptIndex) {
+    let irq = index.as_usize() - usize::from(PIC_1_OFFSET);
+    IRQ_COUNTS[irq].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How many times PIC line `irq` has fired since boot
+pub fn irq_count(irq: u8) -> u64 {
+    IRQ_COUNTS
+        .get(usize::from(irq))
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// What's hooked up to PIC line `irq`, if anything
+pub fn irq_name(irq: u8) -> Option<&'static str> {
+    match irq.checked_add(PIC_1_OFFSET) {
+        Some(vector) if vector == InterruptIndex::Timer.as_u8() => Some("timer"),
+        Some(vector) if vector == InterruptIndex::Keyboard.as_u8() => Some("keyboard"),
+        Some(vector) if vector == InterruptIndex::Serial.as_u8() => Some("serial"),
+        Some(vector) if vector == InterruptIndex::Mouse.as_u8() => Some("mouse"),
+        _ => None,
+    }
+}
+
 // IDT work and fault handlers follow
 
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
+    // A page to itself, so cpu::protect_tables can make it read-only
+    static ref IDT: PageAligned<InterruptDescriptorTable> = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
@@ -42,10 +85,22 @@ lazy_static! {
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()]
+            .set_handler_fn(serial_interrupt_handler);
+        #[cfg(feature = "mouse")]
+        idt[InterruptIndex::Mouse.as_usize()]
+            .set_handler_fn(mouse_interrupt_handler);
+        idt[usize::from(crate::apic::SPURIOUS_VECTOR)]
+            .set_handler_fn(spurious_interrupt_handler);
+        idt[usize::from(crate::ipi::CALL_VECTOR)]
+            .set_handler_fn(call_interrupt_handler);
+        idt[usize::from(crate::ipi::RESCHEDULE_VECTOR)]
+            .set_handler_fn(reschedule_interrupt_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
 
         idt.page_fault.set_handler_fn(page_fault_handler);
 
-        idt
+        PageAligned(idt)
     };
 }
 
@@ -53,80 +108,363 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// The IDT every CPU loads
+pub fn idt() -> &'static PageAligned<InterruptDescriptorTable> {
+    &IDT
+}
+
 pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-// x86 Interrupt Handler Funcs
+// To whichever interrupt controller delivered it
+fn end_of_interrupt(index: InterruptIndex) {
+    if crate::apic::enabled() {
+        crate::apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
+}
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
-{
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
+/// Let legacy IRQ line `irq` through: on the IO-APIC once that's taken
+/// over, otherwise on the PICs, along with the cascade if it's on the
+/// secondary one. The firmware leaves some lines masked.
+pub fn unmask_irq(irq: u8) {
     use x86_64::instructions::port::Port;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
-                HandleControl::Ignore)
-            );
+    if crate::apic::enabled() {
+        crate::apic::unmask_irq(irq);
+        return;
     }
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+    let mut primary: Port<u8> = Port::new(0x21);
+    let mut secondary: Port<u8> = Port::new(0xa1);
+    let _pics = PICS.lock();
+    unsafe {
+        if irq < 8 {
+            let mask = primary.read();
+            primary.write(mask & !(1 << irq));
+        } else {
+            let mask = secondary.read();
+            secondary.write(mask & !(1 << (irq - 8)));
+            let mask = primary.read();
+            primary.write(mask & !(1 << CASCADE_IRQ));
+        }
+    }
+}
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
+// The masks as suspend found them; the PICs forget them on the way down
+static SAVED_PIC_MASKS: [AtomicU8; 2] = [AtomicU8::new(0xff), AtomicU8::new(0xff)];
+
+fn suspend_pics(_id: DeviceId) -> Result<(), &'static str> {
+    let masks = unsafe { PICS.lock().read_masks() };
+    for (saved, mask) in SAVED_PIC_MASKS.iter().zip(masks.iter()) {
+        saved.store(*mask, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+fn resume_pics(_id: DeviceId) -> Result<(), &'static str> {
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();
+        let [primary, secondary] = &SAVED_PIC_MASKS;
+        // Under the APICs they stay quiet, however they were left
+        if crate::apic::enabled() {
+            pics.write_masks(0xff, 0xff);
+        } else {
+            pics.write_masks(primary.load(Ordering::Relaxed), secondary.load(Ordering::Relaxed));
         }
     }
+    Ok(())
+}
+
+pub(crate) const PIC_POWER: PowerOps = PowerOps {
+    suspend: suspend_pics,
+    resume: resume_pics,
+};
 
+// Fault recovery
+
+/// Exception vectors, as recorded in `Fault`
+pub const DIVIDE_ERROR_VECTOR: u8 = 0;
+pub const INVALID_OPCODE_VECTOR: u8 = 6;
+pub const GENERAL_PROTECTION_VECTOR: u8 = 13;
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+
+/// An exception caught by `catch_fault`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub vector: u8,
+    pub error_code: Option<u64>,
+    /// CR2, for page faults
+    pub address: Option<VirtAddr>,
+    pub instruction_pointer: VirtAddr,
+}
+
+// Where catch_fault resumes if its function faults: [rip, rsp], zero when unarmed
+static RESUME: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static CAUGHT: spin::Mutex<Option<Fault>> = spin::Mutex::new(None);
+
+/// Run `f`, and if it triggers a divide error, invalid opcode, general
+/// protection fault, or page fault, abandon it and return what happened
+/// instead of crashing. Meant for code that faults on purpose, like
+/// probing for hardware or fault-injection tests.
+///
+/// Nothing in `f`'s frames gets dropped if it faults, so keep it small.
+pub fn catch_fault(f: fn()) -> Result<(), Fault> {
+    *CAUGHT.lock() = None;
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        // Save the registers f would have restored on return, note the
+        // landing address and stack pointer, and call it. The handler
+        // resumes at 2: with the stack just as it was before the call.
+        asm!(
+            "push rbx",
+            "push rbp",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "lea rax, [rip + 2f]",
+            "mov [rdi], rax",
+            "mov [rdi + 8], rsp",
+            "call rsi",
+            "2:",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbp",
+            "pop rbx",
+            in("rdi") RESUME.as_ptr(),
+            in("rsi") f,
+            clobber_abi("C"),
+        );
+    }
+    RESUME[0].store(0, Ordering::SeqCst);
+    match CAUGHT.lock().take() {
+        Some(fault) => Err(fault),
+        None => Ok(()),
     }
 }
 
+// Called by fault handlers: if catch_fault is waiting, point the frame at its
+// landing address so the iretq goes there
+fn recover(stack_frame: &mut InterruptStackFrame, vector: u8, error_code: Option<u64>, address: Option<VirtAddr>) -> bool {
+    let resume_at = RESUME[0].swap(0, Ordering::SeqCst);
+    if resume_at == 0 {
+        return false;
+    }
+    *CAUGHT.lock() = Some(Fault {
+        vector,
+        error_code,
+        address,
+        instruction_pointer: stack_frame.instruction_pointer,
+    });
+    let stack_pointer = RESUME[1].load(Ordering::SeqCst);
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(resume_at);
+            frame.stack_pointer = VirtAddr::new(stack_pointer);
+        });
+    }
+    true
+}
+
+// x86 Interrupt Handler Funcs
+
+#[cfg(feature = "keyboard")]
+extern "x86-interrupt" fn keyboard_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    use x86_64::instructions::port::Port;
+
+    count_irq(InterruptIndex::Keyboard);
+
+    // Decoding happens in whoever reads the keyboard, not in here
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    crate::entropy::add_interrupt_timing(scancode);
+    crate::keyboard::push_scancode(scancode);
+
+    end_of_interrupt(InterruptIndex::Keyboard);
+    crate::softirq::irq_exit();
+}
+
+// Without the keyboard feature IRQ1 still fires; drain the controller and ack it
+#[cfg(not(feature = "keyboard"))]
+extern "x86-interrupt" fn keyboard_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    use x86_64::instructions::port::Port;
+
+    count_irq(InterruptIndex::Keyboard);
+
+    let mut port: Port<u8> = Port::new(0x60);
+    unsafe { port.read() };
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+#[cfg(feature = "mouse")]
+extern "x86-interrupt" fn mouse_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    use x86_64::instructions::port::Port;
+
+    count_irq(InterruptIndex::Mouse);
+
+    let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+    crate::entropy::add_interrupt_timing(byte);
+    crate::mouse::push_byte(byte);
+
+    end_of_interrupt(InterruptIndex::Mouse);
+    crate::softirq::irq_exit();
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    count_irq(InterruptIndex::Serial);
+    crate::serial::receive();
+    end_of_interrupt(InterruptIndex::Serial);
+    crate::softirq::irq_exit();
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    // Before anything else, so the measurement is of getting here
+    #[cfg(feature = "irq-latency")]
+    crate::latency::record_timer_entry();
+
+    count_irq(InterruptIndex::Timer);
+    crate::time::tick();
+
+    end_of_interrupt(InterruptIndex::Timer);
+    crate::softirq::irq_exit();
+    crate::user::signal::timer_tick(&mut stack_frame);
+    // Last: this may switch threads, and only come back here much later
+    crate::sched::timer_tick();
+}
+
+// The local APIC raises this when an interrupt went away before it could
+// be delivered; there's nothing to acknowledge
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+}
+
+// Another CPU wants something run here
+extern "x86-interrupt" fn call_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    print!(".");
+    crate::ipi::handle_call();
+}
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+// Only here to wake the CPU from a hlt
+extern "x86-interrupt" fn reschedule_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    crate::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn nmi_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    crate::ipi::handle_nmi();
 }
 
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", kptr::frame(&stack_frame));
+}
+
+extern "x86-interrupt" fn divide_error_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    if crate::user::kill(&mut stack_frame, DIVIDE_ERROR_VECTOR, None) {
+        return;
+    }
+    if !recover(&mut stack_frame, DIVIDE_ERROR_VECTOR, None, None) {
+        panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", kptr::frame(&stack_frame));
+    }
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    if crate::user::kill(&mut stack_frame, INVALID_OPCODE_VECTOR, None) {
+        return;
+    }
+    if !recover(&mut stack_frame, INVALID_OPCODE_VECTOR, None, None) {
+        panic!("EXCEPTION: INVALID OPCODE\n{:#?}", kptr::frame(&stack_frame));
+    }
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame, error_code: u64)
+{
+    if crate::user::kill(&mut stack_frame, GENERAL_PROTECTION_VECTOR, Some(error_code)) {
+        return;
+    }
+    if !recover(&mut stack_frame, GENERAL_PROTECTION_VECTOR, Some(error_code), None) {
+        panic!("EXCEPTION: GENERAL PROTECTION FAULT (error code {:#x})\n{:#?}",
+            error_code, kptr::frame(&stack_frame));
+    }
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", kptr::frame(&stack_frame));
 }
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
+    if crate::user::kill(&mut stack_frame, PAGE_FAULT_VECTOR, Some(error_code.bits())) {
+        return;
+    }
+    let anon = if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        crate::memory::anon::handle_fault(Cr2::read())
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        crate::memory::anon::handle_write_fault(Cr2::read())
+    } else {
+        None
+    };
+    match anon {
+        Some(Ok(())) => return,
+        Some(Err(message)) => crate::log::error!("anonymous memory: {}", message),
+        None => {}
+    }
+    if recover(&mut stack_frame, PAGE_FAULT_VECTOR, Some(error_code.bits()), Some(Cr2::read())) {
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {}", kptr::Ptr::from(Cr2::read()));
     println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && !error_code.contains(PageFaultErrorCode::USER_MODE)
+        && Cr2::read().as_u64() < crate::uaccess::USER_SPACE_END
+    {
+        println!("Kernel touched user memory outside of a user_access scope (SMEP/SMAP)");
+    }
+    println!("{:#?}", kptr::frame(&stack_frame));
     hlt_loop();
 }
 
 /// TESTS
 
+#[test_case]
+fn test_catch_fault_passes_through() {
+    fn harmless() {}
+    assert_eq!(catch_fault(harmless), Ok(()));
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     // Test an int3 interrupt
@@ -0,0 +1,129 @@
+//! How drivers are waited on. Each kind of device has a trait of `poll_`
+//! methods, as `Future::poll` has it: one that can't go on yet registers
+//! the task's waker, for its interrupt handler to wake, and returns
+//! Pending. The async fns here wrap them, so a task awaits its device and
+//! the executor runs others meanwhile, rather than a loop spinning on a
+//! status bit. Devices that never keep anyone waiting, like disks in
+//! memory, are Ready every time.
+
+use core::future::poll_fn;
+use core::task::{Context, Poll};
+use crate::fs::{self, SECTOR_SIZE};
+
+/// Something bytes are read from or written to in order: the keyboard, a
+/// serial port
+pub trait CharDevice {
+    /// Read whatever's come, a byte at least, into `buf`
+    fn poll_read(&self, context: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, &'static str>>;
+
+    /// Write as much of `buf` as there's room for, a byte at least
+    fn poll_write(&self, _context: &mut Context, _buf: &[u8]) -> Poll<Result<usize, &'static str>> {
+        Poll::Ready(Err(fs::READ_ONLY))
+    }
+}
+
+/// Sectors, as `fs::BlockDevice` has them, but waited for
+pub trait BlockDevice {
+    fn poll_read_sector(&self, context: &mut Context, lba: u64, buf: &mut [u8; SECTOR_SIZE])
+        -> Poll<Result<(), &'static str>>;
+
+    fn poll_write_sector(&self, _context: &mut Context, _lba: u64, _buf: &[u8; SECTOR_SIZE])
+        -> Poll<Result<(), &'static str>> {
+        Poll::Ready(Err(fs::READ_ONLY))
+    }
+}
+
+/// Frames in and out of a network interface
+pub trait NetDevice {
+    fn mac(&self) -> [u8; 6];
+
+    /// The biggest frame it can send, header and all
+    fn mtu(&self) -> usize;
+
+    /// Take the next frame that's come in, into `buf`; how long it was
+    fn poll_receive(&self, context: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, &'static str>>;
+
+    /// Queue `frame` to be sent, once there's room
+    fn poll_send(&self, context: &mut Context, frame: &[u8]) -> Poll<Result<(), &'static str>>;
+}
+
+pub async fn read(device: &impl CharDevice, buf: &mut [u8]) -> Result<usize, &'static str> {
+    poll_fn(|context| device.poll_read(context, buf)).await
+}
+
+/// Write all of `buf`, however many writes that takes
+pub async fn write_all(device: &impl CharDevice, buf: &[u8]) -> Result<(), &'static str> {
+    let mut done = 0;
+    while done < buf.len() {
+        done += poll_fn(|context| device.poll_write(context, &buf[done..])).await?;
+    }
+    Ok(())
+}
+
+pub async fn read_sector(device: &impl BlockDevice, lba: u64, buf: &mut [u8; SECTOR_SIZE])
+    -> Result<(), &'static str> {
+    poll_fn(|context| device.poll_read_sector(context, lba, buf)).await
+}
+
+pub async fn write_sector(device: &impl BlockDevice, lba: u64, buf: &[u8; SECTOR_SIZE])
+    -> Result<(), &'static str> {
+    poll_fn(|context| device.poll_write_sector(context, lba, buf)).await
+}
+
+pub async fn receive(device: &impl NetDevice, buf: &mut [u8]) -> Result<usize, &'static str> {
+    poll_fn(|context| device.poll_receive(context, buf)).await
+}
+
+pub async fn send(device: &impl NetDevice, frame: &[u8]) -> Result<(), &'static str> {
+    poll_fn(|context| device.poll_send(context, frame)).await
+}
+
+/// TESTS
+
+#[test_case]
+fn test_devices() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use crate::drivers::loopback::{Loopback, MAX_QUEUED};
+    use crate::task::simple_executor::SimpleExecutor;
+    use crate::task::Task;
+    use crate::testing::RamDisk;
+
+    static LOOPBACK: Loopback = Loopback::new();
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = SimpleExecutor::new();
+    // Waits for the frame the other task sends
+    executor.spawn(Task::new(async {
+        let mut frame = [0; 64];
+        let len = receive(&LOOPBACK, &mut frame).await.unwrap();
+        assert_eq!(&frame[..len], b"ping");
+        DONE.store(true, Ordering::Relaxed);
+    }));
+    executor.spawn(Task::new(async {
+        let disk = RamDisk::new(4);
+        write_sector(&&disk, 2, &[9; SECTOR_SIZE]).await.unwrap();
+        let mut sector = [0; SECTOR_SIZE];
+        read_sector(&&disk, 2, &mut sector).await.unwrap();
+        assert_eq!(sector[0], 9);
+        send(&LOOPBACK, b"ping").await.unwrap();
+    }));
+    executor.run();
+    assert!(DONE.load(Ordering::Relaxed));
+
+    // A full queue holds sends back until something's received
+    executor.spawn(Task::new(async {
+        for _ in 0..MAX_QUEUED + 1 {
+            send(&LOOPBACK, b"x").await.unwrap();
+        }
+    }));
+    executor.spawn(Task::new(async {
+        crate::task::yield_now().await;
+        assert_eq!(LOOPBACK.queued(), MAX_QUEUED);
+        let mut frame = [0; 8];
+        for _ in 0..MAX_QUEUED + 1 {
+            receive(&LOOPBACK, &mut frame).await.unwrap();
+        }
+    }));
+    executor.run();
+    assert_eq!(LOOPBACK.queued(), 0);
+}
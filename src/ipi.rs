@@ -0,0 +1,199 @@
+//! Interrupts one CPU sends another through the local APICs: to run a
+//! function, to wake and look for something to run, to drop a page from
+//! its TLB, or, for a panic, to stop for good.
+//!
+//! Each CPU has a mailbox for the function it's been asked to run. Only one
+//! call is sent at a time, and the sender waits for it to be run. The halt
+//! goes as an NMI, so a CPU takes it even with interrupts off; so does the
+//! wake-up for a parked one (smp::resume).
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use crate::apic;
+use crate::smp::{self, MAX_CPUS};
+use crate::time::{self, Duration, Instant};
+
+/// Where a CPU is asked to run what's in its mailbox
+pub const CALL_VECTOR: u8 = 0xf0;
+/// Where a CPU is woken to look for something to run
+pub const RESCHEDULE_VECTOR: u8 = 0xf1;
+
+// The interrupt command register's delivery modes
+const FIXED: u32 = 0;
+const NMI: u32 = 0b100 << 8;
+
+// How long a CPU has to take a call, and the others to halt
+const CALL_TIMEOUT: Duration = Duration::from_millis(10);
+const HALT_TIMEOUT: Duration = Duration::from_millis(10);
+
+struct Mailbox {
+    // The fn(u64) to run, as a usize; 0 when there's none
+    call: AtomicUsize,
+    argument: AtomicU64,
+    // Set once it's run
+    done: AtomicBool,
+}
+
+const MAILBOX_INIT: Mailbox = Mailbox {
+    call: AtomicUsize::new(0),
+    argument: AtomicU64::new(0),
+    done: AtomicBool::new(false),
+};
+static MAILBOXES: [Mailbox; MAX_CPUS] = [MAILBOX_INIT; MAX_CPUS];
+// Held while a call is out. Never touched by interrupt handlers.
+static SENDING: Mutex<()> = Mutex::new(());
+// Set by halt_others; other CPUs' NMIs then halt them
+static HALTING: AtomicBool = AtomicBool::new(false);
+static HALTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Have CPU `cpu` run `f(argument)`, in an interrupt handler, and wait for
+/// it to. Not from an interrupt handler, nor with interrupts off when `cpu`
+/// is this one.
+pub fn call(cpu: usize, f: fn(u64), argument: u64) -> Result<(), &'static str> {
+    if !apic::enabled() {
+        return Err("needs the local APIC");
+    }
+    let _sending = SENDING.lock();
+    // Looked up with the lock held, so a CPU that's parking can't miss it
+    let apic_id = smp::apic_id(cpu).ok_or("no such CPU")?;
+    let mailbox = &MAILBOXES[cpu];
+    mailbox.done.store(false, Ordering::Relaxed);
+    mailbox.argument.store(argument, Ordering::Relaxed);
+    mailbox.call.store(f as usize, Ordering::Release);
+    apic::send_ipi(apic_id, FIXED | u32::from(CALL_VECTOR));
+
+    let start = Instant::now();
+    while !mailbox.done.load(Ordering::Acquire) {
+        // Taken back if it hasn't been taken; if it has, it's running
+        if start.elapsed() > CALL_TIMEOUT
+            && mailbox.call.compare_exchange(f as usize, 0, Ordering::AcqRel, Ordering::Acquire).is_ok()
+        {
+            return Err("the CPU didn't take the call");
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Have every other running CPU run `f(argument)`, one after another
+pub fn call_others(f: fn(u64), argument: u64) -> Result<(), &'static str> {
+    let this = smp::cpu_id();
+    for cpu in (0..MAX_CPUS).filter(|&cpu| cpu != this && smp::apic_id(cpu).is_some()) {
+        call(cpu, f, argument)?;
+    }
+    Ok(())
+}
+
+/// Run `f` with no call out to any CPU, and none sent until it's done
+pub(crate) fn without_calls<R>(f: impl FnOnce() -> R) -> R {
+    let _sending = SENDING.lock();
+    f()
+}
+
+/// Send the CPU with APIC ID `apic_id` an NMI, which wakes it even halted
+/// with interrupts off. Past a halt_others, it halts it for good instead.
+pub(crate) fn nmi(apic_id: u32) {
+    apic::send_ipi(apic_id, NMI);
+}
+
+/// Wake CPU `cpu` if it's halted, to look for something to run
+pub fn reschedule(cpu: usize) -> Result<(), &'static str> {
+    if !apic::enabled() {
+        return Err("needs the local APIC");
+    }
+    let apic_id = smp::apic_id(cpu).ok_or("no such CPU")?;
+    apic::send_ipi(apic_id, FIXED | u32::from(RESCHEDULE_VECTOR));
+    Ok(())
+}
+
+fn flush_page(addr: u64) {
+    x86_64::instructions::tlb::flush(VirtAddr::new(addr));
+}
+
+/// Drop the page at `addr` from every CPU's TLB, after changing or removing
+/// its mapping
+pub fn flush_tlb(addr: VirtAddr) -> Result<(), &'static str> {
+    flush_page(addr.as_u64());
+    if smp::online() > 1 {
+        call_others(flush_page, addr.as_u64())?;
+    }
+    Ok(())
+}
+
+/// Stop every other CPU where it is, for good, and wait a little for them
+/// to have: for a panic. Works with interrupts off. Returns whether they
+/// all did.
+pub fn halt_others() -> bool {
+    if smp::online() <= 1 {
+        return true;
+    }
+    if !apic::enabled() || HALTING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    apic::broadcast_ipi(NMI);
+    let others = smp::online() - 1;
+    let mut waited = Duration::from_secs(0);
+    // time::sleep_precise, as Instant needs the timer's interrupts without a TSC
+    while HALTED.load(Ordering::Acquire) < others && waited < HALT_TIMEOUT {
+        time::sleep_precise(Duration::from_micros(100));
+        waited += Duration::from_micros(100);
+    }
+    HALTED.load(Ordering::Acquire) >= others
+}
+
+/// Whether halt_others has been called
+pub fn halting() -> bool {
+    HALTING.load(Ordering::Acquire)
+}
+
+/// From the call vector's handler
+pub(crate) fn handle_call() {
+    let mailbox = &MAILBOXES[smp::cpu_id()];
+    let call = mailbox.call.swap(0, Ordering::AcqRel);
+    if call != 0 {
+        // Only ever a fn(u64), put there by `call`
+        let f: fn(u64) = unsafe { core::mem::transmute(call) };
+        f(mailbox.argument.load(Ordering::Relaxed));
+        mailbox.done.store(true, Ordering::Release);
+    }
+    apic::end_of_interrupt();
+}
+
+/// From the NMI handler: halts this CPU for good if halt_others sent it.
+/// Any other NMI, like `nmi`'s, is left be.
+pub(crate) fn handle_nmi() {
+    if !halting() {
+        return;
+    }
+    HALTED.fetch_add(1, Ordering::Release);
+    // Another NMI can't come until this one returns, which it doesn't
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// TESTS
+
+#[cfg(test)]
+static CALLED: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn test_call_runs_on_the_cpu() {
+    fn record(argument: u64) {
+        CALLED.store(argument + smp::cpu_id() as u64, Ordering::SeqCst);
+    }
+
+    if !apic::enabled() || smp::this_cpu().is_none() {
+        assert!(call(0, record, 1).is_err());
+        return;
+    }
+    // To itself, which takes it as soon as it's sent
+    call(smp::cpu_id(), record, 41).unwrap();
+    assert_eq!(CALLED.load(Ordering::SeqCst), 41);
+    assert_eq!(call(MAX_CPUS, record, 0), Err("no such CPU"));
+    // Whatever other CPUs there are take theirs too
+    call_others(record, 0).unwrap();
+    flush_tlb(VirtAddr::new(0x_4444_0000_0000)).unwrap();
+}
@@ -0,0 +1,382 @@
+//! KASAN-lite: catches reads and writes past the end of globals and stack
+//! arrays, in a kernel built with the compiler's kernel address sanitizer
+//! (see README.md for the flags). The compiler pads globals and stack
+//! arrays with redzones and marks them in shadow memory, one byte for each
+//! eight, as compiler-rt's ASan does; every load and store calls a hook
+//! here that checks its bytes' shadow first.
+//!
+//! Only some of memory has shadow: the kernel image, the boot stack, the
+//! heap (where threads' stacks are) and the other CPUs' stacks, from a
+//! fixed pool. Everything else goes unchecked. The flags make the compiler
+//! go through the hooks for shadow writes too, so the shadow can be kept
+//! wherever suits.
+
+use core::ptr;
+use x86_64::VirtAddr;
+use crate::allocator::{HEAP_SIZE, HEAP_START};
+use crate::memory::{paging, FRAME_SIZE};
+
+/// What the compiler adds to an address shifted right by 3 to get its
+/// shadow (-asan-mapping-offset); the hooks undo it, so any would do
+pub const SHADOW_OFFSET: u64 = 0xdfff_fc00_0000_0000;
+// Bytes of memory per shadow byte
+const GRANULE: u64 = 8;
+// Enough for 8 MiB of memory
+const POOL_SIZE: usize = 1024 * 1024;
+const MAX_RANGES: usize = 4;
+// How far from where it is at init the boot stack is looked for
+const MAX_STACK_PAGES: u64 = 256;
+
+// Shadow values, as ASan has them: 1 to 7 say how many of the granule's
+// bytes are good, and these that none are
+const STACK_LEFT: u8 = 0xf1;
+const STACK_MID: u8 = 0xf2;
+const STACK_RIGHT: u8 = 0xf3;
+const STACK_AFTER_RETURN: u8 = 0xf5;
+const STACK_USE_AFTER_SCOPE: u8 = 0xf8;
+const GLOBAL_REDZONE: u8 = 0xf9;
+const ALLOCA_LEFT: u8 = 0xca;
+const ALLOCA_RIGHT: u8 = 0xcb;
+
+// Memory with shadow, and where its shadow is in the pool
+#[derive(Clone, Copy)]
+struct Range {
+    start: u64,
+    end: u64,
+    shadow: usize,
+}
+
+// The hooks read these with plain loads and call nothing else that's
+// instrumented, or they'd call themselves. Written only by init, before
+// ENABLED is set, and by report, which clears it.
+static mut POOL: [u8; POOL_SIZE] = [0; POOL_SIZE];
+static mut RANGES: [Range; MAX_RANGES] = [Range { start: 0, end: 0, shadow: 0 }; MAX_RANGES];
+static mut ENABLED: bool = false;
+// Set while a hook runs, so anything instrumented it calls isn't checked.
+// Shared between CPUs: another CPU's accesses meanwhile go unchecked too.
+static mut IN_HOOK: bool = false;
+
+/// A global's description, as the compiler emits them for
+/// __asan_register_globals
+#[repr(C)]
+pub struct Global {
+    beg: u64,
+    size: u64,
+    size_with_redzone: u64,
+    name: *const u8,
+    module_name: *const u8,
+    has_dynamic_init: u64,
+    location: *const u8,
+    odr_indicator: u64,
+}
+
+// The shadow byte for `address`, if it has one
+#[no_sanitize(address)]
+unsafe fn shadow(address: u64) -> Option<*mut u8> {
+    let mut index = 0;
+    while index < MAX_RANGES {
+        let range = RANGES[index];
+        if address >= range.start && address < range.end {
+            let offset = range.shadow + ((address - range.start) / GRANULE) as usize;
+            return Some(ptr::addr_of_mut!(POOL).cast::<u8>().add(offset));
+        }
+        index += 1;
+    }
+    None
+}
+
+// `value` into the shadow of `len` bytes from `address`, a granule boundary
+#[no_sanitize(address)]
+unsafe fn fill(address: u64, len: u64, value: u8) {
+    let mut granule = address;
+    while granule < address + len {
+        if let Some(shadow) = shadow(granule) {
+            *shadow = value;
+        }
+        granule += GRANULE;
+    }
+}
+
+// The first bad byte of the `size` from `address`, and its shadow
+#[no_sanitize(address)]
+unsafe fn first_bad(address: u64, size: u64) -> Option<(u64, u8)> {
+    let mut byte = address;
+    while byte < address.saturating_add(size) {
+        let value = match shadow(byte) {
+            Some(shadow) => *shadow,
+            None => 0,
+        };
+        if value != 0 && (value >= 0x80 || byte % GRANULE >= u64::from(value)) {
+            return Some((byte, value));
+        }
+        // A clear granule is good all through
+        byte = if value == 0 { (byte | (GRANULE - 1)) + 1 } else { byte + 1 };
+    }
+    None
+}
+
+fn describe(value: u8) -> &'static str {
+    match value {
+        STACK_LEFT | STACK_MID | STACK_RIGHT => "stack-out-of-bounds",
+        STACK_AFTER_RETURN => "stack-use-after-return",
+        STACK_USE_AFTER_SCOPE => "stack-use-after-scope",
+        GLOBAL_REDZONE => "global-out-of-bounds",
+        ALLOCA_LEFT | ALLOCA_RIGHT => "alloca-out-of-bounds",
+        _ => "out-of-bounds",
+    }
+}
+
+// Stop checking, so the panic's own accesses go through, and panic
+#[no_sanitize(address)]
+#[inline(never)]
+unsafe fn report(address: u64, size: u64, write: bool, bad: u64, value: u8) -> ! {
+    ENABLED = false;
+    panic!("kasan: {} {} of {} bytes at {:#x} ({:#x} is poisoned)",
+        describe(value), if write { "write" } else { "read" }, size, address, bad);
+}
+
+#[no_sanitize(address)]
+unsafe fn check(address: u64, size: u64, write: bool) {
+    if !ENABLED || IN_HOOK {
+        return;
+    }
+    IN_HOOK = true;
+    let bad = first_bad(address, size);
+    IN_HOOK = false;
+    if let Some((bad, value)) = bad {
+        report(address, size, write, bad, value);
+    }
+}
+
+/// Mark `len` bytes from `address` good again, as the heap does with what's
+/// freed: it may have been a thread's stack, with its redzones still marked
+#[no_sanitize(address)]
+pub fn unpoison(address: u64, len: u64) {
+    unsafe { fill(address & !(GRANULE - 1), len + address % GRANULE, 0) };
+}
+
+// The shadow the compiler computed, back to the memory it's for
+fn unshadow(shadow: u64) -> u64 {
+    shadow.wrapping_sub(SHADOW_OFFSET) << 3
+}
+
+macro_rules! access_hooks {
+    ($($load:ident, $store:ident, $report_load:ident, $report_store:ident: $size:expr;)*) => {$(
+        #[no_mangle]
+        #[no_sanitize(address)]
+        pub unsafe extern "C" fn $load(address: u64) {
+            check(address, $size, false);
+        }
+
+        #[no_mangle]
+        #[no_sanitize(address)]
+        pub unsafe extern "C" fn $store(address: u64) {
+            check(address, $size, true);
+        }
+
+        #[no_mangle]
+        #[no_sanitize(address)]
+        pub unsafe extern "C" fn $report_load(address: u64) {
+            check(address, $size, false);
+        }
+
+        #[no_mangle]
+        #[no_sanitize(address)]
+        pub unsafe extern "C" fn $report_store(address: u64) {
+            check(address, $size, true);
+        }
+    )*};
+}
+
+// With and without _noabort, which -Zsanitizer-recover gives; the
+// report_ ones are what inline checks call, should they be on
+access_hooks! {
+    __asan_load1, __asan_store1, __asan_report_load1, __asan_report_store1: 1;
+    __asan_load2, __asan_store2, __asan_report_load2, __asan_report_store2: 2;
+    __asan_load4, __asan_store4, __asan_report_load4, __asan_report_store4: 4;
+    __asan_load8, __asan_store8, __asan_report_load8, __asan_report_store8: 8;
+    __asan_load16, __asan_store16, __asan_report_load16, __asan_report_store16: 16;
+    __asan_load1_noabort, __asan_store1_noabort, __asan_report_load1_noabort, __asan_report_store1_noabort: 1;
+    __asan_load2_noabort, __asan_store2_noabort, __asan_report_load2_noabort, __asan_report_store2_noabort: 2;
+    __asan_load4_noabort, __asan_store4_noabort, __asan_report_load4_noabort, __asan_report_store4_noabort: 4;
+    __asan_load8_noabort, __asan_store8_noabort, __asan_report_load8_noabort, __asan_report_store8_noabort: 8;
+    __asan_load16_noabort, __asan_store16_noabort, __asan_report_load16_noabort, __asan_report_store16_noabort: 16;
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_loadN(address: u64, size: u64) {
+    check(address, size, false);
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_storeN(address: u64, size: u64) {
+    check(address, size, true);
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_loadN_noabort(address: u64, size: u64) {
+    check(address, size, false);
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_storeN_noabort(address: u64, size: u64) {
+    check(address, size, true);
+}
+
+macro_rules! shadow_hooks {
+    ($($name:ident: $value:expr;)*) => {$(
+        /// Set `size` shadow bytes from `shadow`, for a stack frame's redzones
+        #[no_mangle]
+        #[no_sanitize(address)]
+        pub unsafe extern "C" fn $name(shadow: u64, size: u64) {
+            fill(unshadow(shadow), size * GRANULE, $value);
+        }
+    )*};
+}
+
+shadow_hooks! {
+    __asan_set_shadow_00: 0;
+    __asan_set_shadow_f1: STACK_LEFT;
+    __asan_set_shadow_f2: STACK_MID;
+    __asan_set_shadow_f3: STACK_RIGHT;
+    __asan_set_shadow_f5: STACK_AFTER_RETURN;
+    __asan_set_shadow_f8: STACK_USE_AFTER_SCOPE;
+}
+
+// Poison `size_with_redzone` bytes from `beg` past the first `size`
+#[no_sanitize(address)]
+unsafe fn poison_redzone(beg: u64, size: u64, size_with_redzone: u64, value: u8) {
+    let aligned = (size + GRANULE - 1) / GRANULE * GRANULE;
+    if size % GRANULE != 0 {
+        if let Some(shadow) = shadow(beg + size / GRANULE * GRANULE) {
+            *shadow = (size % GRANULE) as u8;
+        }
+    }
+    fill(beg + aligned, size_with_redzone.saturating_sub(aligned), value);
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_register_globals(globals: *const Global, count: u64) {
+    for index in 0..count as usize {
+        let global = &*globals.add(index);
+        poison_redzone(global.beg, global.size, global.size_with_redzone, GLOBAL_REDZONE);
+    }
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_unregister_globals(globals: *const Global, count: u64) {
+    for index in 0..count as usize {
+        let global = &*globals.add(index);
+        fill(global.beg, global.size_with_redzone, 0);
+    }
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_alloca_poison(address: u64, size: u64) {
+    // A 32-byte redzone each side, as the compiler lays them out
+    fill(address - 32, 32, ALLOCA_LEFT);
+    poison_redzone(address, size, (size + 31) / 32 * 32 + 32, ALLOCA_RIGHT);
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_allocas_unpoison(top: u64, bottom: u64) {
+    if bottom > top {
+        fill(top, bottom - top, 0);
+    }
+}
+
+/// Before a call that doesn't return; its frames' redzones are left, and
+/// cleared as the stack's reused
+#[no_mangle]
+pub extern "C" fn __asan_handle_no_return() {}
+
+extern "C" {
+    // The compiler's constructors, which register the globals
+    static __init_array_start: extern "C" fn();
+    static __init_array_end: extern "C" fn();
+}
+
+// The mapped pages either side of `rsp`, up to the guard page below
+fn boot_stack(rsp: u64) -> (u64, u64) {
+    let mapped = |address: u64| paging::translate_addr(VirtAddr::new(address)).is_some();
+    let page = rsp & !(FRAME_SIZE - 1);
+    let (mut bottom, mut top) = (page, page + FRAME_SIZE);
+    while page - bottom < MAX_STACK_PAGES * FRAME_SIZE && mapped(bottom - FRAME_SIZE) {
+        bottom -= FRAME_SIZE;
+    }
+    while top - page < MAX_STACK_PAGES * FRAME_SIZE && mapped(top) {
+        top += FRAME_SIZE;
+    }
+    (bottom, top)
+}
+
+/// Give the kernel's memory shadow, register the globals and start
+/// checking. Needs paging, to find the boot stack, and comes before
+/// anything else touches the heap or starts the other CPUs.
+pub fn init() -> Result<(), &'static str> {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let kernel = crate::image::extent();
+    let ranges = [
+        kernel,
+        boot_stack(rsp),
+        (HEAP_START as u64, (HEAP_START + HEAP_SIZE) as u64),
+        crate::smp::STACKS,
+    ];
+    let mut used = 0;
+    for (index, &(start, end)) in ranges.iter().enumerate() {
+        let start = start & !(GRANULE - 1);
+        let len = ((end - start + GRANULE - 1) / GRANULE) as usize;
+        if used + len > POOL_SIZE {
+            return Err("not enough shadow for the kernel");
+        }
+        unsafe { RANGES[index] = Range { start, end, shadow: used } };
+        used += len;
+    }
+    unsafe {
+        let mut constructor = ptr::addr_of!(__init_array_start);
+        while constructor < ptr::addr_of!(__init_array_end) {
+            (*constructor)();
+            constructor = constructor.add(1);
+        }
+        ENABLED = true;
+    }
+    crate::log::info!("kasan: {} KiB of shadow, for {} KiB of kernel", used / 1024, (kernel.1 - kernel.0) / 1024);
+    Ok(())
+}
+
+/// TESTS
+
+#[test_case]
+fn test_global_redzone() {
+    // As the compiler would pad a 20-byte global
+    static mut PADDED: [u8; 64] = [0; 64];
+    let beg = unsafe { ptr::addr_of!(PADDED) as u64 };
+    let global = Global {
+        beg,
+        size: 20,
+        size_with_redzone: 64,
+        name: ptr::null(),
+        module_name: ptr::null(),
+        has_dynamic_init: 0,
+        location: ptr::null(),
+        odr_indicator: 0,
+    };
+    unsafe {
+        __asan_register_globals(&global, 1);
+        assert_eq!(first_bad(beg, 20), None);
+        assert_eq!(first_bad(beg + 16, 8), Some((beg + 20, 4)));
+        assert_eq!(first_bad(beg + 40, 1), Some((beg + 40, GLOBAL_REDZONE)));
+        __asan_unregister_globals(&global, 1);
+        assert_eq!(first_bad(beg, 64), None);
+    }
+    assert_eq!(describe(STACK_MID), "stack-out-of-bounds");
+}
@@ -0,0 +1,91 @@
+use pc_keyboard::KeyCode;
+use spin::Mutex;
+use super::Modifiers;
+
+const MAX_CHORDS: usize = 16;
+
+/// A global shortcut: `run` is called when `key` goes down with exactly
+/// `modifiers` held, and the key isn't passed on to whoever is reading
+#[derive(Clone, Copy)]
+pub struct Chord {
+    pub modifiers: Modifiers,
+    pub key: KeyCode,
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+// Only the keyboard's readers and registration touch it, never an IRQ handler
+static CHORDS: Mutex<[Option<Chord>; MAX_CHORDS]> = Mutex::new([None; MAX_CHORDS]);
+
+/// Add a shortcut. It needs at least one modifier other than Shift, so
+/// typing can't set it off, and can't clash with one already registered.
+pub fn register(chord: Chord) -> Result<(), &'static str> {
+    let needed = [Modifiers::CTRL, Modifiers::ALT, Modifiers::ALT_GR, Modifiers::META];
+    if !needed.iter().any(|&modifier| chord.modifiers.contains(modifier)) {
+        return Err("a chord needs Ctrl, Alt, AltGr, or Meta");
+    }
+    let mut chords = CHORDS.lock();
+    let taken = chords
+        .iter()
+        .flatten()
+        .any(|other| other.modifiers == chord.modifiers && other.key == chord.key);
+    if taken {
+        return Err("that chord is already taken");
+    }
+    let slot = chords.iter_mut().find(|slot| slot.is_none()).ok_or("too many chords")?;
+    *slot = Some(chord);
+    Ok(())
+}
+
+/// Remove the shortcut registered under `name`
+pub fn unregister(name: &str) -> Result<(), &'static str> {
+    let mut chords = CHORDS.lock();
+    let slot = chords
+        .iter_mut()
+        .find(|slot| slot.map_or(false, |chord| chord.name == name))
+        .ok_or("no such chord")?;
+    *slot = None;
+    Ok(())
+}
+
+/// What to run for `key` with `modifiers` held, if it's a chord
+pub(super) fn lookup(modifiers: Modifiers, key: KeyCode) -> Option<fn()> {
+    CHORDS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|chord| chord.modifiers == modifiers && chord.key == key)
+        .map(|chord| chord.run)
+}
+
+/// Call `f` with each registered chord
+pub fn for_each(mut f: impl FnMut(&Chord)) {
+    // Copied out so `f` can register or unregister chords itself
+    let chords = *CHORDS.lock();
+    for chord in chords.iter().flatten() {
+        f(chord);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_chord_lookup() {
+    fn run() {}
+    let chord = Chord {
+        modifiers: Modifiers::CTRL | Modifiers::META,
+        key: KeyCode::F12,
+        name: "test",
+        run,
+    };
+    register(chord).unwrap();
+    assert!(register(chord).is_err());
+    assert!(register(Chord { modifiers: Modifiers::SHIFT, ..chord }).is_err());
+
+    assert!(lookup(Modifiers::CTRL | Modifiers::META, KeyCode::F12).is_some());
+    // Exactly those modifiers, no more
+    assert!(lookup(Modifiers::CTRL | Modifiers::META | Modifiers::SHIFT, KeyCode::F12).is_none());
+    assert!(lookup(Modifiers::CTRL, KeyCode::F12).is_none());
+    unregister("test").unwrap();
+    assert!(lookup(Modifiers::CTRL | Modifiers::META, KeyCode::F12).is_none());
+}
@@ -0,0 +1,82 @@
+use pc_keyboard::DecodedKey;
+use super::layout;
+
+// (dead key, base, result)
+const COMPOSED: &[(char, char, char)] = &[
+    ('^', 'a', 'â'), ('^', 'e', 'ê'), ('^', 'i', 'î'), ('^', 'o', 'ô'), ('^', 'u', 'û'),
+    ('^', 'A', 'Â'), ('^', 'E', 'Ê'), ('^', 'I', 'Î'), ('^', 'O', 'Ô'), ('^', 'U', 'Û'),
+    ('´', 'a', 'á'), ('´', 'e', 'é'), ('´', 'i', 'í'), ('´', 'o', 'ó'), ('´', 'u', 'ú'), ('´', 'y', 'ý'),
+    ('´', 'A', 'Á'), ('´', 'E', 'É'), ('´', 'I', 'Í'), ('´', 'O', 'Ó'), ('´', 'U', 'Ú'), ('´', 'Y', 'Ý'),
+    ('`', 'a', 'à'), ('`', 'e', 'è'), ('`', 'i', 'ì'), ('`', 'o', 'ò'), ('`', 'u', 'ù'),
+    ('`', 'A', 'À'), ('`', 'E', 'È'), ('`', 'I', 'Ì'), ('`', 'O', 'Ò'), ('`', 'U', 'Ù'),
+    ('¨', 'a', 'ä'), ('¨', 'e', 'ë'), ('¨', 'i', 'ï'), ('¨', 'o', 'ö'), ('¨', 'u', 'ü'), ('¨', 'y', 'ÿ'),
+    ('¨', 'A', 'Ä'), ('¨', 'E', 'Ë'), ('¨', 'I', 'Ï'), ('¨', 'O', 'Ö'), ('¨', 'U', 'Ü'),
+];
+
+fn compose(dead: char, base: char) -> Option<char> {
+    COMPOSED.iter().find(|&&(d, b, _)| d == dead && b == base).map(|&(_, _, result)| result)
+}
+
+/// Dead-key handling on top of the layout: a dead key waits for the next
+/// key and combines with it (^ then e gives ê). Followed by a space it
+/// types itself; followed by anything it can't combine with, both get typed.
+pub struct Composer {
+    dead: Option<char>,
+}
+
+impl Composer {
+    pub const fn new() -> Composer {
+        Composer { dead: None }
+    }
+
+    /// Run a key through composition; `emit` gets whatever comes out (zero,
+    /// one, or two keys)
+    pub fn feed(&mut self, key: DecodedKey, mut emit: impl FnMut(DecodedKey)) {
+        let character = match key {
+            DecodedKey::Unicode(character) => character,
+            // Arrows and the like don't take accents; pass them straight through
+            DecodedKey::RawKey(_) => return emit(key),
+        };
+        match self.dead.take() {
+            Some(dead) => match compose(dead, character) {
+                Some(composed) => emit(DecodedKey::Unicode(composed)),
+                None if character == ' ' => emit(DecodedKey::Unicode(dead)),
+                None => {
+                    emit(DecodedKey::Unicode(dead));
+                    self.feed(key, emit);
+                }
+            },
+            None if layout::layout().dead_keys().contains(&character) => self.dead = Some(character),
+            None => emit(key),
+        }
+    }
+}
+
+impl Default for Composer {
+    fn default() -> Composer {
+        Composer::new()
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_dead_keys() {
+    use layout::Layout;
+
+    let previous = layout::layout();
+    layout::set_layout(Layout::De);
+    let mut composer = Composer::new();
+    let mut typed = ['\0'; 4];
+    let mut count = 0;
+    for character in ['^', 'e', '´', ' ', '`', 'x'].iter() {
+        composer.feed(DecodedKey::Unicode(*character), |key| {
+            if let DecodedKey::Unicode(character) = key {
+                typed[count] = character;
+                count += 1;
+            }
+        });
+    }
+    layout::set_layout(previous);
+    assert_eq!(typed[..count], ['ê', '´', '`', 'x']);
+}
@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers};
+
+/// Keyboard layouts the decoder can switch between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+    Fr,
+    Dvorak,
+}
+
+pub const LAYOUTS: [Layout; 5] = [Layout::Us, Layout::Uk, Layout::De, Layout::Fr, Layout::Dvorak];
+
+impl Layout {
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+            Layout::Fr => "fr",
+            Layout::Dvorak => "dvorak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        LAYOUTS.iter().copied().find(|layout| layout.name() == name)
+    }
+
+    /// Characters that combine with the next key instead of being typed
+    pub fn dead_keys(self) -> &'static [char] {
+        match self {
+            Layout::De => &['^', '´', '`'],
+            Layout::Fr => &['^', '¨'],
+            Layout::Us | Layout::Uk | Layout::Dvorak => &[],
+        }
+    }
+}
+
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+pub fn layout() -> Layout {
+    LAYOUTS[usize::from(LAYOUT.load(Ordering::Relaxed))]
+}
+
+/// Switch layouts; takes effect from the next key
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// The decoder's layout: whichever one is selected at the time of each key
+pub struct Selected;
+
+impl KeyboardLayout for Selected {
+    fn map_keycode(keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        match layout() {
+            Layout::Us => layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Uk => layouts::Uk105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::De => De105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Fr => layouts::Azerty::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak => layouts::Dvorak104Key::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+/// German QWERTZ. Keys that match the US layout are passed on to it.
+pub struct De105Key;
+
+impl KeyboardLayout for De105Key {
+    fn map_keycode(keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        let shifted = modifiers.is_shifted();
+        let alt_gr = modifiers.alt_gr;
+        // (plain, shifted, AltGr) for the keys that differ from US
+        let (plain, shift, with_alt_gr) = match keycode {
+            KeyCode::BackTick => ('^', '°', None),
+            KeyCode::Key2 => ('2', '"', Some('²')),
+            KeyCode::Key3 => ('3', '§', Some('³')),
+            KeyCode::Key6 => ('6', '&', None),
+            KeyCode::Key7 => ('7', '/', Some('{')),
+            KeyCode::Key8 => ('8', '(', Some('[')),
+            KeyCode::Key9 => ('9', ')', Some(']')),
+            KeyCode::Key0 => ('0', '=', Some('}')),
+            KeyCode::Minus => ('ß', '?', Some('\\')),
+            KeyCode::Equals => ('´', '`', None),
+            KeyCode::BracketSquareRight => ('+', '*', Some('~')),
+            KeyCode::BackSlash => ('#', '\'', None),
+            KeyCode::Comma => (',', ';', None),
+            KeyCode::Fullstop => ('.', ':', None),
+            KeyCode::Slash => ('-', '_', None),
+            KeyCode::Q if alt_gr => return DecodedKey::Unicode('@'),
+            KeyCode::E if alt_gr => return DecodedKey::Unicode('€'),
+            KeyCode::M if alt_gr => return DecodedKey::Unicode('µ'),
+            // Letters: umlauts where US has punctuation, and Y and Z swapped
+            KeyCode::BracketSquareLeft => return letter(modifiers, 'ü', 'Ü'),
+            KeyCode::SemiColon => return letter(modifiers, 'ö', 'Ö'),
+            KeyCode::Quote => return letter(modifiers, 'ä', 'Ä'),
+            KeyCode::Y => return layouts::Us104Key::map_keycode(KeyCode::Z, modifiers, handle_ctrl),
+            KeyCode::Z => return layouts::Us104Key::map_keycode(KeyCode::Y, modifiers, handle_ctrl),
+            _ => return layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+        };
+        DecodedKey::Unicode(match with_alt_gr {
+            Some(character) if alt_gr => character,
+            _ if shifted => shift,
+            _ => plain,
+        })
+    }
+}
+
+fn letter(modifiers: &Modifiers, lower: char, upper: char) -> DecodedKey {
+    DecodedKey::Unicode(if modifiers.is_caps() { upper } else { lower })
+}
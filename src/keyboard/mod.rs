@@ -0,0 +1,214 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::i8042;
+use crate::sync::SpscQueue;
+use crate::time::{Duration, Instant};
+
+pub mod chord;
+pub mod compose;
+pub mod layout;
+pub mod modifiers;
+pub mod sysrq;
+pub mod typematic;
+
+pub use chord::Chord;
+pub use compose::Composer;
+pub use layout::{set_layout, Layout};
+pub use modifiers::Modifiers;
+pub use typematic::{set_typematic, typematic, Typematic};
+
+const QUEUE_SIZE: usize = 128;
+const COMMAND_RETRIES: usize = 3;
+const REPLY_TIMEOUT: Duration = Duration::from_millis(50);
+// Set-1 scancode of the C key
+const C: u8 = 0x2e;
+
+// Filled by the IRQ1 handler, drained by whoever reads the keyboard
+static SCANCODES: SpscQueue<u8, QUEUE_SIZE> = SpscQueue::new();
+// The keyboard's last answer to a command, set by the IRQ1 handler
+static REPLY: AtomicU8 = AtomicU8::new(0);
+
+// Ctrl+letter comes through as the matching control character (Ctrl+A is U+0001)
+lazy_static! {
+    static ref DECODER: Mutex<Keyboard<layout::Selected, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(layout::Selected, ScancodeSet1,
+            HandleControl::MapLettersToUnicode)
+        );
+}
+
+// Dead-key state for try_read_key and read_key, plus the second key when a
+// dead key didn't combine and both have to come out
+struct Reader {
+    composer: Composer,
+    queued: Option<DecodedKey>,
+}
+
+static READER: Mutex<Reader> = Mutex::new(Reader {
+    composer: Composer::new(),
+    queued: None,
+});
+
+/// Register the keyboard in the device tree, pick the layout given as
+/// `keymap=` on the command line, and add the Ctrl+Alt+Delete chord.
+/// Interrupts are already flowing by the time anyone reads it, so there's
+/// nothing else to set up.
+pub fn init() {
+    use crate::device::{self, State};
+    use crate::resource::{self, Resource};
+
+    if let Some(name) = crate::cmdline::get("keymap") {
+        match Layout::from_name(name) {
+            Some(layout) => set_layout(layout),
+            None => crate::log::warn!("unknown keymap {}, using us", name),
+        }
+    }
+
+    let result = device::register("keyboard", "PS/2 keyboard", Some(device::i8042()))
+        .and_then(|id| {
+            let claimed = resource::claim(id, Resource::Irq(1));
+            device::bind(id, "keyboard", if claimed.is_ok() { State::Active } else { State::Failed });
+            claimed
+        });
+    if let Err(message) = result {
+        crate::log::warn!("{}", message);
+    }
+
+    let reboot = Chord {
+        modifiers: Modifiers::CTRL | Modifiers::ALT,
+        key: KeyCode::Delete,
+        name: "reboot",
+        run: || crate::power::reboot(),
+    };
+    if let Err(message) = chord::register(reboot) {
+        crate::log::warn!("{}", message);
+    }
+}
+
+/// Called by the keyboard interrupt handler; drops the scancode if nobody is reading
+pub(crate) fn push_scancode(scancode: u8) {
+    // Answers to send_command, not keys
+    if scancode == i8042::ACK || scancode == i8042::RESEND {
+        REPLY.store(scancode, Ordering::Release);
+        return;
+    }
+    if crate::replay::observe(crate::replay::Source::Keyboard, scancode) {
+        deliver_scancode(scancode);
+    }
+}
+
+/// Take a scancode as if typed; for the interrupt handler, and replays.
+/// Called with interrupts off.
+pub(crate) fn deliver_scancode(scancode: u8) {
+    if sysrq::feed(scancode) {
+        return;
+    }
+    // Ctrl+C goes to the user program, if one's running, not to whoever
+    // reads the keyboard next
+    if scancode == C && sysrq::ctrl_only() && crate::user::signal::interrupt() {
+        return;
+    }
+    if SCANCODES.push(scancode).is_err() {
+        crate::log::warn!("scancode queue full; dropping keyboard input");
+        return;
+    }
+    crate::task::keyboard::wake();
+}
+
+/// The oldest queued scancode, undecoded
+pub(crate) fn pop_scancode() -> Option<u8> {
+    SCANCODES.pop()
+}
+
+/// Send a command byte to the keyboard and wait for it to be acknowledged,
+/// resending if asked to. The answer comes in through IRQ1, so interrupts
+/// have to be on.
+pub(crate) fn send_command(command: u8) -> Result<(), &'static str> {
+    if !interrupts::are_enabled() {
+        return Err("keyboard commands need interrupts on");
+    }
+    for _ in 0..COMMAND_RETRIES {
+        REPLY.store(0, Ordering::Release);
+        i8042::write_data(command)?;
+        let start = Instant::now();
+        loop {
+            match REPLY.load(Ordering::Acquire) {
+                i8042::ACK => return Ok(()),
+                i8042::RESEND => break,
+                _ if start.elapsed() > REPLY_TIMEOUT => return Err("keyboard didn't answer"),
+                _ => core::hint::spin_loop(),
+            }
+        }
+    }
+    Err("keyboard kept asking for a resend")
+}
+
+/// Feed one scancode to the decoder; returns a key once one is complete.
+/// Dead keys come through as themselves; see `Composer`. Chords are run
+/// here and swallowed.
+pub fn decode(scancode: u8) -> Option<DecodedKey> {
+    let mut decoder = DECODER.lock();
+    let key_event = match decoder.add_byte(scancode) {
+        Ok(Some(key_event)) => key_event,
+        _ => return None,
+    };
+    let is_modifier = modifiers::track(&key_event);
+    if !is_modifier && key_event.state == KeyState::Down {
+        if let Some(run) = chord::lookup(modifiers::held(), key_event.code) {
+            // The chord may well read the keyboard itself
+            drop(decoder);
+            run();
+            return None;
+        }
+    }
+    decoder.process_keyevent(key_event)
+}
+
+/// Decode whatever scancodes are queued, returning the first complete key
+pub fn try_read_key() -> Option<DecodedKey> {
+    let mut reader = READER.lock();
+    let Reader { composer, queued } = &mut *reader;
+    if let Some(key) = queued.take() {
+        return Some(key);
+    }
+    while let Some(scancode) = SCANCODES.pop() {
+        let mut first = None;
+        if let Some(key) = decode(scancode) {
+            composer.feed(key, |key| match first {
+                None => first = Some(key),
+                Some(_) => *queued = Some(key),
+            });
+        }
+        if first.is_some() {
+            return first;
+        }
+    }
+    None
+}
+
+/// Whether input is waiting, e.g. to check with interrupts off before idling
+pub fn input_pending() -> bool {
+    !SCANCODES.is_empty() || READER.lock().queued.is_some()
+}
+
+/// Block (halting between interrupts) until a key is pressed. Timer
+/// callbacks keep running while we wait.
+pub fn read_key() -> DecodedKey {
+    loop {
+        crate::timer::run_expired();
+        if let Some(key) = try_read_key() {
+            return key;
+        }
+
+        // Check again with interrupts off so a keypress can't sneak in between
+        // the check and the hlt; idle() re-enables them atomically.
+        interrupts::disable();
+        if !input_pending() {
+            crate::timer::idle();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
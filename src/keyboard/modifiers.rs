@@ -0,0 +1,129 @@
+use core::fmt;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{KeyCode, KeyEvent, KeyState};
+
+/// A set of modifiers. Left and right Shift, Ctrl, and Meta count the
+/// same; right Alt is AltGr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const SHIFT: Modifiers = Modifiers(0x01);
+    pub const CTRL: Modifiers = Modifiers(0x02);
+    pub const ALT: Modifiers = Modifiers(0x04);
+    pub const ALT_GR: Modifiers = Modifiers(0x08);
+    pub const META: Modifiers = Modifiers(0x10);
+
+    pub const fn union(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 | other.0)
+    }
+
+    pub fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, other: Modifiers) -> Modifiers {
+        self.union(other)
+    }
+}
+
+// As a prefix, e.g. "Ctrl+Alt+" for a chord
+impl fmt::Display for Modifiers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = [
+            (Modifiers::CTRL, "Ctrl"),
+            (Modifiers::ALT, "Alt"),
+            (Modifiers::ALT_GR, "AltGr"),
+            (Modifiers::META, "Meta"),
+            (Modifiers::SHIFT, "Shift"),
+        ];
+        for (modifier, name) in names.iter() {
+            if self.contains(*modifier) {
+                write!(f, "{}+", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// One bit per physical modifier key, so letting go of one Shift while the
+// other is still down doesn't drop Shift
+static HELD: AtomicU8 = AtomicU8::new(0);
+
+fn key_bit(code: KeyCode) -> Option<(u8, Modifiers)> {
+    match code {
+        KeyCode::ShiftLeft => Some((0x01, Modifiers::SHIFT)),
+        KeyCode::ShiftRight => Some((0x02, Modifiers::SHIFT)),
+        KeyCode::ControlLeft => Some((0x04, Modifiers::CTRL)),
+        KeyCode::ControlRight => Some((0x08, Modifiers::CTRL)),
+        KeyCode::AltLeft => Some((0x10, Modifiers::ALT)),
+        KeyCode::AltRight => Some((0x20, Modifiers::ALT_GR)),
+        KeyCode::WindowsLeft => Some((0x40, Modifiers::META)),
+        KeyCode::WindowsRight => Some((0x80, Modifiers::META)),
+        _ => None,
+    }
+}
+
+/// Note a key going down or up; returns whether it was a modifier
+pub(super) fn track(event: &KeyEvent) -> bool {
+    match key_bit(event.code) {
+        Some((bit, _)) => {
+            match event.state {
+                KeyState::Down => HELD.fetch_or(bit, Ordering::Relaxed),
+                KeyState::Up => HELD.fetch_and(!bit, Ordering::Relaxed),
+            };
+            true
+        }
+        None => false,
+    }
+}
+
+/// The modifiers held down right now
+pub fn held() -> Modifiers {
+    let keys = HELD.load(Ordering::Relaxed);
+    let all = [
+        KeyCode::ShiftLeft,
+        KeyCode::ShiftRight,
+        KeyCode::ControlLeft,
+        KeyCode::ControlRight,
+        KeyCode::AltLeft,
+        KeyCode::AltRight,
+        KeyCode::WindowsLeft,
+        KeyCode::WindowsRight,
+    ];
+    all.iter()
+        .filter_map(|&code| key_bit(code))
+        .filter(|(bit, _)| keys & bit != 0)
+        .fold(Modifiers::NONE, |held, (_, modifier)| held | modifier)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_both_sides_of_a_modifier() {
+    let saved = HELD.swap(0, Ordering::Relaxed);
+    let event = |code, state| KeyEvent { code, state };
+
+    assert!(track(&event(KeyCode::ShiftLeft, KeyState::Down)));
+    assert!(track(&event(KeyCode::ShiftRight, KeyState::Down)));
+    assert!(track(&event(KeyCode::AltRight, KeyState::Down)));
+    assert!(!track(&event(KeyCode::A, KeyState::Down)));
+    assert_eq!(held(), Modifiers::SHIFT | Modifiers::ALT_GR);
+
+    // Shift stays held until both are up
+    track(&event(KeyCode::ShiftLeft, KeyState::Up));
+    assert!(held().contains(Modifiers::SHIFT));
+    track(&event(KeyCode::ShiftRight, KeyState::Up));
+    assert_eq!(held(), Modifiers::ALT_GR);
+    HELD.store(saved, Ordering::Relaxed);
+}
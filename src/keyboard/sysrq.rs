@@ -0,0 +1,166 @@
+//! Ctrl+Alt+letter debug keys that work even when nothing is reading the
+//! keyboard. They're spotted in the IRQ1 handler, straight from scancodes,
+//! and their actions run as deferred work once the handler is done.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use crate::println;
+
+// Set-1 scancodes
+const EXTENDED: u8 = 0xe0;
+const RELEASED: u8 = 0x80;
+const CTRL: u8 = 0x1d;
+const ALT: u8 = 0x38;
+
+// Left and right of each, since the right ones come after EXTENDED
+const LEFT_CTRL: u8 = 0x01;
+const RIGHT_CTRL: u8 = 0x02;
+const LEFT_ALT: u8 = 0x04;
+const RIGHT_ALT: u8 = 0x08;
+
+struct Action {
+    key: char,
+    scancode: u8,
+    help: &'static str,
+    run: fn(),
+}
+
+const ACTIONS: &[Action] = &[
+    Action { key: 'h', scancode: 0x23, help: "list these keys", run: help },
+    Action { key: 't', scancode: 0x14, help: "backtrace every thread", run: dump_threads },
+    Action { key: 'm', scancode: 0x32, help: "memory stats", run: dump_memory },
+    Action { key: 's', scancode: 0x1f, help: "sync filesystems", run: sync },
+    Action { key: 'k', scancode: 0x25, help: "kill the user program", run: kill },
+    Action { key: 'b', scancode: 0x30, help: "reboot now", run: reboot },
+];
+
+// Only the IRQ1 handler touches these
+static HELD: AtomicU8 = AtomicU8::new(0);
+static AFTER_EXTENDED: AtomicBool = AtomicBool::new(false);
+
+/// Called by the keyboard interrupt handler with every scancode. Returns
+/// whether it was a debug key, which then shouldn't be passed on.
+pub(super) fn feed(scancode: u8) -> bool {
+    if scancode == EXTENDED {
+        AFTER_EXTENDED.store(true, Ordering::Relaxed);
+        return false;
+    }
+    let extended = AFTER_EXTENDED.swap(false, Ordering::Relaxed);
+    let released = scancode & RELEASED != 0;
+    let modifier = match (scancode & !RELEASED, extended) {
+        (CTRL, false) => LEFT_CTRL,
+        (CTRL, true) => RIGHT_CTRL,
+        (ALT, false) => LEFT_ALT,
+        (ALT, true) => RIGHT_ALT,
+        _ => 0,
+    };
+    if modifier != 0 {
+        if released {
+            HELD.fetch_and(!modifier, Ordering::Relaxed);
+        } else {
+            HELD.fetch_or(modifier, Ordering::Relaxed);
+        }
+        return false;
+    }
+
+    let held = HELD.load(Ordering::Relaxed);
+    let ctrl_and_alt = held & (LEFT_CTRL | RIGHT_CTRL) != 0 && held & (LEFT_ALT | RIGHT_ALT) != 0;
+    if released || extended || !ctrl_and_alt {
+        return false;
+    }
+    match ACTIONS.iter().position(|action| action.scancode == scancode) {
+        Some(index) => {
+            if crate::softirq::defer(run, index as u64).is_err() {
+                println!("sysrq: busy, try again");
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Whether Ctrl is held without Alt, as of the last scancode fed
+pub(super) fn ctrl_only() -> bool {
+    let held = HELD.load(Ordering::Relaxed);
+    held & (LEFT_CTRL | RIGHT_CTRL) != 0 && held & (LEFT_ALT | RIGHT_ALT) == 0
+}
+
+fn run(index: u64) {
+    let action = &ACTIONS[index as usize];
+    println!("sysrq: {}", action.help);
+    (action.run)();
+}
+
+fn help() {
+    for action in ACTIONS {
+        println!("  Ctrl+Alt+{}  {}", action.key, action.help);
+    }
+}
+
+fn dump_threads() {
+    crate::sched::for_each_backtrace(|id, name, trace| {
+        println!("thread {} ({}):", id.as_u64(), name);
+        crate::print!("{}", trace);
+    });
+}
+
+fn dump_memory() {
+    use crate::memory::{self, FRAME_SIZE};
+
+    let frames = memory::with_frame_allocator(|frames| (frames.usable_frames(), frames.free_frames()));
+    if let Some((usable, free)) = frames {
+        let kib = |frames: usize| frames as u64 * FRAME_SIZE / 1024;
+        println!("  frames: {} KiB free of {} KiB", kib(free), kib(usable));
+    }
+    println!(
+        "  heap: {} KiB free of {} KiB",
+        crate::allocator::free_bytes() / 1024,
+        crate::allocator::HEAP_SIZE / 1024
+    );
+}
+
+// On a thread of its own: this runs on top of whatever the keypress
+// interrupted, which may hold the filesystem's locks
+fn sync() {
+    fn run() {
+        match crate::fs::sync() {
+            Ok(()) => println!("sysrq: synced"),
+            Err(message) => println!("sysrq: sync failed: {}", message),
+        }
+    }
+
+    if let Err(message) = crate::sched::spawn("sysrq-sync", run) {
+        println!("  {}", message);
+    }
+}
+
+fn kill() {
+    if !crate::user::signal::kill() {
+        println!("  no user program is running");
+    }
+}
+
+fn reboot() {
+    crate::power::reboot();
+}
+
+/// TESTS
+
+#[test_case]
+fn test_only_ctrl_alt_letters_are_taken() {
+    // Plain 'h' is just typing
+    assert!(!feed(0x23));
+    // With Ctrl and right Alt held, 'z' isn't a debug key but 'h' is
+    assert!(!feed(CTRL));
+    assert!(!feed(EXTENDED));
+    assert!(!feed(ALT));
+    assert!(!feed(0x2c));
+    assert!(feed(0x23));
+    assert!(!feed(0x23 | RELEASED));
+
+    // Right Alt up; only left Ctrl is still held
+    assert!(!feed(EXTENDED));
+    assert!(!feed(ALT | RELEASED));
+    assert!(!feed(0x23));
+    assert!(!feed(CTRL | RELEASED));
+    assert_eq!(HELD.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,65 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const SET_TYPEMATIC: u8 = 0xf3;
+
+// Repeat rates the keyboard can do, in tenths of a key per second, indexed
+// by the value that selects them
+const RATES: [u32; 32] = [
+    300, 267, 240, 218, 207, 185, 171, 160, 150, 133, 120, 109, 100, 92, 86, 80,
+    75, 67, 60, 55, 50, 46, 43, 40, 37, 33, 30, 27, 25, 23, 21, 20,
+];
+// Delays before repeating starts, by the value in bits 5 and 6
+const DELAYS_MS: [u32; 4] = [250, 500, 750, 1000];
+
+/// How a held key repeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    pub delay_ms: u32,
+    /// Keys per second, in tenths
+    pub rate_tenths: u32,
+}
+
+// What the keyboard resets to: 500 ms, 10.9 keys a second
+static SETTING: AtomicU8 = AtomicU8::new(0x2b);
+
+// The closest setting the keyboard supports
+fn encode(delay_ms: u32, rate_tenths: u32) -> u8 {
+    let closest = |values: &[u32], wanted: u32| {
+        (0..values.len()).min_by_key(|&index| (values[index] as i64 - wanted as i64).abs()).unwrap() as u8
+    };
+    closest(&DELAYS_MS, delay_ms) << 5 | closest(&RATES, rate_tenths)
+}
+
+fn decode(setting: u8) -> Typematic {
+    Typematic {
+        delay_ms: DELAYS_MS[usize::from(setting >> 5 & 0x3)],
+        rate_tenths: RATES[usize::from(setting & 0x1f)],
+    }
+}
+
+/// The current repeat delay and rate
+pub fn typematic() -> Typematic {
+    decode(SETTING.load(Ordering::Relaxed))
+}
+
+/// Set the repeat delay and rate to the closest the keyboard supports
+/// (250 to 1000 ms, 2 to 30 keys a second), returning what was picked
+pub fn set_typematic(delay_ms: u32, rate_tenths: u32) -> Result<Typematic, &'static str> {
+    let setting = encode(delay_ms, rate_tenths);
+    super::send_command(SET_TYPEMATIC)?;
+    super::send_command(setting)?;
+    SETTING.store(setting, Ordering::Relaxed);
+    Ok(decode(setting))
+}
+
+/// TESTS
+
+#[test_case]
+fn test_typematic_encoding() {
+    assert_eq!(encode(500, 109), 0x2b);
+    assert_eq!(decode(0x2b), Typematic { delay_ms: 500, rate_tenths: 109 });
+    // Out of range rounds to the nearest end
+    assert_eq!(decode(encode(0, 1000)), Typematic { delay_ms: 250, rate_tenths: 300 });
+    assert_eq!(decode(encode(5000, 0)), Typematic { delay_ms: 1000, rate_tenths: 20 });
+    assert_eq!(decode(encode(700, 150)), Typematic { delay_ms: 750, rate_tenths: 150 });
+}
@@ -0,0 +1,156 @@
+use core::convert::TryInto;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+use crate::entropy;
+
+// SipHash key, picked at boot so hashes can't be reversed or compared across boots
+static KEY: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static HASHING: AtomicBool = AtomicBool::new(true);
+
+/// Pick a fresh hashing key. Until this runs the key is zero, so do it early.
+pub fn init() {
+    let mut key = [0; 16];
+    entropy::fill_bytes(&mut key);
+    let (low, high) = key.split_at(8);
+    KEY[0].store(u64::from_le_bytes(low.try_into().unwrap()), Ordering::Relaxed);
+    KEY[1].store(u64::from_le_bytes(high.try_into().unwrap()), Ordering::Relaxed);
+}
+
+/// Whether kernel addresses in logs are hashed (the default)
+pub fn hashing() -> bool {
+    HASHING.load(Ordering::Relaxed)
+}
+
+/// Turn hashing off to see real addresses while debugging
+pub fn set_hashing(on: bool) {
+    HASHING.store(on, Ordering::Relaxed);
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+// SipHash-2-4 of a single little-endian u64
+fn siphash(key: [u64; 2], message: u64) -> u64 {
+    let mut v = [
+        key[0] ^ 0x736f_6d65_7073_6575,
+        key[1] ^ 0x646f_7261_6e64_6f6d,
+        key[0] ^ 0x6c79_6765_6e65_7261,
+        key[1] ^ 0x7465_6462_7974_6573,
+    ];
+    // The message block, then the final block holding just the length (8)
+    for block in [message, 8 << 56].iter() {
+        v[3] ^= block;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= block;
+    }
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// A kernel address as it should appear in logs. Formats as a keyed hash
+/// unless hashing is off; null stays null, since it gives nothing away.
+#[derive(Clone, Copy)]
+pub struct Ptr(u64);
+
+pub fn ptr(address: u64) -> Ptr {
+    Ptr(address)
+}
+
+impl From<VirtAddr> for Ptr {
+    fn from(address: VirtAddr) -> Ptr {
+        Ptr(address.as_u64())
+    }
+}
+
+impl<T: ?Sized> From<*const T> for Ptr {
+    fn from(pointer: *const T) -> Ptr {
+        Ptr(pointer as *const () as u64)
+    }
+}
+
+impl fmt::Display for Ptr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = if self.0 == 0 || !hashing() {
+            self.0
+        } else {
+            siphash([KEY[0].load(Ordering::Relaxed), KEY[1].load(Ordering::Relaxed)], self.0)
+        };
+        write!(f, "{:#018x}", value)
+    }
+}
+
+impl fmt::Debug for Ptr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// An interrupt stack frame with its addresses run through `Ptr`
+pub struct Frame<'a>(&'a InterruptStackFrame);
+
+pub fn frame(stack_frame: &InterruptStackFrame) -> Frame {
+    Frame(stack_frame)
+}
+
+impl fmt::Debug for Frame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InterruptStackFrame")
+            .field("instruction_pointer", &Ptr::from(self.0.instruction_pointer))
+            .field("code_segment", &self.0.code_segment)
+            .field("cpu_flags", &format_args!("{:#x}", self.0.cpu_flags))
+            .field("stack_pointer", &Ptr::from(self.0.stack_pointer))
+            .field("stack_segment", &self.0.stack_segment)
+            .finish()
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_siphash_reference_vector() {
+    // From the SipHash paper's test vectors: key 00..0f, message 00..07
+    let key = [0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908];
+    assert_eq!(siphash(key, 0x0706_0504_0302_0100), 0x93f5_f579_9a93_2462);
+}
+
+#[test_case]
+fn test_pointer_hashing() {
+    use core::fmt::Write;
+
+    struct Buffer([u8; 18], usize);
+    impl Write for Buffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0[self.1..self.1 + s.len()].copy_from_slice(s.as_bytes());
+            self.1 += s.len();
+            Ok(())
+        }
+    }
+    let formatted = |pointer: Ptr| {
+        let mut buffer = Buffer([0; 18], 0);
+        write!(buffer, "{}", pointer).unwrap();
+        buffer.0
+    };
+
+    let address = 0xffff_8000_dead_b000;
+    assert_eq!(&formatted(ptr(0)), b"0x0000000000000000");
+    assert_ne!(&formatted(ptr(address)), b"0xffff8000deadb000");
+    set_hashing(false);
+    assert_eq!(&formatted(ptr(address)), b"0xffff8000deadb000");
+    set_hashing(true);
+}
@@ -0,0 +1,74 @@
+/// Version of the exported kernel API. Bump it when an exported symbol's
+/// signature or behavior changes incompatibly.
+pub const KERNEL_API_VERSION: u32 = 1;
+
+/// One entry in the exported symbol table, placed in the `ksymtab` link
+/// section by `export_symbol!`
+#[repr(C)]
+pub struct KernelSymbol {
+    pub name: &'static str,
+    pub address: *const (),
+    pub version: u32,
+}
+
+// Only ever built from function addresses at compile time and never written
+unsafe impl Sync for KernelSymbol {}
+
+/// Add a kernel function to the exported symbol table, optionally with an
+/// explicit API version (defaults to KERNEL_API_VERSION). Symbol names are
+/// the bare function name, so they need to be unique across the kernel.
+#[macro_export]
+macro_rules! export_symbol {
+    ($name:ident) => {
+        $crate::export_symbol!($name, $crate::ksymtab::KERNEL_API_VERSION);
+    };
+    ($name:ident, $version:expr) => {
+        const _: () = {
+            #[used(linker)]
+            #[link_section = "ksymtab"]
+            static SYMBOL: $crate::ksymtab::KernelSymbol = $crate::ksymtab::KernelSymbol {
+                name: stringify!($name),
+                address: $name as *const (),
+                version: $version,
+            };
+        };
+    };
+}
+
+// The linker defines these around any section whose name is a C identifier
+extern "C" {
+    static __start_ksymtab: KernelSymbol;
+    static __stop_ksymtab: KernelSymbol;
+}
+
+/// Every exported symbol
+pub fn symbols() -> &'static [KernelSymbol] {
+    unsafe {
+        let start = core::ptr::addr_of!(__start_ksymtab);
+        let stop = core::ptr::addr_of!(__stop_ksymtab);
+        let len = (stop as usize - start as usize) / core::mem::size_of::<KernelSymbol>();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// Find an exported symbol by name
+pub fn lookup(name: &str) -> Option<&'static KernelSymbol> {
+    symbols().iter().find(|symbol| symbol.name == name)
+}
+
+/// Find an exported symbol by name, only if it matches the API version a
+/// module was built against
+pub fn lookup_versioned(name: &str, version: u32) -> Option<&'static KernelSymbol> {
+    lookup(name).filter(|symbol| symbol.version == version)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_lookup_exported_symbol() {
+    let symbol = lookup("hlt_loop").expect("hlt_loop should be exported");
+    assert_eq!(symbol.address, crate::hlt_loop as *const ());
+    assert!(lookup_versioned("hlt_loop", KERNEL_API_VERSION).is_some());
+    assert!(lookup_versioned("hlt_loop", KERNEL_API_VERSION + 1).is_none());
+    assert!(lookup("not_a_kernel_symbol").is_none());
+}
@@ -0,0 +1,108 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::time::{self, Duration, PIT_FREQUENCY};
+
+// Bucket n counts latencies of [2^n, 2^(n+1)) PIT input clocks (~838ns each)
+const BUCKETS: usize = 16;
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static HISTOGRAM: [AtomicU64; BUCKETS] = [ZERO; BUCKETS];
+static SAMPLES: AtomicU64 = AtomicU64::new(0);
+static TOTAL_CLOCKS: AtomicU64 = AtomicU64::new(0);
+static MAX_CLOCKS: AtomicU64 = AtomicU64::new(0);
+
+fn clocks_to_duration(clocks: u64) -> Duration {
+    Duration::from_nanos(clocks * 1_000_000_000 / PIT_FREQUENCY)
+}
+
+/// Called first thing in the timer interrupt handler: how far the PIT has
+/// counted since it raised IRQ0 is how long the interrupt took to get here
+pub(crate) fn record_timer_entry() {
+    let clocks = time::pit_clocks_since_irq();
+    let bucket = (63 - clocks.max(1).leading_zeros() as usize).min(BUCKETS - 1);
+    HISTOGRAM[bucket].fetch_add(1, Ordering::Relaxed);
+    SAMPLES.fetch_add(1, Ordering::Relaxed);
+    TOTAL_CLOCKS.fetch_add(clocks, Ordering::Relaxed);
+    MAX_CLOCKS.fetch_max(clocks, Ordering::Relaxed);
+}
+
+/// Number of timer interrupts measured since boot or the last reset
+pub fn samples() -> u64 {
+    SAMPLES.load(Ordering::Relaxed)
+}
+
+pub fn max() -> Duration {
+    clocks_to_duration(MAX_CLOCKS.load(Ordering::Relaxed))
+}
+
+pub fn mean() -> Duration {
+    match samples() {
+        0 => Duration::from_nanos(0),
+        samples => clocks_to_duration(TOTAL_CLOCKS.load(Ordering::Relaxed) / samples),
+    }
+}
+
+/// Call `f` with the lower bound of each non-empty bucket and its count
+pub fn for_each_bucket(mut f: impl FnMut(Duration, u64)) {
+    for (bucket, count) in HISTOGRAM.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        if count > 0 {
+            f(clocks_to_duration(1 << bucket), count);
+        }
+    }
+}
+
+/// Start a fresh measurement, e.g. right before exercising a change
+pub fn reset() {
+    for count in HISTOGRAM.iter() {
+        count.store(0, Ordering::Relaxed);
+    }
+    SAMPLES.store(0, Ordering::Relaxed);
+    TOTAL_CLOCKS.store(0, Ordering::Relaxed);
+    MAX_CLOCKS.store(0, Ordering::Relaxed);
+}
+
+#[cfg(feature = "shell")]
+fn cmd_irqlat(args: &[&str]) -> crate::shell::Status {
+    use crate::println;
+    use crate::shell::{FAILURE, SUCCESS};
+
+    match args {
+        [] => {
+            println!("samples {}, mean {:?}, max {:?}", samples(), mean(), max());
+            for_each_bucket(|lower, count| println!(">= {:<12?} {}", lower, count));
+            SUCCESS
+        }
+        ["reset"] => {
+            reset();
+            SUCCESS
+        }
+        _ => {
+            println!("usage: irqlat [reset]");
+            FAILURE
+        }
+    }
+}
+
+pub fn init() {
+    #[cfg(feature = "shell")]
+    crate::shell::register(crate::shell::Command {
+        name: "irqlat",
+        help: "timer interrupt latency histogram (irqlat reset to clear)",
+        run: cmd_irqlat,
+        complete: None,
+    })
+    .expect("couldn't register irqlat");
+}
+
+/// TESTS
+
+#[test_case]
+fn test_latency_recorded() {
+    let before = samples();
+    let start = time::Instant::now();
+    while start.elapsed() < Duration::from_millis(5) {
+        x86_64::instructions::hlt();
+    }
+    assert!(samples() > before);
+    assert!(max() >= mean());
+}
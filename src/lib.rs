@@ -2,21 +2,150 @@
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
+#![feature(used_with_arg)]
+#![cfg_attr(feature = "kasan", feature(no_sanitize))]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
 use core::panic::PanicInfo;
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
+
+pub use version::version;
 
+pub mod cmdline;
+pub mod error;
+pub mod version;
+pub mod early_console;
+pub mod log;
+pub mod panic;
+pub mod prelude;
+pub mod qemu;
+#[macro_use]
+pub mod mmio;
+pub mod pio;
+pub mod msr;
+pub mod device;
+pub mod drivers;
+pub mod io;
+pub mod net;
+pub mod events;
+pub mod fs;
+pub mod resource;
+#[macro_use]
+pub mod ksymtab;
+pub mod module;
 pub mod serial;
 pub mod vga_buffer;
+pub mod console;
+pub mod cp437;
+pub mod framebuffer;
+pub mod hostchan;
+pub mod screenshot;
 pub mod interrupts;
+pub mod apic;
+pub mod smp;
+pub mod ipi;
 pub mod gdt;
+pub mod acpi;
+pub mod memory;
+pub mod allocator;
+pub mod power;
+pub mod time;
+pub mod timer;
+pub mod poll;
+pub mod rtc;
+#[cfg(any(feature = "keyboard", feature = "mouse"))]
+mod i8042;
+#[cfg(feature = "keyboard")]
+pub mod keyboard;
+#[cfg(feature = "mouse")]
+pub mod mouse;
+#[cfg(feature = "mouse")]
+pub mod selection;
+#[cfg(any(feature = "keyboard", feature = "mouse"))]
+pub mod replay;
+pub mod clipboard;
+#[cfg(feature = "keyboard")]
+pub mod tui;
+#[cfg(feature = "shell")]
+pub mod shell;
+#[cfg(feature = "shell")]
+pub mod snake;
+#[cfg(feature = "shell")]
+pub mod top;
+pub mod cpu;
+pub mod uaccess;
+pub mod user;
+pub mod elf;
+pub mod image;
+pub mod entropy;
+pub mod kptr;
+pub mod crypto;
+pub mod sync;
+pub mod softirq;
+pub mod workqueue;
+pub mod task;
+pub mod sched;
+pub mod selftest;
+pub mod metrics;
+pub mod testing;
+pub mod backtrace;
+pub mod symbols;
+#[cfg(feature = "measured-boot")]
+pub mod measure;
+#[cfg(feature = "fault-inject")]
+pub mod fault;
+pub mod stack_protector;
+#[cfg(feature = "kasan")]
+pub mod kasan;
+#[cfg(feature = "irq-latency")]
+pub mod latency;
+#[cfg(feature = "alloc-trace")]
+pub mod alloctrace;
 
 pub fn init() {
+    log::init();
     gdt::init();
+    user::init();
+    kptr::init();
+    device::init();
+    #[cfg(feature = "measured-boot")]
+    measure::report();
+    #[cfg(feature = "hardening")]
+    cpu::enable_protections();
     interrupts::init_idt();
+    time::init();
+    #[cfg(feature = "irq-latency")]
+    latency::init();
+    #[cfg(feature = "fault-inject")]
+    fault::init();
+    #[cfg(feature = "alloc-trace")]
+    alloctrace::init();
     unsafe { interrupts::PICS.lock().initialize() };
+    serial::init();
+    #[cfg(feature = "keyboard")]
+    keyboard::init();
+    #[cfg(feature = "mouse")]
+    if let Err(message) = mouse::init() {
+        log::warn!("mouse: {}", message);
+    }
     x86_64::instructions::interrupts::enable();
+    if let Err(message) = drivers::pci::init() {
+        log::warn!("pci: {}", message);
+    }
+    // Polls with timeouts, so it needs the clock ticking
+    drivers::ata::init();
+    metrics::init();
+    net::init();
+    module::init();
+    // Not finding one is normal; the shell says so if it's asked for files
+    let _ = fs::mount();
+    let _ = panic::register_hook(panic::Hook { name: "fs", stage: panic::Stage::Flush, run: fs::panic_sync });
+    let _ = memory::swap::swapon();
+    early_console::mark_console_ready();
 }
 
 // Wrap assemply with idle sleep hlt instruction
@@ -26,6 +155,7 @@ pub fn hlt_loop() -> ! {
         x86_64::instructions::hlt();
     }
 }
+export_symbol!(hlt_loop);
 
 // Test stuff
 
@@ -44,26 +174,54 @@ where
     }
 }
 
+/// Run each test, reporting over serial, then exit QEMU. A failing test
+/// panics, and test_panic_handler reports it and exits with a failure.
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
         test.run();
+        testing::reset_shared();
     }
-    exit_qemu(QemuExitCode::Success);
+    serial_println!("All {} tests passed", tests.len());
+    qemu::exit(qemu::ExitCode::Success);
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-    loop {}
+    if panic::enter(info) {
+        panic::serial_print(format_args!("[failed]\n\nError: {}\n\n", info));
+        panic::run_hooks();
+    }
+    qemu::exit(qemu::ExitCode::Failed);
 }
 
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
 /// Entry point for `cargo test`
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    #[cfg(feature = "hardening")]
+    stack_protector::init();
     init();
+    memory::init(boot_info);
+    #[cfg(feature = "kasan")]
+    kasan::init().expect("kasan initialization failed");
+    allocator::init_heap().expect("heap initialization failed");
+    workqueue::init();
+    // Its queue needs frames of its own
+    drivers::virtio_rng::init();
+    match apic::init() {
+        Ok(()) => {
+            if let Err(message) = smp::init() {
+                log::warn!("smp: {}", message);
+            }
+        }
+        Err(message) => log::warn!("apic: {}; using the 8259 PIC", message),
+    }
+    #[cfg(feature = "hardening")]
+    if let Err(message) = cpu::protect_tables() {
+        log::warn!("hardening: {}", message);
+    }
     test_main();
     loop {}
 }
@@ -73,19 +231,3 @@ pub extern "C" fn _start() -> ! {
 fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
-}
-
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
-    }
-}
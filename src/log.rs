@@ -0,0 +1,313 @@
+//! Kernel log messages, with a level each: `log::info!("...")` and so on.
+//! Whatever gets past the filter for its module goes to the console (the
+//! early one until that's ready) and serial, and into a ring buffer that
+//! `dmesg` reads back, oldest lines dropped first.
+//!
+//! The filter is a level for everything, `loglevel=` on the command line
+//! (info without it), and levels for modules and everything under them.
+
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::{cmdline, early_console, time};
+
+pub use crate::{log_debug as debug, log_error as error, log_info as info, log_trace as trace, log_warn as warn};
+
+const RING_SIZE: usize = 16 * 1024;
+const MAX_FILTERS: usize = 8;
+const MAX_MODULE_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Level> {
+        [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace]
+            .iter()
+            .copied()
+            .find(|level| level.name() == name)
+    }
+
+    fn from_u8(value: u8) -> Level {
+        match value {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, module_path!(), format_args!($($arg)*)));
+}
+
+/// Log at Level::Error
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::__log!($crate::log::Level::Error, $($arg)*));
+}
+
+/// Log at Level::Warn
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::__log!($crate::log::Level::Warn, $($arg)*));
+}
+
+/// Log at Level::Info
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::__log!($crate::log::Level::Info, $($arg)*));
+}
+
+/// Log at Level::Debug
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::__log!($crate::log::Level::Debug, $($arg)*));
+}
+
+/// Log at Level::Trace
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)*) => ($crate::__log!($crate::log::Level::Trace, $($arg)*));
+}
+
+// A module, as module_path! has it but without the crate's name
+#[derive(Clone, Copy)]
+struct Filter {
+    module: [u8; MAX_MODULE_LEN],
+    len: usize,
+    level: Level,
+}
+
+impl Filter {
+    fn module(&self) -> &str {
+        core::str::from_utf8(&self.module[..self.len]).unwrap_or("")
+    }
+
+    fn covers(&self, module: &str) -> bool {
+        let own = self.module();
+        module.strip_prefix(own).map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+    }
+}
+
+// 0 until `init` or the first message, which read the command line
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+// Only ever locked with interrupts off, so a handler can't find it held
+static FILTERS: Mutex<[Option<Filter>; MAX_FILTERS]> = Mutex::new([None; MAX_FILTERS]);
+
+struct Ring {
+    bytes: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+    // Whether the oldest line has lost its beginning
+    wrapped: bool,
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.bytes[(self.start + self.len) % RING_SIZE] = byte;
+            if self.len == RING_SIZE {
+                self.start = (self.start + 1) % RING_SIZE;
+                self.wrapped = true;
+            } else {
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static RING: Mutex<Ring> = Mutex::new(Ring { bytes: [0; RING_SIZE], start: 0, len: 0, wrapped: false });
+
+// The crate's root module keeps its name
+fn without_crate(module: &str) -> &str {
+    module.split_once("::").map_or(module, |(_, rest)| rest)
+}
+
+/// Pick up `loglevel=` from the command line
+pub fn init() {
+    let level = cmdline::get("loglevel").and_then(Level::from_name).unwrap_or(Level::Info);
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// The level for everything without one of its own
+pub fn level() -> Level {
+    if LEVEL.load(Ordering::Relaxed) == 0 {
+        init();
+    }
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Give `module` (e.g. "memory::swap") and everything under it a level of
+/// its own, or go back to the general one with None
+pub fn set_module_level(module: &str, level: Option<Level>) -> Result<(), &'static str> {
+    if module.is_empty() || module.len() > MAX_MODULE_LEN {
+        return Err("bad module name");
+    }
+    interrupts::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        let existing = filters.iter().position(|filter| filter.map_or(false, |filter| filter.module() == module));
+        let level = match level {
+            Some(level) => level,
+            None => {
+                if let Some(index) = existing {
+                    filters[index] = None;
+                }
+                return Ok(());
+            }
+        };
+        let index = match existing {
+            Some(index) => index,
+            None => filters.iter().position(Option::is_none).ok_or("too many module levels")?,
+        };
+        let mut filter = Filter { module: [0; MAX_MODULE_LEN], len: module.len(), level };
+        filter.module[..module.len()].copy_from_slice(module.as_bytes());
+        filters[index] = Some(filter);
+        Ok(())
+    })
+}
+
+/// Every module with a level of its own
+pub fn for_each_module_level(mut f: impl FnMut(&str, Level)) {
+    let filters = interrupts::without_interrupts(|| *FILTERS.lock());
+    for filter in filters.iter().flatten() {
+        f(filter.module(), filter.level);
+    }
+}
+
+/// The level messages from `module` have to be at or above
+pub fn module_level(module: &str) -> Level {
+    // The most specific filter wins
+    let filters = interrupts::without_interrupts(|| *FILTERS.lock());
+    filters
+        .iter()
+        .flatten()
+        .filter(|filter| filter.covers(module))
+        .max_by_key(|filter| filter.len)
+        .map_or_else(level, |filter| filter.level)
+}
+
+// One line of the log, as it's printed and kept
+struct Record<'a> {
+    uptime: time::Duration,
+    level: Level,
+    module: &'a str,
+    args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[{:5}.{:06}] {} {}: {}", self.uptime.as_secs(), self.uptime.subsec_micros(), self.level.name(),
+            self.module, self.args)
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module_path: &str, args: fmt::Arguments) {
+    let module = without_crate(module_path);
+    if level > module_level(module) {
+        return;
+    }
+    let record = Record { uptime: time::uptime(), level, module, args };
+    interrupts::without_interrupts(|| {
+        let _ = write!(RING.lock(), "{}", record);
+    });
+    if !early_console::console_ready() {
+        early_console::_print(format_args!("{}", record));
+        return;
+    }
+    crate::vga_buffer::_print(format_args!("{}", record));
+    // Headless, the console is serial already
+    if !cmdline::headless() {
+        crate::serial::_print(format_args!("{}", record));
+    }
+}
+
+/// Put a line in the ring buffer and nowhere else, past any filter, for
+/// what comes too often to print. It's called from inside the allocator, so
+/// if the ring's in use the line is dropped rather than waited for.
+pub fn keep(level: Level, module_path: &str, args: fmt::Arguments) {
+    let record = Record { uptime: time::uptime(), level, module: without_crate(module_path), args };
+    interrupts::without_interrupts(|| {
+        if let Some(mut ring) = RING.try_lock() {
+            let _ = write!(ring, "{}", record);
+        }
+    });
+}
+
+/// What's in the ring buffer, whole lines only
+pub fn contents() -> Vec<u8> {
+    interrupts::without_interrupts(|| {
+        let ring = RING.lock();
+        let mut bytes: Vec<u8> = (0..ring.len).map(|index| ring.bytes[(ring.start + index) % RING_SIZE]).collect();
+        if ring.wrapped {
+            let first_line = bytes.iter().position(|&byte| byte == b'\n').map_or(bytes.len(), |end| end + 1);
+            bytes.drain(..first_line);
+        }
+        bytes
+    })
+}
+
+/// Empty the ring buffer
+pub fn clear() {
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        ring.start = 0;
+        ring.len = 0;
+        ring.wrapped = false;
+    });
+}
+
+/// TESTS
+
+#[test_case]
+fn test_filters_and_ring() {
+    set_module_level("log::test", Some(Level::Trace)).unwrap();
+    set_module_level("log", Some(Level::Error)).unwrap();
+    assert_eq!(module_level("log::test::deeper"), Level::Trace);
+    assert_eq!(module_level("log"), Level::Error);
+    assert_eq!(module_level("logger"), level());
+
+    // From this module, so filtered at Error
+    clear();
+    warn!("not kept");
+    error!("kept {}", 1);
+    let contents = contents();
+    let text = core::str::from_utf8(&contents).unwrap();
+    assert!(text.ends_with("] error log: kept 1\n"));
+    assert!(!text.contains("not kept"));
+
+    set_module_level("log::test", None).unwrap();
+    set_module_level("log", None).unwrap();
+    let mut any = false;
+    for_each_module_level(|_, _| any = true);
+    assert!(!any);
+}
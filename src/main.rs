@@ -4,29 +4,83 @@
 #![test_runner(heorot::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use heorot::println;
+use heorot::{log, println};
 
 // static HELLO: &[u8] = b"Hello World!";
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    println!("Hello world{}", "!");
+// Type-checks the signature the bootloader calls us with
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    #[cfg(feature = "hardening")]
+    heorot::stack_protector::init();
+
+    println!("{}", heorot::version());
 
     heorot::init();
+    heorot::memory::init(boot_info);
+    heorot::power::init();
+    #[cfg(feature = "kasan")]
+    heorot::kasan::init().expect("kasan initialization failed");
+    heorot::allocator::init_heap().expect("heap initialization failed");
+    heorot::workqueue::init();
+    // Its queue needs frames of its own
+    heorot::drivers::virtio_rng::init();
+    match heorot::apic::init() {
+        Ok(()) => match heorot::smp::init() {
+            Ok(cpus) if cpus > 1 => log::info!("smp: {} CPUs running", cpus),
+            Ok(_) => {}
+            Err(message) => log::warn!("smp: {}", message),
+        },
+        Err(message) => log::warn!("apic: {}; using the 8259 PIC", message),
+    }
+    // Every CPU has its GDT and IDT by now
+    #[cfg(feature = "hardening")]
+    if let Err(message) = heorot::cpu::protect_tables() {
+        log::warn!("hardening: {}", message);
+    }
 
     #[cfg(test)]
     test_main();
 
     println!("It did not crash!");
+
+    heorot::memory::reclaim::start_kswapd().expect("couldn't start kswapd");
+
+    if heorot::cmdline::get("hostchan").is_some() {
+        if let Err(message) = heorot::hostchan::start() {
+            log::warn!("hostchan: {}", message);
+        }
+    }
+    #[cfg(any(feature = "keyboard", feature = "mouse"))]
+    if let Some(path) = heorot::cmdline::get("replay") {
+        if let Err(message) = heorot::replay::play_file(path) {
+            log::warn!("replay: {}: {}", path, message);
+        }
+    }
+    if heorot::cmdline::get("selftest").is_some() {
+        heorot::selftest::boot();
+    }
+
+    #[cfg(feature = "shell")]
+    heorot::shell::run();
+
+    #[cfg(not(feature = "shell"))]
     heorot::hlt_loop();
 }
 
 /// This gets called in the event of a panic
 #[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    println!("{}", _info);
+fn panic(info: &PanicInfo) -> ! {
+    if !heorot::panic::enter(info) {
+        heorot::hlt_loop();
+    }
+    // If we died inside init() the regular console may not be usable yet
+    heorot::panic::report(info);
+    heorot::panic::run_hooks();
     heorot::hlt_loop();
 }
 
@@ -0,0 +1,24 @@
+use crate::crypto::{self, Sha256};
+use crate::image::{self, PF_W};
+use crate::{serial_print, serial_println};
+
+/// SHA-256 over the file contents of every read-only loadable segment, in
+/// program header order, as they sit in memory now. Writable segments are
+/// skipped since they change as soon as the kernel runs.
+/// tools/kernel-digest.py computes the same digest from the ELF on the host.
+pub fn kernel_digest() -> [u8; crypto::sha256::DIGEST_LEN] {
+    let mut hasher = Sha256::new();
+    for segment in image::segments().filter(|segment| segment.flags & PF_W == 0) {
+        hasher.update(segment.contents());
+    }
+    hasher.finalize()
+}
+
+/// Report the kernel digest on the serial port for CI to check
+pub fn report() {
+    serial_print!("measured boot: kernel sha256 ");
+    for byte in kernel_digest().iter() {
+        serial_print!("{:02x}", byte);
+    }
+    serial_println!();
+}
@@ -0,0 +1,446 @@
+//! Anonymous memory: regions of address space backed by nothing in
+//! particular. Pages are zero-filled the first time they're touched. When
+//! RAM runs short, or more are resident than the clock can keep track of,
+//! the ones not touched lately go to swap, and come back in when next
+//! touched. Pages ksm finds to be the same share a frame, mapped
+//! read-only, until one of them is written to.
+//!
+//! Pages move in and out with interrupts off, from the page fault handler
+//! or the shrinker, and the disk is polled while they do. A region mustn't
+//! be the buffer for a disk transfer, since a fault partway through would
+//! find the channel busy.
+
+use core::slice;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::structures::paging::{Page, PageTableEntry, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use super::{paging, rmap, swap, FRAME_SIZE, OUT_OF_MEMORY};
+
+/// Where regions are put, well away from the heap
+pub const ANON_START: u64 = 0x_5555_0000_0000;
+/// The most one region can span
+pub const REGION_SPAN: usize = 64 * 1024 * 1024;
+const MAX_REGIONS: usize = 16;
+// Pages the clock tracks, and so the most that can be resident at once (16 MiB)
+const MAX_RESIDENT: usize = 4096;
+// Frames more than one page is merged onto
+const MAX_MERGED: usize = 1024;
+
+// On a page that isn't present: it's in swap, in the slot its address says
+const SWAPPED: PageTableFlags = PageTableFlags::BIT_9;
+// On a page that is present: its frame may be shared with others with the
+// same contents, so it's read-only until written to
+const MERGED: PageTableFlags = PageTableFlags::BIT_10;
+
+struct Anon {
+    // Pages in each region, by index; 0 for one not in use
+    regions: [usize; MAX_REGIONS],
+    // Resident pages, for the clock to go round
+    resident: [Option<Page>; MAX_RESIDENT],
+    resident_count: usize,
+    hand: usize,
+    // Shared frames, and how many pages they're mapped at. A merged page
+    // whose frame isn't here is the only one left on it.
+    merged: [Option<(PhysFrame, usize)>; MAX_MERGED],
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held.
+// The page fault handler takes it, so nothing touches a region holding it.
+static ANON: Mutex<Anon> = Mutex::new(Anon {
+    regions: [0; MAX_REGIONS],
+    resident: [None; MAX_RESIDENT],
+    resident_count: 0,
+    hand: 0,
+    merged: [None; MAX_MERGED],
+});
+
+fn swap_slot(entry: &PageTableEntry) -> Option<usize> {
+    let flags = entry.flags();
+    if flags.contains(SWAPPED) && !flags.contains(PageTableFlags::PRESENT) {
+        Some((entry.addr().as_u64() / FRAME_SIZE) as usize)
+    } else {
+        None
+    }
+}
+
+impl Anon {
+    fn track(&mut self, page: Page) {
+        if let Some(slot) = self.resident.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(page);
+            self.resident_count += 1;
+        }
+    }
+
+    fn untrack(&mut self, page: Page) {
+        if let Some(slot) = self.resident.iter_mut().find(|slot| **slot == Some(page)) {
+            *slot = None;
+            self.resident_count -= 1;
+        }
+    }
+
+    // Swap out the first page the clock finds that hasn't been touched
+    // since it last came round, clearing the accessed bit on those that have
+    fn evict_one(&mut self) -> Result<(), &'static str> {
+        if !swap::enabled() {
+            return Err("swap is off");
+        }
+        // Twice round is enough: the first clears every accessed bit
+        for _ in 0..2 * MAX_RESIDENT {
+            if self.resident_count == 0 {
+                break;
+            }
+            let index = self.hand;
+            self.hand = (self.hand + 1) % MAX_RESIDENT;
+            let page = match self.resident[index] {
+                Some(page) => page,
+                None => continue,
+            };
+            let flags = unsafe {
+                paging::with_entry(page, |entry| {
+                    let flags = entry.flags();
+                    if !flags.contains(MERGED) {
+                        entry.set_flags(flags - PageTableFlags::ACCESSED);
+                    }
+                    flags
+                })
+            };
+            match flags {
+                // Swapping out a shared page wouldn't free its frame
+                Some(flags) if flags.contains(MERGED) => continue,
+                Some(flags) if flags.contains(PageTableFlags::ACCESSED) => {
+                    tlb::flush(page.start_address());
+                    continue;
+                }
+                _ => return self.swap_out(page),
+            }
+        }
+        Err("nothing to swap out")
+    }
+
+    fn swap_out(&mut self, page: Page) -> Result<(), &'static str> {
+        let frame = match unsafe { paging::with_entry(page, |entry| entry.frame().ok()) }.flatten() {
+            Some(frame) => frame,
+            None => {
+                self.untrack(page);
+                return Err("resident page isn't mapped");
+            }
+        };
+        // With interrupts off, nothing can write to it between here and the unmap
+        let slot = swap::write_out(frame)?;
+        unsafe {
+            paging::with_entry(page, |entry| entry.set_addr(PhysAddr::new(slot as u64 * FRAME_SIZE), SWAPPED));
+            tlb::flush(page.start_address());
+            rmap::remove(frame, page);
+            super::deallocate_frame(frame);
+        }
+        self.untrack(page);
+        Ok(())
+    }
+
+    // A frame for a page coming in, swapping another out for it if need be
+    fn frame(&mut self) -> Result<PhysFrame, &'static str> {
+        // Any shrinking this sets off can't get at us, since we hold the lock
+        if let Some(frame) = super::allocate_frame() {
+            return Ok(frame);
+        }
+        self.evict_one().map_err(|_| OUT_OF_MEMORY)?;
+        super::allocate_frame().ok_or(OUT_OF_MEMORY)
+    }
+
+    fn fault_in(&mut self, page: Page) -> Result<(), &'static str> {
+        let slot = unsafe { paging::with_entry(page, |entry| swap_slot(entry)) }.flatten();
+        if self.resident_count == MAX_RESIDENT {
+            self.evict_one().map_err(|_| "too many pages resident")?;
+        }
+        let frame = self.frame()?;
+        let contents = paging::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        let result = match slot {
+            Some(slot) => swap::read_in(slot, frame).map(|()| unsafe {
+                paging::with_entry(page, |entry| entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+                rmap::add(frame, page);
+                swap::free_slot(slot);
+            }),
+            None => unsafe {
+                contents.write_bytes(0, FRAME_SIZE as usize);
+                paging::map_page(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+            },
+        };
+        match result {
+            Ok(()) => self.track(page),
+            Err(_) => unsafe { super::deallocate_frame(frame) },
+        }
+        result
+    }
+
+    // Let go of `page`, wherever it is
+    fn release(&mut self, page: Page) {
+        let previous = unsafe {
+            paging::with_entry(page, |entry| {
+                let previous = (entry.frame().ok(), swap_slot(entry), entry.flags().contains(MERGED));
+                entry.set_unused();
+                previous
+            })
+        };
+        match previous {
+            Some((Some(frame), _, merged)) => {
+                tlb::flush(page.start_address());
+                self.untrack(page);
+                rmap::remove(frame, page);
+                if !merged || self.unshare(frame) {
+                    unsafe { super::deallocate_frame(frame) };
+                }
+            }
+            Some((None, Some(slot), _)) => swap::free_slot(slot),
+            _ => {}
+        }
+    }
+
+    fn sharers(&self, frame: PhysFrame) -> usize {
+        self.merged.iter().flatten().find(|(shared, _)| *shared == frame).map_or(1, |&(_, count)| count)
+    }
+
+    // One more page on `frame`, which one is already on
+    fn share(&mut self, frame: PhysFrame) -> Result<(), &'static str> {
+        if let Some((_, count)) = self.merged.iter_mut().flatten().find(|(shared, _)| *shared == frame) {
+            *count += 1;
+            return Ok(());
+        }
+        let slot = self.merged.iter_mut().find(|slot| slot.is_none()).ok_or("too many merged frames")?;
+        *slot = Some((frame, 2));
+        Ok(())
+    }
+
+    // One page fewer on `frame`: true if that was the last
+    fn unshare(&mut self, frame: PhysFrame) -> bool {
+        for slot in self.merged.iter_mut() {
+            if let Some((shared, count)) = slot {
+                if *shared == frame {
+                    *count -= 1;
+                    if *count == 1 {
+                        *slot = None;
+                    }
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    // A write to a merged page: it gets a copy of its own, or the frame
+    // itself if nothing else is on it any more
+    fn unmerge(&mut self, page: Page) -> Result<(), &'static str> {
+        let frame = match unsafe { paging::with_entry(page, |entry| entry.frame().ok()) }.flatten() {
+            Some(frame) => frame,
+            None => return Err("merged page isn't mapped"),
+        };
+        let frame = if self.sharers(frame) > 1 {
+            let copy = self.frame()?;
+            unsafe {
+                paging::phys_to_virt(frame.start_address()).as_ptr::<u8>()
+                    .copy_to_nonoverlapping(paging::phys_to_virt(copy.start_address()).as_mut_ptr(), FRAME_SIZE as usize);
+            }
+            self.unshare(frame);
+            rmap::remove(frame, page);
+            rmap::add(copy, page);
+            copy
+        } else {
+            self.unshare(frame);
+            frame
+        };
+        unsafe {
+            paging::with_entry(page, |entry| entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+        }
+        tlb::flush(page.start_address());
+        Ok(())
+    }
+
+    // Put `page` on `keep`'s frame if their contents are the same. `page`
+    // mustn't be merged already.
+    fn merge(&mut self, keep: Page, page: Page) -> Result<bool, &'static str> {
+        let (keep_frame, frame) = match (resident_frame(keep), resident_frame(page)) {
+            (Some((keep_frame, _)), Some((frame, false))) if keep_frame != frame => (keep_frame, frame),
+            _ => return Ok(false),
+        };
+        if contents(keep_frame) != contents(frame) {
+            return Ok(false);
+        }
+        self.share(keep_frame)?;
+        let flags = PageTableFlags::PRESENT | MERGED;
+        unsafe {
+            paging::with_entry(keep, |entry| entry.set_flags((entry.flags() - PageTableFlags::WRITABLE) | MERGED));
+            paging::with_entry(page, |entry| entry.set_frame(keep_frame, flags));
+            tlb::flush(keep.start_address());
+            tlb::flush(page.start_address());
+            super::deallocate_frame(frame);
+        }
+        rmap::remove(frame, page);
+        rmap::add(keep_frame, page);
+        Ok(true)
+    }
+}
+
+// The frame `page` is on, and whether it's merged, if it's in RAM
+fn resident_frame(page: Page) -> Option<(PhysFrame, bool)> {
+    unsafe { paging::with_entry(page, |entry| entry.frame().ok().map(|frame| (frame, entry.flags().contains(MERGED)))) }
+        .flatten()
+}
+
+fn contents(frame: PhysFrame) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(paging::phys_to_virt(frame.start_address()).as_ptr(), FRAME_SIZE as usize) }
+}
+
+/// Called by the page fault handler for a page that isn't present: None if
+/// the address isn't in a region, otherwise whether it could be brought in
+pub(crate) fn handle_fault(addr: VirtAddr) -> Option<Result<(), &'static str>> {
+    let offset = addr.as_u64().checked_sub(ANON_START)? as usize;
+    let index = offset / REGION_SPAN;
+    if index >= MAX_REGIONS {
+        return None;
+    }
+    interrupts::without_interrupts(|| {
+        let mut anon = ANON.lock();
+        if offset % REGION_SPAN >= anon.regions[index] * FRAME_SIZE as usize {
+            return None;
+        }
+        Some(anon.fault_in(Page::containing_address(addr)))
+    })
+}
+
+/// Called by the page fault handler for a write to a page that's mapped
+/// read-only: None if it isn't a merged page in a region, otherwise whether
+/// it could be made writable
+pub(crate) fn handle_write_fault(addr: VirtAddr) -> Option<Result<(), &'static str>> {
+    addr.as_u64().checked_sub(ANON_START).filter(|&offset| offset < (MAX_REGIONS * REGION_SPAN) as u64)?;
+    let page = Page::containing_address(addr);
+    interrupts::without_interrupts(|| {
+        let mut anon = ANON.lock();
+        match resident_frame(page) {
+            Some((_, true)) => Some(anon.unmerge(page)),
+            _ => None,
+        }
+    })
+}
+
+/// The resident pages, for ksm to go through
+pub(super) fn resident_pages() -> alloc::vec::Vec<Page> {
+    interrupts::without_interrupts(|| ANON.lock().resident.iter().flatten().copied().collect())
+}
+
+/// A hash of what's in `page`, if it's in RAM
+pub(super) fn hash_page(page: Page) -> Option<u64> {
+    interrupts::without_interrupts(|| {
+        let _anon = ANON.lock();
+        let (frame, _) = resident_frame(page)?;
+        // FNV-1a
+        Some(contents(frame).iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+        }))
+    })
+}
+
+/// Put `page` on `keep`'s frame, read-only, if they hold the same bytes
+/// and `page` isn't merged already; true if it was
+pub(super) fn merge(keep: Page, page: Page) -> Result<bool, &'static str> {
+    interrupts::without_interrupts(|| ANON.lock().merge(keep, page))
+}
+
+/// How many frames are shared, and how many pages are on them
+pub(super) fn merged() -> (usize, usize) {
+    interrupts::without_interrupts(|| {
+        let anon = ANON.lock();
+        anon.merged.iter().flatten().fold((0, 0), |(frames, pages), &(_, count)| (frames + 1, pages + count))
+    })
+}
+
+/// Shrinker for reclaim: swap out up to `wanted` pages, and say how many
+pub(super) fn shrink(wanted: usize) -> usize {
+    let mut freed = 0;
+    while freed < wanted {
+        // A page at a time, so interrupts aren't off for the whole lot.
+        // Busy means this came from an allocation anon itself made.
+        let evicted = interrupts::without_interrupts(|| match ANON.try_lock() {
+            Some(mut anon) => anon.evict_one().is_ok(),
+            None => false,
+        });
+        if !evicted {
+            break;
+        }
+        freed += 1;
+    }
+    freed
+}
+
+/// Anonymous memory that starts out zeroed. Dropping it frees its pages,
+/// in RAM and in swap.
+pub struct Region {
+    index: usize,
+    pages: usize,
+}
+
+impl Region {
+    /// At least `len` bytes, rounded up to whole pages
+    pub fn new(len: usize) -> Result<Region, &'static str> {
+        if len == 0 || len > REGION_SPAN {
+            return Err("bad region size");
+        }
+        let pages = (len + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+        interrupts::without_interrupts(|| {
+            let mut anon = ANON.lock();
+            let index = anon.regions.iter().position(|&pages| pages == 0).ok_or("too many regions")?;
+            anon.regions[index] = pages;
+            Ok(Region { index, pages })
+        })
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        VirtAddr::new(ANON_START + (self.index * REGION_SPAN) as u64)
+    }
+
+    /// In bytes: always whole pages
+    pub fn len(&self) -> usize {
+        self.pages * FRAME_SIZE as usize
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // Ours alone, and faulted in as it's touched
+        unsafe { slice::from_raw_parts(self.start().as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.start().as_mut_ptr(), self.len()) }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        let first: Page<Size4KiB> = Page::containing_address(self.start());
+        interrupts::without_interrupts(|| {
+            let mut anon = ANON.lock();
+            for page in Page::range(first, first + self.pages as u64) {
+                anon.release(page);
+            }
+            anon.regions[self.index] = 0;
+        });
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_regions_fill_with_zeroes_on_touch() {
+    let free = || super::with_frame_allocator(|frames| frames.free_frames()).unwrap();
+    let mut region = Region::new(3 * FRAME_SIZE as usize - 1).unwrap();
+    assert_eq!(region.len(), 3 * FRAME_SIZE as usize);
+    let before = free();
+    assert!(region.as_slice().iter().all(|&byte| byte == 0));
+    region.as_mut_slice()[FRAME_SIZE as usize] = 7;
+    assert_eq!(region.as_slice()[FRAME_SIZE as usize], 7);
+    assert!(free() < before);
+
+    drop(region);
+    // The next region in its place starts out zeroed again
+    let region = Region::new(FRAME_SIZE as usize).unwrap();
+    assert_eq!(region.as_slice()[0], 0);
+    assert_eq!(Region::new(REGION_SPAN + 1).err(), Some("bad region size"));
+}
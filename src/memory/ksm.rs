@@ -0,0 +1,149 @@
+//! Same-page merging, and experimental: ksmd goes through the resident
+//! anonymous pages now and then, and puts pages with the same contents
+//! (zeroed ones, mostly) on one frame, mapped read-only. Writing to one
+//! gets it a copy of its own again; see `anon`. Off until `start`ed.
+
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use crate::sched::{self, ThreadId};
+use crate::time::{self, Duration};
+use super::{anon, FRAME_SIZE};
+
+// Between one pass and the next
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// Only ever locked with interrupts off, so a handler can't find it held
+static KSMD: Mutex<Option<ThreadId>> = Mutex::new(None);
+static SCANS: AtomicU64 = AtomicU64::new(0);
+static MERGES: AtomicU64 = AtomicU64::new(0);
+
+/// What merging has done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub running: bool,
+    /// Passes over resident memory since boot
+    pub scans: u64,
+    /// Pages put on another's frame since boot
+    pub merges: u64,
+    /// Frames shared right now, and the pages on them
+    pub shared_frames: usize,
+    pub sharing_pages: usize,
+}
+
+impl Stats {
+    /// Memory not taken up thanks to sharing, in bytes
+    pub fn saved(&self) -> u64 {
+        (self.sharing_pages - self.shared_frames) as u64 * FRAME_SIZE
+    }
+}
+
+pub fn stats() -> Stats {
+    let (shared_frames, sharing_pages) = anon::merged();
+    Stats {
+        running: RUNNING.load(Ordering::Relaxed),
+        scans: SCANS.load(Ordering::Relaxed),
+        merges: MERGES.load(Ordering::Relaxed),
+        shared_frames,
+        sharing_pages,
+    }
+}
+
+/// Go through every resident page once, merging each onto the first one
+/// before it with the same contents. Returns how many it merged.
+pub fn scan() -> usize {
+    let mut seen = BTreeMap::new();
+    let mut merged = 0;
+    for page in anon::resident_pages() {
+        // Gone since the list was taken, or swapped out
+        let hash = match anon::hash_page(page) {
+            Some(hash) => hash,
+            None => continue,
+        };
+        match seen.entry(hash) {
+            Entry::Vacant(entry) => {
+                entry.insert(page);
+            }
+            // A hash collision, or a page written to since, just doesn't merge
+            Entry::Occupied(entry) => {
+                if anon::merge(*entry.get(), page) == Ok(true) {
+                    merged += 1;
+                }
+            }
+        }
+    }
+    SCANS.fetch_add(1, Ordering::Relaxed);
+    MERGES.fetch_add(merged as u64, Ordering::Relaxed);
+    merged
+}
+
+fn ksmd() {
+    while RUNNING.load(Ordering::Relaxed) {
+        scan();
+        time::sleep(SCAN_INTERVAL);
+    }
+    interrupts::without_interrupts(|| *KSMD.lock() = None);
+    sched::exit();
+}
+
+/// Start merging in the background
+pub fn start() -> Result<(), &'static str> {
+    // Without it, the kernel's writes go straight through read-only pages
+    if !Cr0::read().contains(Cr0Flags::WRITE_PROTECT) {
+        return Err("needs CR0.WP");
+    }
+    if interrupts::without_interrupts(|| KSMD.lock().is_some()) {
+        return Err("ksmd is already running");
+    }
+    RUNNING.store(true, Ordering::Relaxed);
+    match sched::spawn("ksmd", ksmd) {
+        Ok(id) => {
+            interrupts::without_interrupts(|| *KSMD.lock() = Some(id));
+            Ok(())
+        }
+        Err(message) => {
+            RUNNING.store(false, Ordering::Relaxed);
+            Err(message)
+        }
+    }
+}
+
+/// Stop merging after the pass in progress. Pages already merged stay so
+/// until written to.
+pub fn stop() {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+/// TESTS
+
+#[test_case]
+fn test_same_pages_share_until_written() {
+    let free = || super::with_frame_allocator(|frames| frames.free_frames()).unwrap();
+    let page = FRAME_SIZE as usize;
+    let mut region = anon::Region::new(3 * page).unwrap();
+    let bytes = region.as_mut_slice();
+    bytes[..page].fill(0x5a);
+    bytes[page..2 * page].fill(0xa5);
+    bytes[2 * page..].fill(0x5a);
+    let (before, frames_before) = (stats(), free());
+
+    assert_eq!(scan(), 1);
+    let after = stats();
+    assert_eq!(after.saved() - before.saved(), FRAME_SIZE);
+    assert_eq!(free(), frames_before + 1);
+    // Nothing new the second time round
+    assert_eq!(scan(), 0);
+
+    // The copy on write leaves the other page as it was
+    region.as_mut_slice()[2 * page] = 1;
+    assert_eq!(region.as_slice()[2 * page], 1);
+    assert!(region.as_slice()[..page].iter().all(|&byte| byte == 0x5a));
+    assert_eq!(stats().saved(), before.saved());
+    // And the one left on the frame can write to it again
+    region.as_mut_slice()[0] = 2;
+    assert_eq!(region.as_slice()[0], 2);
+    assert_eq!(stats().shared_frames, before.shared_frames);
+}
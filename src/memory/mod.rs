@@ -0,0 +1,475 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::BootInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+use crate::events::{self, Event};
+use crate::log;
+use numa::{Topology, MAX_NODES};
+
+pub mod anon;
+pub mod ksm;
+pub mod numa;
+pub mod paging;
+pub mod reclaim;
+pub mod rmap;
+pub mod swap;
+
+pub const FRAME_SIZE: u64 = 4096;
+// Ends a free list; no frame is there
+const END: u64 = u64::MAX;
+/// Free frames below which subscribers get a LowMemory event (4 MiB)
+pub const LOW_MEMORY_FRAMES: usize = 1024;
+/// What fails for want of a free frame
+pub const OUT_OF_MEMORY: &str = "out of memory";
+
+// Whether the last allocation left us below LOW_MEMORY_FRAMES
+static LOW: AtomicBool = AtomicBool::new(false);
+// A frame below 1 MiB set aside at boot, or 0
+static LOW_FRAME: AtomicU64 = AtomicU64::new(0);
+// Real mode reaches no further
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Which node's memory a frame should come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Whatever's free: freed frames first, then fresh ones node by node
+    Any,
+    /// From `node` while it has any, then from the nodes nearest it
+    Preferred(usize),
+    /// From `node` or not at all
+    Bind(usize),
+}
+
+// Where the next never-used frame on a node comes from: a region of the
+// memory map, and how far in
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    region: usize,
+    offset: u64,
+}
+
+/// Hands out the 4 KiB frames the bootloader's memory map marks usable.
+/// Each NUMA node's are taken in map order; freed ones go on their node's
+/// free list and are handed out again before any fresh ones. The lists
+/// are kept in the freed frames themselves, each starting with the address
+/// of the next, so there's no limit on how many there are.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    // Where all of physical memory is mapped, for reaching freed frames
+    phys_offset: u64,
+    topology: Topology,
+    cursors: [Cursor; MAX_NODES],
+    // The most recently freed frame on each node, or END
+    freed: [u64; MAX_NODES],
+    freed_counts: [usize; MAX_NODES],
+}
+
+impl BootInfoFrameAllocator {
+    /// # Safety
+    /// Every frame `memory_map` marks usable must really be unused, and only
+    /// one allocator may be created from it. All of physical memory must be
+    /// mapped, writable, from `phys_offset` on.
+    pub unsafe fn init(memory_map: &'static MemoryMap, phys_offset: u64) -> BootInfoFrameAllocator {
+        BootInfoFrameAllocator {
+            memory_map,
+            phys_offset,
+            topology: Topology::uniform(),
+            cursors: [Cursor { region: 0, offset: 0 }; MAX_NODES],
+            freed: [END; MAX_NODES],
+            freed_counts: [0; MAX_NODES],
+        }
+    }
+
+    /// Start telling frames apart by node. Until now there was only node 0,
+    /// so everything before its cursor is taken, whatever node it's on, and
+    /// every freed frame is on its list.
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = topology;
+        self.cursors = [self.cursors[0]; MAX_NODES];
+        let mut freed = self.freed[0];
+        self.freed[0] = END;
+        self.freed_counts[0] = 0;
+        while freed != END {
+            let frame = PhysFrame::containing_address(PhysAddr::new(freed));
+            freed = unsafe { self.next_freed(frame).read() };
+            unsafe { self.push_freed(frame) };
+        }
+    }
+
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    fn next_fresh(&mut self, node: usize) -> Option<PhysFrame> {
+        let cursor = &mut self.cursors[node];
+        while let Some(region) = self.memory_map.get(cursor.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                let start = region.range.start_addr() + cursor.offset;
+                if start + FRAME_SIZE <= region.range.end_addr() {
+                    let (owner, end) = self.topology.span(PhysAddr::new(start));
+                    if owner == node {
+                        cursor.offset += FRAME_SIZE;
+                        return Some(PhysFrame::containing_address(PhysAddr::new(start)));
+                    }
+                    // Past the other node's stretch, maybe to the region's end
+                    cursor.offset = end.min(region.range.end_addr()) - region.range.start_addr();
+                    continue;
+                }
+            }
+            cursor.region += 1;
+            cursor.offset = 0;
+        }
+        None
+    }
+
+    // Fresh frames left on `node`
+    fn fresh_frames(&self, node: usize) -> u64 {
+        let cursor = self.cursors[node];
+        let mut fresh = 0;
+        for (index, region) in self.memory_map.iter().enumerate().skip(cursor.region) {
+            if region.region_type != MemoryRegionType::Usable {
+                continue;
+            }
+            let used = if index == cursor.region { cursor.offset } else { 0 };
+            let mut at = region.range.start_addr() + used;
+            while at < region.range.end_addr() {
+                let (owner, end) = self.topology.span(PhysAddr::new(at));
+                let end = end.min(region.range.end_addr());
+                if owner == node {
+                    fresh += (end - at) / FRAME_SIZE;
+                }
+                at = end;
+            }
+        }
+        fresh
+    }
+
+    // Where a freed frame keeps the address of the next one on its list
+    fn next_freed(&self, frame: PhysFrame) -> *mut u64 {
+        self.phys_offset.wrapping_add(frame.start_address().as_u64()) as *mut u64
+    }
+
+    // Safety: `frame` is free, and on no list
+    unsafe fn push_freed(&mut self, frame: PhysFrame) {
+        let node = self.topology.node_of(frame.start_address());
+        self.next_freed(frame).write(self.freed[node]);
+        self.freed[node] = frame.start_address().as_u64();
+        self.freed_counts[node] += 1;
+    }
+
+    // The most recently freed frame on `node`, or on any node for None
+    fn take_freed(&mut self, node: Option<usize>) -> Option<PhysFrame> {
+        let node = match node {
+            Some(node) => node,
+            None => (0..self.topology.nodes()).find(|&node| self.freed[node] != END)?,
+        };
+        if self.freed[node] == END {
+            return None;
+        }
+        let frame = PhysFrame::containing_address(PhysAddr::new(self.freed[node]));
+        // Only frames on the list are written to, and this one still is
+        self.freed[node] = unsafe { self.next_freed(frame).read() };
+        self.freed_counts[node] -= 1;
+        Some(frame)
+    }
+
+    fn from_node(&mut self, node: usize) -> Option<PhysFrame> {
+        self.take_freed(Some(node)).or_else(|| self.next_fresh(node))
+    }
+
+    /// A frame from where `policy` says. There's no node bigger than the
+    /// topology's count to bind to, and preferring one is the same as Any.
+    pub fn allocate(&mut self, policy: Policy) -> Option<PhysFrame> {
+        let nodes = self.topology.nodes();
+        match policy {
+            Policy::Bind(node) if node < nodes => self.from_node(node),
+            Policy::Bind(_) => None,
+            Policy::Preferred(node) if node < nodes => {
+                let (order, count) = self.topology.nearest(node);
+                order[..count].iter().find_map(|&node| self.from_node(node))
+            }
+            Policy::Preferred(_) | Policy::Any => {
+                self.take_freed(None).or_else(|| (0..nodes).find_map(|node| self.next_fresh(node)))
+            }
+        }
+    }
+
+    /// `count` fresh frames in a row, for hardware that needs contiguous
+    /// memory; the first of them. Each goes back on its own once freed, so
+    /// only never-used frames are contiguous enough, and fresh runs that
+    /// turn out too short are freed on the way.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        for node in 0..self.topology.nodes() {
+            let mut run: Option<(PhysFrame, usize)> = None;
+            while let Some(frame) = self.next_fresh(node) {
+                let (first, len) = match run {
+                    Some((first, len)) if first + len as u64 == frame => (first, len + 1),
+                    Some((first, len)) => {
+                        self.free_run(first, len);
+                        (frame, 1)
+                    }
+                    None => (frame, 1),
+                };
+                if len == count {
+                    return Some(first);
+                }
+                run = Some((first, len));
+            }
+            if let Some((first, len)) = run {
+                self.free_run(first, len);
+            }
+        }
+        None
+    }
+
+    fn free_run(&mut self, first: PhysFrame, len: usize) {
+        for index in 0..len as u64 {
+            // Just taken fresh, and handed to no one
+            unsafe { self.push_freed(first + index) };
+        }
+    }
+
+    /// Frames that can still be allocated, fresh or reused
+    pub fn free_frames(&self) -> usize {
+        let fresh: u64 = (0..self.topology.nodes()).map(|node| self.fresh_frames(node)).sum();
+        fresh as usize + self.freed_counts.iter().sum::<usize>()
+    }
+
+    /// Frames that can still be allocated from `node`
+    pub fn free_frames_on(&self, node: usize) -> usize {
+        if node >= self.topology.nodes() {
+            return 0;
+        }
+        self.fresh_frames(node) as usize + self.freed_counts[node]
+    }
+
+    /// Frames the memory map marks usable in total
+    pub fn usable_frames(&self) -> usize {
+        self.memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .map(|region| ((region.range.end_addr() - region.range.start_addr()) / FRAME_SIZE) as usize)
+            .sum()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(Policy::Any)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.push_freed(frame);
+    }
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static FRAMES: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// Start handing out frames from the bootloader's memory map, and take over
+/// its page tables. Only the first call does anything; the map can't be
+/// trusted twice.
+pub fn init(boot_info: &'static BootInfo) {
+    let first = interrupts::without_interrupts(|| {
+        let mut frames = FRAMES.lock();
+        if frames.is_some() {
+            return false;
+        }
+        // The bootloader marks everything it or the kernel uses as taken
+        let mut allocator =
+            unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, boot_info.physical_memory_offset) };
+        // Before anything else can take them all. Frame 0 is the BIOS's,
+        // whatever the map says, so it's never handed out.
+        match allocator.allocate(Policy::Any) {
+            Some(frame) if frame.start_address().as_u64() == 0 => {
+                if let Some(frame) = allocator.allocate(Policy::Any) {
+                    reserve_low(&mut allocator, frame);
+                }
+            }
+            Some(frame) => reserve_low(&mut allocator, frame),
+            None => {}
+        }
+        *frames = Some(allocator);
+        true
+    });
+    if first {
+        unsafe { paging::init(boot_info.physical_memory_offset) };
+        // Needs paging, to read the ACPI tables through
+        match numa::read() {
+            Ok(Some(topology)) => {
+                with_frame_allocator(|frames| frames.set_topology(topology));
+            }
+            Ok(None) => {}
+            Err(message) => log::warn!("numa: {}", message),
+        }
+    }
+}
+
+fn reserve_low(allocator: &mut BootInfoFrameAllocator, frame: PhysFrame) {
+    if frame.start_address().as_u64() < LOW_MEMORY_END {
+        LOW_FRAME.store(frame.start_address().as_u64(), Ordering::Relaxed);
+    } else {
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+}
+
+/// The frame below 1 MiB that `init` set aside, for code that has to start
+/// out in real mode, if there was one free
+pub fn low_frame() -> Option<PhysFrame> {
+    match LOW_FRAME.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+/// Run `f` with the kernel's frame allocator, or None before `init`
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut BootInfoFrameAllocator) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| FRAMES.lock().as_mut().map(f))
+}
+
+/// A free physical frame, or None when memory is exhausted (or before `init`).
+/// Dropping below LOW_MEMORY_FRAMES publishes a LowMemory event; running
+/// short starts reclaim (see `reclaim`).
+pub fn allocate_frame() -> Option<PhysFrame> {
+    allocate_frame_with(Policy::Any)
+}
+
+/// `allocate_frame`, from the node `policy` says
+pub fn allocate_frame_with(policy: Policy) -> Option<PhysFrame> {
+    let take = || with_frame_allocator(|frames| (frames.allocate(policy), frames.free_frames()));
+    let (mut frame, mut free) = take()?;
+    if frame.is_none() && reclaim::shrink(1) > 0 {
+        // Out altogether; one more try with whatever the shrinkers gave back
+        let retry = take()?;
+        frame = retry.0;
+        free = retry.1;
+    }
+    // Once per dip, not once per frame taken while low
+    let low = free < LOW_MEMORY_FRAMES;
+    let was_low = LOW.swap(low, Ordering::Relaxed);
+    if low && !was_low {
+        events::publish(Event::LowMemory { free_frames: free });
+    }
+    reclaim::note_free(free);
+    frame
+}
+
+/// `count` physically contiguous frames, the first of them, or None if
+/// there's no such run left; see BootInfoFrameAllocator::allocate_contiguous
+pub fn allocate_contiguous(count: usize) -> Option<PhysFrame> {
+    with_frame_allocator(|frames| frames.allocate_contiguous(count)).flatten()
+}
+
+/// Give a frame back for reuse.
+///
+/// # Safety
+/// The frame must have come from `allocate_frame` and must no longer be in use.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    with_frame_allocator(|frames| frames.deallocate_frame(frame));
+}
+
+/// TESTS
+
+#[test_case]
+fn test_frames_are_distinct_and_reused() {
+    let first = allocate_frame().expect("no frames");
+    let second = allocate_frame().expect("no frames");
+    assert_ne!(first, second);
+    assert_eq!(first.start_address().as_u64() % FRAME_SIZE, 0);
+
+    let free = with_frame_allocator(|frames| frames.free_frames()).unwrap();
+    unsafe { deallocate_frame(second) };
+    assert_eq!(with_frame_allocator(|frames| frames.free_frames()), Some(free + 1));
+    assert_eq!(allocate_frame(), Some(second));
+    unsafe {
+        deallocate_frame(second);
+        deallocate_frame(first);
+    }
+}
+
+#[test_case]
+fn test_free_list_has_no_limit() {
+    use alloc::vec::Vec;
+
+    // All while the allocator's held, so nothing else takes any meanwhile
+    with_frame_allocator(|frames| {
+        let free = frames.free_frames();
+        let mut taken: Vec<PhysFrame> = (0..1000).map(|_| frames.allocate(Policy::Bind(0)).unwrap()).collect();
+        for frame in taken.iter() {
+            unsafe { frames.deallocate_frame(*frame) };
+        }
+        assert_eq!(frames.free_frames(), free);
+        // The most recently freed come back first
+        let mut again: Vec<PhysFrame> = (0..1000).map(|_| frames.allocate(Policy::Bind(0)).unwrap()).collect();
+        assert_eq!(again.first(), taken.last());
+        taken.sort();
+        again.sort();
+        assert_eq!(again, taken);
+        for frame in taken {
+            unsafe { frames.deallocate_frame(frame) };
+        }
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn test_policies_pick_nodes() {
+    use alloc::boxed::Box;
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    // Made up, so nothing may be written to these frames. The one freed
+    // below is reached through a made-up offset, at LINK.
+    static mut LINK: u64 = 0;
+    let mut map = MemoryMap::new();
+    map.add_region(MemoryRegion { range: FrameRange::new(0x10_0000, 0x20_0000), region_type: MemoryRegionType::Usable });
+    let phys_offset = unsafe { core::ptr::addr_of_mut!(LINK) } as u64;
+    let map = Box::leak(Box::new(map));
+    let mut frames = unsafe { BootInfoFrameAllocator::init(map, phys_offset.wrapping_sub(0x18_0000)) };
+    let frame = |addr: u64| Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+    assert_eq!(frames.allocate(Policy::Any), frame(0x10_0000));
+    frames.set_topology(numa::two_nodes(0x10_0000, 0x18_0000, 0x20_0000));
+    assert_eq!(frames.free_frames_on(1), 128);
+    assert_eq!(frames.free_frames(), 255);
+
+    assert_eq!(frames.allocate(Policy::Bind(1)), frame(0x18_0000));
+    assert_eq!(frames.allocate(Policy::Preferred(1)), frame(0x18_1000));
+    assert_eq!(frames.allocate(Policy::Any), frame(0x10_1000));
+    assert_eq!(frames.allocate(Policy::Bind(2)), None);
+    // A freed frame goes back to its own node
+    unsafe { frames.deallocate_frame(PhysFrame::containing_address(PhysAddr::new(0x18_0000))) };
+    assert_eq!(frames.allocate(Policy::Bind(0)), frame(0x10_2000));
+    assert_eq!(frames.allocate(Policy::Bind(1)), frame(0x18_0000));
+
+    // Node 1 runs out, and Preferred moves on to node 0
+    while frames.allocate(Policy::Bind(1)).is_some() {}
+    assert_eq!(frames.free_frames_on(1), 0);
+    assert_eq!(frames.allocate(Policy::Preferred(1)), frame(0x10_3000));
+}
+
+#[test_case]
+fn test_contiguous_frames_skip_short_runs() {
+    use alloc::boxed::Box;
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    // Made up as above; the lone first frame is the one freed, at LINK
+    static mut LINK: u64 = 0;
+    let mut map = MemoryMap::new();
+    let usable = |start, end| MemoryRegion {
+        range: FrameRange::new(start, end),
+        region_type: MemoryRegionType::Usable,
+    };
+    map.add_region(usable(0x10_0000, 0x10_1000));
+    map.add_region(usable(0x30_0000, 0x30_3000));
+    let phys_offset = unsafe { core::ptr::addr_of_mut!(LINK) } as u64;
+    let map = Box::leak(Box::new(map));
+    let mut frames = unsafe { BootInfoFrameAllocator::init(map, phys_offset.wrapping_sub(0x10_0000)) };
+    let frame = |addr: u64| Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+    assert_eq!(frames.allocate_contiguous(2), frame(0x30_0000));
+    assert_eq!(frames.allocate(Policy::Any), frame(0x10_0000));
+    assert_eq!(frames.allocate(Policy::Any), frame(0x30_2000));
+}
@@ -0,0 +1,274 @@
+//! NUMA topology from the ACPI SRAT and SLIT: which node each range of
+//! physical memory (and each CPU, by APIC ID) belongs to, and how far apart
+//! the nodes are. Nodes are numbered from 0 in the order the SRAT first
+//! mentions their proximity domains. Memory it doesn't cover, and all of
+//! it on a machine without an SRAT, is node 0.
+
+use x86_64::PhysAddr;
+use crate::acpi;
+use super::FRAME_SIZE;
+
+pub const MAX_NODES: usize = 8;
+const MAX_RANGES: usize = 32;
+const MAX_CPUS: usize = 64;
+/// How far the SLIT says a node is from itself
+pub const LOCAL_DISTANCE: u8 = 10;
+/// How far apart two nodes are taken to be without a SLIT
+pub const REMOTE_DISTANCE: u8 = 20;
+
+// Everything in the SRAT after its header and reserved fields
+const SRAT_ENTRIES: usize = acpi::HEADER_SIZE + 12;
+const SRAT_PROCESSOR: u8 = 0;
+const SRAT_MEMORY: u8 = 1;
+const SRAT_X2APIC: u8 = 2;
+const SRAT_ENABLED: u32 = 1;
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(u32_at(bytes, offset)) | (u64::from(u32_at(bytes, offset + 4)) << 32)
+}
+
+fn align_up(addr: u64) -> u64 {
+    addr.checked_add(FRAME_SIZE - 1).map_or(u64::MAX, |addr| addr / FRAME_SIZE * FRAME_SIZE)
+}
+
+/// Physical memory `[start, end)` on `node`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRange {
+    pub start: u64,
+    pub end: u64,
+    pub node: usize,
+}
+
+/// The nodes, and what's on each
+#[derive(Debug, Clone)]
+pub struct Topology {
+    nodes: usize,
+    // The proximity domain each node stands for
+    domains: [u32; MAX_NODES],
+    // By start address
+    ranges: [Option<MemoryRange>; MAX_RANGES],
+    // APIC IDs, and their nodes
+    cpus: [Option<(u32, usize)>; MAX_CPUS],
+    distances: [[u8; MAX_NODES]; MAX_NODES],
+}
+
+impl Topology {
+    /// One node with everything on it
+    pub const fn uniform() -> Topology {
+        let mut distances = [[REMOTE_DISTANCE; MAX_NODES]; MAX_NODES];
+        let mut node = 0;
+        while node < MAX_NODES {
+            distances[node][node] = LOCAL_DISTANCE;
+            node += 1;
+        }
+        Topology { nodes: 1, domains: [0; MAX_NODES], ranges: [None; MAX_RANGES], cpus: [None; MAX_CPUS], distances }
+    }
+
+    /// From the bytes of an SRAT, and a SLIT if there is one
+    pub fn parse(srat: &[u8], slit: Option<&[u8]>) -> Result<Topology, &'static str> {
+        let mut topology = Topology::uniform();
+        topology.nodes = 0;
+        let mut at = SRAT_ENTRIES;
+        while at + 2 <= srat.len() {
+            let (kind, len) = (srat[at], usize::from(srat[at + 1]));
+            if len < 2 || at + len > srat.len() {
+                return Err("SRAT entry is cut off");
+            }
+            let entry = &srat[at..at + len];
+            at += len;
+            match kind {
+                SRAT_PROCESSOR if len >= 16 && u32_at(entry, 4) & SRAT_ENABLED != 0 => {
+                    let domain = u32::from(entry[2]) | (u32_at(entry, 8) & 0xffff_ff00);
+                    let node = topology.node_for(domain)?;
+                    topology.add_cpu(u32::from(entry[3]), node);
+                }
+                SRAT_X2APIC if len >= 24 && u32_at(entry, 12) & SRAT_ENABLED != 0 => {
+                    let node = topology.node_for(u32_at(entry, 4))?;
+                    topology.add_cpu(u32_at(entry, 8), node);
+                }
+                SRAT_MEMORY if len >= 40 && u32_at(entry, 28) & SRAT_ENABLED != 0 => {
+                    let (start, size) = (u64_at(entry, 8), u64_at(entry, 16));
+                    if size > 0 {
+                        let node = topology.node_for(u32_at(entry, 2))?;
+                        topology.add_range(MemoryRange { start, end: start.saturating_add(size), node })?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        topology.nodes = topology.nodes.max(1);
+        if let Some(slit) = slit {
+            topology.read_slit(slit)?;
+        }
+        Ok(topology)
+    }
+
+    fn node_for(&mut self, domain: u32) -> Result<usize, &'static str> {
+        if let Some(node) = self.domains[..self.nodes].iter().position(|&known| known == domain) {
+            return Ok(node);
+        }
+        if self.nodes == MAX_NODES {
+            return Err("too many NUMA nodes");
+        }
+        self.domains[self.nodes] = domain;
+        self.nodes += 1;
+        Ok(self.nodes - 1)
+    }
+
+    fn add_cpu(&mut self, apic_id: u32, node: usize) {
+        // Any past the table are left on node 0
+        if let Some(slot) = self.cpus.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((apic_id, node));
+        }
+    }
+
+    fn add_range(&mut self, range: MemoryRange) -> Result<(), &'static str> {
+        let count = self.ranges.iter().filter(|range| range.is_some()).count();
+        if count == MAX_RANGES {
+            return Err("too many NUMA memory ranges");
+        }
+        let index = self.ranges[..count].iter().flatten().position(|other| other.start > range.start).unwrap_or(count);
+        self.ranges[index..=count].rotate_right(1);
+        self.ranges[index] = Some(range);
+        Ok(())
+    }
+
+    // The SLIT is a matrix of distances between proximity domains
+    fn read_slit(&mut self, slit: &[u8]) -> Result<(), &'static str> {
+        let header = acpi::HEADER_SIZE + 8;
+        if slit.len() < header {
+            return Err("SLIT is cut off");
+        }
+        let localities = u64_at(slit, acpi::HEADER_SIZE);
+        let matrix = &slit[header..];
+        if localities.checked_mul(localities).map_or(true, |len| len > matrix.len() as u64) {
+            return Err("SLIT is cut off");
+        }
+        let localities = localities as usize;
+        let domains = self.domains;
+        for (from, distances) in self.distances.iter_mut().enumerate().take(self.nodes) {
+            for (to, distance) in distances.iter_mut().enumerate().take(self.nodes) {
+                let (row, column) = (domains[from] as usize, domains[to] as usize);
+                if row < localities && column < localities {
+                    *distance = matrix[row * localities + column];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.nodes
+    }
+
+    /// The memory ranges the SRAT gave, by start address
+    pub fn ranges(&self) -> impl Iterator<Item = MemoryRange> + '_ {
+        self.ranges.iter().flatten().copied()
+    }
+
+    /// The node `addr` is on, and where the memory from it on that's all on
+    /// that node ends (rounded up to a whole frame)
+    pub fn span(&self, addr: PhysAddr) -> (usize, u64) {
+        let addr = addr.as_u64();
+        match self.ranges().find(|range| range.end > addr) {
+            Some(range) if range.start <= addr => (range.node, align_up(range.end)),
+            Some(next) => (0, align_up(next.start)),
+            None => (0, u64::MAX),
+        }
+    }
+
+    pub fn node_of(&self, addr: PhysAddr) -> usize {
+        self.span(addr).0
+    }
+
+    /// The node of the CPU with `apic_id`, if the SRAT lists it
+    pub fn cpu_node(&self, apic_id: u32) -> Option<usize> {
+        self.cpus.iter().flatten().find(|(id, _)| *id == apic_id).map(|&(_, node)| node)
+    }
+
+    /// As the SLIT gives it: LOCAL_DISTANCE is a node to itself
+    pub fn distance(&self, from: usize, to: usize) -> u8 {
+        self.distances[from][to]
+    }
+
+    /// Every node, nearest to `node` first (so `node` itself leads)
+    pub fn nearest(&self, node: usize) -> ([usize; MAX_NODES], usize) {
+        let mut order = [0; MAX_NODES];
+        for (index, slot) in order.iter_mut().enumerate() {
+            *slot = index;
+        }
+        let nodes = &mut order[..self.nodes];
+        // Ties go by node number, but with `node` ahead of its equals
+        nodes.sort_unstable_by_key(|&other| (self.distance(node, other), other != node, other));
+        (order, self.nodes)
+    }
+}
+
+/// The topology the firmware describes, or None without an SRAT
+pub fn read() -> Result<Option<Topology>, &'static str> {
+    let srat = match acpi::find(b"SRAT") {
+        Some(srat) => srat,
+        None => return Ok(None),
+    };
+    let slit = acpi::find(b"SLIT");
+    Topology::parse(srat.bytes(), slit.as_ref().map(acpi::Table::bytes)).map(Some)
+}
+
+/// TESTS
+
+// An SRAT with two nodes' memory, split at `split`, and a CPU on each
+#[cfg(test)]
+pub(super) fn two_nodes(start: u64, split: u64, end: u64) -> Topology {
+    let mut srat = alloc::vec![0u8; SRAT_ENTRIES];
+    for (domain, apic_id) in [(7u8, 0u8), (3, 1)].iter() {
+        let mut cpu = [0u8; 16];
+        cpu[..4].copy_from_slice(&[SRAT_PROCESSOR, 16, *domain, *apic_id]);
+        cpu[4] = SRAT_ENABLED as u8;
+        srat.extend_from_slice(&cpu);
+    }
+    for &(domain, from, to) in [(3u32, split, end), (7, start, split)].iter() {
+        let mut memory = [0u8; 40];
+        memory[..2].copy_from_slice(&[SRAT_MEMORY, 40]);
+        memory[2..6].copy_from_slice(&domain.to_le_bytes());
+        memory[8..16].copy_from_slice(&from.to_le_bytes());
+        memory[16..24].copy_from_slice(&(to - from).to_le_bytes());
+        memory[28] = SRAT_ENABLED as u8;
+        srat.extend_from_slice(&memory);
+    }
+    Topology::parse(&srat, None).unwrap()
+}
+
+#[test_case]
+fn test_srat_and_slit_give_nodes_and_distances() {
+    let topology = two_nodes(0, 0x10_0000, 0x20_0000);
+    assert_eq!(topology.nodes(), 2);
+    assert_eq!(topology.cpu_node(1), Some(1));
+    assert_eq!(topology.cpu_node(5), None);
+    // Sorted, whatever order the SRAT had them in
+    let ranges: alloc::vec::Vec<_> = topology.ranges().collect();
+    assert_eq!(ranges, [MemoryRange { start: 0, end: 0x10_0000, node: 0 },
+        MemoryRange { start: 0x10_0000, end: 0x20_0000, node: 1 }]);
+    assert_eq!(topology.span(PhysAddr::new(0x10_8000)), (1, 0x20_0000));
+    assert_eq!(topology.node_of(PhysAddr::new(0x30_0000)), 0);
+    assert_eq!(topology.distance(0, 1), REMOTE_DISTANCE);
+    assert_eq!(topology.nearest(1).0[..2], [1, 0]);
+
+    // Domains 3 and 7 in a SLIT big enough for both
+    let localities = 8;
+    let mut slit = alloc::vec![0u8; acpi::HEADER_SIZE + 8 + localities * localities];
+    slit[acpi::HEADER_SIZE..acpi::HEADER_SIZE + 8].copy_from_slice(&(localities as u64).to_le_bytes());
+    let matrix = acpi::HEADER_SIZE + 8;
+    slit[matrix + 7 * localities + 3] = 31;
+    slit[matrix + 3 * localities + 7] = 32;
+    slit[matrix + 7 * localities + 7] = LOCAL_DISTANCE;
+    let mut with_slit = topology.clone();
+    with_slit.read_slit(&slit).unwrap();
+    assert_eq!(with_slit.distance(0, 1), 31);
+    assert_eq!(with_slit.distance(1, 0), 32);
+    assert_eq!(with_slit.distance(0, 0), LOCAL_DISTANCE);
+    assert_eq!(with_slit.read_slit(&slit[..50]), Err("SLIT is cut off"));
+}
@@ -0,0 +1,327 @@
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult, UnmapError};
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableEntry,
+    PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+use super::FRAME_SIZE;
+
+// Where the kernel's top-level table is mapped writable once
+// protect_level_4 has made it read-only everywhere else
+const LEVEL_4_ALIAS: u64 = 0x_7777_0000_0000;
+const HUGE_PAGE_SIZE: u64 = 512 * FRAME_SIZE;
+
+// Where the bootloader mapped all of physical memory
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+// The kernel's top-level table, once protect_level_4 has made it read-only
+static PROTECTED_LEVEL_4: AtomicU64 = AtomicU64::new(0);
+
+// Only ever locked with interrupts off. Locked before the frame allocator,
+// never the other way round.
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// Take over the page tables the bootloader left in CR3. `physical_memory_offset`
+/// is where it mapped all of physical memory.
+///
+/// # Safety
+/// The offset has to be right, and this may only be called once.
+pub(super) unsafe fn init(physical_memory_offset: u64) {
+    PHYS_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table = &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>();
+    let mapper = OffsetPageTable::new(level_4_table, VirtAddr::new(physical_memory_offset));
+    interrupts::without_interrupts(|| *MAPPER.lock() = Some(mapper));
+}
+
+/// Where physical address `addr` can be reached through the bootloader's
+/// mapping of all physical memory
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYS_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// `frame` as a page table to write to: through LEVEL_4_ALIAS if it's the
+/// kernel's top-level table and that's read-only now
+///
+/// # Safety
+/// `frame` has to hold a page table, and nothing else may be using it.
+pub unsafe fn table<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    let addr = if PROTECTED_LEVEL_4.load(Ordering::Relaxed) == frame.start_address().as_u64() {
+        VirtAddr::new(LEVEL_4_ALIAS)
+    } else {
+        phys_to_virt(frame.start_address())
+    };
+    &mut *addr.as_mut_ptr::<PageTable>()
+}
+
+fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> Result<R, &'static str>)
+    -> Result<R, &'static str>
+{
+    interrupts::without_interrupts(|| match MAPPER.lock().as_mut() {
+        Some(mapper) => f(mapper),
+        None => Err("paging isn't initialized"),
+    })
+}
+
+/// Make the top-level table in `level_4` the one the CPU and everything
+/// here use, and return the one it replaces.
+///
+/// # Safety
+/// The new table must map the kernel the way the old one did.
+pub unsafe fn switch_to(level_4: PhysFrame) -> PhysFrame {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let (previous, flags) = Cr3::read();
+        *mapper = Some(OffsetPageTable::new(table(level_4), VirtAddr::new(PHYS_OFFSET.load(Ordering::Relaxed))));
+        Cr3::write(level_4, flags);
+        previous
+    })
+}
+
+/// Map `page` to `frame`, noting it in the reverse map. Any page tables
+/// needed along the way come from the frame allocator.
+///
+/// # Safety
+/// Whatever the mapping makes reachable must be safe to reach: the frame
+/// can't be memory something else owns, unless that's the point.
+pub unsafe fn map_page(page: Page<Size4KiB>, frame: PhysFrame<Size4KiB>, flags: PageTableFlags)
+    -> Result<(), &'static str>
+{
+    with_mapper(|mapper| {
+        let result = super::with_frame_allocator(|frames| mapper.map_to(page, frame, flags, frames))
+            .ok_or("no frame allocator")?;
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(MapToError::FrameAllocationFailed) => Err("out of memory for page tables"),
+            Err(MapToError::ParentEntryHugePage) => Err("page is inside a huge page"),
+            Err(MapToError::PageAlreadyMapped(_)) => Err("page is already mapped"),
+        }
+    })?;
+    super::rmap::add(frame, page);
+    Ok(())
+}
+
+/// Remove `page`'s mapping, flush it from the TLB, and hand back the frame
+/// it pointed to. The frame isn't freed; that's up to whoever owns it.
+///
+/// # Safety
+/// Nothing may still be using the page.
+pub unsafe fn unmap_page(page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, &'static str> {
+    let frame = with_mapper(|mapper| match mapper.unmap(page) {
+        Ok((frame, flush)) => {
+            flush.flush();
+            Ok(frame)
+        }
+        Err(UnmapError::PageNotMapped) => Err("page isn't mapped"),
+        Err(UnmapError::ParentEntryHugePage) => Err("page is inside a huge page"),
+        Err(UnmapError::InvalidFrameAddress(_)) => Err("page maps an invalid frame"),
+    })?;
+    super::rmap::remove(frame, page);
+    Ok(frame)
+}
+
+/// Run `f` on the last-level entry for `page`, or return None if the tables
+/// on the way to it don't exist. Nothing is flushed from the TLB.
+///
+/// # Safety
+/// Whatever `f` leaves in the entry must be sound to have mapped.
+pub unsafe fn with_entry<R>(page: Page<Size4KiB>, f: impl FnOnce(&mut PageTableEntry) -> R) -> Option<R> {
+    with_mapper(|mapper| {
+        let mut table: *mut PageTable = mapper.level_4_table();
+        for &index in [page.p4_index(), page.p3_index(), page.p2_index()].iter() {
+            let entry = &(*table)[index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+                return Ok(None);
+            }
+            table = phys_to_virt(entry.addr()).as_mut_ptr();
+        }
+        Ok(Some(f(&mut (*table)[page.p1_index()])))
+    })
+    .ok()
+    .flatten()
+}
+
+// Turn the 2 MiB page `page` is in, if it's in one, into 512 of 4 KiB
+// mapping the same memory the same way
+unsafe fn split(mapper: &mut OffsetPageTable<'static>, page: Page<Size4KiB>) -> Result<(), &'static str> {
+    let mut table: *mut PageTable = mapper.level_4_table();
+    for &index in [page.p4_index(), page.p3_index()].iter() {
+        let entry = &(*table)[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err("page isn't mapped");
+        }
+        if flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Err("page is inside a 1 GiB page");
+        }
+        table = phys_to_virt(entry.addr()).as_mut_ptr();
+    }
+    let entry = &mut (*table)[page.p2_index()];
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE) {
+        return Ok(());
+    }
+    let frame = super::with_frame_allocator(|frames| frames.allocate_frame())
+        .flatten()
+        .ok_or("out of memory for page tables")?;
+    // Bit 12 of a 2 MiB entry is PAT, not part of the address
+    let start = entry.addr().as_u64() & !(HUGE_PAGE_SIZE - 1);
+    let small = &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>();
+    for (index, small_entry) in small.iter_mut().enumerate() {
+        small_entry.set_addr(PhysAddr::new(start + index as u64 * FRAME_SIZE), flags - PageTableFlags::HUGE_PAGE);
+    }
+    // What the pages may do is up to each of them now
+    let parent = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (flags & PageTableFlags::USER_ACCESSIBLE);
+    entry.set_addr(frame.start_address(), parent);
+    // The other CPUs' 2 MiB translations are still right until a page in it
+    // changes, and whatever changes it flushes that page everywhere
+    tlb::flush_all();
+    Ok(())
+}
+
+/// Take WRITABLE off the page `addr` is in, splitting the 2 MiB page that
+/// is in if it's in one, and drop it from every CPU's TLB
+pub fn make_read_only(addr: VirtAddr) -> Result<(), &'static str> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    with_mapper(|mapper| unsafe { split(mapper, page) })?;
+    // Less is reachable than before, not more
+    let present = unsafe {
+        with_entry(page, |entry| {
+            let flags = entry.flags();
+            entry.set_flags(flags - PageTableFlags::WRITABLE);
+            flags.contains(PageTableFlags::PRESENT)
+        })
+    };
+    if present != Some(true) {
+        return Err("page isn't mapped");
+    }
+    crate::ipi::flush_tlb(page.start_address())
+}
+
+/// Make the kernel's top-level page table read-only where physical memory
+/// is mapped, and have the mapper write it through a writable alias at
+/// LEVEL_4_ALIAS from now on, which only this module knows about
+pub fn protect_level_4() -> Result<(), &'static str> {
+    if PROTECTED_LEVEL_4.load(Ordering::Relaxed) != 0 {
+        return Err("the top-level page table is already read-only");
+    }
+    let (level_4, _) = Cr3::read();
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if crate::cpu::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    // The alias maps the table itself, which nothing else owns
+    unsafe { map_page(Page::containing_address(VirtAddr::new(LEVEL_4_ALIAS)), level_4, flags)? };
+    PROTECTED_LEVEL_4.store(level_4.start_address().as_u64(), Ordering::Relaxed);
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        // CR3 still has it, so the mapper's is the same table
+        let table = unsafe { table(level_4) };
+        *mapper = Some(unsafe { OffsetPageTable::new(table, VirtAddr::new(PHYS_OFFSET.load(Ordering::Relaxed))) });
+    });
+    make_read_only(phys_to_virt(level_4.start_address()))
+}
+
+/// A value with pages to itself, so they can be made read-only without
+/// taking anything else with them
+#[repr(C, align(4096))]
+pub struct PageAligned<T>(pub T);
+
+impl<T> Deref for PageAligned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> PageAligned<T> {
+    /// Make the pages this is on read-only, both where it is and where
+    /// physical memory is mapped. Nothing can write to it after this.
+    pub fn make_read_only(&'static self) -> Result<(), &'static str> {
+        let start = VirtAddr::from_ptr(self);
+        for offset in (0..core::mem::size_of::<Self>() as u64).step_by(FRAME_SIZE as usize) {
+            let frame = translate_addr(start + offset).ok_or("page isn't mapped")?;
+            make_read_only(start + offset)?;
+            make_read_only(phys_to_virt(frame))?;
+        }
+        Ok(())
+    }
+}
+
+/// The physical address `addr` maps to, if it's mapped
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| Ok(mapper.translate_addr(addr))).ok().flatten()
+}
+
+/// The flags on the page `addr` is in, if it's mapped
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    with_mapper(|mapper| match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Ok(Some(flags)),
+        _ => Ok(None),
+    })
+    .ok()
+    .flatten()
+}
+
+/// TESTS
+
+#[test_case]
+fn test_map_translate_unmap() {
+    // Canonical, and well clear of anything we or the bootloader map
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(0x0000_4444_4444_0000));
+    let frame = super::allocate_frame().expect("no frames");
+    assert_eq!(translate_addr(page.start_address()), None);
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { map_page(page, frame, flags) }.unwrap();
+    assert_eq!(unsafe { map_page(page, frame, flags) }, Err("page is already mapped"));
+    assert_eq!(translate_addr(page.start_address() + 0x123u64), Some(frame.start_address() + 0x123u64));
+    assert!(page_flags(page.start_address()).unwrap().contains(PageTableFlags::WRITABLE));
+
+    // Both views of the frame see the same memory
+    unsafe {
+        page.start_address().as_mut_ptr::<u64>().write_volatile(0x1234_5678);
+        assert_eq!(phys_to_virt(frame.start_address()).as_ptr::<u64>().read_volatile(), 0x1234_5678);
+    }
+
+    assert_eq!(unsafe { unmap_page(page) }, Ok(frame));
+    assert_eq!(translate_addr(page.start_address()), None);
+    unsafe { super::deallocate_frame(frame) };
+}
+
+#[test_case]
+fn test_read_only_splits_huge_pages() {
+    let frame = super::allocate_frame().expect("no frames");
+    let alias = phys_to_virt(frame.start_address());
+    make_read_only(alias).unwrap();
+    assert!(!page_flags(alias).unwrap().contains(PageTableFlags::WRITABLE));
+    // The rest of the physical memory map is just as it was
+    let next = alias + FRAME_SIZE;
+    assert_eq!(translate_addr(next), Some(frame.start_address() + FRAME_SIZE));
+    assert!(page_flags(next).unwrap().contains(PageTableFlags::WRITABLE));
+
+    // Writable again before anyone else gets the frame
+    let writable = |entry: &mut PageTableEntry| entry.set_flags(entry.flags() | PageTableFlags::WRITABLE);
+    unsafe { with_entry(Page::containing_address(alias), writable) };
+    crate::ipi::flush_tlb(alias).unwrap();
+    unsafe { super::deallocate_frame(frame) };
+}
+
+#[cfg(feature = "hardening")]
+#[test_case]
+fn test_tables_are_read_only() {
+    // The test runner protects them at boot, as the kernel does
+    let (level_4, _) = Cr3::read();
+    assert_eq!(PROTECTED_LEVEL_4.load(Ordering::Relaxed), level_4.start_address().as_u64());
+    assert!(!page_flags(phys_to_virt(level_4.start_address())).unwrap().contains(PageTableFlags::WRITABLE));
+    let idt = VirtAddr::from_ptr(crate::interrupts::idt());
+    assert!(!page_flags(idt).unwrap().contains(PageTableFlags::WRITABLE));
+    assert!(!page_flags(phys_to_virt(translate_addr(idt).unwrap())).unwrap().contains(PageTableFlags::WRITABLE));
+}
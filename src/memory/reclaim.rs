@@ -0,0 +1,154 @@
+//! Getting frames back when memory runs short. Whatever keeps memory it
+//! could do without (caches, mostly) registers a shrinker. Dropping below
+//! the low watermark wakes kswapd, which shrinks in the background until
+//! free memory is back above the high one; dropping below the min
+//! watermark makes the allocation itself reclaim before going on.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::sched::{self, ThreadId};
+use super::LOW_MEMORY_FRAMES;
+
+const MAX_SHRINKERS: usize = 8;
+
+/// Free-frame levels reclaim works between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Below this, allocations reclaim for themselves
+    pub min: usize,
+    /// Below this, kswapd is woken
+    pub low: usize,
+    /// kswapd stops once free memory is back above this
+    pub high: usize,
+}
+
+pub const WATERMARKS: Watermarks = Watermarks {
+    min: LOW_MEMORY_FRAMES / 4,
+    low: LOW_MEMORY_FRAMES,
+    high: LOW_MEMORY_FRAMES * 2,
+};
+
+/// Something holding frames it can give back
+#[derive(Clone, Copy)]
+pub struct Shrinker {
+    pub name: &'static str,
+    /// Free up to the given number of frames; returns how many it freed.
+    /// Called from kswapd or from whatever allocation ran short, so it
+    /// mustn't allocate frames itself.
+    pub shrink: fn(usize) -> usize,
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static SHRINKERS: Mutex<[Option<Shrinker>; MAX_SHRINKERS]> = Mutex::new([None; MAX_SHRINKERS]);
+// Only ever locked with interrupts off, so a handler can't find it held
+static KSWAPD: Mutex<Option<ThreadId>> = Mutex::new(None);
+
+pub fn register_shrinker(shrinker: Shrinker) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut shrinkers = SHRINKERS.lock();
+        let slot = shrinkers.iter_mut().find(|slot| slot.is_none()).ok_or("too many shrinkers")?;
+        *slot = Some(shrinker);
+        Ok(())
+    })
+}
+
+pub fn unregister_shrinker(name: &str) {
+    interrupts::without_interrupts(|| {
+        for slot in SHRINKERS.lock().iter_mut() {
+            if slot.map_or(false, |shrinker| shrinker.name == name) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// Ask the shrinkers, in the order they registered, for `target` frames.
+/// Returns how many they freed, which may be more or less.
+pub fn shrink(target: usize) -> usize {
+    // A copy, so shrinkers run without the lock and may unregister
+    let shrinkers = interrupts::without_interrupts(|| *SHRINKERS.lock());
+    let mut freed = 0;
+    for shrinker in shrinkers.iter().flatten() {
+        if freed >= target {
+            break;
+        }
+        freed += (shrinker.shrink)(target - freed);
+    }
+    freed
+}
+
+fn free_frames() -> usize {
+    super::with_frame_allocator(|frames| frames.free_frames()).unwrap_or(0)
+}
+
+// Called by allocate_frame with what it left free
+pub(super) fn note_free(free: usize) {
+    if free < WATERMARKS.low {
+        wake_kswapd();
+    }
+    if free < WATERMARKS.min {
+        shrink(WATERMARKS.min - free);
+    }
+}
+
+fn wake_kswapd() {
+    if let Some(id) = interrupts::without_interrupts(|| *KSWAPD.lock()) {
+        sched::unpark(id);
+    }
+}
+
+fn kswapd() {
+    loop {
+        sched::park();
+        loop {
+            let free = free_frames();
+            // Stop when there's enough, or when nobody has anything left
+            if free >= WATERMARKS.high || shrink(WATERMARKS.high - free) == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Start the background reclaim thread. Until then, only the min watermark
+/// does anything.
+pub fn start_kswapd() -> Result<(), &'static str> {
+    if interrupts::without_interrupts(|| KSWAPD.lock().is_some()) {
+        return Err("kswapd is already running");
+    }
+    let id = sched::spawn("kswapd", kswapd)?;
+    interrupts::without_interrupts(|| *KSWAPD.lock() = Some(id));
+    // Memory may already be low
+    if free_frames() < WATERMARKS.low {
+        wake_kswapd();
+    }
+    Ok(())
+}
+
+/// TESTS
+
+#[test_case]
+fn test_shrink_asks_until_satisfied() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static ASKED: AtomicUsize = AtomicUsize::new(0);
+    fn generous(wanted: usize) -> usize {
+        ASKED.fetch_add(1, Ordering::Relaxed);
+        wanted + 1
+    }
+    fn stingy(_wanted: usize) -> usize {
+        ASKED.fetch_add(100, Ordering::Relaxed);
+        0
+    }
+
+    register_shrinker(Shrinker { name: "test-generous", shrink: generous }).unwrap();
+    register_shrinker(Shrinker { name: "test-stingy", shrink: stingy }).unwrap();
+    // The first one freed enough, so the second is never asked
+    assert_eq!(shrink(4), 5);
+    assert_eq!(ASKED.load(Ordering::Relaxed), 1);
+    unregister_shrinker("test-generous");
+    assert_eq!(shrink(4), 0);
+    assert_eq!(ASKED.load(Ordering::Relaxed), 101);
+    unregister_shrinker("test-stingy");
+    assert_eq!(shrink(4), 0);
+}
@@ -0,0 +1,196 @@
+//! The reverse map: for each frame, the pages mapped to it. Everything
+//! mapped through `paging` is in it, and anonymous pages as they move in
+//! and out of RAM; the bootloader's own mappings (the kernel image, and
+//! the map of all physical memory) aren't.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Page, PhysFrame};
+use crate::user::space::{SPACE_END, SPACE_START};
+
+// A power of two, for the hash; room for the heap, every resident
+// anonymous page, and a program twice over
+const MAX_MAPPINGS: usize = 8192;
+
+/// Somewhere a frame is mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub page: Page,
+    /// The top-level table, for a page in a program's part of an address
+    /// space; None for the kernel's part, which every space shares
+    pub space: Option<PhysFrame>,
+}
+
+struct Rmap {
+    // Open addressing by frame, probing linearly
+    slots: [Option<(PhysFrame, Mapping)>; MAX_MAPPINGS],
+    len: usize,
+    // Mappings that didn't fit, since boot
+    untracked: u64,
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held.
+// Never with the mapper held.
+static RMAP: Mutex<Rmap> = Mutex::new(Rmap { slots: [None; MAX_MAPPINGS], len: 0, untracked: 0 });
+
+fn home(frame: PhysFrame) -> usize {
+    let number = frame.start_address().as_u64() >> 12;
+    (number.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 51) as usize % MAX_MAPPINGS
+}
+
+impl Rmap {
+    fn insert(&mut self, frame: PhysFrame, mapping: Mapping) {
+        // One slot always stays empty, so probing always ends
+        if self.len == MAX_MAPPINGS - 1 {
+            self.untracked += 1;
+            return;
+        }
+        let mut index = home(frame);
+        while self.slots[index].is_some() {
+            index = (index + 1) % MAX_MAPPINGS;
+        }
+        self.slots[index] = Some((frame, mapping));
+        self.len += 1;
+    }
+
+    fn remove_where(&mut self, frame: PhysFrame, mut matches: impl FnMut(&Mapping) -> bool) {
+        let mut index = home(frame);
+        while let Some((other, mapping)) = self.slots[index] {
+            if other == frame && matches(&mapping) {
+                self.remove_at(index);
+                // Something may have moved into this slot
+                continue;
+            }
+            index = (index + 1) % MAX_MAPPINGS;
+        }
+    }
+
+    // Empty a slot, moving back whatever later in its run would otherwise
+    // be cut off from its home slot
+    fn remove_at(&mut self, mut hole: usize) {
+        self.slots[hole] = None;
+        self.len -= 1;
+        let mut index = hole;
+        loop {
+            index = (index + 1) % MAX_MAPPINGS;
+            let frame = match self.slots[index] {
+                Some((frame, _)) => frame,
+                None => return,
+            };
+            // Whether its home is cyclically after the hole, up to it
+            let home = home(frame);
+            let stays = if hole <= index { hole < home && home <= index } else { hole < home || home <= index };
+            if !stays {
+                self.slots[hole] = self.slots[index].take();
+                hole = index;
+            }
+        }
+    }
+
+    fn find(&self, frame: PhysFrame) -> impl Iterator<Item = Mapping> + '_ {
+        let start = home(frame);
+        (0..MAX_MAPPINGS)
+            .map(move |step| self.slots[(start + step) % MAX_MAPPINGS])
+            .take_while(Option::is_some)
+            .flatten()
+            .filter(move |(other, _)| *other == frame)
+            .map(|(_, mapping)| mapping)
+    }
+}
+
+// `page` as mapped in the tables in use now
+fn mapping(page: Page) -> Mapping {
+    let addr = page.start_address().as_u64();
+    let space = if (SPACE_START..SPACE_END).contains(&addr) { Some(Cr3::read().0) } else { None };
+    Mapping { page, space }
+}
+
+/// Note that `page`, in the tables in use, now maps `frame`
+pub fn add(frame: PhysFrame, page: Page) {
+    let mapping = mapping(page);
+    interrupts::without_interrupts(|| RMAP.lock().insert(frame, mapping));
+}
+
+/// Note that `page`, in the tables in use, no longer maps `frame`
+pub fn remove(frame: PhysFrame, page: Page) {
+    let mapping = mapping(page);
+    interrupts::without_interrupts(|| RMAP.lock().remove_where(frame, |other| *other == mapping));
+}
+
+/// Forget every mapping in the program's part of the address space with
+/// top-level table `space`, since it's going away
+pub fn remove_space(space: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        let mut rmap = RMAP.lock();
+        let mut index = 0;
+        while index < MAX_MAPPINGS {
+            match rmap.slots[index] {
+                Some((_, mapping)) if mapping.space == Some(space) => rmap.remove_at(index),
+                _ => index += 1,
+            }
+        }
+    });
+}
+
+/// Every page `frame` is mapped at
+pub fn mappings(frame: PhysFrame) -> Vec<Mapping> {
+    interrupts::without_interrupts(|| RMAP.lock().find(frame).collect())
+}
+
+/// Mappings tracked now, and how many there was no room for since boot
+pub fn usage() -> (usize, u64) {
+    interrupts::without_interrupts(|| {
+        let rmap = RMAP.lock();
+        (rmap.len, rmap.untracked)
+    })
+}
+
+/// TESTS
+
+#[test_case]
+fn test_rmap_follows_map_and_unmap() {
+    use x86_64::structures::paging::PageTableFlags;
+    use x86_64::VirtAddr;
+    use super::paging;
+
+    let frame = super::allocate_frame().unwrap();
+    let pages: [Page; 2] = [Page::containing_address(VirtAddr::new(0x0000_4444_5555_0000)),
+        Page::containing_address(VirtAddr::new(0x0000_4444_5555_1000))];
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for &page in pages.iter() {
+        unsafe { paging::map_page(page, frame, flags) }.unwrap();
+    }
+    let mut found = mappings(frame);
+    found.sort_unstable_by_key(|mapping| mapping.page);
+    assert_eq!(found, [Mapping { page: pages[0], space: None }, Mapping { page: pages[1], space: None }]);
+
+    unsafe { paging::unmap_page(pages[0]) }.unwrap();
+    assert_eq!(mappings(frame), [Mapping { page: pages[1], space: None }]);
+    unsafe { paging::unmap_page(pages[1]) }.unwrap();
+    assert!(mappings(frame).is_empty());
+    unsafe { super::deallocate_frame(frame) };
+}
+
+#[test_case]
+fn test_removal_keeps_runs_reachable() {
+    // Made-up frames, three with the same home slot and one in the slot
+    // after, so removing the first has the rest to move back
+    let frame = |number: u64| PhysFrame::containing_address(x86_64::PhysAddr::new((1 << 40) + (number << 12)));
+    let target = home(frame(0));
+    let same: Vec<_> = (0..).map(frame).filter(|&other| home(other) == target).take(3).collect();
+    let next = (0..).map(frame).find(|&other| home(other) == (target + 1) % MAX_MAPPINGS).unwrap();
+    let page = Page::containing_address(x86_64::VirtAddr::new(0x1000));
+    for &frame in same.iter().chain(core::iter::once(&next)) {
+        add(frame, page);
+    }
+    remove(same[0], page);
+    assert!(mappings(same[0]).is_empty());
+    assert_eq!(mappings(same[2]).len(), 1);
+    assert_eq!(mappings(next).len(), 1);
+    for &frame in same[1..].iter().chain(core::iter::once(&next)) {
+        remove(frame, page);
+    }
+    assert!(mappings(next).is_empty());
+}
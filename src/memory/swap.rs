@@ -0,0 +1,263 @@
+//! Somewhere for anonymous pages (see `anon`) to go when RAM runs short. A
+//! swap area is a whole disk, or an MBR partition of type 0x82, whose first
+//! page is a header tools/mkswap.py writes: MAGIC, then the format version
+//! and how many slots follow (both u32, little-endian). Each slot is one
+//! page.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use crate::drivers::ata::{self, Disk, MAX_DISKS, SECTOR_SIZE};
+use super::reclaim::{self, Shrinker};
+use super::{anon, paging, FRAME_SIZE};
+
+const MAGIC: [u8; 8] = *b"HEORSWAP";
+const VERSION: u32 = 1;
+const SWAP_PARTITION_TYPE: u8 = 0x82;
+const SECTORS_PER_SLOT: u64 = FRAME_SIZE / SECTOR_SIZE as u64;
+/// Slots that can be used, however big the area (64 MiB)
+pub const MAX_SLOTS: usize = 16384;
+
+#[derive(Clone, Copy)]
+enum Device {
+    // Slot 0 is the page after the header at `start`
+    Disk { disk: Disk, start: u64 },
+    // For the tests, which have no disk to spare
+    #[cfg(test)]
+    Memory(*mut u8),
+}
+
+struct Area {
+    device: Device,
+    disk: Option<usize>,
+    partition: Option<usize>,
+    slots: usize,
+    used: [u64; MAX_SLOTS / 64],
+    in_use: usize,
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static SWAP: Mutex<Option<Area>> = Mutex::new(None);
+// Raw pointers aren't Send, but the test area is only ever used under the lock
+unsafe impl Send for Area {}
+
+static SWAPPED_OUT: AtomicU64 = AtomicU64::new(0);
+static SWAPPED_IN: AtomicU64 = AtomicU64::new(0);
+
+/// What the swap area is and how full it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// As `ata::disk` numbers it
+    pub disk: Option<usize>,
+    /// The MBR partition, from 1, or None if it's the whole disk
+    pub partition: Option<usize>,
+    pub slots: usize,
+    pub used: usize,
+    /// Pages written out and read back in since boot
+    pub swapped_out: u64,
+    pub swapped_in: u64,
+}
+
+// The slots in the swap area whose header is at `start`
+fn read_header(disk: &Disk, start: u64) -> Option<usize> {
+    let mut sector = [0; SECTOR_SIZE];
+    disk.read_sectors(start, &mut sector).ok()?;
+    let word = |offset: usize| u32::from_le_bytes([sector[offset], sector[offset + 1], sector[offset + 2],
+        sector[offset + 3]]);
+    if sector[..8] != MAGIC || word(8) != VERSION {
+        return None;
+    }
+    // No more than fit on the disk
+    let room = disk.sectors.saturating_sub(start) / SECTORS_PER_SLOT;
+    Some((word(12) as usize).min(room.saturating_sub(1) as usize).min(MAX_SLOTS))
+}
+
+// The swap area on `disk`: its partition if it's in one, where its header
+// is, and its size in slots
+fn probe(disk: &Disk) -> Option<(Option<usize>, u64, usize)> {
+    if let Some(slots) = read_header(disk, 0) {
+        return Some((None, 0, slots));
+    }
+    let mut mbr = [0; SECTOR_SIZE];
+    disk.read_sectors(0, &mut mbr).ok()?;
+    if mbr[510..] != [0x55, 0xaa] {
+        return None;
+    }
+    mbr[0x1be..0x1fe].chunks_exact(16).enumerate().find_map(|(index, entry)| {
+        if entry[4] != SWAP_PARTITION_TYPE {
+            return None;
+        }
+        let start = u64::from(u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]));
+        read_header(disk, start).map(|slots| (Some(index + 1), start, slots))
+    })
+}
+
+fn enable(area: Area) -> Result<(), &'static str> {
+    if area.slots == 0 {
+        return Err("swap area has no slots");
+    }
+    interrupts::without_interrupts(|| {
+        let mut swap = SWAP.lock();
+        if swap.is_some() {
+            return Err("swap is already on");
+        }
+        *swap = Some(area);
+        Ok(())
+    })?;
+    reclaim::register_shrinker(Shrinker { name: "anon", shrink: anon::shrink })
+}
+
+/// Start swapping to the first swap area found on an ATA disk
+pub fn swapon() -> Result<(), &'static str> {
+    for index in 0..MAX_DISKS {
+        let disk = match ata::disk(index) {
+            Some(disk) => disk,
+            None => continue,
+        };
+        if let Some((partition, start, slots)) = probe(&disk) {
+            return enable(Area {
+                device: Device::Disk { disk, start },
+                disk: Some(index),
+                partition,
+                slots,
+                used: [0; MAX_SLOTS / 64],
+                in_use: 0,
+            });
+        }
+    }
+    Err("no swap area found")
+}
+
+/// Swap to `area` in memory instead of to a disk
+#[cfg(test)]
+fn swapon_memory(area: &'static mut [u8]) -> Result<(), &'static str> {
+    enable(Area {
+        device: Device::Memory(area.as_mut_ptr()),
+        disk: None,
+        partition: None,
+        slots: (area.len() / FRAME_SIZE as usize).min(MAX_SLOTS),
+        used: [0; MAX_SLOTS / 64],
+        in_use: 0,
+    })
+}
+
+/// Stop swapping. Fails while anything is swapped out.
+pub fn swapoff() -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut swap = SWAP.lock();
+        match swap.as_ref() {
+            None => return Err("swap is off"),
+            Some(area) if area.in_use > 0 => return Err("pages are still swapped out"),
+            Some(_) => *swap = None,
+        }
+        Ok(())
+    })?;
+    reclaim::unregister_shrinker("anon");
+    Ok(())
+}
+
+pub fn usage() -> Option<Usage> {
+    interrupts::without_interrupts(|| {
+        SWAP.lock().as_ref().map(|area| Usage {
+            disk: area.disk,
+            partition: area.partition,
+            slots: area.slots,
+            used: area.in_use,
+            swapped_out: SWAPPED_OUT.load(Ordering::Relaxed),
+            swapped_in: SWAPPED_IN.load(Ordering::Relaxed),
+        })
+    })
+}
+
+// The rest is for anon, which calls it with interrupts off
+
+pub(super) fn enabled() -> bool {
+    SWAP.lock().is_some()
+}
+
+// Write `frame` to a free slot, and say which
+pub(super) fn write_out(frame: PhysFrame) -> Result<usize, &'static str> {
+    let mut swap = SWAP.lock();
+    let area = swap.as_mut().ok_or("swap is off")?;
+    let slot = (0..area.slots)
+        .find(|&slot| area.used[slot / 64] & 1 << (slot % 64) == 0)
+        .ok_or("swap is full")?;
+    let page = phys_slice(frame);
+    match area.device {
+        Device::Disk { disk, start } => disk.write_sectors(start + (slot as u64 + 1) * SECTORS_PER_SLOT, page)?,
+        #[cfg(test)]
+        Device::Memory(base) => unsafe {
+            core::ptr::copy_nonoverlapping(page.as_ptr(), base.add(slot * FRAME_SIZE as usize), page.len())
+        },
+    }
+    area.used[slot / 64] |= 1 << (slot % 64);
+    area.in_use += 1;
+    SWAPPED_OUT.fetch_add(1, Ordering::Relaxed);
+    Ok(slot)
+}
+
+// Read `slot` into `frame`, leaving the slot in use
+pub(super) fn read_in(slot: usize, frame: PhysFrame) -> Result<(), &'static str> {
+    let swap = SWAP.lock();
+    let area = swap.as_ref().ok_or("swap is off")?;
+    let page = phys_slice(frame);
+    match area.device {
+        Device::Disk { disk, start } => disk.read_sectors(start + (slot as u64 + 1) * SECTORS_PER_SLOT, page)?,
+        #[cfg(test)]
+        Device::Memory(base) => unsafe {
+            core::ptr::copy_nonoverlapping(base.add(slot * FRAME_SIZE as usize), page.as_mut_ptr(), page.len())
+        },
+    }
+    SWAPPED_IN.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+pub(super) fn free_slot(slot: usize) {
+    if let Some(area) = SWAP.lock().as_mut() {
+        if area.used[slot / 64] & 1 << (slot % 64) != 0 {
+            area.used[slot / 64] &= !(1 << (slot % 64));
+            area.in_use -= 1;
+        }
+    }
+}
+
+// The frame's memory, through the mapping of all physical memory
+fn phys_slice(frame: PhysFrame) -> &'static mut [u8] {
+    let start = paging::phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { core::slice::from_raw_parts_mut(start, FRAME_SIZE as usize) }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_pages_swap_out_and_back() {
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    const PAGES: usize = 4;
+    let area = Box::leak(vec![0u8; PAGES * FRAME_SIZE as usize].into_boxed_slice());
+    swapon_memory(area).unwrap();
+
+    let mut region = anon::Region::new(PAGES * FRAME_SIZE as usize).unwrap();
+    for (index, page) in region.as_mut_slice().chunks_exact_mut(FRAME_SIZE as usize).enumerate() {
+        page.fill(index as u8 + 1);
+    }
+    // Touched just now, so the clock has to come round twice for each
+    assert_eq!(anon::shrink(PAGES + 1), PAGES);
+    assert_eq!(usage().unwrap().used, PAGES);
+    assert_eq!(anon::shrink(1), 0);
+
+    // Faulted back in on touch, slots freed as they come
+    for (index, page) in region.as_mut_slice().chunks_exact(FRAME_SIZE as usize).enumerate() {
+        assert!(page.iter().all(|&byte| byte == index as u8 + 1));
+    }
+    assert_eq!(usage().unwrap().used, 0);
+
+    // Dropping the region frees what's swapped out too
+    anon::shrink(1);
+    assert_eq!(usage().unwrap().used, 1);
+    assert_eq!(swapoff(), Err("pages are still swapped out"));
+    drop(region);
+    swapoff().unwrap();
+}
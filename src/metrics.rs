@@ -0,0 +1,270 @@
+//! Statistics by name, in one place: counters that only go up, gauges read
+//! as they are now, and histograms of power-of-two buckets. Subsystems
+//! register what they have; plenty already counted something, so a metric
+//! can read a count kept elsewhere rather than keep its own, as the
+//! network interfaces' counts are, labeled by interface. `render` writes
+//! them all in Prometheus' text format, for the `metrics` command, and for
+//! a `/metrics` endpoint once there's TCP to serve one.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const MAX_METRICS: usize = 32;
+/// Bucket n of a histogram holds values below 2^n that didn't fit in the
+/// one before; the last takes everything bigger, too
+pub const BUCKETS: usize = 20;
+
+/// A count that only goes up
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, count: u64) {
+        self.0.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How a set of values spread out
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Histogram {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram { buckets: [ZERO; BUCKETS], sum: ZERO }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = (64 - value.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum()
+    }
+}
+
+/// Where a metric's value comes from
+#[derive(Clone, Copy)]
+pub enum Value {
+    Counter(&'static Counter),
+    /// A count kept elsewhere, which only goes up
+    CounterFn(fn() -> u64),
+    /// How much of something there is now
+    Gauge(fn() -> u64),
+    /// A counter for each of several things, by label; `f` calls back with
+    /// each label and its count
+    Labeled(&'static str, fn(f: &mut dyn FnMut(&str, u64))),
+    Histogram(&'static Histogram),
+}
+
+#[derive(Clone, Copy)]
+pub struct Metric {
+    /// heorot_ and then the subsystem, by convention
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: Value,
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static METRICS: Mutex<[Option<Metric>; MAX_METRICS]> = Mutex::new([None; MAX_METRICS]);
+
+/// Add a metric; names have to be unique
+pub fn register(metric: Metric) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut metrics = METRICS.lock();
+        if metrics.iter().flatten().any(|other| other.name == metric.name) {
+            return Err("metric already registered");
+        }
+        let slot = metrics.iter_mut().find(|slot| slot.is_none()).ok_or("too many metrics")?;
+        *slot = Some(metric);
+        Ok(())
+    })
+}
+
+/// Call `f` with each metric, in the order they registered
+pub fn for_each(mut f: impl FnMut(&Metric)) {
+    let metrics = interrupts::without_interrupts(|| *METRICS.lock());
+    for metric in metrics.iter().flatten() {
+        f(metric);
+    }
+}
+
+fn render_one(out: &mut dyn Write, metric: &Metric) -> fmt::Result {
+    let kind = match metric.value {
+        Value::Counter(_) | Value::CounterFn(_) | Value::Labeled(..) => "counter",
+        Value::Gauge(_) => "gauge",
+        Value::Histogram(_) => "histogram",
+    };
+    writeln!(out, "# HELP {} {}", metric.name, metric.help)?;
+    writeln!(out, "# TYPE {} {}", metric.name, kind)?;
+    match metric.value {
+        Value::Counter(counter) => writeln!(out, "{} {}", metric.name, counter.get()),
+        Value::CounterFn(read) | Value::Gauge(read) => writeln!(out, "{} {}", metric.name, read()),
+        Value::Labeled(label, each) => {
+            let mut result = Ok(());
+            each(&mut |value, count| {
+                if result.is_ok() {
+                    result = writeln!(out, "{}{{{}=\"{}\"}} {}", metric.name, label, value, count);
+                }
+            });
+            result
+        }
+        Value::Histogram(histogram) => {
+            // Prometheus' buckets count everything up to their bound
+            let mut total = 0;
+            for (bucket, count) in histogram.buckets[..BUCKETS - 1].iter().enumerate() {
+                total += count.load(Ordering::Relaxed);
+                writeln!(out, "{}_bucket{{le=\"{}\"}} {}", metric.name, (1u64 << bucket) - 1, total)?;
+            }
+            let count = histogram.count();
+            writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", metric.name, count)?;
+            writeln!(out, "{}_sum {}", metric.name, histogram.sum.load(Ordering::Relaxed))?;
+            writeln!(out, "{}_count {}", metric.name, count)
+        }
+    }
+}
+
+/// Every metric, in Prometheus' text exposition format
+pub fn render(out: &mut dyn Write) -> fmt::Result {
+    let mut result = Ok(());
+    for_each(|metric| {
+        if result.is_ok() {
+            result = render_one(out, metric);
+        }
+    });
+    result
+}
+
+fn interrupts_by_line(f: &mut dyn FnMut(&str, u64)) {
+    for irq in 0..16 {
+        let count = crate::interrupts::irq_count(irq);
+        if count > 0 {
+            f(crate::interrupts::irq_name(irq).unwrap_or("unknown"), count);
+        }
+    }
+}
+
+fn threads() -> u64 {
+    let mut threads = 0;
+    crate::sched::for_each_thread(|_, _, _, _| threads += 1);
+    threads
+}
+
+fn free_bytes() -> u64 {
+    crate::memory::with_frame_allocator(|frames| frames.free_frames()).unwrap_or(0) as u64 * crate::memory::FRAME_SIZE
+}
+
+fn swap_out() -> u64 {
+    crate::memory::swap::usage().map_or(0, |usage| usage.swapped_out)
+}
+
+fn swap_in() -> u64 {
+    crate::memory::swap::usage().map_or(0, |usage| usage.swapped_in)
+}
+
+fn heap_free_bytes() -> u64 {
+    crate::allocator::free_bytes() as u64
+}
+
+// What the core of the kernel was counting anyway
+const CORE: [Metric; 7] = [
+    Metric {
+        name: "heorot_interrupts_total",
+        help: "Interrupts by PIC line",
+        value: Value::Labeled("irq", interrupts_by_line),
+    },
+    Metric {
+        name: "heorot_context_switches_total",
+        help: "Switches between threads",
+        value: Value::CounterFn(crate::sched::switches),
+    },
+    Metric { name: "heorot_threads", help: "Kernel threads", value: Value::Gauge(threads) },
+    Metric { name: "heorot_memory_free_bytes", help: "Free physical memory", value: Value::Gauge(free_bytes) },
+    Metric { name: "heorot_heap_free_bytes", help: "Free kernel heap", value: Value::Gauge(heap_free_bytes) },
+    Metric { name: "heorot_swap_out_pages_total", help: "Pages written to swap", value: Value::CounterFn(swap_out) },
+    Metric { name: "heorot_swap_in_pages_total", help: "Pages read back from swap", value: Value::CounterFn(swap_in) },
+];
+
+#[cfg(feature = "shell")]
+fn cmd_metrics(_args: &[&str]) -> crate::shell::Status {
+    let mut text = alloc::string::String::new();
+    let _ = render(&mut text);
+    crate::print!("{}", text);
+    crate::shell::SUCCESS
+}
+
+/// Register the core's metrics, and add the `metrics` command
+pub fn init() {
+    for &metric in CORE.iter() {
+        register(metric).expect("couldn't register core metrics");
+    }
+    #[cfg(feature = "shell")]
+    crate::shell::register(crate::shell::Command {
+        name: "metrics",
+        help: "print every metric, in Prometheus' text format",
+        run: cmd_metrics,
+        complete: None,
+    })
+    .expect("couldn't register metrics");
+}
+
+/// TESTS
+
+#[test_case]
+fn test_render() {
+    static REQUESTS: Counter = Counter::new();
+    static SIZES: Histogram = Histogram::new();
+    fn per_disk(f: &mut dyn FnMut(&str, u64)) {
+        f("hda", 3);
+        f("hdb", 4);
+    }
+
+    REQUESTS.add(2);
+    REQUESTS.increment();
+    for size in [0, 1, 3, 4, 1 << 30] {
+        SIZES.observe(size);
+    }
+    let mut text = alloc::string::String::new();
+    render_one(&mut text, &Metric { name: "test_requests_total", help: "Requests", value: Value::Counter(&REQUESTS) })
+        .unwrap();
+    assert_eq!(text, "# HELP test_requests_total Requests\n# TYPE test_requests_total counter\ntest_requests_total 3\n");
+
+    text.clear();
+    render_one(&mut text, &Metric { name: "test_reads_total", help: "Reads", value: Value::Labeled("disk", per_disk) })
+        .unwrap();
+    assert!(text.ends_with("test_reads_total{disk=\"hda\"} 3\ntest_reads_total{disk=\"hdb\"} 4\n"));
+
+    text.clear();
+    render_one(&mut text, &Metric { name: "test_size", help: "Sizes", value: Value::Histogram(&SIZES) }).unwrap();
+    for line in ["test_size_bucket{le=\"0\"} 1", "test_size_bucket{le=\"1\"} 2", "test_size_bucket{le=\"3\"} 3",
+        "test_size_bucket{le=\"7\"} 4", "test_size_bucket{le=\"+Inf\"} 5", "test_size_count 5"] {
+        assert!(text.lines().any(|shown| shown == line), "no {:?} in {}", line, text);
+    }
+
+    // The core's are registered at boot, once only
+    assert_eq!(register(CORE[0]), Err("metric already registered"));
+    let mut names = alloc::vec::Vec::new();
+    for_each(|metric| names.push(metric.name));
+    assert!(names.contains(&"heorot_context_switches_total"));
+    text.clear();
+    render(&mut text).unwrap();
+    let lo_sent = "heorot_net_sent_packets_total{interface=\"lo\"} ";
+    assert!(text.lines().any(|line| line.starts_with(lo_sent)));
+}
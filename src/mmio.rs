@@ -0,0 +1,167 @@
+//! Typed memory-mapped device registers. `mmio!` describes a block of them:
+//! each at its offset, read-only, write-only or both, and with the bits
+//! the device reserves. Reading a write-only register or writing a
+//! read-only one doesn't compile; writes to registers that can be read keep
+//! the reserved bits as the device has them, and debug builds stop code
+//! that tries to set them.
+
+use core::marker::PhantomData;
+use core::ops::{BitAnd, BitOr, Not};
+use core::ptr::{read_volatile, write_volatile};
+
+/// What a register holds
+pub trait Value: Copy + PartialEq + BitAnd<Output = Self> + BitOr<Output = Self> + Not<Output = Self> {
+    const ZERO: Self;
+}
+
+impl Value for u8 {
+    const ZERO: u8 = 0;
+}
+
+impl Value for u16 {
+    const ZERO: u16 = 0;
+}
+
+impl Value for u32 {
+    const ZERO: u32 = 0;
+}
+
+impl Value for u64 {
+    const ZERO: u64 = 0;
+}
+
+pub trait Readable {}
+pub trait Writable {}
+
+pub enum ReadOnly {}
+pub enum WriteOnly {}
+pub enum ReadWrite {}
+
+impl Readable for ReadOnly {}
+impl Readable for ReadWrite {}
+impl Writable for WriteOnly {}
+impl Writable for ReadWrite {}
+
+/// One register, which `A` says can be read, written, or both
+pub struct Register<A, T> {
+    address: *mut T,
+    reserved: T,
+    access: PhantomData<A>,
+}
+
+impl<A, T: Value> Register<A, T> {
+    /// # Safety
+    /// `address` has to be a mapped register of this size, for as long as
+    /// the register's used.
+    pub const unsafe fn new(address: *mut T, reserved: T) -> Register<A, T> {
+        Register { address, reserved, access: PhantomData }
+    }
+}
+
+impl<A: Readable, T: Value> Register<A, T> {
+    pub fn read(&self) -> T {
+        unsafe { read_volatile(self.address) }
+    }
+}
+
+impl<T: Value> Register<WriteOnly, T> {
+    /// Reserved bits are written as zero, as there's no telling what they were
+    pub fn write(&self, value: T) {
+        debug_assert!(value & self.reserved == T::ZERO, "write to reserved register bits");
+        unsafe { write_volatile(self.address, value & !self.reserved) }
+    }
+}
+
+impl<T: Value> Register<ReadWrite, T> {
+    pub fn write(&self, value: T) {
+        debug_assert!(value & self.reserved == T::ZERO, "write to reserved register bits");
+        let kept = if self.reserved == T::ZERO { T::ZERO } else { self.read() & self.reserved };
+        unsafe { write_volatile(self.address, kept | (value & !self.reserved)) }
+    }
+
+    /// Read, change and write back
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()) & !self.reserved);
+    }
+}
+
+/// A block of registers at a base address:
+///
+/// ```ignore
+/// mmio! {
+///     /// The device
+///     pub struct Device {
+///         /// What it is
+///         id @ 0x00: ReadOnly<u32>,
+///         control @ 0x04: ReadWrite<u32> reserved 0xffff_0000,
+///     }
+/// }
+/// ```
+///
+/// gives `Device::new(base)`, unsafe as the base has to be right, and a
+/// method for each register.
+#[macro_export]
+macro_rules! mmio {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field:ident @ $offset:literal: $access:ident<$ty:ty> $(reserved $reserved:expr)?,
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy)]
+        $vis struct $name {
+            base: u64,
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            /// # Safety
+            /// `base` has to be where the registers are mapped, uncached,
+            /// for as long as they're used.
+            pub const unsafe fn new(base: u64) -> $name {
+                $name { base }
+            }
+
+            pub fn base(&self) -> u64 {
+                self.base
+            }
+
+            $(
+                $(#[$field_meta])*
+                pub fn $field(&self) -> $crate::mmio::Register<$crate::mmio::$access, $ty> {
+                    let reserved: $ty = 0 $(| $reserved)?;
+                    unsafe { $crate::mmio::Register::new((self.base + $offset) as *mut $ty, reserved) }
+                }
+            )*
+        }
+    };
+}
+
+/// TESTS
+
+#[test_case]
+fn test_registers() {
+    mmio! {
+        struct Block {
+            id @ 0x0: ReadOnly<u32>,
+            control @ 0x4: ReadWrite<u32> reserved 0xff00_0000,
+            command @ 0x8: WriteOnly<u32>,
+        }
+    }
+
+    // Memory standing in for the device
+    let mut memory = [0x1234_u32, 0xab00_0000, 0];
+    let block = unsafe { Block::new(memory.as_mut_ptr() as u64) };
+    assert_eq!(block.id().read(), 0x1234);
+    // The reserved byte is kept as it was
+    block.control().write(0x42);
+    assert_eq!(block.control().read(), 0xab00_0042);
+    block.control().modify(|control| control | 1);
+    assert_eq!(block.control().read(), 0xab00_0043);
+    block.command().write(7);
+    assert_eq!(memory, [0x1234, 0xab00_0043, 7]);
+}
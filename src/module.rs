@@ -0,0 +1,624 @@
+//! Loadable kernel modules: ELF64 relocatable objects (`ET_REL`, what
+//! `cc -c` or `rustc --emit obj` makes), relocated against the kernel's
+//! exported symbols (heorot::ksymtab) when they're loaded. A module says
+//! which kernel API it was built against with a `KERNEL_API_VERSION` u32
+//! of its own, and only symbols of that version are found for it.
+//!
+//! Each module gets a slot of its own in the gigabyte below 2 GiB, close
+//! enough to the kernel for the small code model's 32-bit relocations both
+//! ways. Its code, read-only data and writable data each start on a page,
+//! so each gets the permissions it needs once it's relocated. Then its
+//! `extern "C" fn init() -> i32` is called, and it's only kept if that
+//! returns 0. Unloading calls its `extern "C" fn exit()`, if it has one,
+//! and frees it all.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::elf::{u16_at, u32_at, u64_at};
+use crate::memory::{self, paging, FRAME_SIZE};
+use crate::{cpu, fs, ipi, ksymtab};
+
+/// The biggest module file `load_file` will read
+pub const MAX_FILE_SIZE: usize = 256 * 1024;
+pub const MAX_MODULES: usize = 16;
+const MODULES_START: u64 = 0x4000_0000;
+// What each module can take once loaded, so they all end below 2 GiB
+const SLOT_SIZE: u64 = 0x400_0000;
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_RELOCATABLE: u16 = 1;
+const MACHINE_X86_64: u16 = 62;
+const HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+// Section types and flags
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+// Special section indexes a symbol can have
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const SHN_COMMON: u16 = 0xfff2;
+const STB_LOCAL: u8 = 0;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+const R_X86_64_PC64: u32 = 24;
+
+/// A loaded module, as `modules` lists them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub name: String,
+    /// Where its first section is
+    pub base: VirtAddr,
+    /// Bytes it takes, in whole pages
+    pub size: u64,
+}
+
+struct Module {
+    info: ModuleInfo,
+    slot: usize,
+    exit: Option<extern "C" fn()>,
+}
+
+// Never touched by interrupt handlers
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy)]
+struct Section {
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: u64,
+    link: usize,
+    info: usize,
+    align: u64,
+}
+
+struct Object<'a> {
+    image: &'a [u8],
+    sections: Vec<Section>,
+}
+
+impl<'a> Object<'a> {
+    fn parse(image: &'a [u8]) -> Result<Object<'a>, &'static str> {
+        if image.len() < HEADER_SIZE || &image[..4] != MAGIC {
+            return Err("not an ELF file");
+        }
+        if image[4] != CLASS_64 || image[5] != DATA_LITTLE_ENDIAN || u16_at(image, 18) != MACHINE_X86_64 {
+            return Err("not an x86-64 ELF file");
+        }
+        if u16_at(image, 16) != TYPE_RELOCATABLE {
+            return Err("not a relocatable object");
+        }
+        let table = u64_at(image, 40);
+        let entry_size = usize::from(u16_at(image, 58));
+        let count = usize::from(u16_at(image, 60));
+        if entry_size < SECTION_HEADER_SIZE
+            || table.checked_add((entry_size * count) as u64).map_or(true, |end| end > image.len() as u64)
+        {
+            return Err("bad section header table");
+        }
+        let mut sections = Vec::with_capacity(count);
+        for index in 0..count {
+            let header = &image[table as usize + index * entry_size..];
+            let section = Section {
+                kind: u32_at(header, 4),
+                flags: u64_at(header, 8),
+                offset: u64_at(header, 24) as usize,
+                size: u64_at(header, 32),
+                link: u32_at(header, 40) as usize,
+                info: u32_at(header, 44) as usize,
+                align: u64_at(header, 48).max(1),
+            };
+            let in_file = if section.kind == SHT_NOBITS { 0 } else { section.size };
+            if (section.offset as u64).checked_add(in_file).map_or(true, |end| end > image.len() as u64) {
+                return Err("section is cut off");
+            }
+            if !section.align.is_power_of_two() || section.align > FRAME_SIZE {
+                return Err("section is aligned too far");
+            }
+            if section.kind == SHT_REL {
+                return Err("REL relocations aren't supported, only RELA");
+            }
+            if section.link >= count {
+                return Err("bad section");
+            }
+            sections.push(section);
+        }
+        Ok(Object { image, sections })
+    }
+
+    fn bytes(&self, section: &Section) -> &'a [u8] {
+        &self.image[section.offset..section.offset + section.size as usize]
+    }
+
+    // The NUL-terminated name at `offset` in string table `table`
+    fn name(&self, table: usize, offset: usize) -> Result<&'a str, &'static str> {
+        let strings = self.bytes(self.sections.get(table).ok_or("bad string table")?);
+        let name = strings.get(offset..).ok_or("bad symbol name")?;
+        let len = name.iter().position(|&byte| byte == 0).ok_or("bad symbol name")?;
+        core::str::from_utf8(&name[..len]).map_err(|_| "bad symbol name")
+    }
+
+    fn symbol_table(&self) -> Result<&Section, &'static str> {
+        let symbols = self.sections.iter().find(|section| section.kind == SHT_SYMTAB).ok_or("no symbol table")?;
+        if symbols.size % SYMBOL_SIZE as u64 != 0 {
+            return Err("bad symbol table");
+        }
+        Ok(symbols)
+    }
+}
+
+// Where each allocated section goes, from the start of the slot, and the
+// page ranges to give code, read-only and writable permissions
+struct Layout {
+    offsets: Vec<Option<u64>>,
+    groups: [(u64, u64); 3],
+    size: u64,
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+fn lay_out(object: &Object) -> Result<Layout, &'static str> {
+    let mut offsets = vec![None; object.sections.len()];
+    let mut groups = [(0, 0); 3];
+    let mut cursor = 0;
+    let kinds: [fn(u64) -> bool; 3] = [
+        |flags| flags & SHF_EXECINSTR != 0,
+        |flags| flags & (SHF_EXECINSTR | SHF_WRITE) == 0,
+        |flags| flags & SHF_EXECINSTR == 0 && flags & SHF_WRITE != 0,
+    ];
+    for (group, in_group) in groups.iter_mut().zip(kinds) {
+        cursor = align_up(cursor, FRAME_SIZE);
+        let start = cursor;
+        for (index, section) in object.sections.iter().enumerate() {
+            if section.flags & SHF_ALLOC != 0 && in_group(section.flags) {
+                cursor = align_up(cursor, section.align);
+                offsets[index] = Some(cursor);
+                cursor = cursor.checked_add(section.size).ok_or("module is too big")?;
+            }
+        }
+        *group = (start, align_up(cursor, FRAME_SIZE));
+    }
+    let size = align_up(cursor, FRAME_SIZE);
+    if size > SLOT_SIZE {
+        return Err("module is too big");
+    }
+    Ok(Layout { offsets, groups, size })
+}
+
+fn pages(base: VirtAddr, start: u64, end: u64) -> impl Iterator<Item = Page<Size4KiB>> {
+    let first = Page::containing_address(base + start);
+    Page::range(first, first + (end - start) / FRAME_SIZE)
+}
+
+// Map `size` bytes at `base` writable and zeroed, for copying the sections in
+fn map(base: VirtAddr, size: u64) -> Result<(), &'static str> {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if cpu::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    for page in pages(base, 0, size) {
+        let frame = memory::allocate_frame().ok_or(memory::OUT_OF_MEMORY)?;
+        if let Err(message) = unsafe { paging::map_page(page, frame, flags) } {
+            unsafe { memory::deallocate_frame(frame) };
+            return Err(message);
+        }
+        unsafe { page.start_address().as_mut_ptr::<u8>().write_bytes(0, FRAME_SIZE as usize) };
+    }
+    Ok(())
+}
+
+// Unmap and free whatever of `size` bytes at `base` is mapped
+fn unmap(base: VirtAddr, size: u64) {
+    for page in pages(base, 0, size) {
+        // Nothing runs from it any more
+        if let Ok(frame) = unsafe { paging::unmap_page(page) } {
+            unsafe { memory::deallocate_frame(frame) };
+        }
+    }
+}
+
+// What each of `object`'s symbols is worth, by index: an address in the
+// module, a kernel export's, or an absolute value
+fn resolve(object: &Object, layout: &Layout, base: VirtAddr) -> Result<Vec<u64>, &'static str> {
+    let table = object.symbol_table()?;
+    let symbols = object.bytes(table);
+    let version = find(object, layout, base, "KERNEL_API_VERSION")
+        .map(|addr| unsafe { (addr as *const u32).read_unaligned() })
+        .ok_or("module doesn't say which kernel API it's built against")?;
+    let mut values = Vec::with_capacity(symbols.len() / SYMBOL_SIZE);
+    for symbol in symbols.chunks_exact(SYMBOL_SIZE) {
+        let value = u64_at(symbol, 8);
+        values.push(match u16_at(symbol, 6) {
+            SHN_UNDEF if u32_at(symbol, 0) == 0 => 0,
+            SHN_UNDEF => {
+                let name = object.name(table.link, u32_at(symbol, 0) as usize)?;
+                match ksymtab::lookup_versioned(name, version) {
+                    Some(export) => export.address as u64,
+                    None => {
+                        crate::log::warn!("module: no kernel symbol {} for API version {}", name, version);
+                        return Err("unknown symbol");
+                    }
+                }
+            }
+            SHN_ABS => value,
+            SHN_COMMON => return Err("common symbols aren't supported; build with -fno-common"),
+            index => {
+                let offset = layout.offsets.get(usize::from(index)).copied().flatten();
+                // Symbols in sections that aren't loaded, like debug info,
+                // are only ever used by them
+                offset.map_or(0, |offset| base.as_u64() + offset + value)
+            }
+        });
+    }
+    Ok(values)
+}
+
+// Where global `name` is once the module's at `base`
+fn find(object: &Object, layout: &Layout, base: VirtAddr, name: &str) -> Option<u64> {
+    let table = object.symbol_table().ok()?;
+    object.bytes(table).chunks_exact(SYMBOL_SIZE).find_map(|symbol| {
+        let index = usize::from(u16_at(symbol, 6));
+        if symbol[4] >> 4 == STB_LOCAL || object.name(table.link, u32_at(symbol, 0) as usize) != Ok(name) {
+            return None;
+        }
+        let offset = layout.offsets.get(index).copied().flatten()?;
+        Some(base.as_u64() + offset + u64_at(symbol, 8))
+    })
+}
+
+fn relocate(object: &Object, layout: &Layout, base: VirtAddr, values: &[u64]) -> Result<(), &'static str> {
+    for relocations in object.sections.iter().filter(|section| section.kind == SHT_RELA) {
+        let target = match layout.offsets.get(relocations.info).copied().flatten() {
+            Some(offset) => offset,
+            // For a section that isn't loaded
+            None => continue,
+        };
+        let target_size = object.sections[relocations.info].size;
+        for rela in object.bytes(relocations).chunks_exact(RELA_SIZE) {
+            let offset = u64_at(rela, 0);
+            let info = u64_at(rela, 8);
+            let addend = u64_at(rela, 16);
+            let symbol = *values.get((info >> 32) as usize).ok_or("bad relocation")?;
+            let kind = info as u32;
+            let width = match kind {
+                R_X86_64_NONE => continue,
+                R_X86_64_64 | R_X86_64_PC64 => 8,
+                R_X86_64_PC32 | R_X86_64_PLT32 | R_X86_64_32 | R_X86_64_32S => 4,
+                _ => return Err("unsupported relocation"),
+            };
+            if offset.checked_add(width).map_or(true, |end| end > target_size) {
+                return Err("bad relocation");
+            }
+            let place = base.as_u64() + target + offset;
+            let value = symbol.wrapping_add(addend);
+            let pc_relative = value.wrapping_sub(place);
+            let at = place as *mut u8;
+            unsafe {
+                match kind {
+                    R_X86_64_64 => (at as *mut u64).write_unaligned(value),
+                    R_X86_64_PC64 => (at as *mut u64).write_unaligned(pc_relative),
+                    R_X86_64_32 => {
+                        let value = u32::try_from(value).map_err(|_| "relocation is out of range")?;
+                        (at as *mut u32).write_unaligned(value);
+                    }
+                    R_X86_64_32S => {
+                        let value = i32::try_from(value as i64).map_err(|_| "relocation is out of range")?;
+                        (at as *mut i32).write_unaligned(value);
+                    }
+                    _ => {
+                        let value = i32::try_from(pc_relative as i64).map_err(|_| "relocation is out of range")?;
+                        (at as *mut i32).write_unaligned(value);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Code read-only and executable, the rest no-execute, and writable only
+// where it asked to be
+fn protect(base: VirtAddr, layout: &Layout) -> Result<(), &'static str> {
+    for (group, &(start, end)) in layout.groups.iter().enumerate() {
+        let mut flags = PageTableFlags::PRESENT;
+        if group == 2 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if group != 0 && cpu::nx_enabled() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        for page in pages(base, start, end) {
+            unsafe { paging::with_entry(page, |entry| entry.set_flags(flags)) };
+            ipi::flush_tlb(page.start_address())?;
+        }
+    }
+    Ok(())
+}
+
+// Everything but calling init; `mapped` is how much of the slot may need
+// unmapping if it fails
+fn place(image: &[u8], base: VirtAddr, mapped: &mut u64) -> Result<(u64, Option<u64>), &'static str> {
+    let object = Object::parse(image)?;
+    let layout = lay_out(&object)?;
+    *mapped = layout.size;
+    map(base, layout.size)?;
+    for (section, offset) in object.sections.iter().zip(&layout.offsets) {
+        if let (Some(offset), true) = (offset, section.kind != SHT_NOBITS) {
+            let to = (base + *offset).as_mut_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(object.bytes(section).as_ptr(), to, section.size as usize) };
+        }
+    }
+    let values = resolve(&object, &layout, base)?;
+    relocate(&object, &layout, base, &values)?;
+    let init = find(&object, &layout, base, "init").ok_or("module has no init")?;
+    let exit = find(&object, &layout, base, "exit");
+    let in_code = |addr: u64| (layout.groups[0].0..layout.groups[0].1).contains(&(addr - base.as_u64()));
+    if !in_code(init) || !exit.map_or(true, in_code) {
+        return Err("module's init or exit isn't code");
+    }
+    protect(base, &layout)?;
+    Ok((init, exit))
+}
+
+/// Load the module in `image` as `name`, and run its init
+pub fn load(name: &str, image: &[u8]) -> Result<(), &'static str> {
+    let mut modules = MODULES.lock();
+    if modules.iter().any(|module| module.info.name == name) {
+        return Err("a module by that name is loaded");
+    }
+    let slot = (0..MAX_MODULES).find(|&slot| modules.iter().all(|module| module.slot != slot))
+        .ok_or("too many modules")?;
+    let base = VirtAddr::new(MODULES_START + slot as u64 * SLOT_SIZE);
+    let mut size = 0;
+    let (init, exit) = match place(image, base, &mut size) {
+        Ok(entries) => entries,
+        Err(message) => {
+            unmap(base, size);
+            return Err(message);
+        }
+    };
+    // Relocated and checked to be in its code
+    let (init, exit): (extern "C" fn() -> i32, Option<extern "C" fn()>) =
+        unsafe { (core::mem::transmute(init), exit.map(|exit| core::mem::transmute(exit))) };
+    if init() != 0 {
+        unmap(base, size);
+        return Err("module's init failed");
+    }
+    modules.push(Module { info: ModuleInfo { name: String::from(name), base, size }, slot, exit });
+    Ok(())
+}
+
+/// Read the module at `path` and `load` it, named for the file without its
+/// extension
+pub fn load_file(path: &str) -> Result<(), &'static str> {
+    let mut file = fs::File::open_for(path, fs::Access::Read)?;
+    let size = file.size() as usize;
+    if size > MAX_FILE_SIZE {
+        return Err("file is too big to load");
+    }
+    let mut image = vec![0u8; size];
+    let mut done = 0;
+    while done < size {
+        match file.read(&mut image[done..])? {
+            0 => return Err("file ended early"),
+            n => done += n,
+        }
+    }
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    load(file_name.split('.').next().unwrap_or(file_name), &image)
+}
+
+/// Run module `name`'s exit, if it has one, and free it
+pub fn unload(name: &str) -> Result<(), &'static str> {
+    let mut modules = MODULES.lock();
+    let index = modules.iter().position(|module| module.info.name == name).ok_or("no such module")?;
+    let module = modules.remove(index);
+    if let Some(exit) = module.exit {
+        exit();
+    }
+    unmap(module.info.base, module.info.size);
+    Ok(())
+}
+
+/// The loaded modules, in the order they were loaded
+pub fn modules() -> Vec<ModuleInfo> {
+    MODULES.lock().iter().map(|module| module.info.clone()).collect()
+}
+
+#[cfg(feature = "shell")]
+fn cmd_insmod(args: &[&str]) -> crate::shell::Status {
+    use crate::println;
+    use crate::shell::{FAILURE, SUCCESS};
+
+    let path = match args {
+        [path] => path,
+        _ => {
+            println!("usage: insmod <file>");
+            return FAILURE;
+        }
+    };
+    match load_file(path) {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("insmod: {}: {}", path, message);
+            FAILURE
+        }
+    }
+}
+
+#[cfg(feature = "shell")]
+fn cmd_rmmod(args: &[&str]) -> crate::shell::Status {
+    use crate::println;
+    use crate::shell::{FAILURE, SUCCESS};
+
+    let name = match args {
+        [name] => name,
+        _ => {
+            println!("usage: rmmod <module>");
+            return FAILURE;
+        }
+    };
+    match unload(name) {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("rmmod: {}: {}", name, message);
+            FAILURE
+        }
+    }
+}
+
+#[cfg(feature = "shell")]
+fn cmd_lsmod(_args: &[&str]) -> crate::shell::Status {
+    crate::println!("MODULE           ADDRESS       SIZE");
+    for module in modules() {
+        crate::println!("{:<16} {:#012x}  {}K", module.name, module.base.as_u64(), module.size / 1024);
+    }
+    crate::shell::SUCCESS
+}
+
+/// Add the `insmod`, `rmmod` and `lsmod` commands
+pub fn init() {
+    #[cfg(feature = "shell")]
+    {
+        crate::shell::register(crate::shell::Command {
+            name: "insmod",
+            help: "load a kernel module from an ELF object file",
+            run: cmd_insmod,
+            complete: None,
+        })
+        .expect("couldn't register insmod");
+        crate::shell::register(crate::shell::Command {
+            name: "rmmod",
+            help: "unload a kernel module",
+            run: cmd_rmmod,
+            complete: None,
+        })
+        .expect("couldn't register rmmod");
+        crate::shell::register(crate::shell::Command {
+            name: "lsmod",
+            help: "list the loaded kernel modules",
+            run: cmd_lsmod,
+            complete: None,
+        })
+        .expect("couldn't register lsmod");
+    }
+}
+
+/// TESTS
+
+// An object with `code` in .text, init at its start and exit at its last
+// byte, and in .data a pointer that's relocated to `import` and then
+// KERNEL_API_VERSION
+#[cfg(test)]
+fn object(code: &[u8], import: &str, version: u32) -> Vec<u8> {
+    fn section(image: &mut Vec<u8>, fields: [u64; 8]) {
+        let [name, kind, flags, offset, size, link, info, align] = fields;
+        for (value, width) in [(name, 4), (kind, 4), (flags, 8), (0, 8), (offset, 8), (size, 8), (link, 4),
+            (info, 4), (align, 8), (0, 8)]
+        {
+            image.extend_from_slice(&value.to_le_bytes()[..width]);
+        }
+    }
+    fn symbol(symbols: &mut Vec<u8>, name: u32, index: u16, value: u64) {
+        symbols.extend_from_slice(&name.to_le_bytes());
+        // Global, and a function or an object doesn't matter here
+        symbols.extend_from_slice(&[0x10, 0]);
+        symbols.extend_from_slice(&index.to_le_bytes());
+        symbols.extend_from_slice(&value.to_le_bytes());
+        symbols.extend_from_slice(&0u64.to_le_bytes());
+    }
+
+    let mut data = vec![0u8; 8];
+    data.extend_from_slice(&version.to_le_bytes());
+    let strings = alloc::format!("\0init\0exit\0KERNEL_API_VERSION\0{}\0", import);
+    let mut symbols = vec![0u8; SYMBOL_SIZE];
+    symbol(&mut symbols, 1, 1, 0);
+    symbol(&mut symbols, 6, 1, code.len() as u64 - 1);
+    symbol(&mut symbols, 11, 2, 8);
+    symbol(&mut symbols, 30, SHN_UNDEF, 0);
+    let mut rela = Vec::new();
+    rela.extend_from_slice(&0u64.to_le_bytes());
+    rela.extend_from_slice(&((4u64 << 32) | u64::from(R_X86_64_64)).to_le_bytes());
+    rela.extend_from_slice(&0u64.to_le_bytes());
+
+    let mut image = vec![0u8; HEADER_SIZE];
+    let mut offsets = Vec::new();
+    for part in [code, &data, &rela, &symbols, strings.as_bytes()] {
+        offsets.push(image.len() as u64);
+        image.extend_from_slice(part);
+    }
+    let table = image.len() as u64;
+    section(&mut image, [0; 8]);
+    section(&mut image, [0, 1, SHF_ALLOC | SHF_EXECINSTR, offsets[0], code.len() as u64, 0, 0, 16]);
+    section(&mut image, [0, 1, SHF_ALLOC | SHF_WRITE, offsets[1], data.len() as u64, 0, 0, 8]);
+    section(&mut image, [0, u64::from(SHT_RELA), 0, offsets[2], rela.len() as u64, 4, 2, 8]);
+    section(&mut image, [0, u64::from(SHT_SYMTAB), 0, offsets[3], symbols.len() as u64, 5, 1, 8]);
+    section(&mut image, [0, 3, 0, offsets[4], strings.len() as u64, 0, 0, 1]);
+    image[..4].copy_from_slice(MAGIC);
+    image[4] = CLASS_64;
+    image[5] = DATA_LITTLE_ENDIAN;
+    image[6] = 1;
+    image[16..18].copy_from_slice(&TYPE_RELOCATABLE.to_le_bytes());
+    image[18..20].copy_from_slice(&MACHINE_X86_64.to_le_bytes());
+    image[40..48].copy_from_slice(&table.to_le_bytes());
+    image[52..54].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image[58..60].copy_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+    image[60..62].copy_from_slice(&6u16.to_le_bytes());
+    image
+}
+
+#[test_case]
+fn test_loads_and_relocates_a_module() {
+    // xor eax, eax; ret; then exit's ret
+    let image = object(&[0x31, 0xc0, 0xc3, 0xc3], "hlt_loop", ksymtab::KERNEL_API_VERSION);
+    load("test_module", &image).unwrap();
+    assert_eq!(load("test_module", &image), Err("a module by that name is loaded"));
+    let module = modules().into_iter().find(|module| module.name == "test_module").unwrap();
+    // .data is on the page after .text
+    let pointer = unsafe { (module.base + FRAME_SIZE).as_ptr::<u64>().read() };
+    assert_eq!(pointer, crate::hlt_loop as usize as u64);
+    let code = Page::<Size4KiB>::containing_address(module.base);
+    let flags = unsafe { paging::with_entry(code, |entry| entry.flags()) }.unwrap();
+    assert!(!flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE));
+    unload("test_module").unwrap();
+    assert_eq!(paging::translate_addr(module.base), None);
+    assert_eq!(unload("test_module"), Err("no such module"));
+}
+
+#[test_case]
+fn test_turns_away_modules_that_cant_load() {
+    let free_frames = || memory::with_frame_allocator(|frames| frames.free_frames()).unwrap();
+    let fails = object(&[0xb8, 1, 0, 0, 0, 0xc3], "hlt_loop", ksymtab::KERNEL_API_VERSION);
+    assert_eq!(load("failing", &fails), Err("module's init failed"));
+    // The page tables the first go needed stay
+    let before = free_frames();
+    assert_eq!(load("failing", &fails), Err("module's init failed"));
+    let unknown = object(&[0x31, 0xc0, 0xc3], "not_a_kernel_symbol", ksymtab::KERNEL_API_VERSION);
+    assert_eq!(load("unknown", &unknown), Err("unknown symbol"));
+    let newer = object(&[0x31, 0xc0, 0xc3], "hlt_loop", ksymtab::KERNEL_API_VERSION + 1);
+    assert_eq!(load("newer", &newer), Err("unknown symbol"));
+    assert_eq!(load("junk", &fails[..HEADER_SIZE]), Err("bad section header table"));
+    assert!(modules().iter().all(|module| module.name != "failing"));
+    assert_eq!(free_frames(), before);
+}
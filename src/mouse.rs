@@ -0,0 +1,182 @@
+use spin::Mutex;
+use crate::i8042::{self, controller_command, read_data, write_data};
+use crate::sync::SpscQueue;
+use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+// Controller commands
+const ENABLE_AUX: u8 = 0xa8;
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const WRITE_AUX: u8 = 0xd4;
+
+// Controller configuration byte bits
+const CONFIG_AUX_IRQ: u8 = 0x02;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 0x20;
+
+// Mouse commands
+const SET_DEFAULTS: u8 = 0xf6;
+const ENABLE_REPORTING: u8 = 0xf4;
+
+// Mouse motion counts per text cell; rows are taller than columns are wide
+const COUNTS_PER_COL: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+
+const QUEUE_SIZE: usize = 64;
+
+/// Which mouse buttons are down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Pointer position in text cells after a packet, and the buttons held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub row: usize,
+    pub col: usize,
+    pub buttons: Buttons,
+}
+
+struct Mouse {
+    packet: [u8; 3],
+    received: usize,
+    // Pointer position in motion counts, so slow movement still adds up
+    x: i32,
+    y: i32,
+}
+
+impl Mouse {
+    const fn new() -> Mouse {
+        Mouse {
+            packet: [0; 3],
+            received: 0,
+            x: BUFFER_WIDTH as i32 / 2 * COUNTS_PER_COL,
+            y: BUFFER_HEIGHT as i32 / 2 * COUNTS_PER_ROW,
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Bit 3 of the first byte is always set; use it to resynchronize
+        if self.received == 0 && byte & 0x08 == 0 {
+            return None;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet.len() {
+            return None;
+        }
+        self.received = 0;
+
+        let [flags, dx, dy] = self.packet;
+        // Overflowed motion is garbage; keep the buttons, skip the move
+        if flags & 0xc0 == 0 {
+            let dx = i32::from(dx) - (i32::from(flags & 0x10) << 4);
+            let dy = i32::from(dy) - (i32::from(flags & 0x20) << 3);
+            let max_x = BUFFER_WIDTH as i32 * COUNTS_PER_COL - 1;
+            let max_y = BUFFER_HEIGHT as i32 * COUNTS_PER_ROW - 1;
+            self.x = (self.x + dx).max(0).min(max_x);
+            // The mouse counts up as it moves away from you, rows count down
+            self.y = (self.y - dy).max(0).min(max_y);
+        }
+
+        Some(MouseEvent {
+            row: (self.y / COUNTS_PER_ROW) as usize,
+            col: (self.x / COUNTS_PER_COL) as usize,
+            buttons: Buttons {
+                left: flags & 0x01 != 0,
+                right: flags & 0x02 != 0,
+                middle: flags & 0x04 != 0,
+            },
+        })
+    }
+}
+
+// Packet decoding state; only the IRQ12 handler touches it
+static MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
+static EVENTS: SpscQueue<MouseEvent, QUEUE_SIZE> = SpscQueue::new();
+
+/// Called by the IRQ12 handler with each byte from the controller
+pub(crate) fn push_byte(byte: u8) {
+    if crate::replay::observe(crate::replay::Source::Mouse, byte) {
+        feed_byte(byte);
+    }
+}
+
+/// Decode a byte as if from the mouse; for the IRQ12 handler, and replays.
+/// Called with interrupts off.
+pub(crate) fn feed_byte(byte: u8) {
+    if let Some(event) = MOUSE.lock().add_byte(byte) {
+        // Losing pointer motion isn't worth a warning; the next packet catches up
+        let _ = EVENTS.push(event);
+    }
+}
+
+pub fn try_read_event() -> Option<MouseEvent> {
+    EVENTS.pop()
+}
+
+/// Whether events are queued, e.g. to check with interrupts off before idling
+pub fn event_pending() -> bool {
+    !EVENTS.is_empty()
+}
+
+fn mouse_command(command: u8) -> Result<(), &'static str> {
+    controller_command(WRITE_AUX)?;
+    write_data(command)?;
+    match read_data()? {
+        i8042::ACK => Ok(()),
+        _ => Err("mouse didn't acknowledge command"),
+    }
+}
+
+/// Turn on the 8042's auxiliary port and start the mouse streaming packets
+/// on IRQ12. Polls the controller, so run it with interrupts still off.
+pub fn init() -> Result<(), &'static str> {
+    use crate::device::{self, State};
+    use crate::resource::{self, Resource};
+
+    let id = device::register("mouse", "PS/2 mouse", Some(device::i8042()))?;
+    let result = resource::claim(id, Resource::Irq(crate::interrupts::MOUSE_IRQ)).and_then(|()| enable());
+    device::bind(id, "mouse", if result.is_ok() { State::Active } else { State::Failed });
+    result
+}
+
+fn enable() -> Result<(), &'static str> {
+    controller_command(ENABLE_AUX)?;
+    controller_command(READ_CONFIG)?;
+    let config = read_data()?;
+    controller_command(WRITE_CONFIG)?;
+    write_data((config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED)?;
+
+    mouse_command(SET_DEFAULTS)?;
+    mouse_command(ENABLE_REPORTING)?;
+    crate::interrupts::unmask_irq(crate::interrupts::MOUSE_IRQ);
+    Ok(())
+}
+
+/// TESTS
+
+#[test_case]
+fn test_packet_decoding() {
+    let mut mouse = Mouse::new();
+    let (row, col) = (BUFFER_HEIGHT / 2, BUFFER_WIDTH / 2);
+
+    // A stray byte without the sync bit is dropped
+    assert_eq!(mouse.add_byte(0x00), None);
+
+    // Left button, one column right and one row up
+    assert_eq!(mouse.add_byte(0x09), None);
+    assert_eq!(mouse.add_byte(COUNTS_PER_COL as u8), None);
+    let event = mouse.add_byte(COUNTS_PER_ROW as u8).unwrap();
+    assert_eq!((event.row, event.col), (row - 1, col + 1));
+    assert!(event.buttons.left && !event.buttons.right);
+
+    // Negative X motion is sign-extended through bit 4 of the flags
+    mouse.add_byte(0x18);
+    mouse.add_byte((-COUNTS_PER_COL) as u8);
+    let event = mouse.add_byte(0).unwrap();
+    assert_eq!(event.col, col);
+    assert!(!event.buttons.left);
+}
@@ -0,0 +1,144 @@
+//! Model-specific registers, by name. Each says whether every x86-64 CPU
+//! has it, or which CPUID feature says so, or that only trying tells; and
+//! whether it's read, written or both, with mmio's access markers. One the
+//! CPU doesn't have is an error, not a #GP: CPUID is asked first, and, as
+//! hypervisors often leave out MSRs it advertises, the access is made under
+//! `catch_fault` too. Architectural MSRs go straight to rdmsr and wrmsr,
+//! so they're cheap enough for hot paths, like GS_BASE is.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr as RawMsr;
+use crate::cpu::{self, Feature};
+use crate::mmio::{Readable, Writable};
+
+pub use crate::mmio::{ReadOnly, ReadWrite, WriteOnly};
+
+pub const UNSUPPORTED: &str = "MSR not supported";
+
+/// How to tell whether the CPU has an MSR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Availability {
+    /// Every x86-64 CPU has it
+    Architectural,
+    /// CPUID advertises it with this
+    Feature(Feature),
+    /// Nothing says; only trying tells
+    Probe,
+}
+
+/// An MSR, which `A` says can be read, written or both
+pub struct Msr<A> {
+    number: u32,
+    availability: Availability,
+    access: PhantomData<A>,
+}
+
+impl<A> Msr<A> {
+    pub const fn new(number: u32, availability: Availability) -> Msr<A> {
+        Msr { number, availability, access: PhantomData }
+    }
+
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    // Whether CPUID rules it out
+    fn advertised(&self) -> bool {
+        match self.availability {
+            Availability::Feature(feature) => cpu::has_feature(feature),
+            Availability::Architectural | Availability::Probe => true,
+        }
+    }
+}
+
+impl<A: Readable> Msr<A> {
+    pub fn read(&self) -> Result<u64, &'static str> {
+        match self.availability {
+            // Every CPU we run on has it
+            Availability::Architectural => Ok(unsafe { RawMsr::new(self.number).read() }),
+            _ if !self.advertised() => Err(UNSUPPORTED),
+            _ => probe(self.number, None),
+        }
+    }
+}
+
+impl<A: Writable> Msr<A> {
+    /// # Safety
+    /// An MSR can change how the CPU does anything at all; the value has to
+    /// be one the rest of the kernel can live with.
+    pub unsafe fn write(&self, value: u64) -> Result<(), &'static str> {
+        match self.availability {
+            Availability::Architectural => {
+                RawMsr::new(self.number).write(value);
+                Ok(())
+            }
+            _ if !self.advertised() => Err(UNSUPPORTED),
+            _ => probe(self.number, Some(value)).map(|_| ()),
+        }
+    }
+}
+
+impl Msr<ReadWrite> {
+    /// Read, change and write back
+    ///
+    /// # Safety
+    /// As for `write`.
+    pub unsafe fn update(&self, f: impl FnOnce(u64) -> u64) -> Result<(), &'static str> {
+        let value = self.read()?;
+        self.write(f(value))
+    }
+}
+
+// The MSR being probed, what's to be written to it if anything, and what
+// was read; catch_fault only takes a plain fn, so they go through here
+static PROBE: Mutex<()> = Mutex::new(());
+static PROBE_MSR: AtomicU64 = AtomicU64::new(0);
+static PROBE_WRITE: AtomicU64 = AtomicU64::new(0);
+static PROBE_VALUE: AtomicU64 = AtomicU64::new(0);
+// The top half of PROBE_MSR, when there's something to write
+const WRITING: u64 = 1 << 32;
+
+// Read MSR `number`, or write `value` to it, catching the #GP if it isn't there
+fn probe(number: u32, value: Option<u64>) -> Result<u64, &'static str> {
+    fn access() {
+        let probe = PROBE_MSR.load(Ordering::SeqCst);
+        let mut msr = RawMsr::new(probe as u32);
+        if probe & WRITING != 0 {
+            unsafe { msr.write(PROBE_WRITE.load(Ordering::SeqCst)) };
+        } else {
+            PROBE_VALUE.store(unsafe { msr.read() }, Ordering::SeqCst);
+        }
+    }
+
+    let _probe = PROBE.lock();
+    PROBE_WRITE.store(value.unwrap_or(0), Ordering::SeqCst);
+    PROBE_MSR.store(u64::from(number) | if value.is_some() { WRITING } else { 0 }, Ordering::SeqCst);
+    crate::interrupts::catch_fault(access).map_err(|_| UNSUPPORTED)?;
+    Ok(PROBE_VALUE.load(Ordering::SeqCst))
+}
+
+pub const TSC: Msr<ReadOnly> = Msr::new(0x10, Availability::Feature(Feature::Tsc));
+pub const APIC_BASE: Msr<ReadWrite> = Msr::new(0x1b, Availability::Feature(Feature::Apic));
+pub const MPERF: Msr<ReadOnly> = Msr::new(0xe7, Availability::Feature(Feature::AperfMperf));
+pub const APERF: Msr<ReadOnly> = Msr::new(0xe8, Availability::Feature(Feature::AperfMperf));
+pub const THERM_STATUS: Msr<ReadOnly> = Msr::new(0x19c, Availability::Feature(Feature::DigitalThermalSensor));
+/// Intel's, and not always even there
+pub const TEMPERATURE_TARGET: Msr<ReadOnly> = Msr::new(0x1a2, Availability::Probe);
+pub const PACKAGE_THERM_STATUS: Msr<ReadOnly> = Msr::new(0x1b1, Availability::Feature(Feature::PackageThermal));
+pub const GS_BASE: Msr<ReadWrite> = Msr::new(0xc000_0101, Availability::Architectural);
+
+/// TESTS
+
+#[test_case]
+fn test_msrs() {
+    let first = TSC.read().expect("every CPU we run on has a TSC");
+    let second = TSC.read().unwrap();
+    assert!(second > first);
+    // Nothing's there; the #GP is caught, and so is the write's
+    const NOWHERE: Msr<ReadWrite> = Msr::new(0x2fff, Availability::Probe);
+    assert_eq!(NOWHERE.read(), Err(UNSUPPORTED));
+    assert_eq!(unsafe { NOWHERE.write(1) }, Err(UNSUPPORTED));
+    assert!(GS_BASE.read().is_ok());
+}
@@ -0,0 +1,541 @@
+//! Network interfaces by name. Each is a NetDevice, up or down, with an
+//! MTU no bigger than the device's and an IPv4 address if it's been given
+//! one. Frames go in and out through `send` and `receive` here, which only
+//! let them through an interface that's up, and count what goes through.
+//! "lo", on the loopback device, is always there, up, as 127.0.0.1/8.
+//!
+//! `vlan` puts 802.1Q VLANs over another interface's device, and `pcap`
+//! records what goes through. `route` has the table of which interface
+//! reaches where. There's no IP layer yet, so nothing sends by it.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::poll_fn;
+use core::str::FromStr;
+use spin::Mutex;
+use crate::drivers::loopback::LOOPBACK;
+use crate::io::NetDevice;
+use crate::metrics::{self, Metric, Value};
+
+pub mod pcap;
+pub mod route;
+pub mod vlan;
+
+const MAX_INTERFACES: usize = 8;
+/// A frame's destination and source MACs and its EtherType, which every
+/// MTU has room for
+pub const ETHERNET_HEADER_LEN: usize = 14;
+/// What a name that isn't an interface's fails with
+pub const NO_SUCH_INTERFACE: &str = "no such interface";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+
+    pub fn from_u32(addr: u32) -> Ipv4Addr {
+        Ipv4Addr(addr.to_be_bytes())
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// Four decimal bytes with dots between
+impl FromStr for Ipv4Addr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Ipv4Addr, &'static str> {
+        let mut bytes = [0; 4];
+        let mut parts = s.split('.');
+        for byte in bytes.iter_mut() {
+            *byte = parts.next().and_then(|part| part.parse().ok()).ok_or("bad IPv4 address")?;
+        }
+        match parts.next() {
+            Some(_) => Err("bad IPv4 address"),
+            None => Ok(Ipv4Addr(bytes)),
+        }
+    }
+}
+
+/// An address and how many of its leading bits are the network's, as in
+/// 10.0.2.15/24
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv4Cidr {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    pub fn new(address: Ipv4Addr, prefix_len: u8) -> Result<Ipv4Cidr, &'static str> {
+        if prefix_len > 32 {
+            return Err("prefix is longer than 32 bits");
+        }
+        Ok(Ipv4Cidr { address, prefix_len })
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0))
+    }
+
+    /// The address with the host's bits cleared
+    pub fn network(&self) -> Ipv4Cidr {
+        let address = Ipv4Addr::from_u32(self.address.to_u32() & self.netmask().to_u32());
+        Ipv4Cidr { address, prefix_len: self.prefix_len }
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        addr.to_u32() & self.netmask().to_u32() == self.network().address.to_u32()
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// An address, a slash and the prefix length; without them, /32
+impl FromStr for Ipv4Cidr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Ipv4Cidr, &'static str> {
+        match s.split_once('/') {
+            Some((address, prefix_len)) => {
+                Ipv4Cidr::new(address.parse()?, prefix_len.parse().map_err(|_| "bad prefix length")?)
+            }
+            None => Ipv4Cidr::new(s.parse()?, 32),
+        }
+    }
+}
+
+struct Interface {
+    name: &'static str,
+    device: &'static (dyn NetDevice + Sync),
+    up: bool,
+    // The biggest frame it sends, header and all
+    mtu: usize,
+    address: Option<Ipv4Cidr>,
+    stats: Stats,
+}
+
+/// What's gone through an interface since it was added
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Receives the device failed
+    pub rx_errors: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Sends the device failed
+    pub tx_errors: u64,
+    /// Sends refused before they got to the device, as it was down or the
+    /// frame was bigger than the MTU
+    pub tx_dropped: u64,
+}
+
+/// How an interface is, as `info` and `interfaces` tell it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceInfo {
+    pub name: &'static str,
+    pub mac: [u8; 6],
+    pub up: bool,
+    pub mtu: usize,
+    pub address: Option<Ipv4Cidr>,
+    pub stats: Stats,
+}
+
+impl Interface {
+    fn info(&self) -> InterfaceInfo {
+        let (name, mac, up, mtu) = (self.name, self.device.mac(), self.up, self.mtu);
+        InterfaceInfo { name, mac, up, mtu, address: self.address, stats: self.stats }
+    }
+}
+
+const NO_INTERFACE: Option<Interface> = None;
+// An array, not a Vec, as lo is added before there's a heap. Never touched
+// by interrupt handlers.
+static INTERFACES: Mutex<[Option<Interface>; MAX_INTERFACES]> = Mutex::new([NO_INTERFACE; MAX_INTERFACES]);
+
+fn with_interface<R>(name: &str, f: impl FnOnce(&mut Interface) -> R) -> Result<R, &'static str> {
+    let mut interfaces = INTERFACES.lock();
+    let interface = interfaces.iter_mut().flatten().find(|interface| interface.name == name);
+    Ok(f(interface.ok_or(NO_SUCH_INTERFACE)?))
+}
+
+/// Add an interface on `device`, down, with the device's MTU and no address
+pub fn register(name: &'static str, device: &'static (dyn NetDevice + Sync)) -> Result<(), &'static str> {
+    let mut interfaces = INTERFACES.lock();
+    if interfaces.iter().flatten().any(|interface| interface.name == name) {
+        return Err("an interface has that name");
+    }
+    let slot = interfaces.iter_mut().find(|slot| slot.is_none()).ok_or("too many interfaces")?;
+    let mtu = device.mtu();
+    *slot = Some(Interface { name, device, up: false, mtu, address: None, stats: Stats::default() });
+    Ok(())
+}
+
+/// Take an interface away, and the routes through it
+pub fn unregister(name: &str) -> Result<(), &'static str> {
+    {
+        let mut interfaces = INTERFACES.lock();
+        let slot = interfaces
+            .iter_mut()
+            .find(|slot| slot.as_ref().map_or(false, |interface| interface.name == name))
+            .ok_or(NO_SUCH_INTERFACE)?;
+        *slot = None;
+    }
+    route::remove_through(name);
+    Ok(())
+}
+
+/// Bring an interface up, or take it down
+pub fn set_up(name: &str, up: bool) -> Result<(), &'static str> {
+    with_interface(name, |interface| interface.up = up)
+}
+
+/// Have an interface send frames no bigger than `mtu`, header and all
+pub fn set_mtu(name: &str, mtu: usize) -> Result<(), &'static str> {
+    with_interface(name, |interface| {
+        if mtu < ETHERNET_HEADER_LEN {
+            return Err("MTU is smaller than a header");
+        }
+        if mtu > interface.device.mtu() {
+            return Err("MTU is bigger than the device takes");
+        }
+        interface.mtu = mtu;
+        Ok(())
+    })?
+}
+
+/// Give an interface an address, or take its away with None
+pub fn set_address(name: &str, address: Option<Ipv4Cidr>) -> Result<(), &'static str> {
+    with_interface(name, |interface| interface.address = address)
+}
+
+pub fn info(name: &str) -> Option<InterfaceInfo> {
+    with_interface(name, |interface| interface.info()).ok()
+}
+
+/// Every interface
+pub fn interfaces() -> Vec<InterfaceInfo> {
+    INTERFACES.lock().iter().flatten().map(Interface::info).collect()
+}
+
+// The device under an interface that's up, and the interface's MTU
+fn device(name: &str) -> Result<(&'static (dyn NetDevice + Sync), usize), &'static str> {
+    with_interface(name, |interface| {
+        if interface.up { Ok((interface.device, interface.mtu)) } else { Err("interface is down") }
+    })?
+}
+
+// Nothing, if it's been taken away meanwhile
+fn count(name: &str, f: impl FnOnce(&mut Stats)) {
+    let _ = with_interface(name, |interface| f(&mut interface.stats));
+}
+
+/// Send `frame` out of interface `name`, once its device has room. It has
+/// to fit the interface's MTU.
+pub async fn send(name: &str, frame: &[u8]) -> Result<(), &'static str> {
+    let checked = device(name).and_then(|(device, mtu)| {
+        if frame.len() <= mtu { Ok(device) } else { Err("frame is bigger than the MTU") }
+    });
+    let device = match checked {
+        Ok(device) => device,
+        Err(message) => {
+            count(name, |stats| stats.tx_dropped += 1);
+            return Err(message);
+        }
+    };
+    #[cfg(feature = "fault-inject")]
+    let sent = crate::fault::check(crate::fault::Point::NetSend);
+    #[cfg(not(feature = "fault-inject"))]
+    let sent = Ok(());
+    let sent = match sent {
+        Ok(()) => poll_fn(|context| device.poll_send(context, frame)).await,
+        Err(message) => Err(message),
+    };
+    count(name, |stats| match sent {
+        Ok(()) => {
+            stats.tx_packets += 1;
+            stats.tx_bytes += frame.len() as u64;
+        }
+        Err(_) => stats.tx_errors += 1,
+    });
+    if sent.is_ok() {
+        pcap::record(name, pcap::Direction::Out, frame);
+    }
+    sent
+}
+
+/// The next frame that comes in on interface `name`, into `buf`; how long
+/// it was
+pub async fn receive(name: &str, buf: &mut [u8]) -> Result<usize, &'static str> {
+    let (device, _) = device(name)?;
+    let received = poll_fn(|context| device.poll_receive(context, buf)).await;
+    #[cfg(feature = "fault-inject")]
+    let received = received.and_then(|len| crate::fault::check(crate::fault::Point::NetReceive).map(|()| len));
+    count(name, |stats| match received {
+        Ok(len) => {
+            stats.rx_packets += 1;
+            stats.rx_bytes += len as u64;
+        }
+        Err(_) => stats.rx_errors += 1,
+    });
+    if let Ok(len) = received {
+        pcap::record(name, pcap::Direction::In, &buf[..len]);
+    }
+    received
+}
+
+// Each interface's count of one thing, labeled with its name, for metrics
+fn each_interface(f: &mut dyn FnMut(&str, u64), count: fn(&Stats) -> u64) {
+    for info in interfaces() {
+        f(info.name, count(&info.stats));
+    }
+}
+
+fn rx_packets(f: &mut dyn FnMut(&str, u64)) {
+    each_interface(f, |stats| stats.rx_packets)
+}
+
+fn rx_bytes(f: &mut dyn FnMut(&str, u64)) {
+    each_interface(f, |stats| stats.rx_bytes)
+}
+
+fn rx_errors(f: &mut dyn FnMut(&str, u64)) {
+    each_interface(f, |stats| stats.rx_errors)
+}
+
+fn tx_packets(f: &mut dyn FnMut(&str, u64)) {
+    each_interface(f, |stats| stats.tx_packets)
+}
+
+fn tx_bytes(f: &mut dyn FnMut(&str, u64)) {
+    each_interface(f, |stats| stats.tx_bytes)
+}
+
+fn tx_errors(f: &mut dyn FnMut(&str, u64)) {
+    each_interface(f, |stats| stats.tx_errors)
+}
+
+fn tx_dropped(f: &mut dyn FnMut(&str, u64)) {
+    each_interface(f, |stats| stats.tx_dropped)
+}
+
+const NET_METRICS: [Metric; 7] = [
+    Metric {
+        name: "heorot_net_received_packets_total",
+        help: "Frames received",
+        value: Value::Labeled("interface", rx_packets),
+    },
+    Metric {
+        name: "heorot_net_received_bytes_total",
+        help: "Bytes received",
+        value: Value::Labeled("interface", rx_bytes),
+    },
+    Metric {
+        name: "heorot_net_receive_errors_total",
+        help: "Receives the device failed",
+        value: Value::Labeled("interface", rx_errors),
+    },
+    Metric {
+        name: "heorot_net_sent_packets_total",
+        help: "Frames sent",
+        value: Value::Labeled("interface", tx_packets),
+    },
+    Metric { name: "heorot_net_sent_bytes_total", help: "Bytes sent", value: Value::Labeled("interface", tx_bytes) },
+    Metric {
+        name: "heorot_net_send_errors_total",
+        help: "Sends the device failed",
+        value: Value::Labeled("interface", tx_errors),
+    },
+    Metric {
+        name: "heorot_net_dropped_packets_total",
+        help: "Sends refused before the device, as the interface was down or the frame too big",
+        value: Value::Labeled("interface", tx_dropped),
+    },
+];
+
+#[cfg(feature = "shell")]
+fn print_interface(info: &InterfaceInfo) {
+    let [a, b, c, d, e, f] = info.mac;
+    let state = if info.up { "up" } else { "down" };
+    crate::print!("{:<8} {:<4} {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", info.name, state, a, b, c, d, e, f);
+    crate::print!(" mtu {}", info.mtu);
+    match info.address {
+        Some(address) => crate::println!(" {}", address),
+        None => crate::println!(),
+    }
+}
+
+#[cfg(feature = "shell")]
+fn cmd_ifconfig(args: &[&str]) -> crate::shell::Status {
+    use crate::shell::{FAILURE, SUCCESS};
+
+    let (name, result) = match args {
+        [] => {
+            interfaces().iter().for_each(print_interface);
+            return SUCCESS;
+        }
+        [name] => match info(name) {
+            Some(info) => {
+                print_interface(&info);
+                return SUCCESS;
+            }
+            None => (name, Err(NO_SUCH_INTERFACE)),
+        },
+        [name, "up"] => (name, set_up(name, true)),
+        [name, "down"] => (name, set_up(name, false)),
+        [name, "mtu", mtu] => (name, mtu.parse().map_err(|_| "bad MTU").and_then(|mtu| set_mtu(name, mtu))),
+        [name, address] => (name, address.parse().and_then(|address| set_address(name, Some(address)))),
+        _ => {
+            crate::println!("usage: ifconfig [<interface> [up|down|mtu <bytes>|<address>/<prefix>]]");
+            return FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            crate::println!("ifconfig: {}: {}", name, message);
+            FAILURE
+        }
+    }
+}
+
+#[cfg(feature = "shell")]
+fn cmd_netstat(_args: &[&str]) -> crate::shell::Status {
+    crate::println!(
+        "{:<8} {:>10} {:>12} {:>6} {:>10} {:>12} {:>6} {:>7}",
+        "iface", "rx packets", "rx bytes", "rx err", "tx packets", "tx bytes", "tx err", "tx drop"
+    );
+    for info in interfaces() {
+        let stats = info.stats;
+        crate::println!(
+            "{:<8} {:>10} {:>12} {:>6} {:>10} {:>12} {:>6} {:>7}",
+            info.name, stats.rx_packets, stats.rx_bytes, stats.rx_errors,
+            stats.tx_packets, stats.tx_bytes, stats.tx_errors, stats.tx_dropped
+        );
+    }
+    crate::shell::SUCCESS
+}
+
+/// Add "lo", the interfaces' metrics, and the `ifconfig`, `route`,
+/// `netstat`, `vlan` and `pcap` commands
+pub fn init() {
+    let lo = Ipv4Cidr { address: Ipv4Addr([127, 0, 0, 1]), prefix_len: 8 };
+    let added = register("lo", &LOOPBACK)
+        .and_then(|()| set_address("lo", Some(lo)))
+        .and_then(|()| set_up("lo", true))
+        .and_then(|()| route::add(lo.network(), None, "lo"));
+    if let Err(message) = added {
+        crate::log::warn!("net: lo: {}", message);
+    }
+    for metric in NET_METRICS {
+        if let Err(message) = metrics::register(metric) {
+            crate::log::warn!("net: {}", message);
+        }
+    }
+    #[cfg(feature = "shell")]
+    {
+        crate::shell::register(crate::shell::Command {
+            name: "ifconfig",
+            help: "list the network interfaces, or bring one up or down or set its MTU or address",
+            run: cmd_ifconfig,
+            complete: None,
+        })
+        .expect("couldn't register ifconfig");
+        crate::shell::register(crate::shell::Command {
+            name: "route",
+            help: "list the routes, or add or delete one",
+            run: route::cmd_route,
+            complete: None,
+        })
+        .expect("couldn't register route");
+        crate::shell::register(crate::shell::Command {
+            name: "netstat",
+            help: "count the packets and bytes in and out of each network interface",
+            run: cmd_netstat,
+            complete: None,
+        })
+        .expect("couldn't register netstat");
+        crate::shell::register(crate::shell::Command {
+            name: "vlan",
+            help: "add an 802.1Q VLAN interface over another interface",
+            run: vlan::cmd_vlan,
+            complete: None,
+        })
+        .expect("couldn't register vlan");
+        crate::shell::register(crate::shell::Command {
+            name: "pcap",
+            help: "capture network frames, and send them over serial as a pcap file",
+            run: pcap::cmd_pcap,
+            complete: None,
+        })
+        .expect("couldn't register pcap");
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_interfaces_pass_frames_only_when_up() {
+    use alloc::string::ToString;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use crate::drivers::loopback::Loopback;
+    use crate::task::simple_executor::SimpleExecutor;
+    use crate::task::Task;
+
+    static DEVICE: Loopback = Loopback::new();
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    assert_eq!("10.0.2.15".parse(), Ok(Ipv4Addr([10, 0, 2, 15])));
+    assert!("10.0.2".parse::<Ipv4Addr>().is_err());
+    assert!("10.0.2.256".parse::<Ipv4Addr>().is_err());
+    let cidr: Ipv4Cidr = "10.0.2.15/24".parse().unwrap();
+    assert_eq!(cidr.netmask(), Ipv4Addr([255, 255, 255, 0]));
+    assert_eq!(cidr.network().to_string(), "10.0.2.0/24");
+    assert!(cidr.contains(Ipv4Addr([10, 0, 2, 2])) && !cidr.contains(Ipv4Addr([10, 0, 3, 2])));
+    assert!("10.0.2.15/33".parse::<Ipv4Cidr>().is_err());
+
+    register("test0", &DEVICE).unwrap();
+    assert!(register("test0", &DEVICE).is_err());
+    assert_eq!(info("test0").map(|info| (info.up, info.address)), Some((false, None)));
+    set_address("test0", Some(cidr)).unwrap();
+    assert_eq!(info("test0").unwrap().mtu, DEVICE.mtu());
+    assert!(set_mtu("test0", DEVICE.mtu() + 1).is_err() && set_mtu("test0", ETHERNET_HEADER_LEN - 1).is_err());
+    assert_eq!(info("test0").unwrap().address, Some(cidr));
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        assert_eq!(send("test0", b"down").await, Err("interface is down"));
+        set_up("test0", true).unwrap();
+        send("test0", b"up").await.unwrap();
+        let mut frame = [0; 8];
+        let len = receive("test0", &mut frame).await.unwrap();
+        assert_eq!(&frame[..len], b"up");
+        assert_eq!(send("nope", b"").await, Err(NO_SUCH_INTERFACE));
+        set_mtu("test0", ETHERNET_HEADER_LEN).unwrap();
+        assert_eq!(send("test0", &[0; ETHERNET_HEADER_LEN + 1]).await, Err("frame is bigger than the MTU"));
+        DONE.store(true, Ordering::Relaxed);
+    }));
+    executor.run();
+    assert!(DONE.load(Ordering::Relaxed));
+    let stats = info("test0").unwrap().stats;
+    assert_eq!((stats.tx_dropped, stats.tx_packets, stats.tx_bytes), (2, 1, 2));
+    assert_eq!((stats.rx_packets, stats.rx_bytes, stats.rx_errors, stats.tx_errors), (1, 2, 0, 0));
+
+    unregister("test0").unwrap();
+    assert_eq!(info("test0"), None);
+    assert!(info("lo").unwrap().up);
+}
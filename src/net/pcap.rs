@@ -0,0 +1,219 @@
+//! Packet capture: while it's on, every frame `net::send` sends and
+//! `net::receive` receives, on one interface or all of them, is recorded
+//! with the time and which way it went, in memory, as a pcap file that
+//! Wireshark opens. It goes over the serial port in base64, as screenshots
+//! do.
+//!
+//! The link type is Linux's "cooked" one, as that says which way a frame
+//! went and plain Ethernet doesn't: each frame's MACs and EtherType are
+//! swapped for a header with the direction, the source MAC and the
+//! EtherType. Frames shorter than an Ethernet header aren't recorded.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::screenshot::Base64;
+use crate::serial_println;
+use crate::time::SystemTime;
+use super::{ETHERNET_HEADER_LEN, NO_SUCH_INTERFACE};
+
+/// The lines around a capture in serial output
+pub const BEGIN: &str = "-----BEGIN PCAP-----";
+pub const END: &str = "-----END PCAP-----";
+
+// Out of a 256 KiB heap
+const MAX_BYTES: usize = 64 * 1024;
+const MAGIC: u32 = 0xa1b2_c3d4;
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+// The cooked header's packet types and hardware type
+const SLL_INCOMING: u16 = 0;
+const SLL_OUTGOING: u16 = 4;
+const ARPHRD_ETHER: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+struct Capture {
+    on: bool,
+    // None for every interface
+    interface: Option<&'static str>,
+    // Record headers and frames, after the global header
+    records: Vec<u8>,
+    frames: usize,
+    // Frames there wasn't room for
+    dropped: usize,
+}
+
+// Never touched by interrupt handlers
+static CAPTURE: Mutex<Capture> =
+    Mutex::new(Capture { on: false, interface: None, records: Vec::new(), frames: 0, dropped: 0 });
+
+/// How a capture's going
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub on: bool,
+    pub interface: Option<&'static str>,
+    pub frames: usize,
+    pub bytes: usize,
+    pub dropped: usize,
+}
+
+/// Throw away what's been captured, and start again, on `interface` or, if
+/// it's None, on all of them
+pub fn start(interface: Option<&str>) -> Result<(), &'static str> {
+    let interface = match interface {
+        Some(name) => Some(super::info(name).ok_or(NO_SUCH_INTERFACE)?.name),
+        None => None,
+    };
+    let mut capture = CAPTURE.lock();
+    *capture = Capture { on: true, interface, records: Vec::new(), frames: 0, dropped: 0 };
+    Ok(())
+}
+
+/// Stop capturing, keeping what's been captured
+pub fn stop() {
+    CAPTURE.lock().on = false;
+}
+
+pub fn status() -> Status {
+    let capture = CAPTURE.lock();
+    let (on, interface, frames, dropped) = (capture.on, capture.interface, capture.frames, capture.dropped);
+    Status { on, interface, frames, bytes: capture.records.len(), dropped }
+}
+
+// From net::send and net::receive, for a frame that went
+pub(super) fn record(interface: &str, direction: Direction, frame: &[u8]) {
+    let mut capture = CAPTURE.lock();
+    if !capture.on || capture.interface.map_or(false, |name| name != interface) || frame.len() < ETHERNET_HEADER_LEN {
+        return;
+    }
+    let payload = &frame[ETHERNET_HEADER_LEN..];
+    let len = 16 + payload.len();
+    if capture.records.len() + 16 + len > MAX_BYTES {
+        capture.dropped += 1;
+        return;
+    }
+    let time = SystemTime::now().since_unix_epoch();
+    let records = &mut capture.records;
+    records.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+    records.extend_from_slice(&time.subsec_micros().to_le_bytes());
+    records.extend_from_slice(&(len as u32).to_le_bytes());
+    records.extend_from_slice(&(len as u32).to_le_bytes());
+    let packet_type = match direction {
+        Direction::In => SLL_INCOMING,
+        Direction::Out => SLL_OUTGOING,
+    };
+    records.extend_from_slice(&packet_type.to_be_bytes());
+    records.extend_from_slice(&ARPHRD_ETHER.to_be_bytes());
+    records.extend_from_slice(&6u16.to_be_bytes());
+    // The source MAC, padded to 8 bytes
+    records.extend_from_slice(&frame[6..12]);
+    records.extend_from_slice(&[0; 2]);
+    records.extend_from_slice(&frame[12..ETHERNET_HEADER_LEN]);
+    records.extend_from_slice(payload);
+    capture.frames += 1;
+}
+
+/// Pass what's been captured to `out`, as a pcap file
+pub fn capture(out: &mut dyn FnMut(&[u8])) {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // The time zone and the timestamps' accuracy, both always 0
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_LINUX_SLL.to_le_bytes());
+    out(&header);
+    out(&CAPTURE.lock().records);
+}
+
+/// Send what's been captured over the serial port, base64-encoded between
+/// BEGIN and END lines
+pub fn send() {
+    serial_println!("{}", BEGIN);
+    let mut encoder = Base64::new(|line: &str| serial_println!("{}", line));
+    capture(&mut |bytes| encoder.feed(bytes));
+    encoder.finish();
+    serial_println!("{}", END);
+}
+
+#[cfg(feature = "shell")]
+pub(super) fn cmd_pcap(args: &[&str]) -> crate::shell::Status {
+    use crate::println;
+    use crate::shell::{FAILURE, SUCCESS};
+
+    let started = match args {
+        [] => {
+            let status = status();
+            let state = if status.on { "capturing" } else { "stopped" };
+            println!(
+                "pcap: {} on {}: {} frames, {} bytes, {} dropped",
+                state,
+                status.interface.unwrap_or("every interface"),
+                status.frames,
+                status.bytes,
+                status.dropped
+            );
+            return SUCCESS;
+        }
+        ["start"] => start(None),
+        ["start", interface] => start(Some(interface)),
+        ["stop"] => {
+            stop();
+            return SUCCESS;
+        }
+        ["dump"] => {
+            send();
+            println!("pcap: sent {} frames over serial", status().frames);
+            return SUCCESS;
+        }
+        _ => {
+            println!("usage: pcap [start [<interface>] | stop | dump]");
+            return FAILURE;
+        }
+    };
+    match started {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("pcap: {}", message);
+            FAILURE
+        }
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_records_frames_both_ways() {
+    let mut frame = [0; ETHERNET_HEADER_LEN + 2];
+    frame[6..12].copy_from_slice(&[2, 0, 0, 0, 0, 1]);
+    frame[12..].copy_from_slice(&[0x08, 0x00, 0xab, 0xcd]);
+
+    assert_eq!(start(Some("nope")), Err(NO_SUCH_INTERFACE));
+    start(Some("lo")).unwrap();
+    record("lo", Direction::Out, &frame);
+    record("lo", Direction::In, &frame);
+    // Another interface's, and a runt
+    record("lo.5", Direction::In, &frame);
+    record("lo", Direction::In, &frame[..ETHERNET_HEADER_LEN - 1]);
+    stop();
+    record("lo", Direction::In, &frame);
+    assert_eq!(status().frames, 2);
+
+    let mut file = Vec::new();
+    capture(&mut |bytes| file.extend_from_slice(bytes));
+    assert_eq!(&file[..4], &MAGIC.to_le_bytes());
+    assert_eq!(&file[20..24], &LINKTYPE_LINUX_SLL.to_le_bytes());
+    let record_len = 16 + 16 + 2;
+    assert_eq!(file.len(), 24 + 2 * record_len);
+    let (first, second) = (&file[24..24 + record_len], &file[24 + record_len..]);
+    assert_eq!(&first[8..12], &18u32.to_le_bytes());
+    assert_eq!(&first[16..18], &SLL_OUTGOING.to_be_bytes());
+    assert_eq!(&second[16..18], &SLL_INCOMING.to_be_bytes());
+    assert_eq!(&first[22..28], &frame[6..12]);
+    assert_eq!(&first[30..], &frame[12..]);
+}
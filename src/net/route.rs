@@ -0,0 +1,166 @@
+//! The route table: which interface, and which gateway on it if any, each
+//! network is reached through. `lookup` picks the longest prefix that
+//! matches through an interface that's up, reading the table without a
+//! lock. Nothing sends packets by it yet, as there's no IP layer; it's here
+//! so there's a table to set up.
+
+use alloc::vec::Vec;
+use crate::sync::Rcu;
+use super::{Ipv4Addr, Ipv4Cidr, NO_SUCH_INTERFACE};
+
+const MAX_ROUTES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    /// A network; 0.0.0.0/0 is the default route
+    pub destination: Ipv4Cidr,
+    /// Where to send for it, if it isn't on the link
+    pub gateway: Option<Ipv4Addr>,
+    pub interface: &'static str,
+}
+
+// An array, not a Vec, as lo's route is added before there's a heap. Never
+// written by interrupt handlers.
+static ROUTES: Rcu<[Option<Route>; MAX_ROUTES]> = Rcu::new([None; MAX_ROUTES]);
+
+/// Route `destination`'s network through `interface`, by way of `gateway`
+/// if there is one
+pub fn add(destination: Ipv4Cidr, gateway: Option<Ipv4Addr>, interface: &str) -> Result<(), &'static str> {
+    // The registry's own copy of the name, which lasts
+    let interface = super::info(interface).ok_or(NO_SUCH_INTERFACE)?.name;
+    let destination = destination.network();
+    ROUTES.update(|routes| {
+        if routes.iter().flatten().any(|route| route.destination == destination) {
+            return Err("there's a route to that network");
+        }
+        let slot = routes.iter_mut().find(|slot| slot.is_none()).ok_or("the route table is full")?;
+        *slot = Some(Route { destination, gateway, interface });
+        Ok(())
+    })
+}
+
+/// Drop the route to `destination`'s network
+pub fn remove(destination: Ipv4Cidr) -> Result<(), &'static str> {
+    let destination = destination.network();
+    ROUTES.update(|routes| {
+        let slot = routes
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |route| route.destination == destination))
+            .ok_or("no route to that network")?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+// For an interface that's gone
+pub(super) fn remove_through(interface: &str) {
+    ROUTES.update(|routes| {
+        for slot in routes.iter_mut() {
+            if slot.map_or(false, |route| route.interface == interface) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// Every route, longest prefix first
+pub fn routes() -> Vec<Route> {
+    let mut routes: Vec<Route> = ROUTES.read(|routes| routes.iter().flatten().copied().collect());
+    routes.sort_by(|a, b| b.destination.prefix_len.cmp(&a.destination.prefix_len));
+    routes
+}
+
+/// The route `addr` would be sent by
+pub fn lookup(addr: Ipv4Addr) -> Option<Route> {
+    ROUTES.read(|routes| {
+        routes
+            .iter()
+            .flatten()
+            .filter(|route| route.destination.contains(addr))
+            .filter(|route| super::info(route.interface).map_or(false, |info| info.up))
+            .max_by_key(|route| route.destination.prefix_len)
+            .copied()
+    })
+}
+
+// "default" for 0.0.0.0/0
+#[cfg(feature = "shell")]
+fn parse_destination(destination: &str) -> Result<Ipv4Cidr, &'static str> {
+    match destination {
+        "default" => Ipv4Cidr::new(Ipv4Addr::UNSPECIFIED, 0),
+        _ => destination.parse(),
+    }
+}
+
+#[cfg(feature = "shell")]
+pub(super) fn cmd_route(args: &[&str]) -> crate::shell::Status {
+    use alloc::string::{String, ToString};
+    use crate::println;
+    use crate::shell::{FAILURE, SUCCESS};
+
+    let (destination, result) = match args {
+        [] => {
+            for route in routes() {
+                let destination: String = match route.destination.prefix_len {
+                    0 => "default".into(),
+                    _ => route.destination.to_string(),
+                };
+                let gateway = route.gateway.map_or_else(|| "-".into(), |gateway| gateway.to_string());
+                println!("{:<18} {:<15} {}", destination, gateway, route.interface);
+            }
+            return SUCCESS;
+        }
+        ["add", destination, "dev", interface] => {
+            (destination, parse_destination(destination).and_then(|to| add(to, None, interface)))
+        }
+        ["add", destination, "via", gateway, "dev", interface] => (
+            destination,
+            parse_destination(destination)
+                .and_then(|to| Ok((to, gateway.parse()?)))
+                .and_then(|(to, gateway)| add(to, Some(gateway), interface)),
+        ),
+        ["del", destination] => (destination, parse_destination(destination).and_then(remove)),
+        _ => {
+            println!("usage: route [add <network>/<prefix>|default [via <gateway>] dev <interface>");
+            println!("             | del <network>/<prefix>|default]");
+            return FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("route: {}: {}", destination, message);
+            FAILURE
+        }
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_lookup_takes_the_longest_prefix() {
+    use crate::drivers::loopback::Loopback;
+
+    static DEVICE: Loopback = Loopback::new();
+
+    super::register("rt0", &DEVICE).unwrap();
+    super::set_up("rt0", true).unwrap();
+    let gateway = Ipv4Addr([10, 9, 0, 1]);
+    add("0.0.0.0/0".parse().unwrap(), Some(gateway), "rt0").unwrap();
+    add("10.9.0.7/16".parse().unwrap(), None, "rt0").unwrap();
+    assert!(add("10.9.1.0/16".parse().unwrap(), None, "rt0").is_err());
+    assert_eq!(add("10.8.0.0/16".parse().unwrap(), None, "nope"), Err(NO_SUCH_INTERFACE));
+
+    assert_eq!(lookup(Ipv4Addr([10, 9, 3, 4])).map(|route| route.gateway), Some(None));
+    assert_eq!(lookup(Ipv4Addr([192, 0, 2, 1])).map(|route| route.gateway), Some(Some(gateway)));
+    assert_eq!(lookup(Ipv4Addr([127, 0, 0, 1])).map(|route| route.interface), Some("lo"));
+
+    // Not through an interface that's down
+    super::set_up("rt0", false).unwrap();
+    assert_eq!(lookup(Ipv4Addr([10, 9, 3, 4])), None);
+    remove("10.9.0.0/16".parse().unwrap()).unwrap();
+    assert!(remove("10.9.0.0/16".parse().unwrap()).is_err());
+
+    super::unregister("rt0").unwrap();
+    assert!(routes().iter().all(|route| route.interface != "rt0"));
+}
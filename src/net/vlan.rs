@@ -0,0 +1,216 @@
+//! 802.1Q VLANs: an interface whose frames go out through another's device
+//! with a tag naming the VLAN, put in after the MACs, and only frames with
+//! the same tag come in, taken out again. Frames the parent device gets
+//! for anything else are dropped, so while a VLAN's receiving, its parent
+//! interface shouldn't be.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crate::io::NetDevice;
+use super::ETHERNET_HEADER_LEN;
+
+/// The EtherType that says a tag follows
+pub const TPID: u16 = 0x8100;
+/// How much longer a frame is tagged
+pub const TAG_LEN: usize = 4;
+// Where the tag goes: after the destination and source MACs
+const MACS_LEN: usize = 12;
+
+pub struct Vlan {
+    parent: &'static (dyn NetDevice + Sync),
+    id: u16,
+    // Frames that came without this VLAN's tag
+    dropped: AtomicU64,
+}
+
+impl Vlan {
+    /// VLAN `id` on `parent`; IDs are 1 to 4094
+    pub const fn new(parent: &'static (dyn NetDevice + Sync), id: u16) -> Vlan {
+        Vlan { parent, id, dropped: AtomicU64::new(0) }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Frames the parent got that weren't for this VLAN
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl NetDevice for Vlan {
+    fn mac(&self) -> [u8; 6] {
+        self.parent.mac()
+    }
+
+    fn mtu(&self) -> usize {
+        self.parent.mtu() - TAG_LEN
+    }
+
+    fn poll_receive(&self, context: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, &'static str>> {
+        let mut received = alloc::vec![0; buf.len() + TAG_LEN];
+        loop {
+            let len = match self.parent.poll_receive(context, &mut received) {
+                Poll::Ready(Ok(len)) => len,
+                other => return other,
+            };
+            // Only what came this time; the rest is an earlier frame's
+            let frame = &received[..len];
+            // Runts, too short to have a tag, are dropped with the rest.
+            // The priority and drop-eligible bits are ignored.
+            let ours = frame.get(MACS_LEN..MACS_LEN + TAG_LEN).map_or(false, |tag| {
+                u16::from_be_bytes([tag[0], tag[1]]) == TPID && u16::from_be_bytes([tag[2], tag[3]]) & 0xfff == self.id
+            });
+            if !ours {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            buf[..MACS_LEN].copy_from_slice(&frame[..MACS_LEN]);
+            buf[MACS_LEN..len - TAG_LEN].copy_from_slice(&frame[MACS_LEN + TAG_LEN..]);
+            return Poll::Ready(Ok(len - TAG_LEN));
+        }
+    }
+
+    fn poll_send(&self, context: &mut Context, frame: &[u8]) -> Poll<Result<(), &'static str>> {
+        if frame.len() < ETHERNET_HEADER_LEN {
+            return Poll::Ready(Err("frame is shorter than a header"));
+        }
+        let mut tagged = Vec::with_capacity(frame.len() + TAG_LEN);
+        tagged.extend_from_slice(&frame[..MACS_LEN]);
+        tagged.extend_from_slice(&TPID.to_be_bytes());
+        tagged.extend_from_slice(&self.id.to_be_bytes());
+        tagged.extend_from_slice(&frame[MACS_LEN..]);
+        self.parent.poll_send(context, &tagged)
+    }
+}
+
+/// Add VLAN `id` on interface `parent`'s device, as the interface
+/// "<parent>.<id>", down; its name. It stays for good.
+pub fn add(parent: &str, id: u16) -> Result<&'static str, &'static str> {
+    if !(1..=4094).contains(&id) {
+        return Err("VLAN IDs are 1 to 4094");
+    }
+    let device = super::with_interface(parent, |interface| interface.device)?;
+    let name = format!("{}.{}", parent, id);
+    if super::info(&name).is_some() {
+        return Err("there's already that VLAN");
+    }
+    let name: &'static str = Box::leak(name.into_boxed_str());
+    super::register(name, Box::leak(Box::new(Vlan::new(device, id))))?;
+    Ok(name)
+}
+
+#[cfg(feature = "shell")]
+pub(super) fn cmd_vlan(args: &[&str]) -> crate::shell::Status {
+    use crate::println;
+    use crate::shell::{FAILURE, SUCCESS};
+
+    let (parent, id) = match args {
+        [parent, id] => match id.parse() {
+            Ok(id) => (parent, id),
+            Err(_) => {
+                println!("vlan: bad VLAN ID: {}", id);
+                return FAILURE;
+            }
+        },
+        _ => {
+            println!("usage: vlan <interface> <id>");
+            return FAILURE;
+        }
+    };
+    match add(parent, id) {
+        Ok(name) => {
+            println!("{}", name);
+            SUCCESS
+        }
+        Err(message) => {
+            println!("vlan: {}: {}", parent, message);
+            FAILURE
+        }
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_tags_go_on_and_come_off() {
+    use core::sync::atomic::AtomicBool;
+    use crate::drivers::loopback::Loopback;
+    use crate::io;
+    use crate::task::simple_executor::SimpleExecutor;
+    use crate::task::Task;
+
+    static PARENT: Loopback = Loopback::new();
+    static VLAN: Vlan = Vlan::new(&PARENT, 5);
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        let mut frame = [0; ETHERNET_HEADER_LEN + 1];
+        frame[..MACS_LEN].copy_from_slice(&[0xff; MACS_LEN]);
+        frame[MACS_LEN..].copy_from_slice(&[0x08, 0x00, 0x2a]);
+        io::send(&VLAN, &frame).await.unwrap();
+        // On the parent's wire, tagged
+        let mut tagged = [0; 32];
+        let len = io::receive(&PARENT, &mut tagged).await.unwrap();
+        assert_eq!(len, frame.len() + TAG_LEN);
+        assert_eq!(&tagged[MACS_LEN..MACS_LEN + TAG_LEN], &[0x81, 0x00, 0x00, 0x05]);
+        assert_eq!(&tagged[MACS_LEN + TAG_LEN..len], &frame[MACS_LEN..]);
+
+        // An untagged frame is passed over for the tagged one after it
+        io::send(&PARENT, &frame).await.unwrap();
+        io::send(&PARENT, &tagged[..len]).await.unwrap();
+        let mut received = [0; 32];
+        let len = io::receive(&VLAN, &mut received).await.unwrap();
+        assert_eq!(&received[..len], &frame);
+        assert_eq!(VLAN.dropped(), 1);
+        assert!(io::send(&VLAN, &frame[..MACS_LEN]).await.is_err());
+        DONE.store(true, Ordering::Relaxed);
+    }));
+    executor.run();
+    assert!(DONE.load(Ordering::Relaxed));
+    assert_eq!(VLAN.mtu(), PARENT.mtu() - TAG_LEN);
+    assert!(add("lo", 4095).is_err() && add("nope", 5).is_err());
+}
+
+#[test_case]
+fn test_runts_are_dropped() {
+    use core::sync::atomic::AtomicBool;
+    use crate::drivers::loopback::Loopback;
+    use crate::io;
+    use crate::task::simple_executor::SimpleExecutor;
+    use crate::task::Task;
+
+    static PARENT: Loopback = Loopback::new();
+    static VLAN: Vlan = Vlan::new(&PARENT, 5);
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        // Untagged, so dropped, but leaving 00 05 where a tag's ID would be
+        let mut untagged = [0xff; MACS_LEN + TAG_LEN];
+        untagged[MACS_LEN..].copy_from_slice(&[0x08, 0x00, 0x00, 0x05]);
+        io::send(&PARENT, &untagged).await.unwrap();
+        // Only the first half of a tag, and nothing after
+        let mut runt = [0xff; MACS_LEN + 2];
+        runt[MACS_LEN..].copy_from_slice(&TPID.to_be_bytes());
+        io::send(&PARENT, &runt).await.unwrap();
+        let mut tagged = [0xff; MACS_LEN + TAG_LEN + 2];
+        tagged[MACS_LEN..].copy_from_slice(&[0x81, 0x00, 0x00, 0x05, 0x08, 0x00]);
+        io::send(&PARENT, &tagged).await.unwrap();
+
+        let mut received = [0; 32];
+        let len = io::receive(&VLAN, &mut received).await.unwrap();
+        assert_eq!(len, MACS_LEN + 2);
+        assert_eq!(&received[..MACS_LEN], &tagged[..MACS_LEN]);
+        assert_eq!(&received[MACS_LEN..len], &[0x08, 0x00]);
+        assert_eq!(VLAN.dropped(), 2);
+        DONE.store(true, Ordering::Relaxed);
+    }));
+    executor.run();
+    assert!(DONE.load(Ordering::Relaxed));
+}
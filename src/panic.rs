@@ -0,0 +1,219 @@
+//! Hooks subsystems register to run when the kernel panics, before it
+//! halts or exits QEMU: stopping devices that write to memory, putting what
+//! was written on disk, printing their own state. They run by stage, and
+//! in the order they registered within one.
+//!
+//! A hook runs with whatever the panicking code held still held, so it
+//! should only try_lock, and give up rather than wait.
+//!
+//! A panic handler starts with `enter`, which catches a panic from within
+//! one: a hook's, or the printing's. That one is reported straight to the
+//! serial port, with no locks and no heap, and the handler gives up. On
+//! the first panic it halts the other CPUs and, once they have, frees the
+//! console from any of them that was halted printing, so nothing's printed
+//! over the report; a CPU that panics after another has just halts.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::early_console::RawSerial;
+use crate::serial::SERIAL1;
+use crate::{ipi, smp};
+
+const MAX_HOOKS: usize = 8;
+
+/// When a hook runs, earliest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Stop devices doing DMA, so nothing changes under what comes after
+    Quiesce,
+    /// Write out caches
+    Flush,
+    /// Print state worth having in the report
+    Dump,
+}
+
+const STAGES: [Stage; 3] = [Stage::Quiesce, Stage::Flush, Stage::Dump];
+
+#[derive(Clone, Copy)]
+pub struct Hook {
+    pub name: &'static str,
+    pub stage: Stage,
+    pub run: fn(),
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static HOOKS: Mutex<[Option<Hook>; MAX_HOOKS]> = Mutex::new([None; MAX_HOOKS]);
+// Set once the hooks have started, so a hook that panics doesn't run them again
+static RAN: AtomicBool = AtomicBool::new(false);
+// Panics so far; a handler never returns, so each one after the first
+// happened inside a handler
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+// The CPU that panicked first; NO_CPU until one has
+const NO_CPU: usize = usize::MAX;
+static PANICKED: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+/// Call first thing in a panic handler. True for the first panic, which
+/// the handler reports as usual; false for one within the handler, which
+/// has been reported here, and the handler should halt or exit at once.
+pub fn enter(info: &PanicInfo) -> bool {
+    let cpu = smp::cpu_id();
+    match PANICKED.compare_exchange(NO_CPU, cpu, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => {}
+        Err(first) if first == cpu => {}
+        // That one's halting this one, if it hasn't already
+        Err(_) => loop {
+            interrupts::disable();
+            x86_64::instructions::hlt();
+        },
+    }
+    match DEPTH.fetch_add(1, Ordering::SeqCst) {
+        0 => {
+            // Halted, they'll never give back the locks they held
+            if smp::online() > 1 && ipi::halt_others() {
+                unsafe { crate::console::break_locks() };
+            }
+            return true;
+        }
+        1 => {
+            let _ = writeln!(RawSerial, "panicked while panicking: {}", info);
+        }
+        // Formatting the last one panicked, so not even that
+        _ => {
+            let _ = RawSerial.write_str("panicked while reporting a panic\n");
+        }
+    }
+    false
+}
+
+/// Print over serial from a panic handler: as `serial_print!` does, unless
+/// the port is held (perhaps by what panicked), then straight to it
+pub fn serial_print(args: fmt::Arguments) {
+    match SERIAL1.try_lock() {
+        Some(mut serial) => {
+            let _ = serial.write_fmt(args);
+        }
+        None => {
+            let _ = RawSerial.write_fmt(args);
+        }
+    }
+}
+
+pub fn register_hook(hook: Hook) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut hooks = HOOKS.lock();
+        let slot = hooks.iter_mut().find(|slot| slot.is_none()).ok_or("too many panic hooks")?;
+        *slot = Some(hook);
+        Ok(())
+    })
+}
+
+pub fn unregister_hook(name: &str) {
+    interrupts::without_interrupts(|| {
+        for slot in HOOKS.lock().iter_mut() {
+            if slot.map_or(false, |hook| hook.name == name) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+// Every hook, by stage; none if the table was locked when the panic hit
+fn run_all() {
+    let hooks = match HOOKS.try_lock() {
+        Some(hooks) => *hooks,
+        None => return,
+    };
+    for stage in STAGES {
+        for hook in hooks.iter().flatten().filter(|hook| hook.stage == stage) {
+            (hook.run)();
+        }
+    }
+}
+
+// What happened, and where when there's more than one CPU it could have
+struct Panicked<'a>(&'a dyn fmt::Display);
+
+impl fmt::Display for Panicked<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        if smp::online() > 1 {
+            write!(f, "\non CPU {}", smp::cpu_id())?;
+        }
+        Ok(())
+    }
+}
+
+/// The panic screen: what happened (and on which CPU), and the backtrace
+/// from here, on the console if it's up or else the early one
+pub fn report(what: &dyn fmt::Display) {
+    let backtrace = crate::backtrace::Backtrace::capture();
+    let what = Panicked(what);
+    if crate::early_console::console_ready() {
+        crate::println!("{}", what);
+        crate::println!("Backtrace:\n{}", backtrace);
+    } else {
+        crate::early_println!("{}", what);
+        crate::early_println!("Backtrace:\n{}", backtrace);
+    }
+}
+
+/// Run the hooks, from a panic handler. Only the first call does anything.
+pub fn run_hooks() {
+    if !RAN.swap(true, Ordering::SeqCst) {
+        run_all();
+    }
+}
+
+/// TESTS
+
+#[cfg(test)]
+mod order {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // Each hook's digit, in the order they ran
+    pub static RAN: AtomicUsize = AtomicUsize::new(0);
+
+    fn ran(digit: usize) {
+        let _ = RAN.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |ran| Some(ran * 10 + digit));
+    }
+
+    pub fn one() { ran(1) }
+    pub fn two() { ran(2) }
+    pub fn three() { ran(3) }
+}
+
+#[test_case]
+fn test_hook_order() {
+    use order::RAN;
+
+    register_hook(Hook { name: "test dump", stage: Stage::Dump, run: order::three }).unwrap();
+    register_hook(Hook { name: "test flush", stage: Stage::Flush, run: order::one }).unwrap();
+    register_hook(Hook { name: "test flush 2", stage: Stage::Flush, run: order::two }).unwrap();
+    RAN.store(0, Ordering::SeqCst);
+    run_all();
+    // Whatever else registered doesn't touch RAN
+    assert_eq!(RAN.load(Ordering::SeqCst), 123);
+
+    for name in ["test dump", "test flush", "test flush 2"] {
+        unregister_hook(name);
+    }
+    RAN.store(0, Ordering::SeqCst);
+    run_all();
+    assert_eq!(RAN.load(Ordering::SeqCst), 0);
+}
+
+#[test_case]
+fn test_report() {
+    use alloc::format;
+
+    let ((), screen) = crate::console::capture(|| report(&"panicked at 'test'"));
+    let expected = if smp::online() > 1 {
+        format!("panicked at 'test'\non CPU {}\nBacktrace:\n", smp::cpu_id())
+    } else {
+        "panicked at 'test'\nBacktrace:\n".into()
+    };
+    assert!(screen.starts_with(&expected), "{:?}", screen);
+}
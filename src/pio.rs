@@ -0,0 +1,99 @@
+//! Typed I/O ports. A driver gets at ports through `Ports`, which it can
+//! only have for ports the resource table says it's claimed, and each port
+//! is a fixed width and read-only, write-only or both, with mmio's access
+//! markers: reading a write-only port doesn't compile. With the claim
+//! checked up front, reads and writes are safe; a driver whose claim
+//! failed gets an error instead of ports, and can do without.
+
+use core::marker::PhantomData;
+use core::mem::size_of;
+use x86_64::instructions::port::{PortRead, PortWrite};
+use crate::device::DeviceId;
+use crate::mmio::{Readable, Writable};
+use crate::resource::{self, Resource};
+
+pub use crate::mmio::{ReadOnly, ReadWrite, WriteOnly};
+
+pub const NOT_OWNED: &str = "ports aren't claimed by this device";
+
+/// Ports `first..=last`, which a device holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+    first: u16,
+    last: u16,
+}
+
+impl Ports {
+    /// Claim `first..=last` for `owner`, as `resource::claim` does
+    pub fn claim(owner: DeviceId, first: u16, last: u16) -> Result<Ports, &'static str> {
+        resource::claim(owner, Resource::Ports { first, last })?;
+        Ok(Ports { first, last })
+    }
+
+    /// Ports `owner` has claimed already, e.g. those `device::init` claims
+    /// for the legacy hardware
+    pub fn owned(owner: DeviceId, first: u16, last: u16) -> Result<Ports, &'static str> {
+        if !resource::owns(owner, Resource::Ports { first, last }) {
+            return Err(NOT_OWNED);
+        }
+        Ok(Ports { first, last })
+    }
+
+    /// The `T`-wide port `offset` in, which has to be in the range
+    pub fn port<A, T>(&self, offset: u16) -> Result<Port<A, T>, &'static str> {
+        let port = self.first.checked_add(offset).ok_or("port outside the range")?;
+        match port.checked_add(size_of::<T>() as u16 - 1) {
+            Some(end) if end <= self.last => Ok(Port { port, access: PhantomData }),
+            _ => Err("port outside the range"),
+        }
+    }
+}
+
+/// One port, which `A` says can be read, written or both
+#[derive(Debug)]
+pub struct Port<A, T> {
+    port: u16,
+    access: PhantomData<(A, T)>,
+}
+
+impl<A, T> Port<A, T> {
+    pub fn number(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<A: Readable, T: PortRead> Port<A, T> {
+    pub fn read(&self) -> T {
+        // The port's the owner's, and `T` wide, as Ports::port checked
+        unsafe { T::read_from_port(self.port) }
+    }
+}
+
+impl<A: Writable, T: PortWrite> Port<A, T> {
+    pub fn write(&self, value: T) {
+        unsafe { T::write_to_port(self.port, value) }
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_ports() {
+    use crate::device;
+
+    let owner = device::register("test-pio", "port test", Some(device::platform())).unwrap();
+    let other = device::register("test-pio-b", "port test", Some(device::platform())).unwrap();
+    // Made-up ports nothing real uses
+    let ports = Ports::claim(owner, 0xe100, 0xe107).unwrap();
+    assert_eq!(Ports::owned(owner, 0xe104, 0xe107), Ok(Ports { first: 0xe104, last: 0xe107 }));
+    assert_eq!(Ports::owned(other, 0xe100, 0xe100), Err(NOT_OWNED));
+    assert_eq!(Ports::owned(owner, 0xe104, 0xe108), Err(NOT_OWNED));
+
+    assert_eq!(ports.port::<ReadWrite, u32>(4).map(|port| port.number()), Ok(0xe104));
+    assert!(ports.port::<ReadOnly, u32>(5).is_err());
+    assert!(ports.port::<WriteOnly, u8>(8).is_err());
+
+    resource::release(owner, Resource::Ports { first: 0xe100, last: 0xe107 });
+    device::unregister(owner).unwrap();
+    device::unregister(other).unwrap();
+}
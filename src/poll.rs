@@ -0,0 +1,206 @@
+//! Waiting on several things at once: `wait` takes a set of sources, each
+//! with the events it's wanted for, and returns once any of them is ready,
+//! saying which, so one thread (or program, through the poll syscall) can
+//! follow them all. Readiness is level-triggered, as poll's is: a source is
+//! ready for as long as it has something to read, not just when it gets it.
+//! The console's input and output are sources, and so are timers; there are
+//! no pipes or sockets yet.
+
+use core::convert::TryFrom;
+use crate::time::{Duration, Instant};
+use crate::timer;
+
+/// Something to read
+pub const POLLIN: u16 = 0x1;
+/// Room to write
+pub const POLLOUT: u16 = 0x4;
+
+/// A timer to wait on, expiring `first` after it's made and then, if it has
+/// a period, every period after that. Reading it says how many times it's
+/// expired since the last read; it's readable while that isn't none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timer {
+    due: Instant,
+    period: Option<Duration>,
+    // Expiries already read
+    read: u64,
+}
+
+impl Timer {
+    pub fn new(first: Duration, period: Option<Duration>) -> Timer {
+        Timer { due: Instant::now() + first, period: period.filter(|period| *period > Duration::ZERO), read: 0 }
+    }
+
+    // Expiries by `now`, read or not
+    fn expired_by(&self, now: Instant) -> u64 {
+        if now < self.due {
+            return 0;
+        }
+        match self.period {
+            Some(period) => 1 + (now.duration_since(self.due).as_nanos() / period.as_nanos()) as u64,
+            None => 1,
+        }
+    }
+
+    /// How many times it's expired since the last read
+    pub fn pending(&self) -> u64 {
+        self.expired_by(Instant::now()) - self.read
+    }
+
+    /// `pending`, counting those as read
+    pub fn read(&mut self) -> u64 {
+        let expired = self.expired_by(Instant::now());
+        expired - core::mem::replace(&mut self.read, expired)
+    }
+
+    // When it next becomes readable, if it isn't already and ever will be
+    fn next(&self) -> Option<Instant> {
+        match (self.read, self.period) {
+            (0, _) => Some(self.due),
+            (_, None) => None,
+            (read, Some(period)) => self.due.checked_add(period.checked_mul(u32::try_from(read).ok()?)?),
+        }
+    }
+}
+
+/// What can be waited on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Keys typed at the console
+    ConsoleInput,
+    /// The console, which always takes what's written
+    ConsoleOutput,
+    Timer(Timer),
+}
+
+impl Source {
+    fn ready(&self) -> u16 {
+        match self {
+            Source::ConsoleInput if input_pending() => POLLIN,
+            Source::ConsoleInput => 0,
+            Source::ConsoleOutput => POLLOUT,
+            Source::Timer(timer) if timer.pending() > 0 => POLLIN,
+            Source::Timer(_) => 0,
+        }
+    }
+}
+
+#[cfg(feature = "keyboard")]
+fn input_pending() -> bool {
+    crate::keyboard::input_pending()
+}
+
+#[cfg(not(feature = "keyboard"))]
+fn input_pending() -> bool {
+    false
+}
+
+/// A source, what it's wanted for, and what it's ready for once waited on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollFd {
+    pub source: Source,
+    pub events: u16,
+    pub revents: u16,
+}
+
+impl PollFd {
+    pub fn new(source: Source, events: u16) -> PollFd {
+        PollFd { source, events, revents: 0 }
+    }
+}
+
+/// Set each one's `revents` to what it's ready for now, of what it wants;
+/// returns how many are ready for something
+pub fn ready(fds: &mut [PollFd]) -> usize {
+    let mut ready = 0;
+    for fd in fds.iter_mut() {
+        fd.revents = fd.source.ready() & fd.events;
+        if fd.revents != 0 {
+            ready += 1;
+        }
+    }
+    ready
+}
+
+/// The soonest a timer among them becomes readable
+pub fn next_expiry(fds: &[PollFd]) -> Option<Instant> {
+    fds.iter()
+        .filter(|fd| fd.events & POLLIN != 0)
+        .filter_map(|fd| match fd.source {
+            Source::Timer(timer) => timer.next(),
+            _ => None,
+        })
+        .min()
+}
+
+/// Wait until some of them are ready, or `timeout` passes, or `stop` says
+/// to give up, halting in between; returns how many are ready, or None if
+/// stopped. Interrupts wake it, and a timer is set for when the nearest
+/// timer among them is due, so nothing's missed with the tick stopped.
+pub fn wait_or(fds: &mut [PollFd], timeout: Option<Duration>, mut stop: impl FnMut() -> bool) -> Option<usize> {
+    // Does nothing, but makes a tickless idle wake when we're due
+    fn wake() {}
+
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let wakes = [deadline, next_expiry(fds)]
+        .map(|at| at.and_then(|at| timer::after(at.duration_since(Instant::now()), wake).ok()));
+    let result = loop {
+        timer::run_expired();
+        if stop() {
+            break None;
+        }
+        let count = ready(fds);
+        if count > 0 || timeout == Some(Duration::ZERO) {
+            break Some(count);
+        }
+        // Checked again with interrupts off, so input can't come between
+        // the check and the halt
+        x86_64::instructions::interrupts::disable();
+        let count = ready(fds);
+        if count > 0 || deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            x86_64::instructions::interrupts::enable();
+            break Some(count);
+        }
+        timer::idle();
+    };
+    for id in wakes.iter().flatten() {
+        timer::cancel(*id);
+    }
+    result
+}
+
+/// `wait_or`, for as long as it takes
+pub fn wait(fds: &mut [PollFd], timeout: Option<Duration>) -> usize {
+    wait_or(fds, timeout, || false).unwrap_or(0)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_wait() {
+    let mut fds = [
+        PollFd::new(Source::ConsoleOutput, POLLIN | POLLOUT),
+        PollFd::new(Source::Timer(Timer::new(Duration::from_secs(60), None)), POLLIN),
+    ];
+    assert_eq!(wait(&mut fds, None), 1);
+    assert_eq!((fds[0].revents, fds[1].revents), (POLLOUT, 0));
+
+    // Nothing ready by the timeout
+    let start = Instant::now();
+    assert_eq!(wait(&mut fds[1..], Some(Duration::from_millis(5))), 0);
+    assert!(start.elapsed() >= Duration::from_millis(5));
+
+    // A periodic timer wakes the wait, and counts what it missed
+    let every = Duration::from_millis(2);
+    let mut fds = [PollFd::new(Source::Timer(Timer::new(every, Some(every))), POLLIN)];
+    assert_eq!(wait(&mut fds, Some(Duration::from_secs(1))), 1);
+    assert_eq!(fds[0].revents, POLLIN);
+    let mut timer = match fds[0].source {
+        Source::Timer(timer) => timer,
+        _ => unreachable!(),
+    };
+    crate::time::sleep(Duration::from_millis(4));
+    assert!(timer.read() >= 2);
+    assert_eq!(timer.read(), 0);
+    assert!(wait_or(&mut fds, None, || true).is_none());
+}
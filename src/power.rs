@@ -0,0 +1,137 @@
+use core::arch::asm;
+use core::convert::TryFrom;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+use crate::{acpi, hlt_loop, log};
+
+/// The FADT RESET_REG. Only the system I/O address space is supported.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiResetRegister {
+    pub port: u16,
+    pub value: u8,
+}
+
+// Filled in by `init` from the FADT; until then we skip straight to the 8042
+static ACPI_RESET: Mutex<Option<AcpiResetRegister>> = Mutex::new(None);
+
+// Where the FADT has its flags, RESET_REG (a generic address structure)
+// and RESET_VALUE; ACPI 2.0 on
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REG: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const RESET_REG_SUP: u32 = 1 << 10;
+const ADDRESS_SPACE_IO: u8 = 1;
+
+const KBC_STATUS_PORT: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+pub fn set_acpi_reset_register(register: AcpiResetRegister) {
+    *ACPI_RESET.lock() = Some(register);
+}
+
+/// The reset register `fadt` describes, if it says there's one we can use
+pub fn parse_reset_register(fadt: &[u8]) -> Result<AcpiResetRegister, &'static str> {
+    if fadt.len() <= FADT_RESET_VALUE {
+        return Err("the FADT predates the reset register");
+    }
+    let flags = u32::from_le_bytes([fadt[FADT_FLAGS], fadt[FADT_FLAGS + 1], fadt[FADT_FLAGS + 2], fadt[FADT_FLAGS + 3]]);
+    if flags & RESET_REG_SUP == 0 {
+        return Err("the FADT says there's no reset register");
+    }
+    let register = &fadt[FADT_RESET_REG..FADT_RESET_REG + 12];
+    if register[0] != ADDRESS_SPACE_IO {
+        return Err("the reset register isn't an I/O port");
+    }
+    let mut address = [0; 8];
+    address.copy_from_slice(&register[4..]);
+    let port = u16::try_from(u64::from_le_bytes(address)).map_err(|_| "the reset register is past the I/O ports")?;
+    Ok(AcpiResetRegister { port, value: fadt[FADT_RESET_VALUE] })
+}
+
+/// Find the reset register in the FADT, for `reboot` to try first. Needs
+/// memory::init, for the ACPI tables.
+pub fn init() {
+    match acpi::find(b"FACP").ok_or("no FADT").and_then(|fadt| parse_reset_register(fadt.bytes())) {
+        Ok(register) => set_acpi_reset_register(register),
+        Err(message) => log::debug!("power: {}; reboot starts with the 8042", message),
+    }
+}
+
+// Give a reset method some time to take effect before trying the next one
+fn settle() {
+    for _ in 0..10_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+fn acpi_reset() {
+    // try_lock: never block rebooting on a lock someone else is holding
+    let register = ACPI_RESET.try_lock().and_then(|register| *register);
+    if let Some(register) = register {
+        let mut port = Port::new(register.port);
+        unsafe { port.write(register.value) };
+        settle();
+    }
+}
+
+fn keyboard_controller_reset() {
+    let mut port: Port<u8> = Port::new(KBC_STATUS_PORT);
+    unsafe {
+        for _ in 0..100_000 {
+            if port.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        port.write(KBC_PULSE_RESET);
+    }
+    settle();
+}
+
+// With an empty IDT any exception escalates straight to a triple fault
+fn triple_fault() {
+    let idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&idt);
+        asm!("int3", options(nomem, nostack));
+    }
+}
+
+/// Reboot the machine, trying the ACPI reset register, then the keyboard
+/// controller, then a deliberate triple fault.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    acpi_reset();
+    keyboard_controller_reset();
+    triple_fault();
+
+    // Not much else we can do
+    hlt_loop();
+}
+crate::export_symbol!(reboot);
+
+/// TESTS
+
+#[test_case]
+fn test_parse_reset_register() {
+    // Where QEMU's q35 puts it: port 0xcf9, reset by writing 6
+    let mut fadt = [0u8; 244];
+    fadt[FADT_FLAGS + 1] = (RESET_REG_SUP >> 8) as u8;
+    fadt[FADT_RESET_REG] = ADDRESS_SPACE_IO;
+    fadt[FADT_RESET_REG + 1] = 8;
+    fadt[FADT_RESET_REG + 4..FADT_RESET_REG + 6].copy_from_slice(&0xcf9u16.to_le_bytes());
+    fadt[FADT_RESET_VALUE] = 6;
+    let register = parse_reset_register(&fadt).unwrap();
+    assert_eq!((register.port, register.value), (0xcf9, 6));
+
+    fadt[FADT_RESET_REG] = 0;
+    assert!(parse_reset_register(&fadt).is_err());
+    assert!(parse_reset_register(&fadt[..116]).is_err());
+}
@@ -0,0 +1,33 @@
+//! What code outside the kernel crate builds against: the test crates, and
+//! modules kept out of tree. `use heorot::prelude::*` brings in printing,
+//! the clocks, spawning work, reading files, and the types that describe
+//! network interfaces and their addresses, and nothing else.
+//!
+//! Everything here keeps its name and signature until `API_VERSION`'s
+//! major number goes up; new items only bump the minor one. The rest of
+//! the crate is public so the kernel's own modules can reach each other,
+//! and changes whenever it needs to.
+
+/// (major, minor) of what this module promises
+pub const API_VERSION: (u32, u32) = (1, 1);
+
+// Console and logging; the macros are at the crate's root, as
+// #[macro_export] puts them
+pub use crate::{print, println, serial_print, serial_println};
+pub use crate::{log_debug, log_error, log_info, log_trace, log_warn};
+pub use crate::console::Console;
+
+// Time
+pub use crate::time::{sleep_precise, uptime, Duration, Instant, SystemTime};
+
+// Threads, and async tasks with their executor
+pub use crate::sched::{spawn, yield_now, ThreadId};
+pub use crate::task::{sleep, Executor, Task};
+
+// Files, on whatever's mounted
+pub use crate::fs::{flock, read_dir, stat, Access, DirEntry, File, FileLock, LockKind, Metadata};
+
+// Network interfaces and their addresses; since 1.1
+pub use crate::net::{InterfaceInfo, Ipv4Addr, Ipv4Cidr};
+
+pub use crate::hlt_loop;
@@ -0,0 +1,23 @@
+use x86_64::instructions::port::Port;
+
+// Where the test configuration in Cargo.toml puts QEMU's isa-debug-exit device
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Status to exit QEMU with. QEMU exits with `(code << 1) | 1`, so these
+/// come out as 33 (the test-success-exit-code in Cargo.toml) and 35.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Shut QEMU down through the isa-debug-exit device. On hardware, or QEMU
+/// without the device, the write does nothing and we just halt.
+pub fn exit(code: ExitCode) -> ! {
+    unsafe {
+        let mut port = Port::new(ISA_DEBUG_EXIT_PORT);
+        port.write(code as u32);
+    }
+    crate::hlt_loop();
+}
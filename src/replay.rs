@@ -0,0 +1,274 @@
+//! Input recorded as it came in, to play back in a later boot, so a bug
+//! that depends on what was typed when can be had again. `replay record`
+//! starts recording keyboard scancodes and mouse bytes, each at its time
+//! since the recording started, and `replay save` sends the recording over
+//! COM1 between BEGIN and END lines. Put on the disk, `replay=PATH` on the
+//! command line plays it back once the kernel's up: a kernel thread feeds
+//! each byte in where the interrupt handler would have, at its time, and
+//! real input is dropped until it's done.
+//!
+//! The timer is what events are timed against rather than something
+//! recorded, and there's no network to record.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::time::{self, Duration, Instant};
+use crate::{fs, sched, serial_println};
+
+/// The lines around a recording in serial output
+pub const BEGIN: &str = "-----BEGIN REPLAY-----";
+pub const END: &str = "-----END REPLAY-----";
+
+// The first line of a recording
+const HEADER: &str = "heorot-replay 1";
+const MAX_EVENTS: usize = 4096;
+// Biggest recording file that's read: a line per event, at most
+const MAX_FILE_SIZE: usize = MAX_EVENTS * 24;
+
+/// Where a byte came in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Keyboard,
+    Mouse,
+}
+
+impl Source {
+    fn name(self) -> &'static str {
+        match self {
+            Source::Keyboard => "kbd",
+            Source::Mouse => "mouse",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Source> {
+        match name {
+            "kbd" => Some(Source::Keyboard),
+            "mouse" => Some(Source::Mouse),
+            _ => None,
+        }
+    }
+}
+
+/// One byte of input, and when it came
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// Since the recording started
+    pub at: Duration,
+    pub source: Source,
+    pub byte: u8,
+}
+
+struct Recording {
+    /// None unless recording
+    started: Option<Instant>,
+    events: [Event; MAX_EVENTS],
+    len: usize,
+    /// What didn't fit
+    dropped: usize,
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static RECORDING: Mutex<Recording> = Mutex::new(Recording {
+    started: None,
+    events: [Event { at: Duration::ZERO, source: Source::Keyboard, byte: 0 }; MAX_EVENTS],
+    len: 0,
+    dropped: 0,
+});
+// Set while a replay is feeding input in
+static PLAYING: AtomicBool = AtomicBool::new(false);
+// What the replay thread is to play; never touched by interrupt handlers
+static PENDING: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+
+/// Called by the interrupt handlers with each byte of input; false if it's
+/// to be dropped, as a replay is feeding input in instead
+pub(crate) fn observe(source: Source, byte: u8) -> bool {
+    if PLAYING.load(Ordering::Acquire) {
+        return false;
+    }
+    let mut recording = RECORDING.lock();
+    if let Some(started) = recording.started {
+        let len = recording.len;
+        if len == MAX_EVENTS {
+            recording.dropped += 1;
+        } else {
+            recording.events[len] = Event { at: started.elapsed(), source, byte };
+            recording.len += 1;
+        }
+    }
+    true
+}
+
+/// Start recording, throwing away whatever was recorded before
+pub fn record() -> Result<(), &'static str> {
+    if PLAYING.load(Ordering::Acquire) {
+        return Err("can't record while replaying");
+    }
+    interrupts::without_interrupts(|| {
+        let mut recording = RECORDING.lock();
+        recording.len = 0;
+        recording.dropped = 0;
+        recording.started = Some(Instant::now());
+    });
+    Ok(())
+}
+
+/// Stop recording, and take what was recorded and how many events didn't fit
+pub fn stop() -> (Vec<Event>, usize) {
+    interrupts::without_interrupts(|| {
+        let mut recording = RECORDING.lock();
+        recording.started = None;
+        let events = recording.events[..recording.len].to_vec();
+        recording.len = 0;
+        (events, core::mem::take(&mut recording.dropped))
+    })
+}
+
+/// Whether input's being recorded, and whether a replay is going
+pub fn status() -> (bool, bool) {
+    let recording = interrupts::without_interrupts(|| RECORDING.lock().started.is_some());
+    (recording, PLAYING.load(Ordering::Acquire))
+}
+
+/// A recording as text, a line per event
+pub fn format(events: &[Event]) -> String {
+    let mut text = format!("{}\n", HEADER);
+    for event in events {
+        let _ = writeln!(text, "{} {} {:02x}", event.at.as_micros(), event.source.name(), event.byte);
+    }
+    text
+}
+
+/// Read back what `format` wrote
+pub fn parse(text: &str) -> Result<Vec<Event>, &'static str> {
+    let mut lines = text.lines();
+    if lines.next().map(str::trim) != Some(HEADER) {
+        return Err("not a recording");
+    }
+    let mut events = Vec::new();
+    let mut last = Duration::ZERO;
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (micros, source, byte) = match fields.as_slice() {
+            [micros, source, byte] => (micros, source, byte),
+            _ => return Err("bad event line"),
+        };
+        let at = Duration::from_micros(micros.parse().map_err(|_| "bad event time")?);
+        if at < last {
+            return Err("events out of order");
+        }
+        last = at;
+        events.push(Event {
+            at,
+            source: Source::from_name(source).ok_or("unknown event source")?,
+            byte: u8::from_str_radix(byte, 16).map_err(|_| "bad event byte")?,
+        });
+    }
+    Ok(events)
+}
+
+/// Stop recording and send the recording over serial; returns how many
+/// events were sent, and how many didn't fit
+pub fn save() -> (usize, usize) {
+    let (events, dropped) = stop();
+    serial_println!("{}", BEGIN);
+    for line in format(&events).lines() {
+        serial_println!("{}", line);
+    }
+    serial_println!("{}", END);
+    (events.len(), dropped)
+}
+
+// Hand a byte on as its interrupt handler would
+fn inject(event: &Event) {
+    match event.source {
+        #[cfg(feature = "keyboard")]
+        Source::Keyboard => crate::keyboard::deliver_scancode(event.byte),
+        #[cfg(feature = "mouse")]
+        Source::Mouse => crate::mouse::feed_byte(event.byte),
+        // Not built in; dropped as the real thing would be
+        #[allow(unreachable_patterns)]
+        _ => {}
+    }
+}
+
+fn run() {
+    let events = core::mem::take(&mut *PENDING.lock());
+    let start = Instant::now();
+    for event in events.iter() {
+        let elapsed = start.elapsed();
+        if event.at > elapsed {
+            time::sleep(event.at - elapsed);
+        }
+        interrupts::without_interrupts(|| inject(event));
+    }
+    PLAYING.store(false, Ordering::Release);
+    crate::log::info!("replay: played {} events", events.len());
+}
+
+/// Play `events` back from now, on a kernel thread, in place of real input
+pub fn play(events: Vec<Event>) -> Result<(), &'static str> {
+    if PLAYING.swap(true, Ordering::AcqRel) {
+        return Err("already replaying");
+    }
+    stop();
+    *PENDING.lock() = events;
+    sched::spawn("replay", run).map(|_| ()).map_err(|message| {
+        PLAYING.store(false, Ordering::Release);
+        message
+    })
+}
+
+/// Read a recording from `path` and play it
+pub fn play_file(path: &str) -> Result<(), &'static str> {
+    let mut file = fs::File::open(path)?;
+    let size = file.size() as usize;
+    if size > MAX_FILE_SIZE {
+        return Err("recording is too big");
+    }
+    let mut text = alloc::vec![0u8; size];
+    let mut done = 0;
+    while done < size {
+        match file.read(&mut text[done..])? {
+            0 => return Err("file ended early"),
+            n => done += n,
+        }
+    }
+    play(parse(core::str::from_utf8(&text).map_err(|_| "recording isn't text")?)?)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_round_trip() {
+    let events = [
+        Event { at: Duration::from_micros(0), source: Source::Keyboard, byte: 0x1e },
+        Event { at: Duration::from_micros(1500), source: Source::Mouse, byte: 0x08 },
+        Event { at: Duration::from_millis(20), source: Source::Keyboard, byte: 0x9e },
+    ];
+    let text = format(&events);
+    assert_eq!(text, "heorot-replay 1\n0 kbd 1e\n1500 mouse 08\n20000 kbd 9e\n");
+    assert_eq!(parse(&text), Ok(events.to_vec()));
+    assert_eq!(parse("0 kbd 1e\n"), Err("not a recording"));
+    assert_eq!(parse("heorot-replay 1\n5 kbd 1e\n4 kbd 9e\n"), Err("events out of order"));
+    assert_eq!(parse("heorot-replay 1\n5 disk 1e\n"), Err("unknown event source"));
+}
+
+#[test_case]
+fn test_recording() {
+    record().unwrap();
+    assert!(observe(Source::Keyboard, 0x1e));
+    assert!(observe(Source::Mouse, 0x08));
+    let (events, dropped) = stop();
+    assert_eq!(dropped, 0);
+    let bytes: Vec<(Source, u8)> = events.iter().map(|event| (event.source, event.byte)).collect();
+    assert_eq!(bytes, [(Source::Keyboard, 0x1e), (Source::Mouse, 0x08)]);
+    assert!(events[0].at <= events[1].at);
+    // Nothing's kept once stopped
+    observe(Source::Keyboard, 0x9e);
+    assert!(stop().0.is_empty());
+}
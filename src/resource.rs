@@ -0,0 +1,175 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::device::{self, DeviceId};
+
+const MAX_CLAIMS: usize = 64;
+
+/// Something a driver needs exclusive use of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// I/O ports `first..=last`
+    Ports { first: u16, last: u16 },
+    /// Physical memory-mapped I/O, `length` bytes from `start`
+    Mmio { start: u64, length: u64 },
+    /// A legacy PIC line
+    Irq(u8),
+}
+
+impl Resource {
+    pub const fn port(port: u16) -> Resource {
+        Resource::Ports { first: port, last: port }
+    }
+
+    fn overlaps(&self, other: &Resource) -> bool {
+        match (*self, *other) {
+            (Resource::Ports { first, last }, Resource::Ports { first: other_first, last: other_last }) => {
+                first <= other_last && other_first <= last
+            }
+            (Resource::Mmio { start, length }, Resource::Mmio { start: other_start, length: other_length }) => {
+                start < other_start.saturating_add(other_length) && other_start < start.saturating_add(length)
+            }
+            (Resource::Irq(irq), Resource::Irq(other_irq)) => irq == other_irq,
+            _ => false,
+        }
+    }
+
+    // Whether all of `other` is within this
+    fn contains(&self, other: &Resource) -> bool {
+        match (*self, *other) {
+            (Resource::Ports { first, last }, Resource::Ports { first: other_first, last: other_last }) => {
+                first <= other_first && other_last <= last
+            }
+            (Resource::Mmio { start, length }, Resource::Mmio { start: other_start, length: other_length }) => {
+                start <= other_start
+                    && other_start.saturating_add(other_length) <= start.saturating_add(length)
+            }
+            (Resource::Irq(irq), Resource::Irq(other_irq)) => irq == other_irq,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Resource::Ports { first, last } if first == last => write!(f, "port {:#x}", first),
+            Resource::Ports { first, last } => write!(f, "ports {:#x}-{:#x}", first, last),
+            Resource::Mmio { start, length } => {
+                write!(f, "mmio {:#x}-{:#x}", start, start + length.saturating_sub(1))
+            }
+            Resource::Irq(irq) => write!(f, "irq {}", irq),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Claim {
+    resource: Resource,
+    owner: DeviceId,
+}
+
+// Only ever locked with interrupts off, like the device table
+static CLAIMS: Mutex<[Option<Claim>; MAX_CLAIMS]> = Mutex::new([None; MAX_CLAIMS]);
+
+/// Give `owner` exclusive use of `resource`. Fails, and says so on the
+/// console, if any part of it already belongs to another device.
+pub fn claim(owner: DeviceId, resource: Resource) -> Result<(), &'static str> {
+    let result = interrupts::without_interrupts(|| {
+        let mut claims = CLAIMS.lock();
+        if let Some(existing) = claims.iter().flatten().find(|claim| claim.resource.overlaps(&resource)) {
+            return Err(Some(*existing));
+        }
+        let slot = claims.iter_mut().find(|slot| slot.is_none()).ok_or(None)?;
+        *slot = Some(Claim { resource, owner });
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(Some(existing)) => {
+            let name = |id| device::info(id).map_or("?", |device| device.name);
+            crate::early_println!("resource conflict: {} wants {}, but {} already has {}",
+                name(owner), resource, name(existing.owner), existing.resource);
+            Err("resource already claimed by another device")
+        }
+        Err(None) => Err("resource table is full"),
+    }
+}
+
+/// Claim several resources, all or nothing
+pub fn claim_all(owner: DeviceId, resources: &[Resource]) -> Result<(), &'static str> {
+    for (index, resource) in resources.iter().enumerate() {
+        if let Err(message) = claim(owner, *resource) {
+            for claimed in &resources[..index] {
+                release(owner, *claimed);
+            }
+            return Err(message);
+        }
+    }
+    Ok(())
+}
+
+/// Give back a resource `owner` claimed earlier
+pub fn release(owner: DeviceId, resource: Resource) {
+    interrupts::without_interrupts(|| {
+        for slot in CLAIMS.lock().iter_mut() {
+            if matches!(slot, Some(claim) if claim.owner == owner && claim.resource == resource) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// Whether `owner` holds all of `resource`, in one claim
+pub fn owns(owner: DeviceId, resource: Resource) -> bool {
+    interrupts::without_interrupts(|| {
+        CLAIMS.lock().iter().flatten().any(|claim| claim.owner == owner && claim.resource.contains(&resource))
+    })
+}
+
+/// Call `f` with each resource `owner` holds
+pub fn for_each_owned(owner: DeviceId, mut f: impl FnMut(Resource)) {
+    let claims = interrupts::without_interrupts(|| *CLAIMS.lock());
+    for claim in claims.iter().flatten().filter(|claim| claim.owner == owner) {
+        f(claim.resource);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_overlapping_claims_fail() {
+    let first = device::register("test-res-a", "resource test", Some(device::platform())).unwrap();
+    let second = device::register("test-res-b", "resource test", Some(device::platform())).unwrap();
+
+    // Made-up resources nothing real uses
+    let ports = Resource::Ports { first: 0xe000, last: 0xe00f };
+    let mmio = Resource::Mmio { start: 0xfd00_0000, length: 0x1000 };
+    claim_all(first, &[ports, mmio]).unwrap();
+    assert!(claim(second, Resource::port(0xe00f)).is_err());
+    assert!(claim(second, Resource::Mmio { start: 0xfd00_0fff, length: 1 }).is_err());
+    assert_eq!(claim(second, Resource::port(0xe010)), Ok(()));
+
+    // All or nothing: the overlapping second MMIO range rolls back the ports
+    let more_ports = Resource::Ports { first: 0xe020, last: 0xe02f };
+    assert!(claim_all(second, &[more_ports, mmio]).is_err());
+    assert_eq!(claim(first, more_ports), Ok(()));
+
+    for device in [first, second].iter() {
+        let mut owned = [None; 4];
+        let mut count = 0;
+        for_each_owned(*device, |resource| {
+            owned[count] = Some(resource);
+            count += 1;
+        });
+        for resource in owned.iter().flatten() {
+            release(*device, *resource);
+        }
+    }
+    // Released resources are free for anyone again
+    assert_eq!(claim(second, ports), Ok(()));
+    release(second, ports);
+    device::unregister(first).unwrap();
+    device::unregister(second).unwrap();
+}
@@ -0,0 +1,110 @@
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// Keep NMIs masked while we poke at CMOS registers
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+/// A calendar date and time as the RTC reports it (normally UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        address.write(NMI_DISABLE | register);
+        data.read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+// The raw registers, before BCD/12-hour decoding
+fn read_raw() -> [u8; 6] {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Read the current date and time from the CMOS real-time clock
+pub fn read() -> DateTime {
+    interrupts::without_interrupts(|| {
+        // An update can land between reads; go until two reads agree
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        let status_b = read_register(REG_STATUS_B);
+        let [mut second, mut minute, hour_raw, mut day, mut month, mut year] = raw;
+        let pm = hour_raw & HOUR_PM != 0;
+        let mut hour = hour_raw & !HOUR_PM;
+
+        if status_b & STATUS_B_BINARY == 0 {
+            second = from_bcd(second);
+            minute = from_bcd(minute);
+            hour = from_bcd(hour);
+            day = from_bcd(day);
+            month = from_bcd(month);
+            year = from_bcd(year);
+        }
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // 12-hour mode: 12 AM is midnight, 12 PM is noon
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        DateTime {
+            // The century register isn't reliably present; assume the 2000s
+            year: 2000 + u16::from(year),
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    })
+}
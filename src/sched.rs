@@ -0,0 +1,830 @@
+//! Preemptive kernel threads on one CPU. Threads are switched round-robin
+//! when their time slice runs out, or sooner if they yield, park or exit.
+//! Whatever was running at boot becomes the first thread, "main".
+//!
+//! Each thread has a mask of the CPUs it may run on, all of them unless
+//! `set_affinity` says otherwise, and is only picked on those. Only the
+//! boot CPU runs threads for now, so a mask has to include it.
+//!
+//! Each thread's CPU time is counted at every switch, as user time while it
+//! runs a program in ring 3 (interrupts there included) and kernel time
+//! otherwise; crate::user marks the boundaries on the way in and out and
+//! around syscalls.
+//!
+//! Each thread's switches, preemptions and migrations are counted too, and
+//! how long it's been running, ready and parked; so is each CPU's share of
+//! the switches, and how deep its run queue is at each timer tick.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::backtrace::Backtrace;
+use crate::metrics::Counter;
+use crate::time::{Duration, Instant};
+
+pub const STACK_SIZE: usize = 16 * 1024;
+/// Every CPU there could be, as an affinity mask
+pub const ALL_CPUS: u64 = (1 << crate::smp::MAX_CPUS) - 1;
+// The CPUs that pick threads to run
+const SCHEDULING_CPUS: u64 = 1;
+// How long a thread runs before the timer switches to the next, in ticks
+const TIME_SLICE: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    /// Waiting in `park` for someone to `unpark` it
+    Parked,
+}
+
+/// CPU time a thread has had
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuTimes {
+    pub user: Duration,
+    pub kernel: Duration,
+}
+
+impl CpuTimes {
+    /// What was had since `earlier`
+    pub fn since(&self, earlier: CpuTimes) -> CpuTimes {
+        CpuTimes { user: self.user.saturating_sub(earlier.user), kernel: self.kernel.saturating_sub(earlier.kernel) }
+    }
+}
+
+/// What's been counted for a thread
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThreadStats {
+    /// Times it was switched to
+    pub switches: u64,
+    /// Times the timer switched it out, rather than it yielding or parking
+    pub preemptions: u64,
+    /// Times it was switched to on another CPU than the last time
+    pub migrations: u64,
+    pub running: Duration,
+    pub ready: Duration,
+    pub parked: Duration,
+}
+
+/// What's been counted for a CPU
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuStats {
+    /// Switches from one thread to another on it
+    pub switches: u64,
+    /// The ones the timer forced
+    pub preemptions: u64,
+    /// Its run queue's depth, summed over the timer ticks it was sampled at
+    pub queue_total: u64,
+    pub queue_samples: u64,
+    pub queue_max: usize,
+}
+
+impl CpuStats {
+    /// The run queue's average depth, in tenths
+    pub fn queue_mean_tenths(&self) -> u64 {
+        (self.queue_total * 10).checked_div(self.queue_samples).unwrap_or(0)
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    // Saved stack pointer while switched out; the registers are on the stack
+    rsp: u64,
+    // None for main, which runs on the bootloader's stack
+    stack: Option<Box<[u8]>>,
+    // Set by an unpark that came while the thread wasn't parked, so its
+    // next park returns straight away instead of missing the wakeup
+    unparked: bool,
+    times: CpuTimes,
+    // Running a program in ring 3
+    in_user: bool,
+    // The CPUs it may run on, a bit each
+    affinity: u64,
+    state: ThreadState,
+    // When it went into that state
+    state_since: Instant,
+    stats: ThreadStats,
+    // Where it ran last; None until it first has
+    last_cpu: Option<usize>,
+}
+
+impl Thread {
+    // Count the time in the state it's leaving
+    fn enter(&mut self, state: ThreadState, now: Instant) {
+        self.stats = self.stats_at(now);
+        self.state = state;
+        self.state_since = now;
+    }
+
+    // Its stats, with the time in its state counted up to now
+    fn stats_at(&self, now: Instant) -> ThreadStats {
+        let mut stats = self.stats;
+        let spent = now - self.state_since;
+        match self.state {
+            ThreadState::Running => stats.running += spent,
+            ThreadState::Ready => stats.ready += spent,
+            ThreadState::Parked => stats.parked += spent,
+        }
+        stats
+    }
+}
+
+struct Scheduler {
+    current: Box<Thread>,
+    ready: VecDeque<Box<Thread>>,
+    parked: Vec<Box<Thread>>,
+    // Exited threads, freed by whichever thread runs next (not while their
+    // stack is still in use)
+    dead: Vec<Box<Thread>>,
+    slice_left: u32,
+    // Up to when the current thread's time has been counted
+    since: Instant,
+    cpus: [CpuStats; crate::smp::MAX_CPUS],
+}
+
+// Main's, before there's a scheduler: it's had all the time since boot
+fn boot_times(now: Instant) -> CpuTimes {
+    CpuTimes { user: Duration::ZERO, kernel: now.since_boot() }
+}
+
+impl Scheduler {
+    fn new() -> Scheduler {
+        let now = Instant::now();
+        Scheduler {
+            current: Box::new(Thread {
+                id: ThreadId(0),
+                name: "main",
+                rsp: 0,
+                stack: None,
+                unparked: false,
+                times: boot_times(now),
+                in_user: false,
+                affinity: ALL_CPUS,
+                state: ThreadState::Running,
+                state_since: now,
+                // It's been running since boot
+                stats: ThreadStats { running: now.since_boot(), ..ThreadStats::default() },
+                last_cpu: Some(crate::smp::cpu_id()),
+            }),
+            ready: VecDeque::new(),
+            parked: Vec::new(),
+            dead: Vec::new(),
+            slice_left: TIME_SLICE,
+            since: now,
+            cpus: [CpuStats::default(); crate::smp::MAX_CPUS],
+        }
+    }
+
+    // The current thread's times, counted up to now
+    fn current_times(&self, now: Instant) -> CpuTimes {
+        let mut times = self.current.times;
+        let elapsed = now - self.since;
+        if self.current.in_user {
+            times.user += elapsed;
+        } else {
+            times.kernel += elapsed;
+        }
+        times
+    }
+
+    // The first ready thread that may run on this CPU
+    fn next_ready(&self) -> Option<usize> {
+        let cpu = 1 << crate::smp::cpu_id();
+        self.ready.iter().position(|thread| thread.affinity & cpu != 0)
+    }
+
+    fn thread_mut(&mut self, id: ThreadId) -> Option<&mut Thread> {
+        if self.current.id == id {
+            return Some(&mut *self.current);
+        }
+        self.ready
+            .iter_mut()
+            .chain(self.parked.iter_mut())
+            .find(|thread| thread.id == id)
+            .map(|thread| &mut **thread)
+    }
+
+    fn account(&mut self) {
+        let now = Instant::now();
+        self.current.times = self.current_times(now);
+        self.since = now;
+    }
+}
+
+// Only ever locked with interrupts off, so the timer interrupt can't find it
+// held, and never held across a switch. None until the first spawn.
+static SCHED: Mutex<Option<Scheduler>> = Mutex::new(None);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static SWITCHES: Counter = Counter::new();
+
+// switch_context(old_rsp: *mut u64, new_rsp: u64): save the callee-saved
+// registers on the current stack, store its pointer in *old_rsp, and pick up
+// the other thread where its own switch_context call left off
+global_asm!(
+    ".global heorot_switch_context",
+    "heorot_switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    // New threads "return" here from their first switch, with the entry
+    // point in r12
+    ".global heorot_thread_trampoline",
+    "heorot_thread_trampoline:",
+    "mov rdi, r12",
+    "call {start}",
+    "ud2",
+    start = sym thread_start,
+);
+
+extern "C" {
+    fn heorot_switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn heorot_thread_trampoline();
+}
+
+extern "C" fn thread_start(entry: fn()) -> ! {
+    reap();
+    interrupts::enable();
+    entry();
+    exit();
+}
+
+// Free threads that exited; never called on a dead thread's own stack
+fn reap() {
+    let dead = interrupts::without_interrupts(|| {
+        SCHED.lock().as_mut().map(|sched| core::mem::take(&mut sched.dead))
+    });
+    drop(dead);
+}
+
+// Where the current thread goes when it's switched out
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Next {
+    Ready,
+    // Ready, but because the timer said so
+    Preempted,
+    Parked,
+    Dead,
+}
+
+// Switch to the next ready thread, if there is one. The current thread goes
+// to the back of the queue, among the parked, or among the dead. Interrupts
+// must be off, and come back however the thread we switch to left them.
+// Returns false if there was nothing to switch to.
+fn switch_to_next(next: Next) -> bool {
+    let (old_rsp, new_rsp) = {
+        let mut guard = SCHED.lock();
+        let sched = match guard.as_mut() {
+            Some(sched) => sched,
+            None => return false,
+        };
+        let mut incoming = match sched.next_ready().and_then(|index| sched.ready.remove(index)) {
+            Some(incoming) => incoming,
+            None => return false,
+        };
+        sched.account();
+        let (now, cpu) = (Instant::now(), crate::smp::cpu_id());
+        incoming.enter(ThreadState::Running, now);
+        incoming.stats.switches += 1;
+        if incoming.last_cpu.map_or(false, |last| last != cpu) {
+            incoming.stats.migrations += 1;
+        }
+        incoming.last_cpu = Some(cpu);
+        let mut previous = core::mem::replace(&mut sched.current, incoming);
+        sched.slice_left = TIME_SLICE;
+        sched.cpus[cpu].switches += 1;
+        match next {
+            Next::Ready => previous.enter(ThreadState::Ready, now),
+            Next::Preempted => {
+                previous.enter(ThreadState::Ready, now);
+                previous.stats.preemptions += 1;
+                sched.cpus[cpu].preemptions += 1;
+            }
+            Next::Parked => previous.enter(ThreadState::Parked, now),
+            Next::Dead => {}
+        }
+        // Boxed, so the pointer stays good however the list moves it around
+        let old_rsp: *mut u64 = match next {
+            Next::Ready | Next::Preempted => {
+                sched.ready.push_back(previous);
+                &mut sched.ready.back_mut().unwrap().rsp
+            }
+            Next::Parked => {
+                sched.parked.push(previous);
+                &mut sched.parked.last_mut().unwrap().rsp
+            }
+            Next::Dead => {
+                sched.dead.push(previous);
+                &mut sched.dead.last_mut().unwrap().rsp
+            }
+        };
+        (old_rsp, sched.current.rsp)
+    };
+    SWITCHES.increment();
+    unsafe { heorot_switch_context(old_rsp, new_rsp) };
+    reap();
+    true
+}
+
+/// Switches from one thread to another since boot
+pub fn switches() -> u64 {
+    SWITCHES.get()
+}
+
+/// Start a kernel thread running `entry`. It exits when `entry` returns.
+pub fn spawn(name: &'static str, entry: fn()) -> Result<ThreadId, &'static str> {
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let top = (stack.as_mut_ptr() as usize + STACK_SIZE) & !0xf;
+    // What heorot_switch_context pops: six registers (entry point in r12),
+    // then the trampoline as the return address. The trampoline's call then
+    // finds the stack 16-byte aligned, as the ABI wants.
+    let frame = (top - 7 * 8) as *mut u64;
+    unsafe {
+        for register in 0..6 {
+            frame.add(register).write(0);
+        }
+        // pop order is r15, r14, r13, r12
+        frame.add(3).write(entry as usize as u64);
+        frame.add(6).write(heorot_thread_trampoline as usize as u64);
+    }
+
+    let id = ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let thread = Box::new(Thread {
+        id,
+        name,
+        rsp: frame as u64,
+        stack: Some(stack),
+        unparked: false,
+        times: CpuTimes::default(),
+        in_user: false,
+        affinity: ALL_CPUS,
+        state: ThreadState::Ready,
+        state_since: Instant::now(),
+        stats: ThreadStats::default(),
+        last_cpu: None,
+    });
+    interrupts::without_interrupts(|| SCHED.lock().get_or_insert_with(Scheduler::new).ready.push_back(thread));
+    Ok(id)
+}
+
+/// Let the next ready thread run; returns when it's this one's turn again
+pub fn yield_now() {
+    interrupts::without_interrupts(|| switch_to_next(Next::Ready));
+}
+
+/// Sleep until another thread or an interrupt handler calls `unpark` on
+/// this one. May also return early, so callers should check whatever they
+/// were waiting for and park again if it isn't so yet.
+pub fn park() {
+    interrupts::disable();
+    let unparked = SCHED
+        .lock()
+        .as_mut()
+        .map_or(false, |sched| core::mem::replace(&mut sched.current.unparked, false));
+    if unparked {
+        interrupts::enable();
+        return;
+    }
+    // With nothing else to run, wait for an interrupt instead
+    if switch_to_next(Next::Parked) {
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Make a parked thread ready to run, or, if it isn't parked yet, have its
+/// next `park` return straight away. Safe from interrupt handlers.
+pub fn unpark(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHED.lock();
+        let sched = match guard.as_mut() {
+            Some(sched) => sched,
+            None => return,
+        };
+        if let Some(index) = sched.parked.iter().position(|thread| thread.id == id) {
+            let mut thread = sched.parked.swap_remove(index);
+            thread.enter(ThreadState::Ready, Instant::now());
+            sched.ready.push_back(thread);
+        } else if sched.current.id == id {
+            sched.current.unparked = true;
+        } else if let Some(thread) = sched.ready.iter_mut().find(|thread| thread.id == id) {
+            thread.unparked = true;
+        }
+    });
+}
+
+/// End the current thread. Main can't exit; there'd be nothing to return to.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let is_main = SCHED.lock().as_ref().map_or(true, |sched| sched.current.stack.is_none());
+    if is_main {
+        panic!("the main thread can't exit");
+    }
+    switch_to_next(Next::Dead);
+    // Main never exits, so there's always a thread to switch to
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Whether another thread is waiting for this CPU
+pub fn has_ready() -> bool {
+    interrupts::without_interrupts(|| SCHED.lock().as_ref().map_or(false, |sched| sched.next_ready().is_some()))
+}
+
+/// The thread running right now
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| SCHED.lock().as_ref().map_or(ThreadId(0), |sched| sched.current.id))
+}
+
+/// The CPU time the running thread has had so far
+pub fn cpu_times() -> CpuTimes {
+    interrupts::without_interrupts(|| {
+        let now = Instant::now();
+        SCHED.lock().as_ref().map_or_else(|| boot_times(now), |sched| sched.current_times(now))
+    })
+}
+
+/// Count the running thread's time from now on as user time, or as kernel
+/// time again; crate::user calls it at each crossing between the rings
+pub(crate) fn set_in_user(in_user: bool) {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHED.lock();
+        let sched = guard.get_or_insert_with(Scheduler::new);
+        sched.account();
+        sched.current.in_user = in_user;
+    });
+}
+
+/// Let thread `id` run only on the CPUs in `mask`, a bit each from the boot
+/// CPU's; bits past the last CPU there could be are dropped
+pub fn set_affinity(id: ThreadId, mask: u64) -> Result<(), &'static str> {
+    let mask = mask & ALL_CPUS;
+    if mask == 0 {
+        return Err("no CPUs in the mask");
+    }
+    if mask & SCHEDULING_CPUS == 0 {
+        return Err("no CPU in the mask runs threads");
+    }
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHED.lock();
+        let sched = guard.get_or_insert_with(Scheduler::new);
+        let thread = sched.thread_mut(id).ok_or("no such thread")?;
+        thread.affinity = mask;
+        Ok(())
+    })
+}
+
+/// The CPUs thread `id` may run on, or None if there's no such thread
+pub fn affinity(id: ThreadId) -> Option<u64> {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHED.lock();
+        guard.get_or_insert_with(Scheduler::new).thread_mut(id).map(|thread| thread.affinity)
+    })
+}
+
+/// Call `f` with each thread's id, name, state, and CPU time
+pub fn for_each_thread(mut f: impl FnMut(ThreadId, &'static str, ThreadState, CpuTimes)) {
+    // Copied out so `f` can print without holding the scheduler
+    let mut threads = Vec::new();
+    interrupts::without_interrupts(|| match SCHED.lock().as_ref() {
+        Some(sched) => {
+            let now = Instant::now();
+            threads.push((sched.current.id, sched.current.name, ThreadState::Running, sched.current_times(now)));
+            for thread in sched.ready.iter() {
+                threads.push((thread.id, thread.name, ThreadState::Ready, thread.times));
+            }
+            for thread in sched.parked.iter() {
+                threads.push((thread.id, thread.name, ThreadState::Parked, thread.times));
+            }
+        }
+        None => threads.push((ThreadId(0), "main", ThreadState::Running, boot_times(Instant::now()))),
+    });
+    for (id, name, state, times) in threads {
+        f(id, name, state, times);
+    }
+}
+
+/// How much of its stack thread `id` has used at most, going by how much
+/// of it isn't zero any more; None for main, which is on the bootloader's
+/// stack, and for threads there aren't
+pub fn stack_used(id: ThreadId) -> Option<usize> {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHED.lock();
+        let stack = guard.as_mut()?.thread_mut(id)?.stack.as_ref()?;
+        Some(stack.len() - stack.iter().position(|&byte| byte != 0).unwrap_or(stack.len()))
+    })
+}
+
+/// Call `f` with each thread's id, name, and what's been counted for it
+pub fn for_each_thread_stats(mut f: impl FnMut(ThreadId, &'static str, ThreadStats)) {
+    let mut threads = Vec::new();
+    interrupts::without_interrupts(|| {
+        let now = Instant::now();
+        match SCHED.lock().as_ref() {
+            Some(sched) => {
+                let all = core::iter::once(&sched.current).chain(sched.ready.iter()).chain(sched.parked.iter());
+                threads.extend(all.map(|thread| (thread.id, thread.name, thread.stats_at(now))));
+            }
+            None => {
+                let stats = ThreadStats { running: now.since_boot(), ..ThreadStats::default() };
+                threads.push((ThreadId(0), "main", stats));
+            }
+        }
+    });
+    for (id, name, stats) in threads {
+        f(id, name, stats);
+    }
+}
+
+/// What's been counted for CPU `cpu`, or None past the last there could be
+pub fn cpu_stats(cpu: usize) -> Option<CpuStats> {
+    if cpu >= crate::smp::MAX_CPUS {
+        return None;
+    }
+    let stats = interrupts::without_interrupts(|| SCHED.lock().as_ref().map(|sched| sched.cpus[cpu]));
+    Some(stats.unwrap_or_default())
+}
+
+/// Call `f` with each thread's id, name, and where it is: the caller's own
+/// backtrace for the running thread, and where each other one switched out
+pub fn for_each_backtrace(mut f: impl FnMut(ThreadId, &'static str, &Backtrace)) {
+    let mut traces = Vec::new();
+    interrupts::without_interrupts(|| match SCHED.lock().as_ref() {
+        Some(sched) => {
+            traces.push((sched.current.id, sched.current.name, Backtrace::capture()));
+            for thread in sched.ready.iter().chain(sched.parked.iter()) {
+                // What heorot_switch_context pushed: rbp at 5, its return at 6.
+                // Interrupts are off, so nothing else runs on that stack.
+                let trace = unsafe {
+                    let frame = thread.rsp as *const u64;
+                    Backtrace::from_frame(Some(frame.add(6).read()), frame.add(5).read())
+                };
+                traces.push((thread.id, thread.name, trace));
+            }
+        }
+        None => traces.push((ThreadId(0), "main", Backtrace::capture())),
+    });
+    for (id, name, trace) in traces.iter() {
+        f(*id, name, trace);
+    }
+}
+
+/// Called by the timer interrupt handler, after its EOI: switch threads
+/// when the current one's slice is used up. Not from a nested interrupt,
+/// since the outer handler still has work to finish on this stack.
+pub(crate) fn timer_tick() {
+    if crate::softirq::in_progress() {
+        return;
+    }
+    let expired = match SCHED.lock().as_mut() {
+        Some(sched) => {
+            let depth = sched.ready.len();
+            let cpu = &mut sched.cpus[crate::smp::cpu_id()];
+            cpu.queue_total += depth as u64;
+            cpu.queue_samples += 1;
+            cpu.queue_max = cpu.queue_max.max(depth);
+            if sched.next_ready().is_some() {
+                sched.slice_left = sched.slice_left.saturating_sub(1);
+                sched.slice_left == 0
+            } else {
+                false
+            }
+        }
+        None => false,
+    };
+    if expired {
+        switch_to_next(Next::Preempted);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_threads_yield_and_exit() {
+    static STEPS: AtomicU64 = AtomicU64::new(0);
+    fn worker() {
+        for _ in 0..3 {
+            STEPS.fetch_add(1, Ordering::SeqCst);
+            yield_now();
+        }
+    }
+
+    spawn("test-a", worker).unwrap();
+    spawn("test-b", worker).unwrap();
+    while STEPS.load(Ordering::SeqCst) < 6 || has_ready() {
+        yield_now();
+    }
+    // Both are gone from the thread list once they've exited
+    let mut others = 0;
+    for_each_thread(|id, _, _, _| {
+        if id != current() {
+            others += 1;
+        }
+    });
+    assert_eq!(others, 0);
+}
+
+#[test_case]
+fn test_timer_preempts() {
+    use core::sync::atomic::AtomicBool;
+
+    static SPINS: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    fn spinner() {
+        // Never yields; only the timer can get main running again
+        while !STOP.load(Ordering::SeqCst) {
+            SPINS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    spawn("test-spinner", spinner).unwrap();
+    // Never yields either, so the spinner only runs if main is preempted
+    let start = crate::time::Instant::now();
+    while SPINS.load(Ordering::SeqCst) == 0 {
+        assert!(start.elapsed() < crate::time::Duration::from_secs(1));
+        core::hint::spin_loop();
+    }
+    STOP.store(true, Ordering::SeqCst);
+    while has_ready() {
+        yield_now();
+    }
+}
+
+#[test_case]
+fn test_park_waits_for_unpark() {
+    use core::sync::atomic::AtomicBool;
+
+    static WOKEN: AtomicBool = AtomicBool::new(false);
+    static DONE: AtomicBool = AtomicBool::new(false);
+    fn sleeper() {
+        while !WOKEN.load(Ordering::SeqCst) {
+            park();
+        }
+        DONE.store(true, Ordering::SeqCst);
+    }
+
+    let id = spawn("test-parker", sleeper).unwrap();
+    // Once it's parked, yielding never runs it
+    yield_now();
+    assert!(!has_ready());
+    let mut state = None;
+    for_each_thread(|thread, _, thread_state, _| {
+        if thread == id {
+            state = Some(thread_state);
+        }
+    });
+    assert_eq!(state, Some(ThreadState::Parked));
+
+    WOKEN.store(true, Ordering::SeqCst);
+    unpark(id);
+    while !DONE.load(Ordering::SeqCst) || has_ready() {
+        yield_now();
+    }
+
+    // An unpark before the park isn't lost
+    unpark(current());
+    park();
+}
+
+#[test_case]
+fn test_stack_used_is_a_high_water_mark() {
+    use core::sync::atomic::AtomicBool;
+
+    static DONE: AtomicBool = AtomicBool::new(false);
+    fn deep() {
+        core::hint::black_box([0xffu8; 4096]);
+        while !DONE.load(Ordering::SeqCst) {
+            park();
+        }
+    }
+
+    let id = spawn("test-stack", deep).unwrap();
+    // Only the frame spawn left for the switch
+    assert!(stack_used(id).unwrap() < 256);
+    yield_now();
+    assert!(stack_used(id).unwrap() >= 4096);
+    assert_eq!(stack_used(current()), None);
+
+    DONE.store(true, Ordering::SeqCst);
+    unpark(id);
+    while has_ready() {
+        yield_now();
+    }
+    assert_eq!(stack_used(id), None);
+}
+
+#[test_case]
+fn test_affinity_masks() {
+    fn idler() {}
+
+    let id = spawn("test-affinity", idler).unwrap();
+    assert_eq!(affinity(id), Some(ALL_CPUS));
+    set_affinity(id, 1 | 1 << 63).unwrap();
+    assert_eq!(affinity(id), Some(1));
+    assert!(set_affinity(id, 0).is_err());
+    assert!(set_affinity(id, 1 << 1).is_err());
+    assert_eq!(affinity(id), Some(1));
+    // Still picked on this CPU, the one it's allowed
+    while has_ready() {
+        yield_now();
+    }
+    assert_eq!(affinity(id), None);
+    assert!(set_affinity(id, 1).is_err());
+}
+
+#[test_case]
+fn test_threads_are_charged_cpu_time() {
+    use core::sync::atomic::AtomicBool;
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static TIMES: Mutex<Option<CpuTimes>> = Mutex::new(None);
+    fn spinner() {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(20) {
+            core::hint::spin_loop();
+        }
+        *TIMES.lock() = Some(cpu_times());
+        while !STOP.load(Ordering::SeqCst) {
+            yield_now();
+        }
+    }
+
+    let before = cpu_times();
+    let id = spawn("test-times", spinner).unwrap();
+    while TIMES.lock().is_none() {
+        yield_now();
+    }
+    // All of it in the kernel, and most of the time it spun for (main got
+    // some of it, whenever the timer switched to it)
+    let times = TIMES.lock().unwrap();
+    assert_eq!(times.user, Duration::ZERO);
+    assert!(times.kernel >= Duration::from_millis(10));
+    let mut listed = None;
+    for_each_thread(|thread, _, _, times| {
+        if thread == id {
+            listed = Some(times);
+        }
+    });
+    assert!(listed.unwrap().kernel >= times.kernel);
+    assert!(cpu_times().since(before).kernel > Duration::ZERO);
+    STOP.store(true, Ordering::SeqCst);
+    while has_ready() {
+        yield_now();
+    }
+}
+
+#[test_case]
+fn test_stats_count_switches() {
+    static STATS: Mutex<Option<ThreadStats>> = Mutex::new(None);
+    fn worker() {
+        for _ in 0..3 {
+            yield_now();
+        }
+        let id = current();
+        for_each_thread_stats(|thread, _, stats| {
+            if thread == id {
+                *STATS.lock() = Some(stats);
+            }
+        });
+    }
+
+    let before = cpu_stats(0).unwrap();
+    spawn("test-stats", worker).unwrap();
+    while STATS.lock().is_none() || has_ready() {
+        yield_now();
+    }
+    // Switched to once to start, then after each yield; more only if the
+    // timer happened to preempt it
+    let stats = STATS.lock().unwrap();
+    assert!(stats.switches >= 4);
+    assert_eq!(stats.migrations, 0);
+    assert_eq!(stats.parked, Duration::ZERO);
+    assert!(cpu_stats(0).unwrap().switches >= before.switches + 2 * stats.switches);
+    assert_eq!(cpu_stats(crate::smp::MAX_CPUS), None);
+}
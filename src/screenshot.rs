@@ -0,0 +1,170 @@
+//! Screenshots as binary PPM images, sent over the serial port in base64 so
+//! a CI job capturing serial output can check what was on screen. Without a
+//! framebuffer, the VGA text screen is drawn with the framebuffer's font.
+
+use alloc::format;
+use alloc::vec;
+use x86_64::instructions::interrupts;
+use crate::framebuffer::{self, font, PALETTE};
+use crate::vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::{cp437, serial_println};
+
+/// The lines around a screenshot in serial output
+pub const BEGIN: &str = "-----BEGIN SCREENSHOT-----";
+pub const END: &str = "-----END SCREENSHOT-----";
+
+// Characters per line of base64, as in MIME
+const LINE_LENGTH: usize = 76;
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Image size in pixels, as (width, height)
+pub fn size() -> (usize, usize) {
+    interrupts::without_interrupts(|| match framebuffer::CONSOLE.lock().as_ref() {
+        Some(console) => console.resolution(),
+        None => (BUFFER_WIDTH * font::WIDTH, BUFFER_HEIGHT * font::HEIGHT),
+    })
+}
+
+// Scanline `y` of the screen as RGB
+fn scanline(y: usize, line: &mut [u8]) {
+    interrupts::without_interrupts(|| {
+        if let Some(console) = framebuffer::CONSOLE.lock().as_ref() {
+            console.read_scanline(y, line);
+            return;
+        }
+        let (row, glyph_row) = (y / font::HEIGHT, y % font::HEIGHT);
+        for (col, cell) in line.chunks_exact_mut(3 * font::WIDTH).enumerate() {
+            let (byte, foreground, background) = vga_buffer::read_cell_colors(row, col);
+            let bits = font::glyph(font::nearest(cp437::decode(byte)))[glyph_row];
+            for (x, pixel) in cell.chunks_exact_mut(3).enumerate() {
+                let color = if bits & (0x80 >> x) != 0 { foreground } else { background };
+                pixel.copy_from_slice(&PALETTE[color]);
+            }
+        }
+    });
+}
+
+/// Pass the screen to `out` as a PPM image, a scanline at a time; returns
+/// its size. Whatever is printed meanwhile may or may not make it in.
+pub fn capture(out: &mut dyn FnMut(&[u8])) -> (usize, usize) {
+    let (width, height) = size();
+    out(format!("P6\n{} {}\n255\n", width, height).as_bytes());
+    let mut line = vec![0u8; width * 3];
+    for y in 0..height {
+        scanline(y, &mut line);
+        out(&line);
+    }
+    (width, height)
+}
+
+/// Capture the screen and send it over the serial port, base64-encoded
+/// between BEGIN and END lines; returns its size
+pub fn send() -> (usize, usize) {
+    serial_println!("{}", BEGIN);
+    let mut encoder = Base64::new(|line: &str| serial_println!("{}", line));
+    let size = capture(&mut |bytes| encoder.feed(bytes));
+    encoder.finish();
+    serial_println!("{}", END);
+    size
+}
+
+// Base64 encoding, handed out a line at a time; net::pcap uses it too
+pub(crate) struct Base64<F: FnMut(&str)> {
+    out: F,
+    carry: [u8; 3],
+    carried: usize,
+    line: [u8; LINE_LENGTH],
+    len: usize,
+}
+
+impl<F: FnMut(&str)> Base64<F> {
+    pub(crate) fn new(out: F) -> Base64<F> {
+        Base64 { out, carry: [0; 3], carried: 0, line: [0; LINE_LENGTH], len: 0 }
+    }
+
+    pub(crate) fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.carry[self.carried] = byte;
+            self.carried += 1;
+            if self.carried == 3 {
+                self.encode_carry();
+            }
+        }
+    }
+
+    // Four characters for the carried bytes, padded with = if there are
+    // fewer than three
+    fn encode_carry(&mut self) {
+        let [a, b, c] = self.carry;
+        let group = u32::from(a) << 16 | u32::from(b) << 8 | u32::from(c);
+        for index in 0..4 {
+            self.line[self.len] = if index <= self.carried {
+                ALPHABET[((group >> (18 - 6 * index)) & 0x3f) as usize]
+            } else {
+                b'='
+            };
+            self.len += 1;
+        }
+        self.carry = [0; 3];
+        self.carried = 0;
+        if self.len == LINE_LENGTH {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        // Only ever ASCII from ALPHABET
+        (self.out)(core::str::from_utf8(&self.line[..self.len]).unwrap());
+        self.len = 0;
+    }
+
+    pub(crate) fn finish(mut self) {
+        if self.carried > 0 {
+            self.encode_carry();
+        }
+        if self.len > 0 {
+            self.flush();
+        }
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_base64() {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    let mut lines = Vec::new();
+    let mut encoder = Base64::new(|line: &str| lines.push(String::from(line)));
+    // Split across feeds, and not a multiple of three
+    encoder.feed(b"Ma");
+    encoder.feed(b"nMa");
+    encoder.finish();
+    assert_eq!(lines, ["TWFuTWE="]);
+
+    lines.clear();
+    let mut encoder = Base64::new(|line: &str| lines.push(String::from(line)));
+    encoder.feed(&[0xff; 60]);
+    encoder.finish();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].len(), LINE_LENGTH);
+    assert_eq!(lines[1], "////");
+}
+
+#[test_case]
+fn test_text_screen_capture() {
+    use alloc::vec::Vec;
+
+    let mut header = Vec::new();
+    let mut total = 0;
+    let size = capture(&mut |bytes| {
+        if header.is_empty() {
+            header.extend_from_slice(bytes);
+        }
+        total += bytes.len();
+    });
+    assert_eq!(size, (640, 400));
+    assert_eq!(header, b"P6\n640 400\n255\n");
+    assert_eq!(total, header.len() + 640 * 400 * 3);
+}
@@ -0,0 +1,117 @@
+use crate::mouse::MouseEvent;
+use crate::vga_buffer::{self, BUFFER_WIDTH};
+use crate::clipboard::{self, CLIPBOARD_MAX};
+
+/// Text selection with the mouse: dragging with the left button highlights
+/// cells on screen, and letting go copies their text to the clipboard
+pub struct Selection {
+    // Where the drag started and where the pointer is now, as (row, col)
+    anchor: (usize, usize),
+    end: (usize, usize),
+    dragging: bool,
+    middle: bool,
+}
+
+impl Selection {
+    pub const fn new() -> Selection {
+        Selection { anchor: (0, 0), end: (0, 0), dragging: false, middle: false }
+    }
+
+    /// Track one mouse event. Returns true for a middle click, which the
+    /// caller should take as a paste.
+    pub fn feed(&mut self, event: MouseEvent) -> bool {
+        let position = (event.row, event.col);
+        match (self.dragging, event.buttons.left) {
+            (false, true) => {
+                self.anchor = position;
+                self.end = position;
+                self.dragging = true;
+                self.highlight();
+            }
+            (true, true) if position != self.end => {
+                // Un-highlight the old range, then highlight the new one
+                self.highlight();
+                self.end = position;
+                self.highlight();
+            }
+            (true, false) => {
+                self.highlight();
+                self.dragging = false;
+                self.copy();
+            }
+            _ => {}
+        }
+
+        let clicked = event.buttons.middle && !self.middle;
+        self.middle = event.buttons.middle;
+        clicked
+    }
+
+    // The selected cells in reading order, first and last included
+    fn range(&self) -> impl Iterator<Item = (usize, usize)> {
+        let (start, end) = if self.anchor <= self.end { (self.anchor, self.end) } else { (self.end, self.anchor) };
+        let index = |(row, col): (usize, usize)| row * BUFFER_WIDTH + col;
+        (index(start)..=index(end)).map(|index| (index / BUFFER_WIDTH, index % BUFFER_WIDTH))
+    }
+
+    // Inverting is its own undo, so this both draws and erases the highlight
+    fn highlight(&self) {
+        for (row, col) in self.range() {
+            vga_buffer::invert_cell(row, col);
+        }
+    }
+
+    // One line per row, without the blanks at the end of each
+    fn copy(&self) {
+        let mut text = [0u8; CLIPBOARD_MAX];
+        let mut len = 0;
+        let mut line_start = 0;
+        let mut last_row = None;
+        for (row, col) in self.range() {
+            if last_row.map_or(false, |last| last != row) {
+                len = line_start + trimmed_len(&text[line_start..len]);
+                text[len] = b'\n';
+                len += 1;
+                line_start = len;
+            }
+            last_row = Some(row);
+            text[len] = vga_buffer::read_cell(row, col);
+            len += 1;
+        }
+        len = line_start + trimmed_len(&text[line_start..len]);
+        clipboard::copy(&text[..len]);
+    }
+}
+
+fn trimmed_len(line: &[u8]) -> usize {
+    line.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_drag_copies_text() {
+    use crate::mouse::Buttons;
+    use crate::vga_buffer::Color;
+
+    for (row, text) in [(0, "hello"), (1, "world")].iter() {
+        for col in 0..BUFFER_WIDTH {
+            let byte = text.as_bytes().get(col).copied().unwrap_or(b' ');
+            vga_buffer::write_cell(*row, col, byte, Color::Green, Color::Black);
+        }
+    }
+
+    let event = |row, col, left| MouseEvent {
+        row,
+        col,
+        buttons: Buttons { left, ..Buttons::default() },
+    };
+    let mut selection = Selection::new();
+    selection.feed(event(0, 1, true));
+    selection.feed(event(2, 0, true));
+    // Dragged back up; the selection follows
+    selection.feed(event(1, 2, true));
+    assert!(!selection.feed(event(1, 2, false)));
+    clipboard::with_contents(|text| assert_eq!(text, &b"ello\nwor"[..]));
+    assert!(selection.feed(MouseEvent { buttons: Buttons { middle: true, ..Buttons::default() }, ..event(1, 2, false) }));
+}
@@ -0,0 +1,160 @@
+//! A handful of checks that a boot works, for machines the test harness
+//! can't reach: `selftest` on the command line runs them once the kernel's
+//! up, prints a summary, and exits QEMU (if it's there) or halts. The shell
+//! has them as `selftest` too.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::drivers::ata::{self, SECTOR_SIZE};
+use crate::time::{Duration, Instant};
+use crate::{interrupts, net, println, qemu};
+
+// How long the timer has to tick
+const TICK_WINDOW: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(&'static str),
+    /// Couldn't be checked here, e.g. for want of the hardware
+    Skip(&'static str),
+}
+
+fn timer_fires() -> Outcome {
+    let (before, start) = (interrupts::irq_count(0), Instant::now());
+    // Busy, so the tick isn't stopped for idling
+    while start.elapsed() < TICK_WINDOW {
+        if interrupts::irq_count(0) > before + 1 {
+            return Outcome::Pass;
+        }
+        core::hint::spin_loop();
+    }
+    Outcome::Fail("no timer interrupts")
+}
+
+fn clock_runs() -> Outcome {
+    let start = Instant::now();
+    for _ in 0..10_000_000 {
+        if start.elapsed() > Duration::ZERO {
+            return Outcome::Pass;
+        }
+        core::hint::spin_loop();
+    }
+    Outcome::Fail("the clock doesn't move")
+}
+
+fn heap() -> Outcome {
+    // Enough sizes to go through every block size and the fallback
+    let blocks: Vec<Box<[u8]>> =
+        (0..12).map(|shift| alloc::vec![shift as u8; 8 << shift].into_boxed_slice()).collect();
+    let intact = blocks.iter().enumerate()
+        .all(|(shift, block)| block.len() == 8 << shift && block.iter().all(|&byte| byte == shift as u8));
+    drop(blocks);
+    let sum: u64 = (0..1000u64).collect::<Vec<_>>().iter().sum();
+    if intact && sum == 499_500 { Outcome::Pass } else { Outcome::Fail("heap memory corrupted") }
+}
+
+fn disk_read() -> Outcome {
+    let disk = match ata::disk(0) {
+        Some(disk) => disk,
+        None => return Outcome::Skip("no disk"),
+    };
+    // Twice, and the same both times
+    let (mut first, mut second) = ([0; SECTOR_SIZE], [0xaa; SECTOR_SIZE]);
+    match disk.read_sectors(0, &mut first).and_then(|()| disk.read_sectors(0, &mut second)) {
+        Ok(()) if first == second => Outcome::Pass,
+        Ok(()) => Outcome::Fail("sector 0 reads differently twice"),
+        Err(message) => Outcome::Fail(message),
+    }
+}
+
+fn network_loopback() -> Outcome {
+    use crate::task::{simple_executor::SimpleExecutor, Task};
+
+    static OUTCOME: spin::Mutex<Outcome> = spin::Mutex::new(Outcome::Fail("didn't finish"));
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        let mut frame = [0; 16];
+        let outcome = match net::send("lo", b"selftest").await {
+            Ok(()) => match net::receive("lo", &mut frame).await {
+                Ok(len) if &frame[..len] == b"selftest" => Outcome::Pass,
+                Ok(_) => Outcome::Fail("lo gave back a different frame"),
+                Err(message) => Outcome::Fail(message),
+            },
+            Err(message) => Outcome::Fail(message),
+        };
+        *OUTCOME.lock() = outcome;
+    }));
+    executor.run();
+    *OUTCOME.lock()
+}
+
+const CHECKS: [(&str, fn() -> Outcome); 5] = [
+    ("timer interrupts", timer_fires),
+    ("monotonic clock", clock_runs),
+    ("heap", heap),
+    ("disk read", disk_read),
+    ("network loopback", network_loopback),
+];
+
+/// The checks, by name
+pub fn names() -> impl Iterator<Item = &'static str> {
+    CHECKS.iter().map(|&(name, _)| name)
+}
+
+/// Run the check called `name`, if there is one
+pub fn check(name: &str) -> Option<Outcome> {
+    CHECKS.iter().find(|&&(check, _)| check == name).map(|&(_, check)| check())
+}
+
+/// How many checks came out each way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+/// Run every check, printing how each went
+pub fn run() -> Summary {
+    let mut summary = Summary::default();
+    for &(name, check) in CHECKS.iter() {
+        match check() {
+            Outcome::Pass => {
+                summary.passed += 1;
+                println!("selftest: {:<18} PASS", name);
+            }
+            Outcome::Fail(message) => {
+                summary.failed += 1;
+                println!("selftest: {:<18} FAIL ({})", name, message);
+            }
+            Outcome::Skip(message) => {
+                summary.skipped += 1;
+                println!("selftest: {:<18} SKIP ({})", name, message);
+            }
+        }
+    }
+    println!("selftest: {} passed, {} failed, {} skipped", summary.passed, summary.failed, summary.skipped);
+    summary
+}
+
+/// For the `selftest` boot mode: run the checks, and exit QEMU with how
+/// they went, or on hardware halt with the summary on screen
+pub fn boot() -> ! {
+    let summary = run();
+    qemu::exit(if summary.failed == 0 { qemu::ExitCode::Success } else { qemu::ExitCode::Failed })
+}
+
+/// TESTS
+
+#[test_case]
+fn test_checks_pass() {
+    assert_eq!(timer_fires(), Outcome::Pass);
+    assert_eq!(clock_runs(), Outcome::Pass);
+    assert_eq!(heap(), Outcome::Pass);
+    // The boot image is a disk, if QEMU has it on ATA
+    assert!(matches!(disk_read(), Outcome::Pass | Outcome::Skip(_)));
+    assert_eq!(network_loopback(), Outcome::Pass);
+    let summary = run();
+    assert_eq!((summary.failed, summary.passed + summary.skipped), (0, CHECKS.len()));
+}
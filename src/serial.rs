@@ -1,19 +1,162 @@
+//! COM1: where the kernel logs to, and a character device. What's received
+//! comes in on IRQ4 and is queued for whoever has the port open.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
 use uart_16550::SerialPort;
-use spin::Mutex;
+use spin::{Mutex, Once};
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use crate::device;
+use crate::io::CharDevice;
+use crate::pio::{Port, Ports, ReadOnly, ReadWrite};
+use crate::sync::{SpscQueue, WakerSlot};
+
+const COM1: u16 = 0x3F8;
+/// PIC line COM1 interrupts on
+pub const IRQ: u8 = 4;
+// Bytes that can come in before the reader falls behind
+const QUEUE_SIZE: usize = 256;
+// Line status: a byte has been received
+const LINE_STATUS_DATA_READY: u8 = 1;
+// Line status: there's room to send a byte
+const LINE_STATUS_EMPTY: u8 = 0x20;
+// Interrupt enable: when a byte's received
+const INTERRUPT_RECEIVED: u8 = 1;
+// Registers, from COM1
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const LINE_STATUS: u16 = 5;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+struct Registers {
+    data: Port<ReadWrite, u8>,
+    line_status: Port<ReadOnly, u8>,
+}
+
+// Set by init, once device::init has claimed the ports for uart0
+static REGISTERS: Once<Registers> = Once::new();
+static RECEIVED: SpscQueue<u8, QUEUE_SIZE> = SpscQueue::new();
+// The task waiting for the next byte
+static WAKER: WakerSlot = WakerSlot::new();
+// Received bytes can only go to one reader
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Have COM1 interrupt as bytes come in. Without the ports claimed for it,
+/// it stays for logging alone.
+pub fn init() {
+    let found = device::find(Some(device::platform()), "uart0").ok_or("no uart0").and_then(|id| {
+        let ports = Ports::owned(id, COM1, COM1 + 7)?;
+        let enable: Port<ReadWrite, u8> = ports.port(INTERRUPT_ENABLE)?;
+        Ok((Registers { data: ports.port(DATA)?, line_status: ports.port(LINE_STATUS)? }, enable))
+    });
+    let (registers, enable) = match found {
+        Ok(found) => found,
+        Err(message) => {
+            crate::log::warn!("serial: {}", message);
+            return;
+        }
+    };
+    lazy_static::initialize(&SERIAL1);
+    REGISTERS.call_once(|| registers);
+    interrupts::without_interrupts(|| enable.write(INTERRUPT_RECEIVED));
+    crate::interrupts::unmask_irq(IRQ);
+}
+
+/// Called by the interrupt handler: queue whatever's been received, or
+/// drop it with nobody to read it
+pub(crate) fn receive() {
+    let registers = match REGISTERS.r#try() {
+        Some(registers) => registers,
+        None => return,
+    };
+    while registers.line_status.read() & LINE_STATUS_DATA_READY != 0 {
+        let byte = registers.data.read();
+        if !TAKEN.load(Ordering::Acquire) {
+            continue;
+        }
+        if RECEIVED.push(byte).is_err() {
+            crate::log::warn!("serial queue full; dropping input");
+            break;
+        }
+    }
+    WAKER.wake();
+}
+
+/// COM1, as a character device: reads are of what's been received since it
+/// was opened, writes go out as the kernel's own output does
+pub struct Serial {
+    _private: (),
+}
+
+impl Serial {
+    /// Only one can be open at a time, since each byte goes to one reader
+    pub fn open() -> Option<Serial> {
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        while RECEIVED.pop().is_some() {}
+        Some(Serial { _private: () })
+    }
+}
+
+impl CharDevice for Serial {
+    fn poll_read(&self, context: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, &'static str>> {
+        let mut len = 0;
+        for _ in 0..2 {
+            while len < buf.len() {
+                match RECEIVED.pop() {
+                    Some(byte) => buf[len] = byte,
+                    None => break,
+                }
+                len += 1;
+            }
+            if len > 0 || buf.is_empty() {
+                return Poll::Ready(Ok(len));
+            }
+            // Again once registered, in case a byte came in before
+            WAKER.register(context.waker());
+        }
+        Poll::Pending
+    }
+
+    // There's room for the next byte as soon as the last is on the wire, a
+    // byte's time at most, so a write is never Pending
+    fn poll_write(&self, _context: &mut Context, buf: &[u8]) -> Poll<Result<usize, &'static str>> {
+        let registers = match REGISTERS.r#try() {
+            Some(registers) => registers,
+            None => return Poll::Ready(Err("serial port isn't set up")),
+        };
+        interrupts::without_interrupts(|| {
+            // Held so the kernel's output doesn't land in the middle
+            let _port = SERIAL1.lock();
+            for &byte in buf {
+                while registers.line_status.read() & LINE_STATUS_EMPTY == 0 {
+                    core::hint::spin_loop();
+                }
+                registers.data.write(byte);
+            }
+        });
+        Poll::Ready(Ok(buf.len()))
+    }
+}
+
+impl Drop for Serial {
+    fn drop(&mut self) {
+        TAKEN.store(false, Ordering::Release);
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
         SERIAL1
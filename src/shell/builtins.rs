@@ -0,0 +1,1596 @@
+use alloc::string::String;
+use crate::{interrupts, kptr, log, print, println, time};
+use super::{env, Command, Status, COMMANDS, FAILURE, SUCCESS};
+
+pub(super) const BUILTINS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list commands, or describe one",
+        run: cmd_help,
+        complete: Some(complete_command_names),
+    },
+    Command {
+        name: "echo",
+        help: "print the arguments",
+        run: cmd_echo,
+        complete: None,
+    },
+    Command {
+        name: "true",
+        help: "do nothing, successfully",
+        run: cmd_true,
+        complete: None,
+    },
+    Command {
+        name: "false",
+        help: "do nothing, unsuccessfully",
+        run: cmd_false,
+        complete: None,
+    },
+    Command {
+        name: "set",
+        help: "list variables, or set one: set NAME VALUE / set NAME=VALUE",
+        run: cmd_set,
+        complete: Some(complete_variable_names),
+    },
+    Command {
+        name: "export",
+        help: "mark variables for export: export NAME[=VALUE]...",
+        run: cmd_export,
+        complete: Some(complete_variable_names),
+    },
+    Command {
+        name: "unset",
+        help: "remove variables",
+        run: cmd_unset,
+        complete: Some(complete_variable_names),
+    },
+    Command {
+        name: "clear",
+        help: "clear the screen",
+        run: cmd_clear,
+        complete: None,
+    },
+    Command {
+        name: "cmdline",
+        help: "the kernel command line",
+        run: cmd_cmdline,
+        complete: None,
+    },
+    Command {
+        name: "uptime",
+        help: "time since boot",
+        run: cmd_uptime,
+        complete: None,
+    },
+    Command {
+        name: "date",
+        help: "current date and time (RFC 3339)",
+        run: cmd_date,
+        complete: None,
+    },
+    Command {
+        name: "tz",
+        help: "show or set the UTC offset: tz [+HH:MM|-HH:MM]",
+        run: cmd_tz,
+        complete: None,
+    },
+    Command {
+        name: "keymap",
+        help: "show or set the keyboard layout: keymap [us|uk|de|fr|dvorak]",
+        run: cmd_keymap,
+        complete: Some(complete_keymap),
+    },
+    Command {
+        name: "kbdrate",
+        help: "show or set key repeat: kbdrate [delay_ms keys_per_second]",
+        run: cmd_kbdrate,
+        complete: None,
+    },
+    Command {
+        name: "chords",
+        help: "list global keyboard shortcuts",
+        run: cmd_chords,
+        complete: None,
+    },
+    Command {
+        name: "ps",
+        help: "list kernel threads and the CPU time each has had",
+        run: cmd_ps,
+        complete: None,
+    },
+    Command {
+        name: "top",
+        help: "watch the kernel threads, busiest first, with their stacks and the heap",
+        run: cmd_top,
+        complete: None,
+    },
+    Command {
+        name: "schedstat",
+        help: "scheduler counts for each CPU and each thread",
+        run: cmd_schedstat,
+        complete: None,
+    },
+    Command {
+        name: "taskset",
+        help: "show or set the CPUs a thread may run on: taskset <tid> [mask]",
+        run: cmd_taskset,
+        complete: None,
+    },
+    Command {
+        name: "cpu",
+        help: "list the CPUs, or take one offline or back: cpu [park|resume <id>]",
+        run: cmd_cpu,
+        complete: None,
+    },
+    Command {
+        name: "lsirq",
+        help: "interrupt counts per IRQ line",
+        run: cmd_lsirq,
+        complete: None,
+    },
+    Command {
+        name: "lsdev",
+        help: "show the device tree",
+        run: cmd_lsdev,
+        complete: None,
+    },
+    Command {
+        name: "cpuinfo",
+        help: "processor, frequency and temperature",
+        run: cmd_cpuinfo,
+        complete: None,
+    },
+    Command {
+        name: "meminfo",
+        help: "physical memory usage",
+        run: cmd_meminfo,
+        complete: None,
+    },
+    Command {
+        name: "swap",
+        help: "swap usage, or swap on|off",
+        run: cmd_swap,
+        complete: None,
+    },
+    Command {
+        name: "ksm",
+        help: "same-page merging savings, or ksm on|off",
+        run: cmd_ksm,
+        complete: None,
+    },
+    Command {
+        name: "rmap",
+        help: "where a physical frame is mapped: rmap <address>",
+        run: cmd_rmap,
+        complete: None,
+    },
+    Command {
+        name: "memlimit",
+        help: "user program memory use, or set its limit: memlimit <KiB>|none",
+        run: cmd_memlimit,
+        complete: None,
+    },
+    Command {
+        name: "caps",
+        help: "capabilities user programs start with, or set them: caps [all|none|<cap>,...]",
+        run: cmd_caps,
+        complete: None,
+    },
+    Command {
+        name: "lspci",
+        help: "list PCI devices, with lspci -v their BARs and interrupts too",
+        run: cmd_lspci,
+        complete: None,
+    },
+    Command {
+        name: "lsblk",
+        help: "list disks",
+        run: cmd_lsblk,
+        complete: None,
+    },
+    Command {
+        name: "smartctl",
+        help: "show what a disk is and what SMART says of it: smartctl <disk>",
+        run: cmd_smartctl,
+        complete: None,
+    },
+    Command {
+        name: "ls",
+        help: "list a directory on disk, with -l modes and times: ls [-l] [path]",
+        run: cmd_ls,
+        complete: Some(complete_path),
+    },
+    Command {
+        name: "cat",
+        help: "print files from disk",
+        run: cmd_cat,
+        complete: Some(complete_path),
+    },
+    Command {
+        name: "stat",
+        help: "show a file's size, mode and times: stat path...",
+        run: cmd_stat,
+        complete: Some(complete_path),
+    },
+    Command {
+        name: "fsck",
+        help: "check the filesystem on disk, and with -r repair it: fsck [-r]",
+        run: cmd_fsck,
+        complete: None,
+    },
+    Command {
+        name: "mount",
+        help: "list what's mounted, or mount a disk's FAT32 filesystem on a directory: mount [<disk> <path>]",
+        run: cmd_mount,
+        complete: Some(complete_path),
+    },
+    Command {
+        name: "umount",
+        help: "sync and unmount the filesystem mounted on a directory: umount <path>",
+        run: cmd_umount,
+        complete: None,
+    },
+    Command {
+        name: "cryptmount",
+        help: "mount an encrypted disk, asking for its passphrase: cryptmount <disk>",
+        run: cmd_cryptmount,
+        complete: None,
+    },
+    Command {
+        name: "resync",
+        help: "copy a mirror's member over the others where they differ: resync [member]",
+        run: cmd_resync,
+        complete: None,
+    },
+    Command {
+        name: "strace",
+        help: "run a program, printing each syscall it makes: strace <program>",
+        run: cmd_strace,
+        complete: Some(complete_path),
+    },
+    Command {
+        name: "sync",
+        help: "put everything written to the filesystem on the disk",
+        run: cmd_sync,
+        complete: None,
+    },
+    Command {
+        name: "selftest",
+        help: "check the timer, clock, heap and disk work",
+        run: cmd_selftest,
+        complete: None,
+    },
+    Command {
+        name: "pmtest",
+        help: "suspend every device, then resume it again",
+        run: cmd_pmtest,
+        complete: None,
+    },
+    Command {
+        name: "ksyms",
+        help: "list exported kernel symbols",
+        run: cmd_ksyms,
+        complete: None,
+    },
+    Command {
+        name: "sizeinfo",
+        help: "show the kernel image's size by section, and its biggest functions: sizeinfo [count]",
+        run: cmd_sizeinfo,
+        complete: None,
+    },
+    Command {
+        name: "kptr",
+        help: "show or set kernel address hashing in logs (kptr hashed|raw)",
+        run: cmd_kptr,
+        complete: Some(complete_kptr),
+    },
+    Command {
+        name: "dmesg",
+        help: "print the kernel log, or dmesg -c to print and clear it",
+        run: cmd_dmesg,
+        complete: None,
+    },
+    Command {
+        name: "loglevel",
+        help: "show or set log levels: loglevel [module] <level|default>",
+        run: cmd_loglevel,
+        complete: Some(complete_loglevel),
+    },
+    Command {
+        name: "events",
+        help: "print kernel events as they happen until Escape",
+        run: cmd_events,
+        complete: None,
+    },
+    Command {
+        name: "keys",
+        help: "echo keypresses from an async task until Escape",
+        run: cmd_keys,
+        complete: None,
+    },
+    Command {
+        name: "replay",
+        help: "record input, send the recording over serial, or play one back",
+        run: cmd_replay,
+        complete: None,
+    },
+    Command {
+        name: "screenshot",
+        help: "send the screen over serial as a base64 PPM image",
+        run: cmd_screenshot,
+        complete: None,
+    },
+    Command {
+        name: "snake",
+        help: "play snake",
+        run: cmd_snake,
+        complete: None,
+    },
+    Command {
+        name: "panic",
+        help: "panic the kernel, to test the panic path: panic [message]",
+        run: cmd_panic,
+        complete: None,
+    },
+    Command {
+        name: "reboot",
+        help: "restart the machine",
+        run: cmd_reboot,
+        complete: None,
+    },
+];
+
+fn cmd_help(args: &[&str]) -> Status {
+    let commands = COMMANDS.lock();
+    match args.first() {
+        Some(name) => match commands.get(name) {
+            Some(command) => println!("{}: {}", command.name, command.help),
+            None => {
+                println!("help: no such command: {}", name);
+                return FAILURE;
+            }
+        },
+        None => {
+            for command in commands.iter() {
+                println!("{:<12} {}", command.name, command.help);
+            }
+        }
+    }
+    SUCCESS
+}
+
+fn complete_command_names(_word: &str, candidates: &mut dyn FnMut(&str)) {
+    for command in COMMANDS.lock().iter() {
+        candidates(command.name);
+    }
+}
+
+fn cmd_echo(args: &[&str]) -> Status {
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        print!("{}", arg);
+    }
+    println!();
+    SUCCESS
+}
+
+fn cmd_true(_args: &[&str]) -> Status {
+    SUCCESS
+}
+
+fn cmd_false(_args: &[&str]) -> Status {
+    FAILURE
+}
+
+// Accepts both NAME=VALUE and NAME VALUE...
+fn assign<'a>(args: &[&'a str]) -> Result<&'a str, &'static str> {
+    let (name, value) = match args[0].find('=') {
+        Some(eq) => (&args[0][..eq], &args[0][eq + 1..]),
+        None if args.len() > 1 => (args[0], args[1]),
+        None => return Ok(args[0]),
+    };
+    env::set(name, value)?;
+    Ok(name)
+}
+
+fn cmd_set(args: &[&str]) -> Status {
+    if args.is_empty() {
+        env::for_each(|name, value, exported| {
+            println!("{}{}={}", if exported { "export " } else { "" }, name, value);
+        });
+        return SUCCESS;
+    }
+    match assign(args) {
+        Ok(_) => SUCCESS,
+        Err(message) => {
+            println!("set: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_export(args: &[&str]) -> Status {
+    for arg in args {
+        if let Err(message) = assign(&[*arg]).and_then(env::export) {
+            println!("export: {}: {}", arg, message);
+            return FAILURE;
+        }
+    }
+    SUCCESS
+}
+
+fn cmd_unset(args: &[&str]) -> Status {
+    for name in args {
+        env::unset(name);
+    }
+    SUCCESS
+}
+
+fn complete_variable_names(_word: &str, candidates: &mut dyn FnMut(&str)) {
+    env::for_each(|name, _, _| candidates(name));
+}
+
+fn cmd_clear(_args: &[&str]) -> Status {
+    crate::vga_buffer::clear_screen();
+    SUCCESS
+}
+
+fn cmd_cmdline(_args: &[&str]) -> Status {
+    println!("{}", crate::cmdline::cmdline());
+    SUCCESS
+}
+
+fn cmd_uptime(_args: &[&str]) -> Status {
+    let uptime = time::uptime();
+    println!("up {}.{:03}s ({} timer ticks, clock source: {})", uptime.as_secs(),
+        uptime.subsec_millis(), time::ticks(), time::source());
+    SUCCESS
+}
+
+fn cmd_date(_args: &[&str]) -> Status {
+    println!("{}", time::SystemTime::now());
+    SUCCESS
+}
+
+// Parses [+-]HH[:MM] into minutes
+fn parse_utc_offset(offset: &str) -> Option<i32> {
+    let (sign, rest) = match offset.as_bytes().first()? {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => (1, offset),
+    };
+    let (hours, minutes) = match rest.find(':') {
+        Some(colon) => (&rest[..colon], &rest[colon + 1..]),
+        None => (rest, "0"),
+    };
+    let hours: i32 = hours.parse().ok()?;
+    let minutes: i32 = minutes.parse().ok()?;
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+    Some(sign * (hours * 60 + minutes))
+}
+
+fn cmd_tz(args: &[&str]) -> Status {
+    match args.first() {
+        None => {
+            let offset = time::system::utc_offset_minutes();
+            let sign = if offset < 0 { '-' } else { '+' };
+            println!("UTC{}{:02}:{:02}", sign, offset.abs() / 60, offset.abs() % 60);
+            SUCCESS
+        }
+        Some(offset) => match parse_utc_offset(offset) {
+            Some(minutes) => {
+                time::system::set_utc_offset_minutes(minutes);
+                SUCCESS
+            }
+            None => {
+                println!("tz: invalid offset: {}", offset);
+                FAILURE
+            }
+        },
+    }
+}
+
+fn cmd_keymap(args: &[&str]) -> Status {
+    use crate::keyboard::{self, layout};
+
+    match args {
+        [] => println!("{}", layout::layout().name()),
+        [name] => match keyboard::Layout::from_name(name) {
+            Some(layout) => keyboard::set_layout(layout),
+            None => {
+                println!("keymap: unknown layout: {}", name);
+                return FAILURE;
+            }
+        },
+        _ => {
+            println!("usage: keymap [us|uk|de|fr|dvorak]");
+            return FAILURE;
+        }
+    }
+    SUCCESS
+}
+
+fn complete_keymap(_word: &str, candidates: &mut dyn FnMut(&str)) {
+    for layout in crate::keyboard::layout::LAYOUTS.iter() {
+        candidates(layout.name());
+    }
+}
+
+fn cmd_kbdrate(args: &[&str]) -> Status {
+    use crate::keyboard;
+
+    let setting = match args {
+        [] => keyboard::typematic(),
+        [delay, rate] => {
+            let (delay, rate) = match (delay.parse::<u32>(), rate.parse::<u32>()) {
+                (Ok(delay), Ok(rate)) => (delay, rate),
+                _ => {
+                    println!("kbdrate: expected numbers");
+                    return FAILURE;
+                }
+            };
+            match keyboard::set_typematic(delay, rate.saturating_mul(10)) {
+                Ok(setting) => setting,
+                Err(message) => {
+                    println!("kbdrate: {}", message);
+                    return FAILURE;
+                }
+            }
+        }
+        _ => {
+            println!("usage: kbdrate [delay_ms keys_per_second]");
+            return FAILURE;
+        }
+    };
+    println!(
+        "delay {} ms, rate {}.{} keys/s",
+        setting.delay_ms,
+        setting.rate_tenths / 10,
+        setting.rate_tenths % 10
+    );
+    SUCCESS
+}
+
+fn cmd_chords(_args: &[&str]) -> Status {
+    crate::keyboard::chord::for_each(|chord| {
+        println!("{}{:?}\t{}", chord.modifiers, chord.key, chord.name);
+    });
+    SUCCESS
+}
+
+fn cmd_ps(_args: &[&str]) -> Status {
+    use crate::sched::{self, ThreadState};
+
+    let seconds = |duration: time::Duration| alloc::format!("{}.{:03}", duration.as_secs(), duration.subsec_millis());
+    println!("TID  STATE    USER      SYS       NAME");
+    sched::for_each_thread(|id, name, state, times| {
+        let state = match state {
+            ThreadState::Running => "running",
+            ThreadState::Ready => "ready",
+            ThreadState::Parked => "parked",
+        };
+        println!("{:<4} {:<8} {:<9} {:<9} {}", id.as_u64(), state, seconds(times.user), seconds(times.kernel), name);
+    });
+    SUCCESS
+}
+
+fn cmd_top(_args: &[&str]) -> Status {
+    if crate::cmdline::headless() {
+        println!("top: needs the VGA console");
+        return FAILURE;
+    }
+    crate::top::run();
+    SUCCESS
+}
+
+fn cmd_schedstat(_args: &[&str]) -> Status {
+    use crate::sched;
+
+    let seconds = |duration: time::Duration| alloc::format!("{}.{:03}", duration.as_secs(), duration.subsec_millis());
+    println!("CPU  SWITCHES  PREEMPTED  QUEUE-AVG  QUEUE-MAX");
+    for cpu in 0..crate::smp::online() {
+        if let Some(stats) = sched::cpu_stats(cpu) {
+            let mean = stats.queue_mean_tenths();
+            let mean = alloc::format!("{}.{}", mean / 10, mean % 10);
+            println!("{:<4} {:<9} {:<10} {:<10} {}", cpu, stats.switches, stats.preemptions, mean, stats.queue_max);
+        }
+    }
+    println!();
+    println!("TID  SWITCHES  PREEMPTED  MIGRATED  RUNNING   READY     PARKED    NAME");
+    sched::for_each_thread_stats(|id, name, stats| {
+        println!("{:<4} {:<9} {:<10} {:<9} {:<9} {:<9} {:<9} {}", id.as_u64(), stats.switches, stats.preemptions,
+            stats.migrations, seconds(stats.running), seconds(stats.ready), seconds(stats.parked), name);
+    });
+    SUCCESS
+}
+
+fn cmd_taskset(args: &[&str]) -> Status {
+    use crate::sched;
+
+    let (tid, mask) = match args {
+        [tid] => (tid, None),
+        [tid, mask] => (tid, Some(mask)),
+        _ => {
+            println!("usage: taskset <tid> [mask]");
+            return FAILURE;
+        }
+    };
+    let mut thread = None;
+    sched::for_each_thread(|id, _, _, _| {
+        if tid.parse() == Ok(id.as_u64()) {
+            thread = Some(id);
+        }
+    });
+    let thread = match thread {
+        Some(thread) => thread,
+        None => {
+            println!("taskset: no thread {}", tid);
+            return FAILURE;
+        }
+    };
+    if let Some(mask) = mask {
+        let parsed = match mask.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => mask.parse(),
+        };
+        let result = parsed.map_err(|_| "bad mask").and_then(|mask| sched::set_affinity(thread, mask));
+        if let Err(message) = result {
+            println!("taskset: {}", message);
+            return FAILURE;
+        }
+    }
+    match sched::affinity(thread) {
+        Some(mask) => println!("thread {} may run on CPUs {:#x}", tid, mask),
+        None => println!("taskset: no thread {}", tid),
+    }
+    SUCCESS
+}
+
+fn cmd_cpu(args: &[&str]) -> Status {
+    use crate::smp::{self, CpuState};
+
+    let result = match args {
+        [] => {
+            println!("CPU  STATE");
+            for cpu in 0..smp::MAX_CPUS {
+                match smp::state(cpu) {
+                    Some(CpuState::Online) => println!("{:<4} online", cpu),
+                    Some(CpuState::Parked) => println!("{:<4} parked", cpu),
+                    None => {}
+                }
+            }
+            return SUCCESS;
+        }
+        ["park", cpu] => cpu.parse().map_err(|_| "bad CPU").and_then(smp::park),
+        ["resume", cpu] => cpu.parse().map_err(|_| "bad CPU").and_then(smp::resume),
+        _ => {
+            println!("usage: cpu [park|resume <id>]");
+            return FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("cpu: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_lsirq(_args: &[&str]) -> Status {
+    println!("IRQ  VECTOR  COUNT       NAME");
+    for irq in 0..16 {
+        let count = interrupts::irq_count(irq);
+        let name = interrupts::irq_name(irq);
+        if count > 0 || name.is_some() {
+            println!("{:<4} {:<7} {:<11} {}", irq, interrupts::PIC_1_OFFSET + irq,
+                count, name.unwrap_or("-"));
+        }
+    }
+    SUCCESS
+}
+
+fn cmd_lsdev(_args: &[&str]) -> Status {
+    crate::device::walk(|depth, device| {
+        let indent = 2 * depth;
+        println!("{:indent$}{:<width$} {:<10} {:<11} {}", "", device.name,
+            device.driver.unwrap_or("-"), device.state.as_str(), device.description,
+            indent = indent, width = 16usize.saturating_sub(indent));
+        crate::resource::for_each_owned(device.id, |resource| {
+            println!("{:indent$}  {}", "", resource, indent = indent);
+        });
+    });
+    SUCCESS
+}
+
+fn cmd_selftest(_args: &[&str]) -> Status {
+    if crate::selftest::run().failed == 0 { SUCCESS } else { FAILURE }
+}
+
+fn cmd_pmtest(_args: &[&str]) -> Status {
+    use crate::device;
+
+    let result = x86_64::instructions::interrupts::without_interrupts(|| {
+        device::suspend_all().and_then(|()| device::resume_all())
+    });
+    match result {
+        Ok(()) => {
+            println!("pmtest: all devices suspended and resumed");
+            SUCCESS
+        }
+        Err(message) => {
+            println!("pmtest: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_cpuinfo(_args: &[&str]) -> Status {
+    use crate::cpu::{self, Feature};
+
+    let text = |bytes: &[u8]| core::str::from_utf8(bytes).unwrap_or("?").trim_matches('\0').trim();
+    println!("vendor_id       : {}", text(&cpu::vendor()));
+    if let Some(brand) = cpu::brand() {
+        println!("model name      : {}", text(&brand));
+    }
+    println!("cpus online     : {}", crate::smp::online());
+    if let Some(hz) = time::tsc_frequency() {
+        println!("tsc MHz         : {}.{:03}", hz / 1_000_000, hz % 1_000_000 / 1000);
+    }
+    if let Some(frequency) = cpu::effective_frequency(time::Duration::from_millis(100)) {
+        let hz = frequency.effective_hz;
+        println!("effective MHz   : {}.{:03}", hz / 1_000_000, hz % 1_000_000 / 1000);
+        println!("busy            : {}%", frequency.busy_percent);
+    }
+    if let Some(thermal) = cpu::thermal() {
+        println!("core temp       : {} C (tjmax {} C)", thermal.core_celsius, thermal.tj_max);
+        if let Some(celsius) = thermal.package_celsius {
+            println!("package temp    : {} C", celsius);
+        }
+        println!("throttling      : {}{}", if thermal.throttling { "yes" } else { "no" },
+            if thermal.throttled { " (has throttled)" } else { "" });
+    }
+    print!("flags           :");
+    let features = [
+        (Feature::Tsc, "tsc"),
+        (Feature::InvariantTsc, "constant_tsc"),
+        (Feature::Rdrand, "rdrand"),
+        (Feature::NoExecute, "nx"),
+        (Feature::Apic, "apic"),
+        (Feature::Smep, "smep"),
+        (Feature::Smap, "smap"),
+        (Feature::Umip, "umip"),
+        (Feature::AperfMperf, "aperfmperf"),
+        (Feature::DigitalThermalSensor, "dts"),
+        (Feature::PackageThermal, "pts"),
+        (Feature::Hypervisor, "hypervisor"),
+    ];
+    for (feature, name) in features.iter() {
+        if cpu::has_feature(*feature) {
+            print!(" {}", name);
+        }
+    }
+    println!();
+    SUCCESS
+}
+
+fn cmd_meminfo(_args: &[&str]) -> Status {
+    use crate::memory::{self, FRAME_SIZE};
+
+    let report = memory::with_frame_allocator(|frames| {
+        (frames.usable_frames(), frames.free_frames())
+    });
+    match report {
+        Some((usable, free)) => {
+            let kib = |frames: usize| frames as u64 * FRAME_SIZE / 1024;
+            println!("usable {:>8} KiB", kib(usable));
+            println!("free   {:>8} KiB", kib(free));
+            println!("used   {:>8} KiB", kib(usable - free));
+            let nodes = memory::with_frame_allocator(|frames| frames.topology().nodes()).unwrap_or(1);
+            if nodes > 1 {
+                for node in 0..nodes {
+                    let free = memory::with_frame_allocator(|frames| frames.free_frames_on(node)).unwrap_or(0);
+                    println!("node {} {:>8} KiB free", node, kib(free));
+                }
+            }
+            let heap_free = crate::allocator::free_bytes();
+            println!("heap   {:>8} KiB, {} KiB free", crate::allocator::HEAP_SIZE / 1024, heap_free / 1024);
+            SUCCESS
+        }
+        None => {
+            println!("meminfo: no memory map");
+            FAILURE
+        }
+    }
+}
+
+fn cmd_swap(args: &[&str]) -> Status {
+    use crate::memory::swap;
+
+    let result = match args {
+        [] => {
+            match swap::usage() {
+                Some(usage) => {
+                    match (usage.disk, usage.partition) {
+                        (Some(disk), Some(partition)) => print!("ata{} partition {}: ", disk, partition),
+                        (Some(disk), None) => print!("ata{}: ", disk),
+                        _ => {}
+                    }
+                    println!("{} of {} KiB used, {} pages out and {} in since boot",
+                        usage.used * 4, usage.slots * 4, usage.swapped_out, usage.swapped_in);
+                }
+                None => println!("swap is off"),
+            }
+            Ok(())
+        }
+        ["on"] => swap::swapon(),
+        ["off"] => swap::swapoff(),
+        _ => Err("usage: swap [on|off]"),
+    };
+    match result {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("swap: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_ksm(args: &[&str]) -> Status {
+    use crate::memory::ksm;
+
+    let result = match args {
+        [] => {
+            let stats = ksm::stats();
+            println!("ksmd is {}, {} scans and {} merges since boot", if stats.running { "on" } else { "off" },
+                stats.scans, stats.merges);
+            println!("{} pages share {} frames, saving {} KiB", stats.sharing_pages, stats.shared_frames,
+                stats.saved() / 1024);
+            Ok(())
+        }
+        ["on"] => ksm::start(),
+        ["off"] => {
+            ksm::stop();
+            Ok(())
+        }
+        _ => Err("usage: ksm [on|off]"),
+    };
+    match result {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("ksm: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_memlimit(args: &[&str]) -> Status {
+    use crate::user::limit;
+
+    match args {
+        [] => {
+            let usage = limit::usage();
+            match usage.limit {
+                Some(pages) => println!("limit {} KiB", pages * 4),
+                None => println!("no limit"),
+            }
+            println!("{} KiB in use, {} KiB at most by the last program, {} killed since boot", usage.charged * 4,
+                usage.peak * 4, usage.kills);
+        }
+        ["none"] => limit::set_limit(None),
+        [kib] => match kib.parse() {
+            Ok(kib) => limit::set_limit(Some(kib)),
+            Err(_) => {
+                println!("memlimit: bad size {}", kib);
+                return FAILURE;
+            }
+        },
+        _ => {
+            println!("usage: memlimit [<KiB>|none]");
+            return FAILURE;
+        }
+    }
+    SUCCESS
+}
+
+fn cmd_caps(args: &[&str]) -> Status {
+    use crate::user::caps;
+
+    match args {
+        [] => {
+            println!("programs start with {}", caps::names(caps::granted()));
+            print!("capabilities:");
+            for &(name, _) in caps::NAMES.iter() {
+                print!(" {}", name);
+            }
+            println!();
+        }
+        [text] => match caps::parse(text) {
+            Ok(set) => caps::set_granted(set),
+            Err(message) => {
+                println!("caps: {}: {}", message, text);
+                return FAILURE;
+            }
+        },
+        _ => {
+            println!("usage: caps [all|none|<cap>,...]");
+            return FAILURE;
+        }
+    }
+    SUCCESS
+}
+
+fn cmd_rmap(args: &[&str]) -> Status {
+    use crate::kptr;
+    use crate::memory::{paging, rmap};
+    use x86_64::structures::paging::PhysFrame;
+    use x86_64::PhysAddr;
+
+    let addr = match args {
+        [] => {
+            let (tracked, untracked) = rmap::usage();
+            println!("{} mappings tracked, {} there was no room for", tracked, untracked);
+            return SUCCESS;
+        }
+        [addr] => u64::from_str_radix(addr.trim_start_matches("0x"), 16).ok().filter(|&addr| addr < 1 << 52),
+        _ => None,
+    };
+    let addr = match addr {
+        Some(addr) => addr,
+        None => {
+            println!("usage: rmap [physical address, in hex]");
+            return FAILURE;
+        }
+    };
+    let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+    let mappings = rmap::mappings(frame);
+    println!("{:#x}: also at {} in the map of all physical memory", frame.start_address().as_u64(),
+        kptr::Ptr::from(paging::phys_to_virt(frame.start_address())));
+    for mapping in mappings.iter() {
+        match mapping.space {
+            Some(space) => println!("  {:#x} in the address space at {:#x}", mapping.page.start_address().as_u64(),
+                space.start_address().as_u64()),
+            None => println!("  {} in the kernel", kptr::Ptr::from(mapping.page.start_address())),
+        }
+    }
+    SUCCESS
+}
+
+fn cmd_lspci(args: &[&str]) -> Status {
+    use crate::drivers::pci;
+
+    let verbose = match args {
+        [] => false,
+        ["-v"] => true,
+        _ => {
+            println!("usage: lspci [-v]");
+            return FAILURE;
+        }
+    };
+    let mut any = false;
+    for function in pci::functions() {
+        print!("{} {}: ", function.address, function.class_name());
+        match function.names() {
+            (Some(vendor), Some(device)) => print!("{} {}", vendor, device),
+            (Some(vendor), None) => print!("{} device {:04x}", vendor, function.device_id),
+            _ => print!("Device"),
+        }
+        println!(" [{:04x}:{:04x}] (rev {:02x})", function.vendor_id, function.device_id, function.revision);
+        if verbose {
+            if let Some(irq) = function.irq {
+                println!("        IRQ {}", irq);
+            }
+            for bar in function.bars.iter().flatten() {
+                println!("        {}", bar);
+            }
+        }
+        any = true;
+    }
+    if !any {
+        println!("lspci: no PCI bus");
+    }
+    SUCCESS
+}
+
+fn cmd_lsblk(_args: &[&str]) -> Status {
+    use crate::drivers::ata::{self, SECTOR_SIZE};
+
+    let mut any = false;
+    ata::for_each_disk(|index, disk| {
+        let mib = disk.sectors * SECTOR_SIZE as u64 / (1024 * 1024);
+        println!("ata{}  {:<9} {:<6} {:>8} MiB  {}  {}", index, disk.bus, disk.drive, mib, disk.model(), disk.serial());
+        any = true;
+    });
+    if !any {
+        println!("lsblk: no disks");
+    }
+    SUCCESS
+}
+
+fn cmd_smartctl(args: &[&str]) -> Status {
+    use crate::drivers::ata::{self, Smart, SECTOR_SIZE};
+
+    let disk = match args {
+        [index] => match index.parse().ok().and_then(ata::disk) {
+            Some(disk) => disk,
+            None => {
+                println!("smartctl: no such disk: {}", index);
+                return FAILURE;
+            }
+        },
+        _ => {
+            println!("usage: smartctl <disk>");
+            return FAILURE;
+        }
+    };
+    println!("model:    {}", disk.model());
+    println!("serial:   {}", disk.serial());
+    println!("firmware: {}", disk.firmware());
+    println!("capacity: {} sectors, {} bytes", disk.sectors, disk.sectors * SECTOR_SIZE as u64);
+    match disk.smart() {
+        Smart::Unsupported => println!("SMART:    not supported"),
+        Smart::Disabled => println!("SMART:    supported, turned off"),
+        Smart::Enabled => println!("SMART:    on"),
+    }
+    if disk.smart() != Smart::Enabled {
+        return SUCCESS;
+    }
+    let healthy = match disk.smart_healthy() {
+        Ok(healthy) => healthy,
+        Err(message) => {
+            println!("smartctl: {}", message);
+            return FAILURE;
+        }
+    };
+    println!("health:   {}", if healthy { "PASSED" } else { "FAILED" });
+    match disk.smart_attributes() {
+        Ok(attributes) => {
+            println!("ID  ATTRIBUTE                VALUE WORST THRESH RAW");
+            for attribute in attributes {
+                println!("{:>3} {:<24} {:>5} {:>5} {:>6} {}{}", attribute.id, attribute.name(), attribute.value,
+                    attribute.worst, attribute.threshold, attribute.raw,
+                    if attribute.failing() { "  FAILING" } else { "" });
+            }
+        }
+        Err(message) => println!("smartctl: {}", message),
+    }
+    if healthy { SUCCESS } else { FAILURE }
+}
+
+// "drwxr-xr-x" and so on
+fn mode_string(mode: u32) -> String {
+    let mut text = String::from(if mode & crate::fs::S_IFDIR != 0 { "d" } else { "-" });
+    for shift in [6, 3, 0].iter() {
+        let bits = mode >> shift;
+        text.push(if bits & 4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 2 != 0 { 'w' } else { '-' });
+        text.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    text
+}
+
+fn cmd_ls(args: &[&str]) -> Status {
+    let (long, path) = match args {
+        [] => (false, "/"),
+        ["-l"] => (true, "/"),
+        ["-l", path] => (true, *path),
+        [path] => (false, *path),
+        _ => {
+            println!("usage: ls [-l] [path]");
+            return FAILURE;
+        }
+    };
+    let entries = match crate::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(message) => {
+            println!("ls: {}: {}", path, message);
+            return FAILURE;
+        }
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(message) => {
+                println!("ls: {}: {}", path, message);
+                return FAILURE;
+            }
+        };
+        let name = if entry.is_dir() { alloc::format!("{}/", entry.name()) } else { String::from(entry.name()) };
+        let size = if entry.is_dir() { String::new() } else { alloc::format!("{}", entry.size()) };
+        if !long {
+            println!("{:>10}  {}", size, name);
+            continue;
+        }
+        let metadata = crate::fs::Metadata::from(&entry);
+        // To the minute, in local time
+        let modified = metadata.modified.map_or_else(|| String::from("-"), |time| {
+            alloc::format!("{}", time)[..16].replace('T', " ")
+        });
+        println!("{} {:>10}  {:<16}  {}", mode_string(metadata.mode), size, modified, name);
+    }
+    SUCCESS
+}
+
+// The entries of the directory the word's in, as the word would be
+// finished by each; directories get their slash, so the next Tab goes on
+// into them. Nothing if there's nothing mounted there.
+fn complete_path(word: &str, candidates: &mut dyn FnMut(&str)) {
+    let dir = &word[..word.rfind('/').map_or(0, |slash| slash + 1)];
+    let entries = match crate::fs::read_dir(if dir.is_empty() { "/" } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if entry.name() == "." || entry.name() == ".." {
+            continue;
+        }
+        let slash = if entry.is_dir() { "/" } else { "" };
+        candidates(&alloc::format!("{}{}{}", dir, entry.name(), slash));
+    }
+}
+
+fn cmd_fsck(args: &[&str]) -> Status {
+    let repair = match args {
+        [] => false,
+        ["-r"] => true,
+        _ => {
+            println!("usage: fsck [-r]");
+            return FAILURE;
+        }
+    };
+    let report = match crate::fs::fsck(repair) {
+        Ok(report) => report,
+        Err(message) => {
+            println!("fsck: {}", message);
+            return FAILURE;
+        }
+    };
+    for (problem, repaired) in report.problems.iter() {
+        println!("{}{}", problem, if *repaired { " (repaired)" } else { "" });
+    }
+    println!("{} files, {} directories, {} clusters used, {} free", report.files, report.directories, report.used,
+        report.free);
+    if report.is_clean() { SUCCESS } else { FAILURE }
+}
+
+// A line typed without echoing it, for passphrases
+fn read_secret(prompt: &str) -> String {
+    use crate::keyboard;
+    use pc_keyboard::DecodedKey;
+
+    print!("{}", prompt);
+    let mut secret = String::new();
+    loop {
+        match keyboard::read_key() {
+            DecodedKey::Unicode('\n') => break,
+            DecodedKey::Unicode('\u{8}') => {
+                secret.pop();
+            }
+            DecodedKey::Unicode(character) if !character.is_control() => secret.push(character),
+            _ => {}
+        }
+    }
+    println!();
+    secret
+}
+
+fn cmd_mount(args: &[&str]) -> Status {
+    let (disk, path) = match args {
+        [] => {
+            for (path, mount) in crate::fs::mounts() {
+                print!("disk {}", mount.disk);
+                if let Some(partition) = mount.partition {
+                    print!(" partition {}", partition);
+                }
+                let kind = match (mount.encrypted, mount.raid) {
+                    (_, Some(crate::fs::Level::Stripe)) => "fat32, striped",
+                    (_, Some(crate::fs::Level::Mirror)) => "fat32, mirrored",
+                    (true, None) => "fat32, encrypted",
+                    (false, None) => "fat32",
+                };
+                println!(" on {} ({})", path, kind);
+            }
+            return SUCCESS;
+        }
+        [disk, path] => match disk.parse() {
+            Ok(disk) => (disk, *path),
+            Err(_) => {
+                println!("mount: bad disk number: {}", disk);
+                return FAILURE;
+            }
+        },
+        _ => {
+            println!("usage: mount [<disk> <path>]");
+            return FAILURE;
+        }
+    };
+    match crate::fs::mount_disk(disk, path) {
+        Ok(_) => SUCCESS,
+        Err(message) => {
+            println!("mount: {}: {}", path, message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_umount(args: &[&str]) -> Status {
+    let path = match args {
+        [path] => *path,
+        _ => {
+            println!("usage: umount <path>");
+            return FAILURE;
+        }
+    };
+    match crate::fs::unmount(path) {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("umount: {}: {}", path, message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_cryptmount(args: &[&str]) -> Status {
+    let disk = match args {
+        [disk] => match disk.parse() {
+            Ok(disk) => disk,
+            Err(_) => {
+                println!("cryptmount: bad disk number: {}", disk);
+                return FAILURE;
+            }
+        },
+        _ => {
+            println!("usage: cryptmount <disk>");
+            return FAILURE;
+        }
+    };
+    let passphrase = read_secret("passphrase: ");
+    match crate::fs::mount_encrypted(disk, passphrase.as_bytes()) {
+        Ok(mount) => {
+            match mount.partition {
+                Some(partition) => println!("mounted disk {} partition {}", mount.disk, partition),
+                None => println!("mounted disk {}", mount.disk),
+            }
+            SUCCESS
+        }
+        Err(message) => {
+            println!("cryptmount: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_resync(args: &[&str]) -> Status {
+    let from = match args {
+        [] => 0,
+        [member] => match member.parse() {
+            Ok(member) => member,
+            Err(_) => {
+                println!("resync: bad member number: {}", member);
+                return FAILURE;
+            }
+        },
+        _ => {
+            println!("usage: resync [member]");
+            return FAILURE;
+        }
+    };
+    match crate::fs::resync(from) {
+        Ok(rewritten) => {
+            for (member, &sectors) in rewritten.iter().enumerate().filter(|&(member, _)| member != from) {
+                println!("member {}: {} sectors rewritten", member, sectors);
+            }
+            SUCCESS
+        }
+        Err(message) => {
+            println!("resync: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_strace(args: &[&str]) -> Status {
+    let path = match args {
+        [path] => *path,
+        _ => {
+            println!("usage: strace <program>");
+            return FAILURE;
+        }
+    };
+    crate::user::trace::set(true);
+    let status = super::run_program(path);
+    // In case it never got as far as running
+    crate::user::trace::set(false);
+    status
+}
+
+fn cmd_sync(args: &[&str]) -> Status {
+    if !args.is_empty() {
+        println!("usage: sync");
+        return FAILURE;
+    }
+    match crate::fs::sync() {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("sync: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_cat(args: &[&str]) -> Status {
+    if args.is_empty() {
+        println!("usage: cat path...");
+        return FAILURE;
+    }
+    let mut status = SUCCESS;
+    for path in args {
+        let mut file = match crate::fs::File::open(path) {
+            Ok(file) => file,
+            Err(message) => {
+                println!("cat: {}: {}", path, message);
+                status = FAILURE;
+                continue;
+            }
+        };
+        let mut buf = [0; 512];
+        // Bytes of a character cut off by the end of the last read
+        let mut kept = 0;
+        loop {
+            match file.read(&mut buf[kept..]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let len = kept + n;
+                    kept = crate::console::print_utf8(&buf[..len]);
+                    buf.copy_within(len - kept..len, 0);
+                }
+                Err(message) => {
+                    println!("cat: {}: {}", path, message);
+                    status = FAILURE;
+                    break;
+                }
+            }
+        }
+        if kept > 0 {
+            print!("{}", core::char::REPLACEMENT_CHARACTER);
+        }
+    }
+    status
+}
+
+fn cmd_stat(args: &[&str]) -> Status {
+    if args.is_empty() {
+        println!("usage: stat path...");
+        return FAILURE;
+    }
+    let mut status = SUCCESS;
+    for path in args {
+        let metadata = match crate::fs::stat(path) {
+            Ok(metadata) => metadata,
+            Err(message) => {
+                println!("stat: {}: {}", path, message);
+                status = FAILURE;
+                continue;
+            }
+        };
+        let when = |time: Option<time::SystemTime>| {
+            time.map_or_else(|| String::from("-"), |time| alloc::format!("{}", time))
+        };
+        println!("  File: {}", path);
+        println!("  Size: {:<10}  {}", metadata.size, if metadata.is_dir() { "directory" } else { "regular file" });
+        println!("Access: ({:04o}/{})", metadata.mode & 0o7777, mode_string(metadata.mode));
+        println!("Access: {}", when(metadata.accessed));
+        println!("Modify: {}", when(metadata.modified));
+        println!("Change: {}", when(metadata.created));
+    }
+    status
+}
+
+fn cmd_ksyms(_args: &[&str]) -> Status {
+    for symbol in crate::ksymtab::symbols() {
+        println!("{} v{} {}", kptr::Ptr::from(symbol.address), symbol.version, symbol.name);
+    }
+    SUCCESS
+}
+
+fn cmd_sizeinfo(args: &[&str]) -> Status {
+    let count = match args {
+        [] => 10,
+        [count] => match count.parse() {
+            Ok(count) => count,
+            Err(_) => {
+                println!("sizeinfo: bad count: {}", count);
+                return FAILURE;
+            }
+        },
+        _ => {
+            println!("usage: sizeinfo [count]");
+            return FAILURE;
+        }
+    };
+    let sizes = crate::image::sizes();
+    for (name, size) in [(".text", sizes.text), (".rodata", sizes.rodata), (".data", sizes.data), (".bss", sizes.bss)] {
+        println!("{:<8} {:>8} KiB", name, size / 1024);
+    }
+    println!("{:<8} {:>8} KiB", "total", sizes.total() / 1024);
+    let largest = crate::symbols::largest(count);
+    if largest.is_empty() && count > 0 {
+        println!("no symbol table; see tools/embed-symbols.py");
+    }
+    for (name, size) in largest {
+        println!("{:>8} {}", size, name);
+    }
+    SUCCESS
+}
+
+fn cmd_kptr(args: &[&str]) -> Status {
+    match args {
+        [] => println!("{}", if kptr::hashing() { "hashed" } else { "raw" }),
+        ["hashed"] => kptr::set_hashing(true),
+        ["raw"] => kptr::set_hashing(false),
+        _ => {
+            println!("usage: kptr [hashed|raw]");
+            return FAILURE;
+        }
+    }
+    SUCCESS
+}
+
+fn complete_kptr(_word: &str, candidates: &mut dyn FnMut(&str)) {
+    candidates("hashed");
+    candidates("raw");
+}
+
+fn cmd_dmesg(args: &[&str]) -> Status {
+    let clear = match args {
+        [] => false,
+        ["-c"] => true,
+        _ => {
+            println!("usage: dmesg [-c]");
+            return FAILURE;
+        }
+    };
+    print!("{}", String::from_utf8_lossy(&log::contents()));
+    if clear {
+        log::clear();
+    }
+    SUCCESS
+}
+
+fn cmd_loglevel(args: &[&str]) -> Status {
+    match args {
+        [] => {
+            println!("{}", log::level().name());
+            log::for_each_module_level(|module, level| println!("{} {}", module, level.name()));
+        }
+        [level] => match log::Level::from_name(level) {
+            Some(level) => log::set_level(level),
+            None => {
+                println!("loglevel: unknown level {}", level);
+                return FAILURE;
+            }
+        },
+        [module, level] => {
+            let level = match (*level, log::Level::from_name(level)) {
+                ("default", _) => None,
+                (_, Some(level)) => Some(level),
+                (level, None) => {
+                    println!("loglevel: unknown level {}", level);
+                    return FAILURE;
+                }
+            };
+            if let Err(message) = log::set_module_level(module, level) {
+                println!("loglevel: {}", message);
+                return FAILURE;
+            }
+        }
+        _ => {
+            println!("usage: loglevel [module] <level|default>");
+            return FAILURE;
+        }
+    }
+    SUCCESS
+}
+
+fn complete_loglevel(_word: &str, candidates: &mut dyn FnMut(&str)) {
+    for level in ["error", "warn", "info", "debug", "trace"].iter() {
+        candidates(level);
+    }
+}
+
+fn cmd_keys(_args: &[&str]) -> Status {
+    use crate::task::{keyboard, Executor, Task};
+
+    let mut executor = Executor::new();
+    if let Err(message) = executor.spawn(Task::new(keyboard::print_keypresses())) {
+        println!("keys: {}", message);
+        return FAILURE;
+    }
+    executor.run();
+    SUCCESS
+}
+
+fn cmd_events(_args: &[&str]) -> Status {
+    use crate::task::{Executor, Task};
+
+    let mut executor = Executor::new();
+    if let Err(message) = executor.spawn(Task::new(crate::events::print_events())) {
+        println!("events: {}", message);
+        return FAILURE;
+    }
+    executor.run();
+    SUCCESS
+}
+
+fn cmd_replay(args: &[&str]) -> Status {
+    use crate::replay;
+
+    let result = match args {
+        ["record"] => replay::record().map(|()| println!("replay: recording")),
+        ["save"] => {
+            let (sent, dropped) = replay::save();
+            println!("replay: sent {} events over serial", sent);
+            if dropped > 0 {
+                println!("replay: {} more didn't fit", dropped);
+            }
+            Ok(())
+        }
+        ["play", path] => replay::play_file(path),
+        [] => {
+            let (recording, playing) = replay::status();
+            println!("replay: {}", match (recording, playing) {
+                (true, _) => "recording",
+                (false, true) => "playing",
+                (false, false) => "idle",
+            });
+            Ok(())
+        }
+        _ => {
+            println!("usage: replay [record | save | play path]");
+            return FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => SUCCESS,
+        Err(message) => {
+            println!("replay: {}", message);
+            FAILURE
+        }
+    }
+}
+
+fn cmd_screenshot(_args: &[&str]) -> Status {
+    if crate::cmdline::headless() {
+        println!("screenshot: nothing is drawn in headless mode");
+        return FAILURE;
+    }
+    let (width, height) = crate::screenshot::send();
+    println!("screenshot: sent {}x{} over serial", width, height);
+    SUCCESS
+}
+
+fn cmd_snake(_args: &[&str]) -> Status {
+    if crate::cmdline::headless() {
+        println!("snake: needs the VGA console");
+        return FAILURE;
+    }
+    let score = crate::snake::play();
+    println!("Game over! Score: {}", score);
+    SUCCESS
+}
+
+fn cmd_panic(args: &[&str]) -> Status {
+    if args.is_empty() {
+        panic!("panic requested from the shell");
+    }
+    panic!("{}", args.join(" "));
+}
+
+fn cmd_reboot(_args: &[&str]) -> Status {
+    crate::power::reboot();
+}
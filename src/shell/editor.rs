@@ -0,0 +1,315 @@
+use pc_keyboard::{DecodedKey, KeyCode};
+use x86_64::instructions::interrupts;
+use crate::{clipboard, keyboard, print, println, vga_buffer};
+
+/// Longest line the editor accepts; keeps prompt + line on a single row
+pub const LINE_MAX: usize = 64;
+const HISTORY_LEN: usize = 32;
+
+// Control characters as delivered with HandleControl::MapLettersToUnicode
+const CTRL_A: char = '\u{1}';
+const CTRL_E: char = '\u{5}';
+const CTRL_K: char = '\u{b}';
+const CTRL_U: char = '\u{15}';
+const CTRL_V: char = '\u{16}';
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+
+/// Produces completion candidates given the line up to the cursor
+pub type Completer<'a> = &'a dyn Fn(&str, &mut dyn FnMut(&str));
+
+/// Ring of previously entered lines, newest last
+pub struct History {
+    entries: [[u8; LINE_MAX]; HISTORY_LEN],
+    lens: [usize; HISTORY_LEN],
+    next: usize,
+    count: usize,
+}
+
+impl History {
+    pub const fn new() -> History {
+        History {
+            entries: [[0; LINE_MAX]; HISTORY_LEN],
+            lens: [0; HISTORY_LEN],
+            next: 0,
+            count: 0,
+        }
+    }
+
+    /// Remember `line`, skipping blanks and repeats of the previous entry
+    pub fn push(&mut self, line: &[u8]) {
+        if line.iter().all(|byte| *byte == b' ') || self.get(0) == Some(line) {
+            return;
+        }
+        let len = line.len().min(LINE_MAX);
+        self.entries[self.next][..len].copy_from_slice(&line[..len]);
+        self.lens[self.next] = len;
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.count = (self.count + 1).min(HISTORY_LEN);
+    }
+
+    /// The entry `age` steps back, where 0 is the most recent
+    pub fn get(&self, age: usize) -> Option<&[u8]> {
+        if age >= self.count {
+            return None;
+        }
+        let index = (self.next + HISTORY_LEN - 1 - age) % HISTORY_LEN;
+        Some(&self.entries[index][..self.lens[index]])
+    }
+}
+
+/// Single-line editor: cursor movement, Home/End, backspace/delete,
+/// Ctrl+K/Ctrl+U kill, up/down through the history, and Tab completion.
+/// Text dragged over with the mouse is copied; Ctrl+V or a middle click
+/// pastes it.
+pub struct LineEditor {
+    buffer: [u8; LINE_MAX],
+    len: usize,
+    cursor: usize,
+    // How much of the line is on screen, so a redraw can blank the leftovers
+    drawn_len: usize,
+    // Which history entry is loaded, if we're browsing it
+    history_age: Option<usize>,
+    #[cfg(feature = "mouse")]
+    selection: crate::selection::Selection,
+}
+
+impl LineEditor {
+    pub const fn new() -> LineEditor {
+        LineEditor {
+            buffer: [0; LINE_MAX],
+            len: 0,
+            cursor: 0,
+            drawn_len: 0,
+            history_age: None,
+            #[cfg(feature = "mouse")]
+            selection: crate::selection::Selection::new(),
+        }
+    }
+
+    /// Read and edit a line until Enter; the result is also added to `history`
+    pub fn read_line(&mut self, prompt: &str, history: &mut History, completer: Completer) -> &str {
+        self.len = 0;
+        self.cursor = 0;
+        self.drawn_len = 0;
+        self.history_age = None;
+        print!("{}", prompt);
+
+        loop {
+            match self.next_key() {
+                DecodedKey::Unicode('\n') => break,
+                DecodedKey::Unicode('\t') => self.complete(prompt, completer),
+                DecodedKey::Unicode(BACKSPACE) => self.backspace(),
+                DecodedKey::Unicode(DELETE) | DecodedKey::RawKey(KeyCode::Delete) => self.delete(),
+                DecodedKey::Unicode(CTRL_A) | DecodedKey::RawKey(KeyCode::Home) => self.cursor = 0,
+                DecodedKey::Unicode(CTRL_E) | DecodedKey::RawKey(KeyCode::End) => self.cursor = self.len,
+                DecodedKey::Unicode(CTRL_K) => self.len = self.cursor,
+                DecodedKey::Unicode(CTRL_U) => self.kill_to_start(),
+                DecodedKey::Unicode(CTRL_V) => self.paste(),
+                DecodedKey::RawKey(KeyCode::ArrowLeft) => self.cursor = self.cursor.saturating_sub(1),
+                DecodedKey::RawKey(KeyCode::ArrowRight) => self.cursor = (self.cursor + 1).min(self.len),
+                DecodedKey::RawKey(KeyCode::ArrowUp) => self.history_older(history),
+                DecodedKey::RawKey(KeyCode::ArrowDown) => self.history_newer(history),
+                DecodedKey::Unicode(character) if character.is_ascii() && !character.is_ascii_control() => {
+                    self.insert(character as u8)
+                }
+                _ => continue,
+            }
+            self.redraw(prompt);
+        }
+
+        println!();
+        history.push(&self.buffer[..self.len]);
+        // Only printable ASCII ever goes into the buffer
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+
+    // Like keyboard::read_key, but also watching the mouse for selections.
+    // A middle click comes back as Ctrl+V.
+    fn next_key(&mut self) -> DecodedKey {
+        loop {
+            crate::timer::run_expired();
+            if let Some(key) = keyboard::try_read_key() {
+                return key;
+            }
+            #[cfg(feature = "mouse")]
+            while let Some(event) = crate::mouse::try_read_event() {
+                if self.selection.feed(event) {
+                    return DecodedKey::Unicode(CTRL_V);
+                }
+            }
+
+            // Check again with interrupts off so input can't sneak in before the hlt
+            interrupts::disable();
+            #[cfg(feature = "mouse")]
+            let pending = keyboard::input_pending() || crate::mouse::event_pending();
+            #[cfg(not(feature = "mouse"))]
+            let pending = keyboard::input_pending();
+            if pending {
+                interrupts::enable();
+            } else {
+                crate::timer::idle();
+            }
+        }
+    }
+
+    // The clipboard's first line, as far as it's printable and fits
+    fn paste(&mut self) {
+        clipboard::with_contents(|text| {
+            for &byte in text.iter().take_while(|&&byte| byte != b'\n') {
+                if (0x20..=0x7e).contains(&byte) {
+                    self.insert(byte);
+                }
+            }
+        });
+    }
+
+    fn insert(&mut self, byte: u8) {
+        if self.len == LINE_MAX {
+            return;
+        }
+        self.buffer.copy_within(self.cursor..self.len, self.cursor + 1);
+        self.buffer[self.cursor] = byte;
+        self.len += 1;
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.delete();
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.len {
+            self.buffer.copy_within(self.cursor + 1..self.len, self.cursor);
+            self.len -= 1;
+        }
+    }
+
+    fn kill_to_start(&mut self) {
+        self.buffer.copy_within(self.cursor..self.len, 0);
+        self.len -= self.cursor;
+        self.cursor = 0;
+    }
+
+    // Extend the word under the cursor as far as all candidates agree; if
+    // that doesn't add anything and there's more than one, list them
+    fn complete(&mut self, prompt: &str, completer: Completer) {
+        let mut before = [0u8; LINE_MAX];
+        before[..self.cursor].copy_from_slice(&self.buffer[..self.cursor]);
+        let before = core::str::from_utf8(&before[..self.cursor]).unwrap_or("");
+        let word_len = before.len() - before.rfind(' ').map_or(0, |space| space + 1);
+
+        let mut common = [0u8; LINE_MAX];
+        let mut common_len = 0;
+        let mut matches = 0;
+        completer(before, &mut |candidate: &str| {
+            let candidate = candidate.as_bytes();
+            if matches == 0 {
+                common_len = candidate.len().min(LINE_MAX);
+                common[..common_len].copy_from_slice(&candidate[..common_len]);
+            } else {
+                common_len = common[..common_len]
+                    .iter()
+                    .zip(candidate)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+            }
+            matches += 1;
+        });
+
+        if common_len > word_len {
+            for byte in &common[word_len..common_len] {
+                self.insert(*byte);
+            }
+            // Not after a directory, whose entries come next
+            if matches == 1 && common[common_len - 1] != b'/' {
+                self.insert(b' ');
+            }
+        } else if matches > 1 {
+            println!();
+            completer(before, &mut |candidate: &str| print!("{}  ", candidate));
+            println!();
+            print!("{}", prompt);
+            self.drawn_len = 0;
+        }
+    }
+
+    fn load(&mut self, line: &[u8]) {
+        self.buffer[..line.len()].copy_from_slice(line);
+        self.len = line.len();
+        self.cursor = self.len;
+    }
+
+    fn history_older(&mut self, history: &History) {
+        let age = self.history_age.map_or(0, |age| age + 1);
+        if let Some(line) = history.get(age) {
+            self.load(line);
+            self.history_age = Some(age);
+        }
+    }
+
+    fn history_newer(&mut self, history: &History) {
+        match self.history_age {
+            Some(0) | None => {
+                self.history_age = None;
+                self.load(&[]);
+            }
+            Some(age) => {
+                if let Some(line) = history.get(age - 1) {
+                    self.load(line);
+                    self.history_age = Some(age - 1);
+                }
+            }
+        }
+    }
+
+    // The whole line fits on one row, so just repaint it from the prompt on
+    fn redraw(&mut self, prompt: &str) {
+        vga_buffer::set_column(0);
+        let line = core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("");
+        print!("{}{}", prompt, line);
+        for _ in self.len..self.drawn_len {
+            print!(" ");
+        }
+        self.drawn_len = self.len;
+        vga_buffer::set_column(prompt.len() + self.cursor);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_history_skips_repeats() {
+    let mut history = History::new();
+    history.push(b"help");
+    history.push(b"help");
+    history.push(b"echo hi");
+    assert_eq!(history.get(0), Some(&b"echo hi"[..]));
+    assert_eq!(history.get(1), Some(&b"help"[..]));
+    assert_eq!(history.get(2), None);
+}
+
+#[test_case]
+fn test_history_wraps() {
+    let mut history = History::new();
+    for i in 0..HISTORY_LEN + 3 {
+        history.push(&[b'a' + (i % 26) as u8, b'0' + (i / 26) as u8]);
+    }
+    let newest = HISTORY_LEN + 2;
+    assert_eq!(history.get(0), Some(&[b'a' + (newest % 26) as u8, b'0' + (newest / 26) as u8][..]));
+    assert!(history.get(HISTORY_LEN - 1).is_some());
+    assert!(history.get(HISTORY_LEN).is_none());
+}
+
+#[test_case]
+fn test_paste_stops_at_newline() {
+    let mut editor = LineEditor::new();
+    editor.insert(b'>');
+    clipboard::copy(b"echo hi\nsecond line");
+    editor.paste();
+    assert_eq!(&editor.buffer[..editor.len], &b">echo hi"[..]);
+    assert_eq!(editor.cursor, editor.len);
+}
@@ -0,0 +1,234 @@
+use core::sync::atomic::{AtomicI32, Ordering};
+use spin::Mutex;
+use super::Status;
+
+const MAX_VARS: usize = 32;
+const NAME_MAX: usize = 32;
+const VALUE_MAX: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Var {
+    name: [u8; NAME_MAX],
+    name_len: usize,
+    value: [u8; VALUE_MAX],
+    value_len: usize,
+    exported: bool,
+}
+
+impl Var {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    fn value(&self) -> &str {
+        core::str::from_utf8(&self.value[..self.value_len]).unwrap_or("")
+    }
+}
+
+/// The shell's variables. Exported ones are what a spawned program would
+/// get as its envp, once there are programs to spawn.
+struct Environment {
+    vars: [Option<Var>; MAX_VARS],
+}
+
+impl Environment {
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.vars
+            .iter()
+            .position(|slot| slot.as_ref().map_or(false, |var| var.name() == name))
+    }
+}
+
+static ENVIRONMENT: Mutex<Environment> = Mutex::new(Environment {
+    vars: [None; MAX_VARS],
+});
+static LAST_STATUS: AtomicI32 = AtomicI32::new(0);
+
+/// Valid names are non-empty runs of ASCII letters, digits, and underscores
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= NAME_MAX && name.bytes().all(is_name_byte)
+}
+
+fn is_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+/// Set (or create) a variable, keeping its exported flag if it already exists
+pub fn set(name: &str, value: &str) -> Result<(), &'static str> {
+    if !is_valid_name(name) {
+        return Err("invalid variable name");
+    }
+    if value.len() > VALUE_MAX {
+        return Err("value too long");
+    }
+
+    let mut environment = ENVIRONMENT.lock();
+    let index = match environment.index_of(name) {
+        Some(index) => index,
+        None => {
+            let index = environment
+                .vars
+                .iter()
+                .position(|slot| slot.is_none())
+                .ok_or("too many variables")?;
+            let mut var = Var {
+                name: [0; NAME_MAX],
+                name_len: name.len(),
+                value: [0; VALUE_MAX],
+                value_len: 0,
+                exported: false,
+            };
+            var.name[..name.len()].copy_from_slice(name.as_bytes());
+            environment.vars[index] = Some(var);
+            index
+        }
+    };
+    if let Some(var) = environment.vars[index].as_mut() {
+        var.value[..value.len()].copy_from_slice(value.as_bytes());
+        var.value_len = value.len();
+    }
+    Ok(())
+}
+
+/// Mark a variable for export, creating it empty if it doesn't exist
+pub fn export(name: &str) -> Result<(), &'static str> {
+    if with_var(name, |_| ()).is_none() {
+        set(name, "")?;
+    }
+    let mut environment = ENVIRONMENT.lock();
+    if let Some(index) = environment.index_of(name) {
+        if let Some(var) = environment.vars[index].as_mut() {
+            var.exported = true;
+        }
+    }
+    Ok(())
+}
+
+pub fn unset(name: &str) {
+    let mut environment = ENVIRONMENT.lock();
+    for slot in environment.vars.iter_mut() {
+        if slot.as_ref().map_or(false, |var| var.name() == name) {
+            *slot = None;
+        }
+    }
+}
+
+/// Call `f` with a variable's value, if it's set
+pub fn with_var<R>(name: &str, f: impl FnOnce(&str) -> R) -> Option<R> {
+    let environment = ENVIRONMENT.lock();
+    environment
+        .index_of(name)
+        .and_then(|index| environment.vars[index].as_ref())
+        .map(|var| f(var.value()))
+}
+
+/// Call `f` with every variable as (name, value, exported)
+pub fn for_each(mut f: impl FnMut(&str, &str, bool)) {
+    for var in ENVIRONMENT.lock().vars.iter().filter_map(|slot| slot.as_ref()) {
+        f(var.name(), var.value(), var.exported);
+    }
+}
+
+/// Remember a command's status for `$?`
+pub fn set_last_status(status: Status) {
+    LAST_STATUS.store(status, Ordering::Relaxed);
+}
+
+struct Output<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Output<'_> {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        let end = self.len + bytes.len();
+        if end > self.buffer.len() {
+            return Err("line too long after expanding variables");
+        }
+        self.buffer[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Expand `$NAME`, `${NAME}`, and `$?` in `input` into `buffer`, returning
+/// the expanded length. Unset variables expand to nothing.
+pub fn expand(input: &str, buffer: &mut [u8]) -> Result<usize, &'static str> {
+    let bytes = input.as_bytes();
+    let mut output = Output { buffer, len: 0 };
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'$' {
+            output.push(&bytes[i..i + 1])?;
+            i += 1;
+            continue;
+        }
+
+        let rest = &input[i + 1..];
+        if rest.starts_with('?') {
+            let mut digits = [0u8; 12];
+            let status = format_status(LAST_STATUS.load(Ordering::Relaxed), &mut digits);
+            output.push(status)?;
+            i += 2;
+        } else if rest.starts_with('{') {
+            let close = rest.find('}').ok_or("missing '}' in variable reference")?;
+            let name = &rest[1..close];
+            expand_var(name, &mut output)?;
+            i += close + 2;
+        } else {
+            let name_len = rest.bytes().take_while(|byte| is_name_byte(*byte)).count();
+            if name_len == 0 {
+                // A lone '$' is just a dollar sign
+                output.push(b"$")?;
+                i += 1;
+            } else {
+                expand_var(&rest[..name_len], &mut output)?;
+                i += name_len + 1;
+            }
+        }
+    }
+    Ok(output.len)
+}
+
+fn expand_var(name: &str, output: &mut Output) -> Result<(), &'static str> {
+    with_var(name, |value| output.push(value.as_bytes())).unwrap_or(Ok(()))
+}
+
+fn format_status(status: Status, digits: &mut [u8; 12]) -> &[u8] {
+    let mut value = (status as i64).abs();
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    if status < 0 {
+        start -= 1;
+        digits[start] = b'-';
+    }
+    &digits[start..]
+}
+
+/// TESTS
+
+#[test_case]
+fn test_expand_variables() {
+    set("TEST_GREETING", "hello").unwrap();
+    let mut buffer = [0u8; 64];
+    let len = expand("echo $TEST_GREETING ${TEST_GREETING}! $ $TEST_UNSET.", &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"echo hello hello! $ .");
+    unset("TEST_GREETING");
+}
+
+#[test_case]
+fn test_expand_last_status() {
+    set_last_status(127);
+    let mut buffer = [0u8; 16];
+    let len = expand("$?", &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"127");
+    set_last_status(0);
+}
@@ -0,0 +1,250 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::user::Exit;
+use crate::{print, println};
+use builtins::BUILTINS;
+use editor::{History, LineEditor};
+
+mod builtins;
+pub mod editor;
+pub mod env;
+pub mod script;
+
+const PROMPT: &str = "heorot> ";
+const MAX_ARGS: usize = 16;
+const MAX_COMMANDS: usize = 96;
+const EXPANDED_MAX: usize = 256;
+
+/// Exit status a command reports back; 0 means success, like in sh
+pub type Status = i32;
+
+pub const SUCCESS: Status = 0;
+pub const FAILURE: Status = 1;
+const NOT_FOUND: Status = 127;
+
+/// A shell command: its name, a one-line description, and what to run.
+/// `run` gets the arguments after the command name. `complete`, if set,
+/// offers candidates for an argument, given what's been typed of it; the
+/// shell filters them by prefix.
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub run: fn(&[&str]) -> Status,
+    pub complete: Option<fn(&str, &mut dyn FnMut(&str))>,
+}
+
+struct CommandTable {
+    commands: [Option<Command>; MAX_COMMANDS],
+}
+
+impl CommandTable {
+    fn with_builtins() -> CommandTable {
+        let mut table = CommandTable {
+            commands: [None; MAX_COMMANDS],
+        };
+        for command in BUILTINS {
+            table.insert(*command).expect("too many builtin shell commands");
+        }
+        table
+    }
+
+    fn insert(&mut self, command: Command) -> Result<(), &'static str> {
+        if self.get(command.name).is_some() {
+            return Err("a command with that name is already registered");
+        }
+        let slot = self
+            .commands
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("shell command table is full")?;
+        *slot = Some(command);
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Option<Command> {
+        self.iter().find(|command| command.name == name)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = Command> + '_ {
+        self.commands.iter().filter_map(|slot| *slot)
+    }
+}
+
+lazy_static! {
+    static ref COMMANDS: Mutex<CommandTable> = Mutex::new(CommandTable::with_builtins());
+}
+
+/// Add a command to the shell's table, e.g. from a subsystem's init
+pub fn register(command: Command) -> Result<(), &'static str> {
+    COMMANDS.lock().insert(command)
+}
+
+// Copy the command out so the table isn't locked while it runs
+fn lookup(name: &str) -> Option<Command> {
+    COMMANDS.lock().get(name)
+}
+
+/// Expand variables in a single command, split it into words, and run it,
+/// returning its status
+pub fn execute(line: &str) -> Status {
+    let mut expanded = [0u8; EXPANDED_MAX];
+    let line = match env::expand(line, &mut expanded) {
+        // Expansion only ever copies whole UTF-8 strings
+        Ok(len) => core::str::from_utf8(&expanded[..len]).unwrap_or(""),
+        Err(message) => {
+            println!("{}", message);
+            return FAILURE;
+        }
+    };
+
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return SUCCESS,
+    };
+
+    let mut args = [""; MAX_ARGS];
+    let mut argc = 0;
+    for word in words {
+        if argc == MAX_ARGS {
+            println!("{}: too many arguments (max {})", name, MAX_ARGS);
+            return FAILURE;
+        }
+        args[argc] = word;
+        argc += 1;
+    }
+
+    match lookup(name) {
+        Some(command) => (command.run)(&args[..argc]),
+        None if name.contains('/') => run_program(name, &args[..argc]),
+        None => {
+            println!("{}: command not found", name);
+            NOT_FOUND
+        }
+    }
+}
+
+// A path names a program on disk. There's no working directory, so
+// "./hello" is "/hello". It gets the path as typed and `args` as its argv,
+// and the exported variables as its environment.
+fn run_program(path: &str, args: &[&str]) -> Status {
+    let mut argv = Vec::with_capacity(args.len() + 1);
+    argv.push(path);
+    argv.extend_from_slice(args);
+    let mut exported = Vec::new();
+    env::for_each(|name, value, export| {
+        if export {
+            exported.push(format!("{}={}", name, value));
+        }
+    });
+    let envp: Vec<&str> = exported.iter().map(String::as_str).collect();
+    match crate::elf::exec_file(path.trim_start_matches("./"), &argv, &envp) {
+        Ok(Exit::Exited(code)) => code as Status,
+        Ok(Exit::Faulted { vector, instruction_pointer, .. }) => {
+            println!("{}: killed by exception {} at {:#x}", path, vector, instruction_pointer.as_u64());
+            FAILURE
+        }
+        Ok(Exit::OutOfMemory) => {
+            println!("{}: killed for going over its memory limit", path);
+            FAILURE
+        }
+        Ok(Exit::Signaled(signal)) => {
+            println!("{}: killed by signal {}", path, signal);
+            FAILURE
+        }
+        Err(message) => {
+            println!("{}: {}", path, message);
+            NOT_FOUND
+        }
+    }
+}
+
+/// Tab-completion callback for the line editor: `line` is everything before
+/// the cursor, and each candidate for the word being typed goes to `candidates`
+pub fn complete_line(line: &str, candidates: &mut dyn FnMut(&str)) {
+    let line = line.trim_start();
+    let word = line.rsplit(' ').next().unwrap_or("");
+    let mut matching = |candidate: &str| {
+        if candidate.starts_with(word) {
+            candidates(candidate);
+        }
+    };
+
+    match line.split(' ').next() {
+        // Still typing the command name itself
+        Some(name) if name.len() == line.len() => {
+            for command in COMMANDS.lock().iter() {
+                matching(command.name);
+            }
+        }
+        Some(name) => {
+            if let Some(complete) = lookup(name).and_then(|command| command.complete) {
+                complete(word, &mut matching);
+            }
+        }
+        None => {}
+    }
+}
+
+/// The shell's main loop: run /etc/rc, then prompt, read a line, run it, repeat
+pub fn run() -> ! {
+    script::run(&script::rc());
+
+    let mut editor = LineEditor::new();
+    let mut history = History::new();
+    println!("Type `help` for a list of commands.");
+    loop {
+        let line = editor.read_line(PROMPT, &mut history, &complete_line);
+        script::run_line(line);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_register_rejects_duplicates() {
+    fn noop(_args: &[&str]) -> Status {
+        SUCCESS
+    }
+    let command = Command {
+        name: "test-duplicate",
+        help: "",
+        run: noop,
+        complete: None,
+    };
+    assert!(register(command).is_ok());
+    assert!(register(command).is_err());
+}
+
+#[test_case]
+fn test_execute_output() {
+    use crate::console::capture;
+
+    let (status, output) = capture(|| execute("echo  hi   there"));
+    assert_eq!((status, output.as_str()), (SUCCESS, "hi there\n"));
+    let (status, output) = capture(|| execute("no-such-command"));
+    assert_eq!((status, output.as_str()), (NOT_FOUND, "no-such-command: command not found\n"));
+}
+
+#[test_case]
+fn test_complete_command_names() {
+    let mut matches = 0;
+    let mut found_help = false;
+    complete_line("he", &mut |candidate: &str| {
+        matches += 1;
+        found_help |= candidate == "help";
+    });
+    assert!(found_help);
+    assert!(matches >= 1);
+}
+
+#[test_case]
+fn test_complete_paths() {
+    let mut matches = 0;
+    complete_line("ls /no/such/dir/", &mut |_: &str| matches += 1);
+    assert_eq!(matches, 0);
+}
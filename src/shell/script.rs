@@ -0,0 +1,135 @@
+use alloc::string::String;
+use alloc::vec;
+use crate::fs::{self, File};
+use super::{env, execute, Status, SUCCESS};
+
+/// Where the boot script is read from, on the mounted filesystem
+pub const RC_PATH: &str = "/etc/rc";
+/// The boot script built into the image from `etc/rc` in the source tree,
+/// for when there's no filesystem, or it has no /etc/rc
+pub const BUILTIN_RC: &str = include_str!("../../etc/rc");
+// Far bigger than a boot script needs to be
+const MAX_RC: usize = 64 * 1024;
+
+/// The boot script: /etc/rc from the filesystem if it's there, and if not,
+/// or it can't be read, the built-in one
+pub fn rc() -> String {
+    load(RC_PATH)
+}
+
+fn load(path: &str) -> String {
+    match read(path) {
+        Ok(script) => script,
+        Err(message) => {
+            // Not having one is normal, and so is having no filesystem
+            if ![fs::NOT_FOUND, fs::NOT_A_DIRECTORY, fs::NOT_MOUNTED].contains(&message) {
+                crate::log::warn!("rc: {}: {}; running the built-in one", path, message);
+            }
+            BUILTIN_RC.into()
+        }
+    }
+}
+
+fn read(path: &str) -> Result<String, &'static str> {
+    let mut file = File::open(path)?;
+    let size = file.size() as usize;
+    if size > MAX_RC {
+        return Err("too big for a boot script");
+    }
+    let mut script = vec![0; size];
+    let mut len = 0;
+    while len < size {
+        match file.read(&mut script[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    script.truncate(len);
+    String::from_utf8(script).map_err(|_| "not UTF-8")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Connector {
+    And,
+    Or,
+}
+
+// Split off the first command of `line` along with the `&&`/`||` after it
+fn split_first(line: &str) -> (&str, Option<(Connector, &str)>) {
+    let and = line.find("&&").map(|index| (index, Connector::And));
+    let or = line.find("||").map(|index| (index, Connector::Or));
+    let first = match (and, or) {
+        (Some(a), Some(o)) => Some(if a.0 < o.0 { a } else { o }),
+        (a, o) => a.or(o),
+    };
+
+    match first {
+        Some((index, connector)) => (&line[..index], Some((connector, &line[index + 2..]))),
+        None => (line, None),
+    }
+}
+
+/// Run one line: commands joined by `&&` and `||` are evaluated left to
+/// right like in sh, and the status of the last one that ran is returned
+pub fn run_line(line: &str) -> Status {
+    let mut status = SUCCESS;
+    let mut should_run = true;
+    let mut rest = line;
+
+    loop {
+        let (command, next) = split_first(rest);
+        if should_run {
+            status = execute(command);
+            env::set_last_status(status);
+        }
+        match next {
+            Some((connector, remainder)) => {
+                should_run = match connector {
+                    Connector::And => status == SUCCESS,
+                    Connector::Or => status != SUCCESS,
+                };
+                rest = remainder;
+            }
+            None => return status,
+        }
+    }
+}
+
+/// Run a script line by line; `#` starts a comment that runs to the end of
+/// the line. Returns the status of the last command.
+pub fn run(source: &str) -> Status {
+    let mut status = SUCCESS;
+    for line in source.lines() {
+        let line = match line.find('#') {
+            Some(index) => &line[..index],
+            None => line,
+        };
+        if !line.trim().is_empty() {
+            status = run_line(line);
+        }
+    }
+    status
+}
+
+/// TESTS
+
+#[test_case]
+fn test_and_or_short_circuit() {
+    assert_eq!(run_line("true && false"), 1);
+    assert_eq!(run_line("false && true"), 1);
+    assert_eq!(run_line("false || true"), 0);
+    assert_eq!(run_line("true || false"), 0);
+    assert_eq!(run_line("false && false || true"), 0);
+}
+
+#[test_case]
+fn test_rc_falls_back_to_the_built_in_one() {
+    assert_eq!(load("/no/such/rc"), BUILTIN_RC);
+    assert!(BUILTIN_RC.contains("echo Welcome"));
+}
+
+#[test_case]
+fn test_comments_and_blank_lines() {
+    assert_eq!(run("# nothing to see here\n\n   \ntrue # trailing comment\n"), 0);
+    assert_eq!(run("true\nfalse\n"), 1);
+}
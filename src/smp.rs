@@ -0,0 +1,512 @@
+//! Starting the other processors the MADT lists. Each is sent INIT and
+//! STARTUP IPIs, which have it run the trampoline below in real mode from a
+//! page under 1 MiB (memory::low_frame). That goes straight to long mode on
+//! the kernel's page tables, with the trampoline's page mapped where it is,
+//! and on to `ap_main`, which gives the CPU a GDT and TSS of its own, loads
+//! the IDT, and turns its local APIC on. Then it halts with interrupts on:
+//! no IRQs are routed to it, and the scheduler only runs on the boot CPU,
+//! so it only wakes for IPIs (heorot::ipi). They're started one at a time,
+//! each on its own stack.
+//!
+//! An AP can be parked, and resumed later. It's asked to through its
+//! mailbox, and parks from its idle loop: it stops taking IPIs, calls and
+//! TLB flushes pass it by, and it halts with interrupts off until an NMI
+//! from `resume` finds it's wanted back, when it empties its TLB of what it
+//! missed. There's no run queue to drain first: threads only ever run on
+//! the boot CPU, which is why that one can't be parked.
+//!
+//! Every CPU's GS base points at its `PerCpu`.
+
+use alloc::boxed::Box;
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use x86_64::instructions::{hlt, tlb};
+use x86_64::registers::control::{Cr3, Cr4, Cr4Flags};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+use crate::apic::{self, Madt};
+use crate::memory::{self, paging, FRAME_SIZE};
+use crate::time::{self, Duration, Instant};
+use crate::{cpu, gdt, interrupts, ipi, log, msr};
+
+/// The most CPUs that are started, the boot one included
+pub const MAX_CPUS: usize = 16;
+
+// Each CPU's stacks are in a slot of their own from here: a guard page,
+// the double fault stack, another guard page, then the kernel stack
+const STACKS_START: u64 = 0x_6666_0000_0000;
+const SLOT_PAGES: u64 = 8;
+const DOUBLE_FAULT_PAGES: u64 = 2;
+const STACK_PAGES: u64 = 4;
+/// Where all the CPUs' stack slots are, start and end
+pub(crate) const STACKS: (u64, u64) = (STACKS_START, STACKS_START + MAX_CPUS as u64 * SLOT_PAGES * FRAME_SIZE);
+
+// The low half of the interrupt command register: INIT (asserted), and
+// STARTUP with the trampoline's page number as its vector
+const IPI_INIT: u32 = 0x4500;
+const IPI_STARTUP: u32 = 0x4600;
+// How long a CPU has to come up after its STARTUP IPI, and to park or
+// come back from being parked
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+// How often resume sends its NMI again
+const RESUME_RETRY: Duration = Duration::from_micros(100);
+
+const EFER_LONG_MODE: u32 = 1 << 8;
+const EFER_NO_EXECUTE: u32 = 1 << 11;
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+// Each running CPU's APIC ID, by id; NO_CPU where none has come up
+const NO_CPU: u32 = u32::MAX;
+const APIC_ID_INIT: AtomicU32 = AtomicU32::new(NO_CPU);
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [APIC_ID_INIT; MAX_CPUS];
+// Where each CPU is in being parked and resumed
+const RUNNING: u8 = 0;
+const PARKING: u8 = 1;
+const PARKED: u8 = 2;
+const RESUMING: u8 = 3;
+const STATE_INIT: AtomicU8 = AtomicU8::new(RUNNING);
+static STATES: [AtomicU8; MAX_CPUS] = [STATE_INIT; MAX_CPUS];
+
+/// Whether a CPU that came up is taking IPIs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    Online,
+    Parked,
+}
+
+/// What each CPU keeps for itself, found through its GS base
+#[derive(Debug)]
+pub struct PerCpu {
+    /// From 0, the boot CPU, in the order they were started
+    pub id: usize,
+    pub apic_id: u32,
+    double_fault_stack: VirtAddr,
+}
+
+extern "C" {
+    static heorot_ap_trampoline: u8;
+    static heorot_ap_trampoline_end: u8;
+    static heorot_ap_long_jump: u8;
+    static heorot_ap_long_mode: u8;
+    static heorot_ap_gdt: u8;
+    static heorot_ap_gdt_pointer: u8;
+    static heorot_ap_cr3: u8;
+    static heorot_ap_efer: u8;
+    static heorot_ap_stack: u8;
+    static heorot_ap_entry: u8;
+    static heorot_ap_argument: u8;
+}
+
+// Copied to the low frame, so everything in it is found relative to where
+// it is: through CS in real mode (which the STARTUP IPI points at the page),
+// and through RIP in long mode. The fields from heorot_ap_gdt_pointer on
+// are filled in before each CPU is started. The GDT's selectors match the
+// kernel's.
+global_asm!(
+    ".code16",
+    ".global heorot_ap_trampoline",
+    "heorot_ap_trampoline:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [heorot_ap_gdt_pointer - heorot_ap_trampoline]",
+    // PAE, the kernel's page tables, long mode, then paging
+    "mov eax, cr4",
+    "or eax, 0x20",
+    "mov cr4, eax",
+    "mov eax, dword ptr [heorot_ap_cr3 - heorot_ap_trampoline]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, dword ptr [heorot_ap_efer - heorot_ap_trampoline]",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, 0x80010001",
+    "mov cr0, eax",
+    // A far jump to 32-bit offset heorot_ap_long_mode, in the 64-bit code segment
+    ".byte 0x66, 0xea",
+    ".global heorot_ap_long_jump",
+    "heorot_ap_long_jump:",
+    ".long 0",
+    ".word 0x08",
+    ".code64",
+    ".global heorot_ap_long_mode",
+    "heorot_ap_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor eax, eax",
+    "mov fs, ax",
+    "mov gs, ax",
+    // The end of the frame-pointer chain, for backtraces
+    "xor ebp, ebp",
+    "mov rsp, [rip + heorot_ap_stack]",
+    "mov rdi, [rip + heorot_ap_argument]",
+    "jmp qword ptr [rip + heorot_ap_entry]",
+    ".balign 8",
+    ".global heorot_ap_gdt",
+    "heorot_ap_gdt:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff",
+    ".quad 0x00cf92000000ffff",
+    ".global heorot_ap_gdt_pointer",
+    "heorot_ap_gdt_pointer:",
+    ".word 23",
+    ".long 0",
+    ".balign 8",
+    ".global heorot_ap_cr3",
+    "heorot_ap_cr3:",
+    ".long 0",
+    ".global heorot_ap_efer",
+    "heorot_ap_efer:",
+    ".long 0",
+    ".global heorot_ap_stack",
+    "heorot_ap_stack:",
+    ".quad 0",
+    ".global heorot_ap_entry",
+    "heorot_ap_entry:",
+    ".quad 0",
+    ".global heorot_ap_argument",
+    "heorot_ap_argument:",
+    ".quad 0",
+    ".global heorot_ap_trampoline_end",
+    "heorot_ap_trampoline_end:",
+);
+
+// Where `symbol` ends up in the trampoline's copy at `base`
+fn trampoline_field(base: VirtAddr, symbol: &u8) -> *mut u8 {
+    let start = unsafe { &heorot_ap_trampoline } as *const u8 as u64;
+    (base + (symbol as *const u8 as u64 - start)).as_mut_ptr()
+}
+
+/// This CPU's `PerCpu`, or None before `init` gave it one. Read from the
+/// MSR, not through GS: a program in ring 3 can load GS and zero its base,
+/// and programs only run on the boot CPU, whose id is 0 either way.
+pub fn this_cpu() -> Option<&'static PerCpu> {
+    // Architectural, so it can't fail
+    let base = msr::GS_BASE.read().unwrap_or(0);
+    if base == 0 {
+        return None;
+    }
+    Some(unsafe { &*(base as *const PerCpu) })
+}
+
+/// Which CPU this is, from 0 (the boot CPU)
+pub fn cpu_id() -> usize {
+    this_cpu().map_or(0, |per_cpu| per_cpu.id)
+}
+
+/// CPUs running, the boot one included, and not parked
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// The APIC ID of CPU `id`, if it's running and not parked
+pub fn apic_id(id: usize) -> Option<u32> {
+    match state(id)? {
+        CpuState::Online => Some(APIC_IDS[id].load(Ordering::Acquire)),
+        CpuState::Parked => None,
+    }
+}
+
+/// Where CPU `id` is, or None if it never came up
+pub fn state(id: usize) -> Option<CpuState> {
+    if APIC_IDS.get(id)?.load(Ordering::Acquire) == NO_CPU {
+        return None;
+    }
+    match STATES[id].load(Ordering::Acquire) {
+        RUNNING | PARKING => Some(CpuState::Online),
+        _ => Some(CpuState::Parked),
+    }
+}
+
+fn set_this_cpu(per_cpu: &'static PerCpu) {
+    let _ = unsafe { msr::GS_BASE.write(per_cpu as *const PerCpu as u64) };
+    APIC_IDS[per_cpu.id].store(per_cpu.apic_id, Ordering::Release);
+}
+
+// Map CPU `id`'s stacks; returns the tops of its double fault and kernel
+// stacks. Never unmapped: a CPU that was slow to start may still use them.
+fn map_stacks(id: usize) -> Result<(VirtAddr, VirtAddr), &'static str> {
+    let slot = VirtAddr::new(STACKS_START + id as u64 * SLOT_PAGES * FRAME_SIZE);
+    let double_fault = slot + FRAME_SIZE;
+    let stack = double_fault + (DOUBLE_FAULT_PAGES + 1) * FRAME_SIZE;
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if cpu::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    for &(start, pages) in [(double_fault, DOUBLE_FAULT_PAGES), (stack, STACK_PAGES)].iter() {
+        let first: Page<Size4KiB> = Page::containing_address(start);
+        for page in Page::range(first, first + pages) {
+            let frame = memory::allocate_frame().ok_or(memory::OUT_OF_MEMORY)?;
+            if let Err(message) = unsafe { paging::map_page(page, frame, flags) } {
+                unsafe { memory::deallocate_frame(frame) };
+                return Err(message);
+            }
+        }
+    }
+    Ok((double_fault + DOUBLE_FAULT_PAGES * FRAME_SIZE, stack + STACK_PAGES * FRAME_SIZE))
+}
+
+extern "C" fn ap_main(per_cpu: &'static PerCpu) -> ! {
+    gdt::init_ap(per_cpu.double_fault_stack);
+    interrupts::init_idt();
+    #[cfg(feature = "hardening")]
+    cpu::enable_protections();
+    set_this_cpu(per_cpu);
+    if let Err(message) = apic::init_ap() {
+        log::warn!("cpu {}: local APIC: {}", per_cpu.id, message);
+    }
+    ONLINE.fetch_add(1, Ordering::Release);
+    idle(&STATES[per_cpu.id])
+}
+
+// Where an AP halts between IPIs, parking when it's asked to
+fn idle(state: &AtomicU8) -> ! {
+    use x86_64::instructions::interrupts::{disable, enable, enable_and_hlt};
+
+    loop {
+        // Off while it looks, so the IPI that asks can't land in between
+        disable();
+        if state.load(Ordering::Acquire) == PARKING {
+            enable();
+            park_here(state);
+        } else {
+            enable_and_hlt();
+        }
+    }
+}
+
+fn park_here(state: &AtomicU8) {
+    use x86_64::instructions::interrupts::{disable, enable};
+
+    // Once nothing's being sent, nothing more can be: it's parked
+    ipi::without_calls(|| {
+        state.store(PARKED, Ordering::Release);
+        ONLINE.fetch_sub(1, Ordering::AcqRel);
+    });
+    disable();
+    // Only NMIs get through now
+    while state.load(Ordering::Acquire) != RESUMING {
+        hlt();
+    }
+    // Global pages too, since it missed every flush while it was parked
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        }
+    } else {
+        tlb::flush_all();
+    }
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    state.store(RUNNING, Ordering::Release);
+    enable();
+}
+
+// Run through the mailbox of the CPU being parked; it parks once it's
+// back in its idle loop
+fn ask_to_park(_: u64) {
+    STATES[cpu_id()].store(PARKING, Ordering::Release);
+}
+
+// Wait up to STARTUP_TIMEOUT for CPU `id` to be in `want`, calling `nudge`
+// in between
+fn reached(id: usize, want: u8, mut nudge: impl FnMut()) -> bool {
+    let start = Instant::now();
+    while STATES[id].load(Ordering::Acquire) != want {
+        if start.elapsed() > STARTUP_TIMEOUT {
+            return false;
+        }
+        nudge();
+    }
+    true
+}
+
+/// Take application processor `id` offline until `resume`: it stops taking
+/// IPIs and halts. No thread runs on it, so none has to move off first.
+pub fn park(id: usize) -> Result<(), &'static str> {
+    if id == 0 {
+        return Err("the boot CPU runs the threads");
+    }
+    match state(id) {
+        None => return Err("no such CPU"),
+        Some(CpuState::Parked) => return Err("the CPU's parked already"),
+        Some(CpuState::Online) => {}
+    }
+    ipi::call(id, ask_to_park, 0)?;
+    if !reached(id, PARKED, core::hint::spin_loop) {
+        return Err("the CPU didn't park");
+    }
+    Ok(())
+}
+
+/// Bring parked CPU `id` back
+pub fn resume(id: usize) -> Result<(), &'static str> {
+    if state(id).is_none() {
+        return Err("no such CPU");
+    }
+    if STATES[id].compare_exchange(PARKED, RESUMING, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        return Err("the CPU isn't parked");
+    }
+    let apic_id = APIC_IDS[id].load(Ordering::Acquire);
+    // One that lands between its looking and its halting is missed, so it's
+    // sent until it's back
+    let back = reached(id, RUNNING, || {
+        ipi::nmi(apic_id);
+        time::sleep_precise(RESUME_RETRY);
+    });
+    if !back {
+        // Left parked, unless it's come back since
+        let _ = STATES[id].compare_exchange(RESUMING, PARKED, Ordering::AcqRel, Ordering::Acquire);
+        return Err("the CPU didn't come back");
+    }
+    Ok(())
+}
+
+// Wait for the count to pass `before`, for up to `timeout`
+fn came_up(before: usize, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if online() > before {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    online() > before
+}
+
+// INIT, then STARTUP, and STARTUP again if the first didn't take
+fn start(apic_id: u32, vector: u8) -> bool {
+    let before = online();
+    apic::send_ipi(apic_id, IPI_INIT);
+    time::sleep_precise(Duration::from_millis(10));
+    for _ in 0..2 {
+        apic::send_ipi(apic_id, IPI_STARTUP | u32::from(vector));
+        if came_up(before, Duration::from_millis(1)) {
+            return true;
+        }
+    }
+    came_up(before, STARTUP_TIMEOUT)
+}
+
+/// Start every other processor the MADT lists, up to MAX_CPUS in all, and
+/// give this one its `PerCpu`. Needs the local APIC (apic::init) and the
+/// heap. Returns how many CPUs are running.
+pub fn init() -> Result<usize, &'static str> {
+    if this_cpu().is_some() {
+        return Ok(online());
+    }
+    if !apic::enabled() {
+        return Err("needs the local APIC");
+    }
+    let madt = Madt::read()?;
+    let boot = apic::local_id();
+    // Its double fault stack is the one gdt::init set up
+    set_this_cpu(Box::leak(Box::new(PerCpu { id: 0, apic_id: boot, double_fault_stack: VirtAddr::zero() })));
+
+    if madt.cpus().all(|apic_id| apic_id == boot) {
+        return Ok(1);
+    }
+    let frame = memory::low_frame().ok_or("no memory below 1 MiB to start them from")?;
+    let (level_4, _) = Cr3::read();
+    if level_4.start_address().as_u64() > u64::from(u32::MAX) {
+        return Err("page tables are above 4 GiB");
+    }
+
+    // Where the trampoline switches on paging from, so mapped where it is
+    let phys = frame.start_address();
+    let page = Page::containing_address(VirtAddr::new(phys.as_u64()));
+    let identity = match paging::translate_addr(page.start_address()) {
+        Some(addr) if addr == phys => false,
+        Some(_) => return Err("the trampoline's address is taken"),
+        None => {
+            unsafe { paging::map_page(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)? };
+            true
+        }
+    };
+
+    let base = paging::phys_to_virt(phys);
+    let mut efer = EFER_LONG_MODE;
+    if cpu::nx_enabled() {
+        efer |= EFER_NO_EXECUTE;
+    }
+    unsafe {
+        let start = &heorot_ap_trampoline as *const u8;
+        let len = &heorot_ap_trampoline_end as *const u8 as usize - start as usize;
+        ptr::copy_nonoverlapping(start, base.as_mut_ptr::<u8>(), len);
+        let at = |symbol: &u8| trampoline_field(base, symbol);
+        let phys_of = |symbol: &u8| (phys.as_u64() + (at(symbol) as u64 - base.as_u64())) as u32;
+        ptr::write_unaligned(at(&heorot_ap_gdt_pointer).add(2) as *mut u32, phys_of(&heorot_ap_gdt));
+        ptr::write_unaligned(at(&heorot_ap_long_jump) as *mut u32, phys_of(&heorot_ap_long_mode));
+        ptr::write_unaligned(at(&heorot_ap_cr3) as *mut u32, level_4.start_address().as_u64() as u32);
+        ptr::write_unaligned(at(&heorot_ap_efer) as *mut u32, efer);
+        ptr::write_unaligned(at(&heorot_ap_entry) as *mut u64, ap_main as usize as u64);
+    }
+
+    let result = start_all(&madt, boot, base, frame);
+    if identity {
+        let _ = unsafe { paging::unmap_page(page) };
+    }
+    result
+}
+
+fn start_all(madt: &Madt, boot: u32, base: VirtAddr, frame: PhysFrame) -> Result<usize, &'static str> {
+    let vector = (frame.start_address().as_u64() / FRAME_SIZE) as u8;
+    // An xAPIC can only send IPIs to IDs up to 0xfe. One that doesn't
+    // start still has its id and stacks, in case it does later.
+    let others = madt.cpus().filter(|&apic_id| apic_id != boot && apic_id < 0xff);
+    for (id, apic_id) in (1..MAX_CPUS).zip(others) {
+        let (double_fault_stack, stack) = map_stacks(id)?;
+        let per_cpu: &'static PerCpu = Box::leak(Box::new(PerCpu { id, apic_id, double_fault_stack }));
+        unsafe {
+            // Aligned the way a call would leave it
+            ptr::write_unaligned(trampoline_field(base, &heorot_ap_stack) as *mut u64, stack.as_u64() - 8);
+            ptr::write_unaligned(trampoline_field(base, &heorot_ap_argument) as *mut u64, per_cpu as *const PerCpu as u64);
+        }
+        if !start(apic_id, vector) {
+            log::warn!("the CPU with APIC ID {} didn't start", apic_id);
+        }
+    }
+    Ok(online())
+}
+
+/// TESTS
+
+#[test_case]
+fn test_boot_cpu_is_cpu_0() {
+    assert_eq!(cpu_id(), 0);
+    assert!(online() >= 1);
+    if let Some(per_cpu) = this_cpu() {
+        assert_eq!(per_cpu.apic_id, apic::local_id());
+        assert_eq!(apic_id(0), Some(per_cpu.apic_id));
+    }
+    assert_eq!(apic_id(MAX_CPUS), None);
+}
+
+#[test_case]
+fn test_park_and_resume() {
+    assert_eq!(park(0), Err("the boot CPU runs the threads"));
+    assert_eq!(park(MAX_CPUS), Err("no such CPU"));
+    if state(1).is_none() {
+        assert_eq!(resume(1), Err("no such CPU"));
+        return;
+    }
+    let before = online();
+    park(1).unwrap();
+    assert_eq!(state(1), Some(CpuState::Parked));
+    assert_eq!((online(), apic_id(1)), (before - 1, None));
+    assert_eq!(park(1), Err("the CPU's parked already"));
+    // Calls and flushes pass it by
+    assert_eq!(ipi::call(1, |_| {}, 0), Err("no such CPU"));
+    ipi::flush_tlb(VirtAddr::new(0x_4444_0000_0000)).unwrap();
+
+    resume(1).unwrap();
+    assert_eq!((state(1), online()), (Some(CpuState::Online), before));
+    assert_eq!(resume(1), Err("the CPU isn't parked"));
+    ipi::call(1, |_| {}, 0).unwrap();
+}
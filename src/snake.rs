@@ -0,0 +1,211 @@
+use pc_keyboard::{DecodedKey, KeyCode};
+use crate::vga_buffer::{self, Color, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::time::{Duration, Instant};
+use crate::{entropy, keyboard};
+
+// Row 0 is the status line; the rest of the screen is the playing field
+const TOP: usize = 1;
+const MAX_LEN: usize = 1024;
+const STEP: Duration = Duration::from_millis(110);
+const ESCAPE: char = '\u{1b}';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    fn opposite(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Point {
+    row: usize,
+    col: usize,
+}
+
+impl Point {
+    // None if the step would run into the edge of the field
+    fn step(self, direction: Direction) -> Option<Point> {
+        let Point { row, col } = self;
+        match direction {
+            Direction::Up if row > TOP => Some(Point { row: row - 1, col }),
+            Direction::Down if row + 1 < BUFFER_HEIGHT => Some(Point { row: row + 1, col }),
+            Direction::Left if col > 0 => Some(Point { row, col: col - 1 }),
+            Direction::Right if col + 1 < BUFFER_WIDTH => Some(Point { row, col: col + 1 }),
+            _ => None,
+        }
+    }
+}
+
+enum Input {
+    Turn(Direction),
+    Quit,
+}
+
+fn decode(key: DecodedKey) -> Option<Input> {
+    match key {
+        DecodedKey::RawKey(KeyCode::ArrowUp) | DecodedKey::Unicode('w') => Some(Input::Turn(Direction::Up)),
+        DecodedKey::RawKey(KeyCode::ArrowDown) | DecodedKey::Unicode('s') => Some(Input::Turn(Direction::Down)),
+        DecodedKey::RawKey(KeyCode::ArrowLeft) | DecodedKey::Unicode('a') => Some(Input::Turn(Direction::Left)),
+        DecodedKey::RawKey(KeyCode::ArrowRight) | DecodedKey::Unicode('d') => Some(Input::Turn(Direction::Right)),
+        DecodedKey::Unicode('q') | DecodedKey::Unicode(ESCAPE) => Some(Input::Quit),
+        _ => None,
+    }
+}
+
+struct Game {
+    // Ring buffer of segments; `head` is the index of the newest one
+    body: [Point; MAX_LEN],
+    head: usize,
+    len: usize,
+    occupied: [[bool; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    direction: Direction,
+    food: Point,
+    score: usize,
+}
+
+impl Game {
+    fn new() -> Game {
+        let start = Point {
+            row: (TOP + BUFFER_HEIGHT) / 2,
+            col: BUFFER_WIDTH / 2,
+        };
+        let mut game = Game {
+            body: [start; MAX_LEN],
+            head: 0,
+            len: 1,
+            occupied: [[false; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            direction: Direction::Right,
+            food: start,
+            score: 0,
+        };
+        game.occupied[start.row][start.col] = true;
+        draw(start, b'@', Color::LightGreen);
+        game.place_food();
+        game
+    }
+
+    fn tail(&self) -> usize {
+        (self.head + MAX_LEN - (self.len - 1)) % MAX_LEN
+    }
+
+    fn place_food(&mut self) {
+        loop {
+            let random = entropy::random_u64() as usize;
+            let point = Point {
+                row: TOP + random % (BUFFER_HEIGHT - TOP),
+                col: (random >> 16) % BUFFER_WIDTH,
+            };
+            if !self.occupied[point.row][point.col] {
+                self.food = point;
+                draw(point, b'*', Color::LightRed);
+                return;
+            }
+        }
+    }
+
+    fn turn(&mut self, direction: Direction) {
+        // Reversing straight into yourself isn't a turn
+        if self.len == 1 || direction != self.direction.opposite() {
+            self.direction = direction;
+        }
+    }
+
+    // Move one step; false means the snake hit something
+    fn step(&mut self) -> bool {
+        let old_head = self.body[self.head];
+        let new_head = match old_head.step(self.direction) {
+            Some(point) => point,
+            None => return false,
+        };
+
+        // The old head becomes body; if it's also the tail it's erased next
+        draw(old_head, b'o', Color::Green);
+
+        let growing = new_head == self.food && self.len < MAX_LEN;
+        if !growing {
+            let tail = self.body[self.tail()];
+            self.occupied[tail.row][tail.col] = false;
+            draw(tail, b' ', Color::Black);
+            self.len -= 1;
+        }
+        if self.occupied[new_head.row][new_head.col] {
+            return false;
+        }
+
+        self.head = (self.head + 1) % MAX_LEN;
+        self.body[self.head] = new_head;
+        self.len += 1;
+        self.occupied[new_head.row][new_head.col] = true;
+        draw(new_head, b'@', Color::LightGreen);
+
+        if growing {
+            self.score += 1;
+            self.place_food();
+        }
+        true
+    }
+}
+
+fn draw(point: Point, byte: u8, color: Color) {
+    vga_buffer::write_cell(point.row, point.col, byte, color, Color::Black);
+}
+
+fn draw_status(text: &str) {
+    for col in 0..BUFFER_WIDTH {
+        let byte = text.as_bytes().get(col).copied().unwrap_or(b' ');
+        vga_buffer::write_cell(0, col, byte, Color::Black, Color::LightGray);
+    }
+}
+
+fn clear_field() {
+    for row in 0..BUFFER_HEIGHT {
+        for col in 0..BUFFER_WIDTH {
+            vga_buffer::write_cell(row, col, b' ', Color::Black, Color::Black);
+        }
+    }
+}
+
+/// Play snake until the player crashes or quits; returns the final score
+pub fn play() -> usize {
+    vga_buffer::set_cursor_visible(false);
+    clear_field();
+    draw_status(" SNAKE  arrows/WASD to steer, q to quit");
+    let mut game = Game::new();
+    let mut next_step = Instant::now() + STEP;
+
+    'game: loop {
+        // Take input until it's time for the next move; the timer wakes us up
+        while Instant::now() < next_step {
+            while let Some(key) = keyboard::try_read_key() {
+                match decode(key) {
+                    Some(Input::Turn(direction)) => game.turn(direction),
+                    Some(Input::Quit) => break 'game,
+                    None => {}
+                }
+            }
+            crate::timer::run_expired();
+            x86_64::instructions::hlt();
+        }
+        next_step += STEP;
+
+        if !game.step() {
+            break;
+        }
+    }
+
+    vga_buffer::clear_screen();
+    vga_buffer::set_cursor_visible(true);
+    game.score
+}
@@ -0,0 +1,70 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use crate::sync::MpscQueue;
+
+const QUEUE_SIZE: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Work {
+    run: fn(u64),
+    argument: u64,
+}
+
+// Pushed to from any interrupt handler, drained by whoever holds RUNNING
+static PENDING: MpscQueue<Work, QUEUE_SIZE> = MpscQueue::new();
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Queue `run(argument)` to happen once the current interrupt handler is
+/// done, with interrupts back on. For the parts of interrupt handling that
+/// can wait a little, so handlers themselves stay short.
+pub fn defer(run: fn(u64), argument: u64) -> Result<(), &'static str> {
+    PENDING.push(Work { run, argument }).map_err(|_| {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        "deferred work queue is full"
+    })
+}
+
+/// How many work items were dropped because the queue was full
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Called at the very end of interrupt handlers, after the EOI. Runs queued
+/// work with interrupts enabled, unless an outer handler is already doing so.
+pub(crate) fn irq_exit() {
+    while !PENDING.is_empty() && !RUNNING.swap(true, Ordering::Acquire) {
+        interrupts::enable();
+        while let Some(work) = PENDING.pop() {
+            (work.run)(work.argument);
+        }
+        interrupts::disable();
+        RUNNING.store(false, Ordering::Release);
+        // Loop in case a nested interrupt queued something after our last pop
+    }
+}
+
+/// Whether queued work is running right now, in some interrupt handler's
+/// irq_exit further down the stack
+pub(crate) fn in_progress() -> bool {
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// TESTS
+
+#[test_case]
+fn test_deferred_work_runs() {
+    static RAN_WITH: AtomicU64 = AtomicU64::new(0);
+    fn work(argument: u64) {
+        assert!(interrupts::are_enabled());
+        RAN_WITH.store(argument, Ordering::SeqCst);
+    }
+
+    defer(work, 42).unwrap();
+    // The next timer interrupt picks it up on its way out
+    let start = crate::time::Instant::now();
+    while RAN_WITH.load(Ordering::SeqCst) != 42 {
+        assert!(start.elapsed() < crate::time::Duration::from_millis(100));
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,38 @@
+use core::arch::global_asm;
+use crate::entropy;
+
+/// The canary `-Z stack-protector` compares against on function return.
+/// Starts out as a fixed value and gets randomized by `init()`.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut __stack_chk_guard: u64 = 0x595e_9fbd_94fd_a766;
+
+// The compiler calls __stack_chk_fail from the epilogue of the function whose
+// canary got clobbered, so its return address points into the culprit.
+// Grab it before anything else touches the stack and hand it to Rust.
+global_asm!(
+    ".global __stack_chk_fail",
+    "__stack_chk_fail:",
+    "mov rdi, [rsp]",
+    "jmp heorot_stack_chk_fail",
+);
+
+#[no_mangle]
+extern "C" fn heorot_stack_chk_fail(return_address: u64) -> ! {
+    panic!(
+        "stack smashing detected in function returning through {}",
+        crate::kptr::ptr(return_address)
+    );
+}
+
+/// Replace the build-time canary with a random one.
+///
+/// Every function that's live when this runs saved the old canary, so it must
+/// be inlined into a function that never returns (i.e. `_start`).
+#[inline(always)]
+pub fn init() {
+    let canary = entropy::random_u64();
+    unsafe {
+        core::ptr::write_volatile(core::ptr::addr_of_mut!(__stack_chk_guard), canary);
+    }
+}
@@ -0,0 +1,138 @@
+//! Function names for backtraces. The kernel carries an empty, fixed-size
+//! table that tools/embed-symbols.py fills in from the ELF's symbol table
+//! after linking; patching in place means no address moves. Until it's been
+//! run, nothing resolves.
+
+use alloc::vec::Vec;
+use core::ptr;
+
+const TABLE_SIZE: usize = 256 * 1024;
+const MAGIC: [u8; 8] = *b"HEORSYMS";
+
+// Layout, all little-endian: MAGIC, then the entry count (u32) and where
+// the names start (u32), then the entries sorted by address, then the
+// names, each ending in a NUL
+const HEADER_LEN: usize = 16;
+// address (u64), size (u32), name offset from the start of the names (u32)
+const ENTRY_LEN: usize = 16;
+
+const fn empty_table() -> [u8; TABLE_SIZE] {
+    let mut table = [0; TABLE_SIZE];
+    let mut index = 0;
+    while index < MAGIC.len() {
+        table[index] = MAGIC[index];
+        index += 1;
+    }
+    table
+}
+
+// Found by name and overwritten by the tool. The magic keeps it out of .bss,
+// so it has bytes in the file to overwrite; mut so the compiler can't
+// assume it still holds what it started with.
+#[no_mangle]
+#[used]
+static mut HEOROT_SYMBOLS: [u8; TABLE_SIZE] = empty_table();
+
+fn read_u32(table: &[u8], offset: usize) -> Option<u32> {
+    let bytes = table.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(table: &[u8], offset: usize) -> Option<u64> {
+    let low = read_u32(table, offset)?;
+    let high = read_u32(table, offset + 4)?;
+    Some(u64::from(high) << 32 | u64::from(low))
+}
+
+// The function containing `address` in `table`, and how far into it
+fn lookup(table: &[u8], address: u64) -> Option<(&str, u64)> {
+    if table.get(..MAGIC.len())? != MAGIC {
+        return None;
+    }
+    let count = read_u32(table, 8)? as usize;
+    let names = table.get(read_u32(table, 12)? as usize..)?;
+    let entry = |index: usize| read_u64(table, HEADER_LEN + index * ENTRY_LEN);
+
+    // The last entry starting at or below the address
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry(middle)? <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let offset = HEADER_LEN + low.checked_sub(1)? * ENTRY_LEN;
+    let start = read_u64(table, offset)?;
+    let size = read_u32(table, offset + 8)?;
+    if address - start >= u64::from(size) {
+        return None;
+    }
+    let name = names.get(read_u32(table, offset + 12)? as usize..)?;
+    let name = &name[..name.iter().position(|&byte| byte == 0)?];
+    Some((core::str::from_utf8(name).ok()?, address - start))
+}
+
+/// The function `address` is in, as its name and the offset into it
+pub fn resolve(address: u64) -> Option<(&'static str, u64)> {
+    // Never written at runtime
+    let table: &'static [u8; TABLE_SIZE] = unsafe { &*ptr::addr_of!(HEOROT_SYMBOLS) };
+    lookup(table, address)
+}
+
+// Every function in `table`, as its address, size and name
+fn entries(table: &[u8]) -> impl Iterator<Item = (u64, u32, &str)> {
+    let valid = table.get(..MAGIC.len()) == Some(&MAGIC[..]);
+    let count = if valid { read_u32(table, 8).unwrap_or(0) as usize } else { 0 };
+    let names = read_u32(table, 12).and_then(|start| table.get(start as usize..)).unwrap_or(&[]);
+    (0..count).filter_map(move |index| {
+        let offset = HEADER_LEN + index * ENTRY_LEN;
+        let name = names.get(read_u32(table, offset + 12)? as usize..)?;
+        let name = &name[..name.iter().position(|&byte| byte == 0)?];
+        Some((read_u64(table, offset)?, read_u32(table, offset + 8)?, core::str::from_utf8(name).ok()?))
+    })
+}
+
+/// The `count` biggest functions, biggest first, by name and size; none if
+/// the table hasn't been filled in
+pub fn largest(count: usize) -> Vec<(&'static str, u32)> {
+    let table: &'static [u8; TABLE_SIZE] = unsafe { &*ptr::addr_of!(HEOROT_SYMBOLS) };
+    largest_in(table, count)
+}
+
+fn largest_in(table: &[u8], count: usize) -> Vec<(&str, u32)> {
+    let mut functions: Vec<(&str, u32)> = entries(table).map(|(_, size, name)| (name, size)).collect();
+    functions.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    functions.truncate(count);
+    functions
+}
+
+/// TESTS
+
+#[test_case]
+fn test_lookup() {
+    let mut table = Vec::new();
+    table.extend_from_slice(&MAGIC);
+    table.extend_from_slice(&2u32.to_le_bytes());
+    table.extend_from_slice(&((HEADER_LEN + 2 * ENTRY_LEN) as u32).to_le_bytes());
+    for &(address, size, name) in [(0x1000u64, 0x20u32, 0u32), (0x2000, 0x10, 4)].iter() {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&name.to_le_bytes());
+    }
+    table.extend_from_slice(b"one\0two\0");
+
+    assert_eq!(lookup(&table, 0x1000), Some(("one", 0)));
+    assert_eq!(lookup(&table, 0x101f), Some(("one", 0x1f)));
+    // Between functions, and before the first
+    assert_eq!(lookup(&table, 0x1020), None);
+    assert_eq!(lookup(&table, 0xfff), None);
+    assert_eq!(lookup(&table, 0x2008), Some(("two", 8)));
+    assert_eq!(largest_in(&table, 1), [("one", 0x20)]);
+    // A table as the build leaves it: no entries
+    let mut empty = [0u8; HEADER_LEN];
+    empty[..MAGIC.len()].copy_from_slice(&MAGIC);
+    assert_eq!(lookup(&empty, 0x1000), None);
+    assert!(largest_in(&empty, 4).is_empty());
+}
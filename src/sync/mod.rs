@@ -0,0 +1,9 @@
+//! Synchronization primitives beyond what `spin` provides
+
+pub mod queue;
+pub mod rcu;
+pub mod waker;
+
+pub use queue::{MpscQueue, SpscQueue};
+pub use rcu::Rcu;
+pub use waker::WakerSlot;
@@ -0,0 +1,214 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Fixed-capacity lock-free queue for one producer and one consumer, e.g.
+/// an interrupt handler feeding a reader. Neither side ever waits, so it's
+/// safe to use from interrupt context without disabling interrupts.
+///
+/// Only one context may push and only one may pop at a time; the queue
+/// doesn't check. Capacity must be a power of two.
+pub struct SpscQueue<T: Copy, const N: usize> {
+    buffer: UnsafeCell<[MaybeUninit<T>; N]>,
+    // Free-running counts of pops and pushes; index with `% N`
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for SpscQueue<T, N> {}
+
+impl<T: Copy, const N: usize> SpscQueue<T, N> {
+    pub const fn new() -> SpscQueue<T, N> {
+        // Needed so the counters stay in step with the slots when they wrap
+        assert!(N.is_power_of_two(), "queue capacity must be a power of two");
+        SpscQueue {
+            buffer: UnsafeCell::new([MaybeUninit::uninit(); N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn slot(&self, position: usize) -> *mut MaybeUninit<T> {
+        unsafe { (self.buffer.get() as *mut MaybeUninit<T>).add(position % N) }
+    }
+
+    /// Add a value, or hand it back if the queue is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        unsafe { self.slot(tail).write(MaybeUninit::new(value)) };
+        // Publish the value before the consumer can see the new tail
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { self.slot(head).read().assume_init() };
+        // Only give the slot back once we've read it
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Copy, const N: usize> Default for SpscQueue<T, N> {
+    fn default() -> SpscQueue<T, N> {
+        SpscQueue::new()
+    }
+}
+
+const UNUSED: AtomicUsize = AtomicUsize::new(0);
+
+/// Fixed-capacity lock-free queue that any number of contexts can push to
+/// (say, several interrupt handlers and a thread) and one pops from. Based
+/// on Dmitry Vyukov's bounded queue: each slot has a sequence number that
+/// says whose turn it is. Capacity must be a power of two.
+pub struct MpscQueue<T: Copy, const N: usize> {
+    buffer: UnsafeCell<[MaybeUninit<T>; N]>,
+    // Slot i's sequence number, stored minus i so every slot starts at zero
+    // and the whole array can be built in a const
+    sequences: [AtomicUsize; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for MpscQueue<T, N> {}
+
+impl<T: Copy, const N: usize> MpscQueue<T, N> {
+    pub const fn new() -> MpscQueue<T, N> {
+        assert!(N.is_power_of_two(), "queue capacity must be a power of two");
+        MpscQueue {
+            buffer: UnsafeCell::new([MaybeUninit::uninit(); N]),
+            sequences: [UNUSED; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn slot(&self, position: usize) -> *mut MaybeUninit<T> {
+        unsafe { (self.buffer.get() as *mut MaybeUninit<T>).add(position % N) }
+    }
+
+    fn sequence(&self, position: usize) -> usize {
+        let index = position % N;
+        self.sequences[index].load(Ordering::Acquire).wrapping_add(index)
+    }
+
+    fn set_sequence(&self, position: usize, sequence: usize) {
+        let index = position % N;
+        self.sequences[index].store(sequence.wrapping_sub(index), Ordering::Release);
+    }
+
+    /// Add a value, or hand it back if the queue is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            // A slot is free for position p when its sequence is p
+            let lag = self.sequence(tail).wrapping_sub(tail) as isize;
+            if lag == 0 {
+                match self.tail.compare_exchange_weak(tail, tail.wrapping_add(1),
+                    Ordering::Relaxed, Ordering::Relaxed)
+                {
+                    Ok(_) => {
+                        unsafe { self.slot(tail).write(MaybeUninit::new(value)) };
+                        self.set_sequence(tail, tail.wrapping_add(1));
+                        return Ok(());
+                    }
+                    Err(current) => tail = current,
+                }
+            } else if lag < 0 {
+                // Still holding a value from a lap ago
+                return Err(value);
+            } else {
+                // Another producer claimed it first
+                tail = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Take the oldest value. Returns None when empty, and also while the
+    /// oldest push is still being written.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        // Filled slots have their position plus one
+        if self.sequence(head) != head.wrapping_add(1) {
+            return None;
+        }
+        let value = unsafe { self.slot(head).read().assume_init() };
+        self.head.store(head.wrapping_add(1), Ordering::Relaxed);
+        // Free the slot for whoever pushes the next lap
+        self.set_sequence(head, head.wrapping_add(N));
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        self.sequence(head) != head.wrapping_add(1)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for MpscQueue<T, N> {
+    fn default() -> MpscQueue<T, N> {
+        MpscQueue::new()
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_spsc_fifo_and_full() {
+    let queue: SpscQueue<u8, 4> = SpscQueue::new();
+    assert_eq!(queue.pop(), None);
+    for value in 0..4 {
+        assert_eq!(queue.push(value), Ok(()));
+    }
+    assert_eq!(queue.push(4), Err(4));
+    assert_eq!(queue.len(), 4);
+    for value in 0..4 {
+        assert_eq!(queue.pop(), Some(value));
+    }
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn test_spsc_wraps_around() {
+    let queue: SpscQueue<u32, 8> = SpscQueue::new();
+    for value in 0..1000 {
+        queue.push(value).unwrap();
+        queue.push(value + 1).unwrap();
+        assert_eq!(queue.pop(), Some(value));
+        assert_eq!(queue.pop(), Some(value + 1));
+    }
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn test_mpsc_fifo_and_full() {
+    let queue: MpscQueue<u16, 4> = MpscQueue::new();
+    assert!(queue.is_empty());
+    for value in 0..4 {
+        assert_eq!(queue.push(value), Ok(()));
+    }
+    assert_eq!(queue.push(4), Err(4));
+    for lap in 0..100 {
+        assert_eq!(queue.pop(), Some(lap));
+        assert_eq!(queue.push(lap + 4), Ok(()));
+    }
+    for value in 100..104 {
+        assert_eq!(queue.pop(), Some(value));
+    }
+    assert_eq!(queue.pop(), None);
+}
@@ -0,0 +1,125 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Read-copy-update for read-mostly tables. Readers take no lock and never
+/// wait, so they can be anywhere, interrupt handlers included, and don't
+/// hold each other or the writer up. A writer changes a copy, which readers
+/// are given from then on; readers already in the old one finish there.
+///
+/// There are two copies, so no heap is needed: a writer reuses the copy
+/// before last once the grace period is up, which is once everyone who
+/// started reading it has finished. Until then the writer yields, so
+/// writers can't be interrupt handlers, and a reader mustn't write the
+/// same table.
+pub struct Rcu<T> {
+    copies: [UnsafeCell<MaybeUninit<T>>; 2],
+    // Which copy readers are given
+    current: AtomicUsize,
+    // Readers in each copy
+    readers: [AtomicUsize; 2],
+    // Held by writers, with whether both copies have a value in them yet
+    writer: Mutex<bool>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T: Clone> Rcu<T> {
+    pub const fn new(value: T) -> Rcu<T> {
+        Rcu {
+            copies: [UnsafeCell::new(MaybeUninit::new(value)), UnsafeCell::new(MaybeUninit::uninit())],
+            current: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: Mutex::new(false),
+        }
+    }
+
+    fn copy(&self, index: usize) -> *mut T {
+        self.copies[index].get() as *mut T
+    }
+
+    /// Call `f` with the value as it is now
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let index = loop {
+            let index = self.current.load(Ordering::SeqCst);
+            self.readers[index].fetch_add(1, Ordering::SeqCst);
+            // If a writer has moved on since, that copy may be the one it's
+            // writing
+            if self.current.load(Ordering::SeqCst) == index {
+                break index;
+            }
+            self.readers[index].fetch_sub(1, Ordering::SeqCst);
+        };
+        // Nobody writes the current copy
+        let result = f(unsafe { &*self.copy(index) });
+        self.readers[index].fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Have `f` change a copy of the value, then give readers that instead
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut both = self.writer.lock();
+        let current = self.current.load(Ordering::SeqCst);
+        let spare = 1 - current;
+        // The grace period: readers who started on the spare before the
+        // last update have finished, and new ones back out
+        while self.readers[spare].load(Ordering::SeqCst) != 0 {
+            crate::sched::yield_now();
+        }
+        unsafe {
+            let value = &*self.copy(current);
+            if *both {
+                (*self.copy(spare)).clone_from(value);
+            } else {
+                self.copy(spare).write(value.clone());
+                *both = true;
+            }
+            let result = f(&mut *self.copy(spare));
+            self.current.store(spare, Ordering::SeqCst);
+            result
+        }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let current = *self.current.get_mut();
+        let both = *self.writer.get_mut();
+        for index in 0..2 {
+            if index == current || both {
+                unsafe { (self.copies[index].get() as *mut T).drop_in_place() };
+            }
+        }
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_readers_keep_the_copy_they_started_on() {
+    static TABLE: Rcu<[u32; 2]> = Rcu::new([1, 2]);
+
+    TABLE.read(|before| {
+        TABLE.update(|table| table[0] = 3);
+        assert_eq!(*before, [1, 2]);
+        TABLE.read(|after| assert_eq!(*after, [3, 2]));
+    });
+    TABLE.update(|table| table[1] = 4);
+    assert_eq!(TABLE.read(|table| *table), [3, 4]);
+}
+
+#[test_case]
+fn test_updates_build_on_each_other() {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    let table = Rcu::new(vec![1]);
+    for n in 2..5 {
+        assert_eq!(table.update(|table| {
+            table.push(n);
+            table.len()
+        }), n);
+    }
+    assert_eq!(table.read(Vec::clone), [1, 2, 3, 4]);
+}
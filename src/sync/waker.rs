@@ -0,0 +1,42 @@
+use core::task::Waker;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Where a task waiting for an event leaves its waker, so whatever produces
+/// the event (often an interrupt handler) can wake it
+pub struct WakerSlot {
+    // Only ever locked with interrupts off, so a handler can't find it held
+    waker: Mutex<Option<Waker>>,
+}
+
+impl WakerSlot {
+    pub const fn new() -> WakerSlot {
+        WakerSlot {
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Have the next `wake` wake `waker`, replacing whoever was waiting before
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let mut slot = self.waker.lock();
+            match &*slot {
+                Some(existing) if existing.will_wake(waker) => {}
+                _ => *slot = Some(waker.clone()),
+            }
+        });
+    }
+
+    /// Wake whoever is waiting, if anyone is
+    pub fn wake(&self) {
+        if let Some(waker) = interrupts::without_interrupts(|| self.waker.lock().take()) {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for WakerSlot {
+    fn default() -> WakerSlot {
+        WakerSlot::new()
+    }
+}
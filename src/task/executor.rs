@@ -0,0 +1,150 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
+use crate::sync::MpscQueue;
+use super::{Task, TaskId};
+
+pub const MAX_TASKS: usize = 64;
+// Each live task is queued at most once, and a finished one can leave
+// behind one stale entry, so twice the tasks always fits
+type ReadyQueue = MpscQueue<TaskId, { 2 * MAX_TASKS }>;
+
+struct TaskWaker {
+    id: TaskId,
+    // Set while the task is in the ready queue, so repeated wakes (say, one
+    // per keypress) don't fill it up. Stays set once the task is done.
+    queued: AtomicBool,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<TaskWaker>) {
+        self.wake_by_ref();
+    }
+
+    // Safe from interrupt handlers: the queue is lock-free
+    fn wake_by_ref(self: &Arc<TaskWaker>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            // Can't be full, see ReadyQueue
+            let _ = self.ready.push(self.id);
+        }
+    }
+}
+
+/// Runs tasks when they're woken, and halts the CPU when none are
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    wakers: BTreeMap<TaskId, Arc<TaskWaker>>,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+            wakers: BTreeMap::new(),
+            ready: Arc::new(MpscQueue::new()),
+        }
+    }
+
+    /// Add a task; it gets its first poll on the next pass
+    pub fn spawn(&mut self, task: Task) -> Result<(), &'static str> {
+        if self.tasks.len() == MAX_TASKS {
+            return Err("too many tasks");
+        }
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            return Err("a task with that id is already spawned");
+        }
+        let waker = Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            ready: self.ready.clone(),
+        });
+        waker.wake_by_ref();
+        self.wakers.insert(id, waker);
+        Ok(())
+    }
+
+    fn run_ready_tasks(&mut self) {
+        let Executor { tasks, wakers, ready } = self;
+        while let Some(id) = ready.pop() {
+            let (task, task_waker) = match (tasks.get_mut(&id), wakers.get(&id)) {
+                (Some(task), Some(task_waker)) => (task, task_waker),
+                // Finished since it was woken
+                _ => continue,
+            };
+            // Cleared before the poll, so a wake during it queues the task again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                task_waker.queued.store(true, Ordering::Release);
+                tasks.remove(&id);
+                wakers.remove(&id);
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Check with interrupts off so a wake can't land between the check
+        // and the hlt; idle() re-enables them atomically
+        interrupts::disable();
+        if self.ready.is_empty() {
+            crate::timer::idle();
+        } else {
+            interrupts::enable();
+        }
+    }
+
+    /// Run tasks until every one has finished, halting whenever none of them
+    /// can make progress. Timer callbacks keep running in between.
+    pub fn run(&mut self) {
+        while !self.tasks.is_empty() {
+            self.run_ready_tasks();
+            crate::timer::run_expired();
+            if !self.tasks.is_empty() {
+                self.sleep_if_idle();
+            }
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor::new()
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_tasks_interleave() {
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    let log = Rc::new(RefCell::new([0u8; 6]));
+    let position = Rc::new(RefCell::new(0));
+    let task = |name: u8| {
+        let (log, position) = (log.clone(), position.clone());
+        async move {
+            for _ in 0..3 {
+                let mut at = position.borrow_mut();
+                log.borrow_mut()[*at] = name;
+                *at += 1;
+                drop(at);
+                super::yield_now().await;
+            }
+        }
+    };
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(task(b'a'))).unwrap();
+    executor.spawn(Task::new(task(b'b'))).unwrap();
+    executor.run();
+    // Each yield lets the other task go
+    assert_eq!(&*log.borrow(), b"ababab");
+}
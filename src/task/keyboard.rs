@@ -0,0 +1,111 @@
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use pc_keyboard::DecodedKey;
+use crate::io::CharDevice;
+use crate::sync::WakerSlot;
+use crate::{keyboard, print, println};
+
+const ESCAPE: char = '\u{1b}';
+
+// The task waiting on the next scancode
+static WAKER: WakerSlot = WakerSlot::new();
+// Scancodes can only go to one reader
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Called by the keyboard interrupt handler once a scancode is queued
+pub(crate) fn wake() {
+    WAKER.wake();
+}
+
+/// Scancodes from the keyboard, as they arrive. The interrupt handler only
+/// queues them; decoding happens in whichever task awaits them.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    /// Only one stream can exist at a time, since each scancode goes to one reader
+    pub fn new() -> Option<ScancodeStream> {
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(ScancodeStream { _private: () })
+    }
+
+    pub fn poll_next(&mut self, context: &mut Context) -> Poll<u8> {
+        poll_scancode(context)
+    }
+
+    /// The next scancode, whenever it comes
+    pub async fn next(&mut self) -> u8 {
+        poll_fn(|context| self.poll_next(context)).await
+    }
+}
+
+fn poll_scancode(context: &mut Context) -> Poll<u8> {
+    if let Some(scancode) = keyboard::pop_scancode() {
+        return Poll::Ready(scancode);
+    }
+    WAKER.register(context.waker());
+    // One may have come in before we registered
+    match keyboard::pop_scancode() {
+        Some(scancode) => Poll::Ready(scancode),
+        None => Poll::Pending,
+    }
+}
+
+// Scancodes as bytes: however many are queued, once there's one
+impl CharDevice for ScancodeStream {
+    fn poll_read(&self, context: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, &'static str>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        match poll_scancode(context) {
+            Poll::Ready(first) => {
+                buf[0] = first;
+                let mut len = 1;
+                while len < buf.len() {
+                    match keyboard::pop_scancode() {
+                        Some(scancode) => buf[len] = scancode,
+                        None => break,
+                    }
+                    len += 1;
+                }
+                Poll::Ready(Ok(len))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for ScancodeStream {
+    fn drop(&mut self) {
+        TAKEN.store(false, Ordering::Release);
+    }
+}
+
+/// Echo keys as they're pressed, until Escape
+pub async fn print_keypresses() {
+    let mut scancodes = match ScancodeStream::new() {
+        Some(scancodes) => scancodes,
+        None => {
+            println!("print_keypresses: the keyboard already has a reader");
+            return;
+        }
+    };
+    let mut composer = keyboard::Composer::new();
+    let mut escaped = false;
+    while !escaped {
+        let key = match keyboard::decode(scancodes.next().await) {
+            Some(key) => key,
+            None => continue,
+        };
+        composer.feed(key, |key| match key {
+            DecodedKey::Unicode(ESCAPE) => escaped = true,
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
+        });
+    }
+    println!();
+}
@@ -0,0 +1,68 @@
+use alloc::collections::VecDeque;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use super::Task;
+
+/// Polls every task in turn until they've all finished, whether or not
+/// they've been woken. Fine for tests; `Executor` is the one to use.
+pub struct SimpleExecutor {
+    queue: VecDeque<Task>,
+}
+
+// A waker that does nothing, since everything gets polled again anyway
+fn dummy_waker() -> Waker {
+    fn no_op(_: *const ()) {}
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
+    unsafe { Waker::from_raw(clone(core::ptr::null())) }
+}
+
+impl SimpleExecutor {
+    pub fn new() -> SimpleExecutor {
+        SimpleExecutor {
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        self.queue.push_back(task);
+    }
+
+    pub fn run(&mut self) {
+        let waker = dummy_waker();
+        while let Some(mut task) = self.queue.pop_front() {
+            let mut context = Context::from_waker(&waker);
+            if let Poll::Pending = task.poll(&mut context) {
+                self.queue.push_back(task);
+            }
+        }
+    }
+}
+
+impl Default for SimpleExecutor {
+    fn default() -> SimpleExecutor {
+        SimpleExecutor::new()
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_simple_executor_runs_to_completion() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static STEPS: AtomicUsize = AtomicUsize::new(0);
+    async fn count() {
+        for _ in 0..3 {
+            STEPS.fetch_add(1, Ordering::Relaxed);
+            super::yield_now().await;
+        }
+    }
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(count()));
+    executor.spawn(Task::new(count()));
+    executor.run();
+    assert_eq!(STEPS.load(Ordering::Relaxed), 6);
+}
@@ -0,0 +1,134 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use crate::time::{Duration, Instant};
+use crate::timer;
+
+const MAX_SLEEPERS: usize = 16;
+
+struct Sleeper {
+    id: u64,
+    deadline: Instant,
+    waker: Waker,
+}
+
+// Never touched by interrupt handlers; woken from timer callbacks
+const NO_SLEEPER: Option<Sleeper> = None;
+static SLEEPERS: Mutex<[Option<Sleeper>; MAX_SLEEPERS]> = Mutex::new([NO_SLEEPER; MAX_SLEEPERS]);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Timer callback: wake every sleeper that's due
+fn wake_sleepers() {
+    let now = Instant::now();
+    let mut due: [Option<Waker>; MAX_SLEEPERS] = Default::default();
+    {
+        let mut sleepers = SLEEPERS.lock();
+        for (slot, due) in sleepers.iter_mut().zip(due.iter_mut()) {
+            if slot.as_ref().map_or(false, |sleeper| sleeper.deadline <= now) {
+                *due = slot.take().map(|sleeper| sleeper.waker);
+            }
+        }
+    }
+    // Outside the lock, since waking may poll right away on some executors
+    for waker in due.iter_mut().filter_map(Option::take) {
+        waker.wake();
+    }
+}
+
+/// Future for `sleep`
+pub struct Sleep {
+    id: u64,
+    deadline: Instant,
+}
+
+impl Sleep {
+    // Leave our waker for wake_sleepers, with a timer set to call it.
+    // False if either table is full.
+    fn register(&self, waker: &Waker) -> bool {
+        let mut sleepers = SLEEPERS.lock();
+        if let Some(sleeper) = sleepers.iter_mut().flatten().find(|sleeper| sleeper.id == self.id) {
+            if !sleeper.waker.will_wake(waker) {
+                sleeper.waker = waker.clone();
+            }
+            return true;
+        }
+        let slot = match sleepers.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        if timer::after(self.deadline.duration_since(Instant::now()), wake_sleepers).is_err() {
+            return false;
+        }
+        *slot = Some(Sleeper { id: self.id, deadline: self.deadline, waker: waker.clone() });
+        true
+    }
+
+    fn unregister(&self) {
+        for slot in SLEEPERS.lock().iter_mut() {
+            if slot.as_ref().map_or(false, |sleeper| sleeper.id == self.id) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        if !self.register(context.waker()) {
+            // No room to wait properly; poll again on the executor's next pass
+            context.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Wait at least `duration` without holding up other tasks
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        deadline: Instant::now() + duration,
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_sleeping_tasks_wake_in_order() {
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+    use super::{Executor, Task};
+
+    let order = Rc::new(RefCell::new([0u8; 2]));
+    let position = Rc::new(RefCell::new(0));
+    let task = |name: u8, millis: u64| {
+        let (order, position) = (order.clone(), position.clone());
+        async move {
+            sleep(Duration::from_millis(millis)).await;
+            let mut at = position.borrow_mut();
+            order.borrow_mut()[*at] = name;
+            *at += 1;
+        }
+    };
+
+    let start = Instant::now();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(task(b'b', 6))).unwrap();
+    executor.spawn(Task::new(task(b'a', 3))).unwrap();
+    executor.run();
+    assert_eq!(&*order.borrow(), b"ab");
+    assert!(start.elapsed() >= Duration::from_millis(6));
+}
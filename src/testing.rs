@@ -0,0 +1,242 @@
+//! Fixtures for tests: what a test needs set up beforehand and put back
+//! after, so tests don't each build it, or leave it for the next test to
+//! trip over. `with` runs a test with a fixture; `Shared` is state several
+//! tests use, put back as new after every test by `test_runner`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::task::{Context, Poll};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::drivers::loopback;
+use crate::fs::{BlockDevice, SECTOR_SIZE};
+use crate::io;
+use crate::net::{self, Ipv4Addr, Ipv4Cidr};
+
+const MAX_SHARED: usize = 16;
+
+/// Something set up for a test, and torn down after it
+pub trait Fixture: Sized {
+    fn setup() -> Self;
+
+    fn teardown(self) {}
+}
+
+/// Run `test` with a fixture set up for it, tearing it down after. A failed
+/// test ends the run, so there's no teardown then.
+pub fn with<F: Fixture, R>(test: impl FnOnce(&mut F) -> R) -> R {
+    let mut fixture = F::setup();
+    let result = test(&mut fixture);
+    fixture.teardown();
+    result
+}
+
+trait Reset: Sync {
+    fn reset(&self);
+}
+
+/// State for tests that's made on first use, and dropped after each test
+/// so the next starts from new
+pub struct Shared<T: Send + 'static> {
+    value: Mutex<Option<T>>,
+    new: fn() -> T,
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static SHARED: Mutex<[Option<&'static dyn Reset>; MAX_SHARED]> = Mutex::new([None; MAX_SHARED]);
+
+impl<T: Send + 'static> Shared<T> {
+    pub const fn new(new: fn() -> T) -> Shared<T> {
+        Shared { value: Mutex::new(None), new }
+    }
+
+    /// Call `f` with the state, making it if this test hasn't yet
+    pub fn with<R>(&'static self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut value = self.value.lock();
+        if value.is_none() {
+            *value = Some((self.new)());
+            interrupts::without_interrupts(|| {
+                let mut shared = SHARED.lock();
+                let known = shared.iter().flatten().any(|&other| {
+                    core::ptr::eq(other as *const dyn Reset as *const u8, self as *const Self as *const u8)
+                });
+                if !known {
+                    let slot = shared.iter_mut().find(|slot| slot.is_none()).expect("too many Shared fixtures");
+                    *slot = Some(self);
+                }
+            });
+        }
+        f(value.as_mut().unwrap())
+    }
+}
+
+impl<T: Send + 'static> Reset for Shared<T> {
+    fn reset(&self) {
+        self.value.lock().take();
+    }
+}
+
+/// Drop every `Shared` fixture's state; `test_runner` calls it between tests
+pub fn reset_shared() {
+    let shared = interrupts::without_interrupts(|| *SHARED.lock());
+    for state in shared.iter().flatten() {
+        state.reset();
+    }
+}
+
+/// A disk in memory, which can be made to fail
+pub struct RamDisk {
+    pub sectors: RefCell<Vec<u8>>,
+    /// Every read and write fails while set
+    pub failed: Cell<bool>,
+}
+
+impl RamDisk {
+    pub fn new(sectors: usize) -> RamDisk {
+        RamDisk { sectors: RefCell::new(vec![0; sectors * SECTOR_SIZE]), failed: Cell::new(false) }
+    }
+
+    /// The first byte of each sector, which is often all a test writes
+    pub fn firsts(&self) -> Vec<u8> {
+        self.sectors.borrow().chunks_exact(SECTOR_SIZE).map(|sector| sector[0]).collect()
+    }
+}
+
+// 64 sectors, which is plenty for most tests
+impl Fixture for RamDisk {
+    fn setup() -> RamDisk {
+        RamDisk::new(64)
+    }
+}
+
+impl BlockDevice for &RamDisk {
+    fn read_sector(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        if self.failed.get() {
+            return Err("failed");
+        }
+        let sectors = self.sectors.borrow();
+        let start = lba as usize * SECTOR_SIZE;
+        buf.copy_from_slice(sectors.get(start..start + SECTOR_SIZE).ok_or("past the end")?);
+        Ok(())
+    }
+
+    fn write_sector(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> Result<(), &'static str> {
+        if self.failed.get() {
+            return Err("failed");
+        }
+        let mut sectors = self.sectors.borrow_mut();
+        let start = lba as usize * SECTOR_SIZE;
+        sectors.get_mut(start..start + SECTOR_SIZE).ok_or("past the end")?.copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+// In memory, so nothing's ever waited for
+impl io::BlockDevice for &RamDisk {
+    fn poll_read_sector(&self, _context: &mut Context, lba: u64, buf: &mut [u8; SECTOR_SIZE])
+        -> Poll<Result<(), &'static str>> {
+        Poll::Ready(self.read_sector(lba, buf))
+    }
+
+    fn poll_write_sector(&self, _context: &mut Context, lba: u64, buf: &[u8; SECTOR_SIZE])
+        -> Poll<Result<(), &'static str>> {
+        Poll::Ready(self.write_sector(lba, buf))
+    }
+}
+
+// Under NetLoopback's interface, so lo's own queue is left be
+static TEST_LOOPBACK: loopback::Loopback = loopback::Loopback::new();
+
+/// A loopback interface of the test's own, up, with an address and a route
+/// to its network; taken away after, routes, frames and counts with it
+pub struct NetLoopback {
+    pub name: &'static str,
+    pub address: Ipv4Cidr,
+}
+
+impl NetLoopback {
+    pub fn stats(&self) -> net::Stats {
+        net::info(self.name).expect("the test's interface is gone").stats
+    }
+}
+
+impl Fixture for NetLoopback {
+    fn setup() -> NetLoopback {
+        // A documentation network, which nothing else routes
+        let address = Ipv4Cidr { address: Ipv4Addr([192, 0, 2, 1]), prefix_len: 24 };
+        let fixture = NetLoopback { name: "testlo", address };
+        net::register(fixture.name, &TEST_LOOPBACK).expect("couldn't add the test's interface");
+        net::set_address(fixture.name, Some(fixture.address)).unwrap();
+        net::set_up(fixture.name, true).unwrap();
+        net::route::add(fixture.address.network(), None, fixture.name).unwrap();
+        fixture
+    }
+
+    fn teardown(self) {
+        let _ = net::unregister(self.name);
+        TEST_LOOPBACK.clear();
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_fixtures() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static TORN_DOWN: AtomicUsize = AtomicUsize::new(0);
+    struct Counted(usize);
+
+    impl Fixture for Counted {
+        fn setup() -> Counted {
+            Counted(1)
+        }
+
+        fn teardown(self) {
+            TORN_DOWN.fetch_add(self.0, Ordering::Relaxed);
+        }
+    }
+
+    assert_eq!(with(|counted: &mut Counted| { counted.0 += 1; counted.0 }), 2);
+    assert_eq!(TORN_DOWN.load(Ordering::Relaxed), 2);
+
+    let written = with(|disk: &mut RamDisk| {
+        let disk = &*disk;
+        disk.write_sector(3, &[7; SECTOR_SIZE]).unwrap();
+        disk.failed.set(true);
+        assert_eq!(disk.read_sector(3, &mut [0; SECTOR_SIZE]), Err("failed"));
+        disk.firsts()[3]
+    });
+    assert_eq!(written, 7);
+
+    let name = with(|lo: &mut NetLoopback| {
+        use core::sync::atomic::AtomicBool;
+        use crate::task::{simple_executor::SimpleExecutor, Task};
+
+        static RECEIVED: AtomicBool = AtomicBool::new(false);
+        assert_eq!(net::route::lookup(Ipv4Addr([192, 0, 2, 9])).map(|route| route.interface), Some(lo.name));
+        let name = lo.name;
+        let mut executor = SimpleExecutor::new();
+        executor.spawn(Task::new(async move {
+            net::send(name, b"fixture").await.unwrap();
+            let mut frame = [0; 8];
+            RECEIVED.store(net::receive(name, &mut frame).await == Ok(7), Ordering::Relaxed);
+            // For teardown to throw away
+            net::send(name, b"unread").await.unwrap();
+        }));
+        executor.run();
+        assert!(RECEIVED.load(Ordering::Relaxed));
+        assert_eq!((lo.stats().tx_packets, lo.stats().rx_packets), (2, 1));
+        lo.name
+    });
+    assert_eq!(net::info(name), None);
+    assert_eq!(net::route::lookup(Ipv4Addr([192, 0, 2, 9])), None);
+    assert_eq!(TEST_LOOPBACK.queued(), 0);
+
+    static LIST: Shared<Vec<u32>> = Shared::new(Vec::new);
+    LIST.with(|list| list.push(1));
+    assert_eq!(LIST.with(|list| list.len()), 1);
+    reset_shared();
+    assert!(LIST.with(|list| list.is_empty()));
+}
@@ -0,0 +1,418 @@
+use core::arch::x86_64::_rdtsc;
+use core::convert::TryFrom;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::cpu::{self, Feature};
+use crate::device::{DeviceId, PowerOps};
+
+pub use core::time::Duration;
+pub use system::SystemTime;
+
+pub mod system;
+
+/// The PIT's input clock
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+/// Rate we program PIT channel 0 to interrupt at
+pub const TICK_HZ: u64 = 1000;
+const PIT_DIVISOR: u64 = PIT_FREQUENCY / TICK_HZ;
+
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE_PORT: u16 = 0x61;
+
+// How long to count TSC cycles against PIT channel 2 at boot
+const CALIBRATION_MS: u64 = 10;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// Zero until calibrated; without a usable TSC we fall back to PIT ticks
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+// Whether channel 0 is currently counting down a one-shot rather than ticking
+static ONESHOT: AtomicBool = AtomicBool::new(false);
+
+/// A point on the monotonic clock, counted from boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant { nanos: now_nanos() }
+    }
+
+    /// Time between `earlier` and this instant, or zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(|nanos| Instant { nanos })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_sub(nanos).map(|nanos| Instant { nanos })
+    }
+
+    /// Time since boot at this instant
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn now_nanos() -> u64 {
+    let tsc_hz = TSC_HZ.load(Ordering::Relaxed);
+    if tsc_hz != 0 {
+        let cycles = rdtsc().wrapping_sub(TSC_BASE.load(Ordering::Relaxed));
+        (u128::from(cycles) * 1_000_000_000 / u128::from(tsc_hz)) as u64
+    } else {
+        let ticks = TICKS.load(Ordering::Relaxed);
+        (u128::from(ticks) * u128::from(PIT_DIVISOR) * 1_000_000_000 / u128::from(PIT_FREQUENCY)) as u64
+    }
+}
+
+/// Timer interrupts since boot. They come at TICK_HZ, except while the
+/// kernel idles tickless, so this counts interrupts rather than time.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt handler
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn uptime() -> Duration {
+    Instant::now().since_boot()
+}
+crate::export_symbol!(uptime);
+
+/// Which hardware backs `Instant::now()`
+pub fn source() -> &'static str {
+    if TSC_HZ.load(Ordering::Relaxed) != 0 {
+        "tsc"
+    } else {
+        "pit"
+    }
+}
+
+/// Calibrated TSC frequency, if the TSC is in use
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+// Only trust the TSC if it ticks at a constant rate. Hypervisors don't always
+// advertise that, but their TSC is stable enough for our purposes.
+fn tsc_usable() -> bool {
+    cpu::has_feature(Feature::Tsc)
+        && (cpu::has_feature(Feature::InvariantTsc) || cpu::has_feature(Feature::Hypervisor))
+}
+
+fn write_channel0(mode_command: u8, count: u16) {
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel0: Port<u8> = Port::new(PIT_CHANNEL0);
+    unsafe {
+        command.write(mode_command);
+        channel0.write((count & 0xff) as u8);
+        channel0.write((count >> 8) as u8);
+    }
+}
+
+/// (Re)start the periodic tick: channel 0, lobyte/hibyte, mode 2 (rate
+/// generator). Unlike a square wave, the count then runs straight from the
+/// divisor down to the next interrupt, so reading it tells how far into a
+/// tick we are.
+pub(crate) fn program_periodic() {
+    ONESHOT.store(false, Ordering::Relaxed);
+    if crate::apic::timer_enabled() {
+        crate::apic::timer_periodic();
+    } else {
+        write_channel0(0x34, PIT_DIVISOR as u16);
+    }
+}
+
+/// Let channel 0 run down once and stop, for when the local APIC's timer
+/// takes over: mode 0 doesn't reload
+pub(crate) fn stop_channel0() {
+    write_channel0(0x30, 0);
+}
+
+// Nothing to save: resume reprograms the tick from scratch, and a pending
+// one-shot just gets rearmed on the next trip through idle
+fn suspend_pit(_id: DeviceId) -> Result<(), &'static str> {
+    Ok(())
+}
+
+fn resume_pit(_id: DeviceId) -> Result<(), &'static str> {
+    program_periodic();
+    Ok(())
+}
+
+pub(crate) const PIT_POWER: PowerOps = PowerOps {
+    suspend: suspend_pit,
+    resume: resume_pit,
+};
+
+/// Longest one-shot the 16-bit PIT counter can do (~55ms)
+pub(crate) const MAX_ONESHOT: Duration = Duration::from_nanos(65_535 * 1_000_000_000 / PIT_FREQUENCY);
+
+/// Replace the periodic tick with a single interrupt `delay` from now:
+/// channel 0, lobyte/hibyte, mode 0 (interrupt on terminal count)
+pub(crate) fn program_oneshot(delay: Duration) {
+    ONESHOT.store(true, Ordering::Relaxed);
+    if crate::apic::timer_enabled() {
+        crate::apic::timer_oneshot(delay);
+        return;
+    }
+    let count = (delay.as_nanos() * u128::from(PIT_FREQUENCY) / 1_000_000_000).max(1).min(65_535);
+    write_channel0(0x30, count as u16);
+}
+
+// Latch and read channel 0's current count
+#[cfg(feature = "irq-latency")]
+fn read_channel0() -> u16 {
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel0: Port<u8> = Port::new(PIT_CHANNEL0);
+    unsafe {
+        command.write(0x00);
+        let low = channel0.read();
+        let high = channel0.read();
+        u16::from_le_bytes([low, high])
+    }
+}
+
+/// PIT input clocks since channel 0 last raised IRQ0, or since the local
+/// APIC's timer last fired if it's taken over. Only meaningful from the
+/// timer interrupt handler, before another tick can come in.
+#[cfg(feature = "irq-latency")]
+pub(crate) fn pit_clocks_since_irq() -> u64 {
+    if crate::apic::timer_enabled() {
+        return (crate::apic::timer_elapsed().as_nanos() * u128::from(PIT_FREQUENCY) / 1_000_000_000) as u64;
+    }
+    let count = read_channel0();
+    if ONESHOT.load(Ordering::Relaxed) {
+        // Mode 0 interrupts on reaching zero and keeps counting down from 0xffff
+        u64::from(0u16.wrapping_sub(count))
+    } else {
+        // Mode 2 interrupts when it reloads the divisor
+        PIT_DIVISOR.saturating_sub(u64::from(count))
+    }
+}
+
+/// Whether the periodic tick can be stopped while idle. Only with the TSC:
+/// with PIT ticks as the clock source, stopping them would stop time.
+pub(crate) fn tickless_capable() -> bool {
+    cfg!(feature = "tickless") && tsc_frequency().is_some()
+}
+
+// Guards PIT channel 2, shared by TSC calibration and PIT-based precise sleeps
+static CHANNEL2: spin::Mutex<()> = spin::Mutex::new(());
+
+// Arm PIT channel 2 to count down `latch` input clocks and spin until it hits
+// zero, calling `start` right after arming. Doesn't need interrupts.
+fn pit_channel2_countdown(latch: u16, start: impl FnOnce()) {
+    let _guard = CHANNEL2.lock();
+    let mut gate: Port<u8> = Port::new(PIT_GATE_PORT);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel2: Port<u8> = Port::new(PIT_CHANNEL2);
+
+    unsafe {
+        // Gate channel 2 on, keep the speaker off
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+
+        // Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count)
+        command.write(0xb0);
+        channel2.write((latch & 0xff) as u8);
+        channel2.write((latch >> 8) as u8);
+        start();
+
+        // OUT2 goes high once the count reaches zero
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Call `start`, then return once PIT channel 2 has counted out the
+/// interval it gives back, for calibrating other clocks against. Doesn't
+/// need interrupts.
+pub(crate) fn calibration_window(start: impl FnOnce()) -> Duration {
+    pit_channel2_countdown((PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16, start);
+    Duration::from_millis(CALIBRATION_MS)
+}
+
+// Count TSC cycles while PIT channel 2 counts down a known interval
+fn calibrate_tsc() -> u64 {
+    let latch = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+    let mut start = 0;
+    pit_channel2_countdown(latch as u16, || start = rdtsc());
+    let end = rdtsc();
+    (end - start) * 1000 / CALIBRATION_MS
+}
+
+/// Sleep for `duration` with sub-millisecond accuracy, for driver timing
+/// loops that can't live with 1ms ticks. Uses a TSC deadline when the TSC
+/// is calibrated (halting through most of a long wait, then spinning), and
+/// otherwise counts it out on PIT channel 2. Works with interrupts off.
+pub fn sleep_precise(duration: Duration) {
+    match tsc_frequency() {
+        Some(hz) => {
+            let cycles = (duration.as_nanos() * u128::from(hz) / 1_000_000_000) as u64;
+            let deadline = rdtsc().saturating_add(cycles);
+            // Two ticks' worth of margin so a late timer interrupt can't overshoot
+            let margin = 2 * hz / TICK_HZ;
+
+            if x86_64::instructions::interrupts::are_enabled() {
+                while deadline.saturating_sub(rdtsc()) > margin {
+                    x86_64::instructions::hlt();
+                }
+            }
+            while rdtsc() < deadline {
+                core::hint::spin_loop();
+            }
+        }
+        None => {
+            let mut remaining = (duration.as_nanos() * u128::from(PIT_FREQUENCY) / 1_000_000_000) as u64;
+            while remaining > 0 {
+                let chunk = remaining.min(u64::from(u16::MAX));
+                pit_channel2_countdown(chunk as u16, || {});
+                remaining -= chunk;
+            }
+        }
+    }
+}
+crate::export_symbol!(sleep_precise);
+
+/// Sleep for at least `duration`, letting other threads run meanwhile and
+/// running due timer callbacks; for kernel threads. Async tasks await
+/// `task::sleep` instead. Needs interrupts on.
+pub fn sleep(duration: Duration) {
+    use x86_64::instructions::interrupts;
+
+    // Does nothing, but makes a tickless idle wake when we're due
+    fn wake() {}
+
+    let deadline = Instant::now() + duration;
+    let timer = crate::timer::after(duration, wake).ok();
+    loop {
+        crate::timer::run_expired();
+        interrupts::disable();
+        if Instant::now() >= deadline {
+            interrupts::enable();
+            break;
+        }
+        crate::timer::idle();
+    }
+    if let Some(timer) = timer {
+        crate::timer::cancel(timer);
+    }
+}
+
+/// Set the PIT tick rate, pick the best clock source, and read the wall
+/// clock from the RTC. Must run before interrupts are enabled.
+pub fn init() {
+    program_periodic();
+
+    if tsc_usable() {
+        let hz = calibrate_tsc();
+        TSC_BASE.store(rdtsc(), Ordering::Relaxed);
+        TSC_HZ.store(hz, Ordering::Relaxed);
+    }
+
+    system::init();
+}
+
+/// TESTS
+
+#[test_case]
+fn test_instant_monotonic() {
+    let earlier = Instant::now();
+    let later = Instant::now();
+    assert!(later >= earlier);
+    assert_eq!(earlier - later, Duration::from_nanos(0));
+}
+
+#[test_case]
+fn test_instant_arithmetic() {
+    let start = Instant::now();
+    let later = start + Duration::from_millis(1500);
+    assert_eq!(later - start, Duration::from_millis(1500));
+    assert_eq!(later - Duration::from_millis(1500), start);
+}
+
+#[test_case]
+fn test_sleep_precise() {
+    let start = Instant::now();
+    sleep_precise(Duration::from_micros(2500));
+    let elapsed = start.elapsed();
+    // The PIT-tick clock source only resolves whole milliseconds
+    assert!(elapsed >= Duration::from_millis(2));
+    assert!(elapsed < Duration::from_millis(50));
+}
+
+#[test_case]
+fn test_clock_advances() {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(5) {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_sleep() {
+    let start = Instant::now();
+    sleep(Duration::from_millis(3));
+    assert!(start.elapsed() >= Duration::from_millis(3));
+}
@@ -0,0 +1,160 @@
+use core::fmt;
+use core::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use super::{Duration, Instant};
+use crate::rtc::{self, DateTime};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+// Wall-clock time at boot in nanoseconds since the epoch; SystemTime::now()
+// adds the monotonic clock to it
+static BOOT_TIME_NANOS: AtomicU64 = AtomicU64::new(0);
+static UTC_OFFSET_MINUTES: AtomicI32 = AtomicI32::new(0);
+
+/// Wall-clock time as a duration since the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime {
+    since_epoch: Duration,
+}
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime {
+        since_epoch: Duration::from_secs(0),
+    };
+
+    pub fn now() -> SystemTime {
+        let boot = Duration::from_nanos(BOOT_TIME_NANOS.load(Ordering::Relaxed));
+        SystemTime {
+            since_epoch: boot + Instant::now().since_boot(),
+        }
+    }
+
+    pub fn from_unix(since_epoch: Duration) -> SystemTime {
+        SystemTime { since_epoch }
+    }
+
+    pub fn since_unix_epoch(&self) -> Duration {
+        self.since_epoch
+    }
+
+    /// Calendar fields in UTC
+    pub fn to_datetime(&self) -> DateTime {
+        let secs = self.since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let secs_of_day = secs % SECS_PER_DAY;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+
+    pub fn from_datetime(datetime: &DateTime) -> SystemTime {
+        let days = days_from_civil(
+            i64::from(datetime.year),
+            u32::from(datetime.month),
+            u32::from(datetime.day),
+        );
+        let secs = days as u64 * SECS_PER_DAY
+            + u64::from(datetime.hour) * 3600
+            + u64::from(datetime.minute) * 60
+            + u64::from(datetime.second);
+        SystemTime::from_unix(Duration::from_secs(secs))
+    }
+}
+
+/// Formats as RFC 3339 in the configured timezone, e.g. 2026-10-14T12:24:04.120+02:00
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let offset = utc_offset_minutes();
+        let local_secs = self.since_epoch.as_secs() as i64 + i64::from(offset) * 60;
+        let local = SystemTime::from_unix(Duration::from_secs(local_secs.max(0) as u64));
+        let date = local.to_datetime();
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}",
+            date.year, date.month, date.day, date.hour, date.minute, date.second,
+            self.since_epoch.subsec_millis()
+        )?;
+        if offset == 0 {
+            write!(f, "Z")
+        } else {
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = offset.abs();
+            write!(f, "{}{:02}:{:02}", sign, offset / 60, offset % 60)
+        }
+    }
+}
+
+// Howard Hinnant's days_from_civil: days since 1970-01-01 for a proleptic
+// Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (i64::from(month) + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// The inverse of days_from_civil
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Step the wall clock to `now`, e.g. after asking a time server
+pub fn set(now: SystemTime) {
+    let since_boot = Instant::now().since_boot();
+    let boot = now.since_epoch.checked_sub(since_boot).unwrap_or(Duration::from_secs(0));
+    BOOT_TIME_NANOS.store(boot.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Offset of local time from UTC used when formatting, in minutes
+pub fn utc_offset_minutes() -> i32 {
+    UTC_OFFSET_MINUTES.load(Ordering::Relaxed)
+}
+
+pub fn set_utc_offset_minutes(minutes: i32) {
+    UTC_OFFSET_MINUTES.store(minutes, Ordering::Relaxed);
+}
+
+/// Seed the wall clock from the RTC
+pub fn init() {
+    set(SystemTime::from_datetime(&rtc::read()));
+}
+
+/// TESTS
+
+#[test_case]
+fn test_civil_round_trip() {
+    let datetime = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 23,
+        minute: 59,
+        second: 58,
+    };
+    let time = SystemTime::from_datetime(&datetime);
+    assert_eq!(time.since_unix_epoch().as_secs(), 1_709_251_198);
+    assert_eq!(time.to_datetime(), datetime);
+}
+
+#[test_case]
+fn test_epoch_is_1970() {
+    let date = SystemTime::UNIX_EPOCH.to_datetime();
+    assert_eq!((date.year, date.month, date.day), (1970, 1, 1));
+}
@@ -0,0 +1,171 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use crate::time::{self, Duration, Instant};
+
+const MAX_TIMERS: usize = 32;
+
+/// Handle for cancelling a timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: TimerId,
+    deadline: Instant,
+    period: Option<Duration>,
+    callback: fn(),
+}
+
+static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn add(deadline: Instant, period: Option<Duration>, callback: fn()) -> Result<TimerId, &'static str> {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let mut timers = TIMERS.lock();
+    let slot = timers
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or("too many pending timers")?;
+    *slot = Some(Timer {
+        id,
+        deadline,
+        period,
+        callback,
+    });
+    Ok(id)
+}
+
+/// Run `callback` once, `delay` from now
+pub fn after(delay: Duration, callback: fn()) -> Result<TimerId, &'static str> {
+    add(Instant::now() + delay, None, callback)
+}
+
+/// Run `callback` every `period`, starting one period from now
+pub fn every(period: Duration, callback: fn()) -> Result<TimerId, &'static str> {
+    add(Instant::now() + period, Some(period), callback)
+}
+
+/// Stop a timer; returns false if it already fired (one-shot) or never existed
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    for slot in timers.iter_mut() {
+        if slot.map_or(false, |timer| timer.id == id) {
+            *slot = None;
+            return true;
+        }
+    }
+    false
+}
+
+/// When the next timer is due, if any are pending
+pub fn next_deadline() -> Option<Instant> {
+    TIMERS
+        .lock()
+        .iter()
+        .filter_map(|slot| slot.map(|timer| timer.deadline))
+        .min()
+}
+
+// Pop the earliest expired timer's callback, rescheduling periodic ones
+fn take_expired(now: Instant) -> Option<fn()> {
+    let mut timers = TIMERS.lock();
+    let slot = timers
+        .iter_mut()
+        .filter(|slot| slot.map_or(false, |timer| timer.deadline <= now))
+        .min_by_key(|slot| slot.map(|timer| timer.deadline))?;
+
+    let timer = slot.take()?;
+    if let Some(period) = timer.period {
+        // If we fell far behind, don't fire a burst to catch up
+        let next = timer.deadline + period;
+        *slot = Some(Timer {
+            deadline: if next <= now { now + period } else { next },
+            ..timer
+        });
+    }
+    Some(timer.callback)
+}
+
+/// Run every callback that has come due. Called from the kernel's idle loops,
+/// never from interrupt context, so callbacks are free to take locks and
+/// print. The timer table isn't locked while a callback runs.
+pub fn run_expired() {
+    let now = Instant::now();
+    while let Some(callback) = take_expired(now) {
+        callback();
+    }
+}
+
+/// Halt until the next interrupt. When the clock allows it, the periodic
+/// tick is stopped while we're halted and the PIT is armed one-shot for the
+/// nearest pending timer, so an idle kernel only wakes when there's work.
+///
+/// Call with interrupts disabled (after checking there's nothing to do);
+/// returns with them enabled.
+pub fn idle() {
+    use x86_64::instructions::interrupts;
+
+    // Nothing to do here, but another thread has work
+    if crate::sched::has_ready() {
+        interrupts::enable();
+        crate::sched::yield_now();
+        return;
+    }
+
+    if !time::tickless_capable() {
+        interrupts::enable_and_hlt();
+        return;
+    }
+
+    let wait = match next_deadline() {
+        Some(deadline) => deadline.duration_since(Instant::now()),
+        None => time::MAX_ONESHOT,
+    };
+    if wait < Duration::from_nanos(1_000_000_000 / time::TICK_HZ) {
+        // Due within a tick anyway; not worth reprogramming the PIT
+        interrupts::enable_and_hlt();
+        return;
+    }
+
+    time::program_oneshot(wait.min(time::MAX_ONESHOT));
+    interrupts::enable_and_hlt();
+    // Whatever woke us, the rest of the kernel expects regular ticks
+    time::program_periodic();
+}
+
+/// TESTS
+
+#[test_case]
+fn test_one_shot_fires_once() {
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn callback() {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    after(Duration::from_millis(2), callback).unwrap();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(10) {
+        run_expired();
+        x86_64::instructions::hlt();
+    }
+    run_expired();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn test_periodic_and_cancel() {
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn callback() {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let id = every(Duration::from_millis(2), callback).unwrap();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(20) {
+        run_expired();
+        x86_64::instructions::hlt();
+    }
+    assert!(cancel(id));
+    assert!(!cancel(id));
+    assert!(FIRED.load(Ordering::SeqCst) >= 2);
+}
@@ -0,0 +1,124 @@
+//! A top-like view of the kernel threads, redrawn every REFRESH. Each row
+//! gives a thread's share of the CPU since the last redraw, its state, its
+//! CPU time, and how much of its stack it has used at most. The busiest
+//! threads come first, and the heap's use is shown across the top. Arrows
+//! move the selection, which follows its thread as the rows re-sort; q or
+//! Esc quits.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use pc_keyboard::DecodedKey;
+use crate::sched::{self, CpuTimes, ThreadId, ThreadState};
+use crate::time::{Duration, Instant};
+use crate::tui::{self, Event, Form, Label, ListBox, Rect, Widget};
+use crate::vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::{allocator, keyboard};
+
+const REFRESH: Duration = Duration::from_secs(1);
+const HEADING: &str = "TID   STATE    CPU%      USER       SYS  STACK  NAME";
+
+struct Row {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+    times: CpuTimes,
+    percent: u64,
+    stack: Option<usize>,
+}
+
+// How much of `elapsed` `times` is, in percent
+fn percent(times: CpuTimes, elapsed: Duration) -> u64 {
+    let busy = (times.user + times.kernel).as_micros() * 100;
+    busy.checked_div(elapsed.as_micros()).unwrap_or(0) as u64
+}
+
+// Every thread, with its share of the CPU since `before`, busiest first
+fn sample(before: &[(ThreadId, CpuTimes)], elapsed: Duration) -> Vec<Row> {
+    let mut rows = Vec::new();
+    sched::for_each_thread(|id, name, state, times| {
+        let earlier = before.iter().find(|&&(thread, _)| thread == id).map_or(CpuTimes::default(), |&(_, times)| times);
+        let percent = percent(times.since(earlier), elapsed);
+        rows.push(Row { id, name, state, times, percent, stack: sched::stack_used(id) });
+    });
+    rows.sort_by_key(|row| core::cmp::Reverse(row.percent));
+    rows
+}
+
+fn line(row: &Row) -> String {
+    let seconds = |duration: Duration| format!("{}.{:03}", duration.as_secs(), duration.subsec_millis());
+    let state = match row.state {
+        ThreadState::Running => "running",
+        ThreadState::Ready => "ready",
+        ThreadState::Parked => "parked",
+    };
+    let stack = row.stack.map_or(String::from("-"), |used| format!("{}K", (used + 1023) / 1024));
+    format!("{:<5} {:<8} {:>4} {:>9} {:>9} {:>6}  {}", row.id.as_u64(), state, row.percent, seconds(row.times.user),
+        seconds(row.times.kernel), stack, row.name)
+}
+
+fn summary(threads: usize) -> String {
+    let used = allocator::HEAP_SIZE - allocator::free_bytes();
+    format!("top: up {}s, {} threads, heap {}K of {}K used; q quits", Instant::now().since_boot().as_secs(),
+        threads, used / 1024, allocator::HEAP_SIZE / 1024)
+}
+
+/// Show the view until q or Esc is pressed
+pub fn run() {
+    vga_buffer::set_cursor_visible(false);
+    let mut before = Vec::new();
+    let mut since = Instant::now();
+    let mut selected = None;
+    'view: loop {
+        let now = Instant::now();
+        let rows = sample(&before, now.duration_since(since));
+        before = rows.iter().map(|row| (row.id, row.times)).collect();
+        since = now;
+
+        let lines: Vec<String> = rows.iter().map(line).collect();
+        let items: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let summary = summary(rows.len());
+        let mut title = Label { row: 0, col: 0, text: &summary };
+        let mut heading = Label { row: 1, col: 0, text: HEADING };
+        let mut list = ListBox::new(Rect { row: 2, col: 0, width: BUFFER_WIDTH, height: BUFFER_HEIGHT - 2 }, &items);
+        list.select(rows.iter().position(|row| Some(row.id) == selected).unwrap_or(0));
+        {
+            let mut widgets: [&mut dyn Widget; 3] = [&mut title, &mut heading, &mut list];
+            let mut form = Form::new(&mut widgets);
+            form.draw();
+            // Take keys until it's time to redraw; the timer wakes us up
+            while now.elapsed() < REFRESH {
+                while let Some(key) = keyboard::try_read_key() {
+                    match key {
+                        DecodedKey::Unicode('q') | DecodedKey::Unicode('\u{1b}') => break 'view,
+                        key => {
+                            form.handle(Event::Key(key));
+                            form.draw();
+                        }
+                    }
+                }
+                crate::timer::run_expired();
+                x86_64::instructions::hlt();
+            }
+        }
+        selected = list.selected().map(|index| rows[index].id);
+    }
+    tui::leave();
+    vga_buffer::set_cursor_visible(true);
+}
+
+/// TESTS
+
+#[test_case]
+fn test_rows() {
+    let quarter = Duration::from_millis(250);
+    assert_eq!(percent(CpuTimes { user: quarter, kernel: quarter }, Duration::from_secs(1)), 50);
+    assert_eq!(percent(CpuTimes::default(), Duration::from_secs(0)), 0);
+
+    let rows = sample(&[], Duration::from_secs(1));
+    assert!(rows.windows(2).all(|pair| pair[0].percent >= pair[1].percent));
+    let main = rows.iter().find(|row| row.name == "main").unwrap();
+    assert_eq!(main.stack, None);
+    let line = line(main);
+    assert!(line.ends_with("  main") && line.contains(" - "));
+}
@@ -0,0 +1,403 @@
+use pc_keyboard::{DecodedKey, KeyCode};
+use x86_64::instructions::interrupts;
+use crate::vga_buffer::{self, Color, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::keyboard;
+
+const ESCAPE: char = '\u{1b}';
+const BACKGROUND: Color = Color::Blue;
+
+/// Input for a form and its widgets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Key(DecodedKey),
+    /// The left mouse button went down with the pointer over this cell
+    Click { row: usize, col: usize },
+}
+
+/// What a widget did with an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    Ignored,
+    Handled,
+    /// The widget was pressed or chosen; the form reports it to its caller
+    Activated,
+}
+
+/// The cells a widget occupies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub row: usize,
+    pub col: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn contains(&self, row: usize, col: usize) -> bool {
+        row >= self.row && row < self.row + self.height
+            && col >= self.col && col < self.col + self.width
+    }
+}
+
+pub trait Widget {
+    fn rect(&self) -> Rect;
+    fn draw(&self, focused: bool);
+
+    /// Whether Tab and clicks can move the focus onto this widget
+    fn focusable(&self) -> bool {
+        false
+    }
+
+    /// Only called for keys while focused, and for clicks inside rect()
+    fn handle(&mut self, _event: Event) -> Response {
+        Response::Ignored
+    }
+}
+
+fn colors(focused: bool) -> (Color, Color) {
+    if focused {
+        (Color::Black, Color::LightGray)
+    } else {
+        (Color::White, BACKGROUND)
+    }
+}
+
+// Write `text` at a position, cut off or padded with spaces to `width`
+fn put_str(row: usize, col: usize, width: usize, text: &str, foreground: Color, background: Color) {
+    let bytes = text.as_bytes();
+    for offset in 0..width {
+        let byte = match bytes.get(offset) {
+            Some(byte @ 0x20..=0x7e) => *byte,
+            Some(_) => 0xfe,
+            None => b' ',
+        };
+        vga_buffer::write_cell(row, col + offset, byte, foreground, background);
+    }
+}
+
+fn is_activate_key(key: DecodedKey) -> bool {
+    matches!(key, DecodedKey::Unicode('\n') | DecodedKey::Unicode(' '))
+}
+
+/// A line of static text
+pub struct Label<'a> {
+    pub row: usize,
+    pub col: usize,
+    pub text: &'a str,
+}
+
+impl Widget for Label<'_> {
+    fn rect(&self) -> Rect {
+        Rect { row: self.row, col: self.col, width: self.text.len(), height: 1 }
+    }
+
+    fn draw(&self, _focused: bool) {
+        let (foreground, background) = colors(false);
+        put_str(self.row, self.col, self.text.len(), self.text, foreground, background);
+    }
+}
+
+/// A push button, pressed with Enter, Space, or a click
+pub struct Button<'a> {
+    pub row: usize,
+    pub col: usize,
+    pub label: &'a str,
+}
+
+impl Widget for Button<'_> {
+    fn rect(&self) -> Rect {
+        Rect { row: self.row, col: self.col, width: self.label.len() + 4, height: 1 }
+    }
+
+    fn draw(&self, focused: bool) {
+        let (foreground, background) = colors(focused);
+        let width = self.rect().width;
+        put_str(self.row, self.col, width, "[ ", foreground, background);
+        put_str(self.row, self.col + 2, width - 4, self.label, foreground, background);
+        put_str(self.row, self.col + width - 2, 2, " ]", foreground, background);
+    }
+
+    fn focusable(&self) -> bool {
+        true
+    }
+
+    fn handle(&mut self, event: Event) -> Response {
+        match event {
+            Event::Key(key) if is_activate_key(key) => Response::Activated,
+            Event::Click { .. } => Response::Activated,
+            _ => Response::Ignored,
+        }
+    }
+}
+
+/// A scrolling list with one selected item. Arrows, Home/End, and clicks
+/// move the selection; Enter or clicking the selected item chooses it.
+pub struct ListBox<'a> {
+    rect: Rect,
+    items: &'a [&'a str],
+    selected: usize,
+    // Index of the first visible item
+    scroll: usize,
+}
+
+impl<'a> ListBox<'a> {
+    pub fn new(rect: Rect, items: &'a [&'a str]) -> ListBox<'a> {
+        ListBox { rect, items, selected: 0, scroll: 0 }
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        if self.items.is_empty() {
+            None
+        } else {
+            Some(self.selected)
+        }
+    }
+
+    pub fn select(&mut self, index: usize) {
+        if self.items.is_empty() {
+            return;
+        }
+        self.selected = index.min(self.items.len() - 1);
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + self.rect.height {
+            self.scroll = self.selected + 1 - self.rect.height;
+        }
+    }
+}
+
+impl Widget for ListBox<'_> {
+    fn rect(&self) -> Rect {
+        self.rect
+    }
+
+    fn draw(&self, focused: bool) {
+        for line in 0..self.rect.height {
+            let index = self.scroll + line;
+            let text = self.items.get(index).copied().unwrap_or("");
+            let (foreground, background) = match index == self.selected && !self.items.is_empty() {
+                true if focused => (Color::White, Color::Cyan),
+                true => colors(true),
+                false => colors(false),
+            };
+            put_str(self.rect.row + line, self.rect.col, self.rect.width, text, foreground, background);
+        }
+    }
+
+    fn focusable(&self) -> bool {
+        true
+    }
+
+    fn handle(&mut self, event: Event) -> Response {
+        if self.items.is_empty() {
+            return Response::Ignored;
+        }
+        match event {
+            Event::Key(DecodedKey::RawKey(KeyCode::ArrowUp)) => self.select(self.selected.saturating_sub(1)),
+            Event::Key(DecodedKey::RawKey(KeyCode::ArrowDown)) => self.select(self.selected + 1),
+            Event::Key(DecodedKey::RawKey(KeyCode::Home)) => self.select(0),
+            Event::Key(DecodedKey::RawKey(KeyCode::End)) => self.select(self.items.len() - 1),
+            Event::Key(DecodedKey::Unicode('\n')) => return Response::Activated,
+            Event::Click { row, .. } => {
+                let index = self.scroll + (row - self.rect.row);
+                if index >= self.items.len() {
+                    return Response::Ignored;
+                }
+                if index == self.selected {
+                    return Response::Activated;
+                }
+                self.select(index);
+            }
+            _ => return Response::Ignored,
+        }
+        Response::Handled
+    }
+}
+
+/// A screenful of widgets with keyboard focus. Tab moves the focus to the
+/// next focusable widget, clicks focus whatever is under the pointer.
+pub struct Form<'a, 'w> {
+    widgets: &'a mut [&'w mut dyn Widget],
+    focus: Option<usize>,
+    pointer: Option<(usize, usize)>,
+    // Whether the left button was down in the last mouse event
+    pressed: bool,
+}
+
+impl<'a, 'w> Form<'a, 'w> {
+    pub fn new(widgets: &'a mut [&'w mut dyn Widget]) -> Form<'a, 'w> {
+        let focus = widgets.iter().position(|widget| widget.focusable());
+        Form { widgets, focus, pointer: None, pressed: false }
+    }
+
+    pub fn focused(&self) -> Option<usize> {
+        self.focus
+    }
+
+    fn focus_next(&mut self) {
+        let count = self.widgets.len();
+        let start = self.focus.map_or(0, |focus| focus + 1);
+        self.focus = (0..count)
+            .map(|offset| (start + offset) % count)
+            .find(|&index| self.widgets[index].focusable())
+            .or(self.focus);
+    }
+
+    /// Route one event; returns the index of the widget it activated
+    pub fn handle(&mut self, event: Event) -> Option<usize> {
+        let target = match event {
+            Event::Key(DecodedKey::Unicode('\t')) => {
+                self.focus_next();
+                return None;
+            }
+            Event::Key(_) => self.focus?,
+            Event::Click { row, col } => {
+                let index = self.widgets.iter().position(|widget| widget.rect().contains(row, col))?;
+                if self.widgets[index].focusable() {
+                    self.focus = Some(index);
+                }
+                index
+            }
+        };
+        match self.widgets[target].handle(event) {
+            Response::Activated => Some(target),
+            Response::Handled | Response::Ignored => None,
+        }
+    }
+
+    pub fn draw(&self) {
+        for row in 0..BUFFER_HEIGHT {
+            put_str(row, 0, BUFFER_WIDTH, "", Color::White, BACKGROUND);
+        }
+        for (index, widget) in self.widgets.iter().enumerate() {
+            widget.draw(self.focus == Some(index));
+        }
+        if let Some((row, col)) = self.pointer {
+            vga_buffer::write_cell(row, col, vga_buffer::read_cell(row, col), Color::Black, Color::Yellow);
+        }
+    }
+
+    /// Draw the form and handle input until a widget is activated
+    /// (returning its index) or Esc is pressed (returning None)
+    pub fn run(&mut self) -> Option<usize> {
+        loop {
+            self.draw();
+            match self.next_event() {
+                Event::Key(DecodedKey::Unicode(ESCAPE)) => return None,
+                event => {
+                    if let Some(index) = self.handle(event) {
+                        return Some(index);
+                    }
+                }
+            }
+        }
+    }
+
+    // Wait for a key or click. Pointer motion is handled here, since only
+    // the form draws the pointer.
+    fn next_event(&mut self) -> Event {
+        loop {
+            crate::timer::run_expired();
+            if let Some(key) = keyboard::try_read_key() {
+                return Event::Key(key);
+            }
+            if let Some(event) = self.poll_mouse() {
+                return event;
+            }
+
+            // Same dance as keyboard::read_key so input can't slip in before hlt
+            interrupts::disable();
+            if keyboard::input_pending() || mouse_pending() {
+                interrupts::enable();
+            } else {
+                crate::timer::idle();
+            }
+        }
+    }
+
+    #[cfg(feature = "mouse")]
+    fn poll_mouse(&mut self) -> Option<Event> {
+        while let Some(event) = crate::mouse::try_read_event() {
+            let clicked = event.buttons.left && !self.pressed;
+            let moved = self.pointer != Some((event.row, event.col));
+            self.pressed = event.buttons.left;
+            self.pointer = Some((event.row, event.col));
+            if clicked {
+                return Some(Event::Click { row: event.row, col: event.col });
+            }
+            if moved {
+                self.draw();
+            }
+        }
+        None
+    }
+
+    #[cfg(not(feature = "mouse"))]
+    fn poll_mouse(&mut self) -> Option<Event> {
+        None
+    }
+}
+
+#[cfg(feature = "mouse")]
+fn mouse_pending() -> bool {
+    crate::mouse::event_pending()
+}
+
+#[cfg(not(feature = "mouse"))]
+fn mouse_pending() -> bool {
+    false
+}
+
+/// Clear the screen back to the console's colors, for when a tool is done
+pub fn leave() {
+    vga_buffer::clear_screen();
+}
+
+/// TESTS
+
+#[test_case]
+fn test_tab_cycles_focus() {
+    let mut label = Label { row: 0, col: 0, text: "title" };
+    let mut ok = Button { row: 2, col: 0, label: "OK" };
+    let mut cancel = Button { row: 2, col: 10, label: "Cancel" };
+    let mut widgets: [&mut dyn Widget; 3] = [&mut label, &mut ok, &mut cancel];
+    let mut form = Form::new(&mut widgets);
+
+    // Labels can't take the focus
+    assert_eq!(form.focused(), Some(1));
+    assert_eq!(form.handle(Event::Key(DecodedKey::Unicode('\t'))), None);
+    assert_eq!(form.focused(), Some(2));
+    assert_eq!(form.handle(Event::Key(DecodedKey::Unicode('\t'))), None);
+    assert_eq!(form.focused(), Some(1));
+    assert_eq!(form.handle(Event::Key(DecodedKey::Unicode('\n'))), Some(1));
+}
+
+#[test_case]
+fn test_click_focuses_and_activates() {
+    let mut ok = Button { row: 2, col: 0, label: "OK" };
+    let mut cancel = Button { row: 2, col: 10, label: "Cancel" };
+    let mut widgets: [&mut dyn Widget; 2] = [&mut ok, &mut cancel];
+    let mut form = Form::new(&mut widgets);
+
+    assert_eq!(form.handle(Event::Click { row: 2, col: 12 }), Some(1));
+    assert_eq!(form.focused(), Some(1));
+    // Nothing there
+    assert_eq!(form.handle(Event::Click { row: 5, col: 5 }), None);
+}
+
+#[test_case]
+fn test_list_box_selection() {
+    let items = ["one", "two", "three", "four"];
+    let rect = Rect { row: 0, col: 0, width: 10, height: 2 };
+    let mut list = ListBox::new(rect, &items);
+
+    assert_eq!(list.handle(Event::Key(DecodedKey::RawKey(KeyCode::ArrowUp))), Response::Handled);
+    assert_eq!(list.selected(), Some(0));
+    list.handle(Event::Key(DecodedKey::RawKey(KeyCode::End)));
+    assert_eq!(list.selected(), Some(3));
+    // Scrolled so the last two items are showing; the top row is "three"
+    assert_eq!(list.handle(Event::Click { row: 0, col: 0 }), Response::Handled);
+    assert_eq!(list.selected(), Some(2));
+    assert_eq!(list.handle(Event::Click { row: 0, col: 0 }), Response::Activated);
+    assert_eq!(list.handle(Event::Key(DecodedKey::Unicode('\n'))), Response::Activated);
+}
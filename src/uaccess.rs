@@ -0,0 +1,115 @@
+use core::arch::asm;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::cpu;
+use crate::memory::paging;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// First address past the lower (user) half of the canonical address space
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Lifts SMAP while it's alive: STAC on creation, CLAC on drop
+pub struct UserAccessGuard {
+    _private: (),
+}
+
+impl UserAccessGuard {
+    pub fn new() -> UserAccessGuard {
+        if cpu::smap_enabled() {
+            // Not nomem: accesses to user memory mustn't move out from
+            // between this and the CLAC
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        UserAccessGuard { _private: () }
+    }
+}
+
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        if cpu::smap_enabled() {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
+
+/// Run `f` inside a user_access scope; SMAP is back on as soon as it returns
+pub fn user_access<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = UserAccessGuard::new();
+    f()
+}
+
+/// Make sure `[addr, addr + len)` lies entirely in user space
+pub fn check_user_range(addr: VirtAddr, len: usize) -> Result<(), &'static str> {
+    let end = addr
+        .as_u64()
+        .checked_add(len as u64)
+        .ok_or("user range overflows")?;
+    if end > USER_SPACE_END {
+        return Err("address range is not in user space");
+    }
+    Ok(())
+}
+
+/// Make sure every page `[addr, addr + len)` touches is in user space and
+/// mapped for ring 3, and writable too if `write` is set
+pub fn check_user_mapped(addr: VirtAddr, len: usize, write: bool) -> Result<(), &'static str> {
+    check_user_range(addr, len)?;
+    if len == 0 {
+        return Ok(());
+    }
+    let mut needed = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        needed |= PageTableFlags::WRITABLE;
+    }
+    let first = addr.align_down(PAGE_SIZE).as_u64();
+    let last = (addr + (len - 1) as u64).align_down(PAGE_SIZE).as_u64();
+    for page in (first..=last).step_by(PAGE_SIZE as usize) {
+        match paging::page_flags(VirtAddr::new(page)) {
+            Some(flags) if flags.contains(needed) => {}
+            _ => return Err("user range isn't mapped"),
+        }
+    }
+    Ok(())
+}
+
+/// Copy `dst.len()` bytes from the user pointer `src` into kernel memory.
+///
+/// Unsafe because the caller must make sure the user range is mapped.
+pub unsafe fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), &'static str> {
+    check_user_range(src, dst.len())?;
+    user_access(|| {
+        core::ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr(), dst.len());
+    });
+    Ok(())
+}
+
+/// Copy `src` out to the user pointer `dst`.
+///
+/// Unsafe because the caller must make sure the user range is mapped and writable.
+pub unsafe fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), &'static str> {
+    check_user_range(dst, src.len())?;
+    user_access(|| {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr::<u8>(), src.len());
+    });
+    Ok(())
+}
+
+/// TESTS
+
+#[test_case]
+fn test_copy_from_user_rejects_kernel_address() {
+    let mut buf = [0u8; 8];
+    let kernel_addr = VirtAddr::new(0xffff_8000_0000_0000);
+    assert!(unsafe { copy_from_user(&mut buf, kernel_addr) }.is_err());
+}
+
+#[test_case]
+fn test_user_range_overflow() {
+    let addr = VirtAddr::new(USER_SPACE_END - 4);
+    assert!(check_user_range(addr, 4).is_ok());
+    assert!(check_user_range(addr, 5).is_err());
+}
@@ -0,0 +1,203 @@
+//! Syscall arguments, checked before the syscall runs. SPECS says what
+//! each syscall's are: pointers to how many bytes it reads or writes, fds,
+//! paths, numbers with a limit. `check` turns a call away with the
+//! KernelError the syscall would have failed with, and audits it: a line
+//! in the log ring, which dmesg shows (not printed, since a program can
+//! make as many as it likes), and heorot_syscall_rejected_total. What only
+//! the syscall can tell, like whether a timer's fd is open, it still
+//! checks itself.
+
+use x86_64::VirtAddr;
+use crate::error::KernelError;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter, Metric, Value};
+use crate::uaccess;
+use super::signal::ITIMER_PROF;
+use super::syscall::{CLOCK_MONOTONIC, FDS, FUTEX_WAKE, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN, MAX_PATH, MAX_POLL,
+    RUSAGE_THREAD};
+use super::{caps, trace};
+
+// How one argument is checked
+#[derive(Clone, Copy)]
+enum Arg {
+    // Left to the syscall
+    Any,
+    // A pointer to bytes the syscall reads, or writes, or either of those
+    // or null for none
+    In(Len),
+    Out(Len),
+    MaybeIn(Len),
+    MaybeOut(Len),
+    // Somewhere for the program to jump to, which has to be its own
+    Code,
+    // As many bytes as the next argument says, up to MAX_PATH
+    Path,
+    Fd,
+    // And what's wrong with anything else
+    AtMost(u64, &'static str),
+    AtLeast(u64, &'static str),
+    // With no bits but these
+    Flags(u64, &'static str),
+}
+
+// How many bytes a pointer's good for
+#[derive(Clone, Copy)]
+enum Len {
+    Bytes(usize),
+    // As many as another argument says, or that many u64s
+    Arg(usize),
+    Words(usize),
+}
+
+// Each syscall's arguments, by number
+const SPECS: [[Arg; 3]; 24] = {
+    use Arg::*;
+    use Len::*;
+    [
+        // exit
+        [Any, Any, Any],
+        // write
+        [In(Arg(1)), Any, Any],
+        // yield
+        [Any, Any, Any],
+        // grow
+        [Any, Any, Any],
+        // getrusage
+        [AtMost(RUSAGE_THREAD, "no such rusage target"), Out(Bytes(40)), Any],
+        // times
+        [Out(Bytes(32)), Any, Any],
+        // nanosleep
+        [In(Bytes(16)), MaybeOut(Bytes(16)), Any],
+        // setitimer
+        [AtMost(ITIMER_PROF as u64, "no such timer"), MaybeIn(Bytes(32)), MaybeOut(Bytes(32))],
+        // sigaction
+        [Any, Code, Any],
+        // sigreturn
+        [Any, Any, Any],
+        // sched_setaffinity
+        [Any, Any, In(Arg(1))],
+        // sched_getaffinity
+        [Any, AtLeast(8, "mask too short"), Out(Bytes(8))],
+        // stat
+        [Path, Any, Out(Bytes(40))],
+        // flock
+        [Path, Any, Flags(LOCK_SH | LOCK_EX | LOCK_NB | LOCK_UN, "no such lock operation")],
+        // poll, whose pollfds are written back
+        [Out(Words(1)), AtMost(MAX_POLL as u64, "too many fds"), Any],
+        // read
+        [Fd, Out(Arg(2)), Any],
+        // timerfd
+        [In(Bytes(32)), Any, Any],
+        // close
+        [Fd, Any, Any],
+        // uname
+        [Out(Arg(1)), Any, Any],
+        // gettimeofday
+        [Out(Bytes(16)), Any, Any],
+        // clock_gettime
+        [AtMost(CLOCK_MONOTONIC, "no such clock"), Out(Bytes(16)), Any],
+        // futex
+        [In(Bytes(4)), AtMost(FUTEX_WAKE, "no such futex operation"), Any],
+        // capget
+        [Any, Any, Any],
+        // capdrop
+        [Flags(caps::ALL, "no such capability"), Any, Any],
+    ]
+};
+
+static REJECTED: Counter = Counter::new();
+
+fn bytes(len: Len, args: [u64; 3]) -> Result<usize, KernelError> {
+    let len = match len {
+        Len::Bytes(len) => Some(len as u64),
+        Len::Arg(index) => Some(args[index]),
+        Len::Words(index) => args[index].checked_mul(8),
+    };
+    len.and_then(|len| usize::try_from(len).ok()).ok_or(KernelError::BadAddress)
+}
+
+fn pointer(addr: u64, len: usize, write: bool) -> Result<(), KernelError> {
+    let start = VirtAddr::try_new(addr).map_err(|_| KernelError::BadAddress)?;
+    uaccess::check_user_mapped(start, len, write).map_err(|_| KernelError::BadAddress)
+}
+
+// Numbers first, since a pointer's length can be one of them
+fn check_one(arg: Arg, index: usize, args: [u64; 3], pointers: bool) -> Result<(), KernelError> {
+    let value = args[index];
+    match arg {
+        Arg::Any => Ok(()),
+        Arg::Fd if !pointers && value >= FDS as u64 => Err(KernelError::BadFd),
+        Arg::AtMost(most, message) if !pointers && value > most => Err(KernelError::InvalidArgument(message)),
+        Arg::AtLeast(least, message) if !pointers && value < least => Err(KernelError::InvalidArgument(message)),
+        Arg::Flags(flags, message) if !pointers && value & !flags != 0 => Err(KernelError::InvalidArgument(message)),
+        Arg::Path if !pointers && args[index + 1] > MAX_PATH as u64 => Err(KernelError::NameTooLong),
+        Arg::Code if !pointers && value >= uaccess::USER_SPACE_END => Err(KernelError::BadAddress),
+        _ if !pointers => Ok(()),
+        Arg::In(len) => pointer(value, bytes(len, args)?, false),
+        Arg::Out(len) => pointer(value, bytes(len, args)?, true),
+        Arg::MaybeIn(_) | Arg::MaybeOut(_) if value == 0 => Ok(()),
+        Arg::MaybeIn(len) => pointer(value, bytes(len, args)?, false),
+        Arg::MaybeOut(len) => pointer(value, bytes(len, args)?, true),
+        Arg::Path => pointer(value, args[index + 1] as usize, false),
+        _ => Ok(()),
+    }
+}
+
+/// Check syscall `number`'s arguments before it runs. Numbers no syscall
+/// has are left for the dispatch to turn away.
+pub(super) fn check(number: u64, args: [u64; 3]) -> Result<(), KernelError> {
+    let specs = match SPECS.get(number as usize) {
+        Some(specs) => specs,
+        None => return Ok(()),
+    };
+    for pointers in [false, true] {
+        for (index, &arg) in specs.iter().enumerate() {
+            if let Err(error) = check_one(arg, index, args, pointers) {
+                audit(number, index, args[index], error);
+                return Err(error);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn audit(number: u64, index: usize, value: u64, error: KernelError) {
+    REJECTED.increment();
+    log::keep(Level::Warn, module_path!(), format_args!("audit: {} turned away, argument {} ({:#x}): {}",
+        trace::name(number).unwrap_or("?"), index, value, error));
+}
+
+pub(super) fn init() {
+    let metric = Metric {
+        name: "heorot_syscall_rejected_total",
+        help: "Syscalls turned away for their arguments",
+        value: Value::Counter(&REJECTED),
+    };
+    if let Err(message) = metrics::register(metric) {
+        crate::log::warn!("args: {}", message);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_turns_away_bad_arguments() {
+    use super::syscall::*;
+
+    let before = REJECTED.get();
+    assert_eq!(check(SYS_GETRUSAGE, [5, 0, 0]), Err(KernelError::InvalidArgument("no such rusage target")));
+    assert_eq!(check(SYS_WRITE, [0xffff_8000_0000_0000, 16, 0]), Err(KernelError::BadAddress));
+    assert_eq!(check(SYS_STAT, [super::CODE_START, MAX_PATH as u64 + 1, 0]), Err(KernelError::NameTooLong));
+    assert_eq!(check(SYS_CLOSE, [FDS as u64, 0, 0]), Err(KernelError::BadFd));
+    // Nothing's mapped for a program under the test runner
+    assert_eq!(check(SYS_TIMES, [super::CODE_START, 0, 0]), Err(KernelError::BadAddress));
+    assert_eq!(REJECTED.get(), before + 5);
+    let contents = log::contents();
+    assert!(core::str::from_utf8(&contents).unwrap().contains("audit: close turned away, argument 0"));
+
+    // Empty, null where that's allowed, and unknown numbers all get through
+    assert_eq!(check(SYS_WRITE, [super::CODE_START, 0, 0]), Ok(()));
+    assert_eq!(check(SYS_SETITIMER, [0, 0, 0]), Ok(()));
+    assert_eq!(check(SPECS.len() as u64, [0, 0, 0]), Ok(()));
+    assert!(trace::name(SPECS.len() as u64 - 1).is_some() && trace::name(SPECS.len() as u64).is_none());
+}
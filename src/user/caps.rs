@@ -0,0 +1,143 @@
+//! Capabilities for the program in ring 3: a bitmask of what it may do
+//! beyond looking after itself, checked as each syscall comes in, after its
+//! arguments. A program starts with the set programs are granted, all of
+//! them unless `caps=` on the command line or the shell's `caps` says
+//! otherwise, and can drop any of them but never get one back. One program
+//! runs at a time, and there's no fork or exec from ring 3 yet, so that
+//! start is all the inheritance there is. Syscalls turned away for want of
+//! one are audited like those args turns away, and counted in
+//! heorot_syscall_denied_total.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::cmdline;
+use crate::error::KernelError;
+use crate::log::{self, Level};
+use crate::metrics::{self, Counter, Metric, Value};
+use crate::sched;
+use super::syscall::{SYS_FLOCK, SYS_READ, SYS_SCHED_SETAFFINITY, SYS_STAT, STDIN};
+use super::trace;
+
+/// Look at the filesystem: stat and flock
+pub const CAP_FS: u64 = 1 << 0;
+/// Change threads other than its own: sched_setaffinity on them
+pub const CAP_SCHED: u64 = 1 << 1;
+/// Read what's typed at the console
+pub const CAP_KEYBOARD: u64 = 1 << 2;
+pub const ALL: u64 = CAP_FS | CAP_SCHED | CAP_KEYBOARD;
+
+/// Each capability's name, as `parse` takes it
+pub const NAMES: [(&str, u64); 3] = [("fs", CAP_FS), ("sched", CAP_SCHED), ("keyboard", CAP_KEYBOARD)];
+
+// What programs start with, and what the one running (or the last) has
+static GRANTED: AtomicU64 = AtomicU64::new(ALL);
+static CAPS: AtomicU64 = AtomicU64::new(ALL);
+static DENIED: Counter = Counter::new();
+
+/// What programs start with
+pub fn granted() -> u64 {
+    GRANTED.load(Ordering::Relaxed)
+}
+
+/// Have programs start with `caps` from the next one run
+pub fn set_granted(caps: u64) {
+    GRANTED.store(caps & ALL, Ordering::Relaxed);
+}
+
+/// What the program running has, or the last one had when it ended
+pub fn current() -> u64 {
+    CAPS.load(Ordering::Relaxed)
+}
+
+/// Capabilities by name, comma-separated, or `all` or `none`
+pub fn parse(text: &str) -> Result<u64, &'static str> {
+    match text {
+        "all" => return Ok(ALL),
+        "none" => return Ok(0),
+        _ => {}
+    }
+    text.split(',').try_fold(0, |caps, name| {
+        NAMES.iter().find(|&&(known, _)| known == name).map(|&(_, cap)| caps | cap).ok_or("no such capability")
+    })
+}
+
+/// The names of `caps`, comma-separated, or `none`
+pub fn names(caps: u64) -> String {
+    let names: Vec<&str> = NAMES.iter().filter(|&&(_, cap)| caps & cap != 0).map(|&(name, _)| name).collect();
+    if names.is_empty() { String::from("none") } else { names.join(",") }
+}
+
+// A program's starting
+pub(super) fn start() {
+    CAPS.store(granted(), Ordering::Relaxed);
+}
+
+// Drop `caps` for good; what's left
+pub(super) fn give_up(caps: u64) -> u64 {
+    CAPS.fetch_and(!caps, Ordering::Relaxed) & !caps
+}
+
+// The capability syscall `number` needs with these arguments, if any
+fn needed(number: u64, args: [u64; 3]) -> Option<u64> {
+    match number {
+        SYS_STAT | SYS_FLOCK => Some(CAP_FS),
+        SYS_SCHED_SETAFFINITY if args[0] != 0 && args[0] != sched::current().as_u64() => Some(CAP_SCHED),
+        SYS_READ if args[0] as i32 == STDIN => Some(CAP_KEYBOARD),
+        _ => None,
+    }
+}
+
+/// Turn syscall `number` away if the program lacks a capability it needs
+pub(super) fn check(number: u64, args: [u64; 3]) -> Result<(), KernelError> {
+    match needed(number, args) {
+        Some(cap) if current() & cap == 0 => {
+            DENIED.increment();
+            log::keep(Level::Warn, module_path!(), format_args!("audit: {} turned away, without {}",
+                trace::name(number).unwrap_or("?"), names(cap)));
+            Err(KernelError::NotPermitted)
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(super) fn init() {
+    if let Some(text) = cmdline::get("caps") {
+        match parse(text) {
+            Ok(caps) => set_granted(caps),
+            Err(message) => crate::log::warn!("caps: {}: {}", message, text),
+        }
+    }
+    let metric = Metric {
+        name: "heorot_syscall_denied_total",
+        help: "Syscalls turned away for a capability the program didn't have",
+        value: Value::Counter(&DENIED),
+    };
+    if let Err(message) = metrics::register(metric) {
+        crate::log::warn!("caps: {}", message);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_checks_what_the_program_has() {
+    assert_eq!(parse("fs,keyboard"), Ok(CAP_FS | CAP_KEYBOARD));
+    assert_eq!(parse("none"), Ok(0));
+    assert_eq!(parse("fs,root"), Err("no such capability"));
+    assert_eq!(names(CAP_SCHED | CAP_KEYBOARD), "sched,keyboard");
+
+    let before = current();
+    CAPS.store(ALL & !CAP_FS & !CAP_SCHED, Ordering::Relaxed);
+    let denied = DENIED.get();
+    assert_eq!(check(SYS_STAT, [0, 0, 0]), Err(KernelError::NotPermitted));
+    // Its own thread needs nothing; another does
+    assert_eq!(check(SYS_SCHED_SETAFFINITY, [0, 8, 0]), Ok(()));
+    assert_eq!(check(SYS_SCHED_SETAFFINITY, [sched::current().as_u64(), 8, 0]), Ok(()));
+    assert_eq!(check(SYS_SCHED_SETAFFINITY, [u64::MAX, 8, 0]), Err(KernelError::NotPermitted));
+    assert_eq!(check(SYS_READ, [STDIN as u64, 0, 4]), Ok(()));
+    assert_eq!(DENIED.get(), denied + 2);
+    assert_eq!(give_up(CAP_KEYBOARD), 0);
+    assert_eq!(check(SYS_READ, [STDIN as u64, 0, 4]), Err(KernelError::NotPermitted));
+    CAPS.store(before, Ordering::Relaxed);
+}
@@ -0,0 +1,134 @@
+//! Futexes: a program waits in the kernel until a word of its memory may
+//! have changed, and whoever changes it wakes it, so its locks needn't
+//! spin. Waiters are queued in a table hashed by the word's physical
+//! address, which is the same in every address space the word is mapped
+//! into. Only one program runs at a time, so there's no second one to wake
+//! it yet: its waits end with a signal, as ITIMER_REAL can time them.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+use crate::error::KernelError;
+use crate::sched::{self, ThreadId};
+use crate::timer;
+
+const BUCKETS: usize = 16;
+
+struct Waiter {
+    key: PhysAddr,
+    thread: ThreadId,
+}
+
+const EMPTY: Mutex<Vec<Waiter>> = Mutex::new(Vec::new());
+// Waiters by key, oldest first. Never touched by interrupt handlers.
+static TABLE: [Mutex<Vec<Waiter>>; BUCKETS] = [EMPTY; BUCKETS];
+
+fn bucket(key: PhysAddr) -> &'static Mutex<Vec<Waiter>> {
+    // Words are four bytes; the top bits of the product are the best mixed
+    let hash = (key.as_u64() >> 2).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 60;
+    &TABLE[hash as usize % BUCKETS]
+}
+
+// Take `thread` off `key`'s queue; false if a wake already has
+fn dequeue(key: PhysAddr, thread: ThreadId) -> bool {
+    let mut waiters = bucket(key).lock();
+    match waiters.iter().position(|waiter| waiter.key == key && waiter.thread == thread) {
+        Some(index) => {
+            waiters.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+fn queued(key: PhysAddr, thread: ThreadId) -> bool {
+    bucket(key).lock().iter().any(|waiter| waiter.key == key && waiter.thread == thread)
+}
+
+/// Wait on `key` until `wake`, if `unchanged` says the word still holds
+/// what the caller saw; it's asked with the queue locked, so a wake after
+/// the word changes can't be missed. WouldBlock if it doesn't, Interrupted
+/// if `interrupted` says so first.
+pub(super) fn wait(key: PhysAddr, unchanged: impl FnOnce() -> bool, interrupted: impl Fn() -> bool)
+    -> Result<(), KernelError>
+{
+    let me = sched::current();
+    {
+        let mut waiters = bucket(key).lock();
+        if !unchanged() {
+            return Err(KernelError::WouldBlock);
+        }
+        waiters.push(Waiter { key, thread: me });
+    }
+    loop {
+        timer::run_expired();
+        if !queued(key, me) {
+            return Ok(());
+        }
+        if interrupted() {
+            // Unless a wake has just taken it off, and counted it
+            return if dequeue(key, me) { Err(KernelError::Interrupted) } else { Ok(()) };
+        }
+        interrupts::disable();
+        if !queued(key, me) {
+            interrupts::enable();
+            return Ok(());
+        }
+        timer::idle();
+    }
+}
+
+/// Wake up to `count` of `key`'s waiters, oldest first; how many that was
+pub(super) fn wake(key: PhysAddr, count: usize) -> usize {
+    let mut waiters = bucket(key).lock();
+    let mut woken = 0;
+    waiters.retain(|waiter| {
+        if woken == count || waiter.key != key {
+            return true;
+        }
+        woken += 1;
+        false
+    });
+    woken
+}
+
+/// TESTS
+
+#[test_case]
+fn test_wait_and_wake() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use crate::time::{Duration, Instant};
+
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+    fn key() -> PhysAddr {
+        PhysAddr::new(0x1000)
+    }
+    fn waiter() {
+        if wait(key(), || true, || false).is_ok() {
+            WOKEN.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // The word's changed already, or a signal's come
+    assert_eq!(wait(key(), || false, || false), Err(KernelError::WouldBlock));
+    assert_eq!(wait(key(), || true, || true), Err(KernelError::Interrupted));
+    assert_eq!(wake(key(), 1), 0);
+
+    let start = Instant::now();
+    for _ in 0..2 {
+        sched::spawn("futex-test", waiter).unwrap();
+    }
+    while bucket(key()).lock().len() < 2 {
+        assert!(start.elapsed() < Duration::from_secs(1));
+        sched::yield_now();
+    }
+    // A word at another address isn't woken with it
+    assert_eq!(wake(PhysAddr::new(0x2000), 2), 0);
+    assert_eq!(wake(key(), 1), 1);
+    assert_eq!(wake(key(), 4), 1);
+    while WOKEN.load(Ordering::SeqCst) < 2 {
+        assert!(start.elapsed() < Duration::from_secs(1));
+        sched::yield_now();
+    }
+}
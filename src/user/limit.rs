@@ -0,0 +1,102 @@
+//! Memory accounting for the program in ring 3: every page `map` gives it
+//! is charged to it, and `unmap` takes the charge back. Past its limit,
+//! `memlimit=` on the command line in KiB (none without it), nothing more
+//! is given, and a program asking for more with the grow syscall is killed
+//! with Exit::OutOfMemory instead. Only one program runs at a time, so its
+//! charge is the one count, started over by each Claim. Page tables aren't
+//! charged.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::cmdline;
+use crate::memory::FRAME_SIZE;
+
+/// What charge fails with
+pub const OVER_LIMIT: &str = "over its memory limit";
+const NO_LIMIT: usize = usize::MAX;
+
+// In pages
+static LIMIT: AtomicUsize = AtomicUsize::new(NO_LIMIT);
+static CHARGED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static KILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// The current and last program's use, in pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub charged: usize,
+    pub peak: usize,
+    /// None without a limit
+    pub limit: Option<usize>,
+    /// Programs killed for going over it since boot
+    pub kills: usize,
+}
+
+pub fn usage() -> Usage {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    Usage {
+        charged: CHARGED.load(Ordering::Relaxed),
+        peak: PEAK.load(Ordering::Relaxed),
+        limit: if limit == NO_LIMIT { None } else { Some(limit) },
+        kills: KILLS.load(Ordering::Relaxed),
+    }
+}
+
+/// Limit programs to `kib` KiB (rounded down to whole pages), or None for
+/// as much as there is
+pub fn set_limit(kib: Option<u64>) {
+    let pages = kib.map_or(NO_LIMIT, |kib| (kib * 1024 / FRAME_SIZE) as usize);
+    LIMIT.store(pages, Ordering::Relaxed);
+}
+
+/// Charge `pages` to the program, unless that takes it over its limit
+pub(crate) fn charge(pages: usize) -> Result<(), &'static str> {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    let charged = CHARGED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |charged| {
+            charged.checked_add(pages).filter(|&charged| charged <= limit)
+        })
+        .map_err(|_| OVER_LIMIT)?;
+    PEAK.fetch_max(charged + pages, Ordering::Relaxed);
+    Ok(())
+}
+
+pub(crate) fn uncharge(pages: usize) {
+    let _ = CHARGED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |charged| Some(charged.saturating_sub(pages)));
+}
+
+// A program killed for going over
+pub(super) fn killed() {
+    KILLS.fetch_add(1, Ordering::Relaxed);
+}
+
+// A program's starting, or has ended and had all its memory freed
+pub(super) fn reset() {
+    CHARGED.store(0, Ordering::Relaxed);
+}
+
+pub(super) fn start() {
+    reset();
+    PEAK.store(0, Ordering::Relaxed);
+}
+
+pub(super) fn init() {
+    if let Some(kib) = cmdline::get("memlimit").and_then(|kib| kib.parse().ok()) {
+        set_limit(Some(kib));
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_charge_stops_at_the_limit() {
+    let before = usage();
+    set_limit(Some(CHARGED.load(Ordering::Relaxed) as u64 * FRAME_SIZE / 1024 + 8));
+    charge(1).unwrap();
+    assert_eq!(charge(2), Err(OVER_LIMIT));
+    charge(1).unwrap();
+    assert_eq!(charge(1), Err(OVER_LIMIT));
+    assert_eq!(usage().charged, before.charged + 2);
+    uncharge(2);
+    assert_eq!(usage().charged, before.charged);
+    LIMIT.store(before.limit.unwrap_or(NO_LIMIT), Ordering::Relaxed);
+}
@@ -0,0 +1,394 @@
+//! Running code in ring 3. One program at a time: entered with `iretq` and
+//! left again through `exit` or by faulting, either of which lands back
+//! where `enter` was called. Its syscalls are in `syscall`. `run` loads code
+//! into the kernel's own page tables; crate::elf gives a program an address
+//! space from `space`. What memory it has is charged to it in `limit`, and
+//! the CPU time it uses is told apart from the kernel's in crate::sched.
+//! `trace` prints its syscalls as it makes them, and `futex` queues it
+//! while it waits on a word of its memory. `caps` says what it may do.
+//!
+//! Each program's stack and heap are put a random number of pages below
+//! STACK_TOP and above HEAP_START, unless `noaslr` is on the command line.
+//! Its code goes where it was linked for.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::{cmdline, cpu, entropy, gdt, sched};
+use crate::sched::CpuTimes;
+use crate::time::Duration;
+use crate::memory::{self, paging, FRAME_SIZE};
+
+pub mod args;
+pub mod caps;
+pub mod futex;
+pub mod limit;
+pub mod signal;
+pub mod space;
+pub mod syscall;
+pub mod trace;
+
+/// Where `run` loads a program's code
+pub const CODE_START: u64 = 0x0000_1000_0000_0000;
+/// The highest the top of a program's stack can be
+pub const STACK_TOP: u64 = 0x0000_1000_8000_0000;
+/// And the lowest its bottom can be
+pub const STACK_START: u64 = STACK_TOP - STACK_SLIDE - STACK_PAGES as u64 * FRAME_SIZE;
+/// The lowest the pages a program grows its heap by can start
+pub const HEAP_START: u64 = CODE_START + 0x4000_0000;
+/// How far below STACK_TOP and above HEAP_START they can be put
+pub const STACK_SLIDE: u64 = 0x1000_0000;
+pub const HEAP_SLIDE: u64 = 0x1000_0000;
+const STACK_PAGES: usize = 4;
+const MAX_CODE_PAGES: usize = 16;
+
+/// How a program came back to the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// It called exit with this code
+    Exited(i64),
+    /// It was killed for causing an exception
+    Faulted { vector: u8, error_code: Option<u64>, instruction_pointer: VirtAddr },
+    /// It was killed for asking for memory past its limit
+    OutOfMemory,
+    /// It was killed by a signal it had no handler for
+    Signaled(u8),
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static EXIT: Mutex<Option<Exit>> = Mutex::new(None);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// How far the program has grown its heap
+static HEAP_PAGES: AtomicUsize = AtomicUsize::new(0);
+// Where the program's stack tops out and its heap starts, picked by
+// Claim::new
+static STACK: AtomicU64 = AtomicU64::new(STACK_TOP);
+static HEAP: AtomicU64 = AtomicU64::new(HEAP_START);
+// The running thread's CPU time when the program started
+// Only ever locked with interrupts off, so a handler can't find it held
+static START_TIMES: Mutex<CpuTimes> = Mutex::new(CpuTimes { user: Duration::ZERO, kernel: Duration::ZERO });
+
+// The kernel stack pointer when ring 3 was entered: the callee-saved
+// registers are just above it, and syscalls and interrupts out of ring 3
+// run on the stack below it
+#[no_mangle]
+static mut HEOROT_KERNEL_RSP: u64 = 0;
+
+extern "C" {
+    fn heorot_enter_user(entry: u64, stack: u64, code_selector: u64, data_selector: u64, kernel_stack_slot: *mut VirtAddr);
+    fn heorot_user_return() -> !;
+}
+
+// enter_user(entry, stack, cs, ss, kernel_stack_slot): save the callee-saved
+// registers and flags, make this stack the one to come back to, and iretq
+// into ring 3. user_return abandons whatever's below and returns from
+// enter_user as if it had been an ordinary call.
+global_asm!(
+    ".global heorot_enter_user",
+    "heorot_enter_user:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "cli",
+    "mov [rip + HEOROT_KERNEL_RSP], rsp",
+    "mov [r8], rsp",
+    "push rcx",
+    "push rsi",
+    // Interrupts on in ring 3
+    "push 0x202",
+    "push rdx",
+    "push rdi",
+    // Nothing of the kernel's left in registers
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+    ".global heorot_user_return",
+    "heorot_user_return:",
+    "mov rsp, [rip + HEOROT_KERNEL_RSP]",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+/// Map `pages` fresh, zeroed pages from `start` on, reachable from ring 3
+/// and charged to the program. `flags` adds to PRESENT and USER_ACCESSIBLE.
+pub fn map(start: VirtAddr, pages: usize, flags: PageTableFlags) -> Result<(), &'static str> {
+    let first: Page<Size4KiB> = Page::from_start_address(start).map_err(|_| "unaligned user mapping")?;
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    for page in Page::range(first, first + pages as u64) {
+        limit::charge(1)?;
+        let frame = match memory::allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                limit::uncharge(1);
+                return Err(memory::OUT_OF_MEMORY);
+            }
+        };
+        unsafe {
+            paging::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, FRAME_SIZE as usize);
+            // Whatever was mapped there is the caller's to have cleared
+            if let Err(message) = paging::map_page(page, frame, flags) {
+                memory::deallocate_frame(frame);
+                limit::uncharge(1);
+                return Err(message);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Undo `map`, freeing the frames; pages that aren't mapped are skipped
+pub fn unmap(start: VirtAddr, pages: usize) {
+    let first: Page<Size4KiB> = Page::containing_address(start);
+    for page in Page::range(first, first + pages as u64) {
+        if let Ok(frame) = unsafe { paging::unmap_page(page) } {
+            unsafe { memory::deallocate_frame(frame) };
+            limit::uncharge(1);
+        }
+    }
+}
+
+/// Whether programs' stacks and heaps go at random addresses: unless
+/// `noaslr` is on the command line
+pub fn aslr() -> bool {
+    cmdline::get("noaslr").is_none()
+}
+
+// A random number of pages' worth below `range`, or none without aslr
+fn slide(range: u64) -> u64 {
+    if !aslr() {
+        return 0;
+    }
+    entropy::random_u64() % (range / FRAME_SIZE) * FRAME_SIZE
+}
+
+/// Where the program running (or the last one) has the top of its stack
+pub fn stack_top() -> u64 {
+    STACK.load(Ordering::Relaxed)
+}
+
+/// And where its heap starts
+pub fn heap_start() -> u64 {
+    HEAP.load(Ordering::Relaxed)
+}
+
+/// Map `pages` more writable pages at the top of the program's heap, from
+/// heap_start up, and say where they start. Nothing is left mapped if it
+/// fails, with limit::OVER_LIMIT if that's why.
+pub(crate) fn grow(pages: usize) -> Result<VirtAddr, &'static str> {
+    let grown = HEAP_PAGES.load(Ordering::Relaxed);
+    // Up to as low as the stack could be
+    let most = ((STACK_START - heap_start()) / FRAME_SIZE) as usize;
+    if pages > most - grown {
+        return Err("heap is full");
+    }
+    let start = VirtAddr::new(heap_start() + grown as u64 * FRAME_SIZE);
+    let mut flags = PageTableFlags::WRITABLE;
+    if cpu::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    if let Err(message) = map(start, pages, flags) {
+        unmap(start, pages);
+        return Err(message);
+    }
+    HEAP_PAGES.store(grown + pages, Ordering::Relaxed);
+    Ok(start)
+}
+
+fn unmap_heap() {
+    unmap(VirtAddr::new(heap_start()), HEAP_PAGES.swap(0, Ordering::Relaxed));
+}
+
+/// Copy `bytes` into user memory at `addr`, mapped or not writable from ring
+/// 3, through the kernel's view of physical memory
+pub fn load(addr: VirtAddr, bytes: &[u8]) -> Result<(), &'static str> {
+    let mut done = 0;
+    while done < bytes.len() {
+        let at = addr + done as u64;
+        let phys = paging::translate_addr(at).ok_or("user page isn't mapped")?;
+        let len = (FRAME_SIZE - at.as_u64() % FRAME_SIZE).min((bytes.len() - done) as u64) as usize;
+        unsafe {
+            let dst = paging::phys_to_virt(phys).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), dst, len);
+        }
+        done += len;
+    }
+    Ok(())
+}
+
+// Where the program's stack starts, at the bottom
+fn stack_start() -> VirtAddr {
+    VirtAddr::new(stack_top() - STACK_PAGES as u64 * FRAME_SIZE)
+}
+
+/// Map a program's stack, below stack_top
+pub(crate) fn map_stack() -> Result<(), &'static str> {
+    let mut flags = PageTableFlags::WRITABLE;
+    if cpu::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    map(stack_start(), STACK_PAGES, flags)
+}
+
+fn unmap_stack() {
+    unmap(stack_start(), STACK_PAGES);
+}
+
+/// Held while a program runs, since only one can at a time
+pub(crate) struct Claim {
+    _private: (),
+}
+
+impl Claim {
+    pub(crate) fn new() -> Result<Claim, &'static str> {
+        if RUNNING.swap(true, Ordering::AcqRel) {
+            return Err("a user program is already running");
+        }
+        limit::start();
+        caps::start();
+        signal::reset();
+        HEAP_PAGES.store(0, Ordering::Relaxed);
+        STACK.store(STACK_TOP - slide(STACK_SLIDE), Ordering::Relaxed);
+        HEAP.store(HEAP_START + slide(HEAP_SLIDE), Ordering::Relaxed);
+        Ok(Claim { _private: () })
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        // Whatever the program had has been freed by now
+        signal::reset();
+        syscall::release();
+        trace::set(false);
+        limit::reset();
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+/// Run ring 3 code from `entry` with the stack pointer at `stack`, until it
+/// exits or faults.
+///
+/// # Safety
+/// The code and stack must be mapped user-accessible, and nothing else may
+/// be in ring 3 meanwhile.
+pub unsafe fn enter(entry: VirtAddr, stack: VirtAddr) -> Exit {
+    let selectors = gdt::selectors();
+    interrupts::without_interrupts(|| *START_TIMES.lock() = sched::cpu_times());
+    sched::set_in_user(true);
+    heorot_enter_user(entry.as_u64(), stack.as_u64(), u64::from(selectors.user_code_selector.0),
+        u64::from(selectors.user_data_selector.0), gdt::kernel_stack_slot());
+    sched::set_in_user(false);
+    interrupts::without_interrupts(|| EXIT.lock().take())
+        .expect("came back from ring 3 without an exit")
+}
+
+/// The CPU time the program running (or the last one) has had, in ring 3
+/// and in the kernel for it
+pub fn cpu_times() -> CpuTimes {
+    let start = interrupts::without_interrupts(|| *START_TIMES.lock());
+    sched::cpu_times().since(start)
+}
+
+// Leave ring 3 for good, back to where enter was called
+fn exit(exit: Exit) -> ! {
+    if exit == Exit::OutOfMemory {
+        limit::killed();
+    }
+    interrupts::disable();
+    *EXIT.lock() = Some(exit);
+    unsafe { heorot_user_return() }
+}
+
+/// Called by exception handlers: if the exception came from ring 3, kill
+/// the program by pointing the frame at the way back out of `enter`
+pub(crate) fn kill(stack_frame: &mut InterruptStackFrame, vector: u8, error_code: Option<u64>) -> bool {
+    if stack_frame.code_segment & 3 != 3 {
+        return false;
+    }
+    return_to_kernel(stack_frame, Exit::Faulted { vector, error_code, instruction_pointer: stack_frame.instruction_pointer });
+    true
+}
+
+// End the program with `exit` from an interrupt out of ring 3, by pointing
+// the frame at the way back out of `enter`
+fn return_to_kernel(stack_frame: &mut InterruptStackFrame, exit: Exit) {
+    *EXIT.lock() = Some(exit);
+    let selectors = gdt::selectors();
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(heorot_user_return as usize as u64);
+            frame.code_segment = u64::from(selectors.code_selector.0);
+            frame.stack_segment = u64::from(selectors.data_selector.0);
+            frame.stack_pointer = VirtAddr::new(HEOROT_KERNEL_RSP);
+            // Interrupts stay off until the flags from before enter are back
+            frame.cpu_flags = 0x2;
+        });
+    }
+}
+
+/// Load position-independent `code` at CODE_START, give it a stack, and run
+/// it in ring 3 from its first byte. Its memory is gone again afterwards.
+pub fn run(code: &[u8]) -> Result<Exit, &'static str> {
+    let code_pages = (code.len() + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+    if code_pages == 0 || code_pages > MAX_CODE_PAGES {
+        return Err("bad program size");
+    }
+    let _claim = Claim::new()?;
+    let code_start = VirtAddr::new(CODE_START);
+    let result = map(code_start, code_pages, PageTableFlags::empty())
+        .and_then(|()| load(code_start, code))
+        .and_then(|()| map_stack())
+        .map(|()| unsafe { enter(code_start, VirtAddr::new(stack_top())) });
+    unmap(code_start, code_pages);
+    unmap_stack();
+    unmap_heap();
+    signal::unmap_trampoline();
+    result
+}
+
+/// Turn on SYSCALL/SYSRET, which needs the GDT loaded, and read `memlimit=`
+/// and `caps=`
+pub fn init() {
+    syscall::init();
+    args::init();
+    caps::init();
+    limit::init();
+}
+
+/// TESTS
+
+#[test_case]
+fn test_slides_stay_in_range() {
+    for _ in 0..64 {
+        let slide = slide(STACK_SLIDE);
+        assert!(slide < STACK_SLIDE);
+        assert_eq!(slide % FRAME_SIZE, 0);
+    }
+    assert!(HEAP_START + HEAP_SLIDE < STACK_START);
+}
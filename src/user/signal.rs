@@ -0,0 +1,431 @@
+//! Signals for the program in ring 3: the ones its interval timers raise.
+//! ITIMER_REAL counts wall-clock time and raises SIGALRM, ITIMER_VIRTUAL
+//! the program's user time for SIGVTALRM, and ITIMER_PROF its user and
+//! kernel time for SIGPROF. Ctrl+C raises SIGINT. A signal with no handler
+//! kills the program. SIGKILL, from `kill`, always does: it can't be
+//! handled, blocked or ignored.
+//!
+//! Signals are delivered on the way back to ring 3, from a syscall or a
+//! timer interrupt. A handler runs by way of a trampoline, mapped at
+//! TRAMPOLINE once the program installs one: the program's stack pointer
+//! and instruction pointer are pointed at it, and it saves every register
+//! the handler may clobber, calls the handler with the signal number, tells
+//! the kernel with sigreturn, and goes back to where the program was. Each
+//! signal stays blocked until its handler's sigreturn.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::time::{self, Duration, Instant};
+use crate::{timer, uaccess};
+use super::Exit;
+
+pub const SIGINT: u8 = 2;
+pub const SIGKILL: u8 = 9;
+pub const SIGALRM: u8 = 14;
+pub const SIGVTALRM: u8 = 26;
+pub const SIGPROF: u8 = 27;
+/// Handlers that aren't addresses: kill the program, or drop the signal
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// What set_handler fails with for signals other than these
+pub const NO_SUCH_SIGNAL: &str = "no such signal";
+
+pub const ITIMER_REAL: usize = 0;
+pub const ITIMER_VIRTUAL: usize = 1;
+pub const ITIMER_PROF: usize = 2;
+const TIMERS: usize = 3;
+// The ones a program can handle: each timer's, by timer, then SIGINT
+const SIGNALS: [u8; TIMERS + 1] = [SIGALRM, SIGVTALRM, SIGPROF, SIGINT];
+const INTERRUPT: usize = TIMERS;
+
+/// Where the trampoline goes: the page above the highest the stack can be
+pub const TRAMPOLINE: u64 = super::STACK_TOP;
+// The red zone below the program's stack pointer is the program's
+const RED_ZONE: u64 = 128;
+
+extern "C" {
+    static heorot_sigtramp: u8;
+    static heorot_sigtramp_end: u8;
+}
+
+// Entered with the handler, the signal number and where to go back to on
+// the stack, just below the red zone. `ret 128` skips back over that.
+global_asm!(
+    ".global heorot_sigtramp",
+    ".global heorot_sigtramp_end",
+    "heorot_sigtramp:",
+    "push rax",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "pushfq",
+    "push rbp",
+    "mov rbp, rsp",
+    "and rsp, -16",
+    "cld",
+    "mov rdi, [rbp + 96]",
+    "call qword ptr [rbp + 88]",
+    // sigreturn(signal)
+    "mov rdi, [rbp + 96]",
+    "mov eax, 9",
+    "syscall",
+    "mov rsp, rbp",
+    "pop rbp",
+    "popfq",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rax",
+    // Not add, which would change the flags just restored
+    "lea rsp, [rsp + 16]",
+    "ret 128",
+    "heorot_sigtramp_end:",
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Itimer {
+    /// Every this long after the first, or zero for once
+    pub interval: Duration,
+    /// Until the next, or zero if it's off
+    pub value: Duration,
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    handlers: [u64; SIGNALS.len()],
+    // When each timer is due next, on its own clock, and every how long
+    due: [Option<Duration>; TIMERS],
+    intervals: [Duration; TIMERS],
+    // Bits by where the signal is in SIGNALS
+    pending: u8,
+    blocked: u8,
+    // SIGKILL is pending
+    killed: bool,
+    trampoline: bool,
+}
+
+impl State {
+    // Pending signals that aren't ignored
+    fn interrupting(&self) -> u8 {
+        let heeded = (0..SIGNALS.len())
+            .filter(|&index| self.handlers[index] != SIG_IGN)
+            .fold(0, |bits, index| bits | 1 << index);
+        self.pending & !self.blocked & heeded
+    }
+}
+
+const IDLE: State = State {
+    handlers: [SIG_DFL; SIGNALS.len()],
+    due: [None; TIMERS],
+    intervals: [Duration::ZERO; TIMERS],
+    pending: 0,
+    blocked: 0,
+    killed: false,
+    trampoline: false,
+};
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static STATE: Mutex<State> = Mutex::new(IDLE);
+// Whether any timer is armed or signal pending, so ticks can skip the rest
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn index_of(signal: u8) -> Option<usize> {
+    SIGNALS.iter().position(|&known| known == signal)
+}
+
+// Each timer's clock, as it reads now
+fn clocks() -> [Duration; TIMERS] {
+    let times = super::cpu_times();
+    [time::uptime(), times.user, times.user + times.kernel]
+}
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut state = STATE.lock();
+        let result = f(&mut state);
+        let active = state.pending != 0 || state.killed || state.due.iter().any(Option::is_some);
+        ACTIVE.store(active, Ordering::Relaxed);
+        result
+    })
+}
+
+// Raise the signals of timers that have come due, and set them going again
+fn poll() {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let now = clocks();
+    with_state(|state| {
+        for timer in 0..TIMERS {
+            match state.due[timer] {
+                Some(due) if due <= now[timer] => {
+                    state.pending |= 1 << timer;
+                    let interval = state.intervals[timer];
+                    let next = due.saturating_add(interval);
+                    // Behind by more than an interval, skip what was missed
+                    state.due[timer] = if interval == Duration::ZERO {
+                        None
+                    } else if next <= now[timer] {
+                        Some(now[timer].saturating_add(interval))
+                    } else {
+                        Some(next)
+                    };
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+enum Action {
+    Kill(u8),
+    Handle(u8, u64),
+}
+
+// The next pending signal that isn't blocked or ignored, taken off pending
+fn take() -> Option<Action> {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return None;
+    }
+    with_state(|state| {
+        if core::mem::replace(&mut state.killed, false) {
+            return Some(Action::Kill(SIGKILL));
+        }
+        for index in 0..SIGNALS.len() {
+            let bit = 1 << index;
+            if state.pending & bit == 0 || state.blocked & bit != 0 {
+                continue;
+            }
+            state.pending &= !bit;
+            match state.handlers[index] {
+                SIG_IGN => {}
+                SIG_DFL => return Some(Action::Kill(SIGNALS[index])),
+                handler => {
+                    state.blocked |= bit;
+                    return Some(Action::Handle(SIGNALS[index], handler));
+                }
+            }
+        }
+        None
+    })
+}
+
+// Put what the trampoline needs on the program's stack, below `rsp`'s red
+// zone; where that leaves the stack pointer, if the stack is there
+fn push_frame(rsp: u64, rip: u64, signal: u8, handler: u64) -> Option<u64> {
+    let frame = rsp.checked_sub(RED_ZONE + 24)?;
+    let start = VirtAddr::try_new(frame).ok()?;
+    uaccess::check_user_mapped(start, 24, true).ok()?;
+    let mut bytes = [0u8; 24];
+    for (chunk, value) in bytes.chunks_exact_mut(8).zip([handler, u64::from(signal), rip].iter()) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    // Checked as mapped and writable, and nothing else runs in ring 3
+    unsafe { uaccess::copy_to_user(start, &bytes) }.ok()?;
+    Some(frame)
+}
+
+/// On the way out of a syscall: deliver a signal by pointing the registers
+/// the syscall entry saved at the trampoline, or end the program
+pub(super) fn deliver_from_syscall() {
+    poll();
+    let (signal, handler) = match take() {
+        None => return,
+        Some(Action::Kill(signal)) => super::exit(Exit::Signaled(signal)),
+        Some(Action::Handle(signal, handler)) => (signal, handler),
+    };
+    // The user stack pointer and then its rip (rcx) are the first two
+    // things pushed below HEOROT_KERNEL_RSP
+    unsafe {
+        let saved = super::HEOROT_KERNEL_RSP as *mut u64;
+        let (rsp, rip) = (saved.sub(1), saved.sub(2));
+        match push_frame(rsp.read(), rip.read(), signal, handler) {
+            Some(frame) => {
+                rsp.write(frame);
+                rip.write(TRAMPOLINE);
+            }
+            None => super::exit(Exit::Signaled(signal)),
+        }
+    }
+}
+
+/// Called by the timer interrupt handler: raise due signals, and deliver
+/// one if the interrupt came from ring 3
+pub(crate) fn timer_tick(stack_frame: &mut InterruptStackFrame) {
+    poll();
+    if stack_frame.code_segment & 3 != 3 {
+        return;
+    }
+    let (signal, handler) = match take() {
+        None => return,
+        Some(Action::Kill(signal)) => return super::return_to_kernel(stack_frame, Exit::Signaled(signal)),
+        Some(Action::Handle(signal, handler)) => (signal, handler),
+    };
+    let frame = push_frame(stack_frame.stack_pointer.as_u64(), stack_frame.instruction_pointer.as_u64(), signal,
+        handler);
+    match frame {
+        Some(frame) => unsafe {
+            stack_frame.as_mut().update(|stack_frame| {
+                stack_frame.stack_pointer = VirtAddr::new(frame);
+                stack_frame.instruction_pointer = VirtAddr::new(TRAMPOLINE);
+            });
+        },
+        None => super::return_to_kernel(stack_frame, Exit::Signaled(signal)),
+    }
+}
+
+/// Install `handler` for `signal`, mapping the trampoline the first time;
+/// the handler before
+pub(super) fn set_handler(signal: u8, handler: u64) -> Result<u64, &'static str> {
+    let index = index_of(signal).ok_or(NO_SUCH_SIGNAL)?;
+    let mapped = with_state(|state| state.trampoline);
+    if handler > SIG_IGN && !mapped {
+        let code = unsafe {
+            let start = &heorot_sigtramp as *const u8;
+            core::slice::from_raw_parts(start, &heorot_sigtramp_end as *const u8 as usize - start as usize)
+        };
+        let start = VirtAddr::new(TRAMPOLINE);
+        super::map(start, 1, PageTableFlags::empty()).and_then(|()| super::load(start, code))?;
+        with_state(|state| state.trampoline = true);
+    }
+    Ok(with_state(|state| core::mem::replace(&mut state.handlers[index], handler)))
+}
+
+/// A handler for `signal` is done
+pub(super) fn sigreturn(signal: u8) {
+    if let Some(index) = index_of(signal) {
+        with_state(|state| state.blocked &= !(1 << index));
+    }
+}
+
+/// Set `timer` going as `new`, or stop it with a zero value, or leave it as
+/// it is with None; how it was
+pub(super) fn set_timer(timer: usize, new: Option<Itimer>) -> Result<Itimer, &'static str> {
+    if timer >= TIMERS {
+        return Err("no such timer");
+    }
+    let now = clocks()[timer];
+    let due = match new {
+        Some(new) if new.value != Duration::ZERO => Some(now.checked_add(new.value).ok_or("too long")?),
+        _ => None,
+    };
+    Ok(with_state(|state| {
+        let old = Itimer {
+            interval: state.intervals[timer],
+            value: state.due[timer].map_or(Duration::ZERO, |due| due.saturating_sub(now)),
+        };
+        if let Some(new) = new {
+            state.intervals[timer] = new.interval;
+            state.due[timer] = due;
+        }
+        old
+    }))
+}
+
+// Does nothing, but makes a tickless idle wake when we're due
+fn wake() {}
+
+/// A kernel timer for when ITIMER_REAL is next due, so a wait halted until
+/// then is woken to be cut short; for the waiter to cancel once it's done
+pub(super) fn wake_for_real() -> Option<timer::TimerId> {
+    let real = with_state(|state| state.due[ITIMER_REAL]).map(|due| due.saturating_sub(time::uptime()));
+    real.and_then(|delay| timer::after(delay, wake).ok())
+}
+
+/// Whether a signal the program doesn't ignore has come, which cuts waits
+/// short
+pub(super) fn interrupted() -> bool {
+    poll();
+    with_state(|state| state.killed || state.interrupting() != 0)
+}
+
+/// Sleep for `duration`, unless a signal the program doesn't ignore comes
+/// first; then how much of it was left. The kernel's timers wake the CPU for it and
+/// for ITIMER_REAL.
+pub(super) fn sleep(duration: Duration) -> Result<(), Duration> {
+    let deadline = Instant::now() + duration;
+    let wakes = [timer::after(duration, wake).ok(), wake_for_real()];
+    let result = loop {
+        timer::run_expired();
+        if interrupted() {
+            break Err(deadline.duration_since(Instant::now()));
+        }
+        interrupts::disable();
+        if Instant::now() >= deadline {
+            interrupts::enable();
+            break Ok(());
+        }
+        timer::idle();
+    };
+    for id in wakes.iter().flatten() {
+        timer::cancel(*id);
+    }
+    result
+}
+
+/// Unmap the trampoline, if the program had it, from the kernel's page
+/// tables; programs in address spaces of their own take it with them
+pub(super) fn unmap_trampoline() {
+    if with_state(|state| core::mem::replace(&mut state.trampoline, false)) {
+        super::unmap(VirtAddr::new(TRAMPOLINE), 1);
+    }
+}
+
+/// Kill the program running with SIGKILL, the next time it's back in ring 3
+/// from a syscall or a timer interrupt, cutting short any wait it's in.
+/// False if there isn't one. One sent just as a program starts may be lost.
+pub fn kill() -> bool {
+    if !super::RUNNING.load(Ordering::Acquire) {
+        return false;
+    }
+    with_state(|state| state.killed = true);
+    true
+}
+
+/// Send the program running SIGINT, as Ctrl+C does; false if there isn't
+/// one. Like `kill`, it's safe from an interrupt handler.
+pub fn interrupt() -> bool {
+    if !super::RUNNING.load(Ordering::Acquire) {
+        return false;
+    }
+    with_state(|state| state.pending |= 1 << INTERRUPT);
+    true
+}
+
+/// Back to no handlers and no timers, for the next program
+pub(super) fn reset() {
+    with_state(|state| *state = IDLE);
+}
+
+/// TESTS
+
+#[test_case]
+fn test_interrupt_kills_by_default() {
+    // Nothing's running in ring 3 under the test runner
+    assert!(!interrupt());
+    with_state(|state| state.pending |= 1 << INTERRUPT);
+    assert!(matches!(take(), Some(Action::Kill(SIGINT))));
+    with_state(|state| {
+        state.handlers[INTERRUPT] = SIG_IGN;
+        state.pending |= 1 << INTERRUPT;
+    });
+    assert!(!interrupted());
+    assert!(take().is_none());
+    reset();
+}
@@ -0,0 +1,108 @@
+//! Address spaces for programs. Each has its own top-level page table, with
+//! one entry's worth of it (512 GiB from SPACE_START) the program's, and
+//! every other entry shared with the kernel's.
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use crate::memory::{self, paging, rmap};
+
+/// Where a program's part of its address space starts
+pub const SPACE_START: u64 = super::CODE_START;
+/// And the first address past it
+pub const SPACE_END: u64 = SPACE_START + (1 << 39);
+const SPACE_SLOT: usize = (SPACE_START >> 39) as usize & 0x1ff;
+
+unsafe fn table<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    // The kernel's is only writable through paging's alias of it
+    paging::table(frame)
+}
+
+// Free the frame an entry `level` tables up points to, and all under it
+unsafe fn free(frame: PhysFrame, level: u8) {
+    if level > 0 {
+        for entry in table(frame).iter() {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                free(PhysFrame::containing_address(entry.addr()), level - 1);
+            }
+        }
+    }
+    memory::deallocate_frame(frame);
+}
+
+/// Page tables for one program. Dropping them frees everything mapped in
+/// the program's part.
+pub struct AddressSpace {
+    level_4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Nothing of the program's mapped yet
+    pub fn new() -> Result<AddressSpace, &'static str> {
+        let frame = memory::allocate_frame().ok_or(memory::OUT_OF_MEMORY)?;
+        let (kernel, _) = Cr3::read();
+        unsafe {
+            let new = table(frame);
+            new.zero();
+            for (index, entry) in table(kernel).iter().enumerate() {
+                if index != SPACE_SLOT {
+                    new[index] = entry.clone();
+                }
+            }
+        }
+        Ok(AddressSpace { level_4: frame })
+    }
+
+    /// Run `f` with this the address space in use, so paging maps into it,
+    /// then go back to the kernel's
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        // Everything but the program's part is the kernel's own
+        let kernel = unsafe { paging::switch_to(self.level_4) };
+        let result = f();
+        unsafe {
+            paging::switch_to(kernel);
+            // The kernel may have mapped things meanwhile where it had no
+            // top-level entry yet: those tables went here, so hand them over
+            let ours = table(self.level_4);
+            for (index, entry) in table(kernel).iter_mut().enumerate() {
+                if index != SPACE_SLOT && entry.is_unused() && !ours[index].is_unused() {
+                    *entry = ours[index].clone();
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Not in use: enter always switches back before returning
+        unsafe {
+            let entry = &table(self.level_4)[SPACE_SLOT];
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                free(PhysFrame::containing_address(entry.addr()), 3);
+            }
+            memory::deallocate_frame(self.level_4);
+        }
+        rmap::remove_space(self.level_4);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_spaces_keep_programs_apart() {
+    use x86_64::VirtAddr;
+    let free_frames = || memory::with_frame_allocator(|frames| frames.free_frames()).unwrap();
+    let page = VirtAddr::new(SPACE_START);
+    let before = free_frames();
+    let space = AddressSpace::new().unwrap();
+    space.enter(|| {
+        super::map(page, 1, PageTableFlags::WRITABLE).unwrap();
+        assert!(paging::translate_addr(page).is_some());
+        // The kernel's still there
+        assert!(paging::translate_addr(VirtAddr::from_ptr(&before)).is_some());
+    });
+    assert_eq!(paging::translate_addr(page), None);
+    drop(space);
+    assert_eq!(free_frames(), before);
+}
@@ -0,0 +1,703 @@
+//! The syscall ABI. A program puts the syscall number in rax and up to
+//! three arguments in rdi, rsi and rdx, then executes `syscall`; the result
+//! comes back in rax, negative for an error. rcx and r11 are clobbered, as
+//! SYSCALL and SYSRET need them; every other register is kept.
+
+use core::arch::global_asm;
+use core::convert::TryFrom;
+use alloc::format;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+use crate::{console, fs, gdt, poll, print, sched, time, uaccess};
+use crate::error::{KernelError, MemoryError};
+use crate::fs::{FileLock, LockKind};
+use crate::memory::{paging, FRAME_SIZE};
+use crate::poll::{PollFd, Source};
+use crate::time::{Duration, Instant};
+use super::signal::{self, Itimer};
+use super::{args, caps, futex, limit, trace, Exit};
+
+/// exit(code): never returns
+pub const SYS_EXIT: u64 = 0;
+/// write(buf, len): print `len` bytes of UTF-8 from `buf` to the console
+pub const SYS_WRITE: u64 = 1;
+/// yield(): let other threads run
+pub const SYS_YIELD: u64 = 2;
+/// grow(pages): map `pages` more zeroed, writable pages at the top of the
+/// heap and return where they start; grow(0) says where the top is. Going
+/// over the memory limit gets the program killed.
+pub const SYS_GROW: u64 = 3;
+/// getrusage(who, buf): fill `buf` with five u64s, the user and then the
+/// system CPU time as seconds and microseconds each, then the most memory
+/// the program has had in KiB. `who` is RUSAGE_SELF for the program's time
+/// or RUSAGE_THREAD for all of the thread's running it.
+pub const SYS_GETRUSAGE: u64 = 4;
+/// times(buf): fill `buf` with four u64s, the program's user and system
+/// time and its children's (of which it has none) in CLOCKS_PER_SEC, and
+/// return the time since boot in them too
+pub const SYS_TIMES: u64 = 5;
+/// nanosleep(req, rem): sleep for `req`, two u64s of seconds and then
+/// nanoseconds. Cut short by a signal, it returns EINTR and fills `rem`, if
+/// it isn't null, with how much of the sleep was left.
+pub const SYS_NANOSLEEP: u64 = 6;
+/// setitimer(which, new, old): set ITIMER_REAL, ITIMER_VIRTUAL or ITIMER_PROF
+/// from `new`, four u64s: the interval as seconds and microseconds, then
+/// the time to the next expiry the same way, zero to turn it off. A null
+/// `new` leaves the timer be; `old`, if not null, gets how it was.
+pub const SYS_SETITIMER: u64 = 7;
+/// sigaction(signal, handler): call `handler(signal)` when the signal comes,
+/// or with SIG_DFL be killed by it, or with SIG_IGN drop it; returns the
+/// handler before
+pub const SYS_SIGACTION: u64 = 8;
+/// sigreturn(signal): a handler for `signal` is done. The signal trampoline
+/// makes this call, not programs.
+pub const SYS_SIGRETURN: u64 = 9;
+/// sched_setaffinity(tid, len, mask): let thread `tid`, or with 0 the one
+/// running the program, run only on the CPUs in `mask`, `len` bytes of bits
+/// from the boot CPU's
+pub const SYS_SCHED_SETAFFINITY: u64 = 10;
+/// sched_getaffinity(tid, len, mask): fill `mask` with the CPUs thread `tid`
+/// may run on, and return how many bytes that took
+pub const SYS_SCHED_GETAFFINITY: u64 = 11;
+/// stat(path, len, buf): fill `buf` with five u64s about the file or
+/// directory at the `len` bytes of UTF-8 at `path`: its mode, its size, and
+/// when it was last read, last written and made, in seconds since the Unix
+/// epoch (0 where the filesystem doesn't say)
+pub const SYS_STAT: u64 = 12;
+/// flock(path, len, operation): take a shared (LOCK_SH) or exclusive
+/// (LOCK_EX) advisory lock on the file at `path` for as long as the program
+/// runs, waiting for it unless LOCK_NB is or'd in, or give it up (LOCK_UN).
+/// A lock already held is given up before the new one is taken.
+pub const SYS_FLOCK: u64 = 13;
+/// poll(fds, nfds, timeout): wait until one of the `nfds` pollfds at `fds`
+/// is ready, for up to `timeout` milliseconds, or with u64::MAX (-1) for as
+/// long as it takes. Each pollfd is eight bytes: an i32 fd, then a u16 of the
+/// events it's wanted for, POLLIN and POLLOUT, and a u16 that the events it's
+/// ready for are written to. Returns how many are ready; 0 at the timeout.
+/// At most MAX_POLL fds, all of them open.
+pub const SYS_POLL: u64 = 14;
+/// read(fd, buf, len): read without waiting from `fd` into `buf`. The
+/// console (fd 0) gives the keys typed so far, as UTF-8; a timer, how many
+/// times it's expired since it was last read, as a u64, so `len` has to
+/// have room for one of those (or for a character). Nothing to read is
+/// EWOULDBLOCK.
+pub const SYS_READ: u64 = 15;
+/// timerfd(new): make a timer to poll and read, from `new` as setitimer
+/// takes it: the interval and then the time to the first expiry, each as
+/// seconds and microseconds; with no interval it expires once. Returns its
+/// fd.
+pub const SYS_TIMERFD: u64 = 16;
+/// close(fd): close a timer
+pub const SYS_CLOSE: u64 = 17;
+/// uname(buf, len): fill `buf` with what the kernel is, as NUL-terminated
+/// UTF-8 fields: its name, its release, its version (the commit and when it
+/// was built), the machine, and the features it was built with,
+/// comma-separated. Returns how many bytes that took; EINVAL if it's more
+/// than `len`.
+pub const SYS_UNAME: u64 = 18;
+/// gettimeofday(tv): fill `tv` with two u64s, the seconds and then the
+/// microseconds since the Unix epoch, by the clock set from the RTC at boot
+pub const SYS_GETTIMEOFDAY: u64 = 19;
+/// clock_gettime(clock, ts): fill `ts` with two u64s of seconds and then
+/// nanoseconds: since the Unix epoch for CLOCK_REALTIME, or since boot for
+/// CLOCK_MONOTONIC, which never goes back when the time is set
+pub const SYS_CLOCK_GETTIME: u64 = 20;
+/// futex(addr, op, val): with FUTEX_WAIT, wait until woken if the u32 at
+/// `addr` still holds `val`, or fail with EWOULDBLOCK (EAGAIN) if it
+/// doesn't; a signal cuts the wait short with EINTR. With FUTEX_WAKE, wake
+/// up to `val` of those waiting on it, and return how many. `addr` has to
+/// be four-byte aligned.
+pub const SYS_FUTEX: u64 = 21;
+/// capget(): the program's capabilities, as user::caps has them
+pub const SYS_CAPGET: u64 = 22;
+/// capdrop(caps): give up `caps` for as long as the program runs, and
+/// return the ones it has left
+pub const SYS_CAPDROP: u64 = 23;
+
+pub const LOCK_SH: u64 = 1;
+pub const LOCK_EX: u64 = 2;
+pub const LOCK_NB: u64 = 4;
+pub const LOCK_UN: u64 = 8;
+
+pub use crate::poll::{POLLIN, POLLOUT};
+
+/// The console's fds
+pub const STDIN: i32 = 0;
+pub const STDOUT: i32 = 1;
+
+pub const RUSAGE_SELF: u64 = 0;
+pub const RUSAGE_THREAD: u64 = 1;
+/// What times counts in
+pub const CLOCKS_PER_SEC: u64 = 100;
+
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+
+/// The program has dropped a capability the syscall needs
+pub const EPERM: i64 = -1;
+/// No file or directory is at that path
+pub const ENOENT: i64 = -2;
+/// No thread has that id
+pub const ESRCH: i64 = -3;
+/// A sleep was cut short by a signal
+pub const EINTR: i64 = -4;
+/// The disk couldn't be read
+pub const EIO: i64 = -5;
+/// No fd has that number
+pub const EBADF: i64 = -9;
+/// A lock is held, and the caller said not to wait for it, or there's
+/// nothing to read
+pub const EWOULDBLOCK: i64 = -11;
+/// There's no memory left for the program, limit or not
+pub const ENOMEM: i64 = -12;
+/// A pointer argument isn't mapped for the program
+pub const EFAULT: i64 = -14;
+/// Part of a path isn't a directory
+pub const ENOTDIR: i64 = -20;
+/// An argument makes no sense
+pub const EINVAL: i64 = -22;
+/// The filesystem can't be written to
+pub const EROFS: i64 = -30;
+/// A path is longer than MAX_PATH
+pub const ENAMETOOLONG: i64 = -36;
+/// No syscall has that number
+pub const ENOSYS: i64 = -38;
+
+/// The longest path a syscall takes, in bytes
+pub const MAX_PATH: usize = 256;
+/// The most fds one poll waits on
+pub const MAX_POLL: usize = 8;
+const MAX_TIMERS: usize = 8;
+// The first timer's fd; the console has those below
+const FIRST_TIMER: i32 = 3;
+// One past the last fd there can be
+pub(super) const FDS: i32 = FIRST_TIMER + MAX_TIMERS as i32;
+
+// The locks the program has taken with flock
+// Never touched by interrupt handlers
+static LOCKS: Mutex<Vec<FileLock>> = Mutex::new(Vec::new());
+// The program's timers, by fd from FIRST_TIMER
+// Never touched by interrupt handlers
+static TIMERS: Mutex<[Option<poll::Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+
+// The program's stack pointer while a syscall runs on the kernel's
+#[no_mangle]
+static mut HEOROT_USER_RSP: u64 = 0;
+
+extern "C" {
+    fn heorot_syscall_entry();
+}
+
+// What LSTAR points at. SFMASK has cleared IF, so nothing can interrupt
+// before the switch to the kernel stack (just below where the registers
+// enter_user saved are). rax, rdi, rsi and rdx become the four arguments
+// of heorot_syscall.
+global_asm!(
+    ".global heorot_syscall_entry",
+    "heorot_syscall_entry:",
+    "mov [rip + HEOROT_USER_RSP], rsp",
+    "mov rsp, [rip + HEOROT_KERNEL_RSP]",
+    "push qword ptr [rip + HEOROT_USER_RSP]",
+    "push rcx",
+    "push r11",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r8",
+    "push r9",
+    "push r10",
+    // Nine pushes from a 16-byte aligned stack; the call wants it aligned
+    "sub rsp, 8",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "sti",
+    "call heorot_syscall",
+    "cli",
+    "add rsp, 8",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+);
+
+// Time from here to the sysretq is the kernel's, not the program's
+#[no_mangle]
+extern "C" fn heorot_syscall(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    sched::set_in_user(false);
+    let tracing = trace::enabled();
+    if tracing && number == SYS_EXIT {
+        trace::entered(number, [arg0, arg1, arg2]);
+    }
+    let result = args::check(number, [arg0, arg1, arg2])
+        .and_then(|()| caps::check(number, [arg0, arg1, arg2]))
+        .and_then(|()| dispatch(number, arg0, arg1, arg2));
+    // The one place errors become errno values
+    let result = result.unwrap_or_else(|error| error.errno());
+    if tracing {
+        trace::returned(number, [arg0, arg1, arg2], result);
+    }
+    signal::deliver_from_syscall();
+    sched::set_in_user(true);
+    result
+}
+
+fn dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64) -> Result<i64, KernelError> {
+    match number {
+        SYS_EXIT => super::exit(Exit::Exited(arg0 as i64)),
+        SYS_WRITE => sys_write(arg0, arg1 as usize),
+        SYS_YIELD => {
+            sched::yield_now();
+            Ok(0)
+        }
+        SYS_GROW => sys_grow(arg0),
+        SYS_GETRUSAGE => sys_getrusage(arg0, arg1),
+        SYS_TIMES => sys_times(arg0),
+        SYS_NANOSLEEP => sys_nanosleep(arg0, arg1),
+        SYS_SETITIMER => sys_setitimer(arg0, arg1, arg2),
+        SYS_SIGACTION => sys_sigaction(arg0, arg1),
+        SYS_SIGRETURN => {
+            signal::sigreturn(arg0 as u8);
+            Ok(0)
+        }
+        SYS_SCHED_SETAFFINITY => sys_sched_setaffinity(arg0, arg1 as usize, arg2),
+        SYS_SCHED_GETAFFINITY => sys_sched_getaffinity(arg0, arg1 as usize, arg2),
+        SYS_STAT => sys_stat(arg0, arg1 as usize, arg2),
+        SYS_FLOCK => sys_flock(arg0, arg1 as usize, arg2),
+        SYS_POLL => sys_poll(arg0, arg1 as usize, arg2),
+        SYS_READ => sys_read(arg0 as i32, arg1, arg2 as usize),
+        SYS_TIMERFD => sys_timerfd(arg0),
+        SYS_CLOSE => sys_close(arg0 as i32),
+        SYS_UNAME => sys_uname(arg0, arg1 as usize),
+        SYS_GETTIMEOFDAY => sys_gettimeofday(arg0),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(arg0, arg1),
+        SYS_FUTEX => sys_futex(arg0, arg1, arg2),
+        SYS_CAPGET => Ok(caps::current() as i64),
+        SYS_CAPDROP => Ok(caps::give_up(arg0) as i64),
+        _ => Err(KernelError::Unsupported),
+    }
+}
+
+// `len` bytes at `addr` the program can read, or write too
+fn user_range(addr: u64, len: usize, write: bool) -> Result<VirtAddr, KernelError> {
+    let start = VirtAddr::try_new(addr).map_err(|_| KernelError::BadAddress)?;
+    uaccess::check_user_mapped(start, len, write).map_err(|_| KernelError::BadAddress)?;
+    Ok(start)
+}
+
+// Copy `values` out to `buf`
+fn copy_out(buf: u64, values: &[u64]) -> Result<(), KernelError> {
+    let mut bytes = [0; 64];
+    let bytes = &mut bytes[..values.len() * 8];
+    for (chunk, value) in bytes.chunks_exact_mut(8).zip(values) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    let start = user_range(buf, bytes.len(), true)?;
+    // Checked as mapped and writable above
+    unsafe { uaccess::copy_to_user(start, bytes) }.map_err(|_| KernelError::BadAddress)
+}
+
+// Fill `values` from `buf`
+fn copy_in(buf: u64, values: &mut [u64]) -> Result<(), KernelError> {
+    let mut bytes = [0; 64];
+    let bytes = &mut bytes[..values.len() * 8];
+    let start = user_range(buf, bytes.len(), false)?;
+    // Checked as mapped above
+    unsafe { uaccess::copy_from_user(bytes, start) }.map_err(|_| KernelError::BadAddress)?;
+    for (value, chunk) in values.iter_mut().zip(bytes.chunks_exact(8)) {
+        let mut le = [0; 8];
+        le.copy_from_slice(chunk);
+        *value = u64::from_le_bytes(le);
+    }
+    Ok(())
+}
+
+fn sys_getrusage(who: u64, buf: u64) -> Result<i64, KernelError> {
+    let times = match who {
+        RUSAGE_SELF => super::cpu_times(),
+        RUSAGE_THREAD => sched::cpu_times(),
+        _ => return Err(KernelError::InvalidArgument("no such rusage target")),
+    };
+    let (user, kernel) = (times.user, times.kernel);
+    let max_rss = limit::usage().peak as u64 * FRAME_SIZE / 1024;
+    copy_out(buf, &[user.as_secs(), u64::from(user.subsec_micros()), kernel.as_secs(), u64::from(kernel.subsec_micros()),
+        max_rss])?;
+    Ok(0)
+}
+
+fn sys_times(buf: u64) -> Result<i64, KernelError> {
+    let clocks = |duration: Duration| (duration.as_nanos() * u128::from(CLOCKS_PER_SEC) / 1_000_000_000) as u64;
+    let times = super::cpu_times();
+    copy_out(buf, &[clocks(times.user), clocks(times.kernel), 0, 0])?;
+    Ok(clocks(time::uptime()) as i64)
+}
+
+fn sys_nanosleep(req: u64, rem: u64) -> Result<i64, KernelError> {
+    let mut request = [0; 2];
+    copy_in(req, &mut request)?;
+    if request[1] >= 1_000_000_000 {
+        return Err(KernelError::InvalidArgument("nanoseconds out of range"));
+    }
+    let duration = Duration::new(request[0], request[1] as u32);
+    if Instant::now().checked_add(duration).is_none() {
+        return Err(KernelError::InvalidArgument("sleep too long"));
+    }
+    match signal::sleep(duration) {
+        Ok(()) => Ok(0),
+        Err(_) if rem == 0 => Err(KernelError::Interrupted),
+        Err(left) => {
+            copy_out(rem, &[left.as_secs(), u64::from(left.subsec_nanos())])?;
+            Err(KernelError::Interrupted)
+        }
+    }
+}
+
+fn sys_setitimer(which: u64, new: u64, old: u64) -> Result<i64, KernelError> {
+    let timeval = |secs: u64, micros: u64| {
+        if micros < 1_000_000 { Some(Duration::new(secs, micros as u32 * 1000)) } else { None }
+    };
+    let new = if new == 0 {
+        None
+    } else {
+        let mut values = [0; 4];
+        copy_in(new, &mut values)?;
+        match (timeval(values[0], values[1]), timeval(values[2], values[3])) {
+            (Some(interval), Some(value)) => Some(Itimer { interval, value }),
+            _ => return Err(KernelError::InvalidArgument("microseconds out of range")),
+        }
+    };
+    let before = signal::set_timer(which as usize, new).map_err(KernelError::InvalidArgument)?;
+    if old == 0 {
+        return Ok(0);
+    }
+    let (interval, value) = (before.interval, before.value);
+    copy_out(old, &[interval.as_secs(), u64::from(interval.subsec_micros()), value.as_secs(),
+        u64::from(value.subsec_micros())])?;
+    Ok(0)
+}
+
+fn sys_sigaction(signo: u64, handler: u64) -> Result<i64, KernelError> {
+    if handler >= uaccess::USER_SPACE_END {
+        return Err(KernelError::BadAddress);
+    }
+    let signo = u8::try_from(signo).map_err(|_| KernelError::NoSuchSignal)?;
+    match signal::set_handler(signo, handler).map_err(KernelError::from) {
+        Ok(before) => Ok(before as i64),
+        Err(KernelError::NoSuchSignal) => Err(KernelError::NoSuchSignal),
+        // Mapping the trampoline is all else that can fail
+        Err(_) => Err(KernelError::Memory(MemoryError::OutOfMemory)),
+    }
+}
+
+// The thread a tid names
+fn thread(tid: u64) -> Result<sched::ThreadId, KernelError> {
+    if tid == 0 {
+        return Ok(sched::current());
+    }
+    let mut found = None;
+    sched::for_each_thread(|id, _, _, _| {
+        if id.as_u64() == tid {
+            found = Some(id);
+        }
+    });
+    found.ok_or(KernelError::NoSuchThread)
+}
+
+fn sys_sched_setaffinity(tid: u64, len: usize, mask: u64) -> Result<i64, KernelError> {
+    let thread = thread(tid)?;
+    // Bytes past the first eight are of CPUs there can't be
+    let mut bytes = [0; 8];
+    let len = len.min(bytes.len());
+    let start = user_range(mask, len, false)?;
+    // Checked as mapped above
+    unsafe { uaccess::copy_from_user(&mut bytes[..len], start) }.map_err(|_| KernelError::BadAddress)?;
+    sched::set_affinity(thread, u64::from_le_bytes(bytes)).map_err(KernelError::InvalidArgument)?;
+    Ok(0)
+}
+
+fn sys_sched_getaffinity(tid: u64, len: usize, mask: u64) -> Result<i64, KernelError> {
+    if len < 8 {
+        return Err(KernelError::InvalidArgument("mask too short"));
+    }
+    let affinity = sched::affinity(thread(tid)?).ok_or(KernelError::NoSuchThread)?;
+    copy_out(mask, &[affinity])?;
+    Ok(8)
+}
+
+// The `len` bytes of UTF-8 at `path`, copied into `bytes`
+pub(super) fn copy_path(path: u64, len: usize, bytes: &mut [u8; MAX_PATH]) -> Result<&str, KernelError> {
+    if len > MAX_PATH {
+        return Err(KernelError::NameTooLong);
+    }
+    let start = user_range(path, len, false)?;
+    // Checked as mapped above
+    unsafe { uaccess::copy_from_user(&mut bytes[..len], start) }.map_err(|_| KernelError::BadAddress)?;
+    core::str::from_utf8(&bytes[..len]).map_err(|_| KernelError::InvalidArgument("path isn't UTF-8"))
+}
+
+fn sys_stat(path: u64, len: usize, buf: u64) -> Result<i64, KernelError> {
+    let mut bytes = [0; MAX_PATH];
+    let metadata = fs::stat(copy_path(path, len, &mut bytes)?)?;
+    let secs = |time: Option<time::SystemTime>| time.map_or(0, |time| time.since_unix_epoch().as_secs());
+    copy_out(buf, &[u64::from(metadata.mode), u64::from(metadata.size), secs(metadata.accessed), secs(metadata.modified),
+        secs(metadata.created)])?;
+    Ok(0)
+}
+
+fn sys_flock(path: u64, len: usize, operation: u64) -> Result<i64, KernelError> {
+    let mut bytes = [0; MAX_PATH];
+    let path = copy_path(path, len, &mut bytes)?;
+    let kind = match operation & !LOCK_NB {
+        LOCK_SH => Some(LockKind::Shared),
+        LOCK_EX => Some(LockKind::Exclusive),
+        LOCK_UN => None,
+        _ => return Err(KernelError::InvalidArgument("no such lock operation")),
+    };
+    let normal = fs::lock::normalize(path);
+    // Dropped after the lock on LOCKS is
+    let old = {
+        let mut locks = LOCKS.lock();
+        locks.iter().position(|lock| lock.path() == normal).map(|index| locks.swap_remove(index))
+    };
+    drop(old);
+    let kind = match kind {
+        Some(kind) => kind,
+        None => return Ok(0),
+    };
+    let lock = fs::flock(path, kind, operation & LOCK_NB == 0)?;
+    LOCKS.lock().push(lock);
+    Ok(0)
+}
+
+// The timer at `fd`, in the program's table
+fn timer_slot(timers: &mut [Option<poll::Timer>; MAX_TIMERS], fd: i32) -> Result<&mut poll::Timer, KernelError> {
+    let index = fd.checked_sub(FIRST_TIMER).and_then(|index| usize::try_from(index).ok()).ok_or(KernelError::BadFd)?;
+    timers.get_mut(index).and_then(Option::as_mut).ok_or(KernelError::BadFd)
+}
+
+// What `fd` is, to poll
+fn source(fd: i32) -> Result<Source, KernelError> {
+    match fd {
+        STDIN => Ok(Source::ConsoleInput),
+        STDOUT => Ok(Source::ConsoleOutput),
+        _ => Ok(Source::Timer(*timer_slot(&mut TIMERS.lock(), fd)?)),
+    }
+}
+
+fn sys_poll(fds: u64, nfds: usize, timeout: u64) -> Result<i64, KernelError> {
+    if nfds > MAX_POLL {
+        return Err(KernelError::InvalidArgument("too many fds"));
+    }
+    let mut raw = [0; MAX_POLL];
+    copy_in(fds, &mut raw[..nfds])?;
+    let mut polled = [PollFd::new(Source::ConsoleOutput, 0); MAX_POLL];
+    for (pollfd, &raw) in polled.iter_mut().zip(&raw[..nfds]) {
+        let fd = raw as u32 as i32;
+        *pollfd = PollFd::new(source(fd)?, (raw >> 32) as u16);
+    }
+    let timeout = match timeout {
+        u64::MAX => None,
+        millis => Some(Duration::from_millis(millis)),
+    };
+    let wake = signal::wake_for_real();
+    let ready = poll::wait_or(&mut polled[..nfds], timeout, signal::interrupted);
+    if let Some(id) = wake {
+        crate::timer::cancel(id);
+    }
+    let ready = ready.ok_or(KernelError::Interrupted)?;
+    for (raw, pollfd) in raw.iter_mut().zip(&polled[..nfds]) {
+        *raw = *raw & 0xffff_ffff_ffff | u64::from(pollfd.revents) << 48;
+    }
+    copy_out(fds, &raw[..nfds])?;
+    Ok(ready as i64)
+}
+
+fn sys_read(fd: i32, buf: u64, len: usize) -> Result<i64, KernelError> {
+    if fd == STDIN {
+        return read_console(buf, len);
+    }
+    if len < 8 {
+        return Err(KernelError::InvalidArgument("no room for a timer's count"));
+    }
+    let expired = timer_slot(&mut TIMERS.lock(), fd)?.read();
+    if expired == 0 {
+        return Err(KernelError::WouldBlock);
+    }
+    copy_out(buf, &[expired])?;
+    Ok(8)
+}
+
+#[cfg(feature = "keyboard")]
+fn read_console(buf: u64, len: usize) -> Result<i64, KernelError> {
+    use pc_keyboard::DecodedKey;
+
+    if len < 4 {
+        return Err(KernelError::InvalidArgument("no room for a character"));
+    }
+    let start = user_range(buf, len, true)?;
+    let mut done = 0;
+    while len - done >= 4 {
+        let c = match crate::keyboard::try_read_key() {
+            Some(DecodedKey::Unicode(c)) => c,
+            // Keys with no character to them
+            Some(DecodedKey::RawKey(_)) => continue,
+            None => break,
+        };
+        let mut utf8 = [0; 4];
+        let bytes = c.encode_utf8(&mut utf8).as_bytes();
+        // Checked as mapped and writable above
+        unsafe { uaccess::copy_to_user(start + done as u64, bytes) }.map_err(|_| KernelError::BadAddress)?;
+        done += bytes.len();
+    }
+    if done == 0 {
+        return Err(KernelError::WouldBlock);
+    }
+    Ok(done as i64)
+}
+
+#[cfg(not(feature = "keyboard"))]
+fn read_console(_buf: u64, _len: usize) -> Result<i64, KernelError> {
+    Err(KernelError::WouldBlock)
+}
+
+fn sys_timerfd(new: u64) -> Result<i64, KernelError> {
+    let mut values = [0; 4];
+    copy_in(new, &mut values)?;
+    if values[1] >= 1_000_000 || values[3] >= 1_000_000 {
+        return Err(KernelError::InvalidArgument("microseconds out of range"));
+    }
+    let interval = Duration::new(values[0], values[1] as u32 * 1000);
+    let first = Duration::new(values[2], values[3] as u32 * 1000);
+    if Instant::now().checked_add(first).is_none() {
+        return Err(KernelError::InvalidArgument("timer too long"));
+    }
+    let mut timers = TIMERS.lock();
+    let index = timers.iter().position(Option::is_none).ok_or(KernelError::InvalidArgument("too many timers"))?;
+    timers[index] = Some(poll::Timer::new(first, Some(interval)));
+    Ok(i64::from(FIRST_TIMER) + index as i64)
+}
+
+fn sys_close(fd: i32) -> Result<i64, KernelError> {
+    let mut timers = TIMERS.lock();
+    timer_slot(&mut timers, fd)?;
+    timers[(fd - FIRST_TIMER) as usize] = None;
+    Ok(0)
+}
+
+fn sys_uname(buf: u64, len: usize) -> Result<i64, KernelError> {
+    let version = crate::version();
+    let features: Vec<&str> = version.features().collect();
+    let fields = format!("heorot\0{}\0{} {}\0x86_64\0{}\0", version.release, version.git, version.built(),
+        features.join(","));
+    if fields.len() > len {
+        return Err(KernelError::InvalidArgument("buffer too short"));
+    }
+    let start = user_range(buf, fields.len(), true)?;
+    // Checked as mapped and writable above
+    unsafe { uaccess::copy_to_user(start, fields.as_bytes()) }.map_err(|_| KernelError::BadAddress)?;
+    Ok(fields.len() as i64)
+}
+
+fn sys_gettimeofday(tv: u64) -> Result<i64, KernelError> {
+    let now = time::SystemTime::now().since_unix_epoch();
+    copy_out(tv, &[now.as_secs(), u64::from(now.subsec_micros())])?;
+    Ok(0)
+}
+
+fn sys_clock_gettime(clock: u64, ts: u64) -> Result<i64, KernelError> {
+    let now = match clock {
+        CLOCK_REALTIME => time::SystemTime::now().since_unix_epoch(),
+        CLOCK_MONOTONIC => time::uptime(),
+        _ => return Err(KernelError::InvalidArgument("no such clock")),
+    };
+    copy_out(ts, &[now.as_secs(), u64::from(now.subsec_nanos())])?;
+    Ok(0)
+}
+
+fn sys_futex(addr: u64, op: u64, val: u64) -> Result<i64, KernelError> {
+    if addr % 4 != 0 {
+        return Err(KernelError::InvalidArgument("futex word isn't aligned"));
+    }
+    let start = user_range(addr, 4, false)?;
+    let key = paging::translate_addr(start).ok_or(KernelError::BadAddress)?;
+    match op {
+        FUTEX_WAIT => {
+            let unchanged = || {
+                let mut word = [0; 4];
+                // Checked as mapped above
+                let read = unsafe { uaccess::copy_from_user(&mut word, start) };
+                read.is_ok() && u64::from(u32::from_le_bytes(word)) == val
+            };
+            let wake = signal::wake_for_real();
+            let result = futex::wait(key, unchanged, signal::interrupted);
+            if let Some(id) = wake {
+                crate::timer::cancel(id);
+            }
+            result.map(|()| 0)
+        }
+        FUTEX_WAKE => Ok(futex::wake(key, usize::try_from(val).unwrap_or(usize::MAX)) as i64),
+        _ => Err(KernelError::InvalidArgument("no such futex operation")),
+    }
+}
+
+/// Give up the locks the program took, and close its timers; it's ended
+pub(super) fn release() {
+    let locks = core::mem::take(&mut *LOCKS.lock());
+    drop(locks);
+    *TIMERS.lock() = [None; MAX_TIMERS];
+}
+
+fn sys_grow(pages: u64) -> Result<i64, KernelError> {
+    match super::grow(pages as usize).map_err(KernelError::from) {
+        Ok(start) => Ok(start.as_u64() as i64),
+        Err(KernelError::Memory(MemoryError::OverLimit)) => super::exit(Exit::OutOfMemory),
+        Err(_) => Err(KernelError::Memory(MemoryError::OutOfMemory)),
+    }
+}
+
+fn sys_write(buf: u64, len: usize) -> Result<i64, KernelError> {
+    let start = user_range(buf, len, false)?;
+    let mut chunk = [0; 256];
+    // Bytes of a character cut off by the end of the last chunk
+    let mut kept = 0;
+    let mut done = 0;
+    while done < len {
+        let n = (chunk.len() - kept).min(len - done);
+        // Checked as mapped above, and nothing else runs in ring 3 to unmap it
+        unsafe { uaccess::copy_from_user(&mut chunk[kept..kept + n], start + done as u64) }
+            .map_err(|_| KernelError::BadAddress)?;
+        done += n;
+        let filled = kept + n;
+        kept = console::print_utf8(&chunk[..filled]);
+        chunk.copy_within(filled - kept..filled, 0);
+    }
+    if kept > 0 {
+        print!("{}", core::char::REPLACEMENT_CHARACTER);
+    }
+    Ok(len as i64)
+}
+
+pub(super) fn init() {
+    let selectors = gdt::selectors();
+    // The GDT is laid out the way this wants, so it can't fail
+    Star::write(selectors.user_code_selector, selectors.user_data_selector, selectors.code_selector,
+        selectors.data_selector)
+        .expect("GDT isn't laid out for SYSCALL");
+    LStar::write(VirtAddr::new(heorot_syscall_entry as usize as u64));
+    // Alignment check too, or a program could switch SMAP off for the kernel
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
@@ -0,0 +1,155 @@
+//! Syscall tracing, as strace does it: with it on, each syscall the
+//! program makes is printed once it's done, with its arguments decoded and
+//! what it returned. It's for the one program running, and goes off when
+//! that program ends.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::string::String;
+use crate::println;
+use super::syscall::{self, MAX_PATH};
+
+// Set for the program about to run, or running
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+// Strings longer than this are cut short, as strace's -s has it
+const MAX_SHOWN: usize = 32;
+
+// How an argument is shown
+#[derive(Clone, Copy)]
+enum Arg {
+    Number,
+    Pointer,
+    // A pointer to as many bytes as the next argument says
+    Text,
+}
+
+// Each syscall's name and arguments, by number, and whether it returns an
+// address rather than a number
+const SYSCALLS: [(&str, &[Arg], bool); 24] = {
+    use Arg::*;
+    [
+        ("exit", &[Number], false),
+        ("write", &[Text, Number], false),
+        ("yield", &[], false),
+        ("grow", &[Number], true),
+        ("getrusage", &[Number, Pointer], false),
+        ("times", &[Pointer], false),
+        ("nanosleep", &[Pointer, Pointer], false),
+        ("setitimer", &[Number, Pointer, Pointer], false),
+        ("sigaction", &[Number, Pointer], true),
+        ("sigreturn", &[Number], false),
+        ("sched_setaffinity", &[Number, Number, Pointer], false),
+        ("sched_getaffinity", &[Number, Number, Pointer], false),
+        ("stat", &[Text, Number, Pointer], false),
+        ("flock", &[Text, Number, Number], false),
+        ("poll", &[Pointer, Number, Number], false),
+        ("read", &[Number, Pointer, Number], false),
+        ("timerfd", &[Pointer], false),
+        ("close", &[Number], false),
+        ("uname", &[Pointer, Number], false),
+        ("gettimeofday", &[Pointer], false),
+        ("clock_gettime", &[Number, Pointer], false),
+        ("futex", &[Pointer, Number, Number], false),
+        ("capget", &[], false),
+        ("capdrop", &[Number], false),
+    ]
+};
+
+/// Trace the syscalls of the next program run, or stop
+pub fn set(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+fn errno_name(errno: i64) -> Option<&'static str> {
+    Some(match errno {
+        syscall::EPERM => "EPERM",
+        syscall::ENOENT => "ENOENT",
+        syscall::ESRCH => "ESRCH",
+        syscall::EINTR => "EINTR",
+        syscall::EIO => "EIO",
+        syscall::EBADF => "EBADF",
+        syscall::EWOULDBLOCK => "EWOULDBLOCK",
+        syscall::ENOMEM => "ENOMEM",
+        syscall::EFAULT => "EFAULT",
+        syscall::ENOTDIR => "ENOTDIR",
+        syscall::EINVAL => "EINVAL",
+        syscall::EROFS => "EROFS",
+        syscall::ENAMETOOLONG => "ENAMETOOLONG",
+        syscall::ENOSYS => "ENOSYS",
+        _ => return None,
+    })
+}
+
+/// Syscall `number`'s name, if there's one by that number
+pub(super) fn name(number: u64) -> Option<&'static str> {
+    SYSCALLS.get(number as usize).map(|&(name, _, _)| name)
+}
+
+// "name(arguments" for syscall `number`, without the closing parenthesis
+fn call(number: u64, args: [u64; 3]) -> String {
+    let mut line = String::new();
+    let (name, kinds) = match SYSCALLS.get(number as usize) {
+        Some(&(name, kinds, _)) => (name, kinds),
+        None => {
+            let _ = write!(line, "syscall_{}({:#x}, {:#x}, {:#x}", number, args[0], args[1], args[2]);
+            return line;
+        }
+    };
+    let _ = write!(line, "{}(", name);
+    for (index, kind) in kinds.iter().enumerate() {
+        if index > 0 {
+            line.push_str(", ");
+        }
+        let arg = args[index];
+        let _ = match kind {
+            Arg::Number => write!(line, "{}", arg as i64),
+            Arg::Pointer if arg == 0 => write!(line, "NULL"),
+            Arg::Pointer => write!(line, "{:#x}", arg),
+            Arg::Text => {
+                let len = (args[index + 1] as usize).min(MAX_SHOWN).min(MAX_PATH);
+                let mut bytes = [0; MAX_PATH];
+                match syscall::copy_path(arg, len, &mut bytes) {
+                    Ok(text) if len < args[index + 1] as usize => write!(line, "{:?}...", text),
+                    Ok(text) => write!(line, "{:?}", text),
+                    Err(_) => write!(line, "{:#x}", arg),
+                }
+            }
+        };
+    }
+    line
+}
+
+/// Print a syscall that's returned `result`
+pub(super) fn returned(number: u64, args: [u64; 3], result: i64) {
+    let address = SYSCALLS.get(number as usize).map_or(false, |&(_, _, address)| address);
+    let line = call(number, args);
+    match errno_name(result) {
+        Some(name) => println!("{}) = -1 {}", line, name),
+        None if address && result > 0 => println!("{}) = {:#x}", line, result),
+        None => println!("{}) = {}", line, result),
+    }
+}
+
+/// Print a syscall that won't return
+pub(super) fn entered(number: u64, args: [u64; 3]) {
+    println!("{}) = ?", call(number, args));
+}
+
+/// TESTS
+
+#[test_case]
+fn test_decoding() {
+    assert_eq!(call(syscall::SYS_GETRUSAGE, [0, 0x1000, 7]), "getrusage(0, 0x1000");
+    assert_eq!(call(syscall::SYS_SETITIMER, [0, 0, 0x2000]), "setitimer(0, NULL, 0x2000");
+    assert_eq!(call(syscall::SYS_YIELD, [1, 2, 3]), "yield(");
+    assert_eq!(call(99, [1, 2, 3]), "syscall_99(0x1, 0x2, 0x3");
+    // Not a user address, so shown as a pointer
+    assert_eq!(call(syscall::SYS_WRITE, [0xffff_8000_0000_0000, 4, 0]), "write(0xffff800000000000, 4");
+    assert_eq!(errno_name(syscall::ENOENT), Some("ENOENT"));
+    assert_eq!(errno_name(-1000), None);
+}
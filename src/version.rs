@@ -0,0 +1,73 @@
+//! What this kernel is: its release, and the commit, time, compiler and
+//! features it was built from, as build.rs found them. It's what the boot
+//! banner and the uname syscall say.
+
+use core::fmt;
+use crate::time::{Duration, SystemTime};
+
+pub struct Version {
+    /// The crate's version
+    pub release: &'static str,
+    /// The commit, short, with -dirty if the tree had changes; "unknown"
+    /// built outside git
+    pub git: &'static str,
+    /// What `rustc --version` said
+    pub rustc: &'static str,
+    // Seconds since the Unix epoch
+    built: &'static str,
+    // Comma-separated
+    features: &'static str,
+}
+
+static VERSION: Version = Version {
+    release: env!("CARGO_PKG_VERSION"),
+    git: env!("HEOROT_GIT_HASH"),
+    rustc: env!("HEOROT_RUSTC"),
+    built: env!("HEOROT_BUILD_TIME"),
+    features: env!("HEOROT_FEATURES"),
+};
+
+pub fn version() -> &'static Version {
+    &VERSION
+}
+
+impl Version {
+    /// When it was built
+    pub fn built(&self) -> SystemTime {
+        SystemTime::from_unix(Duration::from_secs(self.built.parse().unwrap_or(0)))
+    }
+
+    /// The Cargo features it was built with, sorted
+    pub fn features(&self) -> impl Iterator<Item = &'static str> {
+        self.features.split(',').filter(|feature| !feature.is_empty())
+    }
+}
+
+/// e.g. heorot 0.1.0 (1a2b3c4, built 2026-10-14T12:24:04.000Z, rustc 1.75.0-nightly) [keyboard, shell]
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "heorot {} ({}, built {}, {}) [", self.release, self.git, self.built(), self.rustc)?;
+        for (index, feature) in self.features().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", feature)?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_version() {
+    use alloc::string::ToString;
+
+    let version = version();
+    assert_eq!(version.release, env!("CARGO_PKG_VERSION"));
+    assert!(!version.git.is_empty());
+    assert!(version.built() > SystemTime::UNIX_EPOCH);
+    assert_eq!(version.features().any(|feature| feature == "shell"), cfg!(feature = "shell"));
+    assert_eq!(version.features().any(|feature| feature == "fault-inject"), cfg!(feature = "fault-inject"));
+    assert!(version.to_string().starts_with("heorot "));
+}
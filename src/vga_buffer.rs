@@ -2,6 +2,9 @@ use volatile::Volatile;
 use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::console::{Attributes, Console, EscapeParser, Parsed};
+use crate::cp437;
 
 /// Static values for colors in a C-style struct
 #[allow(dead_code)] // Don't throw compiler errors for unused items
@@ -45,8 +48,8 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 /// New Buffer type (for the text buffer)
 #[repr(transparent)]
@@ -54,16 +57,137 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
-/// New Writer type, this writes to the screen.
+// ANSI color numbers (30-37 and 90-97 in SGR) in VGA terms
+const ANSI_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+
+// CRT controller: write a register's index to the first port, then read or
+// write its value through the second
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CURSOR_START: u8 = 0x0a;
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8 = 0x0f;
+// In CURSOR_START: hide the cursor
+const CURSOR_DISABLE: u8 = 0x20;
+
+fn read_crtc(index: u8) -> u8 {
+    unsafe {
+        Port::new(CRTC_INDEX).write(index);
+        Port::new(CRTC_DATA).read()
+    }
+}
+
+fn write_crtc(index: u8, value: u8) {
+    unsafe {
+        Port::new(CRTC_INDEX).write(index);
+        Port::new(CRTC_DATA).write(value);
+    }
+}
+
+/// New Writer type, this writes to the screen. Everything goes into a
+/// shadow copy of the screen first, and `flush` copies the rows that changed
+/// out to the real buffer in one pass; writes flush when they're done, so
+/// only the last of several scrolls in between costs a full-screen copy.
 pub struct Writer {
     column_position: usize,
+    // Usually the bottom row, unless an escape sequence moved the cursor
+    row_position: usize,
     color_code: ColorCode,
+    // The colors escape sequences picked
+    attributes: Attributes,
+    escape: EscapeParser,
     buffer: &'static mut Buffer, // 'static specifies that the reference is valid for the whole program's run time
+    // What the screen is to show, once flushed
+    shadow: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    // Per row, the columns changed since the last flush
+    dirty: [Option<(usize, usize)>; BUFFER_HEIGHT],
 }
 
 /// Allow us to write a single ASCII byte
 impl Writer {
+    fn new(buffer: &'static mut Buffer) -> Writer {
+        let blank = ScreenChar { ascii_character: b' ', color_code: ColorCode(0) };
+        let mut writer = Writer {
+            column_position: 0,
+            row_position: BUFFER_HEIGHT - 1,
+            color_code: ColorCode::new(
+                ANSI_COLORS[Attributes::DEFAULT.foreground],
+                ANSI_COLORS[Attributes::DEFAULT.background],
+            ),
+            attributes: Attributes::DEFAULT,
+            escape: EscapeParser::new(),
+            buffer,
+            shadow: [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            dirty: [None; BUFFER_HEIGHT],
+        };
+        writer.reload();
+        writer
+    }
+
+    // Start the shadow over from what's on screen, e.g. what the early
+    // console wrote there behind our back
+    fn reload(&mut self) {
+        for (shadow, buffer) in self.shadow.iter_mut().zip(self.buffer.chars.iter()) {
+            for (cell, screen) in shadow.iter_mut().zip(buffer.iter()) {
+                *cell = screen.read();
+            }
+        }
+        self.dirty = [None; BUFFER_HEIGHT];
+    }
+
+    // Put a cell in the shadow, to go out with the next flush
+    fn put(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.shadow[row][col] = character;
+        self.mark_dirty(row, col..col + 1);
+    }
+
+    fn mark_dirty(&mut self, row: usize, cols: core::ops::Range<usize>) {
+        self.dirty[row] = Some(match self.dirty[row] {
+            Some((start, end)) => (start.min(cols.start), end.max(cols.end)),
+            None => (cols.start, cols.end),
+        });
+    }
+
+    /// Copy whatever changed since last time out to the screen
+    pub fn flush(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            if let Some((start, end)) = self.dirty[row].take() {
+                for col in start..end {
+                    self.buffer.chars[row][col].write(self.shadow[row][col]);
+                }
+            }
+        }
+    }
+
+    /// Write a byte at the write position; a newline flushes
     pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => {
+                self.new_line();
+                self.flush();
+            }
+            byte => self.put_byte(byte),
+        }
+    }
+
+    fn put_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(), // If the character given is \n, then call the new_line method
             byte => {
@@ -71,12 +195,12 @@ impl Writer {
                     self.new_line(); // If the current line is full, insert a newline
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
                 // Write a new ScreenChar at the current position
-                self.buffer.chars[row][col].write(ScreenChar {
+                self.put(row, col, ScreenChar {
                     ascii_character: byte,
                     color_code,
                 });
@@ -86,35 +210,176 @@ impl Writer {
     }
 
     fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-              let character = self.buffer.chars[row][col].read();
-              self.buffer.chars[row - 1][col].write(character);
-            }
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            return;
+        }
+        self.shadow.copy_within(1.., 0);
+        for row in 0..BUFFER_HEIGHT {
+            self.mark_dirty(row, 0..BUFFER_WIDTH);
         }
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
 
     fn clear_row(&mut self, row: usize) {
+        self.blank(row, 0..BUFFER_WIDTH);
+    }
+
+    // Blank some of a row in the current colors
+    fn blank(&mut self, row: usize, cols: core::ops::Range<usize>) {
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        for col in cols {
+            self.put(row, col, blank);
+        }
+    }
+
+    fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+        self.row_position = BUFFER_HEIGHT - 1;
+        self.flush();
+        self.update_cursor();
+    }
+
+    // Put the blinking cursor where the next character will go. At the end
+    // of a full row it waits in the last column until the row wraps.
+    fn update_cursor(&self) {
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        let position = (self.row_position * BUFFER_WIDTH + col) as u16;
+        write_crtc(CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+        write_crtc(CURSOR_LOCATION_LOW, position as u8);
+    }
+
+    // Step back a column and blank it; stops at the start of the row
+    fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position = self.column_position.min(BUFFER_WIDTH) - 1;
+            let col = self.column_position;
+            self.blank(self.row_position, col..col + 1);
+        }
+    }
+
+    /// Put a character straight into a cell, ignoring the write position
+    fn write_cell(&mut self, row: usize, col: usize, byte: u8, color_code: ColorCode) {
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            self.put(row, col, ScreenChar {
+                ascii_character: byte,
+                color_code,
+            });
+        }
+    }
+
+    // Swap a cell's foreground and background colors
+    fn invert_cell(&mut self, row: usize, col: usize) {
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            let mut cell = self.shadow[row][col];
+            cell.color_code = ColorCode(cell.color_code.0.rotate_left(4));
+            self.put(row, col, cell);
+        }
+    }
+
+    fn read_cell(&self, row: usize, col: usize) -> u8 {
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            self.shadow[row][col].ascii_character
+        } else {
+            b' '
+        }
+    }
+
+    fn read_cell_colors(&self, row: usize, col: usize) -> (u8, usize, usize) {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return (b' ', Attributes::DEFAULT.foreground, Attributes::DEFAULT.background);
         }
+        let cell = self.shadow[row][col];
+        // Back from VGA colors to ANSI numbers; every VGA color has one
+        let ansi = |color: u8| ANSI_COLORS.iter().position(|&ansi| ansi as u8 == color).unwrap_or(0);
+        (cell.ascii_character, ansi(cell.color_code.0 & 0xf), ansi((cell.color_code.0 >> 4) & 0xf))
+    }
+
+    /// Move the write position within the current row
+    pub fn set_column(&mut self, column: usize) {
+        self.column_position = column.min(BUFFER_WIDTH);
+        self.flush();
+        self.update_cursor();
     }
 
+    /// Write text, interpreting the ANSI escape sequences a serial terminal
+    /// would for the same output: SGR colors (ESC[...m), cursor moves
+    /// (ESC[H, A, B, C, D), and erasing (ESC[J, K). Rows and columns count
+    /// from 1 at the top left. Anything else is swallowed. Backspace steps
+    /// back and erases. Each character takes one cell, shown as its code page
+    /// 437 glyph if it has one.
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // not part of printable ASCII range; print a box
-                _ => self.write_byte(0xfe),
+        for c in s.chars() {
+            match self.escape.feed(c) {
+                Parsed::Char('\r') => self.column_position = 0,
+                Parsed::Char('\u{8}') => self.backspace(),
+                Parsed::Char('\n') => self.new_line(),
+                // not in code page 437; print a box
+                Parsed::Char(c) => self.put_byte(cp437::encode(c).unwrap_or(0xfe)),
+                Parsed::Control { command, params, count } => self.control_sequence(command, &params[..count]),
+                Parsed::Pending => {}
             }
+        }
+        self.flush();
+        self.update_cursor();
+    }
+
+    fn control_sequence(&mut self, command: u8, params: &[u16]) {
+        // Counts and positions treat 0 (or nothing) as 1
+        let count = |index: usize| params.get(index).map_or(1, |&param| usize::from(param).max(1));
+        let last_row = BUFFER_HEIGHT - 1;
+        let last_col = BUFFER_WIDTH - 1;
+        match command {
+            b'm' => {
+                for &param in params {
+                    self.attributes.select_graphic(param);
+                }
+                let foreground = ANSI_COLORS[self.attributes.shown_foreground()];
+                self.color_code = ColorCode::new(foreground, ANSI_COLORS[self.attributes.background]);
+            }
+            b'H' | b'f' => {
+                self.row_position = (count(0) - 1).min(last_row);
+                self.column_position = (count(1) - 1).min(last_col);
+            }
+            b'A' => self.row_position = self.row_position.saturating_sub(count(0)),
+            b'B' => self.row_position = (self.row_position + count(0)).min(last_row),
+            b'C' => self.column_position = (self.column_position + count(0)).min(last_col),
+            b'D' => self.column_position = self.column_position.min(last_col).saturating_sub(count(0)),
+            b'J' => self.erase_display(params[0]),
+            b'K' => self.erase_line(params[0]),
+            _ => {}
+        }
+    }
+
+    // 0: from the cursor on, 1: up to and including the cursor, 2: all of it
+    fn erase_line(&mut self, mode: u16) {
+        let (row, col) = (self.row_position, self.column_position.min(BUFFER_WIDTH - 1));
+        match mode {
+            0 => self.blank(row, col..BUFFER_WIDTH),
+            1 => self.blank(row, 0..col + 1),
+            2 => self.clear_row(row),
+            _ => {}
+        }
+    }
 
+    // Same modes as erase_line, for the whole screen; the cursor stays put
+    fn erase_display(&mut self, mode: u16) {
+        let row = self.row_position;
+        match mode {
+            0 => (row + 1..BUFFER_HEIGHT).for_each(|row| self.clear_row(row)),
+            1 => (0..row).for_each(|row| self.clear_row(row)),
+            2 => (0..BUFFER_HEIGHT).for_each(|row| self.clear_row(row)),
+            _ => return,
+        }
+        if mode != 2 {
+            self.erase_line(mode);
         }
     }
 }
@@ -122,17 +387,27 @@ impl Writer {
 /// Add support for Rust's core write methods & formatting macros
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-      self.write_string(s);
-      Ok(())
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+impl Console for Writer {
+    fn write_string(&mut self, s: &str) {
+        Writer::write_string(self, s);
+    }
+
+    fn set_column(&mut self, column: usize) {
+        Writer::set_column(self, column);
+    }
+
+    fn clear_screen(&mut self) {
+        Writer::clear_screen(self);
     }
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Green, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new(unsafe { &mut *(0xb8000 as *mut Buffer) }));
 }
 
 #[macro_export]
@@ -148,14 +423,130 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
+    crate::console::tee(args);
+    if crate::cmdline::headless() {
+        crate::serial::_print(args);
+        return;
+    }
+    crate::console::with_console(|console| crate::console::write_fmt(console, args));
+}
+
+/// Move the console's write position to `column` on the current row, e.g.
+/// to redraw a line
+pub fn set_column(column: usize) {
+    // Over serial, the terminal's cursor stands in for the write position
+    if crate::cmdline::headless() {
+        crate::serial::_print(format_args!("\r"));
+        if column > 0 {
+            crate::serial::_print(format_args!("\x1b[{}C", column));
+        }
+        return;
+    }
+
+    crate::console::with_console(|console| console.set_column(column));
+}
+
+/// Blank the whole screen and start writing again from the bottom left
+pub fn clear_screen() {
+    if crate::cmdline::headless() {
+        crate::serial::_print(format_args!("\x1b[2J\x1b[H"));
+        return;
+    }
+
+    crate::console::with_console(|console| console.clear_screen());
+}
+
+/// Show or hide the blinking cursor, e.g. while a full-screen program runs
+pub fn set_cursor_visible(visible: bool) {
     use x86_64::instructions::interrupts;
 
+    if crate::cmdline::headless() {
+        crate::serial::_print(format_args!("\x1b[?25{}", if visible { 'h' } else { 'l' }));
+        return;
+    }
+
+    // The rest of the register is the cursor's shape; leave it alone
     interrupts::without_interrupts(|| {
-      WRITER.lock().write_fmt(args).unwrap();
+        let start = read_crtc(CURSOR_START);
+        let start = if visible { start & !CURSOR_DISABLE } else { start | CURSOR_DISABLE };
+        write_crtc(CURSOR_START, start);
     });
 }
 
+/// Draw a character anywhere on screen, for full-screen programs like games
+pub fn write_cell(row: usize, col: usize, byte: u8, foreground: Color, background: Color) {
+    use x86_64::instructions::interrupts;
+
+    if crate::cmdline::headless() {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_cell(row, col, byte, ColorCode::new(foreground, background));
+        writer.flush();
+    });
+}
+
+/// Swap a cell's colors, e.g. to highlight a selection; doing it twice
+/// puts the cell back as it was
+pub fn invert_cell(row: usize, col: usize) {
+    use x86_64::instructions::interrupts;
+
+    if crate::cmdline::headless() {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.invert_cell(row, col);
+        writer.flush();
+    });
+}
+
+/// The character currently shown in a cell, e.g. to draw a pointer over it
+pub fn read_cell(row: usize, col: usize) -> u8 {
+    use x86_64::instructions::interrupts;
+
+    if crate::cmdline::headless() {
+        return b' ';
+    }
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().read_cell(row, col)
+    })
+}
+
+/// Pick up whatever was written straight to the screen meanwhile, as the
+/// early console does until the regular one is ready
+pub(crate) fn reload() {
+    use x86_64::instructions::interrupts;
+
+    if crate::cmdline::headless() {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.flush();
+        writer.reload();
+    });
+}
+
+/// A cell's character with its foreground and background as ANSI color
+/// numbers, e.g. to draw the screen into an image
+pub fn read_cell_colors(row: usize, col: usize) -> (u8, usize, usize) {
+    use x86_64::instructions::interrupts;
+
+    if crate::cmdline::headless() {
+        return (b' ', Attributes::DEFAULT.foreground, Attributes::DEFAULT.background);
+    }
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().read_cell_colors(row, col)
+    })
+}
+
 /// TESTS
 
 #[test_case]
@@ -185,3 +576,111 @@ fn test_println_output() {
         }
     });
 }
+
+#[test_case]
+fn test_clear_screen() {
+    use x86_64::instructions::interrupts;
+
+    println!("some text to clear");
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.clear_screen();
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                assert_eq!(writer.buffer.chars[row][col].read().ascii_character, b' ');
+            }
+        }
+        assert_eq!(writer.column_position, 0);
+    });
+}
+
+#[test_case]
+fn test_ansi_colors() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\n\x1b[31mR\x1b[1;44mB\x1b[0mD");
+        let row = &writer.buffer.chars[BUFFER_HEIGHT - 1];
+        assert_eq!(row[0].read().color_code, ColorCode::new(Color::Red, Color::Black));
+        assert_eq!(row[1].read().color_code, ColorCode::new(Color::LightRed, Color::Blue));
+        assert_eq!(row[2].read().color_code, ColorCode::new(Color::Green, Color::Black));
+        assert_eq!(row[2].read().ascii_character, b'D');
+    });
+}
+
+#[test_case]
+fn test_ansi_cursor_movement() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        // Split mid-sequence, as separate print! calls might
+        writer.write_string("\x1b[3;");
+        writer.write_string("5Hab\x1b[2Dc");
+        assert_eq!(writer.buffer.chars[2][4].read().ascii_character, b'c');
+        assert_eq!(writer.buffer.chars[2][5].read().ascii_character, b'b');
+        writer.write_string("\x1b[2K");
+        assert_eq!(writer.buffer.chars[2][5].read().ascii_character, b' ');
+
+        // Back to the bottom row, where everything else expects to write
+        writer.write_string("\x1b[25;1H\x1b[K");
+        assert_eq!((writer.row_position, writer.column_position), (BUFFER_HEIGHT - 1, 0));
+    });
+}
+
+#[test_case]
+fn test_backspace_and_cursor() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\nab\x08");
+        let row = BUFFER_HEIGHT - 1;
+        assert_eq!(writer.buffer.chars[row][1].read().ascii_character, b' ');
+        assert_eq!(writer.column_position, 1);
+        let position = u16::from(read_crtc(CURSOR_LOCATION_HIGH)) << 8 | u16::from(read_crtc(CURSOR_LOCATION_LOW));
+        assert_eq!(usize::from(position), row * BUFFER_WIDTH + 1);
+    });
+}
+
+#[test_case]
+fn test_utf8_one_cell_per_char() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\né─€x");
+        let row = &writer.buffer.chars[BUFFER_HEIGHT - 1];
+        assert_eq!(row[0].read().ascii_character, 0x82);
+        assert_eq!(row[1].read().ascii_character, 0xc4);
+        assert_eq!(row[2].read().ascii_character, 0xfe);
+        assert_eq!(row[3].read().ascii_character, b'x');
+        assert_eq!(writer.column_position, 4);
+    });
+}
+
+#[test_case]
+fn test_writes_wait_for_flush() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\n");
+        let row = BUFFER_HEIGHT - 1;
+        writer.write_byte(b'q');
+        // In the shadow, not on screen yet
+        assert_eq!(writer.read_cell(row, 0), b'q');
+        assert_eq!(writer.buffer.chars[row][0].read().ascii_character, b' ');
+        assert_eq!(writer.dirty[row], Some((0, 1)));
+        // A newline scrolls it up and flushes the lot
+        writer.write_byte(b'\n');
+        assert_eq!(writer.buffer.chars[row - 1][0].read().ascii_character, b'q');
+        assert_eq!(writer.buffer.chars[row][0].read().ascii_character, b' ');
+        assert!(writer.dirty.iter().all(Option::is_none));
+        writer.write_byte(b'r');
+        writer.flush();
+        assert_eq!(writer.buffer.chars[row][0].read().ascii_character, b'r');
+        writer.write_string("\n");
+    });
+}
@@ -0,0 +1,237 @@
+//! Workqueues: kernel threads that run the closures queued on them, one at
+//! a time and in the order they were queued. They're for driver work that
+//! can wait, or blocks for too long, to do in an interrupt handler or in a
+//! timer callback run from the idle loop.
+//!
+//! Delayed work waits out its delay on a timer, then joins the back of its
+//! queue. Workqueues last forever. The system one, "events", is for
+//! whoever doesn't need their own.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::sched::{self, ThreadId};
+use crate::time::{self, Duration, Instant};
+use crate::timer;
+
+const MAX_QUEUES: usize = 8;
+// How often flush looks again
+const FLUSH_POLL: Duration = Duration::from_millis(1);
+
+/// A workqueue, from `create`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Workqueue(usize);
+
+/// Handle for cancelling queued work
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WorkId(u64);
+
+struct Work {
+    id: WorkId,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+struct Queue {
+    worker: ThreadId,
+    pending: VecDeque<Work>,
+    // Waiting out their delays, with when they're due
+    delayed: Vec<(Instant, Work)>,
+    // What the worker's running now
+    running: Option<WorkId>,
+}
+
+// Only ever locked with interrupts off, so a handler can't find it held
+static QUEUES: Mutex<Vec<Queue>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Start a workqueue, with a worker thread called `name` to run it
+pub fn create(name: &'static str) -> Result<Workqueue, &'static str> {
+    // With interrupts off the worker can't run before its queue is there
+    interrupts::without_interrupts(|| {
+        let mut queues = QUEUES.lock();
+        if queues.len() == MAX_QUEUES {
+            return Err("too many workqueues");
+        }
+        let worker = sched::spawn(name, worker)?;
+        queues.push(Queue { worker, pending: VecDeque::new(), delayed: Vec::new(), running: None });
+        Ok(Workqueue(queues.len() - 1))
+    })
+}
+
+/// The workqueue for work that doesn't need one of its own
+pub fn system() -> Workqueue {
+    // The first one init creates
+    Workqueue(0)
+}
+
+fn worker() {
+    let me = sched::current();
+    let index = interrupts::without_interrupts(|| QUEUES.lock().iter().position(|queue| queue.worker == me))
+        .expect("workqueue worker without a workqueue");
+    loop {
+        let work = interrupts::without_interrupts(|| {
+            let mut queues = QUEUES.lock();
+            let queue = &mut queues[index];
+            let work = queue.pending.pop_front();
+            queue.running = work.as_ref().map(|work| work.id);
+            work
+        });
+        match work {
+            Some(work) => (work.run)(),
+            None => sched::park(),
+        }
+    }
+}
+
+fn next_id() -> WorkId {
+    WorkId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Have `queue`'s worker run `work`, after whatever's queued there already.
+/// Safe from interrupt handlers.
+pub fn queue(queue: Workqueue, work: impl FnOnce() + Send + 'static) -> Result<WorkId, &'static str> {
+    let work = Work { id: next_id(), run: Box::new(work) };
+    let id = work.id;
+    interrupts::without_interrupts(|| {
+        let mut queues = QUEUES.lock();
+        let queue = queues.get_mut(queue.0).ok_or("no such workqueue")?;
+        queue.pending.push_back(work);
+        sched::unpark(queue.worker);
+        Ok(id)
+    })
+}
+
+/// `queue` `work` once `delay` is up
+pub fn queue_delayed(queue: Workqueue, delay: Duration, work: impl FnOnce() + Send + 'static)
+    -> Result<WorkId, &'static str>
+{
+    let work = Work { id: next_id(), run: Box::new(work) };
+    let id = work.id;
+    let due = Instant::now() + delay;
+    interrupts::without_interrupts(|| {
+        let mut queues = QUEUES.lock();
+        queues.get_mut(queue.0).ok_or("no such workqueue").map(|queue| queue.delayed.push((due, work)))
+    })?;
+    if let Err(message) = timer::after(delay, come_due) {
+        cancel(id);
+        return Err(message);
+    }
+    Ok(id)
+}
+
+// The timer callback: queue whatever delayed work is due, earliest first
+fn come_due() {
+    let now = Instant::now();
+    interrupts::without_interrupts(|| {
+        for queue in QUEUES.lock().iter_mut() {
+            let mut queued = false;
+            loop {
+                let due = queue.delayed.iter().enumerate().filter(|(_, (due, _))| *due <= now);
+                let index = match due.min_by_key(|(_, (due, _))| *due) {
+                    Some((index, _)) => index,
+                    None => break,
+                };
+                let (_, work) = queue.delayed.remove(index);
+                queue.pending.push_back(work);
+                queued = true;
+            }
+            if queued {
+                sched::unpark(queue.worker);
+            }
+        }
+    });
+}
+
+/// Take back work that hasn't started yet; false if it has, or if it's
+/// already done
+pub fn cancel(id: WorkId) -> bool {
+    let work = interrupts::without_interrupts(|| {
+        for queue in QUEUES.lock().iter_mut() {
+            if let Some(index) = queue.pending.iter().position(|work| work.id == id) {
+                return queue.pending.remove(index);
+            }
+            if let Some(index) = queue.delayed.iter().position(|(_, work)| work.id == id) {
+                return Some(queue.delayed.swap_remove(index).1);
+            }
+        }
+        None
+    });
+    // Dropped here, once the lock's let go and interrupts are back on
+    work.is_some()
+}
+
+/// Wait until everything queued on `queue` so far has run; delayed work
+/// counts once it's due. Not from `queue`'s own work, which would wait
+/// for itself, and needs interrupts on.
+pub fn flush(queue: Workqueue) {
+    let last = next_id();
+    loop {
+        let busy = interrupts::without_interrupts(|| {
+            QUEUES.lock().get(queue.0).map_or(false, |queue| {
+                queue.running.map_or(false, |id| id < last) || queue.pending.iter().any(|work| work.id < last)
+            })
+        });
+        if !busy {
+            return;
+        }
+        time::sleep(FLUSH_POLL);
+    }
+}
+
+/// Start the system workqueue. Needs the heap, for the worker's stack.
+pub fn init() {
+    if let Err(message) = create("events") {
+        crate::log::warn!("workqueue: {}", message);
+    }
+}
+
+/// TESTS
+
+#[test_case]
+fn test_runs_work_in_order() {
+    static RAN: AtomicU64 = AtomicU64::new(0);
+
+    for n in 0..4 {
+        queue(system(), move || assert_eq!(RAN.fetch_add(1, Ordering::SeqCst), n)).unwrap();
+    }
+    flush(system());
+    assert_eq!(RAN.load(Ordering::SeqCst), 4);
+}
+
+#[test_case]
+fn test_runs_on_its_own_thread() {
+    static RAN_ON: AtomicU64 = AtomicU64::new(0);
+
+    let own = create("wq-test").unwrap();
+    queue(own, || RAN_ON.store(sched::current().as_u64(), Ordering::SeqCst)).unwrap();
+    flush(own);
+    let ran_on = RAN_ON.load(Ordering::SeqCst);
+    assert!(ran_on != 0 && ran_on != sched::current().as_u64());
+}
+
+#[test_case]
+fn test_delayed_and_cancelled_work() {
+    static RAN: AtomicU64 = AtomicU64::new(0);
+
+    let start = Instant::now();
+    queue_delayed(system(), Duration::from_millis(5), || {
+        RAN.fetch_add(1, Ordering::SeqCst);
+    })
+    .unwrap();
+    let cancelled = queue_delayed(system(), Duration::from_millis(5), || {
+        RAN.fetch_add(10, Ordering::SeqCst);
+    })
+    .unwrap();
+    assert!(cancel(cancelled));
+    assert!(!cancel(cancelled));
+    while RAN.load(Ordering::SeqCst) == 0 {
+        assert!(start.elapsed() < Duration::from_millis(100));
+        time::sleep(Duration::from_millis(1));
+    }
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    time::sleep(Duration::from_millis(10));
+    assert_eq!(RAN.load(Ordering::SeqCst), 1);
+}
@@ -14,10 +14,6 @@ pub extern "C" fn _start() -> ! {
     loop {}
 }
 
-fn test_runner(tests: &[&dyn Fn()]) {
-    unimplemented!();
-}
-
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     heorot::test_panic_handler(info)
@@ -27,3 +23,14 @@ fn panic(info: &PanicInfo) -> ! {
 fn test_println() {
     println!("test_println output");
 }
+
+#[test_case]
+fn test_prelude() {
+    use heorot::prelude::*;
+
+    // What out-of-tree code can count on, without heorot::init
+    assert_eq!(API_VERSION.0, 1);
+    assert!(Duration::from_millis(1) < Duration::from_secs(1));
+    assert_eq!("127.0.0.1/8".parse::<Ipv4Cidr>().map(|cidr| cidr.address), Ok(Ipv4Addr([127, 0, 0, 1])));
+    serial_println!("prelude {}.{}", API_VERSION.0, API_VERSION.1);
+}
@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use heorot::qemu::{self, ExitCode};
+use heorot::{serial_print, serial_println};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("double_panic::panic_in_handler...\t");
+    panic!("first");
+}
+
+// Panics again while handling the first, as a broken logger would; the
+// second has to be caught rather than start the handler over
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !heorot::panic::enter(info) {
+        serial_println!("[ok]");
+        qemu::exit(ExitCode::Success);
+    }
+    panic!("second");
+}
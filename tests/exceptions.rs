@@ -0,0 +1,108 @@
+//! Fault injection: trigger each recoverable exception class on purpose and
+//! check the kernel's handlers report it and let execution carry on. The
+//! double fault handler never returns, so tests/stack_overflow.rs covers it.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(heorot::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use heorot::interrupts::{self, catch_fault};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::VirtAddr;
+
+// Far above anything the bootloader maps, but still canonical
+const UNMAPPED: u64 = 0x0000_7fff_dead_0000;
+const NON_CANONICAL: u64 = 0x8000_0000_0000_0000;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    heorot::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    heorot::test_panic_handler(info)
+}
+
+fn divide_by_zero() {
+    unsafe {
+        asm!("div rcx", inout("rax") 1u64 => _, inout("rdx") 0u64 => _, in("rcx") 0u64);
+    }
+}
+
+fn invalid_opcode() {
+    unsafe { asm!("ud2") };
+}
+
+fn non_canonical_read() {
+    unsafe { core::ptr::read_volatile(NON_CANONICAL as *const u64) };
+}
+
+fn unmapped_read() {
+    unsafe { core::ptr::read_volatile(UNMAPPED as *const u64) };
+}
+
+fn unmapped_write() {
+    unsafe { core::ptr::write_volatile(UNMAPPED as *mut u64, 0) };
+}
+
+#[test_case]
+fn test_divide_error() {
+    let fault = catch_fault(divide_by_zero).unwrap_err();
+    assert_eq!(fault.vector, interrupts::DIVIDE_ERROR_VECTOR);
+    assert_eq!(fault.error_code, None);
+}
+
+#[test_case]
+fn test_invalid_opcode() {
+    let fault = catch_fault(invalid_opcode).unwrap_err();
+    assert_eq!(fault.vector, interrupts::INVALID_OPCODE_VECTOR);
+    // The fault points at the ud2 itself
+    let opcode = unsafe { core::ptr::read(fault.instruction_pointer.as_ptr::<[u8; 2]>()) };
+    assert_eq!(opcode, [0x0f, 0x0b]);
+}
+
+#[test_case]
+fn test_general_protection_fault() {
+    let fault = catch_fault(non_canonical_read).unwrap_err();
+    assert_eq!(fault.vector, interrupts::GENERAL_PROTECTION_VECTOR);
+    assert_eq!(fault.error_code, Some(0));
+}
+
+#[test_case]
+fn test_page_fault_read() {
+    let fault = catch_fault(unmapped_read).unwrap_err();
+    assert_eq!(fault.vector, interrupts::PAGE_FAULT_VECTOR);
+    assert_eq!(fault.address, Some(VirtAddr::new(UNMAPPED)));
+    let error_code = PageFaultErrorCode::from_bits_truncate(fault.error_code.unwrap());
+    assert!(!error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION));
+    assert!(!error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE));
+}
+
+#[test_case]
+fn test_page_fault_write() {
+    let fault = catch_fault(unmapped_write).unwrap_err();
+    let error_code = PageFaultErrorCode::from_bits_truncate(fault.error_code.unwrap());
+    assert!(error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE));
+}
+
+#[test_case]
+fn test_recovery_is_repeatable() {
+    // Each recovery leaves the stack and callee-saved registers intact
+    for _ in 0..100 {
+        assert!(catch_fault(invalid_opcode).is_err());
+        assert!(catch_fault(unmapped_read).is_err());
+    }
+    assert_eq!(catch_fault(|| {}), Ok(()));
+}
+
+#[test_case]
+fn test_breakpoint_continues() {
+    x86_64::instructions::interrupts::int3();
+}
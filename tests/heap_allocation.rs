@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(heorot::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use heorot::allocator::{self, HEAP_SIZE};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    heorot::init();
+    heorot::memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    heorot::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn string_formatting() {
+    let mut string = String::from("heorot");
+    string.push_str(" heap");
+    assert_eq!(alloc::format!("{}!", string), "heorot heap!");
+}
+
+// More allocations in total than the heap could hold at once, so this only
+// passes if freed memory gets reused
+#[test_case]
+fn many_boxes() {
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+// Same, with one allocation held the whole time
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+// Big allocations go to the linked-list allocator, which has to merge
+// freed regions back together for the next one to fit
+#[test_case]
+fn large_allocations_coalesce() {
+    for _ in 0..16 {
+        let first: Vec<u8> = Vec::with_capacity(HEAP_SIZE / 3);
+        let second: Vec<u8> = Vec::with_capacity(HEAP_SIZE / 3);
+        drop(first);
+        drop(second);
+    }
+    let free = allocator::free_bytes();
+    let whole: Vec<u8> = Vec::with_capacity(HEAP_SIZE / 2);
+    assert!(allocator::free_bytes() < free);
+    drop(whole);
+}
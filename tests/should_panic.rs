@@ -2,14 +2,14 @@
 #![no_main]
 
 use core::panic::PanicInfo;
-use heorot::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use heorot::qemu::{self, ExitCode};
+use heorot::{serial_print, serial_println};
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
     should_fail();
     serial_println!("[test did not panic]");
-    exit_qemu(QemuExitCode::Failed);
-    loop{}
+    qemu::exit(ExitCode::Failed);
 }
 
 fn should_fail() {
@@ -20,6 +20,5 @@ fn should_fail() {
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop {}
+    qemu::exit(ExitCode::Success);
 }
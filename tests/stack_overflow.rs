@@ -4,7 +4,8 @@
 
 use core::panic::PanicInfo;
 use heorot::serial_print;
-use heorot::{exit_qemu, QemuExitCode, serial_println};
+use heorot::qemu::{self, ExitCode};
+use heorot::serial_println;
 use lazy_static::lazy_static;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
@@ -27,8 +28,7 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _error_code: u64,
 ) -> ! {
     serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop {}
+    qemu::exit(ExitCode::Success);
 }
 
 #[allow(unconditional_recursion)]
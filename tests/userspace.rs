@@ -0,0 +1,605 @@
+//! Ring 3: small bundled programs that make syscalls or fault, run the way
+//! heorot::user runs them or wrapped up as ELF executables, SMAP catching
+//! the kernel touching their memory directly, memory limits, timers and
+//! the signals they raise, the clocks, futexes, and capabilities.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(heorot::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use heorot::interrupts::{self, catch_fault};
+use heorot::elf;
+use heorot::time::{Duration, Instant, SystemTime};
+use heorot::user::{self, limit, signal, Exit};
+use heorot::user::space::SPACE_START;
+use heorot::vga_buffer::{self, BUFFER_HEIGHT};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    heorot::init();
+    heorot::memory::init(boot_info);
+    heorot::allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    heorot::test_panic_handler(info)
+}
+
+// The programs, position-independent so they run wherever they're loaded.
+// Syscall numbers as in heorot::user::syscall.
+global_asm!(
+    ".global user_hello",
+    ".global user_hello_end",
+    "user_hello:",
+    "lea rdi, [rip + 2f]",
+    "lea rsi, [rip + 3f]",
+    "sub rsi, rdi",
+    "mov eax, 1",
+    "syscall",
+    // Exit with what write returned, after giving way once
+    "mov r12, rax",
+    "mov eax, 2",
+    "syscall",
+    "mov rdi, r12",
+    "mov eax, 0",
+    "syscall",
+    "2: .ascii \"hello from ring 3\\n\"",
+    "3:",
+    "user_hello_end:",
+    ".global user_gp",
+    ".global user_gp_end",
+    "user_gp:",
+    // Privileged, so ring 3 gets #GP(0)
+    "cli",
+    "jmp user_gp",
+    "user_gp_end:",
+    ".global user_bad_write",
+    ".global user_bad_write_end",
+    "user_bad_write:",
+    // A kernel address: write must refuse it, not read it
+    "mov rdi, 0xffff800000000000",
+    "mov esi, 16",
+    "mov eax, 1",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, 0",
+    "syscall",
+    "user_bad_write_end:",
+    ".global user_self_modify",
+    ".global user_self_modify_end",
+    "user_self_modify:",
+    // Code isn't writable once it's loaded from an ELF file
+    "lea rax, [rip]",
+    "mov byte ptr [rax], 0x90",
+    "mov edi, 0",
+    "mov eax, 0",
+    "syscall",
+    "user_self_modify_end:",
+    ".global user_grow",
+    ".global user_grow_end",
+    "user_grow:",
+    // Two pages, the second written to, and exit with where they start
+    "mov edi, 2",
+    "mov eax, 3",
+    "syscall",
+    "mov qword ptr [rax + 4096], 7",
+    "mov rdi, rax",
+    "mov eax, 0",
+    "syscall",
+    "user_grow_end:",
+    ".global user_hog",
+    ".global user_hog_end",
+    "user_hog:",
+    // A page at a time, for as long as it's let
+    "mov edi, 1",
+    "mov eax, 3",
+    "syscall",
+    "mov byte ptr [rax], 1",
+    "jmp user_hog",
+    "user_hog_end:",
+    ".global user_rusage",
+    ".global user_rusage_end",
+    "user_rusage:",
+    // Spin in ring 3 for a while, then exit with the user time in
+    // microseconds, or what getrusage returned if that failed
+    "mov ecx, 10000000",
+    "2: dec rcx",
+    "jnz 2b",
+    "sub rsp, 48",
+    "mov edi, 0",
+    "mov rsi, rsp",
+    "mov eax, 4",
+    "syscall",
+    "test rax, rax",
+    "jnz 3f",
+    "imul rax, [rsp], 1000000",
+    "add rax, [rsp + 8]",
+    "3: mov rdi, rax",
+    "mov eax, 0",
+    "syscall",
+    "user_rusage_end:",
+    ".global user_alarm",
+    ".global user_alarm_end",
+    "user_alarm:",
+    // ITIMER_REAL in 10ms, unhandled, then spin until it goes off
+    "sub rsp, 32",
+    "mov qword ptr [rsp], 0",
+    "mov qword ptr [rsp + 8], 0",
+    "mov qword ptr [rsp + 16], 0",
+    "mov qword ptr [rsp + 24], 10000",
+    "mov edi, 0",
+    "mov rsi, rsp",
+    "xor edx, edx",
+    "mov eax, 7",
+    "syscall",
+    "2: jmp 2b",
+    "user_alarm_end:",
+    ".global user_ticker",
+    ".global user_ticker_end",
+    "user_ticker:",
+    // The SIGALRM handler counts in r12, which nothing saves for it
+    "xor r12d, r12d",
+    "mov edi, 14",
+    "lea rsi, [rip + 4f]",
+    "mov eax, 8",
+    "syscall",
+    // Every 2ms; spin in ring 3 until the handler has run three times
+    "sub rsp, 32",
+    "mov qword ptr [rsp], 0",
+    "mov qword ptr [rsp + 8], 2000",
+    "mov qword ptr [rsp + 16], 0",
+    "mov qword ptr [rsp + 24], 2000",
+    "mov edi, 0",
+    "mov rsi, rsp",
+    "xor edx, edx",
+    "mov eax, 7",
+    "syscall",
+    "2: cmp r12, 3",
+    "jb 2b",
+    // Then a second's sleep, which the next one cuts short; exit with
+    // what nanosleep returned times 100 plus the count
+    "mov qword ptr [rsp], 1",
+    "mov qword ptr [rsp + 8], 0",
+    "mov rdi, rsp",
+    "xor esi, esi",
+    "mov eax, 6",
+    "syscall",
+    "imul rdi, rax, 100",
+    "add rdi, r12",
+    "mov eax, 0",
+    "syscall",
+    "4: inc r12",
+    "ret",
+    "user_ticker_end:",
+    ".global user_nap",
+    ".global user_nap_end",
+    "user_nap:",
+    // Sleep 20ms and exit with what nanosleep returned
+    "sub rsp, 16",
+    "mov qword ptr [rsp], 0",
+    "mov qword ptr [rsp + 8], 20000000",
+    "mov rdi, rsp",
+    "xor esi, esi",
+    "mov eax, 6",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, 0",
+    "syscall",
+    "user_nap_end:",
+    ".global user_pin",
+    ".global user_pin_end",
+    "user_pin:",
+    // Pin itself to the boot CPU, then exit with the mask it reads back, or
+    // what either call returned if it failed
+    "sub rsp, 16",
+    "mov qword ptr [rsp], 1",
+    "xor edi, edi",
+    "mov esi, 8",
+    "mov rdx, rsp",
+    "mov eax, 10",
+    "syscall",
+    "test rax, rax",
+    "jnz 2f",
+    "mov qword ptr [rsp], 0",
+    "xor edi, edi",
+    "mov esi, 8",
+    "mov rdx, rsp",
+    "mov eax, 11",
+    "syscall",
+    "cmp rax, 8",
+    "jne 2f",
+    "mov rax, [rsp]",
+    "2: mov rdi, rax",
+    "mov eax, 0",
+    "syscall",
+    "user_pin_end:",
+    ".global user_args",
+    ".global user_args_end",
+    "user_args:",
+    // Exit with argc * 256 plus the first byte of the first environment
+    // string, found past argv and its null
+    "mov rcx, [rsp]",
+    "mov rdx, [rsp + rcx * 8 + 16]",
+    "movzx edi, byte ptr [rdx]",
+    "shl rcx, 8",
+    "add rdi, rcx",
+    "mov eax, 0",
+    "syscall",
+    "user_args_end:",
+    ".global user_clock",
+    ".global user_clock_end",
+    "user_clock:",
+    // Exit with the seconds gettimeofday gives, or with what either it or
+    // clock_gettime(CLOCK_MONOTONIC) returned if that failed
+    "sub rsp, 16",
+    "mov edi, 1",
+    "mov rsi, rsp",
+    "mov eax, 20",
+    "syscall",
+    "test rax, rax",
+    "jnz 2f",
+    "mov rdi, rsp",
+    "mov eax, 19",
+    "syscall",
+    "test rax, rax",
+    "jnz 2f",
+    "mov rax, [rsp]",
+    "2: mov rdi, rax",
+    "mov eax, 0",
+    "syscall",
+    "user_clock_end:",
+    ".global user_futex",
+    ".global user_futex_end",
+    "user_futex:",
+    // Exit with what futex(FUTEX_WAIT) returns for a word that doesn't hold
+    // what it's told, once futex(FUTEX_WAKE) has found nobody to wake; or
+    // with what the wake returned if it found somebody
+    "sub rsp, 16",
+    "mov dword ptr [rsp], 1",
+    "mov rdi, rsp",
+    "mov esi, 1",
+    "mov edx, 1",
+    "mov eax, 21",
+    "syscall",
+    "test rax, rax",
+    "jnz 2f",
+    "mov rdi, rsp",
+    "xor esi, esi",
+    "mov edx, 2",
+    "mov eax, 21",
+    "syscall",
+    "2: mov rdi, rax",
+    "mov eax, 0",
+    "syscall",
+    "user_futex_end:",
+    ".global user_caps",
+    ".global user_caps_end",
+    "user_caps:",
+    // Exit with what stat("/") returns once capdrop has taken CAP_FS away
+    // from all three capabilities; or with what capget returned if it didn't
+    // start with those, or capdrop if it didn't leave the other two
+    "sub rsp, 64",
+    "mov eax, 22",
+    "syscall",
+    "cmp rax, 7",
+    "jne 2f",
+    "mov edi, 1",
+    "mov eax, 23",
+    "syscall",
+    "cmp rax, 6",
+    "jne 2f",
+    "mov byte ptr [rsp], 0x2f",
+    "mov rdi, rsp",
+    "mov esi, 1",
+    "lea rdx, [rsp + 8]",
+    "mov eax, 12",
+    "syscall",
+    "2: mov rdi, rax",
+    "mov eax, 0",
+    "syscall",
+    "user_caps_end:",
+    ".global user_spin",
+    ".global user_spin_end",
+    "user_spin:",
+    // Never a syscall, so only a timer interrupt gets it back
+    "jmp user_spin",
+    "user_spin_end:",
+);
+
+extern "C" {
+    static user_hello: u8;
+    static user_hello_end: u8;
+    static user_gp: u8;
+    static user_gp_end: u8;
+    static user_bad_write: u8;
+    static user_bad_write_end: u8;
+    static user_self_modify: u8;
+    static user_self_modify_end: u8;
+    static user_grow: u8;
+    static user_grow_end: u8;
+    static user_hog: u8;
+    static user_hog_end: u8;
+    static user_rusage: u8;
+    static user_rusage_end: u8;
+    static user_alarm: u8;
+    static user_alarm_end: u8;
+    static user_ticker: u8;
+    static user_ticker_end: u8;
+    static user_nap: u8;
+    static user_nap_end: u8;
+    static user_pin: u8;
+    static user_pin_end: u8;
+    static user_args: u8;
+    static user_args_end: u8;
+    static user_clock: u8;
+    static user_clock_end: u8;
+    static user_futex: u8;
+    static user_futex_end: u8;
+    static user_caps: u8;
+    static user_caps_end: u8;
+    static user_spin: u8;
+    static user_spin_end: u8;
+}
+
+fn program(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let len = end as *const u8 as usize - start as *const u8 as usize;
+    unsafe { core::slice::from_raw_parts(start, len) }
+}
+
+// An executable with `code` after the headers, all in one read-only,
+// executable segment at SPACE_START, run from the start of the code
+fn executable(code: &[u8]) -> Vec<u8> {
+    const HEADERS: usize = 64 + 56;
+    let mut image = vec![0u8; HEADERS];
+    image[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+    let mut put = |offset: usize, bytes: &[u8]| image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    put(16, &2u16.to_le_bytes());
+    put(18, &62u16.to_le_bytes());
+    put(24, &(SPACE_START + HEADERS as u64).to_le_bytes());
+    put(32, &64u64.to_le_bytes());
+    put(54, &56u16.to_le_bytes());
+    put(56, &1u16.to_le_bytes());
+    let len = (HEADERS + code.len()) as u64;
+    // PT_LOAD, readable and executable, from the start of the file
+    put(64, &1u32.to_le_bytes());
+    put(68, &5u32.to_le_bytes());
+    put(80, &SPACE_START.to_le_bytes());
+    put(96, &len.to_le_bytes());
+    put(104, &len.to_le_bytes());
+    image.extend_from_slice(code);
+    image
+}
+
+fn free_frames() -> usize {
+    heorot::memory::with_frame_allocator(|frames| frames.free_frames()).unwrap()
+}
+
+#[test_case]
+fn test_write_reaches_the_console() {
+    let text = "hello from ring 3";
+    let exit = user::run(program(unsafe { &user_hello }, unsafe { &user_hello_end })).unwrap();
+    assert_eq!(exit, Exit::Exited(text.len() as i64 + 1));
+    // The line it printed is the one above the cursor's
+    for (col, byte) in text.bytes().enumerate() {
+        assert_eq!(vga_buffer::read_cell(BUFFER_HEIGHT - 2, col), byte);
+    }
+}
+
+#[test_case]
+fn test_survives_general_protection_fault() {
+    let code = program(unsafe { &user_gp }, unsafe { &user_gp_end });
+    match user::run(code).unwrap() {
+        Exit::Faulted { vector, error_code, instruction_pointer } => {
+            assert_eq!(vector, interrupts::GENERAL_PROTECTION_VECTOR);
+            assert_eq!(error_code, Some(0));
+            assert_eq!(instruction_pointer, VirtAddr::new(user::CODE_START));
+        }
+        exit => panic!("expected a fault, got {:?}", exit),
+    }
+    // And the next program runs as if nothing happened
+    let exit = user::run(program(unsafe { &user_hello }, unsafe { &user_hello_end })).unwrap();
+    assert!(matches!(exit, Exit::Exited(_)));
+}
+
+#[test_case]
+fn test_write_rejects_kernel_pointers() {
+    let code = program(unsafe { &user_bad_write }, unsafe { &user_bad_write_end });
+    assert_eq!(user::run(code), Ok(Exit::Exited(user::syscall::EFAULT)));
+}
+
+fn read_user_page() {
+    unsafe { core::ptr::read_volatile(user::CODE_START as *const u64) };
+}
+
+#[test_case]
+fn test_smap_stops_stray_user_access() {
+    if !heorot::cpu::smap_enabled() {
+        return;
+    }
+    let page = VirtAddr::new(user::CODE_START);
+    user::map(page, 1, PageTableFlags::WRITABLE).unwrap();
+    let fault = catch_fault(read_user_page).unwrap_err();
+    assert_eq!(fault.vector, interrupts::PAGE_FAULT_VECTOR);
+    assert_eq!(fault.address, Some(page));
+    let error_code = PageFaultErrorCode::from_bits_truncate(fault.error_code.unwrap());
+    assert!(error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION));
+    // Fine inside a user_access scope
+    assert!(catch_fault(|| heorot::uaccess::user_access(read_user_page)).is_ok());
+    user::unmap(page, 1);
+}
+
+#[test_case]
+fn test_exec_runs_an_executable() {
+    let image = executable(program(unsafe { &user_hello }, unsafe { &user_hello_end }));
+    let before = free_frames();
+    assert_eq!(elf::exec(&image), Ok(Exit::Exited("hello from ring 3".len() as i64 + 1)));
+    // Its address space went with it
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn test_exec_passes_arguments_and_environment() {
+    let image = executable(program(unsafe { &user_args }, unsafe { &user_args_end }));
+    let exit = elf::exec_with(&image, &["/args", "one", "two"], &["HOME=/", "TERM=vga"]);
+    assert_eq!(exit, Ok(Exit::Exited(3 * 256 + i64::from(b'H'))));
+    let long = "x".repeat(elf::MAX_ARGS_SIZE);
+    assert!(elf::exec_with(&image, &[&long], &[]).is_err());
+}
+
+#[test_case]
+fn test_exec_maps_code_read_only() {
+    let image = executable(program(unsafe { &user_self_modify }, unsafe { &user_self_modify_end }));
+    match elf::exec(&image).unwrap() {
+        Exit::Faulted { vector, error_code, .. } => {
+            assert_eq!(vector, interrupts::PAGE_FAULT_VECTOR);
+            let error_code = PageFaultErrorCode::from_bits_truncate(error_code.unwrap());
+            assert!(error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE));
+        }
+        exit => panic!("expected a fault, got {:?}", exit),
+    }
+}
+
+#[test_case]
+fn test_grow_maps_heap_pages() {
+    let image = executable(program(unsafe { &user_grow }, unsafe { &user_grow_end }));
+    let before = free_frames();
+    // Wherever this run's heap was put
+    assert_eq!(elf::exec(&image), Ok(Exit::Exited(user::heap_start() as i64)));
+    assert!((user::HEAP_START..user::HEAP_START + user::HEAP_SLIDE).contains(&user::heap_start()));
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn test_over_the_memory_limit_is_killed() {
+    let image = executable(program(unsafe { &user_hog }, unsafe { &user_hog_end }));
+    let before = free_frames();
+    let kills = limit::usage().kills;
+    limit::set_limit(Some(64));
+    let exit = elf::exec(&image);
+    limit::set_limit(None);
+    assert_eq!(exit, Ok(Exit::OutOfMemory));
+    // A page of code and four of stack, then the heap up to 64 KiB
+    let usage = limit::usage();
+    assert_eq!((usage.peak, usage.charged, usage.kills), (16, 0, kills + 1));
+    assert_eq!(free_frames(), before);
+    // And the next program runs as if nothing happened
+    let exit = user::run(program(unsafe { &user_hello }, unsafe { &user_hello_end })).unwrap();
+    assert!(matches!(exit, Exit::Exited(_)));
+}
+
+#[test_case]
+fn test_getrusage_counts_user_time() {
+    let thread_before = heorot::sched::cpu_times();
+    let exit = user::run(program(unsafe { &user_rusage }, unsafe { &user_rusage_end })).unwrap();
+    let micros = match exit {
+        Exit::Exited(micros) => micros,
+        exit => panic!("expected an exit, got {:?}", exit),
+    };
+    assert!(micros > 0);
+    // All of it also the thread's, which ran nothing else in ring 3
+    let program = user::cpu_times();
+    assert!(program.user.as_micros() >= micros as u128);
+    assert_eq!(heorot::sched::cpu_times().since(thread_before).user, program.user);
+}
+
+#[test_case]
+fn test_unhandled_alarm_kills() {
+    let exit = user::run(program(unsafe { &user_alarm }, unsafe { &user_alarm_end })).unwrap();
+    assert_eq!(exit, Exit::Signaled(signal::SIGALRM));
+}
+
+#[test_case]
+fn test_alarm_handler_runs_and_interrupts_sleep() {
+    let start = Instant::now();
+    let exit = user::run(program(unsafe { &user_ticker }, unsafe { &user_ticker_end })).unwrap();
+    // EINTR, after at least one more run of the handler
+    match exit {
+        Exit::Exited(code) => assert!((-396..-300).contains(&code), "exited with {}", code),
+        exit => panic!("expected an exit, got {:?}", exit),
+    }
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
+
+#[test_case]
+fn test_nanosleep_sleeps() {
+    let start = Instant::now();
+    let exit = user::run(program(unsafe { &user_nap }, unsafe { &user_nap_end })).unwrap();
+    assert_eq!(exit, Exit::Exited(0));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn test_program_reads_the_time() {
+    let secs = || SystemTime::now().since_unix_epoch().as_secs() as i64;
+    let before = secs();
+    match user::run(program(unsafe { &user_clock }, unsafe { &user_clock_end })).unwrap() {
+        Exit::Exited(now) => assert!(before <= now && now <= secs(), "{} isn't the time", now),
+        exit => panic!("expected an exit, got {:?}", exit),
+    }
+}
+
+#[test_case]
+fn test_kill_ends_a_spinning_program() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    // Again and again, as one sent just as the program starts is lost
+    static DONE: AtomicBool = AtomicBool::new(false);
+    fn killer() {
+        while !DONE.load(Ordering::SeqCst) {
+            signal::kill();
+            heorot::sched::yield_now();
+        }
+    }
+
+    assert!(!signal::kill());
+    heorot::sched::spawn("test-killer", killer).unwrap();
+    let exit = user::run(program(unsafe { &user_spin }, unsafe { &user_spin_end })).unwrap();
+    DONE.store(true, Ordering::SeqCst);
+    assert_eq!(exit, Exit::Signaled(signal::SIGKILL));
+}
+
+#[test_case]
+fn test_futex_wait_sees_the_word_changed() {
+    let code = program(unsafe { &user_futex }, unsafe { &user_futex_end });
+    assert_eq!(user::run(code), Ok(Exit::Exited(user::syscall::EWOULDBLOCK)));
+}
+
+#[test_case]
+fn test_dropped_capabilities_stay_dropped() {
+    use heorot::user::caps;
+
+    let code = program(unsafe { &user_caps }, unsafe { &user_caps_end });
+    assert_eq!(user::run(code), Ok(Exit::Exited(user::syscall::EPERM)));
+    assert_eq!(caps::current(), caps::ALL & !caps::CAP_FS);
+    // The next program starts with all of them again, and one granted
+    // fewer starts with those
+    assert_eq!(user::run(code), Ok(Exit::Exited(user::syscall::EPERM)));
+    caps::set_granted(caps::CAP_FS);
+    assert_eq!(user::run(code), Ok(Exit::Exited(caps::CAP_FS as i64)));
+    caps::set_granted(caps::ALL);
+}
+
+#[test_case]
+fn test_program_pins_its_thread() {
+    let thread = heorot::sched::current();
+    let exit = user::run(program(unsafe { &user_pin }, unsafe { &user_pin_end })).unwrap();
+    assert_eq!(exit, Exit::Exited(1));
+    assert_eq!(heorot::sched::affinity(thread), Some(1));
+    heorot::sched::set_affinity(thread, heorot::sched::ALL_CPUS).unwrap();
+}
@@ -0,0 +1,89 @@
+#!/usr/bin/env python3
+"""Encrypt a disk image the way heorot's crypt device reads it.
+
+Usage: tools/crypt-image.py fat.img [PASSPHRASE]
+
+Rewrites the image in place. A header sector goes in front: the magic, the
+PBKDF2 rounds, and a random salt. Then each 512-byte sector is XORed with
+ChaCha20 keyed by PBKDF2-HMAC-SHA256 of the passphrase and that salt, with
+the sector's number as the nonce (see src/fs/crypt.rs). Run on an image
+that has the header, it decrypts and takes the header off again. Without
+PASSPHRASE it's asked for. Mount it from the shell with `cryptmount`.
+"""
+import getpass
+import hashlib
+import os
+import struct
+import sys
+
+SECTOR_SIZE = 512
+MAGIC = b"HEOCRYPT"
+ITERATIONS = 10_000
+SALT_LEN = 16
+MASK = 0xFFFFFFFF
+
+
+def quarter_round(state, a, b, c, d):
+    state[a] = (state[a] + state[b]) & MASK
+    state[d] ^= state[a]
+    state[d] = (state[d] << 16 | state[d] >> 16) & MASK
+    state[c] = (state[c] + state[d]) & MASK
+    state[b] ^= state[c]
+    state[b] = (state[b] << 12 | state[b] >> 20) & MASK
+    state[a] = (state[a] + state[b]) & MASK
+    state[d] ^= state[a]
+    state[d] = (state[d] << 8 | state[d] >> 24) & MASK
+    state[c] = (state[c] + state[d]) & MASK
+    state[b] ^= state[c]
+    state[b] = (state[b] << 7 | state[b] >> 25) & MASK
+
+
+def chacha20_block(key, counter, nonce):
+    initial = [0x61707865, 0x3320646E, 0x79622D32, 0x6B206574]
+    initial += list(struct.unpack("<8I", key)) + [counter] + list(struct.unpack("<3I", nonce))
+    state = list(initial)
+    for _ in range(10):
+        quarter_round(state, 0, 4, 8, 12)
+        quarter_round(state, 1, 5, 9, 13)
+        quarter_round(state, 2, 6, 10, 14)
+        quarter_round(state, 3, 7, 11, 15)
+        quarter_round(state, 0, 5, 10, 15)
+        quarter_round(state, 1, 6, 11, 12)
+        quarter_round(state, 2, 7, 8, 13)
+        quarter_round(state, 3, 4, 9, 14)
+    return struct.pack("<16I", *((s + i) & MASK for s, i in zip(state, initial)))
+
+
+def crypt_sector(key, lba, sector):
+    nonce = struct.pack("<Q", lba) + bytes(4)
+    stream = b"".join(chacha20_block(key, counter, nonce) for counter in range(SECTOR_SIZE // 64))
+    return bytes(a ^ b for a, b in zip(sector, stream))
+
+
+def crypt_image(path, passphrase):
+    with open(path, "rb") as f:
+        image = f.read()
+    sectors = len(image) // SECTOR_SIZE
+    if image.startswith(MAGIC):
+        (iterations,) = struct.unpack_from("<I", image, 8)
+        salt = image[12:12 + SALT_LEN]
+        data, header = image[SECTOR_SIZE:sectors * SECTOR_SIZE], b""
+    else:
+        iterations, salt = ITERATIONS, os.urandom(SALT_LEN)
+        data = image[:sectors * SECTOR_SIZE]
+        header = (MAGIC + struct.pack("<I", iterations) + salt).ljust(SECTOR_SIZE, b"\0")
+    key = hashlib.pbkdf2_hmac("sha256", passphrase.encode(), salt, iterations)
+    out = bytearray(header)
+    for lba in range(len(data) // SECTOR_SIZE):
+        out += crypt_sector(key, lba, data[lba * SECTOR_SIZE:(lba + 1) * SECTOR_SIZE])
+    with open(path, "wb") as f:
+        f.write(out)
+    return len(data) // SECTOR_SIZE, bool(header)
+
+
+if __name__ == "__main__":
+    if len(sys.argv) not in (2, 3):
+        raise SystemExit(__doc__.strip())
+    passphrase = sys.argv[2] if len(sys.argv) == 3 else getpass.getpass("passphrase: ")
+    sectors, encrypted = crypt_image(sys.argv[1], passphrase)
+    print(f"{sectors} sectors {'encrypted' if encrypted else 'decrypted'}")
@@ -0,0 +1,121 @@
+#!/usr/bin/env python3
+"""Fill in the symbol table heorot's backtraces resolve names from.
+
+Usage: tools/embed-symbols.py target/x86_64-heorot/debug/heorot
+
+Run it on the linked kernel before making a boot image. It reads the
+function symbols from the ELF's .symtab and writes them, sorted by address,
+into the HEOROT_SYMBOLS static (see src/symbols.rs), in place. Nothing else
+in the file changes, so running it again after a rebuild is always safe.
+"""
+import re
+import struct
+import sys
+
+MAGIC = b"HEORSYMS"
+HEADER_LEN = 16
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+# Escapes in legacy Rust symbol names
+ESCAPES = {
+    "$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">",
+    "$LP$": "(", "$RP$": ")", "$C$": ",",
+}
+
+
+def demangle(name):
+    """Rust's legacy mangling, without the trailing hash; others as they are"""
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+    parts, rest = [], name[3:-1]
+    while rest:
+        match = re.match(r"(\d+)", rest)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        parts.append(rest[start:start + length])
+        rest = rest[start + length:]
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+    demangled = []
+    for part in parts:
+        if part.startswith("_$"):
+            part = part[1:]
+        part = part.replace("..", "::")
+        for escape, char in ESCAPES.items():
+            part = part.replace(escape, char)
+        part = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+        demangled.append(part)
+    return "::".join(demangled)
+
+
+def sections(image):
+    shoff, = struct.unpack_from("<Q", image, 0x28)
+    shentsize, shnum = struct.unpack_from("<HH", image, 0x3a)
+    for index in range(shnum):
+        # name, type, flags, addr, offset, size, link, info, align, entsize
+        yield struct.unpack_from("<IIQQQQIIQQ", image, shoff + index * shentsize)
+
+
+def symbols(image):
+    """(name, address, size, section index) for every symbol in .symtab"""
+    all_sections = list(sections(image))
+    for section in all_sections:
+        if section[1] != SHT_SYMTAB:
+            continue
+        strtab = all_sections[section[6]]
+        for offset in range(section[4], section[4] + section[5], section[9]):
+            name, info, _, shndx, value, size = struct.unpack_from("<IBBHQQ", image, offset)
+            start = strtab[4] + name
+            end = image.index(b"\0", start)
+            yield image[start:end].decode(), info & 0xf, value, size, shndx
+
+
+def build_table(functions, table_size):
+    names = bytearray()
+    entries = bytearray()
+    for address, size, name in functions:
+        entries += struct.pack("<QII", address, min(size, 0xffffffff), len(names))
+        names += name.encode() + b"\0"
+    names_offset = HEADER_LEN + len(entries)
+    table = MAGIC + struct.pack("<II", len(functions), names_offset) + entries + names
+    if len(table) > table_size:
+        raise SystemExit(f"symbol table needs {len(table)} bytes; raise TABLE_SIZE in src/symbols.rs")
+    return table + bytes(table_size - len(table))
+
+
+def embed_symbols(path):
+    with open(path, "rb") as f:
+        image = bytearray(f.read())
+    if image[:4] != b"\x7fELF" or image[4] != 2:
+        raise SystemExit(f"{path}: not a 64-bit ELF file")
+
+    all_symbols = list(symbols(image))
+    table = [s for s in all_symbols if s[0] == "HEOROT_SYMBOLS"]
+    if not table:
+        raise SystemExit(f"{path}: no HEOROT_SYMBOLS; is this a heorot kernel?")
+    _, _, table_address, table_size, table_section = table[0]
+    section = list(sections(image))[table_section]
+    table_offset = section[4] + table_address - section[3]
+    if image[table_offset:table_offset + len(MAGIC)] != MAGIC:
+        raise SystemExit(f"{path}: HEOROT_SYMBOLS doesn't start with {MAGIC!r}")
+
+    # One name per address, the first one seen
+    functions = {}
+    for name, kind, address, size, _ in all_symbols:
+        if kind == STT_FUNC and address != 0:
+            functions.setdefault(address, (address, size, demangle(name)))
+    functions = sorted(functions.values())
+
+    image[table_offset:table_offset + table_size] = build_table(functions, table_size)
+    with open(path, "wb") as f:
+        f.write(image)
+    return len(functions)
+
+
+if __name__ == "__main__":
+    if len(sys.argv) != 2:
+        raise SystemExit(__doc__.strip())
+    print(f"embedded {embed_symbols(sys.argv[1])} symbols")
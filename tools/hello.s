@@ -0,0 +1,21 @@
+# A program heorot can run from disk: it prints a line and exits with 0.
+# Syscall numbers are in src/user/syscall.rs. Build it with
+#
+#   as tools/hello.s -o hello.o && ld -static -Ttext-segment=0x100000000000 -o hello hello.o
+
+    .intel_syntax noprefix
+    .global _start
+    .text
+_start:
+    lea rdi, [rip + message]
+    mov esi, message_end - message
+    mov eax, 1                  # write
+    syscall
+    xor edi, edi
+    mov eax, 0                  # exit
+    syscall
+
+    .section .rodata
+message:
+    .ascii "hello from disk\n"
+message_end:
@@ -0,0 +1,40 @@
+#!/usr/bin/env python3
+"""Send a command to heorot's host channel and print the reply.
+
+Usage: tools/hostctl.py SOCKET COMMAND...
+
+Boot with HEOROT_CMDLINE="hostchan" and COM2 on a socket:
+
+    cargo run -- -serial stdio -serial unix:/tmp/heorot.sock,server,nowait
+    tools/hostctl.py /tmp/heorot.sock test heap
+
+Commands are ping, stats, tests, test NAME, shutdown and reboot (see
+src/hostchan.rs). It exits with 0 for an "ok" reply and 1 for "err", so CI
+scripts can check on the kernel as it runs.
+"""
+import socket
+import sys
+
+TIMEOUT = 30
+
+
+def command(path, line):
+    with socket.socket(socket.AF_UNIX, socket.SOCK_STREAM) as sock:
+        sock.settimeout(TIMEOUT)
+        sock.connect(path)
+        sock.sendall(line.encode() + b"\n")
+        reply = b""
+        while not reply.endswith(b"\n"):
+            chunk = sock.recv(256)
+            if not chunk:
+                break
+            reply += chunk
+    return reply.decode(errors="replace").strip()
+
+
+if __name__ == "__main__":
+    if len(sys.argv) < 3:
+        raise SystemExit(__doc__.strip())
+    reply = command(sys.argv[1], " ".join(sys.argv[2:]))
+    print(reply)
+    sys.exit(0 if reply.split(" ", 1)[0] == "ok" else 1)
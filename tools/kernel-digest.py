@@ -0,0 +1,38 @@
+#!/usr/bin/env python3
+"""Compute the digest heorot's measured-boot feature reports over serial.
+
+Usage: tools/kernel-digest.py target/x86_64-heorot/debug/heorot
+
+It's the SHA-256 of the file contents of every read-only PT_LOAD segment,
+in program header order, so CI can compare it with the
+"measured boot: kernel sha256 ..." line from a boot log.
+"""
+import hashlib
+import struct
+import sys
+
+PT_LOAD = 1
+PF_W = 2
+
+
+def kernel_digest(path):
+    with open(path, "rb") as f:
+        image = f.read()
+    if image[:4] != b"\x7fELF" or image[4] != 2:
+        raise SystemExit(f"{path}: not a 64-bit ELF file")
+    phoff, = struct.unpack_from("<Q", image, 0x20)
+    phentsize, phnum = struct.unpack_from("<HH", image, 0x36)
+
+    digest = hashlib.sha256()
+    for index in range(phnum):
+        kind, flags, offset, _, _, file_size = struct.unpack_from(
+            "<IIQQQQ", image, phoff + index * phentsize)
+        if kind == PT_LOAD and not flags & PF_W:
+            digest.update(image[offset:offset + file_size])
+    return digest.hexdigest()
+
+
+if __name__ == "__main__":
+    if len(sys.argv) != 2:
+        raise SystemExit(__doc__.strip())
+    print(kernel_digest(sys.argv[1]))
@@ -0,0 +1,32 @@
+#!/usr/bin/env python3
+"""Make a swap area heorot can use.
+
+Usage: tools/mkswap.py swap.img SIZE_MIB
+
+Creates (or overwrites) a raw disk image of SIZE_MIB MiB whose first page
+is the header src/memory/swap.rs looks for; every page after it is a slot.
+Give it to QEMU as a spare drive, e.g. -drive file=swap.img,format=raw,index=2.
+"""
+import struct
+import sys
+
+MAGIC = b"HEORSWAP"
+VERSION = 1
+PAGE_SIZE = 4096
+
+
+def mkswap(path, size_mib):
+    pages = size_mib * 1024 * 1024 // PAGE_SIZE
+    if pages < 2:
+        raise SystemExit("a swap area needs room for at least one slot")
+    header = MAGIC + struct.pack("<II", VERSION, pages - 1)
+    with open(path, "wb") as f:
+        f.write(header + bytes(PAGE_SIZE - len(header)))
+        f.truncate(pages * PAGE_SIZE)
+    return pages - 1
+
+
+if __name__ == "__main__":
+    if len(sys.argv) != 3:
+        raise SystemExit(__doc__.strip())
+    print(f"{mkswap(sys.argv[1], int(sys.argv[2]))} slots")
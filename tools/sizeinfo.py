@@ -0,0 +1,113 @@
+#!/usr/bin/env python3
+"""Report how big the kernel is, by section and by symbol.
+
+Usage: tools/sizeinfo.py [--top N] [--baseline OLD] target/x86_64-heorot/debug/heorot
+
+Run it on the linked kernel. It prints the sizes of .text, .rodata, .data
+and .bss (each with the sections whose names start the same, so .text.*
+counts as .text), then the N biggest functions and objects. With
+--baseline, another build of the kernel, every line says how much it's
+grown or shrunk since, and symbols that came or went are listed, so a merge
+that makes the kernel bigger says where. The kernel's own `sizeinfo`
+command shows the same sections, going by the loaded segments instead.
+"""
+import argparse
+import struct
+
+SHT_SYMTAB = 2
+SHT_NOBITS = 8
+SHF_ALLOC = 2
+STT_OBJECT = 1
+STT_FUNC = 2
+KINDS = [".text", ".rodata", ".data", ".bss"]
+
+
+def sections(image):
+    """(name, type, flags, size) for every section"""
+    shoff, = struct.unpack_from("<Q", image, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", image, 0x3a)
+    headers = [struct.unpack_from("<IIQQQQIIQQ", image, shoff + index * shentsize) for index in range(shnum)]
+    names = headers[shstrndx][4]
+    for header in headers:
+        end = image.index(b"\0", names + header[0])
+        yield image[names + header[0]:end].decode(), header[1], header[2], header[5], header
+
+
+def kind_sizes(image):
+    sizes = dict.fromkeys(KINDS, 0)
+    for name, kind, flags, size, _ in sections(image):
+        if not flags & SHF_ALLOC:
+            continue
+        for prefix in KINDS:
+            if name == prefix or name.startswith(prefix + "."):
+                sizes[prefix] += size
+                break
+    return sizes
+
+
+def symbol_sizes(image):
+    """{name: size} for every function and object with a size"""
+    all_sections = list(sections(image))
+    found = {}
+    for _, kind, _, _, header in all_sections:
+        if kind != SHT_SYMTAB:
+            continue
+        strtab = all_sections[header[6]][4]
+        for offset in range(header[4], header[4] + header[5], header[9]):
+            name, info, _, _, _, size = struct.unpack_from("<IBBHQQ", image, offset)
+            if info & 0xf not in (STT_FUNC, STT_OBJECT) or size == 0:
+                continue
+            end = image.index(b"\0", strtab[4] + name)
+            found[image[strtab[4] + name:end].decode()] = size
+    return found
+
+
+def load(path):
+    with open(path, "rb") as f:
+        image = f.read()
+    if image[:4] != b"\x7fELF" or image[4] != 2:
+        raise SystemExit(f"{path}: not a 64-bit ELF file")
+    return image
+
+
+def change(new, old):
+    if old is None:
+        return ""
+    return f" {new - old:+}"
+
+
+def report(path, top, baseline):
+    image = load(path)
+    old = load(baseline) if baseline else None
+    sizes = kind_sizes(image)
+    old_sizes = kind_sizes(old) if old else {}
+    for kind in KINDS:
+        print(f"{kind:<8} {sizes[kind]:>10}{change(sizes[kind], old_sizes.get(kind))}")
+    total = sum(sizes.values())
+    print(f"{'total':<8} {total:>10}{change(total, sum(old_sizes.values()) if old else None)}")
+
+    # Mangled names keep apart what demangling would merge
+    symbols = symbol_sizes(image)
+    old_symbols = symbol_sizes(old) if old else {}
+    print()
+    print(f"{top} biggest symbols:")
+    for name, size in sorted(symbols.items(), key=lambda item: -item[1])[:top]:
+        print(f"{size:>10}{change(size, old_symbols.get(name, 0) if old else None)} {name}")
+    if old:
+        grown = sorted(((size - old_symbols.get(name, 0), name) for name, size in symbols.items()), reverse=True)
+        print()
+        print("most grown:")
+        for delta, name in [entry for entry in grown if entry[0] > 0][:top]:
+            print(f"{delta:>+10} {name}")
+        gone = sorted(set(old_symbols) - set(symbols), key=lambda name: -old_symbols[name])
+        for name in gone[:top]:
+            print(f"{-old_symbols[name]:>+10} {name} (gone)")
+
+
+if __name__ == "__main__":
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("kernel")
+    parser.add_argument("--top", type=int, default=20)
+    parser.add_argument("--baseline", help="another build to compare against")
+    arguments = parser.parse_args()
+    report(arguments.kernel, arguments.top, arguments.baseline)